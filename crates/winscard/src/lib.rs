@@ -17,6 +17,7 @@ mod dummy_rng;
 #[cfg(feature = "std")]
 pub mod env;
 mod piv_cert;
+mod piv_key;
 mod scard;
 mod scard_context;
 /// Constants with most popular tags used in this PIV smart card implementation.
@@ -37,7 +38,8 @@ use iso7816_tlv::TlvError;
 use num_derive::{FromPrimitive, ToPrimitive};
 use picky::key::KeyError;
 use picky::x509::certificate::CertError;
-pub use scard::{SmartCard, ATR, CHUNK_SIZE, PIV_AID, SUPPORTED_CONNECTION_PROTOCOL};
+pub use piv_key::{PivKey, PivSlot};
+pub use scard::{PinPolicy, SmartCard, ATR, CHUNK_SIZE, PIV_AID, SUPPORTED_CONNECTION_PROTOCOL};
 pub use scard_context::{
     Reader, ScardContext, SmartCardInfo, DEFAULT_CARD_NAME, MICROSOFT_DEFAULT_CSP, MICROSOFT_DEFAULT_KSP,
     MICROSOFT_SCARD_DRIVER_LOCATION,
@@ -142,6 +144,33 @@ impl From<rsa::Error> for Error {
     }
 }
 
+impl From<p256::elliptic_curve::Error> for Error {
+    fn from(value: p256::elliptic_curve::Error) -> Self {
+        Error::new(
+            ErrorKind::InvalidValue,
+            format!("error: an unexpected EllipticCurveError happened: {}", value),
+        )
+    }
+}
+
+impl From<p256::ecdsa::Error> for Error {
+    fn from(value: p256::ecdsa::Error) -> Self {
+        Error::new(
+            ErrorKind::InternalError,
+            format!("error: an unexpected EcdsaError happened: {}", value),
+        )
+    }
+}
+
+impl From<p256::pkcs8::Error> for Error {
+    fn from(value: p256::pkcs8::Error) -> Self {
+        Error::new(
+            ErrorKind::InternalError,
+            format!("error: an unexpected Pkcs8Error happened: {}", value),
+        )
+    }
+}
+
 impl From<TlvError> for Error {
     fn from(value: TlvError) -> Self {
         Error::new(
@@ -339,7 +368,10 @@ impl From<ErrorKind> for u32 {
 
 /// Represents Status Word (SW) - a 2-byte value returned by a card command at the card edge.
 /// [Table 6. Status Words](https://nvlpubs.nist.gov/nistpubs/SpecialPublications/NIST.SP.800-73-4.pdf#page=36)
+///
+/// More status words can be added as the emulated card supports more commands.
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Status {
     /// Data object or application not found.
     NotFound,
@@ -347,7 +379,13 @@ pub enum Status {
     OK,
     /// Verification failed, X indicates the number of further allowed retries or resets.
     /// Number of allowed retries is always 9.
+    ///
+    /// The emulated card reports the actual number with [Status::VerificationFailedWithRetriesLeft].
     VerificationFailedWithRetries,
+    /// Verification failed, SW2 encodes the number of further allowed retries or resets.
+    VerificationFailedWithRetriesLeft(u8),
+    /// Authentication method blocked: the retry counter of the reference data has reached zero.
+    AuthenticationMethodBlocked,
     /// Successful execution where SW2 encodes the number of response data bytes still available.
     MoreAvailable(u8),
     /// Referenced data or reference data not found.
@@ -369,6 +407,8 @@ impl From<Status> for [u8; 2] {
             Status::NotFound => [0x6A, 0x82],
             Status::OK => [0x90, 0x00],
             Status::VerificationFailedWithRetries => [0x63, 0xC9],
+            Status::VerificationFailedWithRetriesLeft(retries_left) => [0x63, 0xC0 | (retries_left & 0x0F)],
+            Status::AuthenticationMethodBlocked => [0x69, 0x83],
             Status::MoreAvailable(bytes_left) => [0x61, bytes_left],
             Status::KeyReferenceNotFound => [0x6A, 0x88],
             Status::SecurityStatusNotSatisfied => [0x69, 0x82],
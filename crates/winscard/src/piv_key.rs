@@ -0,0 +1,253 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+
+use p256::ecdsa::signature::hazmat::PrehashSigner;
+use p256::pkcs8::DecodePrivateKey;
+use picky::key::PrivateKey;
+use picky_asn1_x509::{oids, AlgorithmIdentifierParameters, PublicKey, SubjectPublicKeyInfo};
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+
+use crate::{Error, ErrorKind, WinScardResult};
+
+// NIST.SP.800-73-4, Part 1, Table 4b
+const FIRST_RETIRED_KEY_REFERENCE: u8 = 0x82;
+// NIST.SP.800-73-4, Part 1, Table 4b
+const RETIRED_KEYS_COUNT: u8 = 20;
+// NIST.SP.800-73-4, part 1, section 4.3, Table 3
+const CARD_AUTH_CERT_TAG: &[u8] = &[0x5F, 0xC1, 0x01];
+// NIST.SP.800-73-4, part 1, section 4.3, Table 3
+const PIV_CERT_TAG: &[u8] = &[0x5F, 0xC1, 0x05];
+// NIST.SP.800-73-4, part 1, section 4.3, Table 3
+const DIGITAL_SIGNATURE_CERT_TAG: &[u8] = &[0x5F, 0xC1, 0x0A];
+// NIST.SP.800-73-4, part 1, section 4.3, Table 3
+const KEY_MANAGEMENT_CERT_TAG: &[u8] = &[0x5F, 0xC1, 0x0B];
+// NIST.SP.800-73-4, part 1, section 4.3, Table 3
+// Certificates of the retired keys use consecutive tags: 5FC10D - 5FC120
+const FIRST_RETIRED_CERT_TAG: &[u8] = &[0x5F, 0xC1, 0x0D];
+// NIST.SP.800-73-4, Part 1, Table 5
+const RSA_1024_ALGORITHM: u8 = 0x06;
+// NIST.SP.800-73-4, Part 1, Table 5
+const RSA_2048_ALGORITHM: u8 = 0x07;
+// NIST.SP.800-73-4, Part 1, Table 5
+const ECC_P256_ALGORITHM: u8 = 0x11;
+// NIST.SP.800-73-4, Part 1, Table 5
+const ECC_P384_ALGORITHM: u8 = 0x14;
+
+/// PIV key slot.
+///
+/// Every slot holds its own private key and the corresponding certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PivSlot {
+    /// PIV Authentication Key (9A).
+    Authentication,
+    /// Digital Signature Key (9C).
+    DigitalSignature,
+    /// Key Management Key (9D).
+    KeyManagement,
+    /// Card Authentication Key (9E).
+    ///
+    /// Unlike the other keys, it can be used without the PIN verification.
+    CardAuthentication,
+    /// Retired Key Management Key (82-95) with the provided number (1-20).
+    RetiredKeyManagement(u8),
+}
+
+impl PivSlot {
+    /// Returns the key reference of the slot.
+    pub fn key_reference(self) -> u8 {
+        match self {
+            PivSlot::Authentication => 0x9A,
+            PivSlot::DigitalSignature => 0x9C,
+            PivSlot::KeyManagement => 0x9D,
+            PivSlot::CardAuthentication => 0x9E,
+            PivSlot::RetiredKeyManagement(number) => FIRST_RETIRED_KEY_REFERENCE.wrapping_add(number.wrapping_sub(1)),
+        }
+    }
+
+    /// Returns the slot with the provided key reference.
+    pub fn from_key_reference(key_reference: u8) -> Option<Self> {
+        match key_reference {
+            0x9A => Some(PivSlot::Authentication),
+            0x9C => Some(PivSlot::DigitalSignature),
+            0x9D => Some(PivSlot::KeyManagement),
+            0x9E => Some(PivSlot::CardAuthentication),
+            key_reference => key_reference
+                .checked_sub(FIRST_RETIRED_KEY_REFERENCE)
+                .filter(|index| *index < RETIRED_KEYS_COUNT)
+                .map(|index| PivSlot::RetiredKeyManagement(index + 1)),
+        }
+    }
+
+    /// Returns the slot which certificate is stored in the data object with the provided tag.
+    pub(crate) fn from_certificate_tag(tag: &[u8]) -> Option<Self> {
+        match tag {
+            PIV_CERT_TAG => Some(PivSlot::Authentication),
+            DIGITAL_SIGNATURE_CERT_TAG => Some(PivSlot::DigitalSignature),
+            KEY_MANAGEMENT_CERT_TAG => Some(PivSlot::KeyManagement),
+            CARD_AUTH_CERT_TAG => Some(PivSlot::CardAuthentication),
+            [0x5F, 0xC1, last] => last
+                .checked_sub(FIRST_RETIRED_CERT_TAG[2])
+                .filter(|index| *index < RETIRED_KEYS_COUNT)
+                .map(|index| PivSlot::RetiredKeyManagement(index + 1)),
+            _ => None,
+        }
+    }
+
+    fn validate(self) -> WinScardResult<()> {
+        if let PivSlot::RetiredKeyManagement(number) = self {
+            if !(1..=RETIRED_KEYS_COUNT).contains(&number) {
+                return Err(Error::new(
+                    ErrorKind::InvalidParameter,
+                    format!("retired key number should be in range 1..={}", RETIRED_KEYS_COUNT),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Private key and certificate stored in a PIV key slot.
+#[derive(Debug, Clone)]
+pub struct PivKey {
+    /// Key slot.
+    pub slot: PivSlot,
+    /// DER-encoded certificate.
+    pub cert_der: Vec<u8>,
+    /// Private key. Supported keys are RSA 1024, RSA 2048, ECC P-256 and ECC P-384.
+    pub private_key: PrivateKey,
+}
+
+/// Private key of a PIV slot along with the PIV algorithm it is used with.
+#[derive(Debug, Clone)]
+pub(crate) enum CardKey {
+    Rsa(Box<RsaPrivateKey>),
+    EccP256(p256::SecretKey),
+    EccP384(p384::SecretKey),
+}
+
+impl CardKey {
+    pub(crate) fn new(key: &PivKey) -> WinScardResult<Self> {
+        key.slot.validate()?;
+
+        let public_key = key.private_key.to_public_key()?;
+        let public_key: &SubjectPublicKeyInfo = public_key.as_ref();
+
+        let card_key = match &public_key.subject_public_key {
+            PublicKey::Rsa(_) => CardKey::Rsa(Box::new(RsaPrivateKey::try_from(&key.private_key)?)),
+            PublicKey::Ec(_) => match public_key.algorithm.parameters() {
+                AlgorithmIdentifierParameters::Ec(parameters) if *parameters.curve_oid() == oids::secp256r1() => {
+                    CardKey::EccP256(p256::SecretKey::from_pkcs8_der(&key.private_key.to_pkcs8()?)?)
+                }
+                AlgorithmIdentifierParameters::Ec(parameters) if *parameters.curve_oid() == oids::secp384r1() => {
+                    CardKey::EccP384(p384::SecretKey::from_pkcs8_der(&key.private_key.to_pkcs8()?)?)
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::UnsupportedFeature,
+                        "only P-256 and P-384 EC keys are supported",
+                    ))
+                }
+            },
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::UnsupportedFeature,
+                    "only RSA and EC keys are supported",
+                ))
+            }
+        };
+        // fails on unsupported RSA key sizes
+        card_key.algorithm()?;
+
+        Ok(card_key)
+    }
+
+    /// Returns the PIV cryptographic algorithm identifier of the key.
+    pub(crate) fn algorithm(&self) -> WinScardResult<u8> {
+        match self {
+            CardKey::Rsa(key) => match key.size() {
+                128 => Ok(RSA_1024_ALGORITHM),
+                256 => Ok(RSA_2048_ALGORITHM),
+                size => Err(Error::new(
+                    ErrorKind::UnsupportedFeature,
+                    format!("only RSA 1024 and RSA 2048 keys are supported: got {} bits", size * 8),
+                )),
+            },
+            CardKey::EccP256(_) => Ok(ECC_P256_ALGORITHM),
+            CardKey::EccP384(_) => Ok(ECC_P384_ALGORITHM),
+        }
+    }
+
+    /// Returns the size of the key in bytes: the modulus size for RSA and the field size for ECC.
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            CardKey::Rsa(key) => key.size(),
+            CardKey::EccP256(_) => 32,
+            CardKey::EccP384(_) => 48,
+        }
+    }
+
+    /// Applies the RSA private key to the already padded data.
+    ///
+    /// This is how the PIV card both signs the data and decrypts the key transport messages.
+    pub(crate) fn rsa_private_operation(&self, data: &[u8]) -> WinScardResult<Vec<u8>> {
+        use rsa::BigUint;
+
+        let CardKey::Rsa(rsa_private_key) = self else {
+            return Err(Error::new(ErrorKind::InvalidParameter, "the key is not an RSA key"));
+        };
+        // According to the specification, the PIV smart card accepts already padded digest.
+        // So, it's safe to use the `rsa_decrypt_and_check` function here.
+        let signature = rsa::hazmat::rsa_decrypt_and_check(
+            rsa_private_key.as_ref(),
+            None::<&mut crate::dummy_rng::Dummy>,
+            &BigUint::from_bytes_be(data),
+        )?;
+
+        let mut signature = signature.to_bytes_be();
+
+        while signature.len() < rsa_private_key.size() {
+            signature.insert(0, 0);
+        }
+
+        Ok(signature)
+    }
+
+    /// Signs the already hashed data using ECDSA and returns the DER-encoded signature.
+    pub(crate) fn ecdsa_sign_prehash(&self, hash: &[u8]) -> WinScardResult<Vec<u8>> {
+        match self {
+            CardKey::EccP256(key) => {
+                let signature: p256::ecdsa::Signature = p256::ecdsa::SigningKey::from(key).sign_prehash(hash)?;
+
+                Ok(signature.to_der().as_bytes().to_vec())
+            }
+            CardKey::EccP384(key) => {
+                let signature: p384::ecdsa::Signature = p384::ecdsa::SigningKey::from(key).sign_prehash(hash)?;
+
+                Ok(signature.to_der().as_bytes().to_vec())
+            }
+            CardKey::Rsa(_) => Err(Error::new(ErrorKind::InvalidParameter, "the key is not an EC key")),
+        }
+    }
+
+    /// Computes the ECDH shared secret (the x-coordinate of the shared point) with the provided SEC1-encoded point.
+    pub(crate) fn ecdh(&self, peer_point: &[u8]) -> WinScardResult<Vec<u8>> {
+        match self {
+            CardKey::EccP256(key) => {
+                let peer_public_key = p256::PublicKey::from_sec1_bytes(peer_point)?;
+                let shared_secret = p256::ecdh::diffie_hellman(key.to_nonzero_scalar(), peer_public_key.as_affine());
+
+                Ok(shared_secret.raw_secret_bytes().to_vec())
+            }
+            CardKey::EccP384(key) => {
+                let peer_public_key = p384::PublicKey::from_sec1_bytes(peer_point)?;
+                let shared_secret = p384::ecdh::diffie_hellman(key.to_nonzero_scalar(), peer_public_key.as_affine());
+
+                Ok(shared_secret.raw_secret_bytes().to_vec())
+            }
+            CardKey::Rsa(_) => Err(Error::new(ErrorKind::InvalidParameter, "the key is not an EC key")),
+        }
+    }
+}
@@ -2,14 +2,17 @@ use alloc::borrow::{Cow, ToOwned};
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use alloc::{format, vec};
 
 use picky::key::PrivateKey;
 use picky_asn1_x509::{PublicKey, SubjectPublicKeyInfo};
+use spin::Mutex;
 use uuid::Uuid;
 
-use crate::scard::{SmartCard, SUPPORTED_CONNECTION_PROTOCOL};
+use crate::piv_key::{PivKey, PivSlot};
+use crate::scard::{CardPins, PinPolicy, SmartCard, SUPPORTED_CONNECTION_PROTOCOL};
 use crate::winscard::{
     CurrentState, DeviceTypeId, Icon, Protocol, ProviderId, ReaderState, ScardConnectData, ShareMode, WinScardContext,
 };
@@ -129,11 +132,16 @@ impl<'a> SmartCardInfo<'a> {
 #[derive(Debug, Clone)]
 pub struct ScardContext<'a> {
     smart_card_info: SmartCardInfo<'a>,
+    // Every connection emulates the same card, so they all share its PIN state.
+    pins: Arc<Mutex<CardPins>>,
+    key_slots: Vec<PivKey>,
     cache: BTreeMap<String, Vec<u8>>,
 }
 
 impl<'a> ScardContext<'a> {
     /// Creates a new smart card based on the list of smart card readers
+    ///
+    /// The PUK and the retry limits of the PIN are set to [PinPolicy::default].
     pub fn new(smart_card_info: SmartCardInfo<'a>) -> WinScardResult<Self> {
         // Freshness values may vary at different points in time.
         // We do not need to change them in runtime, so we hardcode them here.
@@ -241,67 +249,53 @@ impl<'a> ScardContext<'a> {
 
             value
         });
-        cache.insert("Cached_ContainerInfo_00".into(), {
-            // Note. We can hardcode lengths values in this cache item because we support only 2048 RSA keys.
-            // RSA 4096 is not defined in the specification so we don't support it.
-            // https://nvlpubs.nist.gov/nistpubs/SpecialPublications/NIST.SP.800-73-4.pdf#page=34
-            // 5.3 Cryptographic Mechanism Identifiers
-            // '07' - RSA 2048
-
-            let mut value = CACHE_ITEM_HEADER.to_vec();
-            // unkown flags
-            value.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
-            // actual data len (precalculated)
-            value.extend_from_slice(&292_u32.to_le_bytes());
-
-            value.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x14, 0x01, 0x00, 0x00]); // container info header
-
-            // https://learn.microsoft.com/en-us/windows/win32/api/wincrypt/ns-wincrypt-publickeystruc
-            // PUBLICKEYSTRUC
-            value.push(0x06); // bType = PUBLICKEYBLOB
-            value.push(0x02); // bVersion = 0x2
-            value.extend_from_slice(&[0x00, 0x00]); // reserved
-            value.extend_from_slice(&[0x00, 0xa4, 0x00, 0x00]); // aiKeyAlg = CALG_RSA_KEYX
-
-            // https://learn.microsoft.com/en-us/windows/win32/api/wincrypt/ns-wincrypt-rsapubkey
-            // RSAPUBKEY
-            value.extend_from_slice(b"RSA1"); // magic = RSA1
-            value.extend_from_slice(&2048_u32.to_le_bytes()); // bitlen = 2048
-
-            // let pub_key = smart_cards_info.
-            let public_key = smart_card_info
-                .auth_pk
-                .to_public_key()
-                .expect("rsa private key to public key");
-            let public_key: &SubjectPublicKeyInfo = public_key.as_ref();
-            let (modulus, public_exponent) = match &public_key.subject_public_key {
-                PublicKey::Rsa(rsa) => (
-                    {
-                        let mut modulus = rsa.0.modulus.to_vec();
-                        modulus.reverse();
-                        modulus.resize(256, 0);
-                        modulus
-                    },
-                    {
-                        let mut pub_exp = rsa.0.public_exponent.to_vec();
-                        pub_exp.reverse();
-                        pub_exp.resize(4, 0);
-                        pub_exp
-                    },
-                ),
-                _ => {
-                    return Err(Error::new(
-                        ErrorKind::UnsupportedFeature,
-                        "only RSA 2048 keys are supported",
-                    ))
-                }
-            };
-
-            value.extend_from_slice(&public_exponent); // pubexp
-            value.extend_from_slice(&modulus); // public key
-
-            value
-        });
+        let public_key = smart_card_info.auth_pk.to_public_key()?;
+        let public_key: &SubjectPublicKeyInfo = public_key.as_ref();
+        if let PublicKey::Rsa(rsa) = &public_key.subject_public_key {
+            cache.insert("Cached_ContainerInfo_00".into(), {
+                // Note. We can hardcode lengths values in this cache item because we support only 2048 RSA keys.
+                // RSA 4096 is not defined in the specification so we don't support it.
+                // https://nvlpubs.nist.gov/nistpubs/SpecialPublications/NIST.SP.800-73-4.pdf#page=34
+                // 5.3 Cryptographic Mechanism Identifiers
+                // '07' - RSA 2048
+
+                let mut value = CACHE_ITEM_HEADER.to_vec();
+                // unkown flags
+                value.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+                // actual data len (precalculated)
+                value.extend_from_slice(&292_u32.to_le_bytes());
+
+                value.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x14, 0x01, 0x00, 0x00]); // container info header
+
+                // https://learn.microsoft.com/en-us/windows/win32/api/wincrypt/ns-wincrypt-publickeystruc
+                // PUBLICKEYSTRUC
+                value.push(0x06); // bType = PUBLICKEYBLOB
+                value.push(0x02); // bVersion = 0x2
+                value.extend_from_slice(&[0x00, 0x00]); // reserved
+                value.extend_from_slice(&[0x00, 0xa4, 0x00, 0x00]); // aiKeyAlg = CALG_RSA_KEYX
+
+                // https://learn.microsoft.com/en-us/windows/win32/api/wincrypt/ns-wincrypt-rsapubkey
+                // RSAPUBKEY
+                value.extend_from_slice(b"RSA1"); // magic = RSA1
+                value.extend_from_slice(&2048_u32.to_le_bytes()); // bitlen = 2048
+
+                let mut modulus = rsa.0.modulus.to_vec();
+                modulus.reverse();
+                modulus.resize(256, 0);
+                let mut public_exponent = rsa.0.public_exponent.to_vec();
+                public_exponent.reverse();
+                public_exponent.resize(4, 0);
+
+                value.extend_from_slice(&public_exponent); // pubexp
+                value.extend_from_slice(&modulus); // public key
+
+                value
+            });
+        } else {
+            // The cached container info describes an RSA key only. The smart card still provides the certificate
+            // and the key of the PIV Authentication slot through the PIV card edge.
+            warn!("PIV Authentication key is not an RSA key: the container info is not cached");
+        }
         cache.insert("Cached_GeneralFile/mscp/kxc00".into(), {
             let mut value = CACHE_ITEM_HEADER.to_vec();
             // unkown flags
@@ -385,7 +379,33 @@ impl<'a> ScardContext<'a> {
             CONTAINER_FRESHNESS.to_vec(),
         );
 
-        Ok(Self { smart_card_info, cache })
+        let pins = CardPins::new(smart_card_info.pin.clone(), &PinPolicy::default())?;
+
+        Ok(Self {
+            smart_card_info,
+            pins,
+            key_slots: Vec::new(),
+            cache,
+        })
+    }
+
+    /// Sets the PUK and the retry limits of the smart card PIN.
+    ///
+    /// The PIN retry counter of the smart card is reset.
+    pub fn with_pin_policy(mut self, pin_policy: &PinPolicy) -> WinScardResult<Self> {
+        self.pins = CardPins::new(self.smart_card_info.pin.clone(), pin_policy)?;
+
+        Ok(self)
+    }
+
+    /// Adds the keys of the other PIV slots.
+    ///
+    /// The PIV Authentication slot (9A) holds the [SmartCardInfo] certificate and private key,
+    /// so it can't be specified here.
+    pub fn with_key_slots(mut self, key_slots: Vec<PivKey>) -> Self {
+        self.key_slots.extend(key_slots);
+
+        self
     }
 
     /// Returns available smart card reader name.
@@ -408,12 +428,17 @@ impl<'a> WinScardContext for ScardContext<'a> {
             ));
         }
 
+        let auth_key = PivKey {
+            slot: PivSlot::Authentication,
+            cert_der: self.smart_card_info.auth_cert_der.clone(),
+            private_key: self.smart_card_info.auth_pk.clone(),
+        };
+
         Ok(ScardConnectData {
-            handle: Box::new(SmartCard::new(
+            handle: Box::new(SmartCard::with_pins(
                 Cow::Owned(reader_name.to_owned()),
-                self.smart_card_info.pin.clone(),
-                self.smart_card_info.auth_cert_der.clone(),
-                self.smart_card_info.auth_pk.clone(),
+                Arc::clone(&self.pins),
+                core::iter::once(&auth_key).chain(&self.key_slots),
             )?),
             protocol: SUPPORTED_CONNECTION_PROTOCOL,
         })
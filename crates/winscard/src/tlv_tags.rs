@@ -29,3 +29,5 @@ pub const DYNAMIC_AUTHENTICATION_TEMPLATE: u8 = 0x7C;
 pub const DAT_CHALLENGE: u8 = 0x81;
 /// [Response](https://nvlpubs.nist.gov/nistpubs/SpecialPublications/NIST.SP.800-73-4.pdf#page=93).
 pub const DAT_RESPONSE: u8 = 0x82;
+/// [Exponentiation](https://nvlpubs.nist.gov/nistpubs/SpecialPublications/NIST.SP.800-73-4.pdf#page=93).
+pub const DAT_EXPONENTIATION: u8 = 0x85;
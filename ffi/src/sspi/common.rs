@@ -193,33 +193,82 @@ pub extern "system" fn RevertSecurityContext(_ph_context: PCtxtHandle) -> Securi
 
 pub type RevertSecurityContextFn = extern "system" fn(PCtxtHandle) -> SecurityStatus;
 
+#[allow(clippy::useless_conversion)]
 #[instrument(skip_all)]
 #[cfg_attr(windows, rename_symbol(to = "Rust_MakeSignature"))]
 #[no_mangle]
-pub extern "system" fn MakeSignature(
-    _ph_context: PCtxtHandle,
-    _f_qop: u32,
-    _p_message: PSecBufferDesc,
-    _message_seq_no: u32,
+pub unsafe extern "system" fn MakeSignature(
+    mut ph_context: PCtxtHandle,
+    f_qop: u32,
+    p_message: PSecBufferDesc,
+    message_seq_no: u32,
 ) -> SecurityStatus {
-    ErrorKind::UnsupportedFunction.to_u32().unwrap()
+    catch_panic! {
+        check_null!(ph_context);
+        check_null!(p_message);
+
+        let sspi_context = try_execute!(p_ctxt_handle_to_sspi_context(
+            &mut ph_context,
+            None,
+            &CredentialsAttributes::default()
+        ))
+        .as_mut()
+        .expect("security context pointer cannot be null");
+
+        let len = (*p_message).c_buffers as usize;
+        let raw_buffers = from_raw_parts((*p_message).p_buffers, len);
+        let mut message = try_execute!(p_sec_buffers_to_decrypt_buffers(raw_buffers));
+
+        let result_status = sspi_context.make_signature(f_qop, &mut message, message_seq_no.try_into().unwrap());
+
+        try_execute!(copy_decrypted_buffers((*p_message).p_buffers, message));
+
+        try_execute!(result_status);
+
+        0
+    }
 }
 
-pub type MakeSignatureFn = extern "system" fn(PCtxtHandle, u32, PSecBufferDesc, u32) -> SecurityStatus;
+pub type MakeSignatureFn = unsafe extern "system" fn(PCtxtHandle, u32, PSecBufferDesc, u32) -> SecurityStatus;
 
+#[allow(clippy::useless_conversion)]
 #[instrument(skip_all)]
 #[cfg_attr(windows, rename_symbol(to = "Rust_VerifySignature"))]
 #[no_mangle]
-pub extern "system" fn VerifySignature(
-    _ph_context: PCtxtHandle,
-    _message: PSecBufferDesc,
-    _message_seq_no: u32,
-    _pf_qop: *mut u32,
+pub unsafe extern "system" fn VerifySignature(
+    mut ph_context: PCtxtHandle,
+    message: PSecBufferDesc,
+    message_seq_no: u32,
+    pf_qop: *mut u32,
 ) -> SecurityStatus {
-    ErrorKind::UnsupportedFunction.to_u32().unwrap()
+    catch_panic! {
+        check_null!(ph_context);
+        check_null!(message);
+
+        let sspi_context = try_execute!(p_ctxt_handle_to_sspi_context(
+            &mut ph_context,
+            None,
+            &CredentialsAttributes::default()
+        ))
+        .as_mut()
+        .expect("security context pointer cannot be null");
+
+        let len = (*message).c_buffers as usize;
+        let raw_buffers = from_raw_parts((*message).p_buffers, len);
+        let message = try_execute!(p_sec_buffers_to_decrypt_buffers(raw_buffers));
+
+        let qop = try_execute!(sspi_context.verify_signature(&message, message_seq_no.try_into().unwrap()));
+
+        // `pf_qop` is optional
+        if !pf_qop.is_null() {
+            *pf_qop = qop;
+        }
+
+        0
+    }
 }
 
-pub type VerifySignatureFn = extern "system" fn(PCtxtHandle, PSecBufferDesc, u32, *mut u32) -> SecurityStatus;
+pub type VerifySignatureFn = unsafe extern "system" fn(PCtxtHandle, PSecBufferDesc, u32, *mut u32) -> SecurityStatus;
 
 #[instrument(skip_all)]
 #[cfg_attr(windows, rename_symbol(to = "Rust_FreeContextBuffer"))]
@@ -350,14 +399,19 @@ unsafe fn p_sec_buffers_to_decrypt_buffers(raw_buffers: &[SecBuffer]) -> sspi::R
     let mut buffers = Vec::with_capacity(raw_buffers.len());
 
     for raw_buffer in raw_buffers {
-        let buf = SecurityBuffer::with_security_buffer_type(
+        // SECBUFFER_READONLY_WITH_CHECKSUM is an attribute flag combined with the actual buffer type
+        // (usually SECBUFFER_DATA). Such buffers are signed but never encrypted.
+        let buffer_type = if raw_buffer.buffer_type & SecurityBufferType::ReadOnlyWithChecksum as u32 != 0 {
+            SecurityBufferType::ReadOnlyWithChecksum
+        } else {
             SecurityBufferType::from_u32(raw_buffer.buffer_type).ok_or_else(|| {
                 sspi::Error::new(
                     ErrorKind::InternalError,
                     format!("u32({}) to SecurityBufferType conversion error", raw_buffer.buffer_type),
                 )
-            })?,
-        )?;
+            })?
+        };
+        let buf = SecurityBuffer::with_security_buffer_type(buffer_type)?;
 
         buffers.push(if let SecurityBuffer::Missing(_) = buf {
             // https://learn.microsoft.com/en-us/windows/win32/api/sspi/ns-sspi-secbuffer
@@ -392,7 +446,13 @@ unsafe fn copy_decrypted_buffers(to_buffers: PSecBuffer, from_buffers: Vec<Secur
         // So, we don't need to copy any data and we skip it.
         //
         // The `SECBUFFER_STREAM` usage example: https://learn.microsoft.com/en-us/windows/win32/secauthn/sspi-kerberos-interoperability-with-gssapi
-        if matches!(from_buffer, SecurityBuffer::Stream(_)) {
+        //
+        // The `SECBUFFER_READONLY_WITH_CHECKSUM` buffers are never modified, and their original buffer type
+        // contains the attribute flag, so we skip them too.
+        if matches!(
+            from_buffer,
+            SecurityBuffer::Stream(_) | SecurityBuffer::ReadOnlyWithChecksum(_)
+        ) {
             continue;
         }
 
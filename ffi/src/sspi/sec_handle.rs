@@ -11,7 +11,10 @@ use sspi::credssp::sspi_cred_ssp;
 use sspi::credssp::sspi_cred_ssp::SspiCredSsp;
 use sspi::credssp::SspiContext;
 use sspi::kerberos::config::KerberosConfig;
+use sspi::kerberos::kdc_certificate::KdcTrustStore;
 use sspi::ntlm::NtlmConfig;
+#[cfg(feature = "scard")]
+use sspi::SmartCardContextProvider;
 use sspi::{
     kerberos, negotiate, ntlm, pku2u, CertContext, ClientRequestFlags, ConnectionInfo, Credentials, CredentialsBuffers,
     DataRepresentation, Error, ErrorKind, Kerberos, Negotiate, NegotiateConfig, Ntlm, PackageInfo, Result, Secret,
@@ -23,6 +26,9 @@ use windows_sys::Win32::Security::Cryptography::{
     CERT_STORE_CREATE_NEW_FLAG, CERT_STORE_PROV_MEMORY,
 };
 
+#[cfg(feature = "scard")]
+use winscard::winscard::{ScardScope, WinScardContext};
+
 cfg_if::cfg_if! {
     if #[cfg(target_os = "windows")] {
         use symbol_rename_macro::rename_symbol;
@@ -42,6 +48,8 @@ use super::sspi_data_types::{
 };
 use super::utils::{hostname, transform_credentials_handle};
 use crate::utils::{c_w_str_to_string, into_raw_ptr};
+#[cfg(feature = "scard")]
+use crate::winscard::system_scard::SystemScardContext;
 
 pub const SECPKG_NEGOTIATION_COMPLETE: u32 = 0;
 pub const SECPKG_NEGOTIATION_OPTIMISTIC: u32 = 1;
@@ -152,6 +160,16 @@ impl Sspi for SspiHandle {
         self.sspi_context.lock()?.decrypt_message(message, sequence_number)
     }
 
+    fn make_signature(&mut self, flags: u32, message: &mut [sspi::SecurityBuffer], sequence_number: u32) -> Result<()> {
+        self.sspi_context
+            .lock()?
+            .make_signature(flags, message, sequence_number)
+    }
+
+    fn verify_signature(&mut self, message: &[sspi::SecurityBuffer], sequence_number: u32) -> Result<u32> {
+        self.sspi_context.lock()?.verify_signature(message, sequence_number)
+    }
+
     fn query_context_sizes(&mut self) -> Result<sspi::ContextSizes> {
         self.sspi_context.lock()?.query_context_sizes()
     }
@@ -201,24 +219,43 @@ pub struct CredentialsHandle {
 
 fn create_negotiate_context(attributes: &CredentialsAttributes) -> Result<Negotiate> {
     let client_computer_name = attributes.hostname()?;
+    let kdc_trust_store = KdcTrustStore::from_env()?;
+
+    let mut negotiate_config = if let Some(kdc_url) = attributes.kdc_url() {
+        let mut kerberos_config = KerberosConfig::new(&kdc_url, client_computer_name.clone());
+        kerberos_config.kdc_trust_store = kdc_trust_store.clone();
+        #[cfg(feature = "scard")]
+        {
+            kerberos_config.scard_context = Some(system_scard_context());
+        }
 
-    if let Some(kdc_url) = attributes.kdc_url() {
-        let kerberos_config = KerberosConfig::new(&kdc_url, client_computer_name.clone());
-        let negotiate_config = NegotiateConfig::new(
+        NegotiateConfig::new(
             Box::new(kerberos_config),
             attributes.package_list.clone(),
             client_computer_name,
-        );
-
-        Negotiate::new(negotiate_config)
+        )
     } else {
-        let negotiate_config = NegotiateConfig {
-            protocol_config: Box::new(NtlmConfig::new(client_computer_name.clone())),
-            package_list: attributes.package_list.clone(),
+        NegotiateConfig::new(
+            Box::new(NtlmConfig::new(client_computer_name.clone())),
+            attributes.package_list.clone(),
             client_computer_name,
-        };
-        Negotiate::new(negotiate_config)
+        )
+    };
+    negotiate_config.kdc_trust_store = kdc_trust_store;
+    #[cfg(feature = "scard")]
+    {
+        negotiate_config.scard_context = Some(system_scard_context());
     }
+
+    Negotiate::new(negotiate_config)
+}
+
+/// Smart card context backed by the system WinSCard API (pcsc-lite on non-Windows systems).
+#[cfg(feature = "scard")]
+fn system_scard_context() -> SmartCardContextProvider {
+    SmartCardContextProvider::new(|| {
+        Ok(Box::new(SystemScardContext::establish(ScardScope::User)?) as Box<dyn WinScardContext>)
+    })
 }
 
 #[instrument(ret)]
@@ -262,14 +299,26 @@ pub(crate) unsafe fn p_ctxt_handle_to_sspi_context(
                 let client_computer_name = attributes.hostname()?;
 
                 if let Some(kdc_url) = attributes.kdc_url() {
-                    SspiContext::Kerberos(Kerberos::new_client_from_config(KerberosConfig::new(
-                        &kdc_url,
-                        client_computer_name,
-                    ))?)
+                    let mut krb_config = KerberosConfig::new(&kdc_url, client_computer_name);
+                    krb_config.kdc_trust_store = KdcTrustStore::from_env()?;
+                    #[cfg(feature = "scard")]
+                    {
+                        krb_config.scard_context = Some(system_scard_context());
+                    }
+                    SspiContext::Kerberos(Kerberos::new_client_from_config(krb_config)?)
                 } else {
                     let krb_config = KerberosConfig {
                         client_computer_name: Some(client_computer_name),
                         kdc_url: None,
+                        server_properties: None,
+                        ticket_cache: None,
+                        allow_rc4_hmac: false,
+                        fast: None,
+                        pkinit: Default::default(),
+                        kdc_trust_store: KdcTrustStore::from_env()?,
+                        allow_unvalidated_kdc_certificate: false,
+                        #[cfg(feature = "scard")]
+                        scard_context: Some(system_scard_context()),
                     };
                     SspiContext::Kerberos(Kerberos::new_client_from_config(krb_config)?)
                 }
@@ -1082,13 +1131,25 @@ pub unsafe extern "system" fn ChangeAccountPasswordA(
                     protocol_config: Box::new(NtlmConfig::new(try_execute!(hostname()))),
                     package_list: None,
                     client_computer_name: try_execute!(hostname()),
+                    kdc_trust_store: try_execute!(KdcTrustStore::from_env()),
+                    #[cfg(feature = "scard")]
+                    scard_context: None,
                 };
                 SspiContext::Negotiate(try_execute!(Negotiate::new(negotiate_config)))
             },
             kerberos::PKG_NAME => {
                 let krb_config = KerberosConfig{
                     client_computer_name:Some(try_execute!(hostname())),
-                    kdc_url:None
+                    kdc_url:None,
+                    server_properties:None,
+                    ticket_cache:None,
+                    allow_rc4_hmac:false,
+                    fast:None,
+                    pkinit:Default::default(),
+                    kdc_trust_store:try_execute!(KdcTrustStore::from_env()),
+                    allow_unvalidated_kdc_certificate:false,
+                    #[cfg(feature = "scard")]
+                    scard_context:None,
                 };
                 SspiContext::Kerberos(try_execute!(Kerberos::new_client_from_config(
                     krb_config
@@ -1235,12 +1296,15 @@ mod tests {
     use std::ptr::{null, null_mut};
 
     use libc::c_void;
+    use sspi::credssp::SspiContext;
+    use sspi::kerberos::kdc_certificate::SSPI_KDC_TRUST_STORE_ENV;
 
     use crate::sspi::common::{DeleteSecurityContext, FreeContextBuffer, FreeCredentialsHandle};
+    use crate::sspi::credentials_attributes::CredentialsAttributes;
     use crate::sspi::sec_buffer::{SecBuffer, SecBufferDesc};
     use crate::sspi::sec_handle::{
-        AcquireCredentialsHandleA, AcquireCredentialsHandleW, InitializeSecurityContextA, InitializeSecurityContextW,
-        SecHandle,
+        p_ctxt_handle_to_sspi_context, AcquireCredentialsHandleA, AcquireCredentialsHandleW,
+        InitializeSecurityContextA, InitializeSecurityContextW, SecHandle,
     };
     use crate::sspi::sec_pkg_info::{
         EnumerateSecurityPackagesA, EnumerateSecurityPackagesW, PSecPkgInfoA, PSecPkgInfoW, QuerySecurityPackageInfoA,
@@ -1728,4 +1792,45 @@ mod tests {
         let status = unsafe { FreeCredentialsHandle(&mut cred_handle) };
         assert_eq!(status, 0);
     }
+
+    #[test]
+    fn kerberos_context_reads_kdc_trust_store_from_env() {
+        let kdc_trust_store_is_set = || {
+            let attributes = CredentialsAttributes {
+                workstation: Some("workstation".into()),
+                ..Default::default()
+            };
+            let mut sec_context = SecHandle {
+                dw_lower: 0,
+                dw_upper: 0,
+            };
+            let mut context: *mut SecHandle = &mut sec_context;
+
+            let sspi_handle =
+                unsafe { p_ctxt_handle_to_sspi_context(&mut context, Some(sspi::kerberos::PKG_NAME), &attributes) }
+                    .unwrap();
+            let is_set = match &*unsafe { &*sspi_handle }.sspi_context.lock().unwrap() {
+                SspiContext::Kerberos(kerberos) => kerberos.config().kdc_trust_store.is_some(),
+                _ => panic!("Kerberos context is expected"),
+            };
+
+            let status = unsafe { DeleteSecurityContext(&mut sec_context) };
+            assert_eq!(status, 0);
+
+            is_set
+        };
+
+        let path = std::env::temp_dir().join(format!("sspi_ffi_kdc_trust_store_test_{}.pem", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+
+        std::env::remove_var(SSPI_KDC_TRUST_STORE_ENV);
+        assert!(!kdc_trust_store_is_set());
+
+        std::env::set_var(SSPI_KDC_TRUST_STORE_ENV, &path);
+        let is_set = kdc_trust_store_is_set();
+        std::env::remove_var(SSPI_KDC_TRUST_STORE_ENV);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(is_set);
+    }
 }
@@ -13,7 +13,7 @@ pub mod pcsc_lite;
 pub mod scard;
 pub mod scard_context;
 mod scard_handle;
-mod system_scard;
+pub(crate) mod system_scard;
 
 // The constants below are not documented anywhere and were discovered during debugging.
 // Related example: https://github.com/bluetech/pcsc-rust/blob/b397cc8e3834a1dc791631105f37f34d321c8696/pcsc/src/lib.rs#L605-L613
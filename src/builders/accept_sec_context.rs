@@ -248,6 +248,28 @@ impl<'b, 'a: 'b, CredsHandle> FilledAcceptSecurityContext<'a, CredsHandle> {
             input: self.input,
         }
     }
+
+    /// Transforms the builder into new one with the other input and output buffers.
+    /// Useful when the security package unwraps the token and passes it into the inner security package.
+    pub(crate) fn buffers_transform(
+        self,
+        input: &'b mut [OwnedSecurityBuffer],
+        output: &'b mut [OwnedSecurityBuffer],
+    ) -> FilledAcceptSecurityContext<'b, CredsHandle> {
+        AcceptSecurityContext {
+            phantom_creds_use_set: PhantomData,
+            phantom_context_req_set: PhantomData,
+            phantom_data_repr_set: PhantomData,
+            phantom_output_set: PhantomData,
+
+            credentials_handle: self.credentials_handle,
+            context_requirements: self.context_requirements,
+            target_data_representation: self.target_data_representation,
+
+            output,
+            input: Some(input),
+        }
+    }
 }
 
 impl<'a, CredsHandle> FilledAcceptSecurityContext<'a, CredsHandle> {
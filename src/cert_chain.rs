@@ -0,0 +1,209 @@
+//! Certificate chain validation shared by the PKINIT KDC certificate and PKU2U client certificate checks.
+
+use picky::signature::SignatureAlgorithm;
+use picky_asn1_x509::signed_data::{CertificateChoices, SignedData};
+use picky_asn1_x509::signer_info::SignerIdentifier;
+use picky_asn1_x509::{AlgorithmIdentifier, Certificate, ExtensionView, Time};
+use time::OffsetDateTime;
+
+use crate::{Error, ErrorKind, Result};
+
+/// Error kinds reported when the certificate chain is invalid.
+///
+/// PKINIT and PKU2U report different errors for the same failures.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChainErrorKinds {
+    /// The chain can not be built up to a trust anchor.
+    pub untrusted: ErrorKind,
+    /// The chain ends with a self-signed certificate that is not a trust anchor.
+    pub untrusted_root: ErrorKind,
+    /// A certificate of the chain is not yet valid or has expired.
+    pub expired: ErrorKind,
+}
+
+/// Decodes the certificates sent in the `signed_data`.
+pub(crate) fn signed_data_certificates(signed_data: &SignedData, error_kind: ErrorKind) -> Result<Vec<Certificate>> {
+    signed_data
+        .certificates
+        .0
+         .0
+        .iter()
+        .map(|certificate| match certificate {
+            CertificateChoices::Certificate(cert) => Ok(picky_asn1_der::from_bytes::<Certificate>(&cert.0)?),
+            cert => {
+                error!(?cert, "Peer sent unsupported certificate format");
+
+                Err(Error::new(error_kind, "Received unknown certificate format"))
+            }
+        })
+        .collect()
+}
+
+/// Finds the certificate that signed the `signed_data` among the `certificates`.
+pub(crate) fn find_signer_certificate<'a>(
+    signed_data: &SignedData,
+    certificates: &'a [Certificate],
+    error_kind: ErrorKind,
+) -> Result<&'a Certificate> {
+    let signer_info = signed_data
+        .signers_infos
+        .0
+         .0
+        .first()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Missing signers_infos in signed data"))?;
+    let signer = match &signer_info.sid {
+        SignerIdentifier::IssuerAndSerialNumber(signer) => signer,
+        SignerIdentifier::SubjectKeyIdentifier(_) => {
+            return Err(Error::new(
+                ErrorKind::OperationNotSupported,
+                "Signer identified by the subject key identifier is not supported",
+            ))
+        }
+    };
+
+    certificates
+        .iter()
+        .find(|cert| {
+            cert.tbs_certificate.issuer == signer.issuer && cert.tbs_certificate.serial_number == signer.serial_number.0
+        })
+        .ok_or_else(|| Error::new(error_kind, "Certificate of the signer is not present"))
+}
+
+/// Builds the chain from the `certificate` up to one of the `trust_anchors`.
+///
+/// The issuers are looked up among the `intermediates`. Every certificate of the chain, including the trust anchor,
+/// must be valid at the moment, and every intermediate issuer must be a certification authority. The signature of
+/// every certificate is verified, and `validate_issued` is called for every certificate and its issuer
+/// (e.g. to check the revocation lists).
+pub(crate) fn build_certificate_chain(
+    certificate: &Certificate,
+    intermediates: &[&Certificate],
+    trust_anchors: &[Certificate],
+    error_kinds: ChainErrorKinds,
+    mut validate_issued: impl FnMut(&Certificate, &Certificate) -> Result<()>,
+) -> Result<()> {
+    let now = OffsetDateTime::now_utc();
+
+    let mut current = certificate;
+    // every certificate can appear in the chain only once
+    for _ in 0..=intermediates.len() {
+        validate_certificate_validity(current, now, error_kinds.expired)?;
+
+        if let Some(trust_anchor) = trust_anchors.iter().find(|trust_anchor| {
+            trust_anchor.tbs_certificate.subject == current.tbs_certificate.issuer
+                && validate_certificate_signature(current, trust_anchor, error_kinds.untrusted).is_ok()
+        }) {
+            validate_certificate_validity(trust_anchor, now, error_kinds.expired)?;
+            validate_issued(current, trust_anchor)?;
+
+            return Ok(());
+        }
+
+        let issuer = intermediates
+            .iter()
+            .copied()
+            .find(|cert| cert.tbs_certificate.subject == current.tbs_certificate.issuer && *cert != current)
+            .ok_or_else(|| {
+                if current.tbs_certificate.subject == current.tbs_certificate.issuer {
+                    Error::new(error_kinds.untrusted_root, "Certificate chains to an untrusted root")
+                } else {
+                    Error::new(
+                        error_kinds.untrusted,
+                        "Certificate is not issued by a trusted certification authority",
+                    )
+                }
+            })?;
+
+        if !is_certification_authority(issuer) {
+            return Err(Error::new(
+                error_kinds.untrusted,
+                "Issuer of the certificate is not a certification authority",
+            ));
+        }
+        validate_certificate_signature(current, issuer, error_kinds.untrusted)?;
+        validate_issued(current, issuer)?;
+
+        current = issuer;
+    }
+
+    Err(Error::new(error_kinds.untrusted, "Certificate chain contains a loop"))
+}
+
+/// Verifies the `signature` of the `data` using the public key of the `issuer` certificate.
+pub(crate) fn verify_signature(
+    signature_algorithm: &AlgorithmIdentifier,
+    data: &[u8],
+    signature: &[u8],
+    issuer: &Certificate,
+) -> Result<()> {
+    let signature_algorithm = SignatureAlgorithm::from_algorithm_identifier(signature_algorithm).map_err(|err| {
+        Error::new(
+            ErrorKind::OperationNotSupported,
+            format!("Unsupported signature algorithm: {:?}", err),
+        )
+    })?;
+
+    signature_algorithm
+        .verify(
+            &issuer.tbs_certificate.subject_public_key_info.clone().into(),
+            data,
+            signature,
+        )
+        .map_err(|_| Error::new(ErrorKind::MessageAltered, "Invalid signature"))
+}
+
+fn is_certification_authority(certificate: &Certificate) -> bool {
+    certificate
+        .extensions()
+        .iter()
+        .any(|extension| match extension.extn_value() {
+            ExtensionView::BasicConstraints(constraints) => constraints.ca() == Some(true),
+            _ => false,
+        })
+}
+
+fn validate_certificate_validity(certificate: &Certificate, now: OffsetDateTime, error_kind: ErrorKind) -> Result<()> {
+    let validity = &certificate.tbs_certificate.validity;
+
+    if time_to_date(&validity.not_before)? > now {
+        return Err(Error::new(error_kind, "Certificate is not yet valid"));
+    }
+
+    if time_to_date(&validity.not_after)? < now {
+        return Err(Error::new(error_kind, "Certificate has expired"));
+    }
+
+    Ok(())
+}
+
+fn time_to_date(time: &Time) -> Result<OffsetDateTime> {
+    match time {
+        Time::Utc(time) => OffsetDateTime::try_from(time.0.clone()),
+        Time::Generalized(time) => OffsetDateTime::try_from(time.0.clone()),
+    }
+    .map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidToken,
+            format!("Invalid certificate validity: {:?}", err),
+        )
+    })
+}
+
+fn validate_certificate_signature(
+    certificate: &Certificate,
+    issuer: &Certificate,
+    error_kind: ErrorKind,
+) -> Result<()> {
+    verify_signature(
+        &certificate.signature_algorithm,
+        &picky_asn1_der::to_vec(&certificate.tbs_certificate)?,
+        certificate.signature_value.0.payload_view(),
+        issuer,
+    )
+    .map_err(|err| {
+        Error::new(
+            error_kind,
+            format!("Invalid certificate signature: {}", err.description),
+        )
+    })
+}
@@ -25,9 +25,9 @@ use crate::ntlm::{self, Ntlm, NtlmConfig, SIGNATURE_SIZE};
 use crate::pku2u::{self, Pku2u, Pku2uConfig};
 use crate::{
     negotiate, AcceptSecurityContextResult, AcquireCredentialsHandleResult, AuthIdentity, AuthIdentityBuffers,
-    CertContext, CertTrustStatus, ClientRequestFlags, ConnectionInfo, ContextNames, ContextSizes, CredentialUse,
-    Credentials, CredentialsBuffers, DataRepresentation, DecryptionFlags, EncryptionFlags, Error, ErrorKind,
-    FilledAcceptSecurityContext, FilledAcquireCredentialsHandle, FilledInitializeSecurityContext,
+    CertContext, CertTrustStatus, ClientRequestFlags, ConnectionInfo, ContextClientIdentity, ContextNames,
+    ContextSizes, CredentialUse, Credentials, CredentialsBuffers, DataRepresentation, DecryptionFlags, EncryptionFlags,
+    Error, ErrorKind, FilledAcceptSecurityContext, FilledAcquireCredentialsHandle, FilledInitializeSecurityContext,
     InitializeSecurityContextResult, Negotiate, NegotiateConfig, OwnedSecurityBuffer, PackageInfo, SecurityBuffer,
     SecurityBufferType, SecurityStatus, ServerRequestFlags, Sspi, SspiEx, SspiImpl, StreamSizes, Username,
 };
@@ -432,6 +432,20 @@ impl<C: CredentialsProxy<AuthenticationData = AuthIdentity>> CredSspServer<C> {
         })
     }
 
+    /// Returns the identity of the client authenticated by the underlying security package.
+    pub fn query_context_client_identity(&mut self) -> crate::Result<ContextClientIdentity> {
+        self.context
+            .as_mut()
+            .ok_or_else(|| {
+                crate::Error::new(
+                    crate::ErrorKind::OutOfSequence,
+                    "Requested the client identity, but the authentication has not started",
+                )
+            })?
+            .sspi_context
+            .query_context_client_identity()
+    }
+
     #[allow(clippy::result_large_err)]
     #[instrument(fields(state = ?self.state), skip_all)]
     pub fn process(&mut self, mut ts_request: TsRequest) -> Result<ServerState, ServerError> {
@@ -532,33 +546,43 @@ impl<C: CredentialsProxy<AuthenticationData = AuthIdentity>> CredSspServer<C> {
                         ts_request.nego_tokens = Some(output_token.remove(0).buffer);
                     }
                     AcceptSecurityContextResult {
-                        status: SecurityStatus::CompleteNeeded,
+                        status: status @ (SecurityStatus::CompleteNeeded | SecurityStatus::Ok),
                         ..
                     } => {
-                        let ContextNames { username } = try_cred_ssp_server!(
-                            self.context.as_mut().unwrap().sspi_context.query_context_names(),
-                            ts_request
-                        );
-                        let auth_data = try_cred_ssp_server!(
-                            self.credentials
-                                .auth_data_by_user(&username)
-                                .map_err(|e| crate::Error::new(crate::ErrorKind::LogonDenied, e.to_string())),
-                            ts_request
-                        );
-                        try_cred_ssp_server!(
-                            self.context
-                                .as_mut()
-                                .unwrap()
-                                .sspi_context
-                                .custom_set_auth_identity(Credentials::AuthIdentity(auth_data)),
-                            ts_request
-                        );
+                        // The Kerberos acceptor validates the client's ticket using the service keys,
+                        // so the user credentials are needed only for NTLM.
+                        if status == SecurityStatus::CompleteNeeded {
+                            let ContextNames { username } = try_cred_ssp_server!(
+                                self.context.as_mut().unwrap().sspi_context.query_context_names(),
+                                ts_request
+                            );
+                            let auth_data = try_cred_ssp_server!(
+                                self.credentials
+                                    .auth_data_by_user(&username)
+                                    .map_err(|e| crate::Error::new(crate::ErrorKind::LogonDenied, e.to_string())),
+                                ts_request
+                            );
+                            try_cred_ssp_server!(
+                                self.context
+                                    .as_mut()
+                                    .unwrap()
+                                    .sspi_context
+                                    .custom_set_auth_identity(Credentials::AuthIdentity(auth_data)),
+                                ts_request
+                            );
+
+                            try_cred_ssp_server!(
+                                self.context.as_mut().unwrap().sspi_context.complete_auth_token(&mut []),
+                                ts_request
+                            );
+                        }
 
-                        try_cred_ssp_server!(
-                            self.context.as_mut().unwrap().sspi_context.complete_auth_token(&mut []),
-                            ts_request
-                        );
-                        ts_request.nego_tokens = None;
+                        let output_token = output_token.remove(0).buffer;
+                        ts_request.nego_tokens = if output_token.is_empty() {
+                            None
+                        } else {
+                            Some(output_token)
+                        };
 
                         let pub_key_auth = try_cred_ssp_server!(
                             ts_request.pub_key_auth.take().ok_or_else(|| {
@@ -849,6 +873,35 @@ impl Sspi for SspiContext {
         }
     }
 
+    #[instrument(ret, fields(security_package = self.package_name()), skip(self))]
+    fn make_signature(
+        &mut self,
+        flags: u32,
+        message: &mut [SecurityBuffer],
+        sequence_number: u32,
+    ) -> crate::Result<()> {
+        match self {
+            SspiContext::Ntlm(ntlm) => ntlm.make_signature(flags, message, sequence_number),
+            SspiContext::Kerberos(kerberos) => kerberos.make_signature(flags, message, sequence_number),
+            SspiContext::Negotiate(negotiate) => negotiate.make_signature(flags, message, sequence_number),
+            SspiContext::Pku2u(pku2u) => pku2u.make_signature(flags, message, sequence_number),
+            #[cfg(feature = "tsssp")]
+            SspiContext::CredSsp(credssp) => credssp.make_signature(flags, message, sequence_number),
+        }
+    }
+
+    #[instrument(ret, fields(security_package = self.package_name()), skip(self))]
+    fn verify_signature(&mut self, message: &[SecurityBuffer], sequence_number: u32) -> crate::Result<u32> {
+        match self {
+            SspiContext::Ntlm(ntlm) => ntlm.verify_signature(message, sequence_number),
+            SspiContext::Kerberos(kerberos) => kerberos.verify_signature(message, sequence_number),
+            SspiContext::Negotiate(negotiate) => negotiate.verify_signature(message, sequence_number),
+            SspiContext::Pku2u(pku2u) => pku2u.verify_signature(message, sequence_number),
+            #[cfg(feature = "tsssp")]
+            SspiContext::CredSsp(credssp) => credssp.verify_signature(message, sequence_number),
+        }
+    }
+
     #[instrument(ret, fields(security_package = self.package_name()), skip(self))]
     fn query_context_sizes(&mut self) -> crate::Result<ContextSizes> {
         match self {
@@ -873,6 +926,18 @@ impl Sspi for SspiContext {
         }
     }
 
+    #[instrument(ret, fields(security_package = self.package_name()), skip(self))]
+    fn query_context_client_identity(&mut self) -> crate::Result<ContextClientIdentity> {
+        match self {
+            SspiContext::Ntlm(ntlm) => ntlm.query_context_client_identity(),
+            SspiContext::Kerberos(kerberos) => kerberos.query_context_client_identity(),
+            SspiContext::Negotiate(negotiate) => negotiate.query_context_client_identity(),
+            SspiContext::Pku2u(pku2u) => pku2u.query_context_client_identity(),
+            #[cfg(feature = "tsssp")]
+            SspiContext::CredSsp(credssp) => credssp.query_context_client_identity(),
+        }
+    }
+
     #[instrument(ret, fields(security_package = self.package_name()), skip(self))]
     fn query_context_stream_sizes(&mut self) -> crate::Result<StreamSizes> {
         match self {
@@ -17,11 +17,11 @@ use crate::credssp::sspi_cred_ssp::tls_connection::{DecryptionResult, Decryption
 use crate::generator::{GeneratorChangePassword, GeneratorInitSecurityContext, YieldPointLocal};
 use crate::{
     builders, negotiate, AcquireCredentialsHandleResult, CertContext, CertEncodingType, CertTrustErrorStatus,
-    CertTrustInfoStatus, CertTrustStatus, ClientRequestFlags, ClientResponseFlags, ConnectionInfo, ContextNames,
-    ContextSizes, CredentialUse, Credentials, CredentialsBuffers, DataRepresentation, DecryptionFlags, EncryptionFlags,
-    Error, ErrorKind, InitializeSecurityContextResult, OwnedSecurityBuffer, PackageCapabilities, PackageInfo, Result,
-    SecurityBuffer, SecurityBufferType, SecurityPackageType, SecurityStatus, Sspi, SspiEx, SspiImpl, StreamSizes,
-    PACKAGE_ID_NONE,
+    CertTrustInfoStatus, CertTrustStatus, ClientRequestFlags, ClientResponseFlags, ConnectionInfo,
+    ContextClientIdentity, ContextNames, ContextSizes, CredentialUse, Credentials, CredentialsBuffers,
+    DataRepresentation, DecryptionFlags, EncryptionFlags, Error, ErrorKind, InitializeSecurityContextResult,
+    OwnedSecurityBuffer, PackageCapabilities, PackageInfo, Result, SecurityBuffer, SecurityBufferType,
+    SecurityPackageType, SecurityStatus, Sspi, SspiEx, SspiImpl, StreamSizes, PACKAGE_ID_NONE,
 };
 
 pub const PKG_NAME: &str = "CREDSSP";
@@ -229,6 +229,22 @@ impl Sspi for SspiCredSsp {
         }
     }
 
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip_all)]
+    fn make_signature(&mut self, _flags: u32, _message: &mut [SecurityBuffer], _sequence_number: u32) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::UnsupportedFunction,
+            "make_signature is not supported by the CredSsp security package",
+        ))
+    }
+
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip_all)]
+    fn verify_signature(&mut self, _message: &[SecurityBuffer], _sequence_number: u32) -> Result<u32> {
+        Err(Error::new(
+            ErrorKind::UnsupportedFunction,
+            "verify_signature is not supported by the CredSsp security package",
+        ))
+    }
+
     #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
     fn query_context_sizes(&mut self) -> Result<ContextSizes> {
         self.cred_ssp_context.sspi_context.query_context_sizes()
@@ -239,6 +255,11 @@ impl Sspi for SspiCredSsp {
         self.cred_ssp_context.sspi_context.query_context_names()
     }
 
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
+    fn query_context_client_identity(&mut self) -> Result<ContextClientIdentity> {
+        self.cred_ssp_context.sspi_context.query_context_client_identity()
+    }
+
     #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
     fn query_context_stream_sizes(&mut self) -> Result<StreamSizes> {
         self.tls_connection()?.stream_sizes()
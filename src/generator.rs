@@ -5,6 +5,7 @@ use std::task::{Context, Poll, Wake, Waker};
 
 use url::Url;
 
+use crate::kerberos::ccache::CCacheCredential;
 use crate::network_client::{NetworkClient, NetworkProtocol};
 use crate::{Error, InitializeSecurityContextResult};
 
@@ -186,6 +187,8 @@ pub type GeneratorInitSecurityContext<'a> =
 
 pub type GeneratorChangePassword<'a> = Generator<'a, NetworkRequest, crate::Result<Vec<u8>>, crate::Result<()>>;
 
+pub type GeneratorS4u<'a> = Generator<'a, NetworkRequest, crate::Result<Vec<u8>>, crate::Result<CCacheCredential>>;
+
 pub(crate) type YieldPointLocal = YieldPoint<NetworkRequest, crate::Result<Vec<u8>>>;
 
 impl<'a, YieldType, ResumeType, OutType, ErrorType> From<Result<OutType, ErrorType>>
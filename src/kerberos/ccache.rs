@@ -0,0 +1,855 @@
+//! MIT credential cache file format.
+//!
+//! [The ccache binary file format](https://web.mit.edu/kerberos/krb5-devel/doc/formats/ccache_file_format.html)
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use picky_asn1::bit_string::BitString;
+use picky_asn1::restricted_string::IA5String;
+use picky_asn1::wrapper::{Asn1SequenceOf, ExplicitContextTag0, ExplicitContextTag1, IntegerAsn1};
+use picky_krb::data_types::{KerberosStringAsn1, KerberosTime, PrincipalName, Realm, Ticket};
+use picky_krb::messages::{EncKdcRepPart, KdcRep};
+use time::OffsetDateTime;
+
+use crate::kerberos::cipher::CipherSuite;
+use crate::kerberos::data_types::KrbCredInfo;
+use crate::kerberos::utils::{integer_as_u32, write_private_file};
+use crate::{Error, ErrorKind, Result, Secret};
+
+/// The first byte of the ccache file.
+const CCACHE_FILE_FORMAT: u8 = 0x05;
+/// Version 3 has the same layout as version 4 but without the header.
+const CCACHE_VERSION_3: u8 = 0x03;
+const CCACHE_VERSION_4: u8 = 0x04;
+
+/// Tagged data used for the header fields, addresses, and authorization data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CCacheTaggedData {
+    pub tag: u16,
+    pub data: Vec<u8>,
+}
+
+/// ```not_rust
+/// principal {
+///     uint32_t name_type;           /* not present if version 0x0501 */
+///     uint32_t num_components;      /* sub 1 if version 0x501 */
+///     counted_octet_string realm;
+///     counted_octet_string components[num_components];
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CCachePrincipal {
+    pub name_type: u32,
+    pub realm: String,
+    pub components: Vec<String>,
+}
+
+impl CCachePrincipal {
+    pub fn from_principal_name(name: &PrincipalName, realm: &Realm) -> Result<Self> {
+        Ok(Self {
+            name_type: integer_as_u32(&name.name_type.0)?,
+            realm: realm.to_string(),
+            components: name.name_string.0 .0.iter().map(|name| name.to_string()).collect(),
+        })
+    }
+
+    pub fn to_principal_name(&self) -> Result<PrincipalName> {
+        Ok(PrincipalName {
+            name_type: ExplicitContextTag0::from(IntegerAsn1::from_bytes_be_unsigned(
+                self.name_type.to_be_bytes().to_vec(),
+            )),
+            name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(
+                self.components
+                    .iter()
+                    .map(|name| Ok(KerberosStringAsn1::from(IA5String::from_string(name.clone())?)))
+                    .collect::<Result<Vec<_>>>()?,
+            )),
+        })
+    }
+
+    pub fn to_realm(&self) -> Result<Realm> {
+        Ok(Realm::from(IA5String::from_string(self.realm.clone())?))
+    }
+
+    /// Returns the principal name in the `component1/component2@REALM` form.
+    pub fn principal_name(&self) -> String {
+        format!("{}@{}", self.components.join("/"), self.realm)
+    }
+
+    /// Checks if the principal has the given name in the given realm. The comparison is case-insensitive.
+    ///
+    /// The name may contain the realm suffix (e.g. `user@example.com`). Enterprise principals
+    /// are matched against the whole name.
+    pub fn matches(&self, name: &str, realm: &str) -> bool {
+        let short_name = match name.rsplit_once('@') {
+            Some((short_name, name_realm)) if name_realm.eq_ignore_ascii_case(realm) => short_name,
+            _ => name,
+        };
+        let components = self.components.join("/");
+
+        self.realm.eq_ignore_ascii_case(realm)
+            && (components.eq_ignore_ascii_case(short_name) || components.eq_ignore_ascii_case(name))
+    }
+
+    fn read(reader: &mut impl Read) -> Result<Self> {
+        let name_type = reader.read_u32::<BigEndian>()?;
+        let num_components = reader.read_u32::<BigEndian>()?;
+        let realm = String::from_utf8(read_data(reader)?)?;
+        let components = (0..num_components)
+            .map(|_| Ok(String::from_utf8(read_data(reader)?)?))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            name_type,
+            realm,
+            components,
+        })
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.name_type)?;
+        writer.write_u32::<BigEndian>(u32::try_from(self.components.len())?)?;
+        write_data(writer, self.realm.as_bytes())?;
+        for component in &self.components {
+            write_data(writer, component.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// ```not_rust
+/// credential {
+///     principal client;
+///     principal server;
+///     keyblock key;
+///     times    time;
+///     uint8_t  is_skey;            /* 1 if skey, 0 otherwise */
+///     uint32_t tktflags;           /* stored in reversed byte order */
+///     uint32_t num_address;
+///     address  addrs[num_address];
+///     uint32_t num_authdata;
+///     authdata authdata[num_authdata];
+///     counted_octet_string ticket;
+///     counted_octet_string second_ticket;
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CCacheCredential {
+    pub client: CCachePrincipal,
+    pub server: CCachePrincipal,
+    /// Kerberos encryption type of the session key.
+    pub key_type: u16,
+    pub key: Secret<Vec<u8>>,
+    /// Times in seconds since the Unix epoch.
+    pub auth_time: u32,
+    pub start_time: u32,
+    pub end_time: u32,
+    pub renew_till: u32,
+    /// Indicates that the ticket is encrypted in the session key of another ticket (user-to-user).
+    pub is_skey: bool,
+    pub ticket_flags: u32,
+    pub addresses: Vec<CCacheTaggedData>,
+    pub auth_data: Vec<CCacheTaggedData>,
+    /// DER-encoded ticket.
+    pub ticket: Vec<u8>,
+    /// DER-encoded second ticket. Empty if not present.
+    pub second_ticket: Vec<u8>,
+}
+
+fn kerberos_time_to_timestamp(time: &KerberosTime) -> Result<u32> {
+    let time = OffsetDateTime::try_from(time.0.clone())
+        .map_err(|err| Error::new(ErrorKind::InvalidToken, format!("Invalid Kerberos time: {:?}", err)))?;
+
+    Ok(u32::try_from(time.unix_timestamp())?)
+}
+
+impl CCacheCredential {
+    /// Creates the credential from the KDC reply and its decrypted part.
+    pub fn from_kdc_rep(kdc_rep: &KdcRep, enc_part: &EncKdcRepPart) -> Result<Self> {
+        let mut ticket_flags = [0; 4];
+        let flags = enc_part.flags.0 .0.payload_view();
+        let len = flags.len().min(ticket_flags.len());
+        ticket_flags[..len].copy_from_slice(&flags[..len]);
+
+        let auth_time = kerberos_time_to_timestamp(&enc_part.auth_time.0)?;
+
+        Ok(Self {
+            client: CCachePrincipal::from_principal_name(&kdc_rep.cname.0, &kdc_rep.crealm.0)?,
+            server: CCachePrincipal::from_principal_name(&enc_part.sname.0, &enc_part.srealm.0)?,
+            key_type: u16::try_from(integer_as_u32(&enc_part.key.0.key_type.0)?)?,
+            key: enc_part.key.0.key_value.0 .0.clone().into(),
+            auth_time,
+            start_time: enc_part
+                .start_time
+                .0
+                .as_ref()
+                .map(|start_time| kerberos_time_to_timestamp(&start_time.0))
+                .transpose()?
+                .unwrap_or(auth_time),
+            end_time: kerberos_time_to_timestamp(&enc_part.end_time.0)?,
+            renew_till: enc_part
+                .renew_till
+                .0
+                .as_ref()
+                .map(|renew_till| kerberos_time_to_timestamp(&renew_till.0))
+                .transpose()?
+                .unwrap_or_default(),
+            is_skey: false,
+            ticket_flags: u32::from_be_bytes(ticket_flags),
+            addresses: Vec::new(),
+            auth_data: Vec::new(),
+            ticket: picky_asn1_der::to_vec(&kdc_rep.ticket.0)?,
+            second_ticket: Vec::new(),
+        })
+    }
+
+    /// Creates the credential from the ticket and its description received in the `KRB-CRED` message.
+    pub(crate) fn from_krb_cred_info(ticket: &Ticket, info: &KrbCredInfo) -> Result<Self> {
+        let missing_field = |field| {
+            Error::new(
+                ErrorKind::InvalidToken,
+                format!("KrbCredInfo does not contain the {}", field),
+            )
+        };
+
+        let mut ticket_flags = [0; 4];
+        if let Some(flags) = info.flags.0.as_ref() {
+            let flags = flags.0 .0.payload_view();
+            let len = flags.len().min(ticket_flags.len());
+            ticket_flags[..len].copy_from_slice(&flags[..len]);
+        }
+
+        let optional_time = |time: Option<&KerberosTime>| time.map(kerberos_time_to_timestamp).transpose();
+
+        let end_time =
+            optional_time(info.end_time.0.as_ref().map(|time| &time.0))?.ok_or_else(|| missing_field("end time"))?;
+        let auth_time = optional_time(info.auth_time.0.as_ref().map(|time| &time.0))?.unwrap_or_default();
+
+        Ok(Self {
+            client: CCachePrincipal::from_principal_name(
+                &info.pname.0.as_ref().ok_or_else(|| missing_field("client name"))?.0,
+                &info.prealm.0.as_ref().ok_or_else(|| missing_field("client realm"))?.0,
+            )?,
+            server: CCachePrincipal::from_principal_name(
+                &info.sname.0.as_ref().ok_or_else(|| missing_field("service name"))?.0,
+                &info.srealm.0.as_ref().ok_or_else(|| missing_field("service realm"))?.0,
+            )?,
+            key_type: u16::try_from(integer_as_u32(&info.key.0.key_type.0)?)?,
+            key: info.key.0.key_value.0 .0.clone().into(),
+            auth_time,
+            start_time: optional_time(info.start_time.0.as_ref().map(|time| &time.0))?.unwrap_or(auth_time),
+            end_time,
+            renew_till: optional_time(info.renew_till.0.as_ref().map(|time| &time.0))?.unwrap_or_default(),
+            is_skey: false,
+            ticket_flags: u32::from_be_bytes(ticket_flags),
+            addresses: Vec::new(),
+            auth_data: Vec::new(),
+            ticket: picky_asn1_der::to_vec(ticket)?,
+            second_ticket: Vec::new(),
+        })
+    }
+
+    pub fn decode_ticket(&self) -> Result<Ticket> {
+        Ok(picky_asn1_der::from_bytes(&self.ticket)?)
+    }
+
+    pub fn encryption_type(&self) -> Result<CipherSuite> {
+        Ok(CipherSuite::try_from(usize::from(self.key_type))?)
+    }
+
+    pub fn ticket_flags(&self) -> BitString {
+        BitString::with_bytes(self.ticket_flags.to_be_bytes().to_vec())
+    }
+
+    /// Checks if the ticket is already valid and not expired at the given moment.
+    ///
+    /// Zero start time means that the ticket is valid since the authentication time.
+    pub fn is_valid_at(&self, time: OffsetDateTime) -> bool {
+        let now = time.unix_timestamp();
+
+        (self.start_time == 0 || i64::from(self.start_time) <= now) && i64::from(self.end_time) > now
+    }
+
+    fn read(reader: &mut impl Read) -> Result<Self> {
+        let client = CCachePrincipal::read(reader)?;
+        let server = CCachePrincipal::read(reader)?;
+
+        let key_type = reader.read_u16::<BigEndian>()?;
+        let key = read_data(reader)?;
+
+        let auth_time = reader.read_u32::<BigEndian>()?;
+        let start_time = reader.read_u32::<BigEndian>()?;
+        let end_time = reader.read_u32::<BigEndian>()?;
+        let renew_till = reader.read_u32::<BigEndian>()?;
+
+        let is_skey = reader.read_u8()? != 0;
+        let ticket_flags = reader.read_u32::<BigEndian>()?;
+
+        let addresses = read_tagged_data_list(reader)?;
+        let auth_data = read_tagged_data_list(reader)?;
+
+        let ticket = read_data(reader)?;
+        let second_ticket = read_data(reader)?;
+
+        Ok(Self {
+            client,
+            server,
+            key_type,
+            key: key.into(),
+            auth_time,
+            start_time,
+            end_time,
+            renew_till,
+            is_skey,
+            ticket_flags,
+            addresses,
+            auth_data,
+            ticket,
+            second_ticket,
+        })
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        self.client.write(writer)?;
+        self.server.write(writer)?;
+
+        writer.write_u16::<BigEndian>(self.key_type)?;
+        write_data(writer, self.key.as_ref())?;
+
+        writer.write_u32::<BigEndian>(self.auth_time)?;
+        writer.write_u32::<BigEndian>(self.start_time)?;
+        writer.write_u32::<BigEndian>(self.end_time)?;
+        writer.write_u32::<BigEndian>(self.renew_till)?;
+
+        writer.write_u8(self.is_skey.into())?;
+        writer.write_u32::<BigEndian>(self.ticket_flags)?;
+
+        write_tagged_data_list(writer, &self.addresses)?;
+        write_tagged_data_list(writer, &self.auth_data)?;
+
+        write_data(writer, &self.ticket)?;
+        write_data(writer, &self.second_ticket)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CCache {
+    /// Header fields (e.g. the KDC time offset). Always empty for the version 3 files.
+    pub header: Vec<CCacheTaggedData>,
+    pub default_principal: CCachePrincipal,
+    pub credentials: Vec<CCacheCredential>,
+}
+
+impl CCache {
+    pub fn new(default_principal: CCachePrincipal) -> Self {
+        Self {
+            header: Vec::new(),
+            default_principal,
+            credentials: Vec::new(),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the credential cache in the version 4 format.
+    ///
+    /// The cache contains session keys, so it is readable only by the owner (mode 0600 on Unix).
+    /// The data is written to a temporary file first and then renamed, so readers never see a partially written cache.
+    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        write_private_file(path.as_ref(), |writer| self.write(writer))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::read(data)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.write(&mut data)?;
+
+        Ok(data)
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<Self> {
+        let file_format = reader.read_u8()?;
+        let version = reader.read_u8()?;
+
+        if file_format != CCACHE_FILE_FORMAT || !matches!(version, CCACHE_VERSION_3 | CCACHE_VERSION_4) {
+            return Err(Error::new(
+                ErrorKind::InvalidParameter,
+                format!("Unsupported ccache version: 0x{:02x}{:02x}", file_format, version),
+            ));
+        }
+
+        let header = if version == CCACHE_VERSION_4 {
+            let header_len = reader.read_u16::<BigEndian>()?;
+            let mut header_data = vec![0; usize::from(header_len)];
+            reader.read_exact(&mut header_data)?;
+
+            let mut header_data = header_data.as_slice();
+            let mut header = Vec::new();
+            while !header_data.is_empty() {
+                let tag = header_data.read_u16::<BigEndian>()?;
+                let len = header_data.read_u16::<BigEndian>()?;
+                let mut data = vec![0; usize::from(len)];
+                header_data.read_exact(&mut data)?;
+
+                header.push(CCacheTaggedData { tag, data });
+            }
+
+            header
+        } else {
+            Vec::new()
+        };
+
+        let default_principal = CCachePrincipal::read(&mut reader)?;
+
+        let mut credentials = Vec::new();
+        loop {
+            // the credentials list is not prefixed by the count, so we read until the end of the file
+            let mut first_byte = [0];
+            if reader.read(&mut first_byte)? == 0 {
+                break;
+            }
+
+            credentials.push(CCacheCredential::read(&mut first_byte.chain(&mut reader))?);
+        }
+
+        Ok(Self {
+            header,
+            default_principal,
+            credentials,
+        })
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> Result<()> {
+        writer.write_u8(CCACHE_FILE_FORMAT)?;
+        writer.write_u8(CCACHE_VERSION_4)?;
+
+        let mut header = Vec::new();
+        for field in &self.header {
+            header.write_u16::<BigEndian>(field.tag)?;
+            header.write_u16::<BigEndian>(u16::try_from(field.data.len())?)?;
+            header.write_all(&field.data)?;
+        }
+        writer.write_u16::<BigEndian>(u16::try_from(header.len())?)?;
+        writer.write_all(&header)?;
+
+        self.default_principal.write(&mut writer)?;
+
+        for credential in &self.credentials {
+            credential.write(&mut writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the credential replacing the existing one with the same client and server.
+    pub fn store(&mut self, credential: CCacheCredential) {
+        self.credentials
+            .retain(|cred| cred.client != credential.client || cred.server != credential.server);
+        self.credentials.push(credential);
+    }
+}
+
+/// Environment variable with the default credential cache name.
+pub const KRB5CCNAME_ENV: &str = "KRB5CCNAME";
+
+type SharedCCache = Arc<Mutex<Option<CCache>>>;
+
+static MEMORY_CACHES: LazyLock<Mutex<HashMap<String, SharedCCache>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone)]
+enum TicketCacheStorage {
+    File(PathBuf),
+    Memory(SharedCCache),
+}
+
+/// Storage of the obtained Kerberos tickets.
+///
+/// The Kerberos client looks for a valid TGT or service ticket in the cache before contacting the KDC
+/// and stores newly obtained tickets in it. Clones share the same storage.
+#[derive(Debug, Clone)]
+pub struct TicketCache {
+    storage: TicketCacheStorage,
+}
+
+impl TicketCache {
+    /// Creates a new empty in-memory cache.
+    pub fn memory() -> Self {
+        Self {
+            storage: TicketCacheStorage::Memory(Arc::new(Mutex::new(None))),
+        }
+    }
+
+    /// Uses the credential cache file. The file is created when the first ticket is stored.
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Self {
+            storage: TicketCacheStorage::File(path.into()),
+        }
+    }
+
+    /// Opens the cache by its name in the `TYPE:residual` form.
+    ///
+    /// Supported types are `FILE` and `MEMORY`. Names without the type are treated as file paths.
+    /// `MEMORY` caches with the same name are shared within the process.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.split_once(':') {
+            Some(("FILE", path)) => Ok(Self::file(path)),
+            Some(("MEMORY", name)) => {
+                let mut caches = MEMORY_CACHES
+                    .lock()
+                    .map_err(|_| Error::new(ErrorKind::InternalError, "Memory ccache registry is poisoned"))?;
+
+                Ok(Self {
+                    storage: TicketCacheStorage::Memory(Arc::clone(caches.entry(name.to_owned()).or_default())),
+                })
+            }
+            // Windows paths like `C:\Users\...` have the colon after the drive letter
+            Some((cache_type, _)) if cache_type.len() > 1 => Err(Error::new(
+                ErrorKind::UnsupportedFunction,
+                format!("Unsupported ccache type: {}", cache_type),
+            )),
+            _ => Ok(Self::file(name)),
+        }
+    }
+
+    /// Opens the cache specified by the `KRB5CCNAME` environment variable.
+    pub fn from_env() -> Result<Self> {
+        let name = env::var(KRB5CCNAME_ENV).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidParameter,
+                format!("The {} environment variable is not set", KRB5CCNAME_ENV),
+            )
+        })?;
+
+        Self::from_name(&name)
+    }
+
+    /// Returns the cache content or `None` if the cache is empty.
+    pub fn load(&self) -> Result<Option<CCache>> {
+        match &self.storage {
+            TicketCacheStorage::File(path) => {
+                if !path.exists() {
+                    return Ok(None);
+                }
+
+                CCache::from_file(path).map(Some)
+            }
+            TicketCacheStorage::Memory(ccache) => Ok(ccache
+                .lock()
+                .map_err(|_| Error::new(ErrorKind::InternalError, "Memory ccache is poisoned"))?
+                .clone()),
+        }
+    }
+
+    /// Stores the credential replacing the existing one for the same client and server.
+    ///
+    /// The client of the first stored credential becomes the default principal of a new cache.
+    pub fn store(&self, credential: CCacheCredential) -> Result<()> {
+        match &self.storage {
+            TicketCacheStorage::File(path) => {
+                let mut ccache = self.load()?.unwrap_or_else(|| CCache::new(credential.client.clone()));
+                ccache.store(credential);
+
+                ccache.to_file(path)
+            }
+            TicketCacheStorage::Memory(ccache) => {
+                let mut ccache = ccache
+                    .lock()
+                    .map_err(|_| Error::new(ErrorKind::InternalError, "Memory ccache is poisoned"))?;
+
+                ccache
+                    .get_or_insert_with(|| CCache::new(credential.client.clone()))
+                    .store(credential);
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Looks for a non-expired ticket of the client for the service.
+    ///
+    /// `service_principal` is the service principal name without the realm (e.g. `krbtgt/EXAMPLE.COM`).
+    pub fn lookup(&self, client_name: &str, realm: &str, service_principal: &str) -> Result<Option<CCacheCredential>> {
+        let now = OffsetDateTime::now_utc();
+
+        Ok(self.load()?.and_then(|ccache| {
+            ccache.credentials.into_iter().rev().find(|credential| {
+                credential.client.matches(client_name, realm)
+                    && credential
+                        .server
+                        .components
+                        .join("/")
+                        .eq_ignore_ascii_case(service_principal)
+                    && credential.is_valid_at(now)
+            })
+        }))
+    }
+}
+
+fn read_data(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let len = reader.read_u32::<BigEndian>()?;
+
+    // The length comes from the untrusted input, so the buffer grows only as much data as is actually available.
+    let mut data = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut data)?;
+    if data.len() != usize::try_from(len)? {
+        return Err(Error::new(
+            ErrorKind::InvalidParameter,
+            format!("Ccache data is truncated: expected {} bytes, got {}", len, data.len()),
+        ));
+    }
+
+    Ok(data)
+}
+
+fn write_data(writer: &mut impl Write, data: &[u8]) -> Result<()> {
+    writer.write_u32::<BigEndian>(u32::try_from(data.len())?)?;
+    writer.write_all(data)?;
+
+    Ok(())
+}
+
+fn read_tagged_data_list(reader: &mut impl Read) -> Result<Vec<CCacheTaggedData>> {
+    let count = reader.read_u32::<BigEndian>()?;
+
+    (0..count)
+        .map(|_| {
+            Ok(CCacheTaggedData {
+                tag: reader.read_u16::<BigEndian>()?,
+                data: read_data(reader)?,
+            })
+        })
+        .collect()
+}
+
+fn write_tagged_data_list(writer: &mut impl Write, list: &[CCacheTaggedData]) -> Result<()> {
+    writer.write_u32::<BigEndian>(u32::try_from(list.len())?)?;
+
+    for item in list {
+        writer.write_u16::<BigEndian>(item.tag)?;
+        write_data(writer, &item.data)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use time::OffsetDateTime;
+
+    use super::{CCache, CCacheCredential, CCachePrincipal, CCacheTaggedData, TicketCache};
+
+    fn principal(components: &[&str]) -> CCachePrincipal {
+        CCachePrincipal {
+            name_type: 1,
+            realm: "EXAMPLE.COM".to_owned(),
+            components: components.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    fn credential(server: &[&str], end_time: u32) -> CCacheCredential {
+        CCacheCredential {
+            client: principal(&["user"]),
+            server: principal(server),
+            key_type: 18,
+            key: vec![0x11; 32].into(),
+            auth_time: 1_700_000_000,
+            start_time: 1_700_000_000,
+            end_time,
+            renew_till: 0,
+            is_skey: false,
+            ticket_flags: 0x40e10000,
+            addresses: Vec::new(),
+            auth_data: vec![CCacheTaggedData {
+                tag: 1,
+                data: vec![1, 2, 3],
+            }],
+            ticket: vec![0x61, 0x03, 0x02, 0x01, 0x05],
+            second_ticket: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn write_read_roundtrip() {
+        let mut ccache = CCache::new(principal(&["user"]));
+        ccache.header.push(CCacheTaggedData {
+            tag: 1,
+            data: vec![0, 0, 0, 0, 0, 0, 0, 0],
+        });
+        ccache.store(credential(&["krbtgt", "EXAMPLE.COM"], 1_700_036_000));
+        ccache.store(credential(&["HTTP", "www.example.com"], 1_700_036_000));
+
+        let parsed = CCache::from_bytes(&ccache.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(parsed, ccache);
+    }
+
+    #[test]
+    fn read_v3() {
+        let mut data = ccache_v4_without_header();
+        data[1] = 0x03;
+        // remove the empty header
+        data.drain(2..4);
+
+        let ccache = CCache::from_bytes(&data).unwrap();
+
+        assert!(ccache.header.is_empty());
+        assert_eq!(ccache.default_principal.principal_name(), "user@EXAMPLE.COM");
+        assert_eq!(ccache.credentials.len(), 1);
+    }
+
+    fn ccache_v4_without_header() -> Vec<u8> {
+        let mut ccache = CCache::new(principal(&["user"]));
+        ccache.store(credential(&["krbtgt", "EXAMPLE.COM"], 1_700_036_000));
+
+        ccache.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn store_replaces_credential() {
+        let mut ccache = CCache::new(principal(&["user"]));
+        ccache.store(credential(&["krbtgt", "EXAMPLE.COM"], 1));
+        ccache.store(credential(&["krbtgt", "EXAMPLE.COM"], 2));
+
+        assert_eq!(ccache.credentials.len(), 1);
+        assert_eq!(ccache.credentials[0].end_time, 2);
+    }
+
+    #[test]
+    fn principal_matches() {
+        let principal = principal(&["user"]);
+
+        assert!(principal.matches("user", "EXAMPLE.COM"));
+        assert!(principal.matches("USER", "example.com"));
+        assert!(principal.matches("user@example.com", "EXAMPLE.COM"));
+        assert!(!principal.matches("user", "OTHER.COM"));
+        assert!(!principal.matches("admin", "EXAMPLE.COM"));
+    }
+
+    #[test]
+    fn invalid_version() {
+        assert!(CCache::from_bytes(&[0x05, 0x02]).is_err());
+        assert!(CCache::from_bytes(&[0x04, 0x04]).is_err());
+    }
+
+    fn valid_end_time() -> u32 {
+        u32::try_from(OffsetDateTime::now_utc().unix_timestamp()).unwrap() + 3600
+    }
+
+    #[test]
+    fn memory_ticket_cache() {
+        let ticket_cache = TicketCache::memory();
+        assert!(ticket_cache
+            .lookup("user", "EXAMPLE.COM", "krbtgt/EXAMPLE.COM")
+            .unwrap()
+            .is_none());
+
+        ticket_cache
+            .store(credential(&["krbtgt", "EXAMPLE.COM"], valid_end_time()))
+            .unwrap();
+        ticket_cache.store(credential(&["HTTP", "www.example.com"], 1)).unwrap();
+
+        let shared = ticket_cache.clone();
+        assert!(shared
+            .lookup("user@example.com", "EXAMPLE.COM", "krbtgt/EXAMPLE.COM")
+            .unwrap()
+            .is_some());
+        // expired
+        assert!(shared
+            .lookup("user", "EXAMPLE.COM", "HTTP/www.example.com")
+            .unwrap()
+            .is_none());
+        assert!(shared
+            .lookup("admin", "EXAMPLE.COM", "krbtgt/EXAMPLE.COM")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn named_memory_ticket_caches_are_shared() {
+        TicketCache::from_name("MEMORY:named_memory_ticket_caches_are_shared")
+            .unwrap()
+            .store(credential(&["krbtgt", "EXAMPLE.COM"], valid_end_time()))
+            .unwrap();
+
+        let ticket_cache = TicketCache::from_name("MEMORY:named_memory_ticket_caches_are_shared").unwrap();
+        assert!(ticket_cache
+            .lookup("user", "EXAMPLE.COM", "krbtgt/EXAMPLE.COM")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn file_ticket_cache() {
+        let path = env::temp_dir().join(format!("sspi_krb5cc_test_{}", std::process::id()));
+        let ticket_cache = TicketCache::from_name(&format!("FILE:{}", path.display())).unwrap();
+
+        ticket_cache
+            .store(credential(&["krbtgt", "EXAMPLE.COM"], valid_end_time()))
+            .unwrap();
+        ticket_cache
+            .store(credential(&["HTTP", "www.example.com"], valid_end_time()))
+            .unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let ccache = CCache::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ccache.default_principal, principal(&["user"]));
+        assert_eq!(ccache.credentials.len(), 2);
+    }
+
+    #[test]
+    fn truncated_data() {
+        let mut ccache = CCache::new(principal(&["user"]));
+        ccache.store(credential(&["krbtgt", "EXAMPLE.COM"], 1));
+        let data = ccache.to_bytes().unwrap();
+
+        // The ticket length is replaced with a huge value which is not backed by the data.
+        let ticket_len_offset = data.len() - 4 - ccache.credentials[0].ticket.len() - 4;
+        let mut invalid = data.clone();
+        invalid[ticket_len_offset..ticket_len_offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        assert!(CCache::from_bytes(&invalid).is_err());
+        assert!(CCache::from_bytes(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn not_yet_valid_credential() {
+        let now = OffsetDateTime::now_utc();
+        let mut credential = credential(&["krbtgt", "EXAMPLE.COM"], valid_end_time());
+
+        credential.start_time = valid_end_time() - 60;
+        assert!(!credential.is_valid_at(now));
+
+        credential.start_time = 0;
+        assert!(credential.is_valid_at(now));
+    }
+
+    #[test]
+    fn unsupported_ticket_cache_type() {
+        assert!(TicketCache::from_name("KEYRING:persistent:1000").is_err());
+    }
+}
@@ -0,0 +1,307 @@
+use picky_krb::constants::etypes::{AES128_CTS_HMAC_SHA1_96, AES256_CTS_HMAC_SHA1_96, DES3_CBC_SHA1_KD};
+use picky_krb::crypto::{KerberosCryptoError, KerberosCryptoResult};
+use rand::rngs::OsRng;
+use rand::Rng;
+
+use crate::crypto::{compute_hmac_md5, compute_md4, compute_md5, Rc4, HASH_SIZE};
+use crate::Result;
+
+/// [RFC 4757 2](https://www.rfc-editor.org/rfc/rfc4757#section-2): the RC4-HMAC encryption type number.
+pub const RC4_HMAC: usize = 23;
+/// [RFC 4757 4](https://www.rfc-editor.org/rfc/rfc4757#section-4): KERB_CHECKSUM_HMAC_MD5 checksum type.
+pub const HMAC_MD5: i32 = -138;
+/// [HMAC_MD5] encoded as the ASN.1 INTEGER content.
+pub const HMAC_MD5_CHECKSUM_TYPE: [u8; 2] = (HMAC_MD5 as i16).to_be_bytes();
+
+const RC4_HMAC_KEY_SIZE: usize = 16;
+const RC4_HMAC_CONFOUNDER_SIZE: usize = 8;
+
+/// Kerberos encryption type.
+///
+/// Extends the [picky_krb::crypto::CipherSuite] with the RC4-HMAC encryption type that is still used
+/// by legacy Active Directory accounts and keytabs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CipherSuite {
+    Aes128CtsHmacSha196,
+    Aes256CtsHmacSha196,
+    Des3CbcSha1Kd,
+    /// [RFC 4757](https://www.rfc-editor.org/rfc/rfc4757). It is deprecated by
+    /// [RFC 8429](https://www.rfc-editor.org/rfc/rfc8429), so it must be explicitly allowed in the
+    /// [KerberosConfig](crate::KerberosConfig).
+    Rc4Hmac,
+}
+
+impl CipherSuite {
+    pub fn cipher(&self) -> Box<dyn Cipher> {
+        match self {
+            CipherSuite::Aes256CtsHmacSha196 => Box::new(picky_krb::crypto::CipherSuite::Aes256CtsHmacSha196.cipher()),
+            CipherSuite::Aes128CtsHmacSha196 => Box::new(picky_krb::crypto::CipherSuite::Aes128CtsHmacSha196.cipher()),
+            CipherSuite::Des3CbcSha1Kd => Box::new(picky_krb::crypto::CipherSuite::Des3CbcSha1Kd.cipher()),
+            CipherSuite::Rc4Hmac => Box::new(Rc4Hmac),
+        }
+    }
+}
+
+/// Encryption and key derivation functions of the Kerberos encryption type.
+///
+/// Mirrors the [picky_krb::crypto::Cipher] trait.
+pub trait Cipher {
+    fn key_size(&self) -> usize;
+
+    fn encrypt(&self, key: &[u8], key_usage: i32, payload: &[u8]) -> KerberosCryptoResult<Vec<u8>>;
+    fn decrypt(&self, key: &[u8], key_usage: i32, cipher_data: &[u8]) -> KerberosCryptoResult<Vec<u8>>;
+
+    fn generate_key_from_password(&self, password: &[u8], salt: &[u8]) -> KerberosCryptoResult<Vec<u8>>;
+    fn random_to_key(&self, key: Vec<u8>) -> Vec<u8>;
+}
+
+impl Cipher for Box<dyn picky_krb::crypto::Cipher> {
+    fn key_size(&self) -> usize {
+        self.as_ref().key_size()
+    }
+
+    fn encrypt(&self, key: &[u8], key_usage: i32, payload: &[u8]) -> KerberosCryptoResult<Vec<u8>> {
+        self.as_ref().encrypt(key, key_usage, payload)
+    }
+
+    fn decrypt(&self, key: &[u8], key_usage: i32, cipher_data: &[u8]) -> KerberosCryptoResult<Vec<u8>> {
+        self.as_ref().decrypt(key, key_usage, cipher_data)
+    }
+
+    fn generate_key_from_password(&self, password: &[u8], salt: &[u8]) -> KerberosCryptoResult<Vec<u8>> {
+        self.as_ref().generate_key_from_password(password, salt)
+    }
+
+    fn random_to_key(&self, key: Vec<u8>) -> Vec<u8> {
+        self.as_ref().random_to_key(key)
+    }
+}
+
+/// [RFC 4757 5](https://www.rfc-editor.org/rfc/rfc4757#section-5): RC4-HMAC encryption type.
+#[derive(Debug, Default)]
+pub struct Rc4Hmac;
+
+impl Rc4Hmac {
+    /// [RFC 4757 3](https://www.rfc-editor.org/rfc/rfc4757#section-3): some key usage numbers are
+    /// translated to the ones used by Windows.
+    fn ms_usage(key_usage: i32) -> i32 {
+        match key_usage {
+            // AS-REP and TGS-REP encrypted parts
+            3 | 9 => 8,
+            // GSS-API sealing
+            23 => 13,
+            key_usage => key_usage,
+        }
+    }
+}
+
+impl Cipher for Rc4Hmac {
+    fn key_size(&self) -> usize {
+        RC4_HMAC_KEY_SIZE
+    }
+
+    fn encrypt(&self, key: &[u8], key_usage: i32, payload: &[u8]) -> KerberosCryptoResult<Vec<u8>> {
+        // K1 = HMAC(K, usage), K2 = K1 for the non-exportable version
+        let k1 = hmac_md5(key, &Self::ms_usage(key_usage).to_le_bytes())?;
+
+        let mut data = OsRng.gen::<[u8; RC4_HMAC_CONFOUNDER_SIZE]>().to_vec();
+        data.extend_from_slice(payload);
+
+        let checksum = hmac_md5(&k1, &data)?;
+        let k3 = hmac_md5(&k1, &checksum)?;
+
+        let mut cipher_data = checksum.to_vec();
+        cipher_data.extend_from_slice(&Rc4::new(&k3).process(&data));
+
+        Ok(cipher_data)
+    }
+
+    fn decrypt(&self, key: &[u8], key_usage: i32, cipher_data: &[u8]) -> KerberosCryptoResult<Vec<u8>> {
+        if cipher_data.len() < HASH_SIZE + RC4_HMAC_CONFOUNDER_SIZE {
+            return Err(KerberosCryptoError::CipherLength(
+                cipher_data.len(),
+                HASH_SIZE + RC4_HMAC_CONFOUNDER_SIZE,
+            ));
+        }
+
+        let (checksum, cipher_data) = cipher_data.split_at(HASH_SIZE);
+
+        let k1 = hmac_md5(key, &Self::ms_usage(key_usage).to_le_bytes())?;
+        let k3 = hmac_md5(&k1, checksum)?;
+
+        let data = Rc4::new(&k3).process(cipher_data);
+
+        if hmac_md5(&k1, &data)? != checksum {
+            return Err(KerberosCryptoError::IntegrityCheck);
+        }
+
+        Ok(data[RC4_HMAC_CONFOUNDER_SIZE..].to_vec())
+    }
+
+    /// [RFC 4757 4](https://www.rfc-editor.org/rfc/rfc4757#section-4): the key is the NT hash of the password.
+    /// The salt is not used.
+    fn generate_key_from_password(&self, password: &[u8], _salt: &[u8]) -> KerberosCryptoResult<Vec<u8>> {
+        let password = String::from_utf8_lossy(password)
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect::<Vec<u8>>();
+
+        Ok(compute_md4(&password).to_vec())
+    }
+
+    fn random_to_key(&self, key: Vec<u8>) -> Vec<u8> {
+        key
+    }
+}
+
+impl TryFrom<&[u8]> for CipherSuite {
+    type Error = KerberosCryptoError;
+
+    fn try_from(identifier: &[u8]) -> KerberosCryptoResult<Self> {
+        match identifier {
+            [etype] if usize::from(*etype) == RC4_HMAC => Ok(Self::Rc4Hmac),
+            identifier => picky_krb::crypto::CipherSuite::try_from(identifier).map(Self::from),
+        }
+    }
+}
+
+impl TryFrom<usize> for CipherSuite {
+    type Error = KerberosCryptoError;
+
+    fn try_from(identifier: usize) -> KerberosCryptoResult<Self> {
+        match identifier {
+            RC4_HMAC => Ok(Self::Rc4Hmac),
+            identifier => picky_krb::crypto::CipherSuite::try_from(identifier).map(Self::from),
+        }
+    }
+}
+
+impl From<picky_krb::crypto::CipherSuite> for CipherSuite {
+    fn from(cipher: picky_krb::crypto::CipherSuite) -> Self {
+        match cipher {
+            picky_krb::crypto::CipherSuite::Aes256CtsHmacSha196 => Self::Aes256CtsHmacSha196,
+            picky_krb::crypto::CipherSuite::Aes128CtsHmacSha196 => Self::Aes128CtsHmacSha196,
+            picky_krb::crypto::CipherSuite::Des3CbcSha1Kd => Self::Des3CbcSha1Kd,
+        }
+    }
+}
+
+impl TryFrom<&CipherSuite> for picky_krb::crypto::CipherSuite {
+    type Error = KerberosCryptoError;
+
+    fn try_from(cipher: &CipherSuite) -> KerberosCryptoResult<Self> {
+        match cipher {
+            CipherSuite::Aes256CtsHmacSha196 => Ok(Self::Aes256CtsHmacSha196),
+            CipherSuite::Aes128CtsHmacSha196 => Ok(Self::Aes128CtsHmacSha196),
+            CipherSuite::Des3CbcSha1Kd => Ok(Self::Des3CbcSha1Kd),
+            CipherSuite::Rc4Hmac => Err(KerberosCryptoError::AlgorithmIdentifier(RC4_HMAC)),
+        }
+    }
+}
+
+impl From<&CipherSuite> for usize {
+    fn from(cipher: &CipherSuite) -> Self {
+        match cipher {
+            CipherSuite::Aes256CtsHmacSha196 => AES256_CTS_HMAC_SHA1_96,
+            CipherSuite::Aes128CtsHmacSha196 => AES128_CTS_HMAC_SHA1_96,
+            CipherSuite::Des3CbcSha1Kd => DES3_CBC_SHA1_KD,
+            CipherSuite::Rc4Hmac => RC4_HMAC,
+        }
+    }
+}
+
+impl From<CipherSuite> for usize {
+    fn from(cipher: CipherSuite) -> Self {
+        Self::from(&cipher)
+    }
+}
+
+impl From<&CipherSuite> for u32 {
+    fn from(cipher: &CipherSuite) -> Self {
+        usize::from(cipher) as u32
+    }
+}
+
+impl From<&CipherSuite> for u8 {
+    fn from(cipher: &CipherSuite) -> Self {
+        usize::from(cipher) as u8
+    }
+}
+
+impl From<CipherSuite> for u8 {
+    fn from(cipher: CipherSuite) -> Self {
+        Self::from(&cipher)
+    }
+}
+
+fn hmac_md5(key: &[u8], data: &[u8]) -> KerberosCryptoResult<[u8; HASH_SIZE]> {
+    compute_hmac_md5(key, data).map_err(|err| KerberosCryptoError::CipherError(err.to_string()))
+}
+
+/// [RFC 4757 4](https://www.rfc-editor.org/rfc/rfc4757#section-4): the KERB_CHECKSUM_HMAC_MD5 checksum.
+///
+/// ```not_rust
+/// Ksign = HMAC(Key, "signaturekey")
+/// tmp = MD5(concat(usage, data))
+/// CHKSUM = HMAC(Ksign, tmp)
+/// ```
+pub fn hmac_md5_checksum(key: &[u8], key_usage: i32, data: &[u8]) -> Result<[u8; HASH_SIZE]> {
+    let signature_key = compute_hmac_md5(key, b"signaturekey\0")?;
+
+    let mut tmp = key_usage.to_le_bytes().to_vec();
+    tmp.extend_from_slice(data);
+
+    Ok(compute_hmac_md5(&signature_key, &compute_md5(&tmp))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rc4_hmac_key_from_password() {
+        // NT hash of "Password"
+        assert_eq!(
+            Rc4Hmac
+                .generate_key_from_password(b"Password", b"EXAMPLE.COMuser")
+                .unwrap(),
+            [0xa4, 0xf4, 0x9c, 0x40, 0x65, 0x10, 0xbd, 0xca, 0xb6, 0x82, 0x4e, 0xe7, 0xc3, 0x0f, 0xd8, 0x52]
+        );
+    }
+
+    #[test]
+    fn rc4_hmac_encrypt_decrypt() {
+        let key = [0x42; RC4_HMAC_KEY_SIZE];
+        let payload = b"Kerberos RC4-HMAC payload";
+
+        let cipher_data = Rc4Hmac.encrypt(&key, 3, payload).unwrap();
+        assert_eq!(cipher_data.len(), HASH_SIZE + RC4_HMAC_CONFOUNDER_SIZE + payload.len());
+
+        // AS-REP and TGS-REP encrypted part key usages are translated to the same one
+        assert_eq!(Rc4Hmac.decrypt(&key, 9, &cipher_data).unwrap(), payload);
+        assert!(Rc4Hmac.decrypt(&key, 7, &cipher_data).is_err());
+    }
+
+    #[test]
+    fn rc4_hmac_decrypt_modified_data() {
+        let key = [0x42; RC4_HMAC_KEY_SIZE];
+
+        let mut cipher_data = Rc4Hmac.encrypt(&key, 7, b"payload").unwrap();
+        *cipher_data.last_mut().unwrap() ^= 0x01;
+
+        assert!(matches!(
+            Rc4Hmac.decrypt(&key, 7, &cipher_data),
+            Err(KerberosCryptoError::IntegrityCheck)
+        ));
+    }
+
+    #[test]
+    fn cipher_suite_conversions() {
+        assert_eq!(CipherSuite::try_from(23).unwrap(), CipherSuite::Rc4Hmac);
+        assert_eq!(CipherSuite::try_from([23].as_slice()).unwrap(), CipherSuite::Rc4Hmac);
+        assert_eq!(CipherSuite::try_from(18).unwrap(), CipherSuite::Aes256CtsHmacSha196);
+        assert_eq!(u8::from(CipherSuite::Rc4Hmac), 23);
+        assert_eq!(u8::from(CipherSuite::Aes128CtsHmacSha196), 17);
+        assert!(CipherSuite::try_from(3).is_err());
+    }
+}
@@ -1,41 +1,67 @@
 use picky_asn1::wrapper::Asn1SequenceOf;
-use picky_krb::constants::key_usages::{AS_REP_ENC, KRB_PRIV_ENC_PART, TGS_REP_ENC_SESSION_KEY};
+use picky_krb::constants::key_usages::{AS_REP_ENC, KRB_PRIV_ENC_PART, TGS_REP_ENC_SESSION_KEY, TGS_REP_ENC_SUB_KEY};
 use picky_krb::constants::types::PA_ETYPE_INFO2_TYPE;
-use picky_krb::crypto::CipherSuite;
-use picky_krb::data_types::{EncKrbPrivPart, EtypeInfo2, PaData};
-use picky_krb::messages::{AsRep, EncAsRepPart, EncTgsRepPart, KrbError, KrbPriv, TgsRep};
+use picky_krb::data_types::{EncKrbPrivPart, EtypeInfo2, EtypeInfo2Entry, PaData};
+use picky_krb::messages::{AsRep, EncAsRepPart, EncKdcRepPart, EncTgsRepPart, KrbError, KrbPriv, TgsRep};
 
+use crate::kerberos::cipher::{CipherSuite, RC4_HMAC};
 use crate::kerberos::{EncryptionParams, DEFAULT_ENCRYPTION_TYPE};
 use crate::{Error, ErrorKind, Result};
 
-pub fn extract_salt_from_krb_error(error: &KrbError) -> Result<Option<String>> {
-    trace!(?error, "KRB_ERROR");
+/// Finds the first ETYPE-INFO2 entry of the KRB_ERROR with the encryption type the client supports.
+///
+/// The KDC lists the entries in the order of preference. The entries with other encryption types are skipped,
+/// so a spoofed KRB_ERROR can not make the client use the encryption type it does not allow.
+fn extract_etype_info2_entry(error: &KrbError, etypes: &[CipherSuite]) -> Result<Option<EtypeInfo2Entry>> {
+    let Some(e_data) = error.0.e_data.0.as_ref() else {
+        return Ok(None);
+    };
+    let pa_datas: Asn1SequenceOf<PaData> = picky_asn1_der::from_bytes(&e_data.0 .0)?;
 
-    if let Some(e_data) = error.0.e_data.0.as_ref() {
-        let pa_datas: Asn1SequenceOf<PaData> = picky_asn1_der::from_bytes(&e_data.0 .0)?;
-
-        if let Some(pa_etype_info_2) = pa_datas
-            .0
-            .into_iter()
-            .find(|pa_data| pa_data.padata_type.0 .0 == PA_ETYPE_INFO2_TYPE)
-        {
-            let etype_info_2: EtypeInfo2 = picky_asn1_der::from_bytes(&pa_etype_info_2.padata_data.0 .0)?;
-            if let Some(params) = etype_info_2.0.first() {
-                return Ok(params.salt.0.as_ref().map(|salt| salt.0.to_string()));
-            }
-        }
+    let Some(pa_etype_info_2) = pa_datas
+        .0
+        .into_iter()
+        .find(|pa_data| pa_data.padata_type.0 .0 == PA_ETYPE_INFO2_TYPE)
+    else {
+        return Ok(None);
+    };
+    let etype_info_2: EtypeInfo2 = picky_asn1_der::from_bytes(&pa_etype_info_2.padata_data.0 .0)?;
+
+    let entry = etype_info_2
+        .0
+        .into_iter()
+        .find(|entry| CipherSuite::try_from(entry.etype.0 .0.as_slice()).is_ok_and(|etype| etypes.contains(&etype)));
+    if entry.is_none() {
+        warn!("the KRB_ERROR does not contain the ETYPE-INFO2 entry with the supported encryption type");
     }
 
-    Ok(None)
+    Ok(entry)
+}
+
+/// Extracts the salt from the first ETYPE-INFO2 entry of the KRB_ERROR with one of the `etypes`.
+pub fn extract_salt_from_krb_error(error: &KrbError, etypes: &[CipherSuite]) -> Result<Option<String>> {
+    trace!(?error, "KRB_ERROR");
+
+    Ok(extract_etype_info2_entry(error, etypes)?.and_then(|entry| entry.salt.0.map(|salt| salt.0.to_string())))
+}
+
+/// Extracts the encryption type preferred by the KDC from the ETYPE-INFO2 of the KRB_ERROR.
+///
+/// Only the encryption types from `etypes` are considered.
+pub fn extract_encryption_type_from_krb_error(error: &KrbError, etypes: &[CipherSuite]) -> Result<Option<CipherSuite>> {
+    extract_etype_info2_entry(error, etypes)?
+        .map(|entry| CipherSuite::try_from(entry.etype.0 .0.as_slice()))
+        .transpose()
+        .map_err(Into::into)
 }
 
 #[instrument(level = "trace", ret, skip(password))]
-pub fn extract_session_key_from_as_rep(
+pub fn extract_enc_as_rep_part(
     as_rep: &AsRep,
     salt: &str,
     password: &str,
     enc_params: &EncryptionParams,
-) -> Result<Vec<u8>> {
+) -> Result<EncKdcRepPart> {
     let cipher = enc_params
         .encryption_type
         .as_ref()
@@ -48,15 +74,46 @@ pub fn extract_session_key_from_as_rep(
 
     let enc_as_rep_part: EncAsRepPart = picky_asn1_der::from_bytes(&enc_data)?;
 
-    Ok(enc_as_rep_part.0.key.0.key_value.0.to_vec())
+    Ok(enc_as_rep_part.0)
+}
+
+#[instrument(level = "trace", ret, skip(password))]
+pub fn extract_session_key_from_as_rep(
+    as_rep: &AsRep,
+    salt: &str,
+    password: &str,
+    enc_params: &EncryptionParams,
+) -> Result<Vec<u8>> {
+    let enc_as_rep_part = extract_enc_as_rep_part(as_rep, salt, password, enc_params)?;
+
+    Ok(enc_as_rep_part.key.0.key_value.0.to_vec())
 }
 
 #[instrument(level = "trace", ret)]
-pub fn extract_session_key_from_tgs_rep(
+pub fn extract_enc_tgs_rep_part(
     tgs_rep: &TgsRep,
     session_key: &[u8],
     enc_params: &EncryptionParams,
-) -> Result<Vec<u8>> {
+) -> Result<EncKdcRepPart> {
+    decrypt_enc_tgs_rep_part(tgs_rep, session_key, TGS_REP_ENC_SESSION_KEY, enc_params)
+}
+
+/// Decrypts the TGS-REP encrypted part with the subkey from the TGS-REQ authenticator.
+#[instrument(level = "trace", ret)]
+pub fn extract_enc_tgs_rep_part_with_sub_key(
+    tgs_rep: &TgsRep,
+    sub_key: &[u8],
+    enc_params: &EncryptionParams,
+) -> Result<EncKdcRepPart> {
+    decrypt_enc_tgs_rep_part(tgs_rep, sub_key, TGS_REP_ENC_SUB_KEY, enc_params)
+}
+
+fn decrypt_enc_tgs_rep_part(
+    tgs_rep: &TgsRep,
+    key: &[u8],
+    key_usage: i32,
+    enc_params: &EncryptionParams,
+) -> Result<EncKdcRepPart> {
     let cipher = enc_params
         .encryption_type
         .as_ref()
@@ -64,14 +121,25 @@ pub fn extract_session_key_from_tgs_rep(
         .cipher();
 
     let enc_data = cipher
-        .decrypt(session_key, TGS_REP_ENC_SESSION_KEY, &tgs_rep.0.enc_part.0.cipher.0 .0)
+        .decrypt(key, key_usage, &tgs_rep.0.enc_part.0.cipher.0 .0)
         .map_err(|e| Error::new(ErrorKind::DecryptFailure, format!("{:?}", e)))?;
 
     trace!(?enc_data, "Plain TgsRep::EncData");
 
-    let enc_as_rep_part: EncTgsRepPart = picky_asn1_der::from_bytes(&enc_data)?;
+    let enc_tgs_rep_part: EncTgsRepPart = picky_asn1_der::from_bytes(&enc_data)?;
+
+    Ok(enc_tgs_rep_part.0)
+}
+
+#[instrument(level = "trace", ret)]
+pub fn extract_session_key_from_tgs_rep(
+    tgs_rep: &TgsRep,
+    session_key: &[u8],
+    enc_params: &EncryptionParams,
+) -> Result<Vec<u8>> {
+    let enc_tgs_rep_part = extract_enc_tgs_rep_part(tgs_rep, session_key, enc_params)?;
 
-    Ok(enc_as_rep_part.0.key.0.key_value.0.to_vec())
+    Ok(enc_tgs_rep_part.key.0.key_value.0.to_vec())
 }
 
 #[instrument(level = "trace", ret)]
@@ -96,15 +164,20 @@ pub fn extract_encryption_params_from_as_rep(as_rep: &AsRep) -> Result<(u8, Stri
                 .first()
                 .ok_or_else(|| Error::new(ErrorKind::InvalidParameter, "Missing EtypeInto2Entry in EtypeInfo2"))?;
 
-            Ok((
-                pa_etype_info2.etype.0 .0.first().copied().unwrap(),
-                pa_etype_info2
-                    .salt
-                    .0
-                    .as_ref()
-                    .map(|salt| salt.0.to_string())
-                    .ok_or_else(|| Error::new(ErrorKind::InvalidParameter, "Missing salt in EtypeInto2Entry"))?,
-            ))
+            let etype = pa_etype_info2.etype.0 .0.first().copied().unwrap();
+            let salt = match pa_etype_info2.salt.0.as_ref() {
+                Some(salt) => salt.0.to_string(),
+                // RC4-HMAC keys are not salted, so the KDC may omit the salt
+                None if usize::from(etype) == RC4_HMAC => String::new(),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidParameter,
+                        "Missing salt in EtypeInto2Entry",
+                    ))
+                }
+            };
+
+            Ok((etype, salt))
         }
         None => Ok((*as_rep.0.enc_part.0.etype.0 .0.first().unwrap(), Default::default())),
     }
@@ -148,3 +221,123 @@ pub fn extract_status_code_from_krb_priv_response(
 
     Ok(u16::from_be_bytes(user_data[0..2].try_into().unwrap()))
 }
+
+#[cfg(test)]
+mod tests {
+    use picky_asn1::date::GeneralizedTime;
+    use picky_asn1::restricted_string::IA5String;
+    use picky_asn1::wrapper::{
+        ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag10, ExplicitContextTag12, ExplicitContextTag2,
+        ExplicitContextTag4, ExplicitContextTag5, ExplicitContextTag6, ExplicitContextTag9, IntegerAsn1,
+        OctetStringAsn1, Optional,
+    };
+    use picky_krb::data_types::{KerberosStringAsn1, KerberosTime, PrincipalName};
+    use picky_krb::messages::KrbErrorInner;
+    use time::OffsetDateTime;
+
+    use super::*;
+    use crate::kerberos::DEFAULT_ETYPES;
+
+    fn krb_error(entries: &[(CipherSuite, Option<&str>)]) -> KrbError {
+        let etype_info_2 = EtypeInfo2::from(
+            entries
+                .iter()
+                .map(|(etype, salt)| EtypeInfo2Entry {
+                    etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![etype.into()])),
+                    salt: Optional::from(salt.map(|salt| {
+                        ExplicitContextTag1::from(KerberosStringAsn1::from(
+                            IA5String::from_string(salt.to_owned()).unwrap(),
+                        ))
+                    })),
+                    s2kparams: Optional::from(None),
+                })
+                .collect::<Vec<_>>(),
+        );
+        let pa_datas = Asn1SequenceOf::from(vec![PaData {
+            padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_ETYPE_INFO2_TYPE.to_vec())),
+            padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(
+                picky_asn1_der::to_vec(&etype_info_2).unwrap(),
+            )),
+        }]);
+        let realm = KerberosStringAsn1::from(IA5String::from_string("EXAMPLE.COM".to_owned()).unwrap());
+
+        KrbError::from(KrbErrorInner {
+            pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![5])),
+            msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![30])),
+            ctime: Optional::from(None),
+            cusec: Optional::from(None),
+            stime: ExplicitContextTag4::from(KerberosTime::from(GeneralizedTime::from(OffsetDateTime::now_utc()))),
+            susec: ExplicitContextTag5::from(IntegerAsn1::from(vec![0])),
+            // KDC_ERR_PREAUTH_REQUIRED
+            error_code: ExplicitContextTag6::from(25),
+            crealm: Optional::from(None),
+            cname: Optional::from(None),
+            realm: ExplicitContextTag9::from(realm.clone()),
+            sname: ExplicitContextTag10::from(PrincipalName {
+                name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![2])),
+                name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![
+                    KerberosStringAsn1::from(IA5String::from_string("krbtgt".to_owned()).unwrap()),
+                    realm,
+                ])),
+            }),
+            e_text: Optional::from(None),
+            e_data: Optional::from(Some(ExplicitContextTag12::from(OctetStringAsn1::from(
+                picky_asn1_der::to_vec(&pa_datas).unwrap(),
+            )))),
+        })
+    }
+
+    #[test]
+    fn etype_info2_preferred_entry() {
+        let krb_error = krb_error(&[
+            (CipherSuite::Aes256CtsHmacSha196, Some("EXAMPLE.COMuser")),
+            (CipherSuite::Rc4Hmac, None),
+        ]);
+
+        assert_eq!(
+            extract_encryption_type_from_krb_error(&krb_error, &DEFAULT_ETYPES).unwrap(),
+            Some(CipherSuite::Aes256CtsHmacSha196)
+        );
+        assert_eq!(
+            extract_salt_from_krb_error(&krb_error, &DEFAULT_ETYPES)
+                .unwrap()
+                .as_deref(),
+            Some("EXAMPLE.COMuser")
+        );
+    }
+
+    #[test]
+    fn etype_info2_entry_with_not_allowed_etype() {
+        // the KDC (or an attacker) prefers RC4-HMAC, but the client does not allow it
+        let krb_error = krb_error(&[
+            (CipherSuite::Rc4Hmac, None),
+            (CipherSuite::Aes128CtsHmacSha196, Some("EXAMPLE.COMuser")),
+        ]);
+
+        assert_eq!(
+            extract_encryption_type_from_krb_error(&krb_error, &DEFAULT_ETYPES).unwrap(),
+            Some(CipherSuite::Aes128CtsHmacSha196)
+        );
+        assert_eq!(
+            extract_salt_from_krb_error(&krb_error, &DEFAULT_ETYPES)
+                .unwrap()
+                .as_deref(),
+            Some("EXAMPLE.COMuser")
+        );
+
+        let krb_error = self::krb_error(&[(CipherSuite::Rc4Hmac, None)]);
+
+        assert_eq!(
+            extract_encryption_type_from_krb_error(&krb_error, &DEFAULT_ETYPES).unwrap(),
+            None
+        );
+
+        let mut etypes = DEFAULT_ETYPES.to_vec();
+        etypes.push(CipherSuite::Rc4Hmac);
+
+        assert_eq!(
+            extract_encryption_type_from_krb_error(&krb_error, &etypes).unwrap(),
+            Some(CipherSuite::Rc4Hmac)
+        );
+    }
+}
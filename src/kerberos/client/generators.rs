@@ -2,6 +2,7 @@ use std::env;
 use std::path::Path;
 use std::str::FromStr;
 
+use crate::kerberos::cipher::{hmac_md5_checksum, CipherSuite, HMAC_MD5_CHECKSUM_TYPE};
 use bitflags;
 use md5::{Digest, Md5};
 use picky_asn1::bit_string::BitString;
@@ -25,7 +26,7 @@ use picky_krb::constants::types::{
     NT_ENTERPRISE, NT_PRINCIPAL, NT_SRV_INST, PA_ENC_TIMESTAMP, PA_ENC_TIMESTAMP_KEY_USAGE, PA_PAC_OPTIONS_TYPE,
     PA_PAC_REQUEST_TYPE, PA_TGS_REQ_TYPE, TGS_REQ_MSG_TYPE, TGT_REQ_MSG_TYPE,
 };
-use picky_krb::crypto::CipherSuite;
+use picky_krb::crypto::ChecksumSuite;
 use picky_krb::data_types::{
     ApOptions, Authenticator, AuthenticatorInner, AuthorizationData, AuthorizationDataInner, Checksum, EncKrbPrivPart,
     EncKrbPrivPartInner, EncryptedData, EncryptionKey, HostAddress, KerbPaPacRequest, KerberosFlags,
@@ -35,8 +36,8 @@ use picky_krb::gss_api::{
     ApplicationTag0, GssApiNegInit, KrbMessage, MechType, MechTypeList, NegTokenInit, NegTokenTarg, NegTokenTarg1,
 };
 use picky_krb::messages::{
-    ApMessage, ApReq, ApReqInner, AsReq, KdcRep, KdcReq, KdcReqBody, KrbPriv, KrbPrivInner, KrbPrivMessage, TgsReq,
-    TgtReq,
+    ApMessage, ApReq, ApReqInner, AsReq, EncKdcRepPart, KdcReq, KdcReqBody, KrbPriv, KrbPrivInner, KrbPrivMessage,
+    TgsReq, TgtReq,
 };
 use rand::rngs::OsRng;
 use rand::Rng;
@@ -44,11 +45,17 @@ use time::{Duration, OffsetDateTime};
 
 use crate::channel_bindings::ChannelBindings;
 use crate::crypto::compute_md5_channel_bindings_hash;
+use crate::kerberos::data_types::{
+    EncKrbCredPart, EncKrbCredPartInner, KrbCred, KrbCredInfo, KrbCredInner, PaForUser, PaS4uX509User, S4uUserId,
+    KRB_CRED_ENC_PART_KEY_USAGE, KRB_CRED_TYPE, PA_FOR_USER_KEY_USAGE, PA_FOR_USER_TYPE,
+    PA_S4U_X509_USER_REQ_KEY_USAGE, PA_S4U_X509_USER_TYPE,
+};
+use crate::kerberos::fast::generate_encrypted_challenge;
 use crate::kerberos::flags::{ApOptions as ApOptionsFlags, KdcOptions};
 use crate::kerberos::{EncryptionParams, DEFAULT_ENCRYPTION_TYPE, KERBEROS_VERSION};
 use crate::krb::Krb5Conf;
 use crate::utils::parse_target_name;
-use crate::{ClientRequestFlags, Error, ErrorKind, Result};
+use crate::{ClientRequestFlags, Error, ErrorKind, Result, ServerResponseFlags};
 
 const TGT_TICKET_LIFETIME_DAYS: i64 = 3;
 const NONCE_LEN: usize = 4;
@@ -64,6 +71,10 @@ pub const DEFAULT_AS_REQ_OPTIONS: [u8; 4] = [0x00, 0x81, 0x00, 0x10];
 const DEFAULT_TGS_REQ_OPTIONS: [u8; 4] = [0x00, 0x81, 0x00, 0x08];
 
 const DEFAULT_PA_PAC_OPTIONS: [u8; 4] = [0x40, 0x00, 0x00, 0x00];
+// [MS-KILE 2.2.10](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-kile/99721e67-a1b2-4b9d-86ba-ec65f6ff5bc8)
+const PA_PAC_OPTIONS_RESOURCE_BASED_CONSTRAINED_DELEGATION: u8 = 0x10;
+
+const S4U_AUTH_PACKAGE: &str = "Kerberos";
 
 /// [Authenticator Checksum](https://datatracker.ietf.org/doc/html/rfc4121#section-4.1.1)
 pub const AUTHENTICATOR_DEFAULT_CHECKSUM: [u8; 24] = [
@@ -120,6 +131,74 @@ fn get_client_principal_realm_impl(krb5_conf_paths: &[&Path], username: &str, do
     domain.to_uppercase()
 }
 
+/// Returns the realm of the service host from the `[domain_realm]` section of the krb5.conf.
+///
+/// Unlike [get_client_principal_realm], the realm is not guessed from the host name if there is no mapping:
+/// the KDC referrals are used to find the realm of the service in this case.
+pub fn get_service_realm(hostname: &str) -> Option<String> {
+    let krb5_config = env::var("KRB5_CONFIG").unwrap_or_else(|_| "/etc/krb5.conf:/usr/local/etc/krb5.conf".to_string());
+    let krb5_conf_paths = krb5_config.split(':').map(Path::new).collect::<Vec<&Path>>();
+
+    get_service_realm_impl(&krb5_conf_paths, hostname)
+}
+
+fn get_service_realm_impl(krb5_conf_paths: &[&Path], hostname: &str) -> Option<String> {
+    krb5_conf_paths
+        .iter()
+        .filter(|krb5_conf_path| krb5_conf_path.exists())
+        .filter_map(|krb5_conf_path| Krb5Conf::new_from_file(krb5_conf_path))
+        .find_map(|krb5_conf| {
+            krb5_conf
+                .get_values_in_section(&["domain_realm"])?
+                .into_iter()
+                .find(|(mapping_domain, _)| matches_domain(hostname, mapping_domain))
+                .map(|(_, realm)| realm.to_owned())
+        })
+}
+
+/// Returns the realms the client goes through to obtain the cross-realm TGT of the `service_realm`: the
+/// intermediate realms followed by the `service_realm`.
+///
+/// The path is configured in the [`[capaths]`](https://web.mit.edu/kerberos/krb5-1.12/doc/admin/conf_files/krb5_conf.html#capaths)
+/// section of the krb5.conf for the non-transitive trusts. The `.` value means that the realms trust each other
+/// directly. The returned path is empty if it is not configured.
+pub fn get_capath(client_realm: &str, service_realm: &str) -> Vec<String> {
+    let krb5_config = env::var("KRB5_CONFIG").unwrap_or_else(|_| "/etc/krb5.conf:/usr/local/etc/krb5.conf".to_string());
+    let krb5_conf_paths = krb5_config.split(':').map(Path::new).collect::<Vec<&Path>>();
+
+    get_capath_impl(&krb5_conf_paths, client_realm, service_realm)
+}
+
+fn get_capath_impl(krb5_conf_paths: &[&Path], client_realm: &str, service_realm: &str) -> Vec<String> {
+    if client_realm.eq_ignore_ascii_case(service_realm) {
+        return Vec::new();
+    }
+
+    for krb5_conf_path in krb5_conf_paths {
+        if !krb5_conf_path.exists() {
+            continue;
+        }
+
+        let Some(krb5_conf) = Krb5Conf::new_from_file(krb5_conf_path) else {
+            continue;
+        };
+        let values = krb5_conf.get_values(vec!["capaths", client_realm, service_realm]);
+        if values.is_empty() {
+            continue;
+        }
+
+        return values
+            .iter()
+            .flat_map(|value| value.split_whitespace())
+            .filter(|realm| *realm != ".")
+            .map(str::to_owned)
+            .chain(std::iter::once(service_realm.to_owned()))
+            .collect();
+    }
+
+    Vec::new()
+}
+
 fn matches_domain(domain: &str, mapping_domain: &str) -> bool {
     if mapping_domain.starts_with('.') {
         domain
@@ -137,6 +216,8 @@ pub struct GenerateAsPaDataOptions<'a> {
     pub salt: Vec<u8>,
     pub enc_params: EncryptionParams,
     pub with_pre_auth: bool,
+    /// FAST armor key. If set, the timestamp is sent in the PA-ENCRYPTED-CHALLENGE instead of the PA-ENC-TIMESTAMP.
+    pub armor_key: Option<EncKey>,
 }
 
 #[instrument(level = "trace", ret, skip_all, fields(options.salt, options.enc_params, options.with_pre_auth))]
@@ -146,6 +227,7 @@ pub fn generate_pa_datas_for_as_req(options: &GenerateAsPaDataOptions) -> Result
         salt,
         enc_params,
         with_pre_auth,
+        armor_key,
     } = options;
 
     let mut pa_datas = if *with_pre_auth {
@@ -169,6 +251,18 @@ pub fn generate_pa_datas_for_as_req(options: &GenerateAsPaDataOptions) -> Result
         let key = cipher.generate_key_from_password(password.as_bytes(), salt)?;
         trace!(?key, ?encryption_type, "AS timestamp encryption params",);
 
+        if let Some(armor_key) = armor_key {
+            let long_term_key = EncKey {
+                key_type: encryption_type.clone(),
+                key_value: key,
+            };
+
+            return Ok(vec![
+                generate_encrypted_challenge(armor_key, &long_term_key, &timestamp_bytes)?,
+                generate_pa_pac_request()?,
+            ]);
+        }
+
         let encrypted_timestamp = cipher.encrypt(&key, PA_ENC_TIMESTAMP_KEY_USAGE, &timestamp_bytes)?;
 
         trace!(
@@ -191,14 +285,18 @@ pub fn generate_pa_datas_for_as_req(options: &GenerateAsPaDataOptions) -> Result
         Vec::new()
     };
 
-    pa_datas.push(PaData {
+    pa_datas.push(generate_pa_pac_request()?);
+
+    Ok(pa_datas)
+}
+
+fn generate_pa_pac_request() -> Result<PaData> {
+    Ok(PaData {
         padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_PAC_REQUEST_TYPE.to_vec())),
         padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&KerbPaPacRequest {
             include_pac: ExplicitContextTag0::from(true),
         })?)),
-    });
-
-    Ok(pa_datas)
+    })
 }
 
 #[derive(Debug)]
@@ -210,6 +308,8 @@ pub struct GenerateAsReqOptions<'a> {
     pub nonce: &'a [u8],
     pub hostname: &'a str,
     pub context_requirements: ClientRequestFlags,
+    /// Encryption types supported by the client in the order of preference.
+    pub etypes: &'a [CipherSuite],
 }
 
 #[instrument(level = "trace", ret)]
@@ -222,6 +322,7 @@ pub fn generate_as_req_kdc_body(options: &GenerateAsReqOptions) -> Result<KdcReq
         nonce,
         hostname: address,
         context_requirements,
+        etypes,
     } = options;
 
     let expiration_date = OffsetDateTime::now_utc()
@@ -266,10 +367,12 @@ pub fn generate_as_req_kdc_body(options: &GenerateAsReqOptions) -> Result<KdcReq
             GeneralizedTime::from(expiration_date),
         )))),
         nonce: ExplicitContextTag7::from(IntegerAsn1::from(nonce.to_vec())),
-        etype: ExplicitContextTag8::from(Asn1SequenceOf::from(vec![
-            IntegerAsn1::from(vec![CipherSuite::Aes256CtsHmacSha196.into()]),
-            IntegerAsn1::from(vec![CipherSuite::Aes128CtsHmacSha196.into()]),
-        ])),
+        etype: ExplicitContextTag8::from(Asn1SequenceOf::from(
+            etypes
+                .iter()
+                .map(|etype| IntegerAsn1::from(vec![etype.into()]))
+                .collect::<Vec<_>>(),
+        )),
         addresses: Optional::from(address),
         enc_authorization_data: Optional::from(None),
         additional_tickets: Optional::from(None),
@@ -296,6 +399,31 @@ pub struct GenerateTgsReqOptions<'a> {
     pub additional_tickets: Option<Vec<Ticket>>,
     pub enc_params: &'a EncryptionParams,
     pub context_requirements: ClientRequestFlags,
+    /// KDC options to use instead of the default ones (e.g. to request a forwarded TGT).
+    pub kdc_options: Option<KdcOptions>,
+    /// Turns the request into the S4U2Self request. `service_principal` is ignored in this case.
+    pub s4u2self: Option<S4u2SelfOptions<'a>>,
+    /// Encryption types supported by the client in the order of preference.
+    pub etypes: &'a [CipherSuite],
+}
+
+/// The user on whose behalf the service requests a service ticket to itself.
+///
+/// [MS-SFU 3.1.5.1.1](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/02636893-7a1f-4357-af9a-b672e3e3de13)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum S4uUser {
+    /// The user principal name (`username` or `username@domain.com`).
+    Name(String),
+    /// The DER-encoded user certificate.
+    Certificate(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub struct S4u2SelfOptions<'a> {
+    /// The service's own principal name (the client name of the service's TGT).
+    pub service: &'a PrincipalName,
+    pub user: &'a S4uUser,
+    pub user_realm: &'a str,
 }
 
 #[instrument(level = "debug", ret)]
@@ -309,18 +437,39 @@ pub fn generate_tgs_req(options: GenerateTgsReqOptions) -> Result<TgsReq> {
         additional_tickets,
         enc_params,
         context_requirements,
+        kdc_options,
+        s4u2self,
+        etypes,
     } = options;
 
-    let (service_name, service_principal_name) = parse_target_name(service_principal)?;
+    let sname = if let Some(s4u2self) = s4u2self.as_ref() {
+        // [MS-SFU 3.1.5.1.1.1](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/f62d2f0f-2ad5-4a66-af46-1fa8b9e4fd25):
+        // "The sname field of the KRB_TGS_REQ contains the name of the service".
+        s4u2self.service.clone()
+    } else {
+        let (service_name, service_principal_name) = parse_target_name(service_principal)?;
+
+        PrincipalName {
+            name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![NT_SRV_INST])),
+            name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![
+                KerberosStringAsn1::from(IA5String::from_string(service_name.into())?),
+                KerberosStringAsn1::from(IA5String::from_string(service_principal_name.into())?),
+            ])),
+        }
+    };
 
     let expiration_date = OffsetDateTime::now_utc()
         .checked_add(Duration::days(TGT_TICKET_LIFETIME_DAYS))
         .unwrap();
 
-    let mut tgs_req_options = KdcOptions::from_bits(u32::from_be_bytes(DEFAULT_TGS_REQ_OPTIONS)).unwrap();
-    if context_requirements.contains(ClientRequestFlags::DELEGATE) {
-        tgs_req_options |= KdcOptions::FORWARDABLE;
-    }
+    let tgs_req_options = kdc_options.unwrap_or_else(|| {
+        let mut tgs_req_options = KdcOptions::from_bits(u32::from_be_bytes(DEFAULT_TGS_REQ_OPTIONS)).unwrap();
+        if context_requirements.contains(ClientRequestFlags::DELEGATE) {
+            tgs_req_options |= KdcOptions::FORWARDABLE;
+        }
+
+        tgs_req_options
+    });
 
     let req_body = KdcReqBody {
         kdc_options: ExplicitContextTag0::from(KerberosFlags::from(BitString::with_bytes(
@@ -328,21 +477,17 @@ pub fn generate_tgs_req(options: GenerateTgsReqOptions) -> Result<TgsReq> {
         ))),
         cname: Optional::from(None),
         realm: ExplicitContextTag2::from(Realm::from(IA5String::from_str(realm)?)),
-        sname: Optional::from(Some(ExplicitContextTag3::from(PrincipalName {
-            name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![NT_SRV_INST])),
-            name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![
-                KerberosStringAsn1::from(IA5String::from_string(service_name.into())?),
-                KerberosStringAsn1::from(IA5String::from_string(service_principal_name.into())?),
-            ])),
-        }))),
+        sname: Optional::from(Some(ExplicitContextTag3::from(sname))),
         from: Optional::from(None),
         till: ExplicitContextTag5::from(GeneralizedTimeAsn1::from(GeneralizedTime::from(expiration_date))),
         rtime: Optional::from(None),
         nonce: ExplicitContextTag7::from(IntegerAsn1::from(OsRng.gen::<[u8; NONCE_LEN]>().to_vec())),
-        etype: ExplicitContextTag8::from(Asn1SequenceOf::from(vec![
-            IntegerAsn1::from(vec![CipherSuite::Aes256CtsHmacSha196.into()]),
-            IntegerAsn1::from(vec![CipherSuite::Aes128CtsHmacSha196.into()]),
-        ])),
+        etype: ExplicitContextTag8::from(Asn1SequenceOf::from(
+            etypes
+                .iter()
+                .map(|etype| IntegerAsn1::from(vec![etype.into()]))
+                .collect::<Vec<_>>(),
+        )),
         addresses: Optional::from(None),
         enc_authorization_data: Optional::from(None),
         additional_tickets: Optional::from(
@@ -367,26 +512,150 @@ pub fn generate_tgs_req(options: GenerateTgsReqOptions) -> Result<TgsReq> {
             )?)),
         };
 
+    let mut pa_pac_options = DEFAULT_PA_PAC_OPTIONS;
+    if tgs_req_options.contains(KdcOptions::CNAME_IN_ADDL_TKT) {
+        // S4U2Proxy request: allow the KDC to perform the resource-based constrained delegation
+        pa_pac_options[0] |= PA_PAC_OPTIONS_RESOURCE_BASED_CONSTRAINED_DELEGATION;
+    }
+
     let pa_pac_options = PaData {
         padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_PAC_OPTIONS_TYPE.to_vec())),
         padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&PaPacOptions {
-            flags: ExplicitContextTag0::from(KerberosFlags::from(BitString::with_bytes(
-                DEFAULT_PA_PAC_OPTIONS.to_vec(),
-            ))),
+            flags: ExplicitContextTag0::from(KerberosFlags::from(BitString::with_bytes(pa_pac_options.to_vec()))),
         })?)),
     };
 
+    let mut pa_datas = vec![pa_tgs_req];
+    if let Some(s4u2self) = s4u2self {
+        pa_datas.extend(generate_s4u2self_pa_datas(
+            &s4u2self,
+            &req_body.nonce.0,
+            session_key,
+            enc_params,
+        )?);
+    }
+    pa_datas.push(pa_pac_options);
+
     Ok(TgsReq::from(KdcReq {
         pvno: ExplicitContextTag1::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
         msg_type: ExplicitContextTag2::from(IntegerAsn1::from(vec![TGS_REQ_MSG_TYPE])),
-        padata: Optional::from(Some(ExplicitContextTag3::from(Asn1SequenceOf::from(vec![
-            pa_tgs_req,
-            pa_pac_options,
-        ])))),
+        padata: Optional::from(Some(ExplicitContextTag3::from(Asn1SequenceOf::from(pa_datas)))),
         req_body: ExplicitContextTag4::from(req_body),
     }))
 }
 
+/// Generates the PA-FOR-USER and PA-S4U-X509-USER pa-datas of the S4U2Self request.
+///
+/// [MS-SFU 3.1.5.1.1.1](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/f62d2f0f-2ad5-4a66-af46-1fa8b9e4fd25):
+/// the PA-FOR-USER is used when the user is identified by its name. The PA-S4U-X509-USER is always sent and
+/// carries the user certificate if it is known.
+fn generate_s4u2self_pa_datas(
+    options: &S4u2SelfOptions,
+    nonce: &IntegerAsn1,
+    session_key: &[u8],
+    enc_params: &EncryptionParams,
+) -> Result<Vec<PaData>> {
+    let S4u2SelfOptions { user, user_realm, .. } = options;
+
+    let user_realm = Realm::from(IA5String::from_str(user_realm)?);
+    let mut pa_datas = Vec::with_capacity(2);
+
+    let (user_name, subject_certificate) = match user {
+        S4uUser::Name(name) => {
+            let user_name = PrincipalName {
+                name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![get_client_principal_name_type(name, "")])),
+                name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![KerberosStringAsn1::from(
+                    IA5String::from_str(name)?,
+                )])),
+            };
+
+            pa_datas.push(PaData {
+                padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_FOR_USER_TYPE.to_vec())),
+                padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(picky_asn1_der::to_vec(
+                    &generate_pa_for_user(user_name.clone(), user_realm.clone(), session_key)?,
+                )?)),
+            });
+
+            (Some(user_name), None)
+        }
+        S4uUser::Certificate(certificate) => (None, Some(certificate.clone())),
+    };
+
+    let user_id = S4uUserId {
+        nonce: ExplicitContextTag0::from(nonce.clone()),
+        cname: Optional::from(user_name.map(ExplicitContextTag1::from)),
+        crealm: ExplicitContextTag2::from(user_realm),
+        subject_certificate: Optional::from(
+            subject_certificate.map(|certificate| ExplicitContextTag3::from(OctetStringAsn1::from(certificate))),
+        ),
+        options: Optional::from(None),
+    };
+
+    let user_id_data = picky_asn1_der::to_vec(&user_id)?;
+    let checksum_suite = match enc_params.encryption_type.as_ref().unwrap_or(&DEFAULT_ENCRYPTION_TYPE) {
+        CipherSuite::Aes256CtsHmacSha196 => Some(ChecksumSuite::HmacSha196Aes256),
+        CipherSuite::Aes128CtsHmacSha196 => Some(ChecksumSuite::HmacSha196Aes128),
+        CipherSuite::Des3CbcSha1Kd => Some(ChecksumSuite::HmacSha1Des3Kd),
+        CipherSuite::Rc4Hmac => None,
+    };
+    let (checksum_type, checksum) = match checksum_suite {
+        Some(checksum_suite) => (
+            vec![u8::from(checksum_suite.clone())],
+            checksum_suite
+                .hasher()
+                .checksum(session_key, PA_S4U_X509_USER_REQ_KEY_USAGE, &user_id_data)?,
+        ),
+        None => (
+            HMAC_MD5_CHECKSUM_TYPE.to_vec(),
+            hmac_md5_checksum(session_key, PA_S4U_X509_USER_REQ_KEY_USAGE, &user_id_data)?.to_vec(),
+        ),
+    };
+
+    pa_datas.push(PaData {
+        padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_S4U_X509_USER_TYPE.to_vec())),
+        padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&PaS4uX509User {
+            user_id: ExplicitContextTag0::from(user_id),
+            checksum: ExplicitContextTag1::from(Checksum {
+                cksumtype: ExplicitContextTag0::from(IntegerAsn1::from(checksum_type)),
+                checksum: ExplicitContextTag1::from(OctetStringAsn1::from(checksum)),
+            }),
+        })?)),
+    });
+
+    Ok(pa_datas)
+}
+
+/// [MS-SFU 2.2.1](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/aceb70de-40f0-4409-87fa-df00ca145f5a):
+/// "The checksum is computed over the userName name-type (4 bytes, little-endian), each userName name-string,
+/// the userRealm, and the auth-package using the KERB_CHECKSUM_HMAC_MD5 algorithm".
+pub fn generate_pa_for_user(user_name: PrincipalName, user_realm: Realm, session_key: &[u8]) -> Result<PaForUser> {
+    let name_type = user_name
+        .name_type
+        .0
+         .0
+        .iter()
+        .fold(0_u32, |acc, byte| (acc << 8) | u32::from(*byte));
+
+    let mut data = name_type.to_le_bytes().to_vec();
+    for name in user_name.name_string.0 .0.iter() {
+        data.extend_from_slice(name.0.as_bytes());
+    }
+    data.extend_from_slice(user_realm.0.as_bytes());
+    data.extend_from_slice(S4U_AUTH_PACKAGE.as_bytes());
+
+    Ok(PaForUser {
+        user_name: ExplicitContextTag0::from(user_name),
+        user_realm: ExplicitContextTag1::from(user_realm),
+        cksum: ExplicitContextTag2::from(Checksum {
+            cksumtype: ExplicitContextTag0::from(IntegerAsn1::from(HMAC_MD5_CHECKSUM_TYPE.to_vec())),
+            checksum: ExplicitContextTag1::from(OctetStringAsn1::from(
+                hmac_md5_checksum(session_key, PA_FOR_USER_KEY_USAGE, &data)?.to_vec(),
+            )),
+        }),
+        auth_package: ExplicitContextTag3::from(KerberosStringAsn1::from(IA5String::from_str(S4U_AUTH_PACKAGE)?)),
+    })
+}
+
 #[derive(Debug)]
 pub struct ChecksumOptions {
     pub checksum_type: Vec<u8>,
@@ -430,6 +699,21 @@ impl ChecksumValues {
         self.inner[20..24].copy_from_slice(&flag_bytes);
     }
 
+    /// Appends the delegated credentials (`KRB-CRED` message) to the checksum.
+    ///
+    /// [Authenticator Checksum](https://datatracker.ietf.org/doc/html/rfc4121#section-4.1.1):
+    /// the `DlgOpt`, `Dlgth`, and `Deleg` fields are present only when `GSS_C_DELEG_FLAG` is set.
+    pub(crate) fn set_delegation(&mut self, krb_cred: &[u8]) -> Result<()> {
+        self.inner.truncate(AUTHENTICATOR_DEFAULT_CHECKSUM.len());
+        // DlgOpt: the only defined value is 1
+        self.inner.extend_from_slice(&1_u16.to_le_bytes());
+        self.inner
+            .extend_from_slice(&u16::try_from(krb_cred.len())?.to_le_bytes());
+        self.inner.extend_from_slice(krb_cred);
+
+        Ok(())
+    }
+
     pub(crate) fn into_inner(self) -> Vec<u8> {
         self.inner
     }
@@ -489,13 +773,45 @@ impl From<ClientRequestFlags> for GssFlags {
     }
 }
 
+impl From<GssFlags> for ServerResponseFlags {
+    fn from(value: GssFlags) -> Self {
+        let mut flags = ServerResponseFlags::empty();
+
+        if value.contains(GssFlags::GSS_C_DELEG_FLAG) {
+            flags |= ServerResponseFlags::DELEGATE;
+        }
+
+        if value.contains(GssFlags::GSS_C_MUTUAL_FLAG) {
+            flags |= ServerResponseFlags::MUTUAL_AUTH;
+        }
+
+        if value.contains(GssFlags::GSS_C_REPLAY_FLAG) {
+            flags |= ServerResponseFlags::REPLAY_DETECT;
+        }
+
+        if value.contains(GssFlags::GSS_C_SEQUENCE_FLAG) {
+            flags |= ServerResponseFlags::SEQUENCE_DETECT;
+        }
+
+        if value.contains(GssFlags::GSS_C_CONF_FLAG) {
+            flags |= ServerResponseFlags::CONFIDENTIALITY;
+        }
+
+        if value.contains(GssFlags::GSS_C_INTEG_FLAG) {
+            flags |= ServerResponseFlags::INTEGRITY;
+        }
+
+        flags
+    }
+}
+
 #[derive(Debug)]
 pub struct AuthenticatorChecksumExtension {
     pub extension_type: u32,
     pub extension_value: Vec<u8>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EncKey {
     pub key_type: CipherSuite,
     pub key_value: Vec<u8>,
@@ -503,7 +819,8 @@ pub struct EncKey {
 
 #[derive(Debug)]
 pub struct GenerateAuthenticatorOptions<'a> {
-    pub kdc_rep: &'a KdcRep,
+    pub crealm: &'a Realm,
+    pub cname: &'a PrincipalName,
     pub seq_num: Option<u32>,
     pub sub_key: Option<EncKey>,
     pub checksum: Option<ChecksumOptions>,
@@ -514,7 +831,8 @@ pub struct GenerateAuthenticatorOptions<'a> {
 #[instrument(level = "trace", ret)]
 pub fn generate_authenticator(options: GenerateAuthenticatorOptions) -> Result<Authenticator> {
     let GenerateAuthenticatorOptions {
-        kdc_rep,
+        crealm,
+        cname,
         seq_num,
         sub_key,
         checksum,
@@ -566,8 +884,8 @@ pub fn generate_authenticator(options: GenerateAuthenticatorOptions) -> Result<A
 
     Ok(Authenticator::from(AuthenticatorInner {
         authenticator_bno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
-        crealm: ExplicitContextTag1::from(kdc_rep.crealm.0.clone()),
-        cname: ExplicitContextTag2::from(kdc_rep.cname.0.clone()),
+        crealm: ExplicitContextTag1::from(crealm.clone()),
+        cname: ExplicitContextTag2::from(cname.clone()),
         cksum,
         cusec: ExplicitContextTag4::from(IntegerAsn1::from(microseconds.to_be_bytes().to_vec())),
         ctime: ExplicitContextTag5::from(KerberosTime::from(GeneralizedTime::from(current_date))),
@@ -780,9 +1098,84 @@ pub fn generate_krb_priv_request(
     })
 }
 
+/// Generates the `KRB-CRED` message that transfers the forwarded TGT to the service.
+///
+/// [RFC 4120 3.6](https://www.rfc-editor.org/rfc/rfc4120#section-3.6): the encrypted part
+/// is encrypted in the session key shared with the service.
+#[instrument(level = "trace", ret, skip(enc_kdc_rep_part, session_key))]
+pub fn generate_krb_cred(
+    ticket: Ticket,
+    crealm: &Realm,
+    cname: &PrincipalName,
+    enc_kdc_rep_part: &EncKdcRepPart,
+    session_key: &EncKey,
+) -> Result<KrbCred> {
+    let current_date = OffsetDateTime::now_utc();
+    let microseconds = current_date.microsecond().min(MAX_MICROSECONDS_IN_SECOND);
+
+    let krb_cred_info = KrbCredInfo {
+        key: ExplicitContextTag0::from(enc_kdc_rep_part.key.0.clone()),
+        prealm: Optional::from(Some(ExplicitContextTag1::from(crealm.clone()))),
+        pname: Optional::from(Some(ExplicitContextTag2::from(cname.clone()))),
+        flags: Optional::from(Some(ExplicitContextTag3::from(enc_kdc_rep_part.flags.0.clone()))),
+        auth_time: Optional::from(Some(ExplicitContextTag4::from(enc_kdc_rep_part.auth_time.0.clone()))),
+        start_time: Optional::from(
+            enc_kdc_rep_part
+                .start_time
+                .0
+                .as_ref()
+                .map(|start_time| ExplicitContextTag5::from(start_time.0.clone())),
+        ),
+        end_time: Optional::from(Some(ExplicitContextTag6::from(enc_kdc_rep_part.end_time.0.clone()))),
+        renew_till: Optional::from(
+            enc_kdc_rep_part
+                .renew_till
+                .0
+                .as_ref()
+                .map(|renew_till| ExplicitContextTag7::from(renew_till.0.clone())),
+        ),
+        srealm: Optional::from(Some(ExplicitContextTag8::from(enc_kdc_rep_part.srealm.0.clone()))),
+        sname: Optional::from(Some(ExplicitContextTag9::from(enc_kdc_rep_part.sname.0.clone()))),
+        caddr: Optional::from(None),
+    };
+
+    let enc_krb_cred_part = EncKrbCredPart::from(EncKrbCredPartInner {
+        ticket_info: ExplicitContextTag0::from(Asn1SequenceOf::from(vec![krb_cred_info])),
+        nonce: Optional::from(None),
+        timestamp: Optional::from(Some(ExplicitContextTag2::from(KerberosTime::from(
+            GeneralizedTime::from(current_date),
+        )))),
+        usec: Optional::from(Some(ExplicitContextTag3::from(IntegerAsn1::from_bytes_be_unsigned(
+            microseconds.to_be_bytes().to_vec(),
+        )))),
+        s_address: Optional::from(None),
+        r_address: Optional::from(None),
+    });
+
+    let enc_part = session_key.key_type.cipher().encrypt(
+        &session_key.key_value,
+        KRB_CRED_ENC_PART_KEY_USAGE,
+        &picky_asn1_der::to_vec(&enc_krb_cred_part)?,
+    )?;
+
+    Ok(KrbCred::from(KrbCredInner {
+        pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+        msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![KRB_CRED_TYPE])),
+        tickets: ExplicitContextTag2::from(Asn1SequenceOf::from(vec![ticket])),
+        enc_part: ExplicitContextTag3::from(EncryptedData {
+            etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![session_key.key_type.clone().into()])),
+            kvno: Optional::from(None),
+            cipher: ExplicitContextTag2::from(OctetStringAsn1::from(enc_part)),
+        }),
+    }))
+}
+
 #[cfg(test)]
 mod tests {
+    use picky_krb::data_types::TicketInner;
+
     use super::*;
+    use crate::kerberos::DEFAULT_ETYPES;
 
     const KRB5_CONFIG_FILE_PATH: &str = "test_assets/krb5.conf";
 
@@ -820,7 +1213,7 @@ mod tests {
         let username = "";
         let domain = "TBT.com";
 
-        let realm = get_client_principal_realm_impl(&vec![Path::new(KRB5_CONFIG_FILE_PATH)], username, domain);
+        let realm = get_client_principal_realm_impl(&[Path::new(KRB5_CONFIG_FILE_PATH)], username, domain);
 
         assert_eq!(realm, "TBT.COM");
     }
@@ -830,7 +1223,7 @@ mod tests {
         let username = "user@tbt.com";
         let domain = "";
 
-        let realm = get_client_principal_realm_impl(&vec![Path::new(KRB5_CONFIG_FILE_PATH)], username, domain);
+        let realm = get_client_principal_realm_impl(&[Path::new(KRB5_CONFIG_FILE_PATH)], username, domain);
 
         assert_eq!(realm, "TBT.COM");
     }
@@ -840,8 +1233,204 @@ mod tests {
         let username = "";
         let domain = "s.tbt.com";
 
-        let realm = get_client_principal_realm_impl(&vec![Path::new(KRB5_CONFIG_FILE_PATH)], username, domain);
+        let realm = get_client_principal_realm_impl(&[Path::new(KRB5_CONFIG_FILE_PATH)], username, domain);
 
         assert_eq!(realm, "TBT.COM");
     }
+
+    #[test]
+    fn test_get_service_realm() {
+        let paths = [Path::new(KRB5_CONFIG_FILE_PATH)];
+
+        assert_eq!(get_service_realm_impl(&paths, "dc.tbt.com").as_deref(), Some("TBT.COM"));
+        assert_eq!(get_service_realm_impl(&paths, "dc.example.com"), None);
+    }
+
+    #[test]
+    fn test_get_capath() {
+        let paths = [Path::new(KRB5_CONFIG_FILE_PATH)];
+
+        assert_eq!(
+            get_capath_impl(&paths, "TBT.COM", "EXAMPLE.COM"),
+            vec!["CHILD.TBT.COM", "PARTNER.COM", "EXAMPLE.COM"]
+        );
+        assert_eq!(get_capath_impl(&paths, "tbt.com", "PARTNER.COM"), vec!["PARTNER.COM"]);
+        assert!(get_capath_impl(&paths, "TBT.COM", "OTHER.COM").is_empty());
+        assert!(get_capath_impl(&paths, "TBT.COM", "TBT.COM").is_empty());
+    }
+
+    fn principal_name(name_type: u8, names: &[&str]) -> PrincipalName {
+        PrincipalName {
+            name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![name_type])),
+            name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(
+                names
+                    .iter()
+                    .map(|name| KerberosStringAsn1::from(IA5String::from_str(name).unwrap()))
+                    .collect::<Vec<_>>(),
+            )),
+        }
+    }
+
+    fn ticket(sname: PrincipalName) -> Ticket {
+        Ticket::from(TicketInner {
+            tkt_vno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            realm: ExplicitContextTag1::from(Realm::from(IA5String::from_str("EXAMPLE.COM").unwrap())),
+            sname: ExplicitContextTag2::from(sname),
+            enc_part: ExplicitContextTag3::from(EncryptedData {
+                etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![CipherSuite::Aes256CtsHmacSha196.into()])),
+                kvno: Optional::from(None),
+                cipher: ExplicitContextTag2::from(OctetStringAsn1::from(vec![1, 2, 3, 4])),
+            }),
+        })
+    }
+
+    fn pa_data_types(tgs_req: &TgsReq) -> Vec<Vec<u8>> {
+        tgs_req
+            .0
+            .padata
+            .0
+            .as_ref()
+            .unwrap()
+            .0
+             .0
+            .iter()
+            .map(|pa_data| pa_data.padata_type.0 .0.clone())
+            .collect()
+    }
+
+    #[test]
+    fn pa_for_user_checksum() {
+        let session_key = (0..32).collect::<Vec<u8>>();
+
+        let pa_for_user = generate_pa_for_user(
+            principal_name(NT_PRINCIPAL, &["alice"]),
+            Realm::from(IA5String::from_str("EXAMPLE.COM").unwrap()),
+            &session_key,
+        )
+        .unwrap();
+
+        assert_eq!(pa_for_user.cksum.0.cksumtype.0 .0, HMAC_MD5_CHECKSUM_TYPE);
+        assert_eq!(
+            pa_for_user.cksum.0.checksum.0 .0,
+            [63, 53, 196, 228, 162, 20, 187, 200, 66, 151, 44, 35, 42, 107, 117, 42]
+        );
+        assert_eq!(pa_for_user.auth_package.0.to_string(), S4U_AUTH_PACKAGE);
+    }
+
+    #[test]
+    fn s4u2self_tgs_req() {
+        let service = principal_name(NT_PRINCIPAL, &["gateway"]);
+        let crealm = Realm::from(IA5String::from_str("EXAMPLE.COM").unwrap());
+        let session_key = (0..32).collect::<Vec<u8>>();
+        let user = S4uUser::Name("alice".to_owned());
+        let mut authenticator = generate_authenticator(GenerateAuthenticatorOptions {
+            crealm: &crealm,
+            cname: &service,
+            seq_num: None,
+            sub_key: None,
+            checksum: None,
+            channel_bindings: None,
+            extensions: Vec::new(),
+        })
+        .unwrap();
+
+        let tgs_req = generate_tgs_req(GenerateTgsReqOptions {
+            realm: "EXAMPLE.COM",
+            service_principal: "",
+            session_key: &session_key,
+            ticket: ticket(principal_name(NT_SRV_INST, &["krbtgt", "EXAMPLE.COM"])),
+            authenticator: &mut authenticator,
+            additional_tickets: None,
+            enc_params: &EncryptionParams::default_for_client(),
+            context_requirements: ClientRequestFlags::empty(),
+            kdc_options: Some(KdcOptions::FORWARDABLE | KdcOptions::CANONICALIZE),
+            s4u2self: Some(S4u2SelfOptions {
+                service: &service,
+                user: &user,
+                user_realm: "EXAMPLE.COM",
+            }),
+            etypes: &DEFAULT_ETYPES,
+        })
+        .unwrap();
+
+        assert_eq!(tgs_req.0.req_body.0.sname.0.as_ref().unwrap().0, service);
+        assert_eq!(
+            pa_data_types(&tgs_req),
+            [
+                PA_TGS_REQ_TYPE.to_vec(),
+                PA_FOR_USER_TYPE.to_vec(),
+                PA_S4U_X509_USER_TYPE.to_vec(),
+                PA_PAC_OPTIONS_TYPE.to_vec(),
+            ]
+        );
+
+        let pa_s4u_x509_user: PaS4uX509User =
+            picky_asn1_der::from_bytes(&tgs_req.0.padata.0.as_ref().unwrap().0 .0[2].padata_data.0 .0).unwrap();
+        let user_id = pa_s4u_x509_user.user_id.0;
+        assert_eq!(user_id.nonce.0, tgs_req.0.req_body.0.nonce.0);
+        assert_eq!(
+            user_id.cname.0.as_ref().unwrap().0,
+            principal_name(NT_PRINCIPAL, &["alice"])
+        );
+
+        let checksum = ChecksumSuite::HmacSha196Aes256
+            .hasher()
+            .checksum(
+                &session_key,
+                PA_S4U_X509_USER_REQ_KEY_USAGE,
+                &picky_asn1_der::to_vec(&user_id).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(pa_s4u_x509_user.checksum.0.checksum.0 .0, checksum);
+    }
+
+    #[test]
+    fn s4u2proxy_tgs_req() {
+        let service = principal_name(NT_PRINCIPAL, &["gateway"]);
+        let crealm = Realm::from(IA5String::from_str("EXAMPLE.COM").unwrap());
+        let evidence_ticket = ticket(service.clone());
+        let mut authenticator = generate_authenticator(GenerateAuthenticatorOptions {
+            crealm: &crealm,
+            cname: &service,
+            seq_num: None,
+            sub_key: None,
+            checksum: None,
+            channel_bindings: None,
+            extensions: Vec::new(),
+        })
+        .unwrap();
+
+        let tgs_req = generate_tgs_req(GenerateTgsReqOptions {
+            realm: "EXAMPLE.COM",
+            service_principal: "HTTP/web.example.com",
+            session_key: &[0; 32],
+            ticket: ticket(principal_name(NT_SRV_INST, &["krbtgt", "EXAMPLE.COM"])),
+            authenticator: &mut authenticator,
+            additional_tickets: Some(vec![evidence_ticket.clone()]),
+            enc_params: &EncryptionParams::default_for_client(),
+            context_requirements: ClientRequestFlags::empty(),
+            kdc_options: Some(KdcOptions::FORWARDABLE | KdcOptions::CANONICALIZE | KdcOptions::CNAME_IN_ADDL_TKT),
+            s4u2self: None,
+            etypes: &DEFAULT_ETYPES,
+        })
+        .unwrap();
+
+        let req_body = &tgs_req.0.req_body.0;
+        let kdc_options = KdcOptions::from_bits_retain(u32::from_be_bytes(
+            req_body.kdc_options.0 .0.payload_view().try_into().unwrap(),
+        ));
+        assert!(kdc_options.contains(KdcOptions::CNAME_IN_ADDL_TKT));
+        assert_eq!(req_body.additional_tickets.0.as_ref().unwrap().0 .0, [evidence_ticket]);
+        assert_eq!(
+            pa_data_types(&tgs_req),
+            [PA_TGS_REQ_TYPE.to_vec(), PA_PAC_OPTIONS_TYPE.to_vec()]
+        );
+
+        let pa_pac_options: PaPacOptions =
+            picky_asn1_der::from_bytes(&tgs_req.0.padata.0.as_ref().unwrap().0 .0[1].padata_data.0 .0).unwrap();
+        assert_ne!(
+            pa_pac_options.flags.0 .0.payload_view()[0] & PA_PAC_OPTIONS_RESOURCE_BASED_CONSTRAINED_DELEGATION,
+            0
+        );
+    }
 }
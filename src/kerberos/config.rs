@@ -4,10 +4,17 @@ use std::str::FromStr;
 use url::Url;
 
 use crate::kdc::detect_kdc_url;
+use crate::kerberos::ccache::TicketCache;
+use crate::kerberos::fast::FastArmor;
+use crate::kerberos::kdc_certificate::KdcTrustStore;
+use crate::kerberos::server::ServerProperties;
 use crate::negotiate::{NegotiatedProtocol, ProtocolConfig};
+use crate::pk_init::PkInitAlgorithms;
+#[cfg(feature = "scard")]
+use crate::SmartCardContextProvider;
 use crate::{Kerberos, Result};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct KerberosConfig {
     /// KDC URL
     ///
@@ -26,6 +33,48 @@ pub struct KerberosConfig {
     ///
     /// This is also referred to as the "Source Workstation", i.e.: the name of the computer attempting to logon.
     pub client_computer_name: Option<String>,
+    /// Kerberos acceptor settings
+    ///
+    /// Must be specified to accept security contexts: the service keys are needed to decrypt tickets of the clients.
+    pub server_properties: Option<ServerProperties>,
+    /// Kerberos ticket cache
+    ///
+    /// If specified, the client reuses the TGT and service tickets from the cache instead of requesting
+    /// them from the KDC, and stores newly obtained tickets in it. See [TicketCache::from_env] to use
+    /// the cache specified by the `KRB5CCNAME` environment variable.
+    pub ticket_cache: Option<TicketCache>,
+    /// Allow the RC4-HMAC (etype 23) encryption type
+    ///
+    /// RC4-HMAC is deprecated by [RFC 8429](https://www.rfc-editor.org/rfc/rfc8429) and disabled by default.
+    /// Enable it only to interoperate with legacy accounts and keytabs that have no AES keys.
+    pub allow_rc4_hmac: bool,
+    /// Kerberos FAST armoring ([RFC 6113](https://www.rfc-editor.org/rfc/rfc6113))
+    ///
+    /// If specified, the AS exchange is armored with the ticket obtained from the given source, the password-based
+    /// pre-authentication uses the encrypted challenge, and the TGS exchanges are armored with the TGT.
+    /// The KDC must support FAST: unarmored replies are rejected.
+    pub fast: Option<FastArmor>,
+    /// PKINIT algorithms of the smart card logon
+    ///
+    /// The client starts with these algorithms and switches to the ones the KDC asks for if it rejects them.
+    pub pkinit: PkInitAlgorithms,
+    /// Trust anchors of the KDC certificate for the smart card logon
+    ///
+    /// The certificate that signs the PKINIT reply must chain to one of the trusted roots and be issued to the KDC
+    /// of the realm. If not specified, PKINIT replies are rejected unless [Self::allow_unvalidated_kdc_certificate]
+    /// is set.
+    pub kdc_trust_store: Option<KdcTrustStore>,
+    /// Accept PKINIT replies without validating the KDC certificate when no KDC trust store is configured
+    ///
+    /// Disabled by default: anyone on the path to the KDC can reply on its behalf if the certificate is not
+    /// validated. Enable it only for testing or when the channel to the KDC is otherwise authenticated.
+    pub allow_unvalidated_kdc_certificate: bool,
+    /// Smart card resource manager context used for the smart card logon
+    ///
+    /// PKINIT requests are signed by the smart card from the credentials, so its private key is never exported.
+    /// Not needed when the credentials carry the private key: the emulated smart card holding it is used then.
+    #[cfg(feature = "scard")]
+    pub scard_context: Option<SmartCardContextProvider>,
 }
 
 impl ProtocolConfig for KerberosConfig {
@@ -35,6 +84,12 @@ impl ProtocolConfig for KerberosConfig {
         )?))
     }
 
+    fn new_server(&self) -> Result<NegotiatedProtocol> {
+        Ok(NegotiatedProtocol::Kerberos(Kerberos::new_server_from_config(
+            Clone::clone(self),
+        )?))
+    }
+
     fn box_clone(&self) -> Box<dyn ProtocolConfig> {
         Box::new(Clone::clone(self))
     }
@@ -55,6 +110,15 @@ impl KerberosConfig {
         Self {
             kdc_url,
             client_computer_name: Some(client_computer_name),
+            server_properties: None,
+            ticket_cache: None,
+            allow_rc4_hmac: false,
+            fast: None,
+            pkinit: PkInitAlgorithms::default(),
+            kdc_trust_store: None,
+            allow_unvalidated_kdc_certificate: false,
+            #[cfg(feature = "scard")]
+            scard_context: None,
         }
     }
 
@@ -72,6 +136,15 @@ impl KerberosConfig {
         Self {
             kdc_url,
             client_computer_name: None,
+            server_properties: None,
+            ticket_cache: None,
+            allow_rc4_hmac: false,
+            fast: None,
+            pkinit: PkInitAlgorithms::default(),
+            kdc_trust_store: None,
+            allow_unvalidated_kdc_certificate: false,
+            #[cfg(feature = "scard")]
+            scard_context: None,
         }
     }
 }
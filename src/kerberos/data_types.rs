@@ -0,0 +1,389 @@
+use picky_asn1::wrapper::{
+    Asn1SequenceOf, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag10, ExplicitContextTag2,
+    ExplicitContextTag3, ExplicitContextTag4, ExplicitContextTag5, ExplicitContextTag6, ExplicitContextTag7,
+    ExplicitContextTag8, ExplicitContextTag9, IntegerAsn1, OctetStringAsn1, Optional,
+};
+use picky_asn1_der::application_tag::ApplicationTag;
+use picky_krb::data_types::{
+    AuthorizationData, Checksum, EncryptedData, EncryptionKey, HostAddress, KerberosFlags, KerberosStringAsn1,
+    KerberosTime, Microseconds, PaData, PrincipalName, Realm, Ticket,
+};
+use picky_krb::messages::KdcReqBody;
+use serde::{Deserialize, Serialize};
+
+// Kerberos structures that are not provided by the `picky-krb` crate.
+
+pub const ENC_TICKET_PART_TYPE: u8 = 3;
+pub const KRB_CRED_TYPE: u8 = 22;
+pub const ENC_KRB_CRED_PART_TYPE: u8 = 29;
+
+/// [RFC 4120 7.5.1](https://www.rfc-editor.org/rfc/rfc4120#section-7.5.1): key usage of the KRB-CRED encrypted part.
+pub const KRB_CRED_ENC_PART_KEY_USAGE: i32 = 14;
+
+/// [MS-SFU 2.2.1](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/aceb70de-40f0-4409-87fa-df00ca145f5a)
+pub const PA_FOR_USER_TYPE: [u8; 1] = [0x81];
+/// [MS-SFU 2.2.2](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/cd9d5ca7-ce20-4693-872b-2f5dd41cbff6)
+pub const PA_S4U_X509_USER_TYPE: [u8; 1] = [0x82];
+/// [MS-SFU 2.2.1](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/aceb70de-40f0-4409-87fa-df00ca145f5a):
+/// key usage of the PA-FOR-USER checksum.
+pub const PA_FOR_USER_KEY_USAGE: i32 = 17;
+/// [MS-SFU 2.2.2](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/cd9d5ca7-ce20-4693-872b-2f5dd41cbff6):
+/// key usage of the PA-S4U-X509-USER checksum in the request.
+pub const PA_S4U_X509_USER_REQ_KEY_USAGE: i32 = 26;
+
+/// [RFC 6113 7.3](https://www.rfc-editor.org/rfc/rfc6113#section-7.3)
+pub const PA_FX_COOKIE_TYPE: [u8; 1] = [133];
+/// [RFC 6113 7.3](https://www.rfc-editor.org/rfc/rfc6113#section-7.3)
+pub const PA_FX_FAST_TYPE: [u8; 1] = [136];
+/// [RFC 6113 7.3](https://www.rfc-editor.org/rfc/rfc6113#section-7.3)
+pub const PA_FX_ERROR_TYPE: [u8; 1] = [137];
+/// [RFC 6113 7.3](https://www.rfc-editor.org/rfc/rfc6113#section-7.3)
+pub const PA_ENCRYPTED_CHALLENGE_TYPE: [u8; 1] = [138];
+/// [RFC 6113 5.4.1](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.1): the armor is an AP-REQ.
+pub const FX_FAST_ARMOR_AP_REQUEST: u8 = 1;
+
+/// [RFC 8062 3](https://www.rfc-editor.org/rfc/rfc8062#section-3): the name type of the well-known principals.
+pub const NT_WELLKNOWN: u8 = 11;
+/// [RFC 8062 3](https://www.rfc-editor.org/rfc/rfc8062#section-3): the first component of the anonymous
+/// principal name `WELLKNOWN/ANONYMOUS`.
+pub const WELLKNOWN_NAME: &str = "WELLKNOWN";
+/// [RFC 8062 3](https://www.rfc-editor.org/rfc/rfc8062#section-3): the second component of the anonymous
+/// principal name `WELLKNOWN/ANONYMOUS`.
+pub const ANONYMOUS_PRINCIPAL_NAME: &str = "ANONYMOUS";
+
+/// [RFC 6113 5.4.2](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.2): key usage of the FAST request checksum.
+pub const KEY_USAGE_FAST_REQ_CHKSUM: i32 = 50;
+/// [RFC 6113 5.4.2](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.2): key usage of the encrypted FAST request.
+pub const KEY_USAGE_FAST_ENC: i32 = 51;
+/// [RFC 6113 5.4.3](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.3): key usage of the encrypted FAST response.
+pub const KEY_USAGE_FAST_REP: i32 = 52;
+/// [RFC 6113 5.4.3](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.3): key usage of the ticket checksum.
+pub const KEY_USAGE_FAST_FINISHED: i32 = 53;
+/// [RFC 6113 5.4.6](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.6): key usage of the client challenge.
+pub const KEY_USAGE_ENC_CHALLENGE_CLIENT: i32 = 54;
+/// [RFC 6113 5.4.6](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.6): key usage of the KDC challenge.
+pub const KEY_USAGE_ENC_CHALLENGE_KDC: i32 = 55;
+
+/// [RFC 4120 5.3](https://www.rfc-editor.org/rfc/rfc4120#section-5.3)
+///
+/// ```not_rust
+/// TransitedEncoding       ::= SEQUENCE {
+///         tr-type         [0] Int32 -- must be registered --,
+///         contents        [1] OCTET STRING
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct TransitedEncoding {
+    pub tr_type: ExplicitContextTag0<IntegerAsn1>,
+    pub contents: ExplicitContextTag1<OctetStringAsn1>,
+}
+
+/// [RFC 4120 5.3](https://www.rfc-editor.org/rfc/rfc4120#section-5.3)
+///
+/// ```not_rust
+/// EncTicketPart   ::= [APPLICATION 3] SEQUENCE {
+///         flags                   [0] TicketFlags,
+///         key                     [1] EncryptionKey,
+///         crealm                  [2] Realm,
+///         cname                   [3] PrincipalName,
+///         transited               [4] TransitedEncoding,
+///         authtime                [5] KerberosTime,
+///         starttime               [6] KerberosTime OPTIONAL,
+///         endtime                 [7] KerberosTime,
+///         renew-till              [8] KerberosTime OPTIONAL,
+///         caddr                   [9] HostAddresses OPTIONAL,
+///         authorization-data      [10] AuthorizationData OPTIONAL
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct EncTicketPartInner {
+    pub flags: ExplicitContextTag0<KerberosFlags>,
+    pub key: ExplicitContextTag1<EncryptionKey>,
+    pub crealm: ExplicitContextTag2<Realm>,
+    pub cname: ExplicitContextTag3<PrincipalName>,
+    pub transited: ExplicitContextTag4<TransitedEncoding>,
+    pub auth_time: ExplicitContextTag5<KerberosTime>,
+    pub start_time: Optional<Option<ExplicitContextTag6<KerberosTime>>>,
+    pub end_time: ExplicitContextTag7<KerberosTime>,
+    #[serde(default)]
+    pub renew_till: Optional<Option<ExplicitContextTag8<KerberosTime>>>,
+    #[serde(default)]
+    pub caddr: Optional<Option<ExplicitContextTag9<Asn1SequenceOf<HostAddress>>>>,
+    #[serde(default)]
+    pub authorization_data: Optional<Option<ExplicitContextTag10<AuthorizationData>>>,
+}
+
+pub type EncTicketPart = ApplicationTag<EncTicketPartInner, ENC_TICKET_PART_TYPE>;
+
+/// [RFC 4120 5.8](https://www.rfc-editor.org/rfc/rfc4120#section-5.8)
+///
+/// ```not_rust
+/// KRB-CRED        ::= [APPLICATION 22] SEQUENCE {
+///         pvno            [0] INTEGER (5),
+///         msg-type        [1] INTEGER (22),
+///         tickets         [2] SEQUENCE OF Ticket,
+///         enc-part        [3] EncryptedData -- EncKrbCredPart
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct KrbCredInner {
+    pub pvno: ExplicitContextTag0<IntegerAsn1>,
+    pub msg_type: ExplicitContextTag1<IntegerAsn1>,
+    pub tickets: ExplicitContextTag2<Asn1SequenceOf<Ticket>>,
+    pub enc_part: ExplicitContextTag3<EncryptedData>,
+}
+
+pub type KrbCred = ApplicationTag<KrbCredInner, KRB_CRED_TYPE>;
+
+/// [RFC 4120 5.8.1](https://www.rfc-editor.org/rfc/rfc4120#section-5.8.1)
+///
+/// ```not_rust
+/// KrbCredInfo     ::= SEQUENCE {
+///         key             [0] EncryptionKey,
+///         prealm          [1] Realm OPTIONAL,
+///         pname           [2] PrincipalName OPTIONAL,
+///         flags           [3] TicketFlags OPTIONAL,
+///         authtime        [4] KerberosTime OPTIONAL,
+///         starttime       [5] KerberosTime OPTIONAL,
+///         endtime         [6] KerberosTime OPTIONAL,
+///         renew-till      [7] KerberosTime OPTIONAL,
+///         srealm          [8] Realm OPTIONAL,
+///         sname           [9] PrincipalName OPTIONAL,
+///         caddr           [10] HostAddresses OPTIONAL
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct KrbCredInfo {
+    pub key: ExplicitContextTag0<EncryptionKey>,
+    #[serde(default)]
+    pub prealm: Optional<Option<ExplicitContextTag1<Realm>>>,
+    #[serde(default)]
+    pub pname: Optional<Option<ExplicitContextTag2<PrincipalName>>>,
+    #[serde(default)]
+    pub flags: Optional<Option<ExplicitContextTag3<KerberosFlags>>>,
+    #[serde(default)]
+    pub auth_time: Optional<Option<ExplicitContextTag4<KerberosTime>>>,
+    #[serde(default)]
+    pub start_time: Optional<Option<ExplicitContextTag5<KerberosTime>>>,
+    #[serde(default)]
+    pub end_time: Optional<Option<ExplicitContextTag6<KerberosTime>>>,
+    #[serde(default)]
+    pub renew_till: Optional<Option<ExplicitContextTag7<KerberosTime>>>,
+    #[serde(default)]
+    pub srealm: Optional<Option<ExplicitContextTag8<Realm>>>,
+    #[serde(default)]
+    pub sname: Optional<Option<ExplicitContextTag9<PrincipalName>>>,
+    #[serde(default)]
+    pub caddr: Optional<Option<ExplicitContextTag10<Asn1SequenceOf<HostAddress>>>>,
+}
+
+/// [RFC 4120 5.8.1](https://www.rfc-editor.org/rfc/rfc4120#section-5.8.1)
+///
+/// ```not_rust
+/// EncKrbCredPart  ::= [APPLICATION 29] SEQUENCE {
+///         ticket-info     [0] SEQUENCE OF KrbCredInfo,
+///         nonce           [1] UInt32 OPTIONAL,
+///         timestamp       [2] KerberosTime OPTIONAL,
+///         usec            [3] Microseconds OPTIONAL,
+///         s-address       [4] HostAddress OPTIONAL,
+///         r-address       [5] HostAddress OPTIONAL
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct EncKrbCredPartInner {
+    pub ticket_info: ExplicitContextTag0<Asn1SequenceOf<KrbCredInfo>>,
+    #[serde(default)]
+    pub nonce: Optional<Option<ExplicitContextTag1<IntegerAsn1>>>,
+    #[serde(default)]
+    pub timestamp: Optional<Option<ExplicitContextTag2<KerberosTime>>>,
+    #[serde(default)]
+    pub usec: Optional<Option<ExplicitContextTag3<Microseconds>>>,
+    #[serde(default)]
+    pub s_address: Optional<Option<ExplicitContextTag4<HostAddress>>>,
+    #[serde(default)]
+    pub r_address: Optional<Option<ExplicitContextTag5<HostAddress>>>,
+}
+
+pub type EncKrbCredPart = ApplicationTag<EncKrbCredPartInner, ENC_KRB_CRED_PART_TYPE>;
+
+/// [MS-SFU 2.2.1](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/aceb70de-40f0-4409-87fa-df00ca145f5a)
+///
+/// ```not_rust
+/// PA-FOR-USER ::= SEQUENCE {
+///         -- PA TYPE 129
+///         userName        [0] PrincipalName,
+///         userRealm       [1] Realm,
+///         cksum           [2] Checksum,
+///         auth-package    [3] KerberosString
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct PaForUser {
+    pub user_name: ExplicitContextTag0<PrincipalName>,
+    pub user_realm: ExplicitContextTag1<Realm>,
+    pub cksum: ExplicitContextTag2<Checksum>,
+    pub auth_package: ExplicitContextTag3<KerberosStringAsn1>,
+}
+
+/// [MS-SFU 2.2.2](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/cd9d5ca7-ce20-4693-872b-2f5dd41cbff6)
+///
+/// ```not_rust
+/// S4UUserID ::= SEQUENCE {
+///         nonce                   [0] UInt32, -- the nonce in KDC-REQ-BODY
+///         cname                   [1] PrincipalName OPTIONAL,
+///         crealm                  [2] Realm,
+///         subject-certificate     [3] OCTET STRING OPTIONAL,
+///         options                 [4] BIT STRING OPTIONAL,
+///         ...
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct S4uUserId {
+    pub nonce: ExplicitContextTag0<IntegerAsn1>,
+    #[serde(default)]
+    pub cname: Optional<Option<ExplicitContextTag1<PrincipalName>>>,
+    pub crealm: ExplicitContextTag2<Realm>,
+    #[serde(default)]
+    pub subject_certificate: Optional<Option<ExplicitContextTag3<OctetStringAsn1>>>,
+    #[serde(default)]
+    pub options: Optional<Option<ExplicitContextTag4<KerberosFlags>>>,
+}
+
+/// [MS-SFU 2.2.2](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/cd9d5ca7-ce20-4693-872b-2f5dd41cbff6)
+///
+/// ```not_rust
+/// PA-S4U-X509-USER ::= SEQUENCE {
+///         user-id         [0] S4UUserID,
+///         checksum        [1] Checksum
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct PaS4uX509User {
+    pub user_id: ExplicitContextTag0<S4uUserId>,
+    pub checksum: ExplicitContextTag1<Checksum>,
+}
+
+/// [RFC 6113 5.4.1](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.1)
+///
+/// ```not_rust
+/// KrbFastArmor ::= SEQUENCE {
+///         armor-type   [0] Int32,
+///         armor-value  [1] OCTET STRING,
+///         ...
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct KrbFastArmor {
+    pub armor_type: ExplicitContextTag0<IntegerAsn1>,
+    pub armor_value: ExplicitContextTag1<OctetStringAsn1>,
+}
+
+/// [RFC 6113 5.4.2](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.2)
+///
+/// ```not_rust
+/// KrbFastArmoredReq ::= SEQUENCE {
+///         armor        [0] KrbFastArmor OPTIONAL,
+///         req-checksum [1] Checksum,
+///         enc-fast-req [2] EncryptedData, -- KrbFastReq --
+///         ...
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct KrbFastArmoredReq {
+    #[serde(default)]
+    pub armor: Optional<Option<ExplicitContextTag0<KrbFastArmor>>>,
+    pub req_checksum: ExplicitContextTag1<Checksum>,
+    pub enc_fast_req: ExplicitContextTag2<EncryptedData>,
+}
+
+/// [RFC 6113 5.4.2](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.2)
+///
+/// ```not_rust
+/// PA-FX-FAST-REQUEST ::= CHOICE {
+///         armored-data [0] KrbFastArmoredReq,
+///         ...
+/// }
+/// ```
+pub type PaFxFastRequest = ExplicitContextTag0<KrbFastArmoredReq>;
+
+/// [RFC 6113 5.4.2](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.2)
+///
+/// ```not_rust
+/// KrbFastReq ::= SEQUENCE {
+///         fast-options [0] FastOptions,
+///         padata       [1] SEQUENCE OF PA-DATA,
+///         req-body     [2] KDC-REQ-BODY,
+///         ...
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct KrbFastReq {
+    pub fast_options: ExplicitContextTag0<KerberosFlags>,
+    pub padata: ExplicitContextTag1<Asn1SequenceOf<PaData>>,
+    pub req_body: ExplicitContextTag2<KdcReqBody>,
+}
+
+/// [RFC 6113 5.4.3](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.3)
+///
+/// ```not_rust
+/// KrbFastArmoredRep ::= SEQUENCE {
+///         enc-fast-rep      [0] EncryptedData, -- KrbFastResponse --
+///         ...
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct KrbFastArmoredRep {
+    pub enc_fast_rep: ExplicitContextTag0<EncryptedData>,
+}
+
+/// [RFC 6113 5.4.3](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.3)
+///
+/// ```not_rust
+/// PA-FX-FAST-REPLY ::= CHOICE {
+///         armored-data [0] KrbFastArmoredRep,
+///         ...
+/// }
+/// ```
+pub type PaFxFastReply = ExplicitContextTag0<KrbFastArmoredRep>;
+
+/// [RFC 6113 5.4.3](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.3)
+///
+/// ```not_rust
+/// KrbFastResponse ::= SEQUENCE {
+///         padata         [0] SEQUENCE OF PA-DATA,
+///         strengthen-key [1] EncryptionKey OPTIONAL,
+///         finished       [2] KrbFastFinished OPTIONAL,
+///         nonce          [3] UInt32,
+///         ...
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct KrbFastResponse {
+    pub padata: ExplicitContextTag0<Asn1SequenceOf<PaData>>,
+    #[serde(default)]
+    pub strengthen_key: Optional<Option<ExplicitContextTag1<EncryptionKey>>>,
+    #[serde(default)]
+    pub finished: Optional<Option<ExplicitContextTag2<KrbFastFinished>>>,
+    pub nonce: ExplicitContextTag3<IntegerAsn1>,
+}
+
+/// [RFC 6113 5.4.3](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.3)
+///
+/// ```not_rust
+/// KrbFastFinished ::= SEQUENCE {
+///         timestamp       [0] KerberosTime,
+///         usec            [1] Microseconds,
+///         crealm          [2] Realm,
+///         cname           [3] PrincipalName,
+///         ticket-checksum [4] Checksum,
+///         ...
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct KrbFastFinished {
+    pub timestamp: ExplicitContextTag0<KerberosTime>,
+    pub usec: ExplicitContextTag1<Microseconds>,
+    pub crealm: ExplicitContextTag2<Realm>,
+    pub cname: ExplicitContextTag3<PrincipalName>,
+    pub ticket_checksum: ExplicitContextTag4<Checksum>,
+}
@@ -1,6 +1,7 @@
 use picky_krb::constants::key_usages::{ACCEPTOR_SEAL, INITIATOR_SEAL};
 use picky_krb::crypto::aes::AesSize;
-use picky_krb::crypto::CipherSuite;
+
+use crate::kerberos::cipher::CipherSuite;
 
 #[derive(Debug, Clone)]
 pub struct EncryptionParams {
@@ -36,7 +37,7 @@ impl EncryptionParams {
         self.encryption_type.as_ref().and_then(|e_type| match e_type {
             CipherSuite::Aes256CtsHmacSha196 => Some(AesSize::Aes256),
             CipherSuite::Aes128CtsHmacSha196 => Some(AesSize::Aes128),
-            CipherSuite::Des3CbcSha1Kd => None,
+            CipherSuite::Des3CbcSha1Kd | CipherSuite::Rc4Hmac => None,
         })
     }
 }
@@ -0,0 +1,767 @@
+//! [Kerberos FAST](https://www.rfc-editor.org/rfc/rfc6113): armoring of the AS and TGS exchanges.
+//!
+//! The armored request carries the pre-authentication data encrypted in the armor key that is shared with the KDC,
+//! so it can not be used for offline password guessing, and the reply is bound to the request.
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::{Aes128, Aes256};
+use picky_asn1::bit_string::BitString;
+use picky_asn1::wrapper::{
+    Asn1SequenceOf, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag12, ExplicitContextTag2,
+    ExplicitContextTag3, IntegerAsn1, OctetStringAsn1, Optional,
+};
+use picky_krb::constants::types::PA_TGS_REQ_TYPE;
+use picky_krb::crypto::aes::{derive_key, AesSize};
+use picky_krb::crypto::ChecksumSuite;
+use picky_krb::data_types::{Checksum, EncryptedData, KerberosFlags, PaData, PaEncTsEnc, PrincipalName, Realm, Ticket};
+use picky_krb::messages::{KdcRep, KdcReq, KrbError};
+use rand::rngs::OsRng;
+use sha1::{Digest, Sha1};
+use time::OffsetDateTime;
+
+use crate::kerberos::ccache::TicketCache;
+use crate::kerberos::cipher::CipherSuite;
+use crate::kerberos::client::generators::{
+    generate_ap_req, generate_authenticator, EncKey, GenerateAuthenticatorOptions,
+};
+use crate::kerberos::data_types::{
+    KrbFastArmor, KrbFastArmoredReq, KrbFastReq, KrbFastResponse, PaFxFastReply, PaFxFastRequest,
+    FX_FAST_ARMOR_AP_REQUEST, KEY_USAGE_ENC_CHALLENGE_CLIENT, KEY_USAGE_ENC_CHALLENGE_KDC, KEY_USAGE_FAST_ENC,
+    KEY_USAGE_FAST_FINISHED, KEY_USAGE_FAST_REP, KEY_USAGE_FAST_REQ_CHKSUM, PA_ENCRYPTED_CHALLENGE_TYPE,
+    PA_FX_COOKIE_TYPE, PA_FX_ERROR_TYPE, PA_FX_FAST_TYPE,
+};
+use crate::kerberos::flags::ApOptions;
+use crate::kerberos::server::DEFAULT_MAX_TIME_SKEW;
+use crate::kerberos::EncryptionParams;
+use crate::utils::generate_random_symmetric_key;
+use crate::{Error, ErrorKind, Result};
+
+/// [RFC 3962 4](https://www.rfc-editor.org/rfc/rfc3962#section-4): the derivation constant of the PRF key.
+const PRF_CONSTANT: &[u8] = b"prf";
+const AES_BLOCK_SIZE: usize = 16;
+
+/// Source of the ticket that armors the AS exchange.
+#[derive(Debug, Clone)]
+pub enum FastArmor {
+    /// The TGT of the default principal of the ticket cache, e.g. the host TGT obtained using the keytab.
+    TicketCache(TicketCache),
+    /// The anonymous TGT obtained using the [anonymous PKINIT](https://www.rfc-editor.org/rfc/rfc8062).
+    AnonymousPkinit,
+}
+
+/// [RFC 3962 4](https://www.rfc-editor.org/rfc/rfc3962#section-4): pseudo-random function of the AES encryption types.
+///
+/// `PRF = E(DK(key, "prf"), truncate(SHA1(data)))`
+pub fn prf(key: &EncKey, data: &[u8]) -> Result<Vec<u8>> {
+    let aes_size = match key.key_type {
+        CipherSuite::Aes256CtsHmacSha196 => AesSize::Aes256,
+        CipherSuite::Aes128CtsHmacSha196 => AesSize::Aes128,
+        ref key_type => {
+            return Err(Error::new(
+                ErrorKind::UnsupportedFunction,
+                format!("PRF is not supported for the {:?} encryption type", key_type),
+            ))
+        }
+    };
+
+    let prf_key = derive_key(&key.key_value, PRF_CONSTANT, &aes_size)?;
+    let mut block = GenericArray::clone_from_slice(&Sha1::digest(data)[..AES_BLOCK_SIZE]);
+
+    let invalid_key = |_| Error::new(ErrorKind::InvalidParameter, "Invalid PRF key length");
+    match aes_size {
+        AesSize::Aes256 => Aes256::new_from_slice(&prf_key)
+            .map_err(invalid_key)?
+            .encrypt_block(&mut block),
+        AesSize::Aes128 => Aes128::new_from_slice(&prf_key)
+            .map_err(invalid_key)?
+            .encrypt_block(&mut block),
+    }
+
+    Ok(block.to_vec())
+}
+
+/// [RFC 6113 5.1](https://www.rfc-editor.org/rfc/rfc6113#section-5.1)
+///
+/// `PRF+(key, shared-info) = PRF(key, 1 || shared-info) || PRF(key, 2 || shared-info) || ...`
+fn prf_plus(key: &EncKey, shared_info: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(len);
+    let mut counter = 1_u8;
+
+    while output.len() < len {
+        let mut data = vec![counter];
+        data.extend_from_slice(shared_info);
+
+        output.extend_from_slice(&prf(key, &data)?);
+        counter += 1;
+    }
+    output.truncate(len);
+
+    Ok(output)
+}
+
+/// [RFC 6113 5.1](https://www.rfc-editor.org/rfc/rfc6113#section-5.1): combines two keys into a key of the
+/// encryption type of the first one.
+///
+/// `KRB-FX-CF2(key1, key2, pepper1, pepper2) = random-to-key(PRF+(key1, pepper1) ^ PRF+(key2, pepper2))`
+pub fn krb_fx_cf2(key1: &EncKey, key2: &EncKey, pepper1: &[u8], pepper2: &[u8]) -> Result<EncKey> {
+    let cipher = key1.key_type.cipher();
+
+    let mut key = prf_plus(key1, pepper1, cipher.key_size())?;
+    key.iter_mut()
+        .zip(prf_plus(key2, pepper2, cipher.key_size())?)
+        .for_each(|(byte1, byte2)| *byte1 ^= byte2);
+
+    Ok(EncKey {
+        key_type: key1.key_type.clone(),
+        key_value: cipher.random_to_key(key),
+    })
+}
+
+/// [RFC 6113 5.4.6](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.6): the PA-ENCRYPTED-CHALLENGE replaces
+/// the PA-ENC-TIMESTAMP in the armored AS-REQ.
+///
+/// The timestamp is encrypted in `KRB-FX-CF2(armor key, long-term key, "clientchallengearmor", "challengelongterm")`.
+pub fn generate_encrypted_challenge(armor_key: &EncKey, long_term_key: &EncKey, timestamp: &[u8]) -> Result<PaData> {
+    let challenge_key = krb_fx_cf2(armor_key, long_term_key, b"clientchallengearmor", b"challengelongterm")?;
+
+    Ok(PaData {
+        padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_ENCRYPTED_CHALLENGE_TYPE.to_vec())),
+        padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&encrypt(
+            &challenge_key,
+            KEY_USAGE_ENC_CHALLENGE_CLIENT,
+            timestamp,
+        )?)?)),
+    })
+}
+
+/// [RFC 6113 5.4.3](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.3): the KDC replaces the reply key with
+/// `KRB-FX-CF2(strengthen-key, reply-key, "strengthenkey", "replykey")` if the strengthen key is present.
+pub fn strengthen_reply_key(fast_response: &KrbFastResponse, reply_key: &EncKey) -> Result<EncKey> {
+    match fast_response.strengthen_key.0.as_ref() {
+        Some(strengthen_key) => krb_fx_cf2(
+            &EncKey {
+                key_type: CipherSuite::try_from(strengthen_key.0.key_type.0 .0.as_slice())?,
+                key_value: strengthen_key.0.key_value.0 .0.clone(),
+            },
+            reply_key,
+            b"strengthenkey",
+            b"replykey",
+        ),
+        None => Ok(reply_key.clone()),
+    }
+}
+
+fn encrypt(key: &EncKey, key_usage: i32, data: &[u8]) -> Result<EncryptedData> {
+    Ok(EncryptedData {
+        etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![(&key.key_type).into()])),
+        kvno: Optional::from(None),
+        cipher: ExplicitContextTag2::from(OctetStringAsn1::from(key.key_type.cipher().encrypt(
+            &key.key_value,
+            key_usage,
+            data,
+        )?)),
+    })
+}
+
+fn checksum_suite(key: &EncKey) -> Result<ChecksumSuite> {
+    match key.key_type {
+        CipherSuite::Aes256CtsHmacSha196 => Ok(ChecksumSuite::HmacSha196Aes256),
+        CipherSuite::Aes128CtsHmacSha196 => Ok(ChecksumSuite::HmacSha196Aes128),
+        ref key_type => Err(Error::new(
+            ErrorKind::UnsupportedFunction,
+            format!("FAST is not supported for the {:?} encryption type", key_type),
+        )),
+    }
+}
+
+/// [RFC 6113 5.2](https://www.rfc-editor.org/rfc/rfc6113#section-5.2): extracts the PA-FX-COOKIE from the
+/// `e-data` of the KRB-ERROR.
+pub fn extract_fx_cookie(krb_error: &KrbError) -> Result<Option<PaData>> {
+    let Some(e_data) = krb_error.0.e_data.0.as_ref() else {
+        return Ok(None);
+    };
+    let pa_datas: Asn1SequenceOf<PaData> = picky_asn1_der::from_bytes(&e_data.0 .0)?;
+
+    Ok(find_pa_data(&pa_datas.0, &PA_FX_COOKIE_TYPE).cloned())
+}
+
+fn find_pa_data<'a>(pa_datas: &'a [PaData], padata_type: &[u8]) -> Option<&'a PaData> {
+    pa_datas.iter().find(|pa_data| pa_data.padata_type.0 .0 == padata_type)
+}
+
+/// The armor of the AS or TGS request and the armor key derived from it.
+#[derive(Debug)]
+pub struct FastArmorKey {
+    /// The armor is absent in the TGS request: the TGS-REQ AP-REQ is the implicit armor.
+    armor: Option<KrbFastArmor>,
+    key: EncKey,
+}
+
+impl FastArmorKey {
+    /// [RFC 6113 5.4.1.1](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.1.1): the AS-REQ is armored with
+    /// the AP-REQ for the armor TGT. The authenticator of the AP-REQ carries a random subkey.
+    pub fn ap_request(crealm: &Realm, cname: &PrincipalName, ticket: Ticket, session_key: &EncKey) -> Result<Self> {
+        let sub_key = EncKey {
+            key_type: session_key.key_type.clone(),
+            key_value: generate_random_symmetric_key(&session_key.key_type, &mut OsRng),
+        };
+
+        let authenticator = generate_authenticator(GenerateAuthenticatorOptions {
+            crealm,
+            cname,
+            seq_num: None,
+            sub_key: Some(sub_key.clone()),
+            checksum: None,
+            channel_bindings: None,
+            extensions: Vec::new(),
+        })?;
+        let enc_params = EncryptionParams {
+            encryption_type: Some(session_key.key_type.clone()),
+            ..EncryptionParams::default_for_client()
+        };
+        let ap_req = generate_ap_req(
+            ticket,
+            &session_key.key_value,
+            &authenticator,
+            &enc_params,
+            ApOptions::empty(),
+        )?;
+
+        Ok(Self {
+            armor: Some(KrbFastArmor {
+                armor_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![FX_FAST_ARMOR_AP_REQUEST])),
+                armor_value: ExplicitContextTag1::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&ap_req)?)),
+            }),
+            key: Self::armor_key(&sub_key, session_key)?,
+        })
+    }
+
+    /// [RFC 6113 5.4.1.1](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.1.1): the TGS-REQ is armored
+    /// implicitly with its own AP-REQ. The authenticator of the AP-REQ must carry the `sub_key`.
+    pub fn tgs_request(sub_key: &EncKey, session_key: &EncKey) -> Result<Self> {
+        Ok(Self {
+            armor: None,
+            key: Self::armor_key(sub_key, session_key)?,
+        })
+    }
+
+    /// `KRB-FX-CF2(subkey, ticket session key, "subkeyarmor", "ticketarmor")`
+    fn armor_key(sub_key: &EncKey, session_key: &EncKey) -> Result<EncKey> {
+        krb_fx_cf2(sub_key, session_key, b"subkeyarmor", b"ticketarmor")
+    }
+
+    pub fn key(&self) -> &EncKey {
+        &self.key
+    }
+
+    /// [RFC 6113 5.4.2](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.2): moves the pa-datas of the request
+    /// to the encrypted KrbFastReq and adds the PA-FX-FAST to the request.
+    ///
+    /// The PA-TGS-REQ stays in the outer request and the request checksum is computed over its AP-REQ.
+    /// Otherwise, the checksum is computed over the KDC-REQ-BODY.
+    pub fn armor_request(&self, kdc_req: &mut KdcReq) -> Result<()> {
+        let pa_datas = kdc_req
+            .padata
+            .0
+            .take()
+            .map(|pa_datas| pa_datas.0 .0)
+            .unwrap_or_default();
+        let (mut outer_pa_datas, inner_pa_datas): (Vec<_>, Vec<_>) = pa_datas
+            .into_iter()
+            .partition(|pa_data| pa_data.padata_type.0 .0 == PA_TGS_REQ_TYPE);
+
+        let checksummed_data = match outer_pa_datas.first() {
+            Some(pa_tgs_req) => pa_tgs_req.padata_data.0 .0.clone(),
+            None => picky_asn1_der::to_vec(&kdc_req.req_body.0)?,
+        };
+
+        let fast_req = KrbFastReq {
+            fast_options: ExplicitContextTag0::from(KerberosFlags::from(BitString::with_bytes(vec![0x00; 4]))),
+            padata: ExplicitContextTag1::from(Asn1SequenceOf::from(inner_pa_datas)),
+            req_body: ExplicitContextTag2::from(kdc_req.req_body.0.clone()),
+        };
+
+        let checksum_suite = checksum_suite(&self.key)?;
+        let req_checksum = Checksum {
+            cksumtype: ExplicitContextTag0::from(IntegerAsn1::from(vec![u8::from(checksum_suite.clone())])),
+            checksum: ExplicitContextTag1::from(OctetStringAsn1::from(checksum_suite.hasher().checksum(
+                &self.key.key_value,
+                KEY_USAGE_FAST_REQ_CHKSUM,
+                &checksummed_data,
+            )?)),
+        };
+
+        let armored_req = PaFxFastRequest::from(KrbFastArmoredReq {
+            armor: Optional::from(self.armor.clone().map(ExplicitContextTag0::from)),
+            req_checksum: ExplicitContextTag1::from(req_checksum),
+            enc_fast_req: ExplicitContextTag2::from(encrypt(
+                &self.key,
+                KEY_USAGE_FAST_ENC,
+                &picky_asn1_der::to_vec(&fast_req)?,
+            )?),
+        });
+
+        outer_pa_datas.push(PaData {
+            padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_FX_FAST_TYPE.to_vec())),
+            padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&armored_req)?)),
+        });
+        kdc_req.padata = Optional::from(Some(ExplicitContextTag3::from(Asn1SequenceOf::from(outer_pa_datas))));
+
+        Ok(())
+    }
+
+    /// Decrypts the KrbFastResponse from the PA-FX-FAST of the KDC reply.
+    pub fn extract_response(&self, pa_datas: &[PaData]) -> Result<KrbFastResponse> {
+        let pa_fx_fast = find_pa_data(pa_datas, &PA_FX_FAST_TYPE)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "The KDC reply is not FAST-armored"))?;
+
+        let fast_reply: PaFxFastReply = picky_asn1_der::from_bytes(&pa_fx_fast.padata_data.0 .0)?;
+        let fast_response = self.key.key_type.cipher().decrypt(
+            &self.key.key_value,
+            KEY_USAGE_FAST_REP,
+            &fast_reply.0.enc_fast_rep.0.cipher.0 .0,
+        )?;
+
+        Ok(picky_asn1_der::from_bytes(&fast_response)?)
+    }
+
+    /// [RFC 6113 5.4.4](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.4): extracts the KRB-ERROR from the
+    /// PA-FX-ERROR of the armored KRB-ERROR.
+    ///
+    /// The rest of the FAST response pa-datas (e.g. ETYPE-INFO2 or PA-FX-COOKIE) is placed in the `e-data` of
+    /// the returned error.
+    pub fn extract_error(&self, krb_error: &KrbError) -> Result<KrbError> {
+        let e_data = krb_error
+            .0
+            .e_data
+            .0
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "The KDC error is not FAST-armored"))?;
+        let pa_datas: Asn1SequenceOf<PaData> = picky_asn1_der::from_bytes(&e_data.0 .0)?;
+
+        let fast_response = self.extract_response(&pa_datas.0)?;
+        let (fx_errors, method_data): (Vec<_>, Vec<_>) = fast_response
+            .padata
+            .0
+             .0
+            .into_iter()
+            .partition(|pa_data| pa_data.padata_type.0 .0 == PA_FX_ERROR_TYPE);
+
+        let fx_error = fx_errors.first().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidToken,
+                "PA-FX-ERROR is not present in the FAST response",
+            )
+        })?;
+
+        let mut error: KrbError = picky_asn1_der::from_bytes(&fx_error.padata_data.0 .0)?;
+        error.0.e_data = Optional::from(Some(ExplicitContextTag12::from(OctetStringAsn1::from(
+            picky_asn1_der::to_vec(&Asn1SequenceOf::from(method_data))?,
+        ))));
+
+        Ok(error)
+    }
+
+    /// [RFC 6113 5.4.3](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.3): checks that the FAST response
+    /// belongs to the request with the `nonce` and that the KrbFastFinished matches the reply.
+    pub fn validate_response(
+        &self,
+        fast_response: &KrbFastResponse,
+        kdc_rep: &KdcRep,
+        nonce: &IntegerAsn1,
+    ) -> Result<()> {
+        if fast_response.nonce.0.as_unsigned_bytes_be() != nonce.as_unsigned_bytes_be() {
+            return Err(Error::new(
+                ErrorKind::MessageAltered,
+                "The FAST response nonce does not match the request nonce",
+            ));
+        }
+
+        let finished = &fast_response
+            .finished
+            .0
+            .as_ref()
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidToken,
+                    "KrbFastFinished is not present in the FAST response",
+                )
+            })?
+            .0;
+
+        if finished.crealm.0 != kdc_rep.crealm.0 || finished.cname.0 != kdc_rep.cname.0 {
+            return Err(Error::new(
+                ErrorKind::MessageAltered,
+                "The KrbFastFinished client name does not match the KDC reply",
+            ));
+        }
+
+        let ticket_checksum = &finished.ticket_checksum.0;
+        let checksum_type = ticket_checksum
+            .cksumtype
+            .0
+             .0
+            .last()
+            .copied()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Missing KrbFastFinished checksum type"))?;
+
+        let checksum = ChecksumSuite::try_from(usize::from(checksum_type))?.hasher().checksum(
+            &self.key.key_value,
+            KEY_USAGE_FAST_FINISHED,
+            &picky_asn1_der::to_vec(&kdc_rep.ticket.0)?,
+        )?;
+
+        if ticket_checksum.checksum.0 .0 != checksum {
+            return Err(Error::new(
+                ErrorKind::MessageAltered,
+                "Invalid KrbFastFinished ticket checksum",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// [RFC 6113 5.4.6](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.6): the KDC proves the knowledge of the
+    /// client's long-term key with the PA-ENCRYPTED-CHALLENGE in the FAST response.
+    ///
+    /// The challenge is checked only if the KDC has sent it.
+    pub fn validate_kdc_challenge(&self, fast_response: &KrbFastResponse, long_term_key: &EncKey) -> Result<()> {
+        let Some(pa_encrypted_challenge) = find_pa_data(&fast_response.padata.0 .0, &PA_ENCRYPTED_CHALLENGE_TYPE)
+        else {
+            return Ok(());
+        };
+
+        let challenge_key = krb_fx_cf2(&self.key, long_term_key, b"kdcchallengearmor", b"challengelongterm")?;
+        let encrypted_challenge: EncryptedData = picky_asn1_der::from_bytes(&pa_encrypted_challenge.padata_data.0 .0)?;
+
+        let timestamp: PaEncTsEnc = picky_asn1_der::from_bytes(&challenge_key.key_type.cipher().decrypt(
+            &challenge_key.key_value,
+            KEY_USAGE_ENC_CHALLENGE_KDC,
+            &encrypted_challenge.cipher.0 .0,
+        )?)?;
+
+        let timestamp = OffsetDateTime::try_from(timestamp.patimestamp.0 .0)
+            .map_err(|err| Error::new(ErrorKind::InvalidToken, format!("Invalid Kerberos time: {:?}", err)))?;
+        if (OffsetDateTime::now_utc() - timestamp).abs() > DEFAULT_MAX_TIME_SKEW {
+            return Err(Error::new(ErrorKind::TimeSkew, "Clock skew too great"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use picky_asn1::date::GeneralizedTime;
+    use picky_asn1::restricted_string::IA5String;
+    use picky_asn1::wrapper::{
+        ExplicitContextTag10, ExplicitContextTag4, ExplicitContextTag5, ExplicitContextTag6, ExplicitContextTag9,
+        GeneralizedTimeAsn1,
+    };
+    use picky_krb::data_types::{EncryptionKey, KerberosStringAsn1, TicketInner};
+    use picky_krb::messages::KrbErrorInner;
+
+    use super::*;
+    use crate::kerberos::client::generators::{generate_as_req_kdc_body, GenerateAsReqOptions};
+    use crate::kerberos::data_types::{KrbFastArmoredRep, KrbFastFinished};
+    use crate::kerberos::KERBEROS_VERSION;
+    use crate::ClientRequestFlags;
+
+    fn key_from_password(key_type: CipherSuite, password: &str) -> EncKey {
+        EncKey {
+            key_value: key_type
+                .cipher()
+                .generate_key_from_password(password.as_bytes(), password.as_bytes())
+                .unwrap(),
+            key_type,
+        }
+    }
+
+    fn armor_key() -> FastArmorKey {
+        FastArmorKey::tgs_request(
+            &key_from_password(CipherSuite::Aes256CtsHmacSha196, "subkey"),
+            &key_from_password(CipherSuite::Aes256CtsHmacSha196, "session key"),
+        )
+        .unwrap()
+    }
+
+    fn pa_data(padata_type: &[u8], data: Vec<u8>) -> PaData {
+        PaData {
+            padata_type: ExplicitContextTag1::from(IntegerAsn1::from(padata_type.to_vec())),
+            padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(data)),
+        }
+    }
+
+    fn realm() -> Realm {
+        Realm::from(IA5String::from_str("EXAMPLE.COM").unwrap())
+    }
+
+    fn principal_name(name: &str) -> PrincipalName {
+        PrincipalName {
+            name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![1])),
+            name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![KerberosStringAsn1::from(
+                IA5String::from_str(name).unwrap(),
+            )])),
+        }
+    }
+
+    fn kdc_rep() -> KdcRep {
+        KdcRep {
+            pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![11])),
+            padata: Optional::from(None),
+            crealm: ExplicitContextTag3::from(realm()),
+            cname: ExplicitContextTag4::from(principal_name("alice")),
+            ticket: ExplicitContextTag5::from(Ticket::from(TicketInner {
+                tkt_vno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+                realm: ExplicitContextTag1::from(realm()),
+                sname: ExplicitContextTag2::from(principal_name("krbtgt")),
+                enc_part: ExplicitContextTag3::from(EncryptedData {
+                    etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![CipherSuite::Aes256CtsHmacSha196.into()])),
+                    kvno: Optional::from(None),
+                    cipher: ExplicitContextTag2::from(OctetStringAsn1::from(vec![1, 2, 3, 4])),
+                }),
+            })),
+            enc_part: ExplicitContextTag6::from(EncryptedData {
+                etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![CipherSuite::Aes256CtsHmacSha196.into()])),
+                kvno: Optional::from(None),
+                cipher: ExplicitContextTag2::from(OctetStringAsn1::from(vec![5, 6, 7, 8])),
+            }),
+        }
+    }
+
+    fn tgs_req(pa_datas: Vec<PaData>) -> KdcReq {
+        let req_body = generate_as_req_kdc_body(&GenerateAsReqOptions {
+            realm: "EXAMPLE.COM",
+            username: "alice",
+            cname_type: 1,
+            snames: &["krbtgt", "EXAMPLE.COM"],
+            nonce: &[0x12, 0x34, 0x56, 0x78],
+            hostname: "WORKSTATION",
+            context_requirements: ClientRequestFlags::empty(),
+            etypes: &[CipherSuite::Aes256CtsHmacSha196],
+        })
+        .unwrap();
+
+        KdcReq {
+            pvno: ExplicitContextTag1::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            msg_type: ExplicitContextTag2::from(IntegerAsn1::from(vec![12])),
+            padata: Optional::from(Some(ExplicitContextTag3::from(Asn1SequenceOf::from(pa_datas)))),
+            req_body: ExplicitContextTag4::from(req_body),
+        }
+    }
+
+    fn fast_response(armor_key: &FastArmorKey, kdc_rep: &KdcRep, padata: Vec<PaData>) -> KrbFastResponse {
+        let checksum_suite = checksum_suite(armor_key.key()).unwrap();
+
+        KrbFastResponse {
+            padata: ExplicitContextTag0::from(Asn1SequenceOf::from(padata)),
+            strengthen_key: Optional::from(None),
+            finished: Optional::from(Some(ExplicitContextTag2::from(KrbFastFinished {
+                timestamp: ExplicitContextTag0::from(GeneralizedTimeAsn1::from(GeneralizedTime::from(
+                    OffsetDateTime::now_utc(),
+                ))),
+                usec: ExplicitContextTag1::from(IntegerAsn1::from(vec![0])),
+                crealm: ExplicitContextTag2::from(kdc_rep.crealm.0.clone()),
+                cname: ExplicitContextTag3::from(kdc_rep.cname.0.clone()),
+                ticket_checksum: ExplicitContextTag4::from(Checksum {
+                    cksumtype: ExplicitContextTag0::from(IntegerAsn1::from(vec![u8::from(checksum_suite.clone())])),
+                    checksum: ExplicitContextTag1::from(OctetStringAsn1::from(
+                        checksum_suite
+                            .hasher()
+                            .checksum(
+                                &armor_key.key().key_value,
+                                KEY_USAGE_FAST_FINISHED,
+                                &picky_asn1_der::to_vec(&kdc_rep.ticket.0).unwrap(),
+                            )
+                            .unwrap(),
+                    )),
+                }),
+            }))),
+            nonce: ExplicitContextTag3::from(IntegerAsn1::from(vec![0x12, 0x34, 0x56, 0x78])),
+        }
+    }
+
+    fn pa_fx_fast_reply(armor_key: &FastArmorKey, fast_response: &KrbFastResponse) -> PaData {
+        let fast_reply = PaFxFastReply::from(KrbFastArmoredRep {
+            enc_fast_rep: ExplicitContextTag0::from(
+                encrypt(
+                    armor_key.key(),
+                    KEY_USAGE_FAST_REP,
+                    &picky_asn1_der::to_vec(fast_response).unwrap(),
+                )
+                .unwrap(),
+            ),
+        });
+
+        pa_data(&PA_FX_FAST_TYPE, picky_asn1_der::to_vec(&fast_reply).unwrap())
+    }
+
+    fn krb_error(e_data: Option<Vec<u8>>) -> KrbError {
+        KrbError::from(KrbErrorInner {
+            pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![30])),
+            ctime: Optional::from(None),
+            cusec: Optional::from(None),
+            stime: ExplicitContextTag4::from(GeneralizedTimeAsn1::from(GeneralizedTime::from(
+                OffsetDateTime::now_utc(),
+            ))),
+            susec: ExplicitContextTag5::from(IntegerAsn1::from(vec![0])),
+            error_code: ExplicitContextTag6::from(25),
+            crealm: Optional::from(None),
+            cname: Optional::from(None),
+            realm: ExplicitContextTag9::from(realm()),
+            sname: ExplicitContextTag10::from(principal_name("krbtgt")),
+            e_text: Optional::from(None),
+            e_data: Optional::from(e_data.map(|e_data| ExplicitContextTag12::from(OctetStringAsn1::from(e_data)))),
+        })
+    }
+
+    #[test]
+    fn krb_fx_cf2_test_vectors() {
+        // [RFC 6113 A](https://www.rfc-editor.org/rfc/rfc6113#appendix-A)
+        let cases = [
+            (CipherSuite::Aes128CtsHmacSha196, "97df97e4b798b29eb31ed7280287a92a"),
+            (
+                CipherSuite::Aes256CtsHmacSha196,
+                "4d6ca4e629785c1f01baf55e2e548566b9617ae3a96868c337cb93b5e72b1c7b",
+            ),
+        ];
+
+        for (key_type, expected) in cases {
+            let key = krb_fx_cf2(
+                &key_from_password(key_type.clone(), "key1"),
+                &key_from_password(key_type.clone(), "key2"),
+                b"a",
+                b"b",
+            )
+            .unwrap();
+
+            assert_eq!(key.key_type, key_type);
+            assert_eq!(
+                key.key_value,
+                (0..expected.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&expected[i..i + 2], 16).unwrap())
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn armor_request_moves_pa_datas_to_fast_request() {
+        let armor_key = armor_key();
+        let ap_req = vec![0xa, 0xb, 0xc];
+        let mut kdc_req = tgs_req(vec![
+            pa_data(&PA_TGS_REQ_TYPE, ap_req.clone()),
+            pa_data(&[128], vec![1, 2, 3]),
+        ]);
+
+        armor_key.armor_request(&mut kdc_req).unwrap();
+
+        let pa_datas = &kdc_req.padata.0.as_ref().unwrap().0 .0;
+        assert_eq!(pa_datas.len(), 2);
+        assert_eq!(pa_datas[0].padata_type.0 .0, PA_TGS_REQ_TYPE);
+
+        let armored_req: PaFxFastRequest =
+            picky_asn1_der::from_bytes(&find_pa_data(pa_datas, &PA_FX_FAST_TYPE).unwrap().padata_data.0 .0).unwrap();
+        assert!(armored_req.0.armor.0.is_none());
+
+        let checksum = ChecksumSuite::HmacSha196Aes256
+            .hasher()
+            .checksum(&armor_key.key().key_value, KEY_USAGE_FAST_REQ_CHKSUM, &ap_req)
+            .unwrap();
+        assert_eq!(armored_req.0.req_checksum.0.checksum.0 .0, checksum);
+
+        let fast_req: KrbFastReq = picky_asn1_der::from_bytes(
+            &armor_key
+                .key()
+                .key_type
+                .cipher()
+                .decrypt(
+                    &armor_key.key().key_value,
+                    KEY_USAGE_FAST_ENC,
+                    &armored_req.0.enc_fast_req.0.cipher.0 .0,
+                )
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(fast_req.padata.0 .0, vec![pa_data(&[128], vec![1, 2, 3])]);
+        assert_eq!(fast_req.req_body.0, kdc_req.req_body.0);
+    }
+
+    #[test]
+    fn extract_error_from_fast_response() {
+        let armor_key = armor_key();
+        let inner_error = krb_error(None);
+        let cookie = pa_data(&PA_FX_COOKIE_TYPE, vec![1, 2, 3, 4]);
+
+        let fast_response = KrbFastResponse {
+            padata: ExplicitContextTag0::from(Asn1SequenceOf::from(vec![
+                pa_data(&PA_FX_ERROR_TYPE, picky_asn1_der::to_vec(&inner_error).unwrap()),
+                cookie.clone(),
+            ])),
+            strengthen_key: Optional::from(None),
+            finished: Optional::from(None),
+            nonce: ExplicitContextTag3::from(IntegerAsn1::from(vec![1])),
+        };
+        let outer_error = krb_error(Some(
+            picky_asn1_der::to_vec(&Asn1SequenceOf::from(vec![pa_fx_fast_reply(
+                &armor_key,
+                &fast_response,
+            )]))
+            .unwrap(),
+        ));
+
+        let error = armor_key.extract_error(&outer_error).unwrap();
+
+        assert_eq!(error.0.error_code, inner_error.0.error_code);
+        assert_eq!(extract_fx_cookie(&error).unwrap(), Some(cookie));
+        assert!(armor_key.extract_error(&inner_error).is_err());
+    }
+
+    #[test]
+    fn validate_fast_response() {
+        let armor_key = armor_key();
+        let kdc_rep = kdc_rep();
+        let fast_response = fast_response(&armor_key, &kdc_rep, Vec::new());
+
+        let pa_datas = [pa_fx_fast_reply(&armor_key, &fast_response)];
+        let extracted = armor_key.extract_response(&pa_datas).unwrap();
+        assert_eq!(extracted, fast_response);
+
+        let nonce = IntegerAsn1::from(vec![0x12, 0x34, 0x56, 0x78]);
+        armor_key.validate_response(&extracted, &kdc_rep, &nonce).unwrap();
+
+        assert!(armor_key
+            .validate_response(&extracted, &kdc_rep, &IntegerAsn1::from(vec![1]))
+            .is_err());
+
+        let mut other_kdc_rep = kdc_rep.clone();
+        other_kdc_rep.ticket.0 .0.sname = ExplicitContextTag2::from(principal_name("other"));
+        assert!(armor_key.validate_response(&extracted, &other_kdc_rep, &nonce).is_err());
+    }
+
+    #[test]
+    fn strengthen_reply_key_uses_strengthen_key() {
+        let armor_key = armor_key();
+        let reply_key = key_from_password(CipherSuite::Aes256CtsHmacSha196, "password");
+        let mut fast_response = fast_response(&armor_key, &kdc_rep(), Vec::new());
+
+        assert_eq!(strengthen_reply_key(&fast_response, &reply_key).unwrap(), reply_key);
+
+        let strengthen_key = key_from_password(CipherSuite::Aes128CtsHmacSha196, "strengthen key");
+        fast_response.strengthen_key = Optional::from(Some(ExplicitContextTag1::from(EncryptionKey {
+            key_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![(&strengthen_key.key_type).into()])),
+            key_value: ExplicitContextTag1::from(OctetStringAsn1::from(strengthen_key.key_value.clone())),
+        })));
+
+        assert_eq!(
+            strengthen_reply_key(&fast_response, &reply_key).unwrap(),
+            krb_fx_cf2(&strengthen_key, &reply_key, b"strengthenkey", b"replykey").unwrap()
+        );
+    }
+}
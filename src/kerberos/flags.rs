@@ -1,4 +1,5 @@
 use bitflags::bitflags;
+use picky_krb::data_types::KerberosFlags;
 
 use crate::ClientRequestFlags;
 
@@ -17,7 +18,10 @@ bitflags! {
         const POSTDATED = 0x02000000;
         const RENEWABLE = 0x00800000;
         const OPT_HARDWARE_AUTH = 0x00100000;
+        const CNAME_IN_ADDL_TKT = 0x00020000;
         const CANONICALIZE = 0x00010000;
+        /// [RFC 8062 3](https://www.rfc-editor.org/rfc/rfc8062#section-3): requests an anonymous ticket.
+        const REQUEST_ANONYMOUS = 0x00008000;
         const DISABLE_TRANSITED_CHECK = 0x00000020;
         const RENEWABLE_OK = 0x00000010;
         const ENC_TKT_IN_SKEY = 0x00000008;
@@ -38,6 +42,24 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// This flags appears in the tickets issued by the KDC.
+    ///
+    /// [TicketFlags](https://www.rfc-editor.org/rfc/rfc4120#section-5.3)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct TicketFlags: u32 {
+        const FORWARDABLE = 0x40000000;
+        const FORWARDED = 0x20000000;
+        const PROXIABLE = 0x10000000;
+        const PROXY = 0x08000000;
+        const RENEWABLE = 0x00800000;
+        const INITIAL = 0x00400000;
+        const PRE_AUTHENT = 0x00200000;
+        const HW_AUTHENT = 0x00100000;
+        const OK_AS_DELEGATE = 0x00040000;
+    }
+}
+
 impl From<ClientRequestFlags> for ApOptions {
     fn from(flags: ClientRequestFlags) -> Self {
         let mut ap_options = ApOptions::empty();
@@ -52,3 +74,14 @@ impl From<ClientRequestFlags> for ApOptions {
         ap_options
     }
 }
+
+impl From<&KerberosFlags> for TicketFlags {
+    fn from(flags: &KerberosFlags) -> Self {
+        let mut bytes = [0; 4];
+        let payload = flags.0.payload_view();
+        let len = payload.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&payload[..len]);
+
+        TicketFlags::from_bits_retain(u32::from_be_bytes(bytes))
+    }
+}
@@ -0,0 +1,343 @@
+//! [RFC 4757 7](https://www.rfc-editor.org/rfc/rfc4757#section-7): GSS-API per-message tokens for the RC4-HMAC
+//! encryption type.
+//!
+//! Unlike [RFC 4121](https://www.rfc-editor.org/rfc/rfc4121) tokens, they use the RFC 1964 format: the header is
+//! wrapped into the GSS-API framing, and the sequence number is encrypted.
+//!
+//! [RFC 1964 1.2.2.3](https://www.rfc-editor.org/rfc/rfc1964#section-1.2.2.3): the wrapped data is always padded.
+//! RC4 is a stream cipher with the block size of 1 byte, so the padding is the single `0x01` byte.
+
+use rand::rngs::OsRng;
+use rand::Rng;
+
+use crate::crypto::{compute_hmac_md5, compute_md5, Rc4, HASH_SIZE};
+use crate::{Error, ErrorKind, Result};
+
+// [RFC 2743 3.1](https://www.rfc-editor.org/rfc/rfc2743#section-3.1): InitialContextToken framing
+const GSS_FRAMING_TAG: u8 = 0x60;
+// DER-encoded Kerberos V5 mechanism OID: 1.2.840.113554.1.2.2
+const KRB5_MECH_OID: [u8; 11] = [0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x02];
+
+const MIC_TOKEN_ID: [u8; 2] = [0x01, 0x01];
+const WRAP_TOKEN_ID: [u8; 2] = [0x02, 0x01];
+// HMAC
+const SGN_ALG: [u8; 2] = [0x11, 0x00];
+// RC4
+const SEAL_ALG: [u8; 2] = [0x10, 0x00];
+const NO_SEAL_FILLER: [u8; 4] = [0xff; 4];
+const SEAL_FILLER: [u8; 2] = [0xff; 2];
+
+const MIC_KEY_USAGE: i32 = 15;
+const WRAP_KEY_USAGE: i32 = 13;
+
+const TOKEN_HEADER_LEN: usize = 8;
+const SND_SEQ_LEN: usize = 8;
+const SGN_CKSUM_LEN: usize = 8;
+const CONFOUNDER_LEN: usize = 8;
+const PADDING: [u8; 1] = [0x01];
+// [RFC 1964 1.2.2.3](https://www.rfc-editor.org/rfc/rfc1964#section-1.2.2.3): "the padding is from one to eight bytes"
+const MAX_PADDING_LEN: u8 = 8;
+const MIC_TOKEN_LEN: usize = TOKEN_HEADER_LEN + SND_SEQ_LEN + SGN_CKSUM_LEN;
+const WRAP_TOKEN_LEN: usize = MIC_TOKEN_LEN + CONFOUNDER_LEN;
+
+// GSS framing: tag (1 byte) + length (1 byte) + mechanism OID
+pub const MAX_SIGNATURE: usize = 2 + KRB5_MECH_OID.len() + MIC_TOKEN_LEN;
+// GSS framing: tag (1 byte) + length (up to 5 bytes) + mechanism OID
+pub const SECURITY_TRAILER: usize = 6 + KRB5_MECH_OID.len() + WRAP_TOKEN_LEN;
+pub const BLOCK_SIZE: usize = PADDING.len();
+
+/// Computes the MIC token over the `data`.
+pub fn make_mic_token(key: &[u8], seq_number: u32, sent_by_acceptor: bool, data: &[u8]) -> Result<Vec<u8>> {
+    let mut header = MIC_TOKEN_ID.to_vec();
+    header.extend_from_slice(&SGN_ALG);
+    header.extend_from_slice(&NO_SEAL_FILLER);
+
+    let checksum = sgn_cksum(key, MIC_KEY_USAGE, &header, &[], data)?;
+    let snd_seq = encrypt_snd_seq(key, &checksum, &snd_seq(seq_number, sent_by_acceptor))?;
+
+    let mut token = header;
+    token.extend_from_slice(&snd_seq);
+    token.extend_from_slice(&checksum);
+
+    Ok(frame(&token, token.len()))
+}
+
+/// Verifies the MIC token over the `data` and returns the sequence number of the token.
+pub fn verify_mic_token(key: &[u8], sent_by_acceptor: bool, token: &[u8], data: &[u8]) -> Result<u32> {
+    let token = unframe(token, MIC_TOKEN_LEN)?;
+    let (header, token) = token.split_at(TOKEN_HEADER_LEN);
+    let (snd_seq, checksum) = token.split_at(SND_SEQ_LEN);
+
+    if header[0..2] != MIC_TOKEN_ID || header[2..4] != SGN_ALG || header[4..8] != NO_SEAL_FILLER {
+        return Err(Error::new(ErrorKind::InvalidToken, "invalid RC4 mic token header"));
+    }
+
+    if sgn_cksum(key, MIC_KEY_USAGE, header, &[], data)? != checksum {
+        return Err(Error::new(ErrorKind::MessageAltered, "bad checksum of the mic token"));
+    }
+
+    parse_snd_seq(&encrypt_snd_seq(key, checksum, snd_seq)?, sent_by_acceptor)
+}
+
+/// Encrypts the `data` and returns the wrap token (without the data), the encrypted data, and the encrypted padding.
+pub fn wrap(key: &[u8], seq_number: u32, sent_by_acceptor: bool, data: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let mut header = WRAP_TOKEN_ID.to_vec();
+    header.extend_from_slice(&SGN_ALG);
+    header.extend_from_slice(&SEAL_ALG);
+    header.extend_from_slice(&SEAL_FILLER);
+
+    let confounder = OsRng.gen::<[u8; CONFOUNDER_LEN]>();
+
+    let mut padded_data = data.to_vec();
+    padded_data.extend_from_slice(&PADDING);
+
+    let checksum = sgn_cksum(key, WRAP_KEY_USAGE, &header, &confounder, &padded_data)?;
+    let snd_seq = snd_seq(seq_number, sent_by_acceptor);
+
+    let mut rc4 = Rc4::new(&seal_key(key, &snd_seq)?);
+    let encrypted_confounder = rc4.process(&confounder);
+    let mut encrypted_data = rc4.process(&padded_data);
+    let encrypted_padding = encrypted_data.split_off(data.len());
+
+    let mut token = header;
+    token.extend_from_slice(&encrypt_snd_seq(key, &checksum, &snd_seq)?);
+    token.extend_from_slice(&checksum);
+    token.extend_from_slice(&encrypted_confounder);
+
+    Ok((
+        frame(&token, token.len() + padded_data.len()),
+        encrypted_data,
+        encrypted_padding,
+    ))
+}
+
+/// Decrypts the wrap token followed by the encrypted data and padding and returns the sequence number
+/// and decrypted data without the padding.
+pub fn unwrap(key: &[u8], sent_by_acceptor: bool, token: &[u8]) -> Result<(u32, Vec<u8>)> {
+    let token = unframe(token, WRAP_TOKEN_LEN)?;
+    let (header, token) = token.split_at(TOKEN_HEADER_LEN);
+    let (snd_seq, token) = token.split_at(SND_SEQ_LEN);
+    let (checksum, token) = token.split_at(SGN_CKSUM_LEN);
+    let (confounder, encrypted_data) = token.split_at(CONFOUNDER_LEN);
+
+    if header[0..2] != WRAP_TOKEN_ID
+        || header[2..4] != SGN_ALG
+        || header[4..6] != SEAL_ALG
+        || header[6..8] != SEAL_FILLER
+    {
+        return Err(Error::new(ErrorKind::InvalidToken, "invalid RC4 wrap token header"));
+    }
+
+    let snd_seq = encrypt_snd_seq(key, checksum, snd_seq)?;
+
+    let mut rc4 = Rc4::new(&seal_key(key, &snd_seq)?);
+    let confounder = rc4.process(confounder);
+    let mut data = rc4.process(encrypted_data);
+
+    if sgn_cksum(key, WRAP_KEY_USAGE, header, &confounder, &data)? != checksum {
+        return Err(Error::new(ErrorKind::MessageAltered, "bad checksum of the wrap token"));
+    }
+
+    // other implementations may pad the data up to the 8-byte boundary: every padding byte is the padding length
+    let padding_len = data.last().copied().unwrap_or_default();
+    if padding_len == 0
+        || padding_len > MAX_PADDING_LEN
+        || usize::from(padding_len) > data.len()
+        || data[data.len() - usize::from(padding_len)..]
+            .iter()
+            .any(|byte| *byte != padding_len)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            "invalid padding of the RC4 wrap token",
+        ));
+    }
+    data.truncate(data.len() - usize::from(padding_len));
+
+    Ok((parse_snd_seq(&snd_seq, sent_by_acceptor)?, data))
+}
+
+// Ksign = HMAC(Kss, "signaturekey")
+// SGN_CKSUM = HMAC(Ksign, MD5(usage, header, confounder, data))[0..8]
+fn sgn_cksum(key: &[u8], key_usage: i32, header: &[u8], confounder: &[u8], data: &[u8]) -> Result<[u8; SGN_CKSUM_LEN]> {
+    let signature_key = compute_hmac_md5(key, b"signaturekey\0")?;
+
+    let mut tmp = key_usage.to_le_bytes().to_vec();
+    tmp.extend_from_slice(header);
+    tmp.extend_from_slice(confounder);
+    tmp.extend_from_slice(data);
+
+    let checksum = compute_hmac_md5(&signature_key, &compute_md5(&tmp))?;
+
+    let mut sgn_cksum = [0; SGN_CKSUM_LEN];
+    sgn_cksum.copy_from_slice(&checksum[0..SGN_CKSUM_LEN]);
+
+    Ok(sgn_cksum)
+}
+
+// The direction bytes follow the sequence number: zeros if the token is sent by the initiator.
+// Windows and MIT Kerberos use 0xff bytes for the acceptor.
+fn snd_seq(seq_number: u32, sent_by_acceptor: bool) -> [u8; SND_SEQ_LEN] {
+    let mut snd_seq = [if sent_by_acceptor { 0xff } else { 0x00 }; SND_SEQ_LEN];
+    snd_seq[0..4].copy_from_slice(&seq_number.to_be_bytes());
+
+    snd_seq
+}
+
+fn parse_snd_seq(snd_seq: &[u8], sent_by_acceptor: bool) -> Result<u32> {
+    let direction = if sent_by_acceptor { 0xff } else { 0x00 };
+    if snd_seq[4..].iter().any(|byte| *byte != direction) {
+        return Err(Error::new(ErrorKind::InvalidToken, "invalid direction of the token"));
+    }
+
+    Ok(u32::from_be_bytes(snd_seq[0..4].try_into().unwrap()))
+}
+
+// Kseq = HMAC(HMAC(Kss, 0), SGN_CKSUM)
+// RC4 is symmetric, so the same function decrypts the sequence number.
+fn encrypt_snd_seq(key: &[u8], checksum: &[u8], snd_seq: &[u8]) -> Result<Vec<u8>> {
+    let seq_key = compute_hmac_md5(&compute_hmac_md5(key, &0_i32.to_le_bytes())?, checksum)?;
+
+    Ok(Rc4::new(&seq_key).process(snd_seq))
+}
+
+// Klocal = Kss XOR 0xf0
+// Kcrypt = HMAC(HMAC(Klocal, 0), seq_number)
+fn seal_key(key: &[u8], snd_seq: &[u8]) -> Result<[u8; HASH_SIZE]> {
+    let local_key = key.iter().map(|byte| byte ^ 0xf0).collect::<Vec<_>>();
+
+    Ok(compute_hmac_md5(
+        &compute_hmac_md5(&local_key, &0_i32.to_le_bytes())?,
+        &snd_seq[0..4],
+    )?)
+}
+
+// The framing length covers the whole token including the encrypted data that is sent separately.
+fn frame(token: &[u8], token_len: usize) -> Vec<u8> {
+    let inner_len = KRB5_MECH_OID.len() + token_len;
+
+    let mut framed = vec![GSS_FRAMING_TAG];
+    if inner_len < 0x80 {
+        framed.push(inner_len as u8);
+    } else {
+        let len_bytes = (inner_len as u32).to_be_bytes();
+        let skip = len_bytes.iter().take_while(|byte| **byte == 0).count();
+        framed.push(0x80 | (len_bytes.len() - skip) as u8);
+        framed.extend_from_slice(&len_bytes[skip..]);
+    }
+    framed.extend_from_slice(&KRB5_MECH_OID);
+    framed.extend_from_slice(token);
+
+    framed
+}
+
+fn unframe(token: &[u8], min_len: usize) -> Result<&[u8]> {
+    let invalid_framing = || Error::new(ErrorKind::InvalidToken, "invalid GSS framing of the RC4 token");
+
+    let (tag, token) = token.split_first().ok_or_else(invalid_framing)?;
+    if *tag != GSS_FRAMING_TAG {
+        return Err(invalid_framing());
+    }
+
+    let (len, token) = token.split_first().ok_or_else(invalid_framing)?;
+    let token = if *len & 0x80 != 0 {
+        token.get(usize::from(*len & 0x7f)..).ok_or_else(invalid_framing)?
+    } else {
+        token
+    };
+
+    let token = token
+        .strip_prefix(KRB5_MECH_OID.as_slice())
+        .ok_or_else(invalid_framing)?;
+    if token.len() < min_len {
+        return Err(invalid_framing());
+    }
+
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [
+        0x0f, 0x1e, 0x2d, 0x3c, 0x4b, 0x5a, 0x69, 0x78, 0x87, 0x96, 0xa5, 0xb4, 0xc3, 0xd2, 0xe1, 0xf0,
+    ];
+
+    #[test]
+    fn wrap_unwrap() {
+        let data = b"RC4-HMAC wrap token payload";
+
+        let (token, encrypted, padding) = wrap(&KEY, 0x01020304, false, data).unwrap();
+        assert_eq!(token.len(), 2 + KRB5_MECH_OID.len() + WRAP_TOKEN_LEN);
+        assert_ne!(encrypted.as_slice(), data.as_slice());
+        assert_eq!(padding.len(), BLOCK_SIZE);
+
+        let mut message = token.clone();
+        message.extend_from_slice(&encrypted);
+        message.extend_from_slice(&padding);
+
+        let (seq_number, decrypted) = unwrap(&KEY, false, &message).unwrap();
+        assert_eq!(seq_number, 0x01020304);
+        assert_eq!(decrypted, data);
+
+        // the direction is checked
+        assert!(unwrap(&KEY, true, &message).is_err());
+
+        // the data is integrity protected
+        let last = message.len() - 1;
+        message[last] ^= 0x01;
+        assert_eq!(
+            unwrap(&KEY, false, &message).unwrap_err().error_type,
+            ErrorKind::MessageAltered
+        );
+    }
+
+    #[test]
+    fn unwrap_mit_krb5_token() {
+        // produced by `gss_wrap` of MIT Kerberos 1.22 on the acceptor side of the context with the RC4-HMAC subkey
+        let token = [
+            0x60, 0x4d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x02, 0x02, 0x01, 0x11, 0x00, 0x10,
+            0x00, 0xff, 0xff, 0xbe, 0x58, 0xa7, 0x18, 0x07, 0x88, 0x59, 0xc8, 0xda, 0xe0, 0x9c, 0x91, 0x42, 0xbf, 0x31,
+            0x17, 0x21, 0xbe, 0xcc, 0x9f, 0xb8, 0x65, 0x33, 0xbf, 0xe3, 0xe1, 0xc4, 0xb7, 0xaf, 0x42, 0x7e, 0x8a, 0x8f,
+            0xf0, 0x64, 0x88, 0xde, 0x07, 0xb8, 0x41, 0xbc, 0xbd, 0x9d, 0x4c, 0x05, 0x1f, 0x9d, 0x29, 0x64, 0x5b, 0x59,
+            0xb0, 0x6f, 0xb3, 0x67, 0x86, 0x2f, 0x6d,
+        ];
+
+        let (seq_number, data) = unwrap(&[0x09; 16], true, &token).unwrap();
+        assert_eq!(seq_number, 682037477);
+        assert_eq!(data, b"RC4-HMAC wrap token from MIT krb5");
+    }
+
+    #[test]
+    fn wrap_long_data_framing() {
+        let data = vec![0xab; 300];
+
+        let (token, encrypted, padding) = wrap(&KEY, 7, true, &data).unwrap();
+        assert!(token.len() <= SECURITY_TRAILER);
+        assert_eq!(&token[0..4], &[GSS_FRAMING_TAG, 0x82, 0x01, 0x58]);
+
+        let mut message = token;
+        message.extend_from_slice(&encrypted);
+        message.extend_from_slice(&padding);
+
+        assert_eq!(unwrap(&KEY, true, &message).unwrap(), (7, data));
+    }
+
+    #[test]
+    fn mic_token() {
+        let data = b"RC4-HMAC mic token payload";
+
+        let token = make_mic_token(&KEY, 42, true, data).unwrap();
+        assert_eq!(token.len(), MAX_SIGNATURE);
+
+        assert_eq!(verify_mic_token(&KEY, true, &token, data).unwrap(), 42);
+        assert!(verify_mic_token(&KEY, false, &token, data).is_err());
+        assert_eq!(
+            verify_mic_token(&KEY, true, &token, b"other data")
+                .unwrap_err()
+                .error_type,
+            ErrorKind::MessageAltered
+        );
+    }
+}
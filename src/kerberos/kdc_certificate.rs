@@ -0,0 +1,661 @@
+//! Validation of the KDC certificate that signs the PKINIT reply.
+//!
+//! [RFC 4556 3.2.4](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.4): the client verifies the KDC's
+//! signature, builds the certificate path to a trust anchor, and checks that the certificate is issued to the KDC
+//! of the requested realm. Otherwise anyone on the path to the KDC can reply on its behalf.
+
+use std::path::Path;
+use std::{env, fs};
+
+use oid::ObjectIdentifier;
+use picky::key::PublicKey;
+use picky::pem::{read_pem, PemError};
+use picky_asn1::wrapper::{ExplicitContextTag0, ExplicitContextTag1};
+use picky_asn1_x509::crls::CertificateList;
+use picky_asn1_x509::signed_data::SignedData;
+use picky_asn1_x509::{Certificate, ExtensionView, GeneralName};
+use picky_krb::data_types::{PrincipalName, Realm};
+use serde::{Deserialize, Serialize};
+
+use crate::cert_chain::{
+    build_certificate_chain, find_signer_certificate, signed_data_certificates, verify_signature, ChainErrorKinds,
+};
+use crate::kerberos::TGT_SERVICE_NAME;
+use crate::pku2u::validate_server_p2p_certificate;
+use crate::{Error, ErrorKind, Result};
+
+/// [RFC 4556 3.2.4](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.4): id-pkinit-KPKdc
+const ID_PKINIT_KPKDC: &str = "1.3.6.1.5.2.3.5";
+/// [RFC 4556 3.2.2](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.2): id-pkinit-san
+const ID_PKINIT_SAN: &str = "1.3.6.1.5.2.2";
+
+/// Environment variable with the path to the PEM file of the KDC trust store (see [KdcTrustStore::from_env]).
+pub const SSPI_KDC_TRUST_STORE_ENV: &str = "SSPI_KDC_TRUST_STORE";
+
+const PEM_CERTIFICATE_LABEL: &str = "CERTIFICATE";
+const PEM_CRL_LABEL: &str = "X509 CRL";
+
+/// Trust anchors the KDC certificate of the PKINIT reply is validated against.
+#[derive(Debug, Clone, Default)]
+pub struct KdcTrustStore {
+    /// Trusted root certification authorities
+    pub roots: Vec<Certificate>,
+    /// Intermediate certification authorities
+    ///
+    /// Used to build the certificate path when the KDC does not send them in the reply.
+    pub intermediates: Vec<Certificate>,
+    /// Certificate revocation lists of the certification authorities
+    ///
+    /// A list is taken into account only if it is signed by the issuer of the checked certificate.
+    pub crls: Vec<CertificateList>,
+}
+
+impl KdcTrustStore {
+    pub fn new(roots: Vec<Certificate>) -> Self {
+        Self {
+            roots,
+            ..Default::default()
+        }
+    }
+
+    /// Reads the trust store from the PEM-encoded certificates and certificate revocation lists.
+    ///
+    /// Self-signed certificates become trusted roots, other certificates are used as intermediates.
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        let mut trust_store = Self::default();
+
+        let mut reader = pem.as_bytes();
+        loop {
+            let pem = match read_pem(&mut reader) {
+                Ok(pem) => pem,
+                Err(PemError::HeaderNotFound) => break,
+                Err(err) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidParameter,
+                        format!("Invalid KDC trust store PEM: {:?}", err),
+                    ))
+                }
+            };
+
+            match pem.label() {
+                PEM_CERTIFICATE_LABEL => {
+                    let certificate: Certificate = picky_asn1_der::from_bytes(pem.data())?;
+
+                    if certificate.tbs_certificate.subject == certificate.tbs_certificate.issuer {
+                        trust_store.roots.push(certificate);
+                    } else {
+                        trust_store.intermediates.push(certificate);
+                    }
+                }
+                PEM_CRL_LABEL => trust_store.crls.push(picky_asn1_der::from_bytes(pem.data())?),
+                label => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidParameter,
+                        format!("Unexpected PEM label in the KDC trust store: {}", label),
+                    ))
+                }
+            }
+        }
+
+        Ok(trust_store)
+    }
+
+    /// Reads the trust store from the PEM file (see [Self::from_pem]).
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_pem(&fs::read_to_string(path)?)
+    }
+
+    /// Reads the trust store from the PEM file specified by the `SSPI_KDC_TRUST_STORE` environment variable.
+    ///
+    /// Returns `None` if the variable is not set.
+    pub fn from_env() -> Result<Option<Self>> {
+        env::var_os(SSPI_KDC_TRUST_STORE_ENV).map(Self::from_file).transpose()
+    }
+}
+
+/// [RFC 4556 3.2.2](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.2)
+/// ```not_rust
+/// KRB5PrincipalName ::= SEQUENCE {
+///     realm                   [0] Realm,
+///     principalName           [1] PrincipalName
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Krb5PrincipalName {
+    realm: ExplicitContextTag0<Realm>,
+    principal_name: ExplicitContextTag1<PrincipalName>,
+}
+
+/// Extracts the public key of the KDC certificate that signs the PKINIT reply.
+///
+/// The certificate is validated against the trust store. Without the trust store, the reply is rejected
+/// unless `allow_unvalidated` is set: then any certificate is accepted.
+pub fn extract_kdc_public_key(
+    signed_data: &SignedData,
+    trust_store: Option<&KdcTrustStore>,
+    allow_unvalidated: bool,
+    realm: &str,
+) -> Result<PublicKey> {
+    match trust_store {
+        Some(trust_store) => validate_kdc_certificate(signed_data, trust_store, realm),
+        None if allow_unvalidated => {
+            warn!("KDC trust store is not configured: the KDC certificate is not validated");
+
+            validate_server_p2p_certificate(signed_data)
+        }
+        None => Err(Error::new(
+            ErrorKind::IssuingCaUntrustedKdc,
+            "KDC trust store is not configured: the KDC certificate can not be validated",
+        )),
+    }
+}
+
+/// Validates the certificate of the KDC that signed the `signed_data` of the PKINIT reply.
+///
+/// The certificate must chain to one of the trusted roots, must not be expired or revoked, must have the
+/// id-pkinit-KPKdc extended key usage, and must be issued to the `krbtgt/realm@realm` principal.
+/// If the certificate is valid then returns its public key.
+#[instrument(level = "trace", ret, skip(signed_data, trust_store))]
+pub fn validate_kdc_certificate(
+    signed_data: &SignedData,
+    trust_store: &KdcTrustStore,
+    realm: &str,
+) -> Result<PublicKey> {
+    let certificates = signed_data_certificates(signed_data, ErrorKind::InvalidToken)?;
+    let kdc_certificate = find_signer_certificate(signed_data, &certificates, ErrorKind::IssuingCaUntrustedKdc)?;
+
+    validate_kdc_usage(kdc_certificate, realm)?;
+
+    let intermediates = certificates
+        .iter()
+        .chain(trust_store.intermediates.iter())
+        .collect::<Vec<_>>();
+    build_certificate_chain(
+        kdc_certificate,
+        &intermediates,
+        &trust_store.roots,
+        ChainErrorKinds {
+            untrusted: ErrorKind::IssuingCaUntrustedKdc,
+            untrusted_root: ErrorKind::UntrustedRoot,
+            expired: ErrorKind::KdcCertExpired,
+        },
+        |certificate, issuer| validate_certificate_revocation(certificate, issuer, &trust_store.crls),
+    )?;
+
+    Ok(PublicKey::from(
+        kdc_certificate.tbs_certificate.subject_public_key_info.clone(),
+    ))
+}
+
+/// Checks that the certificate is issued to the KDC of the realm for the PKINIT.
+fn validate_kdc_usage(certificate: &Certificate, realm: &str) -> Result<()> {
+    let kdc_key_purpose = ObjectIdentifier::try_from(ID_PKINIT_KPKDC).unwrap();
+    let pkinit_san = ObjectIdentifier::try_from(ID_PKINIT_SAN).unwrap();
+
+    let is_kdc = certificate
+        .extensions()
+        .iter()
+        .any(|extension| match extension.extn_value() {
+            ExtensionView::ExtendedKeyUsage(usage) => usage.contains(kdc_key_purpose.clone()),
+            _ => false,
+        });
+    if !is_kdc {
+        return Err(Error::new(
+            ErrorKind::CertWrongUsage,
+            "KDC certificate does not have the id-pkinit-KPKdc extended key usage",
+        ));
+    }
+
+    let principal_names = certificate
+        .extensions()
+        .iter()
+        .filter_map(|extension| match extension.extn_value() {
+            ExtensionView::SubjectAltName(names) => Some(names.0),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|name| match name {
+            GeneralName::OtherName(name) if name.type_id.0 == pkinit_san => {
+                picky_asn1_der::from_bytes::<Krb5PrincipalName>(&name.value.0 .0).ok()
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let is_realm_kdc = principal_names.iter().any(|name| {
+        let name_string = &name.principal_name.0.name_string.0 .0;
+
+        name.realm.0.to_string() == realm
+            && name_string.len() == 2
+            && name_string[0].to_string() == TGT_SERVICE_NAME
+            && name_string[1].to_string() == realm
+    });
+    if !is_realm_kdc {
+        error!(
+            ?principal_names,
+            realm, "KDC certificate is issued to another principal"
+        );
+
+        return Err(Error::new(
+            ErrorKind::PkInitNameMismatch,
+            format!("KDC certificate is not issued to the KDC of the {} realm", realm),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the certificate against the revocation lists issued by its `issuer`.
+fn validate_certificate_revocation(
+    certificate: &Certificate,
+    issuer: &Certificate,
+    crls: &[CertificateList],
+) -> Result<()> {
+    let issuer_crls = crls.iter().filter(|crl| {
+        crl.tbs_cert_list.issuer == issuer.tbs_certificate.subject
+            && picky_asn1_der::to_vec(&crl.tbs_cert_list)
+                .map_err(Error::from)
+                .and_then(|tbs_cert_list| {
+                    verify_signature(
+                        &crl.signature_algorithm,
+                        &tbs_cert_list,
+                        crl.signature_value.0.payload_view(),
+                        issuer,
+                    )
+                })
+                .is_ok()
+    });
+
+    for crl in issuer_crls {
+        let is_revoked = crl
+            .tbs_cert_list
+            .revoked_certificates
+            .iter()
+            .flat_map(|revoked_certificates| revoked_certificates.0 .0.iter())
+            .any(|revoked| revoked.user_certificate.0 == certificate.tbs_certificate.serial_number);
+
+        if is_revoked {
+            return Err(Error::new(
+                ErrorKind::KdcCertRevoked,
+                "KDC certificate path contains a revoked certificate",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use picky::hash::HashAlgorithm;
+    use picky::key::{EcCurve, PrivateKey};
+    use picky::pem::Pem;
+    use picky::signature::SignatureAlgorithm;
+    use picky::x509::certificate::CertificateBuilder;
+    use picky::x509::date::UtcDate;
+    use picky::x509::extension::ExtendedKeyUsage;
+    use picky::x509::name::{DirectoryName, GeneralName, GeneralNames};
+    use picky::x509::Cert;
+    use picky_asn1::bit_string::BitString;
+    use picky_asn1::date::GeneralizedTime;
+    use picky_asn1::restricted_string::IA5String;
+    use picky_asn1::wrapper::{
+        Asn1SequenceOf, Asn1SetOf, BitStringAsn1, ExplicitContextTag0, ExplicitContextTag1, IntegerAsn1,
+        OctetStringAsn1Container, Optional,
+    };
+    use picky_asn1_der::Asn1RawDer;
+    use picky_asn1_x509::cmsversion::CmsVersion;
+    use picky_asn1_x509::content_info::EncapsulatedContentInfo;
+    use picky_asn1_x509::crls::{CertificateList, RevokedCertificate, RevokedCertificates, TbsCertList};
+    use picky_asn1_x509::signed_data::{
+        CertificateChoices, CertificateSet, DigestAlgorithmIdentifiers, SignedData, SignersInfos,
+    };
+    use picky_asn1_x509::signer_info::CertificateSerialNumber;
+    use picky_asn1_x509::{oids, AlgorithmIdentifier, Certificate, Extension, Extensions, OtherName, Time, Version};
+    use picky_krb::constants::types::NT_SRV_INST;
+    use picky_krb::data_types::{KerberosStringAsn1, PrincipalName};
+    use time::OffsetDateTime;
+
+    use super::{
+        extract_kdc_public_key, validate_kdc_certificate, KdcTrustStore, Krb5PrincipalName, ID_PKINIT_KPKDC,
+        ID_PKINIT_SAN, SSPI_KDC_TRUST_STORE_ENV,
+    };
+    use crate::pk_init::{generate_signer_info, PkInitDigest};
+    use crate::ErrorKind;
+
+    const REALM: &str = "EXAMPLE.COM";
+    const SIGNATURE_ALGORITHM: SignatureAlgorithm = SignatureAlgorithm::Ecdsa(HashAlgorithm::SHA2_256);
+
+    struct Pki {
+        root: Cert,
+        intermediate_key: PrivateKey,
+        intermediate: Cert,
+    }
+
+    fn pki() -> Pki {
+        let root_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let root = CertificateBuilder::new()
+            .validity(UtcDate::ymd(2020, 1, 1).unwrap(), UtcDate::ymd(2100, 1, 1).unwrap())
+            .self_signed(DirectoryName::new_common_name("root"), &root_key)
+            .ca(true)
+            .signature_hash_type(SIGNATURE_ALGORITHM)
+            .build()
+            .unwrap();
+
+        let intermediate_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let intermediate = CertificateBuilder::new()
+            .validity(UtcDate::ymd(2020, 1, 1).unwrap(), UtcDate::ymd(2100, 1, 1).unwrap())
+            .subject(
+                DirectoryName::new_common_name("intermediate"),
+                intermediate_key.to_public_key().unwrap(),
+            )
+            .issuer_cert(&root, &root_key)
+            .ca(true)
+            .signature_hash_type(SIGNATURE_ALGORITHM)
+            .build()
+            .unwrap();
+
+        Pki {
+            root,
+            intermediate_key,
+            intermediate,
+        }
+    }
+
+    fn krb5_principal_name(realm: &str) -> GeneralName {
+        let kerberos_string = |value: &str| KerberosStringAsn1::from(IA5String::from_string(value.into()).unwrap());
+        let name = Krb5PrincipalName {
+            realm: ExplicitContextTag0::from(kerberos_string(realm)),
+            principal_name: ExplicitContextTag1::from(PrincipalName {
+                name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![NT_SRV_INST])),
+                name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![
+                    kerberos_string("krbtgt"),
+                    kerberos_string(realm),
+                ])),
+            }),
+        };
+
+        GeneralName::OtherName(OtherName {
+            type_id: oid::ObjectIdentifier::try_from(ID_PKINIT_SAN).unwrap().into(),
+            value: ExplicitContextTag0::from(Asn1RawDer(picky_asn1_der::to_vec(&name).unwrap())),
+        })
+    }
+
+    fn kdc_certificate(pki: &Pki, kdc_key: &PrivateKey, realm: &str, not_after: UtcDate, eku: bool) -> Certificate {
+        let builder = CertificateBuilder::new();
+        builder
+            .validity(UtcDate::ymd(2020, 1, 1).unwrap(), not_after)
+            .subject(DirectoryName::new_common_name("kdc"), kdc_key.to_public_key().unwrap())
+            .issuer_cert(&pki.intermediate, &pki.intermediate_key)
+            .subject_alt_name(GeneralNames::new(krb5_principal_name(realm)))
+            .signature_hash_type(SIGNATURE_ALGORITHM);
+        if eku {
+            builder.extended_key_usage(ExtendedKeyUsage::new(vec![oid::ObjectIdentifier::try_from(
+                ID_PKINIT_KPKDC,
+            )
+            .unwrap()]));
+        }
+
+        builder.build().unwrap().into()
+    }
+
+    fn signed_data(kdc_certificate: &Certificate, kdc_key: &PrivateKey, chain: &[&Cert]) -> SignedData {
+        let content = b"kdc dh key info".to_vec();
+
+        let mut certificates = vec![CertificateChoices::Certificate(Asn1RawDer(
+            picky_asn1_der::to_vec(kdc_certificate).unwrap(),
+        ))];
+        certificates.extend(
+            chain
+                .iter()
+                .map(|cert| CertificateChoices::Certificate(Asn1RawDer(cert.to_der().unwrap()))),
+        );
+
+        SignedData {
+            version: CmsVersion::V3,
+            digest_algorithms: DigestAlgorithmIdentifiers(Asn1SetOf::from(vec![
+                PkInitDigest::Sha256.algorithm_identifier()
+            ])),
+            content_info: EncapsulatedContentInfo::new(oids::kpinit_dh_key_data(), Some(content.clone())),
+            certificates: Optional::from(CertificateSet(certificates)),
+            crls: None,
+            signers_infos: SignersInfos(Asn1SetOf::from(vec![generate_signer_info(
+                kdc_certificate,
+                oids::kpinit_dh_key_data(),
+                &content,
+                PkInitDigest::Sha256,
+                &|data_to_sign, signature_algorithm| Ok(signature_algorithm.sign(data_to_sign, kdc_key).unwrap()),
+            )
+            .unwrap()])),
+        }
+    }
+
+    fn crl(issuer: &Cert, issuer_key: &PrivateKey, revoked: &Certificate) -> CertificateList {
+        let now = Time::from(GeneralizedTime::from(OffsetDateTime::now_utc()));
+        let tbs_cert_list = TbsCertList {
+            version: Some(Version::V2),
+            signature: AlgorithmIdentifier::try_from(SIGNATURE_ALGORITHM).unwrap(),
+            issuer: Certificate::from(issuer.clone()).tbs_certificate.subject,
+            this_update: now.clone(),
+            next_update: None,
+            revoked_certificates: Some(RevokedCertificates(Asn1SequenceOf::from(vec![RevokedCertificate {
+                user_certificate: CertificateSerialNumber(revoked.tbs_certificate.serial_number.clone()),
+                revocation_data: now,
+                crl_entry_extensions: None,
+            }]))),
+            // conforming CRLs include the CRL number (RFC 5280 5.2.3)
+            crl_extension: ExplicitContextTag0::from(Some(Extensions(vec![Extension::new_crl_number(
+                OctetStringAsn1Container(IntegerAsn1::from(vec![1])),
+            )]))),
+        };
+        let signature = SIGNATURE_ALGORITHM
+            .sign(&picky_asn1_der::to_vec(&tbs_cert_list).unwrap(), issuer_key)
+            .unwrap();
+
+        CertificateList {
+            tbs_cert_list,
+            signature_algorithm: AlgorithmIdentifier::try_from(SIGNATURE_ALGORITHM).unwrap(),
+            signature_value: BitStringAsn1::from(BitString::with_bytes(signature)),
+        }
+    }
+
+    fn valid_until() -> UtcDate {
+        UtcDate::ymd(2100, 1, 1).unwrap()
+    }
+
+    #[test]
+    fn valid_kdc_certificate() {
+        let pki = pki();
+        let kdc_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let kdc_certificate = kdc_certificate(&pki, &kdc_key, REALM, valid_until(), true);
+        let trust_store = KdcTrustStore::new(vec![pki.root.clone().into()]);
+
+        let public_key = validate_kdc_certificate(
+            &signed_data(&kdc_certificate, &kdc_key, &[&pki.intermediate]),
+            &trust_store,
+            REALM,
+        )
+        .unwrap();
+
+        assert_eq!(public_key, kdc_key.to_public_key().unwrap());
+    }
+
+    #[test]
+    fn intermediate_from_trust_store() {
+        let pki = pki();
+        let kdc_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let kdc_certificate = kdc_certificate(&pki, &kdc_key, REALM, valid_until(), true);
+        let signed_data = signed_data(&kdc_certificate, &kdc_key, &[]);
+
+        let error = validate_kdc_certificate(&signed_data, &KdcTrustStore::new(vec![pki.root.clone().into()]), REALM)
+            .unwrap_err();
+        assert_eq!(error.error_type, ErrorKind::IssuingCaUntrustedKdc);
+
+        let trust_store = KdcTrustStore {
+            intermediates: vec![pki.intermediate.clone().into()],
+            ..KdcTrustStore::new(vec![pki.root.clone().into()])
+        };
+        validate_kdc_certificate(&signed_data, &trust_store, REALM).unwrap();
+    }
+
+    #[test]
+    fn untrusted_root() {
+        let other_root = pki().root;
+        let pki = pki();
+        let kdc_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let kdc_certificate = kdc_certificate(&pki, &kdc_key, REALM, valid_until(), true);
+
+        let error = validate_kdc_certificate(
+            &signed_data(&kdc_certificate, &kdc_key, &[&pki.intermediate, &pki.root]),
+            &KdcTrustStore::new(vec![other_root.into()]),
+            REALM,
+        )
+        .unwrap_err();
+
+        assert_eq!(error.error_type, ErrorKind::UntrustedRoot);
+    }
+
+    #[test]
+    fn expired_kdc_certificate() {
+        let pki = pki();
+        let kdc_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let kdc_certificate = kdc_certificate(&pki, &kdc_key, REALM, UtcDate::ymd(2021, 1, 1).unwrap(), true);
+
+        let error = validate_kdc_certificate(
+            &signed_data(&kdc_certificate, &kdc_key, &[&pki.intermediate]),
+            &KdcTrustStore::new(vec![pki.root.clone().into()]),
+            REALM,
+        )
+        .unwrap_err();
+
+        assert_eq!(error.error_type, ErrorKind::KdcCertExpired);
+    }
+
+    #[test]
+    fn revoked_kdc_certificate() {
+        let pki = pki();
+        let kdc_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let kdc_certificate = kdc_certificate(&pki, &kdc_key, REALM, valid_until(), true);
+        let signed_data = signed_data(&kdc_certificate, &kdc_key, &[&pki.intermediate]);
+
+        // the list is not signed by the issuer of the KDC certificate
+        let other_issuer_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let trust_store = KdcTrustStore {
+            crls: vec![crl(&pki.intermediate, &other_issuer_key, &kdc_certificate)],
+            ..KdcTrustStore::new(vec![pki.root.clone().into()])
+        };
+        validate_kdc_certificate(&signed_data, &trust_store, REALM).unwrap();
+
+        let trust_store = KdcTrustStore {
+            crls: vec![crl(&pki.intermediate, &pki.intermediate_key, &kdc_certificate)],
+            ..KdcTrustStore::new(vec![pki.root.clone().into()])
+        };
+        let error = validate_kdc_certificate(&signed_data, &trust_store, REALM).unwrap_err();
+
+        assert_eq!(error.error_type, ErrorKind::KdcCertRevoked);
+    }
+
+    #[test]
+    fn kdc_certificate_of_another_realm() {
+        let pki = pki();
+        let kdc_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let kdc_certificate = kdc_certificate(&pki, &kdc_key, "OTHER.COM", valid_until(), true);
+
+        let error = validate_kdc_certificate(
+            &signed_data(&kdc_certificate, &kdc_key, &[&pki.intermediate]),
+            &KdcTrustStore::new(vec![pki.root.clone().into()]),
+            REALM,
+        )
+        .unwrap_err();
+
+        assert_eq!(error.error_type, ErrorKind::PkInitNameMismatch);
+    }
+
+    #[test]
+    fn kdc_certificate_without_kdc_usage() {
+        let pki = pki();
+        let kdc_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let kdc_certificate = kdc_certificate(&pki, &kdc_key, REALM, valid_until(), false);
+
+        let error = validate_kdc_certificate(
+            &signed_data(&kdc_certificate, &kdc_key, &[&pki.intermediate]),
+            &KdcTrustStore::new(vec![pki.root.clone().into()]),
+            REALM,
+        )
+        .unwrap_err();
+
+        assert_eq!(error.error_type, ErrorKind::CertWrongUsage);
+    }
+
+    #[test]
+    fn kdc_certificate_without_trust_store() {
+        let pki = pki();
+        let kdc_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let kdc_certificate = kdc_certificate(&pki, &kdc_key, REALM, valid_until(), true);
+        let signed_data = signed_data(&kdc_certificate, &kdc_key, &[&pki.intermediate]);
+
+        let error = extract_kdc_public_key(&signed_data, None, false, REALM).unwrap_err();
+        assert_eq!(error.error_type, ErrorKind::IssuingCaUntrustedKdc);
+
+        let public_key = extract_kdc_public_key(&signed_data, None, true, REALM).unwrap();
+        assert_eq!(public_key, kdc_key.to_public_key().unwrap());
+    }
+
+    fn trust_store_pem(pki: &Pki) -> String {
+        format!(
+            "{}\n{}\n",
+            pki.root.to_pem().unwrap(),
+            pki.intermediate.to_pem().unwrap()
+        )
+    }
+
+    #[test]
+    fn trust_store_from_pem() {
+        let pki = pki();
+        let kdc_key = PrivateKey::generate_ec(EcCurve::NistP256).unwrap();
+        let kdc_certificate = kdc_certificate(&pki, &kdc_key, REALM, valid_until(), true);
+        let crl = crl(&pki.intermediate, &pki.intermediate_key, &kdc_certificate);
+
+        let pem = format!(
+            "{}{}\n",
+            trust_store_pem(&pki),
+            Pem::new("X509 CRL", picky_asn1_der::to_vec(&crl).unwrap())
+        );
+
+        let trust_store = KdcTrustStore::from_pem(&pem).unwrap();
+
+        assert_eq!(trust_store.roots, vec![Certificate::from(pki.root.clone())]);
+        assert_eq!(
+            trust_store.intermediates,
+            vec![Certificate::from(pki.intermediate.clone())]
+        );
+        assert_eq!(trust_store.crls, vec![crl]);
+
+        let error =
+            validate_kdc_certificate(&signed_data(&kdc_certificate, &kdc_key, &[]), &trust_store, REALM).unwrap_err();
+        assert_eq!(error.error_type, ErrorKind::KdcCertRevoked);
+
+        let error = KdcTrustStore::from_pem(&Pem::new("PRIVATE KEY", vec![1, 2, 3]).to_string()).unwrap_err();
+        assert_eq!(error.error_type, ErrorKind::InvalidParameter);
+    }
+
+    #[test]
+    fn trust_store_from_env() {
+        let pki = pki();
+        let path = std::env::temp_dir().join(format!("sspi_kdc_trust_store_test_{}.pem", std::process::id()));
+        std::fs::write(&path, trust_store_pem(&pki)).unwrap();
+
+        std::env::remove_var(SSPI_KDC_TRUST_STORE_ENV);
+        assert!(KdcTrustStore::from_env().unwrap().is_none());
+
+        std::env::set_var(SSPI_KDC_TRUST_STORE_ENV, &path);
+        let trust_store = KdcTrustStore::from_env();
+        std::env::remove_var(SSPI_KDC_TRUST_STORE_ENV);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            trust_store.unwrap().unwrap().roots,
+            vec![Certificate::from(pki.root.clone())]
+        );
+    }
+}
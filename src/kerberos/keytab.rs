@@ -0,0 +1,448 @@
+//! MIT keytab file format.
+//!
+//! [The keytab binary file format](https://web.mit.edu/kerberos/krb5-devel/doc/formats/keytab_file_format.html)
+
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder, NativeEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::kerberos::cipher::CipherSuite;
+use crate::kerberos::server::ServiceKey;
+use crate::kerberos::utils::write_private_file;
+use crate::{Error, ErrorKind, Result, Secret};
+
+/// The first byte of the keytab file.
+const KEYTAB_FILE_FORMAT: u8 = 0x05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeytabVersion {
+    /// `0x0501`: integers are stored in the native byte order. The principal name type is not stored.
+    V1,
+    /// `0x0502`: integers are stored in the big-endian byte order.
+    V2,
+}
+
+impl KeytabVersion {
+    fn from_byte(version: u8) -> Result<Self> {
+        match version {
+            0x01 => Ok(Self::V1),
+            0x02 => Ok(Self::V2),
+            _ => Err(Error::new(
+                ErrorKind::InvalidParameter,
+                format!("Unsupported keytab version: 0x05{:02x}", version),
+            )),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::V1 => 0x01,
+            Self::V2 => 0x02,
+        }
+    }
+}
+
+/// Single key of the principal.
+///
+/// ```not_rust
+/// keytab_entry {
+///     int32_t size;
+///     uint16_t num_components;    /* sub 1 if version 0x501 */
+///     counted_octet_string realm;
+///     counted_octet_string components[num_components];
+///     uint32_t name_type;   /* not present if version 0x501 */
+///     uint32_t timestamp;
+///     uint8_t vno8;
+///     keyblock key;
+///     uint32_t vno; /* only present if >= 4 bytes left in entry */
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeytabEntry {
+    pub realm: String,
+    /// Principal name components (e.g. `["HTTP", "www.example.com"]`).
+    pub components: Vec<String>,
+    /// Principal name type. Version 1 keytabs do not store it, so `NT_PRINCIPAL` (1) is assumed.
+    pub name_type: u32,
+    /// Time when the key was written to the keytab (seconds since the Unix epoch).
+    pub timestamp: u32,
+    /// Key version number.
+    pub kvno: u32,
+    /// Kerberos encryption type of the key.
+    pub encryption_type: u16,
+    pub key: Secret<Vec<u8>>,
+}
+
+impl KeytabEntry {
+    /// Returns the principal name in the `component1/component2@REALM` form.
+    pub fn principal_name(&self) -> String {
+        format!("{}@{}", self.components.join("/"), self.realm)
+    }
+
+    /// Checks if this entry belongs to the principal.
+    ///
+    /// The realm is compared only if the principal contains it.
+    pub fn matches_principal(&self, principal: &str) -> bool {
+        match principal.rsplit_once('@') {
+            Some((name, realm)) => self.components.join("/") == name && self.realm == realm,
+            None => self.components.join("/") == principal,
+        }
+    }
+
+    /// Converts the entry into the Kerberos acceptor service key.
+    pub fn service_key(&self) -> Result<ServiceKey> {
+        let encryption_type = CipherSuite::try_from(usize::from(self.encryption_type))?;
+
+        Ok(ServiceKey::new(
+            encryption_type,
+            self.key.as_ref().clone(),
+            Some(self.kvno),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keytab {
+    pub version: KeytabVersion,
+    pub entries: Vec<KeytabEntry>,
+}
+
+impl Default for Keytab {
+    fn default() -> Self {
+        Self {
+            version: KeytabVersion::V2,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl Keytab {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the keytab readable only by the owner (mode 0600 on Unix), replacing the file atomically.
+    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        write_private_file(path.as_ref(), |writer| self.write(writer))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::read(data)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.write(&mut data)?;
+
+        Ok(data)
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<Self> {
+        let file_format = reader.read_u8()?;
+        if file_format != KEYTAB_FILE_FORMAT {
+            return Err(Error::new(
+                ErrorKind::InvalidParameter,
+                format!("Invalid keytab file format: 0x{:02x}", file_format),
+            ));
+        }
+
+        match KeytabVersion::from_byte(reader.read_u8()?)? {
+            KeytabVersion::V1 => Self::read_entries::<NativeEndian>(reader, KeytabVersion::V1),
+            KeytabVersion::V2 => Self::read_entries::<BigEndian>(reader, KeytabVersion::V2),
+        }
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> Result<()> {
+        writer.write_u8(KEYTAB_FILE_FORMAT)?;
+        writer.write_u8(self.version.to_byte())?;
+
+        match self.version {
+            KeytabVersion::V1 => self.write_entries::<NativeEndian>(writer),
+            KeytabVersion::V2 => self.write_entries::<BigEndian>(writer),
+        }
+    }
+
+    /// Returns keys of the principal that can be used by the Kerberos acceptor.
+    ///
+    /// Keys with unsupported encryption types are skipped.
+    pub fn service_keys(&self, principal: &str) -> Vec<ServiceKey> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.matches_principal(principal))
+            .filter_map(|entry| entry.service_key().ok())
+            .collect()
+    }
+
+    fn read_entries<B: ByteOrder>(mut reader: impl Read, version: KeytabVersion) -> Result<Self> {
+        let mut entries = Vec::new();
+
+        loop {
+            let size = match reader.read_i32::<B>() {
+                Ok(size) => size,
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            if size == 0 {
+                break;
+            }
+
+            // The size comes from the file: read no more than the remaining data instead of allocating it upfront
+            let size_abs = u64::from(size.unsigned_abs());
+            let mut entry = Vec::new();
+            (&mut reader).take(size_abs).read_to_end(&mut entry)?;
+            if entry.len() as u64 != size_abs {
+                return Err(Error::new(
+                    ErrorKind::InvalidParameter,
+                    format!(
+                        "Keytab entry is truncated: expected {} bytes, got {}",
+                        size_abs,
+                        entry.len()
+                    ),
+                ));
+            }
+
+            // negative size means a hole left by the deleted entry
+            if size > 0 {
+                entries.push(read_entry::<B>(&entry, version)?);
+            }
+        }
+
+        Ok(Self { version, entries })
+    }
+
+    fn write_entries<B: ByteOrder>(&self, mut writer: impl Write) -> Result<()> {
+        for entry in &self.entries {
+            let entry = write_entry::<B>(entry, self.version)?;
+
+            writer.write_i32::<B>(i32::try_from(entry.len())?)?;
+            writer.write_all(&entry)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_counted_string<B: ByteOrder>(reader: &mut impl Read) -> Result<String> {
+    let len = reader.read_u16::<B>()?;
+    let mut data = vec![0; usize::from(len)];
+    reader.read_exact(&mut data)?;
+
+    Ok(String::from_utf8(data)?)
+}
+
+fn write_counted_octet_string<B: ByteOrder>(writer: &mut impl Write, data: &[u8]) -> Result<()> {
+    writer.write_u16::<B>(u16::try_from(data.len())?)?;
+    writer.write_all(data)?;
+
+    Ok(())
+}
+
+fn read_entry<B: ByteOrder>(mut entry: &[u8], version: KeytabVersion) -> Result<KeytabEntry> {
+    let mut num_components = entry.read_u16::<B>()?;
+    if version == KeytabVersion::V1 {
+        // version 1 counts the realm as a component
+        num_components = num_components.checked_sub(1).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidParameter,
+                "Invalid keytab entry: principal components count is zero",
+            )
+        })?;
+    }
+
+    let realm = read_counted_string::<B>(&mut entry)?;
+    let components = (0..num_components)
+        .map(|_| read_counted_string::<B>(&mut entry))
+        .collect::<Result<Vec<_>>>()?;
+
+    let name_type = match version {
+        KeytabVersion::V1 => u32::from(picky_krb::constants::types::NT_PRINCIPAL),
+        KeytabVersion::V2 => entry.read_u32::<B>()?,
+    };
+    let timestamp = entry.read_u32::<B>()?;
+    let vno8 = entry.read_u8()?;
+
+    let encryption_type = entry.read_u16::<B>()?;
+    let key_len = entry.read_u16::<B>()?;
+    let mut key = vec![0; usize::from(key_len)];
+    entry.read_exact(&mut key)?;
+
+    // the 32-bit key version overrides the 8-bit one if present and non-zero
+    let kvno = match entry.read_u32::<B>() {
+        Ok(kvno) if kvno != 0 => kvno,
+        _ => u32::from(vno8),
+    };
+
+    Ok(KeytabEntry {
+        realm,
+        components,
+        name_type,
+        timestamp,
+        kvno,
+        encryption_type,
+        key: key.into(),
+    })
+}
+
+fn write_entry<B: ByteOrder>(entry: &KeytabEntry, version: KeytabVersion) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+
+    let num_components = u16::try_from(entry.components.len())?;
+    data.write_u16::<B>(match version {
+        KeytabVersion::V1 => num_components + 1,
+        KeytabVersion::V2 => num_components,
+    })?;
+
+    write_counted_octet_string::<B>(&mut data, entry.realm.as_bytes())?;
+    for component in &entry.components {
+        write_counted_octet_string::<B>(&mut data, component.as_bytes())?;
+    }
+
+    if version == KeytabVersion::V2 {
+        data.write_u32::<B>(entry.name_type)?;
+    }
+    data.write_u32::<B>(entry.timestamp)?;
+    data.write_u8(entry.kvno as u8)?;
+
+    data.write_u16::<B>(entry.encryption_type)?;
+    write_counted_octet_string::<B>(&mut data, entry.key.as_ref())?;
+
+    data.write_u32::<B>(entry.kvno)?;
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kerberos::cipher::CipherSuite;
+
+    use super::{Keytab, KeytabEntry, KeytabVersion};
+
+    fn entry(components: &[&str], kvno: u32, encryption_type: u16) -> KeytabEntry {
+        KeytabEntry {
+            realm: "EXAMPLE.COM".to_owned(),
+            components: components.iter().map(|c| c.to_string()).collect(),
+            name_type: 3,
+            timestamp: 1_700_000_000,
+            kvno,
+            encryption_type,
+            key: vec![0x42; 32].into(),
+        }
+    }
+
+    #[test]
+    fn read_v2() {
+        let data = [
+            0x05, 0x02, // file format and version
+            0x00, 0x00, 0x00, 0x32, // size
+            0x00, 0x01, // num_components
+            0x00, 0x0b, b'E', b'X', b'A', b'M', b'P', b'L', b'E', b'.', b'C', b'O', b'M', // realm
+            0x00, 0x04, b'u', b's', b'e', b'r', // component
+            0x00, 0x00, 0x00, 0x01, // name_type
+            0x65, 0x53, 0xf1, 0x00, // timestamp
+            0x05, // vno8
+            0x00, 0x11, // encryption type: aes128-cts-hmac-sha1-96
+            0x00, 0x10, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+            0x10, // key
+            0xff, 0xff, 0xff, 0xf0, // hole
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let keytab = Keytab::from_bytes(&data).unwrap();
+
+        assert_eq!(keytab.version, KeytabVersion::V2);
+        assert_eq!(keytab.entries.len(), 1);
+
+        let entry = &keytab.entries[0];
+        assert_eq!(entry.principal_name(), "user@EXAMPLE.COM");
+        assert_eq!(entry.name_type, 1);
+        assert_eq!(entry.timestamp, 0x6553f100);
+        assert_eq!(entry.kvno, 5);
+        assert_eq!(entry.key.as_ref(), &(1..=16).collect::<Vec<u8>>());
+
+        let service_keys = keytab.service_keys("user@EXAMPLE.COM");
+        assert_eq!(service_keys.len(), 1);
+        assert_eq!(service_keys[0].encryption_type, CipherSuite::Aes128CtsHmacSha196);
+        assert_eq!(service_keys[0].kvno, Some(5));
+    }
+
+    #[test]
+    fn write_read_roundtrip() {
+        for version in [KeytabVersion::V1, KeytabVersion::V2] {
+            let mut keytab = Keytab {
+                version,
+                entries: vec![
+                    entry(&["HTTP", "www.example.com"], 2, 18),
+                    entry(&["HTTP", "www.example.com"], 300, 17),
+                    entry(&["host", "www.example.com"], 2, 23),
+                ],
+            };
+
+            let parsed = Keytab::from_bytes(&keytab.to_bytes().unwrap()).unwrap();
+
+            if version == KeytabVersion::V1 {
+                // version 1 does not store the name type
+                keytab.entries.iter_mut().for_each(|entry| entry.name_type = 1);
+            }
+            assert_eq!(parsed, keytab);
+        }
+    }
+
+    #[test]
+    fn write_read_file() {
+        let path = std::env::temp_dir().join(format!("sspi_keytab_test_{}", std::process::id()));
+        let keytab = Keytab {
+            version: KeytabVersion::V2,
+            entries: vec![entry(&["HTTP", "www.example.com"], 2, 18)],
+        };
+
+        keytab.to_file(&path).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let parsed = Keytab::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(parsed, keytab);
+    }
+
+    #[test]
+    fn service_keys() {
+        let keytab = Keytab {
+            version: KeytabVersion::V2,
+            entries: vec![
+                entry(&["HTTP", "www.example.com"], 2, 18),
+                entry(&["HTTP", "www.example.com"], 2, 23),
+                entry(&["HTTP", "www.example.com"], 2, 3),
+                entry(&["host", "www.example.com"], 2, 18),
+            ],
+        };
+
+        // the DES-CBC-MD5 key is skipped because it is not supported
+        assert_eq!(keytab.service_keys("HTTP/www.example.com").len(), 2);
+        assert_eq!(keytab.service_keys("HTTP/www.example.com@EXAMPLE.COM").len(), 2);
+        assert!(keytab.service_keys("HTTP/www.example.com@OTHER.COM").is_empty());
+    }
+
+    #[test]
+    fn truncated_entry() {
+        // the entry size is bigger than the remaining data: it must not be allocated upfront
+        assert!(Keytab::from_bytes(&[0x05, 0x02, 0x7f, 0xff, 0xff, 0xff, 0x00, 0x01]).is_err());
+        // the same for the hole left by the deleted entry
+        assert!(Keytab::from_bytes(&[0x05, 0x02, 0x80, 0x00, 0x00, 0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn invalid_version() {
+        assert!(Keytab::from_bytes(&[0x05, 0x03]).is_err());
+        assert!(Keytab::from_bytes(&[0x06, 0x02]).is_err());
+    }
+}
@@ -1,68 +1,111 @@
+pub mod ccache;
+pub mod cipher;
 pub mod client;
 pub mod config;
+pub(crate) mod data_types;
 mod encryption_params;
+pub mod fast;
 pub mod flags;
+mod gss_rc4;
+pub mod kdc_certificate;
+pub mod keytab;
 mod pa_datas;
+pub mod pac;
+pub(crate) mod sequence_window;
 pub mod server;
-mod utils;
+pub(crate) mod utils;
 
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::io::Write;
 use std::sync::LazyLock;
 
+pub use self::client::generators::S4uUser;
 pub use encryption_params::EncryptionParams;
-use picky::key::PrivateKey;
+use picky_asn1::bit_string::BitString;
 use picky_asn1::restricted_string::IA5String;
-use picky_asn1::wrapper::{ExplicitContextTag0, ExplicitContextTag1, OctetStringAsn1, Optional};
+use picky_asn1::wrapper::{
+    Asn1SequenceOf, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag2, ExplicitContextTag6, IntegerAsn1,
+    OctetStringAsn1, Optional,
+};
 use picky_asn1_x509::oids;
 use picky_krb::constants::gss_api::AUTHENTICATOR_CHECKSUM_TYPE;
-use picky_krb::constants::key_usages::ACCEPTOR_SIGN;
-use picky_krb::crypto::CipherSuite;
-use picky_krb::data_types::{KerberosStringAsn1, KrbResult, ResultExt};
-use picky_krb::gss_api::{NegTokenTarg1, WrapToken};
-use picky_krb::messages::{ApReq, AsRep, KdcProxyMessage, KdcReqBody, KrbPrivMessage, TgsRep};
+use picky_krb::constants::key_usages::{ACCEPTOR_SEAL, ACCEPTOR_SIGN, INITIATOR_SIGN};
+use picky_krb::data_types::{
+    EncryptionKey, KerberosFlags, KerberosStringAsn1, KrbResult, PrincipalName, Realm, ResultExt, Ticket,
+};
+use picky_krb::gss_api::{MicToken, NegTokenTarg1, WrapToken};
+use picky_krb::messages::{AsRep, AsReq, EncKdcRepPart, KdcProxyMessage, KdcRep, KdcReqBody, KrbPrivMessage, TgsRep};
 use rand::rngs::OsRng;
 use rand::Rng;
-use rsa::{Pkcs1v15Sign, RsaPrivateKey};
-use sha1::{Digest, Sha1};
 use url::Url;
 
+use self::ccache::{CCache, CCacheCredential, TicketCache};
+use self::cipher::CipherSuite;
 use self::client::extractors::{
-    extract_encryption_params_from_as_rep, extract_session_key_from_as_rep, extract_session_key_from_tgs_rep,
+    extract_enc_tgs_rep_part, extract_enc_tgs_rep_part_with_sub_key, extract_encryption_params_from_as_rep,
+    extract_session_key_from_as_rep,
 };
 use self::client::generators::{
-    generate_ap_req, generate_as_req, generate_as_req_kdc_body, generate_krb_priv_request, generate_neg_ap_req,
-    generate_neg_token_init, generate_pa_datas_for_as_req, generate_tgs_req, get_client_principal_name_type,
-    get_client_principal_realm, ChecksumOptions, ChecksumValues, EncKey, GenerateAsPaDataOptions, GenerateAsReqOptions,
-    GenerateAuthenticatorOptions,
+    generate_ap_req, generate_as_req, generate_as_req_kdc_body, generate_krb_cred, generate_krb_priv_request,
+    generate_neg_ap_req, generate_neg_token_init, generate_pa_datas_for_as_req, generate_tgs_req, get_capath,
+    get_client_principal_name_type, get_client_principal_realm, get_service_realm, ChecksumOptions, ChecksumValues,
+    EncKey, GenerateAsPaDataOptions, GenerateAsReqOptions, GenerateAuthenticatorOptions, S4u2SelfOptions,
 };
 use self::config::KerberosConfig;
+use self::data_types::{KrbFastResponse, ANONYMOUS_PRINCIPAL_NAME, NT_WELLKNOWN, WELLKNOWN_NAME};
+use self::fast::{extract_fx_cookie, strengthen_reply_key, FastArmor, FastArmorKey};
+use self::flags::{ApOptions, KdcOptions, TicketFlags};
 use self::pa_datas::AsReqPaDataOptions;
-use self::server::extractors::extract_tgt_ticket;
+use self::pac::Pac;
+use self::sequence_window::SequenceWindow;
+use self::server::extractors::{
+    extract_ap_options, extract_ap_req, extract_authenticator, extract_authenticator_checksum,
+    extract_delegated_credentials, extract_enc_ticket_part, extract_encryption_key, extract_tgt_req,
+    extract_tgt_ticket, find_service_key, ApReqToken,
+};
+use self::server::generators::{
+    generate_ap_rep, generate_gss_ap_rep, generate_neg_ap_rep, generate_neg_token_targ_without_tgt,
+};
+use self::server::validate::{
+    kerberos_time_to_date, principal_name_to_string, validate_authenticator, validate_channel_bindings, validate_pac,
+    validate_ticket,
+};
+use self::server::ServerContext;
 use self::utils::{serialize_message, unwrap_hostname};
 use super::channel_bindings::ChannelBindings;
 use crate::builders::ChangePassword;
-use crate::generator::{GeneratorChangePassword, GeneratorInitSecurityContext, NetworkRequest, YieldPointLocal};
-use crate::kerberos::client::extractors::{extract_salt_from_krb_error, extract_status_code_from_krb_priv_response};
+use crate::generator::{
+    GeneratorChangePassword, GeneratorInitSecurityContext, GeneratorS4u, NetworkRequest, YieldPointLocal,
+};
+use crate::kerberos::client::extractors::{
+    extract_encryption_type_from_krb_error, extract_salt_from_krb_error, extract_status_code_from_krb_priv_response,
+};
 use crate::kerberos::client::generators::{
     generate_authenticator, generate_final_neg_token_targ, get_mech_list, GenerateTgsReqOptions, GssFlags,
+    DEFAULT_AS_REQ_OPTIONS,
 };
 use crate::kerberos::pa_datas::AsRepSessionKeyExtractor;
-use crate::kerberos::server::extractors::{extract_ap_rep_from_neg_token_targ, extract_sub_session_key_from_ap_rep};
-use crate::kerberos::utils::{generate_initiator_raw, validate_mic_token};
+use crate::kerberos::server::extractors::{
+    decrypt_ap_rep_enc_part, extract_ap_rep_from_neg_token_targ, extract_seq_number_from_ap_rep,
+    extract_seq_number_from_authenticator, extract_sub_session_key_from_ap_rep,
+};
+use crate::kerberos::utils::{
+    generate_acceptor_raw, generate_initiator_raw, make_mic_token, validate_mic_token, verify_mic_token,
+};
 use crate::network_client::NetworkProtocol;
 use crate::pk_init::{self, DhParameters};
-use crate::pku2u::generate_client_dh_parameters;
+use crate::pku2u::generate_client_key_agreement_parameters;
 use crate::utils::{
     extract_encrypted_data, generate_random_symmetric_key, get_encryption_key, parse_target_name, save_decrypted_data,
     utf16_bytes_to_utf8_string,
 };
 use crate::{
-    check_if_empty, detect_kdc_url, AcceptSecurityContextResult, AcquireCredentialsHandleResult, AuthIdentity,
-    ClientRequestFlags, ClientResponseFlags, ContextNames, ContextSizes, CredentialUse, Credentials,
-    CredentialsBuffers, DecryptionFlags, Error, ErrorKind, InitializeSecurityContextResult, OwnedSecurityBuffer,
-    PackageCapabilities, PackageInfo, Result, SecurityBuffer, SecurityBufferType, SecurityPackageType, SecurityStatus,
-    ServerResponseFlags, Sspi, SspiEx, SspiImpl, PACKAGE_ID_NONE,
+    check_if_empty, detect_kdc_url, pku2u, AcceptSecurityContextResult, AcquireCredentialsHandleResult, AuthIdentity,
+    ClientRequestFlags, ClientResponseFlags, ContextClientIdentity, ContextNames, ContextSizes, CredentialUse,
+    Credentials, CredentialsBuffers, DecryptionFlags, Error, ErrorKind, InitializeSecurityContextResult,
+    OwnedSecurityBuffer, PackageCapabilities, PackageInfo, Result, SecurityBuffer, SecurityBufferType,
+    SecurityPackageType, SecurityStatus, ServerResponseFlags, Sspi, SspiEx, SspiImpl, PACKAGE_ID_NONE,
 };
 
 pub const PKG_NAME: &str = "Kerberos";
@@ -73,6 +116,12 @@ pub const CHANGE_PASSWORD_SERVICE_NAME: &str = "changepw";
 
 // pub const SSPI_KDC_URL_ENV: &str = "SSPI_KDC_URL";
 pub const DEFAULT_ENCRYPTION_TYPE: CipherSuite = CipherSuite::Aes256CtsHmacSha196;
+/// Maximum number of the KDC referrals followed to obtain the service ticket.
+const MAX_REFERRAL_HOPS: usize = 10;
+/// Maximum number of the AS requests resent with the PKINIT algorithms the KDC asked for.
+const MAX_PKINIT_NEGOTIATION_ATTEMPTS: usize = 2;
+/// Encryption types requested from the KDC in the order of preference.
+pub const DEFAULT_ETYPES: [CipherSuite; 2] = [CipherSuite::Aes256CtsHmacSha196, CipherSuite::Aes128CtsHmacSha196];
 
 /// [MS-KILE](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-KILE/%5bMS-KILE%5d.pdf)
 /// The RRC field is 12 if no encryption is requested or 28 if encryption is requested
@@ -110,10 +159,12 @@ pub struct Kerberos {
     auth_identity: Option<CredentialsBuffers>,
     encryption_params: EncryptionParams,
     seq_number: u32,
+    recv_sequence_window: SequenceWindow,
     realm: Option<String>,
     kdc_url: Option<Url>,
     channel_bindings: Option<ChannelBindings>,
     dh_parameters: Option<DhParameters>,
+    server_context: Option<ServerContext>,
 }
 
 impl Kerberos {
@@ -126,10 +177,12 @@ impl Kerberos {
             auth_identity: None,
             encryption_params: EncryptionParams::default_for_client(),
             seq_number: OsRng.gen::<u32>(),
+            recv_sequence_window: SequenceWindow::disabled(),
             realm: None,
             kdc_url,
             channel_bindings: None,
             dh_parameters: None,
+            server_context: None,
         })
     }
 
@@ -142,10 +195,12 @@ impl Kerberos {
             auth_identity: None,
             encryption_params: EncryptionParams::default_for_server(),
             seq_number: OsRng.gen::<u32>(),
+            recv_sequence_window: SequenceWindow::disabled(),
             realm: None,
             kdc_url,
             channel_bindings: None,
             dh_parameters: None,
+            server_context: None,
         })
     }
 
@@ -153,6 +208,75 @@ impl Kerberos {
         &self.config
     }
 
+    /// Encryption types requested from the KDC: RC4-HMAC is the least preferred one and only if it is allowed.
+    fn etypes(&self) -> Vec<CipherSuite> {
+        let mut etypes = DEFAULT_ETYPES.to_vec();
+        if self.config.allow_rc4_hmac {
+            etypes.push(CipherSuite::Rc4Hmac);
+        }
+
+        etypes
+    }
+
+    /// Returns the credentials (forwarded TGT) delegated by the client.
+    ///
+    /// They are available on the acceptor side after the client's AP-REQ is accepted
+    /// if the client has requested the credentials delegation.
+    pub fn delegated_credentials(&self) -> Option<&CCache> {
+        self.server_context
+            .as_ref()
+            .and_then(|server_context| server_context.delegated_credentials.as_ref())
+    }
+
+    /// Returns the PAC from the client's service ticket.
+    ///
+    /// It is available on the acceptor side after the client's AP-REQ is accepted if the ticket
+    /// was issued by the Active Directory KDC. The PAC signatures are verified during the acceptance.
+    pub fn pac(&self) -> Option<&Pac> {
+        self.server_context
+            .as_ref()
+            .and_then(|server_context| server_context.pac.as_ref())
+    }
+
+    /// Requests a service ticket to the service itself on behalf of the `user` (S4U2Self).
+    ///
+    /// The service authenticates using the credentials passed to `acquire_credentials_handle`.
+    /// The returned ticket can be used as the evidence ticket in [Kerberos::s4u2proxy].
+    ///
+    /// [MS-SFU 3.1.5.1.1](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/02636893-7a1f-4357-af9a-b672e3e3de13)
+    pub fn s4u2self(&mut self, user: S4uUser) -> GeneratorS4u<'_> {
+        GeneratorS4u::new(move |mut yield_point| async move {
+            self.s4u_exchange(&mut yield_point, None, Some(user), None).await
+        })
+    }
+
+    /// Requests a service ticket to the `target_name` service on behalf of the user (S4U2Proxy).
+    ///
+    /// The `evidence_ticket` is the user's service ticket to this service (e.g. obtained using [Kerberos::s4u2self]).
+    /// If [KerberosConfig::ticket_cache] is configured, the returned ticket is stored in it. Then the
+    /// `initialize_security_context` called with the user's name and realm as credentials and the same target name
+    /// takes the ticket from the cache instead of requesting a new one, so the user's password is not needed.
+    /// Without the ticket cache, the caller is responsible for using the returned ticket.
+    ///
+    /// [MS-SFU 3.1.5.2.1](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-sfu/c8f8f5f1-ac71-4b1c-a5fc-6d9a5e2ec8e6)
+    pub fn s4u2proxy<'a>(
+        &'a mut self,
+        evidence_ticket: &'a CCacheCredential,
+        target_name: &'a str,
+    ) -> GeneratorS4u<'a> {
+        GeneratorS4u::new(move |mut yield_point| async move {
+            let evidence_ticket = evidence_ticket.decode_ticket()?;
+
+            self.s4u_exchange(&mut yield_point, Some(target_name), None, Some(evidence_ticket))
+                .await
+        })
+    }
+
+    /// Checks if the context is the server side of the authentication.
+    fn is_acceptor(&self) -> bool {
+        self.encryption_params.sspi_encrypt_key_usage == ACCEPTOR_SEAL
+    }
+
     pub fn next_seq_number(&mut self) -> u32 {
         self.seq_number += 1;
         self.seq_number
@@ -169,66 +293,113 @@ impl Kerberos {
         }
     }
 
+    /// Returns the KDC of the `realm`.
+    ///
+    /// The configured KDC serves the client realm. The KDCs of the other realms (e.g. the ones the KDC referrals
+    /// lead to) are discovered using [detect_kdc_url]. The KDC proxy forwards the messages to the KDC of the target
+    /// realm, so it is used for all realms.
+    fn get_realm_kdc(&self, realm: &str) -> Option<Url> {
+        match self.get_kdc() {
+            Some((kdc_realm, kdc_url)) if kdc_realm.eq_ignore_ascii_case(realm) => Some(kdc_url),
+            Some((_, kdc_url))
+                if matches!(
+                    NetworkProtocol::from_url_scheme(kdc_url.scheme()),
+                    Some(NetworkProtocol::Http | NetworkProtocol::Https)
+                ) =>
+            {
+                Some(kdc_url)
+            }
+            _ => detect_kdc_url(realm),
+        }
+    }
+
     async fn send<'data>(&self, yield_point: &mut YieldPointLocal, data: &'data [u8]) -> Result<Vec<u8>> {
-        if let Some((realm, kdc_url)) = self.get_kdc() {
-            let protocol = NetworkProtocol::from_url_scheme(kdc_url.scheme()).ok_or_else(|| {
-                Error::new(
-                    ErrorKind::InvalidParameter,
-                    format!("Invalid protocol `{}` for KDC server", kdc_url.scheme()),
-                )
-            })?;
+        let (realm, kdc_url) = self
+            .get_kdc()
+            .ok_or_else(|| Error::new(ErrorKind::NoAuthenticatingAuthority, "No KDC server found"))?;
 
-            return match protocol {
-                NetworkProtocol::Tcp => {
-                    let request = NetworkRequest {
-                        protocol,
-                        url: kdc_url.clone(),
-                        data: data.to_vec(),
-                    };
-                    yield_point.suspend(request).await
-                }
-                NetworkProtocol::Udp => {
-                    if data.len() < 4 {
-                        return Err(Error::new(
-                            ErrorKind::InternalError,
-                            format!(
-                                "kerberos message has invalid length. expected >= 4 but got {}",
-                                data.len()
-                            ),
-                        ));
-                    }
+        self.send_to_kdc(yield_point, realm, kdc_url, data).await
+    }
 
-                    // First 4 bytes are message length and it’s not included when using UDP
-                    let request = NetworkRequest {
-                        protocol,
-                        url: kdc_url.clone(),
-                        data: data[4..].to_vec(),
-                    };
-                    yield_point.suspend(request).await
-                }
-                NetworkProtocol::Http | NetworkProtocol::Https => {
-                    let data = OctetStringAsn1::from(data.to_vec());
-                    let domain = KerberosStringAsn1::from(IA5String::from_string(realm)?);
-
-                    let kdc_proxy_message = KdcProxyMessage {
-                        kerb_message: ExplicitContextTag0::from(data),
-                        target_domain: Optional::from(Some(ExplicitContextTag1::from(domain))),
-                        dclocator_hint: Optional::from(None),
-                    };
+    async fn send_to_realm<'data>(
+        &self,
+        yield_point: &mut YieldPointLocal,
+        realm: &str,
+        data: &'data [u8],
+    ) -> Result<Vec<u8>> {
+        let kdc_url = self.get_realm_kdc(realm).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NoAuthenticatingAuthority,
+                format!("No KDC server found for the {} realm", realm),
+            )
+        })?;
 
-                    let message_request = picky_asn1_der::to_vec(&kdc_proxy_message)?;
-                    let request = NetworkRequest {
-                        protocol,
-                        url: kdc_url,
-                        data: message_request,
-                    };
-                    let result_bytes = yield_point.suspend(request).await?;
-                    let message_response: KdcProxyMessage = picky_asn1_der::from_bytes(&result_bytes)?;
-                    Ok(message_response.kerb_message.0 .0)
+        self.send_to_kdc(yield_point, realm.to_owned(), kdc_url, data).await
+    }
+
+    async fn send_to_kdc<'data>(
+        &self,
+        yield_point: &mut YieldPointLocal,
+        realm: String,
+        kdc_url: Url,
+        data: &'data [u8],
+    ) -> Result<Vec<u8>> {
+        let protocol = NetworkProtocol::from_url_scheme(kdc_url.scheme()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidParameter,
+                format!("Invalid protocol `{}` for KDC server", kdc_url.scheme()),
+            )
+        })?;
+
+        match protocol {
+            NetworkProtocol::Tcp => {
+                let request = NetworkRequest {
+                    protocol,
+                    url: kdc_url.clone(),
+                    data: data.to_vec(),
+                };
+                yield_point.suspend(request).await
+            }
+            NetworkProtocol::Udp => {
+                if data.len() < 4 {
+                    return Err(Error::new(
+                        ErrorKind::InternalError,
+                        format!(
+                            "kerberos message has invalid length. expected >= 4 but got {}",
+                            data.len()
+                        ),
+                    ));
                 }
-            };
+
+                // First 4 bytes are message length and it’s not included when using UDP
+                let request = NetworkRequest {
+                    protocol,
+                    url: kdc_url.clone(),
+                    data: data[4..].to_vec(),
+                };
+                yield_point.suspend(request).await
+            }
+            NetworkProtocol::Http | NetworkProtocol::Https => {
+                let data = OctetStringAsn1::from(data.to_vec());
+                let domain = KerberosStringAsn1::from(IA5String::from_string(realm)?);
+
+                let kdc_proxy_message = KdcProxyMessage {
+                    kerb_message: ExplicitContextTag0::from(data),
+                    target_domain: Optional::from(Some(ExplicitContextTag1::from(domain))),
+                    dclocator_hint: Optional::from(None),
+                };
+
+                let message_request = picky_asn1_der::to_vec(&kdc_proxy_message)?;
+                let request = NetworkRequest {
+                    protocol,
+                    url: kdc_url,
+                    data: message_request,
+                };
+                let result_bytes = yield_point.suspend(request).await?;
+                let message_response: KdcProxyMessage = picky_asn1_der::from_bytes(&result_bytes)?;
+                Ok(message_response.kerb_message.0 .0)
+            }
         }
-        Err(Error::new(ErrorKind::NoAuthenticatingAuthority, "No KDC server found"))
     }
 
     pub async fn as_exchange(
@@ -237,14 +408,40 @@ impl Kerberos {
         kdc_req_body: &KdcReqBody,
         mut pa_data_options: AsReqPaDataOptions<'_>,
     ) -> Result<AsRep> {
+        self.armored_as_exchange(yield_point, kdc_req_body, &mut pa_data_options, None)
+            .await
+            .map(|(as_rep, _)| as_rep)
+    }
+
+    /// Performs the AS exchange that is FAST-armored if the `armor` is specified.
+    ///
+    /// The FAST response is returned along with the AS-REP of the armored exchange. The pa-datas of the AS-REP are
+    /// replaced with the ones from the FAST response. The `pa_data_options` keep the PKINIT algorithms the exchange
+    /// finished with.
+    async fn armored_as_exchange(
+        &mut self,
+        yield_point: &mut YieldPointLocal,
+        kdc_req_body: &KdcReqBody,
+        pa_data_options: &mut AsReqPaDataOptions<'_>,
+        armor: Option<&FastArmorKey>,
+    ) -> Result<(AsRep, Option<KrbFastResponse>)> {
+        let generate_request = |pa_datas| -> Result<AsReq> {
+            let mut as_req = generate_as_req(pa_datas, kdc_req_body.clone());
+            if let Some(armor) = armor {
+                armor.armor_request(&mut as_req.0)?;
+            }
+
+            Ok(as_req)
+        };
+
         pa_data_options.with_pre_auth(false);
         let pa_datas = pa_data_options.generate()?;
-        let as_req = generate_as_req(pa_datas, kdc_req_body.clone());
+        let as_req = generate_request(pa_datas)?;
 
         let response = self.send(yield_point, &serialize_message(&as_req)?).await?;
 
         // first 4 bytes are message len. skipping them
-        {
+        let cookie = {
             let mut d = picky_asn1_der::Deserializer::new_from_bytes(&response[4..]);
             let as_rep: KrbResult<AsRep> = KrbResult::deserialize(&mut d)?;
 
@@ -259,28 +456,695 @@ impl Kerberos {
                 ));
             }
 
-            if let Some(correct_salt) = extract_salt_from_krb_error(&as_rep.unwrap_err())? {
+            let krb_error = match armor {
+                Some(armor) => armor.extract_error(&as_rep.unwrap_err())?,
+                None => as_rep.unwrap_err(),
+            };
+
+            // only the encryption types allowed by the configuration are used for the preauthentication
+            let etypes = self.etypes();
+
+            if let Some(correct_salt) = extract_salt_from_krb_error(&krb_error, &etypes)? {
                 debug!("salt extracted successfully from the KRB_ERROR");
 
                 pa_data_options.with_salt(correct_salt.as_bytes().to_vec());
             }
-        }
 
+            if let Some(encryption_type) = extract_encryption_type_from_krb_error(&krb_error, &etypes)? {
+                debug!(
+                    ?encryption_type,
+                    "encryption type extracted successfully from the KRB_ERROR"
+                );
+
+                pa_data_options.with_encryption_type(encryption_type);
+            }
+
+            extract_fx_cookie(&krb_error)?
+        };
+
+        if let Some(armor) = armor {
+            pa_data_options.with_armor_key(armor.key().clone());
+        }
         pa_data_options.with_pre_auth(true);
-        let pa_datas = pa_data_options.generate()?;
 
-        let as_req = generate_as_req(pa_datas, kdc_req_body.clone());
+        let mut negotiation_attempts = 0;
+        let mut as_rep = loop {
+            let mut pa_datas = pa_data_options.generate()?;
+            // [RFC 6113 5.2](https://www.rfc-editor.org/rfc/rfc6113#section-5.2): the client returns the cookie
+            // in the next request of the conversation
+            pa_datas.extend(cookie.clone());
 
-        let response = self.send(yield_point, &serialize_message(&as_req)?).await?;
+            let as_req = generate_request(pa_datas)?;
+
+            let response = self.send(yield_point, &serialize_message(&as_req)?).await?;
+
+            // first 4 bytes are message len. skipping them
+            let mut d = picky_asn1_der::Deserializer::new_from_bytes(&response[4..]);
+            let as_rep: KrbResult<AsRep> = KrbResult::deserialize(&mut d)?;
+
+            let err = match as_rep {
+                Ok(as_rep) => break as_rep,
+                Err(err) => err,
+            };
+            error!(?err, "AS exchange error");
+
+            let krb_error = match armor {
+                Some(armor) => armor.extract_error(&err).unwrap_or(err),
+                None => err,
+            };
+
+            // [RFC 4556 3.2.2](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.2): the KDC lists the
+            // algorithms it accepts if it rejects the ones of the request
+            if negotiation_attempts < MAX_PKINIT_NEGOTIATION_ATTEMPTS && pa_data_options.negotiate(&krb_error)? {
+                negotiation_attempts += 1;
+                debug!(
+                    negotiation_attempts,
+                    "retrying the AS exchange with the KDC-supported PKINIT algorithms"
+                );
+
+                continue;
+            }
+
+            return Err(krb_error.into());
+        };
+
+        let Some(armor) = armor else {
+            return Ok((as_rep, None));
+        };
+
+        let fast_response = armor.extract_response(
+            as_rep
+                .0
+                .padata
+                .0
+                .as_ref()
+                .map(|pa_datas| pa_datas.0 .0.as_slice())
+                .unwrap_or_default(),
+        )?;
+        armor.validate_response(&fast_response, &as_rep.0, &kdc_req_body.nonce.0)?;
+
+        as_rep.0.padata = Optional::from(Some(ExplicitContextTag2::from(fast_response.padata.0.clone())));
+
+        Ok((as_rep, Some(fast_response)))
+    }
+
+    /// Returns the client's TGT from the ticket cache or requests it from the KDC using the AS exchange.
+    async fn obtain_tgt(
+        &mut self,
+        yield_point: &mut YieldPointLocal,
+        credentials: &CredentialsBuffers,
+        context_requirements: ClientRequestFlags,
+    ) -> Result<ClientTgt> {
+        let (username, password, realm, cname_type) = credentials_principal(credentials);
+        let ticket_cache = self.config.ticket_cache.clone();
+
+        let cached_tgt = ticket_cache.as_ref().and_then(|ticket_cache| {
+            lookup_cached_ticket(
+                ticket_cache,
+                &username,
+                &realm,
+                &format!("{}/{}", TGT_SERVICE_NAME, realm),
+            )
+        });
+
+        if let Some(credential) = cached_tgt {
+            info!("Using the TGT from the cache.");
+
+            self.encryption_params.encryption_type = Some(credential.encryption_type()?);
+
+            Ok(ClientTgt {
+                crealm: credential.client.to_realm()?,
+                cname: credential.client.to_principal_name()?,
+                ticket: credential.decode_ticket()?,
+                session_key: credential.key.as_ref().clone(),
+                flags: TicketFlags::from_bits_retain(credential.ticket_flags),
+            })
+        } else {
+            let armor = self.fast_armor(yield_point, &realm).await?;
+
+            let options = GenerateAsReqOptions {
+                realm: &realm,
+                username: &username,
+                cname_type,
+                snames: &[TGT_SERVICE_NAME, &realm],
+                // 4 = size of u32
+                nonce: &OsRng.gen::<[u8; 4]>(),
+                hostname: &unwrap_hostname(self.config.client_computer_name.as_deref())?,
+                context_requirements,
+                etypes: &self.etypes(),
+            };
+            let kdc_req_body = generate_as_req_kdc_body(&options)?;
+
+            let mut pa_data_options = match credentials {
+                CredentialsBuffers::AuthIdentity(auth_identity) => {
+                    let domain = utf16_bytes_to_utf8_string(&auth_identity.domain);
+                    let salt = format!("{}{}", domain, username);
+
+                    AsReqPaDataOptions::AuthIdentity(GenerateAsPaDataOptions {
+                        password: &password,
+                        salt: salt.as_bytes().to_vec(),
+                        enc_params: self.encryption_params.clone(),
+                        with_pre_auth: false,
+                        armor_key: None,
+                    })
+                }
+                CredentialsBuffers::SmartCard(smart_card) => {
+                    #[cfg(feature = "scard")]
+                    let sign_data =
+                        crate::smartcard::pkinit_sign_data_fn(smart_card, self.config.scard_context.as_ref())?;
+                    #[cfg(not(feature = "scard"))]
+                    let sign_data = software_sign_data_fn(smart_card)?;
+
+                    AsReqPaDataOptions::SmartCard(Box::new(pk_init::GenerateAsPaDataOptions {
+                        p2p_cert: picky_asn1_der::from_bytes(&smart_card.certificate)?,
+                        kdc_req_body: &kdc_req_body,
+                        dh_parameters: generate_client_key_agreement_parameters(
+                            self.config.pkinit.key_agreement,
+                            &mut OsRng,
+                        )?,
+                        digest: self.config.pkinit.digest,
+                        sign_data,
+                        with_pre_auth: false,
+                        authenticator_nonce: OsRng.gen::<[u8; 4]>(),
+                    }))
+                }
+            };
+
+            let (as_rep, fast_response) = self
+                .armored_as_exchange(yield_point, &kdc_req_body, &mut pa_data_options, armor.as_ref())
+                .await?;
+            self.dh_parameters = pa_data_options.dh_parameters().cloned();
+
+            info!("AS exchange finished successfully.");
+
+            let (encryption_type, salt) = extract_encryption_params_from_as_rep(&as_rep)?;
+
+            let encryption_type = CipherSuite::try_from(encryption_type as usize)?;
+
+            self.encryption_params.encryption_type = Some(encryption_type);
+
+            let mut session_key_extractor = match credentials {
+                CredentialsBuffers::AuthIdentity(_) => AsRepSessionKeyExtractor::AuthIdentity {
+                    salt: &salt,
+                    password: &password,
+                    enc_params: &mut self.encryption_params,
+                },
+                CredentialsBuffers::SmartCard(_) => AsRepSessionKeyExtractor::SmartCard {
+                    dh_parameters: self.dh_parameters.as_mut().unwrap(),
+                    enc_params: &mut self.encryption_params,
+                    kdc_trust_store: self.config.kdc_trust_store.as_ref(),
+                    allow_unvalidated_kdc_certificate: self.config.allow_unvalidated_kdc_certificate,
+                    realm: &realm,
+                },
+            };
+            let enc_as_rep_part = match (armor.as_ref(), fast_response.as_ref()) {
+                (Some(armor), Some(fast_response)) => {
+                    let key_value = session_key_extractor.reply_key(&as_rep)?;
+                    let reply_key = EncKey {
+                        key_type: session_key_extractor
+                            .enc_params()
+                            .encryption_type
+                            .clone()
+                            .unwrap_or(DEFAULT_ENCRYPTION_TYPE),
+                        key_value,
+                    };
+                    armor.validate_kdc_challenge(fast_response, &reply_key)?;
+
+                    let reply_key = strengthen_reply_key(fast_response, &reply_key)?;
+                    pku2u::extract_enc_as_rep_part(
+                        &as_rep,
+                        &reply_key.key_value,
+                        &EncryptionParams {
+                            encryption_type: Some(reply_key.key_type.clone()),
+                            ..session_key_extractor.enc_params().clone()
+                        },
+                    )?
+                }
+                _ => session_key_extractor.enc_as_rep_part(&as_rep)?,
+            };
+
+            if let Some(ticket_cache) = ticket_cache.as_ref() {
+                store_ticket(ticket_cache, &as_rep.0, &enc_as_rep_part);
+            }
+
+            let KdcRep {
+                crealm, cname, ticket, ..
+            } = as_rep.0;
+
+            Ok(ClientTgt {
+                crealm: crealm.0,
+                cname: cname.0,
+                ticket: ticket.0,
+                flags: TicketFlags::from(&enc_as_rep_part.flags.0),
+                session_key: enc_as_rep_part.key.0.key_value.0 .0,
+            })
+        }
+    }
+
+    /// Returns the FAST armor for the AS exchange in the `realm` if it is enabled in the configuration.
+    async fn fast_armor(&mut self, yield_point: &mut YieldPointLocal, realm: &str) -> Result<Option<FastArmorKey>> {
+        match self.config.fast.clone() {
+            Some(FastArmor::TicketCache(ticket_cache)) => cached_armor_key(&ticket_cache, realm).map(Some),
+            Some(FastArmor::AnonymousPkinit) => self.anonymous_armor_key(yield_point, realm).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Requests the anonymous TGT and derives the FAST armor key from it.
+    ///
+    /// [RFC 8062 4.1](https://www.rfc-editor.org/rfc/rfc8062#section-4.1): the client requests the ticket
+    /// for the WELLKNOWN/ANONYMOUS principal using the PKINIT without a client certificate.
+    async fn anonymous_armor_key(&mut self, yield_point: &mut YieldPointLocal, realm: &str) -> Result<FastArmorKey> {
+        let mut kdc_req_body = generate_as_req_kdc_body(&GenerateAsReqOptions {
+            realm,
+            username: ANONYMOUS_PRINCIPAL_NAME,
+            cname_type: NT_WELLKNOWN,
+            snames: &[TGT_SERVICE_NAME, realm],
+            // 4 = size of u32
+            nonce: &OsRng.gen::<[u8; 4]>(),
+            hostname: &unwrap_hostname(self.config.client_computer_name.as_deref())?,
+            context_requirements: ClientRequestFlags::empty(),
+            etypes: &self.etypes(),
+        })?;
+        kdc_req_body.cname = Optional::from(Some(ExplicitContextTag1::from(PrincipalName {
+            name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![NT_WELLKNOWN])),
+            name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![
+                KerberosStringAsn1::from(IA5String::from_string(WELLKNOWN_NAME.into())?),
+                KerberosStringAsn1::from(IA5String::from_string(ANONYMOUS_PRINCIPAL_NAME.into())?),
+            ])),
+        })));
+        let kdc_options =
+            KdcOptions::from_bits_retain(u32::from_be_bytes(DEFAULT_AS_REQ_OPTIONS)) | KdcOptions::REQUEST_ANONYMOUS;
+        kdc_req_body.kdc_options = ExplicitContextTag0::from(KerberosFlags::from(BitString::with_bytes(
+            kdc_options.bits().to_be_bytes().to_vec(),
+        )));
+
+        let mut pa_data_options =
+            AsReqPaDataOptions::AnonymousPkinit(Box::new(pk_init::GenerateAnonymousAsPaDataOptions {
+                kdc_req_body: &kdc_req_body,
+                dh_parameters: generate_client_key_agreement_parameters(self.config.pkinit.key_agreement, &mut OsRng)?,
+                digest: self.config.pkinit.digest,
+                with_pre_auth: false,
+                authenticator_nonce: OsRng.gen::<[u8; 4]>(),
+            }));
+
+        let (as_rep, _) = self
+            .armored_as_exchange(yield_point, &kdc_req_body, &mut pa_data_options, None)
+            .await?;
+        let mut dh_parameters = pa_data_options
+            .dh_parameters()
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::InternalError, "Missing key agreement parameters"))?;
+
+        info!("Anonymous AS exchange finished successfully.");
+
+        let mut enc_params = self.encryption_params.clone();
+        let enc_as_rep_part = AsRepSessionKeyExtractor::SmartCard {
+            dh_parameters: &mut dh_parameters,
+            enc_params: &mut enc_params,
+            kdc_trust_store: self.config.kdc_trust_store.as_ref(),
+            allow_unvalidated_kdc_certificate: self.config.allow_unvalidated_kdc_certificate,
+            realm,
+        }
+        .enc_as_rep_part(&as_rep)?;
+
+        let KdcRep {
+            crealm, cname, ticket, ..
+        } = as_rep.0;
+        let session_key = enc_as_rep_part.key.0;
+
+        FastArmorKey::ap_request(
+            &crealm.0,
+            &cname.0,
+            ticket.0,
+            &EncKey {
+                key_type: CipherSuite::try_from(session_key.key_type.0 .0.as_slice())?,
+                key_value: session_key.key_value.0 .0,
+            },
+        )
+    }
+
+    /// Performs the TGS exchange with the KDC of the request realm. The request is FAST-armored if FAST is enabled
+    /// in the configuration.
+    async fn tgs_exchange(
+        &self,
+        yield_point: &mut YieldPointLocal,
+        options: GenerateTgsReqOptions<'_>,
+    ) -> Result<(TgsRep, EncKdcRepPart)> {
+        let armor = if self.config.fast.is_some() {
+            let session_key = EncKey {
+                key_type: options
+                    .enc_params
+                    .encryption_type
+                    .clone()
+                    .unwrap_or(DEFAULT_ENCRYPTION_TYPE),
+                key_value: options.session_key.to_vec(),
+            };
+            let sub_key = EncKey {
+                key_type: session_key.key_type.clone(),
+                key_value: generate_random_symmetric_key(&session_key.key_type, &mut OsRng),
+            };
+            options.authenticator.0.subkey = Optional::from(Some(ExplicitContextTag6::from(EncryptionKey {
+                key_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![(&sub_key.key_type).into()])),
+                key_value: ExplicitContextTag1::from(OctetStringAsn1::from(sub_key.key_value.clone())),
+            })));
+            let armor_key = FastArmorKey::tgs_request(&sub_key, &session_key)?;
+
+            Some((armor_key, sub_key))
+        } else {
+            None
+        };
+
+        let realm = options.realm.to_owned();
+        let session_key = options.session_key;
+        let enc_params = options.enc_params;
+
+        let mut tgs_req = generate_tgs_req(options)?;
+        if let Some((armor_key, _)) = armor.as_ref() {
+            armor_key.armor_request(&mut tgs_req.0)?;
+        }
+
+        let response = self
+            .send_to_realm(yield_point, &realm, &serialize_message(&tgs_req)?)
+            .await?;
 
         // first 4 bytes are message len. skipping them
         let mut d = picky_asn1_der::Deserializer::new_from_bytes(&response[4..]);
-        let as_rep: KrbResult<AsRep> = KrbResult::deserialize(&mut d)?;
+        let tgs_rep: KrbResult<TgsRep> = KrbResult::deserialize(&mut d)?;
 
-        as_rep.map_err(|err| {
-            error!(?err, "AS exchange error");
-            err.into()
-        })
+        let Some((armor_key, sub_key)) = armor else {
+            let tgs_rep = tgs_rep?;
+            let enc_tgs_rep_part = extract_enc_tgs_rep_part(&tgs_rep, session_key, enc_params)?;
+
+            return Ok((tgs_rep, enc_tgs_rep_part));
+        };
+
+        let tgs_rep = tgs_rep.map_err(|err| Error::from(armor_key.extract_error(&err).unwrap_or(err)))?;
+
+        let fast_response = armor_key.extract_response(
+            tgs_rep
+                .0
+                .padata
+                .0
+                .as_ref()
+                .map(|pa_datas| pa_datas.0 .0.as_slice())
+                .unwrap_or_default(),
+        )?;
+        armor_key.validate_response(&fast_response, &tgs_rep.0, &tgs_req.0.req_body.0.nonce.0)?;
+
+        // [RFC 6113 5.4.3](https://www.rfc-editor.org/rfc/rfc6113#section-5.4.3): the TGS-REP is encrypted
+        // in the (strengthened) subkey of the armored request
+        let reply_key = strengthen_reply_key(&fast_response, &sub_key)?;
+        let enc_tgs_rep_part = extract_enc_tgs_rep_part_with_sub_key(
+            &tgs_rep,
+            &reply_key.key_value,
+            &EncryptionParams {
+                encryption_type: Some(reply_key.key_type.clone()),
+                ..enc_params.clone()
+            },
+        )?;
+
+        Ok((tgs_rep, enc_tgs_rep_part))
+    }
+
+    /// Requests the service ticket using the client's TGT and follows the KDC referrals.
+    ///
+    /// [RFC 6806 8](https://www.rfc-editor.org/rfc/rfc6806#section-8): if the service belongs to another realm,
+    /// the KDC replies with the cross-realm TGT of the next realm on the path to the service realm, and the request
+    /// is repeated to the KDC of that realm. If the `[capaths]` of the krb5.conf configure the path to the service
+    /// realm (non-transitive trusts), the cross-realm TGTs of the path realms are requested explicitly.
+    async fn service_ticket_exchange(
+        &self,
+        yield_point: &mut YieldPointLocal,
+        tgt: ClientTgt,
+        service_principal: &str,
+        additional_tickets: Option<Vec<Ticket>>,
+        context_requirements: ClientRequestFlags,
+    ) -> Result<(TgsRep, EncKdcRepPart)> {
+        let ClientTgt {
+            crealm,
+            cname,
+            mut ticket,
+            session_key,
+            ..
+        } = tgt;
+
+        let mut realm = crealm.to_string();
+        let mut session_key = EncKey {
+            key_type: self
+                .encryption_params
+                .encryption_type
+                .clone()
+                .unwrap_or(DEFAULT_ENCRYPTION_TYPE),
+            key_value: session_key,
+        };
+        let mut visited_realms = vec![realm.clone()];
+
+        let mut capath: VecDeque<String> = parse_target_name(service_principal)
+            .ok()
+            .and_then(|(_, hostname)| get_service_realm(hostname))
+            .map(|service_realm| get_capath(&realm, &service_realm).into())
+            .unwrap_or_default();
+
+        loop {
+            let capath_realm = capath.pop_front();
+            let target_name = match capath_realm.as_ref() {
+                Some(capath_realm) => format!("{}/{}", TGT_SERVICE_NAME, capath_realm),
+                None => service_principal.to_owned(),
+            };
+
+            let enc_params = EncryptionParams {
+                encryption_type: Some(session_key.key_type.clone()),
+                ..self.encryption_params.clone()
+            };
+            let mut authenticator = generate_authenticator(GenerateAuthenticatorOptions {
+                crealm: &crealm,
+                cname: &cname,
+                seq_num: Some(OsRng.gen::<u32>()),
+                sub_key: None,
+                checksum: None,
+                channel_bindings: self.channel_bindings.as_ref(),
+                extensions: Vec::new(),
+            })?;
+
+            let (tgs_rep, enc_tgs_rep_part) = self
+                .tgs_exchange(
+                    yield_point,
+                    GenerateTgsReqOptions {
+                        realm: &realm,
+                        service_principal: &target_name,
+                        session_key: &session_key.key_value,
+                        ticket,
+                        authenticator: &mut authenticator,
+                        additional_tickets: additional_tickets.clone().filter(|_| capath_realm.is_none()),
+                        enc_params: &enc_params,
+                        context_requirements,
+                        etypes: &self.etypes(),
+                        kdc_options: None,
+                        s4u2self: None,
+                    },
+                )
+                .await?;
+
+            let Some(referral_realm) = referral_realm(&tgs_rep.0.ticket.0, &realm) else {
+                return Ok((tgs_rep, enc_tgs_rep_part));
+            };
+
+            if visited_realms
+                .iter()
+                .any(|visited_realm| visited_realm.eq_ignore_ascii_case(&referral_realm))
+            {
+                return Err(Error::new(
+                    ErrorKind::MaxReferralsExceeded,
+                    format!(
+                        "KDC referral loop: the {} realm has already been visited",
+                        referral_realm
+                    ),
+                ));
+            }
+            if visited_realms.len() > MAX_REFERRAL_HOPS {
+                return Err(Error::new(
+                    ErrorKind::MaxReferralsExceeded,
+                    format!("The number of KDC referrals exceeds {}", MAX_REFERRAL_HOPS),
+                ));
+            }
+
+            if capath_realm.is_some_and(|capath_realm| !capath_realm.eq_ignore_ascii_case(&referral_realm)) {
+                // the KDC knows a different path: follow its referrals instead of the configured one
+                capath.clear();
+            }
+
+            info!(%referral_realm, "Following the KDC referral");
+
+            visited_realms.push(referral_realm.clone());
+            realm = referral_realm;
+            ticket = tgs_rep.0.ticket.0;
+            session_key = EncKey {
+                key_type: CipherSuite::try_from(enc_tgs_rep_part.key.0.key_type.0 .0.as_slice())?,
+                key_value: enc_tgs_rep_part.key.0.key_value.0 .0,
+            };
+        }
+    }
+
+    /// Performs the S4U2Self (`user` is set) or S4U2Proxy (`evidence_ticket` is set) TGS exchange
+    /// using the service's TGT.
+    async fn s4u_exchange(
+        &mut self,
+        yield_point: &mut YieldPointLocal,
+        target_name: Option<&str>,
+        user: Option<S4uUser>,
+        evidence_ticket: Option<Ticket>,
+    ) -> Result<CCacheCredential> {
+        let credentials = self
+            .auth_identity
+            .clone()
+            .ok_or_else(|| Error::new(ErrorKind::NoCredentials, "The service credentials are not provided"))?;
+
+        let ClientTgt {
+            crealm,
+            cname,
+            ticket,
+            session_key,
+            ..
+        } = self
+            .obtain_tgt(yield_point, &credentials, ClientRequestFlags::empty())
+            .await?;
+
+        let realm = crealm.to_string();
+        self.realm = Some(realm.clone());
+
+        let user_realm = match user.as_ref() {
+            Some(S4uUser::Name(name)) if name.contains('@') => get_client_principal_realm(name, ""),
+            _ => realm.clone(),
+        };
+
+        let mut kdc_options = KdcOptions::FORWARDABLE | KdcOptions::RENEWABLE | KdcOptions::CANONICALIZE;
+        if evidence_ticket.is_some() {
+            kdc_options |= KdcOptions::CNAME_IN_ADDL_TKT;
+        }
+
+        let mut authenticator = generate_authenticator(GenerateAuthenticatorOptions {
+            crealm: &crealm,
+            cname: &cname,
+            seq_num: Some(OsRng.gen::<u32>()),
+            sub_key: None,
+            checksum: None,
+            channel_bindings: None,
+            extensions: Vec::new(),
+        })?;
+
+        let (tgs_rep, enc_tgs_rep_part) = self
+            .tgs_exchange(
+                yield_point,
+                GenerateTgsReqOptions {
+                    realm: &realm,
+                    service_principal: target_name.unwrap_or_default(),
+                    session_key: &session_key,
+                    ticket,
+                    authenticator: &mut authenticator,
+                    additional_tickets: evidence_ticket.map(|ticket| vec![ticket]),
+                    enc_params: &self.encryption_params,
+                    context_requirements: ClientRequestFlags::empty(),
+                    etypes: &self.etypes(),
+                    kdc_options: Some(kdc_options),
+                    s4u2self: user.as_ref().map(|user| S4u2SelfOptions {
+                        service: &cname,
+                        user,
+                        user_realm: &user_realm,
+                    }),
+                },
+            )
+            .await?;
+
+        info!("S4U TGS exchange finished successfully");
+
+        if let Some(ticket_cache) = self.config.ticket_cache.as_ref() {
+            store_ticket(ticket_cache, &tgs_rep.0, &enc_tgs_rep_part);
+        }
+
+        CCacheCredential::from_kdc_rep(&tgs_rep.0, &enc_tgs_rep_part)
+    }
+
+    /// Requests the forwarded TGT that is transferred to the service during the credentials delegation.
+    ///
+    /// [RFC 4120 2.6](https://www.rfc-editor.org/rfc/rfc4120#section-2.6): "The FORWARDED flag is set by the TGS
+    /// when a client presents a ticket with the FORWARDABLE flag set and requests a forwarded ticket by specifying
+    /// the FORWARDED KDC option".
+    async fn forwarded_tgt_exchange(
+        &mut self,
+        yield_point: &mut YieldPointLocal,
+        crealm: &Realm,
+        cname: &PrincipalName,
+        tgt: DelegationTgt,
+    ) -> Result<(Ticket, EncKdcRepPart)> {
+        let DelegationTgt { ticket, session_key } = tgt;
+
+        let realm = crealm.to_string();
+        let enc_params = EncryptionParams {
+            encryption_type: Some(session_key.key_type.clone()),
+            ..self.encryption_params.clone()
+        };
+
+        let mut authenticator = generate_authenticator(GenerateAuthenticatorOptions {
+            crealm,
+            cname,
+            seq_num: Some(OsRng.gen::<u32>()),
+            sub_key: None,
+            checksum: None,
+            channel_bindings: None,
+            extensions: Vec::new(),
+        })?;
+
+        let (tgs_rep, enc_tgs_rep_part) = self
+            .tgs_exchange(
+                yield_point,
+                GenerateTgsReqOptions {
+                    realm: &realm,
+                    service_principal: &format!("{}/{}", TGT_SERVICE_NAME, realm),
+                    session_key: &session_key.key_value,
+                    ticket,
+                    authenticator: &mut authenticator,
+                    additional_tickets: None,
+                    enc_params: &enc_params,
+                    context_requirements: ClientRequestFlags::empty(),
+                    etypes: &self.etypes(),
+                    kdc_options: Some(KdcOptions::FORWARDABLE | KdcOptions::FORWARDED | KdcOptions::CANONICALIZE),
+                    s4u2self: None,
+                },
+            )
+            .await?;
+
+        Ok((tgs_rep.0.ticket.0, enc_tgs_rep_part))
+    }
+}
+
+/// Client's TGT and its session key.
+#[derive(Debug)]
+struct ClientTgt {
+    crealm: Realm,
+    cname: PrincipalName,
+    ticket: Ticket,
+    session_key: Vec<u8>,
+    flags: TicketFlags,
+}
+
+/// Client's TGT used to request the forwarded TGT for the credentials delegation.
+#[derive(Debug)]
+struct DelegationTgt {
+    ticket: Ticket,
+    session_key: EncKey,
+}
+
+impl DelegationTgt {
+    /// Returns `None` if the TGT can not be forwarded.
+    fn new(ticket: Ticket, session_key: EncKey, flags: TicketFlags) -> Option<Self> {
+        if flags.contains(TicketFlags::FORWARDABLE) {
+            Some(Self { ticket, session_key })
+        } else {
+            warn!("The TGT is not forwardable: the credentials can not be delegated");
+
+            None
+        }
     }
 }
 
@@ -301,45 +1165,58 @@ impl Sspi for Kerberos {
 
         // checks if the Token buffer present
         let _ = SecurityBuffer::find_buffer(message, SecurityBufferType::Token)?;
-        let data_buffer = SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Data)?;
 
-        let cipher = self
+        let encryption_type = self
             .encryption_params
             .encryption_type
-            .as_ref()
-            .unwrap_or(&DEFAULT_ENCRYPTION_TYPE)
-            .cipher();
+            .clone()
+            .unwrap_or(DEFAULT_ENCRYPTION_TYPE);
 
         let seq_number = self.next_seq_number();
+        let is_acceptor = self.is_acceptor();
 
         let key = get_encryption_key(&self.encryption_params)?;
 
         let key_usage = self.encryption_params.sspi_encrypt_key_usage;
 
-        let mut wrap_token = WrapToken::with_seq_number(seq_number as u64);
+        let data_buffer = SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Data)?;
+
+        let (token, data, padding) = if encryption_type == CipherSuite::Rc4Hmac {
+            gss_rc4::wrap(key, seq_number, is_acceptor, data_buffer.data())?
+        } else {
+            let mut wrap_token = WrapToken::with_seq_number(seq_number as u64);
+
+            let mut payload = data_buffer.data().to_vec();
+            payload.extend_from_slice(&wrap_token.header());
 
-        let mut payload = data_buffer.data().to_vec();
-        payload.extend_from_slice(&wrap_token.header());
+            let mut checksum = encryption_type.cipher().encrypt(key, key_usage, &payload)?;
+            checksum.rotate_right(RRC.into());
 
-        let mut checksum = cipher.encrypt(key, key_usage, &payload)?;
-        checksum.rotate_right(RRC.into());
+            wrap_token.set_rrc(RRC);
+            wrap_token.set_checksum(checksum);
+
+            let mut raw_wrap_token = Vec::with_capacity(92);
+            wrap_token.encode(&mut raw_wrap_token)?;
+
+            if raw_wrap_token.len() < SECURITY_TRAILER {
+                return Err(Error::new(ErrorKind::EncryptFailure, "Cannot encrypt the data"));
+            }
 
-        wrap_token.set_rrc(RRC);
-        wrap_token.set_checksum(checksum);
+            let data = raw_wrap_token.split_off(SECURITY_TRAILER);
 
-        let mut raw_wrap_token = Vec::with_capacity(92);
-        wrap_token.encode(&mut raw_wrap_token)?;
+            (raw_wrap_token, data, Vec::new())
+        };
 
         match self.state {
             KerberosState::PubKeyAuth | KerberosState::Credentials | KerberosState::Final => {
-                if raw_wrap_token.len() < SECURITY_TRAILER {
-                    return Err(Error::new(ErrorKind::EncryptFailure, "Cannot encrypt the data"));
-                }
-
-                let (token, data) = raw_wrap_token.split_at(SECURITY_TRAILER);
-                data_buffer.write_data(data)?;
+                data_buffer.write_data(&data)?;
                 let token_buffer = SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Token)?;
-                token_buffer.write_data(token)?;
+                token_buffer.write_data(&token)?;
+                // the RC4 padding follows the data, so it can't be placed into the token
+                if !padding.is_empty() {
+                    let padding_buffer = SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Padding)?;
+                    padding_buffer.write_data(&padding)?;
+                }
             }
             _ => {
                 return Err(Error::new(
@@ -356,26 +1233,42 @@ impl Sspi for Kerberos {
     fn decrypt_message(&mut self, message: &mut [SecurityBuffer], _sequence_number: u32) -> Result<DecryptionFlags> {
         trace!(encryption_params = ?self.encryption_params);
 
-        let encrypted = extract_encrypted_data(message)?;
+        let mut encrypted = extract_encrypted_data(message)?;
 
-        let cipher = self
+        let encryption_type = self
             .encryption_params
             .encryption_type
-            .as_ref()
-            .unwrap_or(&DEFAULT_ENCRYPTION_TYPE)
-            .cipher();
+            .clone()
+            .unwrap_or(DEFAULT_ENCRYPTION_TYPE);
+
+        if encryption_type == CipherSuite::Rc4Hmac {
+            if let Ok(padding) = SecurityBuffer::buf_data(message, SecurityBufferType::Padding) {
+                encrypted.extend_from_slice(padding);
+            }
+        }
 
         let key = get_encryption_key(&self.encryption_params)?;
 
         let key_usage = self.encryption_params.sspi_decrypt_key_usage;
 
-        let mut wrap_token = WrapToken::decode(encrypted.as_slice())?;
+        let (seq_number, decrypted) = if encryption_type == CipherSuite::Rc4Hmac {
+            let (seq_number, decrypted) = gss_rc4::unwrap(key, !self.is_acceptor(), &encrypted)?;
+
+            (u64::from(seq_number), decrypted)
+        } else {
+            let mut wrap_token = WrapToken::decode(encrypted.as_slice())?;
+
+            wrap_token.checksum.rotate_left(RRC.into());
+
+            let mut decrypted = encryption_type.cipher().decrypt(key, key_usage, &wrap_token.checksum)?;
+            // remove wrap token header
+            decrypted.truncate(decrypted.len() - WrapToken::header_len());
 
-        wrap_token.checksum.rotate_left(RRC.into());
+            (wrap_token.seq_num, decrypted)
+        };
 
-        let mut decrypted = cipher.decrypt(key, key_usage, &wrap_token.checksum)?;
-        // remove wrap token header
-        decrypted.truncate(decrypted.len() - WrapToken::header_len());
+        // the sequence number is checked only after the token integrity is verified by the decryption
+        self.recv_sequence_window.check(seq_number).into_result()?;
 
         save_decrypted_data(&decrypted, message)?;
 
@@ -392,18 +1285,87 @@ impl Sspi for Kerberos {
         }
     }
 
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self, _flags, _sequence_number))]
+    fn make_signature(&mut self, _flags: u32, message: &mut [SecurityBuffer], _sequence_number: u32) -> Result<()> {
+        if !matches!(
+            self.state,
+            KerberosState::PubKeyAuth | KerberosState::Credentials | KerberosState::Final
+        ) {
+            return Err(Error::new(
+                ErrorKind::OutOfSequence,
+                "Kerberos context is not established",
+            ));
+        }
+
+        let seq_number = self.next_seq_number();
+
+        let (mic_token, key_usage) = if self.is_acceptor() {
+            (MicToken::with_acceptor_flags(), ACCEPTOR_SIGN)
+        } else {
+            (MicToken::with_initiator_flags(), INITIATOR_SIGN)
+        };
+
+        make_mic_token(
+            message,
+            mic_token,
+            key_usage,
+            u64::from(seq_number),
+            &self.encryption_params,
+        )
+    }
+
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self, _sequence_number))]
+    fn verify_signature(&mut self, message: &[SecurityBuffer], _sequence_number: u32) -> Result<u32> {
+        let (sent_by_acceptor, key_usage) = if self.is_acceptor() {
+            (false, INITIATOR_SIGN)
+        } else {
+            (true, ACCEPTOR_SIGN)
+        };
+
+        let seq_number = verify_mic_token(message, sent_by_acceptor, key_usage, &self.encryption_params)?;
+
+        self.recv_sequence_window.check(seq_number).into_result()?;
+
+        Ok(0)
+    }
+
     #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
     fn query_context_sizes(&mut self) -> Result<ContextSizes> {
+        let (max_signature, security_trailer, block) =
+            if self.encryption_params.encryption_type == Some(CipherSuite::Rc4Hmac) {
+                (gss_rc4::MAX_SIGNATURE, gss_rc4::SECURITY_TRAILER, gss_rc4::BLOCK_SIZE)
+            } else {
+                (MAX_SIGNATURE, SECURITY_TRAILER, 0)
+            };
+
         Ok(ContextSizes {
             max_token: PACKAGE_INFO.max_token_len,
-            max_signature: MAX_SIGNATURE as u32,
-            block: 0,
-            security_trailer: SECURITY_TRAILER as u32,
+            max_signature: max_signature as u32,
+            block: block as u32,
+            security_trailer: security_trailer as u32,
         })
     }
 
     #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
     fn query_context_names(&mut self) -> Result<ContextNames> {
+        if let Some(server_context) = self.server_context.as_ref() {
+            let enc_ticket_part = &server_context.enc_ticket_part.0;
+            let cname = enc_ticket_part
+                .cname
+                .0
+                .name_string
+                .0
+                 .0
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            return Ok(ContextNames {
+                username: crate::Username::new_upn(&cname, &enc_ticket_part.crealm.0.to_string())
+                    .map_err(|e| Error::new(ErrorKind::InvalidParameter, e))?,
+            });
+        }
         if let Some(CredentialsBuffers::AuthIdentity(identity_buffers)) = &self.auth_identity {
             let identity =
                 AuthIdentity::try_from(identity_buffers).map_err(|e| Error::new(ErrorKind::InvalidParameter, e))?;
@@ -428,6 +1390,26 @@ impl Sspi for Kerberos {
         crate::query_security_package_info(SecurityPackageType::Kerberos)
     }
 
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
+    fn query_context_client_identity(&mut self) -> Result<ContextClientIdentity> {
+        let server_context = self.server_context.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NoCredentials,
+                "Requested the client identity, but the acceptor has not accepted the client's ticket",
+            )
+        })?;
+        let enc_ticket_part = &server_context.enc_ticket_part.0;
+
+        Ok(ContextClientIdentity {
+            principal: principal_name_to_string(&enc_ticket_part.cname.0),
+            realm: enc_ticket_part.crealm.0.to_string(),
+            package: SecurityPackageType::Kerberos,
+            logon_time: kerberos_time_to_date(&enc_ticket_part.auth_time.0)?,
+            mic_verified: server_context.mic_verified,
+            channel_bindings_verified: server_context.channel_bindings_verified,
+        })
+    }
+
     #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
     fn query_context_cert_trust_status(&mut self) -> Result<crate::CertTrustStatus> {
         Err(Error::new(
@@ -482,13 +1464,194 @@ impl SspiImpl for Kerberos {
             .ok_or_else(|| crate::Error::new(ErrorKind::InvalidToken, "Input buffers must be specified"))?;
 
         let status = match &self.state {
+            KerberosState::Negotiate => {
+                if let Ok(sec_buffer) = OwnedSecurityBuffer::find_buffer(input, SecurityBufferType::ChannelBindings) {
+                    self.channel_bindings = Some(ChannelBindings::from_bytes(&sec_buffer.buffer)?);
+                }
+
+                let input_token = OwnedSecurityBuffer::find_buffer(input, SecurityBufferType::Token)?;
+
+                // Our client starts with the user-to-user TGT-REQ. The acceptor has no TGT to send back,
+                // so the client is asked to use the regular service ticket encrypted with the service key.
+                if extract_tgt_req(&input_token.buffer).is_some() {
+                    let output_token = OwnedSecurityBuffer::find_buffer_mut(builder.output, SecurityBufferType::Token)?;
+                    output_token
+                        .buffer
+                        .write_all(&picky_asn1_der::to_vec(&generate_neg_token_targ_without_tgt())?)?;
+
+                    return Ok(AcceptSecurityContextResult {
+                        status: SecurityStatus::ContinueNeeded,
+                        flags: ServerResponseFlags::empty(),
+                        expiry: None,
+                    });
+                }
+
+                let ApReqToken {
+                    ap_req,
+                    mech_id,
+                    spnego,
+                } = extract_ap_req(&input_token.buffer)?;
+
+                let server_properties = self.config.server_properties.as_ref().ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NoCredentials,
+                        "Kerberos server properties are not provided: service keys are needed to decrypt the ticket",
+                    )
+                })?;
+
+                let ap_options = extract_ap_options(&ap_req);
+                if ap_options.contains(ApOptions::USE_SESSION_KEY) {
+                    return Err(Error::new(
+                        ErrorKind::UnsupportedFunction,
+                        "User-to-user authentication is not supported by the Kerberos server",
+                    ));
+                }
+
+                let allow_rc4_hmac = self.config.allow_rc4_hmac;
+                let service_keys = server_properties
+                    .service_keys
+                    .iter()
+                    .filter(|service_key| allow_rc4_hmac || service_key.encryption_type != CipherSuite::Rc4Hmac)
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                let ticket = &ap_req.0.ticket.0;
+                let enc_ticket_part = extract_enc_ticket_part(ticket, &service_keys)?;
+                validate_ticket(ticket, &enc_ticket_part, server_properties)?;
+
+                let pac = validate_pac(
+                    &enc_ticket_part,
+                    find_service_key(ticket, &service_keys)?,
+                    server_properties,
+                )?;
+
+                let session_key = extract_encryption_key(&enc_ticket_part.0.key.0)?;
+                if !allow_rc4_hmac && session_key.key_type == CipherSuite::Rc4Hmac {
+                    return Err(Error::new(
+                        ErrorKind::AlgorithmMismatch,
+                        "RC4-HMAC session key is not allowed by the Kerberos config",
+                    ));
+                }
+
+                let authenticator = extract_authenticator(&ap_req, &session_key.key_value)?;
+                validate_authenticator(&authenticator, ticket, &enc_ticket_part, server_properties)?;
+
+                let checksum = extract_authenticator_checksum(&authenticator)?;
+                validate_channel_bindings(checksum.as_ref(), self.channel_bindings.as_ref())?;
+
+                let (flags, delegation) = checksum
+                    .map(|checksum| (checksum.flags, checksum.delegation))
+                    .unwrap_or((GssFlags::empty(), None));
+                info!(?flags, "ApReq Authenticator checksum flags");
+
+                self.recv_sequence_window = SequenceWindow::new(
+                    flags.contains(GssFlags::GSS_C_REPLAY_FLAG),
+                    flags.contains(GssFlags::GSS_C_SEQUENCE_FLAG),
+                    extract_seq_number_from_authenticator(&authenticator)?,
+                );
+
+                let initiator_sub_key = authenticator
+                    .0
+                    .subkey
+                    .0
+                    .as_ref()
+                    .map(|sub_key| extract_encryption_key(&sub_key.0))
+                    .transpose()?;
+
+                let delegated_credentials = delegation
+                    .map(|krb_cred| extract_delegated_credentials(&krb_cred, &session_key, initiator_sub_key.as_ref()))
+                    .transpose()?;
+                if delegated_credentials.is_some() {
+                    info!("The client has delegated its credentials");
+                }
+
+                self.encryption_params.session_key = Some(session_key.key_value.clone());
+
+                // SPNEGO always requires the AP-REP: our client expects the acceptor sub-key and the mechListMIC.
+                let status = if spnego
+                    || ap_options.contains(ApOptions::MUTUAL_REQUIRED)
+                    || flags.contains(GssFlags::GSS_C_MUTUAL_FLAG)
+                {
+                    let encryption_type = initiator_sub_key
+                        .as_ref()
+                        .map(|sub_key| sub_key.key_type.clone())
+                        .unwrap_or_else(|| session_key.key_type.clone());
+                    let acceptor_sub_key = EncKey {
+                        key_value: generate_random_symmetric_key(&encryption_type, &mut OsRng),
+                        key_type: encryption_type,
+                    };
+
+                    self.encryption_params.encryption_type = Some(acceptor_sub_key.key_type.clone());
+                    self.encryption_params.sub_session_key = Some(acceptor_sub_key.key_value.clone());
+
+                    let seq_number = self.next_seq_number();
+                    let ap_rep = generate_ap_rep(&session_key, &authenticator, Some(&acceptor_sub_key), seq_number)?;
+
+                    let encoded_ap_rep = match mech_id {
+                        Some(mech_id) if spnego => picky_asn1_der::to_vec(&generate_neg_ap_rep(
+                            ap_rep,
+                            mech_id,
+                            generate_acceptor_raw(
+                                picky_asn1_der::to_vec(&get_mech_list())?,
+                                seq_number.into(),
+                                &self.encryption_params,
+                            )?,
+                        )?)?,
+                        Some(mech_id) => picky_asn1_der::to_vec(&generate_gss_ap_rep(ap_rep, mech_id))?,
+                        None => picky_asn1_der::to_vec(&ap_rep)?,
+                    };
+
+                    let output_token = OwnedSecurityBuffer::find_buffer_mut(builder.output, SecurityBufferType::Token)?;
+                    output_token.buffer.write_all(&encoded_ap_rep)?;
+
+                    if spnego {
+                        self.state = KerberosState::ApExchange;
+
+                        SecurityStatus::ContinueNeeded
+                    } else {
+                        self.state = KerberosState::PubKeyAuth;
+
+                        SecurityStatus::Ok
+                    }
+                } else {
+                    self.encryption_params.encryption_type = Some(
+                        initiator_sub_key
+                            .as_ref()
+                            .map(|sub_key| sub_key.key_type.clone())
+                            .unwrap_or_else(|| session_key.key_type.clone()),
+                    );
+                    self.encryption_params.sub_session_key = initiator_sub_key.map(|sub_key| sub_key.key_value);
+
+                    self.state = KerberosState::PubKeyAuth;
+
+                    SecurityStatus::Ok
+                };
+
+                self.server_context = Some(ServerContext {
+                    enc_ticket_part,
+                    flags,
+                    delegated_credentials,
+                    channel_bindings_verified: self.channel_bindings.is_some(),
+                    mic_verified: false,
+                    pac,
+                });
+
+                status
+            }
             KerberosState::ApExchange => {
                 let input_token = OwnedSecurityBuffer::find_buffer(input, SecurityBufferType::Token)?;
 
-                let _ap_req: ApReq = picky_asn1_der::from_bytes(&input_token.buffer)
-                    .map_err(|e| Error::new(ErrorKind::DecryptFailure, format!("{:?}", e)))?;
+                let neg_token_targ: NegTokenTarg1 = picky_asn1_der::from_bytes(&input_token.buffer)?;
 
-                self.state = KerberosState::Final;
+                if let Some(ref token) = neg_token_targ.0.mech_list_mic.0 {
+                    validate_mic_token(&token.0 .0, INITIATOR_SIGN, &self.encryption_params)?;
+
+                    if let Some(server_context) = self.server_context.as_mut() {
+                        server_context.mic_verified = true;
+                    }
+                }
+
+                self.state = KerberosState::PubKeyAuth;
 
                 SecurityStatus::Ok
             }
@@ -502,7 +1665,11 @@ impl SspiImpl for Kerberos {
 
         Ok(AcceptSecurityContextResult {
             status,
-            flags: ServerResponseFlags::empty(),
+            flags: self
+                .server_context
+                .as_ref()
+                .map(|server_context| server_context.flags.into())
+                .unwrap_or_else(ServerResponseFlags::empty),
             expiry: None,
         })
     }
@@ -543,6 +1710,7 @@ impl<'a> Kerberos {
             nonce: &OsRng.gen::<u32>().to_ne_bytes(),
             hostname: &hostname,
             context_requirements: ClientRequestFlags::empty(),
+            etypes: &self.etypes(),
         };
         let kdc_req_body = generate_as_req_kdc_body(&options)?;
 
@@ -551,6 +1719,7 @@ impl<'a> Kerberos {
             salt: salt.as_bytes().to_vec(),
             enc_params: self.encryption_params.clone(),
             with_pre_auth: false,
+            armor_key: None,
         });
 
         let as_rep = self.as_exchange(yield_point, &kdc_req_body, pa_data_options).await?;
@@ -576,7 +1745,8 @@ impl<'a> Kerberos {
         let authenticator_seb_key = generate_random_symmetric_key(enc_type, &mut OsRng);
 
         let authenticator = generate_authenticator(GenerateAuthenticatorOptions {
-            kdc_rep: &as_rep.0,
+            crealm: &as_rep.0.crealm.0,
+            cname: &as_rep.0.cname.0,
             seq_num: Some(seq_num),
             sub_key: Some(EncKey {
                 key_type: enc_type.clone(),
@@ -699,180 +1869,175 @@ impl<'a> Kerberos {
                     .as_ref()
                     .ok_or_else(|| Error::new(ErrorKind::WrongCredentialHandle, "No credentials provided"))?;
 
-                let (username, password, realm, cname_type) = match credentials {
-                    CredentialsBuffers::AuthIdentity(auth_identity) => {
-                        let username = utf16_bytes_to_utf8_string(&auth_identity.user);
-                        let domain = utf16_bytes_to_utf8_string(&auth_identity.domain);
-                        let password = utf16_bytes_to_utf8_string(auth_identity.password.as_ref());
-
-                        let realm = get_client_principal_realm(&username, &domain);
-                        let cname_type = get_client_principal_name_type(&username, &domain);
+                let (username, _, realm, _) = credentials_principal(credentials);
+                self.realm = Some(realm.clone());
 
-                        (username, password, realm, cname_type)
-                    }
-                    CredentialsBuffers::SmartCard(smart_card) => {
-                        let username = utf16_bytes_to_utf8_string(&smart_card.username);
-                        let password = utf16_bytes_to_utf8_string(smart_card.pin.as_ref());
+                let service_principal = builder.target_name.ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NoCredentials,
+                        "Service target name (service principal name) is not provided",
+                    )
+                })?;
 
-                        let realm = get_client_principal_realm(&username, "");
-                        let cname_type = get_client_principal_name_type(&username, "");
+                let ticket_cache = self.config.ticket_cache.clone();
+                // user-to-user tickets are encrypted using the session key of the server's TGT,
+                // so they are not reusable and we neither look them up nor store them in the cache
+                let is_u2u = tgt_ticket.is_some();
 
-                        (username, password, realm.to_uppercase(), cname_type)
+                let cached_service_ticket = match ticket_cache.as_ref() {
+                    Some(ticket_cache) if !is_u2u => {
+                        lookup_cached_ticket(ticket_cache, &username, &realm, service_principal)
                     }
+                    _ => None,
                 };
-                self.realm = Some(realm.clone());
 
-                let options = GenerateAsReqOptions {
-                    realm: &realm,
-                    username: &username,
-                    cname_type,
-                    snames: &[TGT_SERVICE_NAME, &realm],
-                    // 4 = size of u32
-                    nonce: &OsRng.gen::<[u8; 4]>(),
-                    hostname: &unwrap_hostname(self.config.client_computer_name.as_deref())?,
-                    context_requirements: builder.context_requirements,
-                };
-                let kdc_req_body = generate_as_req_kdc_body(&options)?;
-
-                let pa_data_options =
-                    match credentials {
-                        CredentialsBuffers::AuthIdentity(auth_identity) => {
-                            let domain = utf16_bytes_to_utf8_string(&auth_identity.domain);
-                            let salt = format!("{}{}", domain, username);
-
-                            AsReqPaDataOptions::AuthIdentity(GenerateAsPaDataOptions {
-                                password: &password,
-                                salt: salt.as_bytes().to_vec(),
-                                enc_params: self.encryption_params.clone(),
-                                with_pre_auth: false,
-                            })
-                        }
-                        CredentialsBuffers::SmartCard(smart_card) => {
-                            let private_key_pem =
-                                utf16_bytes_to_utf8_string(smart_card.private_key_pem.as_ref().ok_or_else(|| {
-                                    Error::new(ErrorKind::InternalError, "scard private key is missing")
-                                })?);
-                            self.dh_parameters = Some(generate_client_dh_parameters(&mut OsRng)?);
-
-                            AsReqPaDataOptions::SmartCard(Box::new(pk_init::GenerateAsPaDataOptions {
-                                p2p_cert: picky_asn1_der::from_bytes(&smart_card.certificate)?,
-                                kdc_req_body: &kdc_req_body,
-                                dh_parameters: self.dh_parameters.clone().unwrap(),
-                                sign_data: Box::new(move |data_to_sign| {
-                                    let mut sha1 = Sha1::new();
-                                    sha1.update(data_to_sign);
-                                    let hash = sha1.finalize().to_vec();
-                                    let private_key = PrivateKey::from_pem_str(&private_key_pem)?;
-                                    let rsa_private_key = RsaPrivateKey::try_from(&private_key)?;
-                                    Ok(rsa_private_key.sign(Pkcs1v15Sign::new::<Sha1>(), &hash)?)
-                                }),
-                                with_pre_auth: false,
-                                authenticator_nonce: OsRng.gen::<[u8; 4]>(),
-                            }))
+                let delegate = builder.context_requirements.contains(ClientRequestFlags::DELEGATE);
+                let mut delegation_tgt = None;
+
+                let (crealm, cname, service_ticket, session_key_type) = if let Some(credential) = cached_service_ticket
+                {
+                    info!("Using the service ticket from the cache.");
+
+                    self.encryption_params.encryption_type = Some(credential.encryption_type()?);
+                    self.encryption_params.session_key = Some(credential.key.as_ref().clone());
+
+                    if delegate {
+                        let cached_tgt = ticket_cache.as_ref().and_then(|ticket_cache| {
+                            lookup_cached_ticket(
+                                ticket_cache,
+                                &username,
+                                &realm,
+                                &format!("{}/{}", TGT_SERVICE_NAME, realm),
+                            )
+                        });
+
+                        if let Some(tgt) = cached_tgt {
+                            delegation_tgt = DelegationTgt::new(
+                                tgt.decode_ticket()?,
+                                EncKey {
+                                    key_type: tgt.encryption_type()?,
+                                    key_value: tgt.key.as_ref().clone(),
+                                },
+                                TicketFlags::from_bits_retain(tgt.ticket_flags),
+                            );
                         }
-                    };
-
-                let as_rep = self.as_exchange(yield_point, &kdc_req_body, pa_data_options).await?;
-
-                info!("AS exchange finished successfully.");
-
-                self.realm = Some(as_rep.0.crealm.0.to_string());
-
-                let (encryption_type, salt) = extract_encryption_params_from_as_rep(&as_rep)?;
-
-                let encryption_type = CipherSuite::try_from(encryption_type as usize)?;
-
-                self.encryption_params.encryption_type = Some(encryption_type);
-
-                let mut authenticator = generate_authenticator(GenerateAuthenticatorOptions {
-                    kdc_rep: &as_rep.0,
-                    seq_num: Some(OsRng.gen::<u32>()),
-                    sub_key: None,
-                    checksum: None,
-                    channel_bindings: self.channel_bindings.as_ref(),
-                    extensions: Vec::new(),
-                })?;
-
-                let mut session_key_extractor = match credentials {
-                    CredentialsBuffers::AuthIdentity(_) => AsRepSessionKeyExtractor::AuthIdentity {
-                        salt: &salt,
-                        password: &password,
-                        enc_params: &mut self.encryption_params,
-                    },
-                    CredentialsBuffers::SmartCard(_) => AsRepSessionKeyExtractor::SmartCard {
-                        dh_parameters: self.dh_parameters.as_mut().unwrap(),
-                        enc_params: &mut self.encryption_params,
-                    },
-                };
-                let session_key_1 = session_key_extractor.session_key(&as_rep)?;
-
-                let service_principal = builder.target_name.ok_or_else(|| {
-                    Error::new(
-                        ErrorKind::NoCredentials,
-                        "Service target name (service principal name) is not provided",
+                    }
+
+                    (
+                        credential.client.to_realm()?,
+                        credential.client.to_principal_name()?,
+                        credential.decode_ticket()?,
+                        credential.encryption_type()?,
                     )
-                })?;
+                } else {
+                    let client_tgt = self
+                        .obtain_tgt(yield_point, credentials, builder.context_requirements)
+                        .await?;
+
+                    if delegate {
+                        delegation_tgt = DelegationTgt::new(
+                            client_tgt.ticket.clone(),
+                            EncKey {
+                                key_type: self
+                                    .encryption_params
+                                    .encryption_type
+                                    .clone()
+                                    .unwrap_or(DEFAULT_ENCRYPTION_TYPE),
+                                key_value: client_tgt.session_key.clone(),
+                            },
+                            client_tgt.flags,
+                        );
+                    }
 
-                let tgs_req = generate_tgs_req(GenerateTgsReqOptions {
-                    realm: &as_rep.0.crealm.0.to_string(),
-                    service_principal,
-                    session_key: &session_key_1,
-                    ticket: as_rep.0.ticket.0,
-                    authenticator: &mut authenticator,
-                    additional_tickets: tgt_ticket.map(|ticket| vec![ticket]),
-                    enc_params: &self.encryption_params,
-                    context_requirements: builder.context_requirements,
-                })?;
+                    self.realm = Some(client_tgt.crealm.to_string());
+
+                    let (tgs_rep, enc_tgs_rep_part) = self
+                        .service_ticket_exchange(
+                            yield_point,
+                            client_tgt,
+                            service_principal,
+                            tgt_ticket.map(|ticket| vec![ticket]),
+                            builder.context_requirements,
+                        )
+                        .await?;
 
-                let response = self.send(yield_point, &serialize_message(&tgs_req)?).await?;
+                    info!("TGS exchange finished successfully");
 
-                // first 4 bytes are message len. skipping them
-                let mut d = picky_asn1_der::Deserializer::new_from_bytes(&response[4..]);
-                let tgs_rep: KrbResult<TgsRep> = KrbResult::deserialize(&mut d)?;
-                let tgs_rep = tgs_rep?;
+                    if let Some(ticket_cache) = ticket_cache.as_ref().filter(|_| !is_u2u) {
+                        store_ticket(ticket_cache, &tgs_rep.0, &enc_tgs_rep_part);
+                    }
+
+                    let session_key = extract_encryption_key(&enc_tgs_rep_part.key.0)?;
+                    self.encryption_params.session_key = Some(session_key.key_value);
 
-                info!("TGS exchange finished successfully");
+                    let KdcRep {
+                        crealm, cname, ticket, ..
+                    } = tgs_rep.0;
 
-                let session_key_2 =
-                    extract_session_key_from_tgs_rep(&tgs_rep, &session_key_1, &self.encryption_params)?;
+                    (crealm.0, cname.0, ticket.0, session_key.key_type)
+                };
 
-                self.encryption_params.session_key = Some(session_key_2);
+                self.realm = Some(crealm.to_string());
 
                 let seq_num = self.next_seq_number();
 
                 let enc_type = self
                     .encryption_params
                     .encryption_type
-                    .as_ref()
-                    .unwrap_or(&DEFAULT_ENCRYPTION_TYPE);
-                let authenticator_sub_key = generate_random_symmetric_key(enc_type, &mut OsRng);
+                    .clone()
+                    .unwrap_or(DEFAULT_ENCRYPTION_TYPE);
+                let authenticator_sub_key = generate_random_symmetric_key(&enc_type, &mut OsRng);
 
                 // the original flag is
                 // GSS_C_MUTUAL_FLAG | GSS_C_REPLAY_FLAG | GSS_C_SEQUENCE_FLAG | GSS_C_CONF_FLAG | GSS_C_INTEG_FLAG
                 // we want to be able to turn of sign and seal, so we leave confidentiality and integrity flags out
                 let mut flags: GssFlags = builder.context_requirements.into();
+                let mut checksum_value = ChecksumValues::default();
                 if flags.contains(GssFlags::GSS_C_DELEG_FLAG) {
-                    // Below are reasons why we turn off the GSS_C_DELEG_FLAG flag.
-                    //
                     // RFC4121: The Kerberos Version 5 GSS-API. Section 4.1.1:  Authenticator Checksum
                     // https://datatracker.ietf.org/doc/html/rfc4121#section-4.1.1.1
                     //
-                    // "The length of the checksum field MUST be at least 24 octets when GSS_C_DELEG_FLAG is not set,
-                    // and at least 28 octets plus Dlgth octets when GSS_C_DELEG_FLAG is set."
-                    // Out implementation _always_ uses the 24 octets checksum and do not support Kerberos credentials delegation.
-                    //
                     // "When delegation is used, a ticket-granting ticket will be transferred in a KRB_CRED message."
-                    // We do not support KRB_CRED messages. So, the GSS_C_DELEG_FLAG flags should be turned off.
-                    warn!("Kerberos ApReq Authenticator checksum GSS_C_DELEG_FLAG is not supported. Turning it off...");
-                    flags.remove(GssFlags::GSS_C_DELEG_FLAG);
+                    match delegation_tgt {
+                        Some(tgt) => {
+                            let (forwarded_tgt, enc_tgs_rep_part) =
+                                self.forwarded_tgt_exchange(yield_point, &crealm, &cname, tgt).await?;
+
+                            info!("Forwarded TGT received successfully");
+
+                            let krb_cred = generate_krb_cred(
+                                forwarded_tgt,
+                                &crealm,
+                                &cname,
+                                &enc_tgs_rep_part,
+                                // the enc-part is encrypted in the session key of the service ticket
+                                &EncKey {
+                                    key_type: session_key_type.clone(),
+                                    key_value: self.encryption_params.session_key.clone().unwrap_or_default(),
+                                },
+                            )?;
+                            checksum_value.set_delegation(&picky_asn1_der::to_vec(&krb_cred)?)?;
+                        }
+                        None => {
+                            warn!("The forwardable TGT is not available. Turning GSS_C_DELEG_FLAG off...");
+                            flags.remove(GssFlags::GSS_C_DELEG_FLAG);
+                        }
+                    }
                 }
                 info!(?flags, "ApReq Authenticator checksum flags");
 
-                let mut checksum_value = ChecksumValues::default();
+                // the initial sequence number of the acceptor becomes known from the AP-REP
+                self.recv_sequence_window = SequenceWindow::new(
+                    flags.contains(GssFlags::GSS_C_REPLAY_FLAG),
+                    flags.contains(GssFlags::GSS_C_SEQUENCE_FLAG),
+                    None,
+                );
+
                 checksum_value.set_flags(flags);
 
                 let authenticator_options = GenerateAuthenticatorOptions {
-                    kdc_rep: &tgs_rep.0,
+                    crealm: &crealm,
+                    cname: &cname,
                     seq_num: Some(seq_num),
                     sub_key: Some(EncKey {
                         key_type: enc_type.clone(),
@@ -891,8 +2056,13 @@ impl<'a> Kerberos {
                 let encoded_auth = picky_asn1_der::to_vec(&authenticator)?;
                 info!(encoded_ap_req_authenticator = ?encoded_auth);
 
-                // FIXME: properly negotiate mech id - Windows always does KRB5 U2U
-                let mech_id = oids::krb5_user_to_user();
+                // the acceptor that has no TGT (e.g. our server that uses the service keys) doesn't send the TGT-REP:
+                // the regular service ticket is used then
+                let mech_id = if is_u2u {
+                    oids::krb5_user_to_user()
+                } else {
+                    oids::krb5()
+                };
 
                 let mut context_requirements = builder.context_requirements;
 
@@ -902,7 +2072,7 @@ impl<'a> Kerberos {
                 }
 
                 let ap_req = generate_ap_req(
-                    tgs_rep.0.ticket.0,
+                    service_ticket,
                     self.encryption_params.session_key.as_ref().unwrap(),
                     &authenticator,
                     &self.encryption_params,
@@ -933,14 +2103,19 @@ impl<'a> Kerberos {
 
                 let ap_rep = extract_ap_rep_from_neg_token_targ(&neg_token_targ)?;
 
-                let sub_session_key = extract_sub_session_key_from_ap_rep(
+                let ap_rep_enc_part = decrypt_ap_rep_enc_part(
                     &ap_rep,
                     self.encryption_params.session_key.as_ref().unwrap(),
                     &self.encryption_params,
                 )?;
+                let sub_session_key = extract_sub_session_key_from_ap_rep(&ap_rep_enc_part)?;
 
                 self.encryption_params.sub_session_key = Some(sub_session_key);
 
+                if let Some(seq_number) = extract_seq_number_from_ap_rep(&ap_rep_enc_part)? {
+                    self.recv_sequence_window.set_initial_seq_number(seq_number);
+                }
+
                 if let Some(ref token) = neg_token_targ.0.mech_list_mic.0 {
                     validate_mic_token(&token.0 .0, ACCEPTOR_SIGN, &self.encryption_params)?;
                 }
@@ -948,7 +2123,7 @@ impl<'a> Kerberos {
                 let neg_token_targ = generate_final_neg_token_targ(Some(generate_initiator_raw(
                     picky_asn1_der::to_vec(&get_mech_list())?,
                     self.seq_number as u64,
-                    self.encryption_params.sub_session_key.as_ref().unwrap(),
+                    &self.encryption_params,
                 )?));
 
                 let encoded_final_neg_token_targ = picky_asn1_der::to_vec(&neg_token_targ)?;
@@ -987,11 +2162,133 @@ impl SspiEx for Kerberos {
     }
 }
 
+/// Returns the user name, password (PIN), realm, and principal name type of the client credentials.
+fn credentials_principal(credentials: &CredentialsBuffers) -> (String, String, String, u8) {
+    match credentials {
+        CredentialsBuffers::AuthIdentity(auth_identity) => {
+            let username = utf16_bytes_to_utf8_string(&auth_identity.user);
+            let domain = utf16_bytes_to_utf8_string(&auth_identity.domain);
+            let password = utf16_bytes_to_utf8_string(auth_identity.password.as_ref());
+
+            let realm = get_client_principal_realm(&username, &domain);
+            let cname_type = get_client_principal_name_type(&username, &domain);
+
+            (username, password, realm, cname_type)
+        }
+        CredentialsBuffers::SmartCard(smart_card) => {
+            let username = utf16_bytes_to_utf8_string(&smart_card.username);
+            let password = utf16_bytes_to_utf8_string(smart_card.pin.as_ref());
+
+            let realm = get_client_principal_realm(&username, "");
+            let cname_type = get_client_principal_name_type(&username, "");
+
+            (username, password, realm.to_uppercase(), cname_type)
+        }
+    }
+}
+
+/// Looks for the ticket in the cache.
+///
+/// The ticket cache is an optimization, so the cache errors are logged and do not fail the authentication.
+fn lookup_cached_ticket(
+    ticket_cache: &TicketCache,
+    username: &str,
+    realm: &str,
+    service_principal: &str,
+) -> Option<CCacheCredential> {
+    ticket_cache
+        .lookup(username, realm, service_principal)
+        .unwrap_or_else(|err| {
+            warn!(?err, "Cannot read the ticket cache");
+
+            None
+        })
+}
+
+/// Returns the realm of the cross-realm TGT issued by the KDC of the `realm` instead of the requested ticket.
+fn referral_realm(ticket: &Ticket, realm: &str) -> Option<String> {
+    match ticket.0.sname.0.name_string.0 .0.as_slice() {
+        [service_name, referral_realm]
+            if service_name.to_string() == TGT_SERVICE_NAME
+                && !referral_realm.to_string().eq_ignore_ascii_case(realm) =>
+        {
+            Some(referral_realm.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Derives the FAST armor key from the TGT of the default principal of the ticket cache.
+fn cached_armor_key(ticket_cache: &TicketCache, realm: &str) -> Result<FastArmorKey> {
+    let ccache = ticket_cache
+        .load()?
+        .ok_or_else(|| Error::new(ErrorKind::NoCredentials, "The FAST armor ticket cache is empty"))?;
+    let principal = ccache.default_principal;
+
+    let credential = ticket_cache
+        .lookup(
+            &principal.components.join("/"),
+            &principal.realm,
+            &format!("{}/{}", TGT_SERVICE_NAME, realm),
+        )?
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NoCredentials,
+                format!(
+                    "The FAST armor TGT for the {} realm is not found in the ticket cache",
+                    realm
+                ),
+            )
+        })?;
+
+    FastArmorKey::ap_request(
+        &credential.client.to_realm()?,
+        &credential.client.to_principal_name()?,
+        credential.decode_ticket()?,
+        &EncKey {
+            key_type: credential.encryption_type()?,
+            key_value: credential.key.as_ref().clone(),
+        },
+    )
+}
+
+/// Signs the PKINIT request with the private key from the credentials.
+///
+/// The system-provided smart cards are reachable only with the `scard` feature.
+#[cfg(not(feature = "scard"))]
+fn software_sign_data_fn(smart_card: &crate::SmartCardIdentityBuffers) -> Result<pk_init::SignDataFn> {
+    use picky::key::PrivateKey;
+
+    let private_key_pem = utf16_bytes_to_utf8_string(
+        smart_card
+            .private_key_pem
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InternalError, "scard private key is missing"))?,
+    );
+
+    Ok(Box::new(move |data_to_sign, signature_algorithm| {
+        let private_key = PrivateKey::from_pem_str(&private_key_pem)?;
+
+        signature_algorithm
+            .sign(data_to_sign, &private_key)
+            .map_err(|err| Error::new(ErrorKind::InternalError, format!("Unable to sign the data: {:?}", err)))
+    }))
+}
+
+fn store_ticket(ticket_cache: &TicketCache, kdc_rep: &KdcRep, enc_part: &EncKdcRepPart) {
+    if let Err(err) =
+        CCacheCredential::from_kdc_rep(kdc_rep, enc_part).and_then(|credential| ticket_cache.store(credential))
+    {
+        warn!(?err, "Cannot store the ticket in the ticket cache");
+    }
+}
+
 #[cfg(any(feature = "__test-data", test))]
 pub mod test_data {
     use picky_krb::constants::key_usages::{ACCEPTOR_SEAL, INITIATOR_SEAL};
-    use picky_krb::crypto::CipherSuite;
 
+    use super::cipher::CipherSuite;
+    use super::sequence_window::SequenceWindow;
     use super::{EncryptionParams, KerberosConfig, KerberosState};
     use crate::Kerberos;
 
@@ -1007,10 +2304,7 @@ pub mod test_data {
     pub fn fake_client() -> Kerberos {
         Kerberos {
             state: KerberosState::Final,
-            config: KerberosConfig {
-                kdc_url: None,
-                client_computer_name: None,
-            },
+            config: KerberosConfig::default(),
             auth_identity: None,
             encryption_params: EncryptionParams {
                 encryption_type: Some(CipherSuite::Aes256CtsHmacSha196),
@@ -1020,20 +2314,19 @@ pub mod test_data {
                 sspi_decrypt_key_usage: ACCEPTOR_SEAL,
             },
             seq_number: 1234,
+            recv_sequence_window: SequenceWindow::disabled(),
             realm: None,
             kdc_url: None,
             channel_bindings: None,
             dh_parameters: None,
+            server_context: None,
         }
     }
 
     pub fn fake_server() -> Kerberos {
         Kerberos {
             state: KerberosState::Final,
-            config: KerberosConfig {
-                kdc_url: None,
-                client_computer_name: None,
-            },
+            config: KerberosConfig::default(),
             auth_identity: None,
             encryption_params: EncryptionParams {
                 encryption_type: Some(CipherSuite::Aes256CtsHmacSha196),
@@ -1043,17 +2336,235 @@ pub mod test_data {
                 sspi_decrypt_key_usage: INITIATOR_SEAL,
             },
             seq_number: 0,
+            recv_sequence_window: SequenceWindow::disabled(),
             realm: None,
             kdc_url: None,
             channel_bindings: None,
             dh_parameters: None,
+            server_context: None,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{EncryptionFlags, SecurityBuffer, Sspi};
+    use picky_asn1::bit_string::BitString;
+    use picky_asn1::date::GeneralizedTime;
+    use picky_asn1::restricted_string::IA5String;
+    use picky_asn1::wrapper::{
+        Asn1SequenceOf, BitStringAsn1, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag10,
+        ExplicitContextTag2, ExplicitContextTag3, ExplicitContextTag4, ExplicitContextTag5, ExplicitContextTag6,
+        ExplicitContextTag7, ExplicitContextTag9, IntegerAsn1, OctetStringAsn1, Optional,
+    };
+    use picky_asn1_x509::oids;
+    use picky_krb::constants::gss_api::AUTHENTICATOR_CHECKSUM_TYPE;
+    use picky_krb::constants::key_usages::{ACCEPTOR_SIGN, TICKET_REP};
+    use picky_krb::constants::types::NT_SRV_INST;
+    use picky_krb::data_types::{
+        Authenticator, AuthenticatorInner, AuthorizationData, AuthorizationDataInner, Checksum, EncryptedData,
+        EncryptionKey, KerberosStringAsn1, KerberosTime, PrincipalName, Ticket, TicketInner,
+    };
+    use picky_krb::gss_api::{NegTokenTarg, NegTokenTarg1};
+    use picky_krb::messages::EncKdcRepPart;
+    use time::{Duration, OffsetDateTime};
+
+    use super::ccache::{CCacheCredential, CCachePrincipal, TicketCache};
+    use super::cipher::CipherSuite;
+    use super::client::generators::{
+        generate_ap_req, generate_final_neg_token_targ, generate_krb_cred, generate_neg_ap_req, get_mech_list,
+        ChecksumValues, EncKey, GssFlags,
+    };
+    use super::config::KerberosConfig;
+    use super::data_types::{EncTicketPart, EncTicketPartInner, TransitedEncoding};
+    use super::flags::ApOptions;
+    use super::pac::tests::{encode_pac, key as pac_key};
+    use super::pac::PacClientInfo;
+    use super::sequence_window::SequenceWindow;
+    use super::server::extractors::{
+        decrypt_ap_rep_enc_part, extract_ap_options, extract_ap_rep_from_neg_token_targ, extract_ap_req,
+        extract_sub_session_key_from_ap_rep,
+    };
+    use super::server::{ServerProperties, ServiceKey};
+    use super::utils::{generate_initiator_raw, validate_mic_token};
+    use super::{referral_realm, EncryptionParams, Kerberos, KerberosState, KERBEROS_VERSION};
+    use crate::channel_bindings::ChannelBindings;
+    use crate::crypto::compute_md5_channel_bindings_hash;
+    use crate::{
+        AuthIdentity, ClientRequestFlags, CredentialsBuffers, DataRepresentation, EncryptionFlags, ErrorKind,
+        OwnedSecurityBuffer, SecurityBuffer, SecurityBufferType, SecurityPackageType, SecurityStatus,
+        ServerRequestFlags, Sspi, SspiImpl, Username,
+    };
+
+    const REALM: &str = "EXAMPLE.COM";
+    const SERVICE_PASSWORD: &str = "ServicePassword1!";
+    const SERVICE_SALT: &str = "EXAMPLE.COMHTTPwww.example.com";
+    const SESSION_KEY: [u8; 32] = [7; 32];
+    const INITIATOR_SUB_KEY: [u8; 32] = [9; 32];
+
+    fn kerberos_string(value: &str) -> KerberosStringAsn1 {
+        KerberosStringAsn1::from(IA5String::from_string(value.to_owned()).unwrap())
+    }
+
+    fn principal_name(name_type: u8, names: &[&str]) -> PrincipalName {
+        PrincipalName {
+            name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![name_type])),
+            name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(
+                names.iter().map(|name| kerberos_string(name)).collect::<Vec<_>>(),
+            )),
+        }
+    }
+
+    fn encryption_key(key: &[u8]) -> EncryptionKey {
+        EncryptionKey {
+            key_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![CipherSuite::Aes256CtsHmacSha196.into()])),
+            key_value: ExplicitContextTag1::from(OctetStringAsn1::from(key.to_vec())),
+        }
+    }
+
+    fn service_key() -> ServiceKey {
+        ServiceKey::from_password(
+            CipherSuite::Aes256CtsHmacSha196,
+            SERVICE_PASSWORD,
+            SERVICE_SALT,
+            Some(2),
+        )
+        .unwrap()
+    }
+
+    fn service_ticket(service_key: &ServiceKey) -> Ticket {
+        service_ticket_with_authorization_data(service_key, OffsetDateTime::now_utc(), None)
+    }
+
+    fn pac_client(name: &str, auth_time: OffsetDateTime) -> PacClientInfo {
+        PacClientInfo {
+            client_id: Some(auth_time),
+            name: name.to_owned(),
+        }
+    }
+
+    /// Wraps the PAC into the AD-IF-RELEVANT element as the Active Directory KDC does.
+    fn pac_authorization_data(pac: Vec<u8>) -> AuthorizationData {
+        let ad_win2k_pac = AuthorizationData::from(vec![AuthorizationDataInner {
+            ad_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![0x00, 0x80])),
+            ad_data: ExplicitContextTag1::from(OctetStringAsn1::from(pac)),
+        }]);
+
+        AuthorizationData::from(vec![AuthorizationDataInner {
+            ad_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![0x01])),
+            ad_data: ExplicitContextTag1::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&ad_win2k_pac).unwrap())),
+        }])
+    }
+
+    fn service_ticket_with_authorization_data(
+        service_key: &ServiceKey,
+        auth_time: OffsetDateTime,
+        authorization_data: Option<AuthorizationData>,
+    ) -> Ticket {
+        let now = OffsetDateTime::now_utc();
+
+        let enc_ticket_part = EncTicketPart::from(EncTicketPartInner {
+            flags: ExplicitContextTag0::from(BitStringAsn1::from(BitString::with_bytes(vec![0x40, 0x81, 0x00, 0x00]))),
+            key: ExplicitContextTag1::from(encryption_key(&SESSION_KEY)),
+            crealm: ExplicitContextTag2::from(kerberos_string(REALM)),
+            cname: ExplicitContextTag3::from(principal_name(1, &["user"])),
+            transited: ExplicitContextTag4::from(TransitedEncoding {
+                tr_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![1])),
+                contents: ExplicitContextTag1::from(OctetStringAsn1::from(Vec::new())),
+            }),
+            auth_time: ExplicitContextTag5::from(KerberosTime::from(GeneralizedTime::from(auth_time))),
+            start_time: Optional::from(None),
+            end_time: ExplicitContextTag7::from(KerberosTime::from(GeneralizedTime::from(now + Duration::hours(10)))),
+            renew_till: Optional::from(None),
+            caddr: Optional::from(None),
+            authorization_data: Optional::from(authorization_data.map(ExplicitContextTag10::from)),
+        });
+
+        let cipher = service_key
+            .encryption_type
+            .cipher()
+            .encrypt(
+                service_key.key.as_ref(),
+                TICKET_REP,
+                &picky_asn1_der::to_vec(&enc_ticket_part).unwrap(),
+            )
+            .unwrap();
+
+        Ticket::from(TicketInner {
+            tkt_vno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            realm: ExplicitContextTag1::from(kerberos_string(REALM)),
+            sname: ExplicitContextTag2::from(principal_name(NT_SRV_INST, &["HTTP", "www.example.com"])),
+            enc_part: ExplicitContextTag3::from(EncryptedData {
+                etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![(&service_key.encryption_type).into()])),
+                kvno: Optional::from(Some(ExplicitContextTag1::from(IntegerAsn1::from(vec![2])))),
+                cipher: ExplicitContextTag2::from(OctetStringAsn1::from(cipher)),
+            }),
+        })
+    }
+
+    fn authenticator(ctime: OffsetDateTime, channel_bindings: Option<&ChannelBindings>) -> Authenticator {
+        let mut checksum_value = ChecksumValues::default();
+        checksum_value.set_flags(GssFlags::GSS_C_MUTUAL_FLAG | GssFlags::GSS_C_INTEG_FLAG | GssFlags::GSS_C_CONF_FLAG);
+        let mut checksum_value = checksum_value.into_inner();
+        if let Some(channel_bindings) = channel_bindings {
+            checksum_value[4..20].copy_from_slice(&compute_md5_channel_bindings_hash(channel_bindings));
+        }
+
+        Authenticator::from(AuthenticatorInner {
+            authenticator_bno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+            crealm: ExplicitContextTag1::from(kerberos_string(REALM)),
+            cname: ExplicitContextTag2::from(principal_name(1, &["user"])),
+            cksum: Optional::from(Some(ExplicitContextTag3::from(Checksum {
+                cksumtype: ExplicitContextTag0::from(IntegerAsn1::from(AUTHENTICATOR_CHECKSUM_TYPE.to_vec())),
+                checksum: ExplicitContextTag1::from(OctetStringAsn1::from(checksum_value)),
+            }))),
+            cusec: ExplicitContextTag4::from(IntegerAsn1::from(vec![0])),
+            ctime: ExplicitContextTag5::from(KerberosTime::from(GeneralizedTime::from(ctime))),
+            subkey: Optional::from(Some(ExplicitContextTag6::from(encryption_key(&INITIATOR_SUB_KEY)))),
+            seq_number: Optional::from(Some(ExplicitContextTag7::from(IntegerAsn1::from(vec![1])))),
+            authorization_data: Optional::from(None),
+        })
+    }
+
+    fn neg_ap_req(ticket: Ticket, authenticator: &Authenticator) -> Vec<u8> {
+        let ap_req = generate_ap_req(
+            ticket,
+            &SESSION_KEY,
+            authenticator,
+            &EncryptionParams::default_for_client(),
+            ApOptions::MUTUAL_REQUIRED,
+        )
+        .unwrap();
+
+        picky_asn1_der::to_vec(&generate_neg_ap_req(ap_req, oids::krb5()).unwrap()).unwrap()
+    }
+
+    fn server() -> Kerberos {
+        let mut server_properties = ServerProperties::new(vec![service_key()]);
+        server_properties.service_name = Some("HTTP/www.example.com".to_owned());
+
+        Kerberos::new_server_from_config(KerberosConfig {
+            server_properties: Some(server_properties),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn accept(server: &mut Kerberos, input: &mut [OwnedSecurityBuffer]) -> crate::Result<(SecurityStatus, Vec<u8>)> {
+        let mut output = [OwnedSecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
+
+        let result = server
+            .accept_security_context()
+            .with_credentials_handle(&mut None)
+            .with_context_requirements(ServerRequestFlags::empty())
+            .with_target_data_representation(DataRepresentation::Native)
+            .with_input(input)
+            .with_output(&mut output)
+            .execute(server)?;
+
+        let [output] = output;
+
+        Ok((result.status, output.buffer))
+    }
 
     #[test]
     fn stream_buffer_decryption() {
@@ -1084,4 +2595,714 @@ mod tests {
 
         assert_eq!(message[1].data(), plain_message);
     }
+
+    #[test]
+    fn make_and_verify_signature() {
+        let mut kerberos_server = super::test_data::fake_server();
+        let mut kerberos_client = super::test_data::fake_client();
+
+        let mut header = b"header".to_vec();
+        let mut body = b"signed but not encrypted".to_vec();
+        let mut token = [0; 1024];
+        let mut message = [
+            SecurityBuffer::Data(header.as_mut_slice()),
+            SecurityBuffer::Data(body.as_mut_slice()),
+            SecurityBuffer::Token(token.as_mut_slice()),
+        ];
+
+        kerberos_client.make_signature(0, &mut message, 0).unwrap();
+
+        assert_eq!(message[0].data(), b"header");
+        assert_eq!(message[1].data(), b"signed but not encrypted");
+
+        kerberos_server.verify_signature(&message, 0).unwrap();
+
+        // the initiator must not accept its own tokens
+        assert_eq!(
+            kerberos_client.verify_signature(&message, 0).unwrap_err().error_type,
+            ErrorKind::InvalidToken
+        );
+
+        let mut token = message[2].data().to_vec();
+        let mut altered_body = b"signed but not encrypted!".to_vec();
+        let message = [
+            SecurityBuffer::Data(header.as_mut_slice()),
+            SecurityBuffer::Data(altered_body.as_mut_slice()),
+            SecurityBuffer::Token(token.as_mut_slice()),
+        ];
+
+        assert_eq!(
+            kerberos_server.verify_signature(&message, 0).unwrap_err().error_type,
+            ErrorKind::MessageAltered
+        );
+    }
+
+    #[test]
+    fn rc4_hmac_encrypt_and_sign() {
+        let mut kerberos_server = super::test_data::fake_server();
+        let mut kerberos_client = super::test_data::fake_client();
+        for kerberos in [&mut kerberos_server, &mut kerberos_client] {
+            kerberos.encryption_params.encryption_type = Some(CipherSuite::Rc4Hmac);
+            kerberos.encryption_params.sub_session_key = Some(INITIATOR_SUB_KEY[..16].to_vec());
+        }
+
+        let sizes = kerberos_client.query_context_sizes().unwrap();
+        assert_eq!(sizes.max_signature, 37);
+        assert_eq!(sizes.security_trailer, 49);
+        assert_eq!(sizes.block, 1);
+
+        let plain_message = b"some plain message";
+
+        let mut token = [0; 1024];
+        let mut data = plain_message.to_vec();
+        let mut padding = [0; 1];
+        let mut message = [
+            SecurityBuffer::Token(token.as_mut_slice()),
+            SecurityBuffer::Data(data.as_mut_slice()),
+            SecurityBuffer::Padding(padding.as_mut_slice()),
+        ];
+
+        kerberos_client
+            .encrypt_message(EncryptionFlags::empty(), &mut message, 0)
+            .unwrap();
+        assert_ne!(message[1].data(), plain_message);
+        assert_eq!(message[2].data().len(), 1);
+
+        // the initiator must not accept its own tokens
+        let mut own_message = message[0].data().to_vec();
+        own_message.extend_from_slice(message[1].data());
+        own_message.extend_from_slice(message[2].data());
+        assert!(kerberos_client
+            .decrypt_message(
+                &mut [SecurityBuffer::Stream(&mut own_message), SecurityBuffer::Data(&mut [])],
+                0
+            )
+            .is_err());
+
+        kerberos_server.decrypt_message(&mut message, 0).unwrap();
+        assert_eq!(message[1].data(), plain_message);
+
+        let mut body = b"signed but not encrypted".to_vec();
+        let mut token = [0; 1024];
+        let mut message = [
+            SecurityBuffer::Data(body.as_mut_slice()),
+            SecurityBuffer::Token(token.as_mut_slice()),
+        ];
+
+        kerberos_server.make_signature(0, &mut message, 0).unwrap();
+        assert_eq!(message[1].data().len(), 37);
+
+        kerberos_client.verify_signature(&message, 0).unwrap();
+        assert_eq!(
+            kerberos_server.verify_signature(&message, 0).unwrap_err().error_type,
+            ErrorKind::InvalidToken
+        );
+    }
+
+    #[test]
+    fn decrypt_message_accepts_reordered_tokens_and_rejects_replays() {
+        let mut kerberos_server = super::test_data::fake_server();
+        let mut kerberos_client = super::test_data::fake_client();
+        kerberos_client.recv_sequence_window = SequenceWindow::new(true, true, None);
+
+        let mut encrypt = |plain_message: &[u8]| {
+            let mut token = [0; 1024];
+            let mut data = plain_message.to_vec();
+            let mut message = [
+                SecurityBuffer::Token(token.as_mut_slice()),
+                SecurityBuffer::Data(data.as_mut_slice()),
+            ];
+
+            kerberos_server
+                .encrypt_message(EncryptionFlags::empty(), &mut message, 0)
+                .unwrap();
+
+            let mut buffer = message[0].data().to_vec();
+            buffer.extend_from_slice(message[1].data());
+
+            buffer
+        };
+
+        let first = encrypt(b"first");
+        let second = encrypt(b"second");
+        let third = encrypt(b"third");
+
+        let mut decrypt = |mut buffer: Vec<u8>| {
+            let mut message = [SecurityBuffer::Stream(&mut buffer), SecurityBuffer::Data(&mut [])];
+
+            kerberos_client
+                .decrypt_message(&mut message, 0)
+                .map(|_| message[1].data().to_vec())
+        };
+
+        assert_eq!(decrypt(first.clone()).unwrap(), b"first");
+        // the reordered tokens are accepted and the communication continues
+        assert_eq!(decrypt(third.clone()).unwrap(), b"third");
+        assert_eq!(decrypt(second.clone()).unwrap(), b"second");
+        assert_eq!(decrypt(encrypt(b"fourth")).unwrap(), b"fourth");
+        // but the replayed ones are not
+        assert_eq!(decrypt(first).unwrap_err().error_type, ErrorKind::OutOfSequence);
+        assert_eq!(decrypt(second).unwrap_err().error_type, ErrorKind::OutOfSequence);
+        assert_eq!(decrypt(third).unwrap_err().error_type, ErrorKind::OutOfSequence);
+        assert_eq!(decrypt(encrypt(b"fifth")).unwrap(), b"fifth");
+    }
+
+    #[test]
+    fn accept_ap_req() {
+        let mut kerberos_server = server();
+        assert!(kerberos_server.query_context_client_identity().is_err());
+
+        let mut input = [OwnedSecurityBuffer::new(
+            neg_ap_req(
+                service_ticket(&service_key()),
+                &authenticator(OffsetDateTime::now_utc(), None),
+            ),
+            SecurityBufferType::Token,
+        )];
+        let (status, output) = accept(&mut kerberos_server, &mut input).unwrap();
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+
+        // client side: extract the acceptor sub-key and validate the acceptor's mechListMIC
+        let neg_token_targ: NegTokenTarg1 = picky_asn1_der::from_bytes(&output).unwrap();
+        let ap_rep = extract_ap_rep_from_neg_token_targ(&neg_token_targ).unwrap();
+        let mut client_enc_params = EncryptionParams::default_for_client();
+        client_enc_params.encryption_type = Some(CipherSuite::Aes256CtsHmacSha196);
+        client_enc_params.session_key = Some(SESSION_KEY.to_vec());
+        let ap_rep_enc_part = decrypt_ap_rep_enc_part(&ap_rep, &SESSION_KEY, &client_enc_params).unwrap();
+        let acceptor_sub_key = extract_sub_session_key_from_ap_rep(&ap_rep_enc_part).unwrap();
+        client_enc_params.sub_session_key = Some(acceptor_sub_key);
+        validate_mic_token(
+            &neg_token_targ.0.mech_list_mic.0.as_ref().unwrap().0 .0,
+            ACCEPTOR_SIGN,
+            &client_enc_params,
+        )
+        .unwrap();
+
+        let final_neg_token_targ = generate_final_neg_token_targ(Some(
+            generate_initiator_raw(picky_asn1_der::to_vec(&get_mech_list()).unwrap(), 1, &client_enc_params).unwrap(),
+        ));
+        let mut input = [OwnedSecurityBuffer::new(
+            picky_asn1_der::to_vec(&final_neg_token_targ).unwrap(),
+            SecurityBufferType::Token,
+        )];
+        let (status, _) = accept(&mut kerberos_server, &mut input).unwrap();
+        assert_eq!(status, SecurityStatus::Ok);
+
+        assert_eq!(
+            kerberos_server.query_context_names().unwrap().username.inner(),
+            "user@EXAMPLE.COM"
+        );
+
+        let client_identity = kerberos_server.query_context_client_identity().unwrap();
+        assert_eq!(client_identity.principal, "user");
+        assert_eq!(client_identity.realm, "EXAMPLE.COM");
+        assert!(matches!(client_identity.package, SecurityPackageType::Kerberos));
+        assert!(client_identity.mic_verified);
+        assert!(!client_identity.channel_bindings_verified);
+        assert!(kerberos_server.pac().is_none());
+
+        let mut kerberos_client = super::test_data::fake_client();
+        kerberos_client.encryption_params = client_enc_params;
+
+        let plain_message = b"some plain message";
+
+        let mut token = [0; 1024];
+        let mut data = plain_message.to_vec();
+        let mut message = [
+            SecurityBuffer::Token(token.as_mut_slice()),
+            SecurityBuffer::Data(data.as_mut_slice()),
+        ];
+
+        kerberos_client
+            .encrypt_message(EncryptionFlags::empty(), &mut message, 0)
+            .unwrap();
+        kerberos_server.decrypt_message(&mut message, 0).unwrap();
+
+        assert_eq!(message[1].data(), plain_message);
+    }
+
+    #[test]
+    fn accept_ap_req_with_pac() {
+        let mut kerberos_server = server();
+        if let Some(server_properties) = kerberos_server.config.server_properties.as_mut() {
+            server_properties.kdc_key = Some(pac_key("krbtgt"));
+        }
+
+        let auth_time = OffsetDateTime::now_utc();
+        let mut input = [OwnedSecurityBuffer::new(
+            neg_ap_req(
+                service_ticket_with_authorization_data(
+                    &service_key(),
+                    auth_time,
+                    Some(pac_authorization_data(encode_pac(
+                        &service_key(),
+                        &pac_key("krbtgt"),
+                        &pac_client("user", auth_time),
+                    ))),
+                ),
+                &authenticator(OffsetDateTime::now_utc(), None),
+            ),
+            SecurityBufferType::Token,
+        )];
+        let (status, _) = accept(&mut kerberos_server, &mut input).unwrap();
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+
+        let logon_info = kerberos_server.pac().unwrap().logon_info.as_ref().unwrap();
+        assert_eq!(logon_info.user_sid().to_string(), "S-1-5-21-1-2-3-1105");
+        assert_eq!(logon_info.logon_domain_name, "EXAMPLE");
+    }
+
+    #[test]
+    fn accept_ap_req_with_invalid_pac_signature() {
+        for (server_key, kdc_key) in [
+            (pac_key("another service"), pac_key("krbtgt")),
+            (service_key(), pac_key("another krbtgt")),
+        ] {
+            let mut kerberos_server = server();
+            if let Some(server_properties) = kerberos_server.config.server_properties.as_mut() {
+                server_properties.kdc_key = Some(pac_key("krbtgt"));
+            }
+
+            let auth_time = OffsetDateTime::now_utc();
+            let mut input = [OwnedSecurityBuffer::new(
+                neg_ap_req(
+                    service_ticket_with_authorization_data(
+                        &service_key(),
+                        auth_time,
+                        Some(pac_authorization_data(encode_pac(
+                            &server_key,
+                            &kdc_key,
+                            &pac_client("user", auth_time),
+                        ))),
+                    ),
+                    &authenticator(OffsetDateTime::now_utc(), None),
+                ),
+                SecurityBufferType::Token,
+            )];
+
+            let err = accept(&mut kerberos_server, &mut input).unwrap_err();
+            assert_eq!(err.error_type, ErrorKind::MessageAltered);
+            assert!(kerberos_server.pac().is_none());
+        }
+    }
+
+    #[test]
+    fn accept_ap_req_with_pac_of_another_client() {
+        let auth_time = OffsetDateTime::now_utc();
+
+        for client in [
+            pac_client("another user", auth_time),
+            pac_client("user", auth_time - Duration::hours(1)),
+        ] {
+            let mut kerberos_server = server();
+
+            let mut input = [OwnedSecurityBuffer::new(
+                neg_ap_req(
+                    service_ticket_with_authorization_data(
+                        &service_key(),
+                        auth_time,
+                        Some(pac_authorization_data(encode_pac(
+                            &service_key(),
+                            &pac_key("krbtgt"),
+                            &client,
+                        ))),
+                    ),
+                    &authenticator(OffsetDateTime::now_utc(), None),
+                ),
+                SecurityBufferType::Token,
+            )];
+
+            let err = accept(&mut kerberos_server, &mut input).unwrap_err();
+            assert_eq!(err.error_type, ErrorKind::InvalidToken);
+            assert!(kerberos_server.pac().is_none());
+        }
+    }
+
+    #[test]
+    fn accept_ap_req_without_service_key() {
+        let mut kerberos_server = server();
+
+        let wrong_key = ServiceKey::from_password(
+            CipherSuite::Aes256CtsHmacSha196,
+            SERVICE_PASSWORD,
+            SERVICE_SALT,
+            Some(3),
+        )
+        .unwrap();
+        let mut ticket = service_ticket(&wrong_key);
+        ticket.0.enc_part.0.kvno = Optional::from(Some(ExplicitContextTag1::from(IntegerAsn1::from(vec![3]))));
+
+        let mut input = [OwnedSecurityBuffer::new(
+            neg_ap_req(ticket, &authenticator(OffsetDateTime::now_utc(), None)),
+            SecurityBufferType::Token,
+        )];
+
+        let err = accept(&mut kerberos_server, &mut input).unwrap_err();
+        assert_eq!(err.error_type, ErrorKind::NoKerbKey);
+        assert!(matches!(kerberos_server.state, KerberosState::Negotiate));
+    }
+
+    #[test]
+    fn accept_ap_req_with_rc4_hmac_ticket() {
+        let rc4_key = ServiceKey::from_password(CipherSuite::Rc4Hmac, SERVICE_PASSWORD, "", Some(2)).unwrap();
+        let input = || {
+            [OwnedSecurityBuffer::new(
+                neg_ap_req(
+                    service_ticket(&rc4_key),
+                    &authenticator(OffsetDateTime::now_utc(), None),
+                ),
+                SecurityBufferType::Token,
+            )]
+        };
+
+        let mut server_properties = ServerProperties::new(vec![service_key(), rc4_key.clone()]);
+        server_properties.service_name = Some("HTTP/www.example.com".to_owned());
+
+        // RC4-HMAC is not allowed by default
+        let mut kerberos_server = Kerberos::new_server_from_config(KerberosConfig {
+            server_properties: Some(server_properties.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        let err = accept(&mut kerberos_server, &mut input()).unwrap_err();
+        assert_eq!(err.error_type, ErrorKind::NoKerbKey);
+
+        let mut kerberos_server = Kerberos::new_server_from_config(KerberosConfig {
+            server_properties: Some(server_properties),
+            allow_rc4_hmac: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let (status, _) = accept(&mut kerberos_server, &mut input()).unwrap();
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+    }
+
+    #[test]
+    fn accept_ap_req_with_time_skew() {
+        let mut kerberos_server = server();
+
+        let mut input = [OwnedSecurityBuffer::new(
+            neg_ap_req(
+                service_ticket(&service_key()),
+                &authenticator(OffsetDateTime::now_utc() - Duration::minutes(10), None),
+            ),
+            SecurityBufferType::Token,
+        )];
+
+        let err = accept(&mut kerberos_server, &mut input).unwrap_err();
+        assert_eq!(err.error_type, ErrorKind::TimeSkew);
+    }
+
+    #[test]
+    fn accept_replayed_ap_req() {
+        let mut server_properties = ServerProperties::new(vec![service_key()]);
+        server_properties.service_name = Some("HTTP/www.example.com".to_owned());
+        let server = || {
+            Kerberos::new_server_from_config(KerberosConfig {
+                server_properties: Some(server_properties.clone()),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        let now = OffsetDateTime::now_utc();
+        let ap_req = neg_ap_req(service_ticket(&service_key()), &authenticator(now, None));
+        let input = |ap_req: &[u8]| [OwnedSecurityBuffer::new(ap_req.to_vec(), SecurityBufferType::Token)];
+
+        let (status, _) = accept(&mut server(), &mut input(&ap_req)).unwrap();
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+
+        // the servers created with the same properties share the replay cache
+        let err = accept(&mut server(), &mut input(&ap_req)).unwrap_err();
+        assert_eq!(err.error_type, ErrorKind::InvalidToken);
+
+        let ap_req = neg_ap_req(
+            service_ticket(&service_key()),
+            &authenticator(now + Duration::seconds(1), None),
+        );
+        let (status, _) = accept(&mut server(), &mut input(&ap_req)).unwrap();
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+    }
+
+    #[test]
+    fn accept_ap_req_with_channel_bindings() {
+        fn channel_bindings_buffer(application_data: &[u8]) -> Vec<u8> {
+            // SEC_CHANNEL_BINDINGS with the application data only
+            let mut buffer = vec![0; 32];
+            buffer[24..28].copy_from_slice(&(application_data.len() as u32).to_le_bytes());
+            buffer[28..32].copy_from_slice(&32_u32.to_le_bytes());
+            buffer.extend_from_slice(application_data);
+
+            buffer
+        }
+
+        let channel_bindings = channel_bindings_buffer(b"tls-server-end-point:1234");
+        let other_channel_bindings = channel_bindings_buffer(b"tls-server-end-point:5678");
+
+        for (client_channel_bindings, expected) in [
+            (Some(&channel_bindings), Ok(SecurityStatus::ContinueNeeded)),
+            (Some(&other_channel_bindings), Err(ErrorKind::BadBindings)),
+            (None, Err(ErrorKind::BadBindings)),
+        ] {
+            let mut kerberos_server = server();
+
+            let client_channel_bindings =
+                client_channel_bindings.map(|buffer| ChannelBindings::from_bytes(buffer).unwrap());
+            let mut input = [
+                OwnedSecurityBuffer::new(
+                    neg_ap_req(
+                        service_ticket(&service_key()),
+                        &authenticator(OffsetDateTime::now_utc(), client_channel_bindings.as_ref()),
+                    ),
+                    SecurityBufferType::Token,
+                ),
+                OwnedSecurityBuffer::new(channel_bindings.clone(), SecurityBufferType::ChannelBindings),
+            ];
+
+            let result = accept(&mut kerberos_server, &mut input)
+                .map(|(status, _)| status)
+                .map_err(|err| err.error_type);
+            assert_eq!(result, expected);
+
+            if result.is_ok() {
+                assert!(
+                    kerberos_server
+                        .query_context_client_identity()
+                        .unwrap()
+                        .channel_bindings_verified
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn accept_ap_req_with_delegated_credentials() {
+        const TGT_SESSION_KEY: [u8; 32] = [5; 32];
+
+        let mut kerberos_server = server();
+
+        let now = OffsetDateTime::now_utc();
+        let forwarded_tgt = service_ticket(&service_key());
+        let enc_tgs_rep_part = EncKdcRepPart {
+            key: ExplicitContextTag0::from(encryption_key(&TGT_SESSION_KEY)),
+            last_req: ExplicitContextTag1::from(Asn1SequenceOf::from(Vec::new())),
+            nonce: ExplicitContextTag2::from(IntegerAsn1::from(vec![1])),
+            key_expiration: Optional::from(None),
+            flags: ExplicitContextTag4::from(BitStringAsn1::from(BitString::with_bytes(vec![0x60, 0x81, 0x00, 0x00]))),
+            auth_time: ExplicitContextTag5::from(KerberosTime::from(GeneralizedTime::from(now))),
+            start_time: Optional::from(None),
+            end_time: ExplicitContextTag7::from(KerberosTime::from(GeneralizedTime::from(now + Duration::hours(10)))),
+            renew_till: Optional::from(None),
+            srealm: ExplicitContextTag9::from(kerberos_string(REALM)),
+            sname: ExplicitContextTag10::from(principal_name(NT_SRV_INST, &["krbtgt", REALM])),
+            caadr: Optional::from(None),
+            encrypted_pa_data: Optional::from(None),
+        };
+        let krb_cred = generate_krb_cred(
+            forwarded_tgt.clone(),
+            &kerberos_string(REALM),
+            &principal_name(1, &["user"]),
+            &enc_tgs_rep_part,
+            &EncKey {
+                key_type: CipherSuite::Aes256CtsHmacSha196,
+                key_value: SESSION_KEY.to_vec(),
+            },
+        )
+        .unwrap();
+
+        let mut checksum_value = ChecksumValues::default();
+        checksum_value.set_flags(GssFlags::GSS_C_MUTUAL_FLAG | GssFlags::GSS_C_DELEG_FLAG);
+        checksum_value
+            .set_delegation(&picky_asn1_der::to_vec(&krb_cred).unwrap())
+            .unwrap();
+
+        let mut authenticator = authenticator(now, None);
+        authenticator.0.cksum = Optional::from(Some(ExplicitContextTag3::from(Checksum {
+            cksumtype: ExplicitContextTag0::from(IntegerAsn1::from(AUTHENTICATOR_CHECKSUM_TYPE.to_vec())),
+            checksum: ExplicitContextTag1::from(OctetStringAsn1::from(checksum_value.into_inner())),
+        })));
+
+        let mut input = [OwnedSecurityBuffer::new(
+            neg_ap_req(service_ticket(&service_key()), &authenticator),
+            SecurityBufferType::Token,
+        )];
+        let (status, _) = accept(&mut kerberos_server, &mut input).unwrap();
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+
+        let delegated_credentials = kerberos_server.delegated_credentials().unwrap();
+        assert_eq!(
+            delegated_credentials.default_principal.principal_name(),
+            "user@EXAMPLE.COM"
+        );
+
+        let [credential] = delegated_credentials.credentials.as_slice() else {
+            panic!("expected exactly one delegated credential");
+        };
+        assert_eq!(credential.server.principal_name(), "krbtgt/EXAMPLE.COM@EXAMPLE.COM");
+        assert_eq!(credential.key.as_ref(), &TGT_SESSION_KEY);
+        assert_eq!(credential.decode_ticket().unwrap(), forwarded_tgt);
+    }
+
+    /// Creates the client that has the service ticket in the cache, so it doesn't need the KDC.
+    fn client_with_cached_service_ticket(ticket: &Ticket) -> Kerberos {
+        let now = u32::try_from(OffsetDateTime::now_utc().unix_timestamp()).unwrap();
+        let ticket_cache = TicketCache::memory();
+        ticket_cache
+            .store(CCacheCredential {
+                client: CCachePrincipal {
+                    name_type: 1,
+                    realm: REALM.to_owned(),
+                    components: vec!["user".to_owned()],
+                },
+                server: CCachePrincipal {
+                    name_type: 2,
+                    realm: REALM.to_owned(),
+                    components: vec!["HTTP".to_owned(), "www.example.com".to_owned()],
+                },
+                key_type: 18,
+                key: SESSION_KEY.to_vec().into(),
+                auth_time: now,
+                start_time: now,
+                end_time: now + 3600,
+                renew_till: 0,
+                is_skey: false,
+                ticket_flags: 0x40810000,
+                addresses: Vec::new(),
+                auth_data: Vec::new(),
+                ticket: picky_asn1_der::to_vec(ticket).unwrap(),
+                second_ticket: Vec::new(),
+            })
+            .unwrap();
+
+        Kerberos::new_client_from_config(KerberosConfig {
+            ticket_cache: Some(ticket_cache),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn initialize(client: &mut Kerberos, input: Option<Vec<u8>>) -> (SecurityStatus, Vec<u8>) {
+        let mut credentials_handle = Some(CredentialsBuffers::AuthIdentity(
+            AuthIdentity {
+                username: Username::new("user", Some(REALM)).unwrap(),
+                password: String::new().into(),
+            }
+            .into(),
+        ));
+        let mut input = input
+            .map(|token| vec![OwnedSecurityBuffer::new(token, SecurityBufferType::Token)])
+            .unwrap_or_default();
+        let mut output = vec![OwnedSecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
+
+        let mut builder = client
+            .initialize_security_context()
+            .with_credentials_handle(&mut credentials_handle)
+            .with_context_requirements(ClientRequestFlags::MUTUAL_AUTH)
+            .with_target_data_representation(DataRepresentation::Native)
+            .with_target_name("HTTP/www.example.com")
+            .with_input(&mut input)
+            .with_output(&mut output);
+        let result = SspiImpl::initialize_security_context_impl(client, &mut builder)
+            .unwrap()
+            .resolve_to_result()
+            .unwrap();
+
+        (result.status, output.remove(0).buffer)
+    }
+
+    #[test]
+    fn initialize_security_context_uses_cached_service_ticket() {
+        // e.g. the ticket obtained by `Kerberos::s4u2proxy` on behalf of the user
+        let ticket = service_ticket(&service_key());
+        let mut client = client_with_cached_service_ticket(&ticket);
+
+        initialize(&mut client, None);
+        // no KDC is configured: the AP-REQ can be created only using the cached ticket
+        let (_, ap_req) = initialize(
+            &mut client,
+            Some(
+                picky_asn1_der::to_vec(&NegTokenTarg1::from(NegTokenTarg {
+                    neg_result: Optional::from(None),
+                    supported_mech: Optional::from(None),
+                    response_token: Optional::from(None),
+                    mech_list_mic: Optional::from(None),
+                }))
+                .unwrap(),
+            ),
+        );
+
+        let ticket = picky_asn1_der::to_vec(&ticket).unwrap();
+        assert!(ap_req.windows(ticket.len()).any(|window| window == ticket));
+    }
+
+    #[test]
+    fn client_authenticates_to_server() {
+        let mut client = client_with_cached_service_ticket(&service_ticket(&service_key()));
+        let mut server = server();
+        let token = |buffer| [OwnedSecurityBuffer::new(buffer, SecurityBufferType::Token)];
+
+        // the client starts with the user-to-user TGT-REQ, but the server uses its service key
+        let (status, tgt_req) = initialize(&mut client, None);
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+        let (status, neg_token_targ) = accept(&mut server, &mut token(tgt_req)).unwrap();
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+
+        let (status, ap_req) = initialize(&mut client, Some(neg_token_targ));
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+        let ap_options = extract_ap_options(&extract_ap_req(&ap_req).unwrap().ap_req);
+        assert!(!ap_options.contains(ApOptions::USE_SESSION_KEY));
+
+        let (status, ap_rep) = accept(&mut server, &mut token(ap_req)).unwrap();
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+        let (status, mech_list_mic) = initialize(&mut client, Some(ap_rep));
+        assert_eq!(status, SecurityStatus::Ok);
+        let (status, _) = accept(&mut server, &mut token(mech_list_mic)).unwrap();
+        assert_eq!(status, SecurityStatus::Ok);
+
+        assert!(server.query_context_client_identity().unwrap().mic_verified);
+
+        fn send_message(sender: &mut Kerberos, receiver: &mut Kerberos) {
+            let plain_message = b"some plain message";
+
+            let mut token = [0; 1024];
+            let mut data = plain_message.to_vec();
+            let mut message = [
+                SecurityBuffer::Token(token.as_mut_slice()),
+                SecurityBuffer::Data(data.as_mut_slice()),
+            ];
+
+            sender
+                .encrypt_message(EncryptionFlags::empty(), &mut message, 0)
+                .unwrap();
+            receiver.decrypt_message(&mut message, 0).unwrap();
+
+            assert_eq!(message[1].data(), plain_message);
+        }
+
+        send_message(&mut client, &mut server);
+        send_message(&mut server, &mut client);
+    }
+
+    #[test]
+    fn referral_realm_of_cross_realm_tgt() {
+        let ticket = |names: &[&str]| {
+            Ticket::from(TicketInner {
+                tkt_vno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+                realm: ExplicitContextTag1::from(kerberos_string(REALM)),
+                sname: ExplicitContextTag2::from(principal_name(NT_SRV_INST, names)),
+                enc_part: ExplicitContextTag3::from(EncryptedData {
+                    etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![CipherSuite::Aes256CtsHmacSha196.into()])),
+                    kvno: Optional::from(None),
+                    cipher: ExplicitContextTag2::from(OctetStringAsn1::from(vec![1, 2, 3, 4])),
+                }),
+            })
+        };
+
+        assert_eq!(
+            referral_realm(&ticket(&["krbtgt", "CHILD.EXAMPLE.COM"]), REALM).as_deref(),
+            Some("CHILD.EXAMPLE.COM")
+        );
+        assert_eq!(referral_realm(&ticket(&["krbtgt", "example.com"]), REALM), None);
+        assert_eq!(referral_realm(&ticket(&["HTTP", "www.example.com"]), REALM), None);
+    }
 }
@@ -1,20 +1,22 @@
 use picky_asn1_x509::signed_data::SignedData;
-use picky_krb::crypto::diffie_hellman::{generate_key, DhNonce};
-use picky_krb::crypto::CipherSuite;
 use picky_krb::data_types::PaData;
-use picky_krb::messages::AsRep;
+use picky_krb::messages::{AsRep, EncKdcRepPart, KrbError};
 use picky_krb::pkinit::PaPkAsRep;
 
-use super::client::extractors::extract_session_key_from_as_rep;
 use super::{
     generate_pa_datas_for_as_req as generate_password_based, EncryptionParams,
-    GenerateAsPaDataOptions as AuthIdentityPaDataOptions,
+    GenerateAsPaDataOptions as AuthIdentityPaDataOptions, DEFAULT_ENCRYPTION_TYPE,
 };
+use crate::kerberos::cipher::CipherSuite;
+use crate::kerberos::client::generators::EncKey;
+use crate::kerberos::kdc_certificate::{extract_kdc_public_key, KdcTrustStore};
 use crate::pk_init::{
-    extract_server_dh_public_key, generate_pa_datas_for_as_req as generate_private_key_based, DhParameters,
-    GenerateAsPaDataOptions as SmartCardPaDataOptions, Wrapper,
+    extract_server_dh_public_key, generate_anonymous_pa_datas_for_as_req as generate_anonymous,
+    generate_pa_datas_for_as_req as generate_private_key_based, negotiate_algorithms, DhParameters,
+    GenerateAnonymousAsPaDataOptions as AnonymousPaDataOptions, GenerateAsPaDataOptions as SmartCardPaDataOptions,
+    Wrapper,
 };
-use crate::pku2u::{extract_pa_pk_as_rep, extract_server_nonce, validate_server_p2p_certificate, validate_signed_data};
+use crate::pku2u::{extract_pa_pk_as_rep, extract_server_nonce, validate_signed_data};
 use crate::{check_if_empty, pku2u, Error, ErrorKind, Result};
 
 // PA-DATAs are very different for the Kerberos logon using username+password and smart card.
@@ -22,6 +24,7 @@ use crate::{check_if_empty, pku2u, Error, ErrorKind, Result};
 pub enum AsReqPaDataOptions<'a> {
     AuthIdentity(AuthIdentityPaDataOptions<'a>),
     SmartCard(Box<SmartCardPaDataOptions<'a>>),
+    AnonymousPkinit(Box<AnonymousPaDataOptions<'a>>),
 }
 
 impl AsReqPaDataOptions<'_> {
@@ -29,6 +32,7 @@ impl AsReqPaDataOptions<'_> {
         match self {
             AsReqPaDataOptions::AuthIdentity(options) => generate_password_based(options),
             AsReqPaDataOptions::SmartCard(options) => generate_private_key_based(options),
+            AsReqPaDataOptions::AnonymousPkinit(options) => generate_anonymous(options),
         }
     }
 
@@ -36,19 +40,59 @@ impl AsReqPaDataOptions<'_> {
         match self {
             AsReqPaDataOptions::AuthIdentity(options) => options.with_pre_auth = pre_auth,
             AsReqPaDataOptions::SmartCard(options) => options.with_pre_auth = pre_auth,
+            AsReqPaDataOptions::AnonymousPkinit(options) => options.with_pre_auth = pre_auth,
         }
     }
 
     pub fn with_salt(&mut self, salt: Vec<u8>) {
         match self {
             AsReqPaDataOptions::AuthIdentity(options) => options.salt = salt,
-            AsReqPaDataOptions::SmartCard(_) => {}
+            AsReqPaDataOptions::SmartCard(_) | AsReqPaDataOptions::AnonymousPkinit(_) => {}
+        }
+    }
+
+    pub fn with_encryption_type(&mut self, encryption_type: CipherSuite) {
+        match self {
+            AsReqPaDataOptions::AuthIdentity(options) => options.enc_params.encryption_type = Some(encryption_type),
+            AsReqPaDataOptions::SmartCard(_) | AsReqPaDataOptions::AnonymousPkinit(_) => {}
+        }
+    }
+
+    /// Sets the FAST armor key. The PKINIT pa-datas do not depend on it.
+    pub fn with_armor_key(&mut self, armor_key: EncKey) {
+        match self {
+            AsReqPaDataOptions::AuthIdentity(options) => options.armor_key = Some(armor_key),
+            AsReqPaDataOptions::SmartCard(_) | AsReqPaDataOptions::AnonymousPkinit(_) => {}
+        }
+    }
+
+    /// Switches to the PKINIT algorithms the KDC asked for in the KRB-ERROR.
+    ///
+    /// Returns `true` if the pa-datas are changed and the request should be sent again.
+    pub fn negotiate(&mut self, krb_error: &KrbError) -> Result<bool> {
+        match self {
+            AsReqPaDataOptions::AuthIdentity(_) => Ok(false),
+            AsReqPaDataOptions::SmartCard(options) => {
+                negotiate_algorithms(krb_error, &mut options.digest, &mut options.dh_parameters)
+            }
+            AsReqPaDataOptions::AnonymousPkinit(options) => {
+                negotiate_algorithms(krb_error, &mut options.digest, &mut options.dh_parameters)
+            }
+        }
+    }
+
+    /// Returns the key agreement parameters the PKINIT request was made with.
+    pub fn dh_parameters(&self) -> Option<&DhParameters> {
+        match self {
+            AsReqPaDataOptions::AuthIdentity(_) => None,
+            AsReqPaDataOptions::SmartCard(options) => Some(&options.dh_parameters),
+            AsReqPaDataOptions::AnonymousPkinit(options) => Some(&options.dh_parameters),
         }
     }
 }
 
 // ApRep session key extraction process is different for the Kerberos logon using username+password and smart card.
-// This enum provides a unified way to extract session key from the AsRep.
+// This enum provides a unified way to extract the encrypted part (and the session key) from the AsRep.
 #[derive(Debug)]
 pub enum AsRepSessionKeyExtractor<'a> {
     AuthIdentity {
@@ -59,21 +103,50 @@ pub enum AsRepSessionKeyExtractor<'a> {
     SmartCard {
         dh_parameters: &'a mut DhParameters,
         enc_params: &'a mut EncryptionParams,
+        /// Trust anchors of the KDC certificate.
+        kdc_trust_store: Option<&'a KdcTrustStore>,
+        /// Accept the reply without the KDC certificate validation if the trust anchors are not configured.
+        allow_unvalidated_kdc_certificate: bool,
+        /// Realm of the KDC the AS exchange is performed with.
+        realm: &'a str,
     },
 }
 
 impl AsRepSessionKeyExtractor<'_> {
     #[instrument(level = "trace", ret, skip(self))]
-    pub fn session_key(&mut self, as_rep: &AsRep) -> Result<Vec<u8>> {
+    pub fn enc_as_rep_part(&mut self, as_rep: &AsRep) -> Result<EncKdcRepPart> {
+        let key = self.reply_key(as_rep)?;
+
+        pku2u::extract_enc_as_rep_part(as_rep, &key, self.enc_params())
+    }
+
+    pub fn enc_params(&self) -> &EncryptionParams {
+        match self {
+            AsRepSessionKeyExtractor::AuthIdentity { enc_params, .. } => enc_params,
+            AsRepSessionKeyExtractor::SmartCard { enc_params, .. } => enc_params,
+        }
+    }
+
+    /// Returns the key the AS-REP encrypted part is encrypted with.
+    #[instrument(level = "trace", ret, skip(self))]
+    pub fn reply_key(&mut self, as_rep: &AsRep) -> Result<Vec<u8>> {
         match self {
             AsRepSessionKeyExtractor::AuthIdentity {
                 salt,
                 password,
                 enc_params,
-            } => extract_session_key_from_as_rep(as_rep, salt, password, enc_params),
+            } => Ok(enc_params
+                .encryption_type
+                .as_ref()
+                .unwrap_or(&DEFAULT_ENCRYPTION_TYPE)
+                .cipher()
+                .generate_key_from_password(password.as_bytes(), salt.as_bytes())?),
             AsRepSessionKeyExtractor::SmartCard {
                 dh_parameters,
                 enc_params,
+                kdc_trust_store,
+                allow_unvalidated_kdc_certificate,
+                realm,
             } => {
                 let dh_rep_info = match extract_pa_pk_as_rep(as_rep)? {
                     PaPkAsRep::DhInfo(dh) => dh.0,
@@ -92,33 +165,27 @@ impl AsRepSessionKeyExtractor<'_> {
                     picky_asn1_der::from_bytes(&dh_rep_info.dh_signed_data.0)?;
                 let signed_data = wrapped_signed_data.content.0;
 
-                let rsa_public_key = validate_server_p2p_certificate(&signed_data)?;
-                validate_signed_data(&signed_data, &rsa_public_key)?;
+                let public_key = extract_kdc_public_key(
+                    &signed_data,
+                    *kdc_trust_store,
+                    *allow_unvalidated_kdc_certificate,
+                    realm,
+                )?;
+                validate_signed_data(&signed_data, &public_key)?;
 
-                let public_key = extract_server_dh_public_key(&signed_data)?;
+                let public_key = extract_server_dh_public_key(&signed_data, dh_parameters.key_agreement)?;
                 dh_parameters.other_public_key = Some(public_key);
 
                 enc_params.encryption_type = Some(CipherSuite::try_from(as_rep.0.enc_part.0.etype.0 .0.as_slice())?);
 
-                let key = generate_key(
-                    check_if_empty!(dh_parameters.other_public_key.as_ref(), "dh public key is not set"),
-                    &dh_parameters.private_key,
-                    &dh_parameters.modulus,
-                    Some(DhNonce {
-                        client_nonce: check_if_empty!(dh_parameters.client_nonce.as_ref(), "dh client none is not set"),
-                        server_nonce: check_if_empty!(
-                            dh_parameters.server_nonce.as_ref(),
-                            "dh server nonce is not set"
-                        ),
-                    }),
-                    check_if_empty!(enc_params.encryption_type.as_ref(), "encryption type is not set")
-                        .cipher()
-                        .as_ref(),
-                )?;
-
-                let session_key = pku2u::extract_session_key_from_as_rep(as_rep, &key, enc_params)?;
-
-                Ok(session_key)
+                dh_parameters.generate_key(
+                    picky_krb::crypto::CipherSuite::try_from(check_if_empty!(
+                        enc_params.encryption_type.as_ref(),
+                        "encryption type is not set"
+                    ))?
+                    .cipher()
+                    .as_ref(),
+                )
             }
         }
     }
@@ -0,0 +1,1059 @@
+//! Privilege Attribute Certificate (PAC) decoding.
+//!
+//! Active Directory KDCs put the PAC into the authorization data of the service tickets. It contains
+//! the user's SID, group memberships, and other logon information needed to make authorization decisions.
+//!
+//! [MS-PAC](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf)
+
+use std::fmt;
+use std::io::{Cursor, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use picky_krb::crypto::ChecksumSuite;
+use time::{Duration, OffsetDateTime};
+
+use crate::kerberos::cipher::{hmac_md5_checksum, CipherSuite, HMAC_MD5};
+use crate::kerberos::server::ServiceKey;
+use crate::{Error, ErrorKind, Result};
+
+/// [MS-PAC 2.4](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf)
+/// PAC_INFO_BUFFER types.
+pub const LOGON_INFO: u32 = 1;
+pub const SERVER_CHECKSUM: u32 = 6;
+pub const PRIVSVR_CHECKSUM: u32 = 7;
+pub const CLIENT_INFO: u32 = 10;
+pub const UPN_DNS_INFO: u32 = 12;
+pub const TICKET_CHECKSUM: u32 = 16;
+pub const FULL_CHECKSUM: u32 = 19;
+
+/// [MS-PAC 2.8](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf):
+/// the key usage of the PAC signatures (KERB_NON_KERB_CKSUM_SALT).
+pub const PAC_SIGNATURE_KEY_USAGE: i32 = 17;
+
+/// [RFC 3962 7](https://www.rfc-editor.org/rfc/rfc3962#section-7): hmac-sha1-96-aes128 checksum type.
+const HMAC_SHA1_96_AES128: i32 = 15;
+/// [RFC 3962 7](https://www.rfc-editor.org/rfc/rfc3962#section-7): hmac-sha1-96-aes256 checksum type.
+const HMAC_SHA1_96_AES256: i32 = 16;
+
+/// The PACTYPE header size: `cBuffers` and `Version`.
+const PAC_HEADER_SIZE: usize = 8;
+/// The PAC_INFO_BUFFER size: `ulType`, `cbBufferSize`, and `Offset`.
+const PAC_INFO_BUFFER_SIZE: usize = 16;
+
+/// UPN_DNS_INFO flag: the structure is extended with the user's SAM name and SID.
+const UPN_DNS_INFO_EXTENDED: u32 = 0x02;
+
+/// Difference between the FILETIME epoch (1601-01-01) and the Unix epoch in 100-nanosecond intervals.
+const FILETIME_UNIX_EPOCH: i64 = 116_444_736_000_000_000;
+/// [MS-PAC 2.5](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf):
+/// the FILETIME value that means "never".
+const FILETIME_NEVER: u64 = 0x7fff_ffff_ffff_ffff;
+
+fn invalid_pac(message: impl fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidToken, format!("Invalid PAC: {}", message))
+}
+
+/// MS-DTYP 2.4.2 SID: security identifier.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Sid {
+    pub revision: u8,
+    pub identifier_authority: [u8; 6],
+    pub sub_authorities: Vec<u32>,
+}
+
+impl Sid {
+    /// Appends the relative identifier to the domain SID.
+    pub fn with_rid(&self, rid: u32) -> Self {
+        let mut sid = self.clone();
+        sid.sub_authorities.push(rid);
+
+        sid
+    }
+}
+
+impl fmt::Display for Sid {
+    /// Formats the SID as a string (e.g. `S-1-5-21-1004336348-1177238915-682003330-512`).
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let authority = self
+            .identifier_authority
+            .iter()
+            .fold(0_u64, |authority, byte| (authority << 8) | u64::from(*byte));
+
+        if authority >> 32 == 0 {
+            write!(f, "S-{}-{}", self.revision, authority)?;
+        } else {
+            write!(f, "S-{}-0x{:012X}", self.revision, authority)?;
+        }
+
+        for sub_authority in &self.sub_authorities {
+            write!(f, "-{}", sub_authority)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Group membership of the user: the group SID and the SE_GROUP attributes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupMembership {
+    pub sid: Sid,
+    pub attributes: u32,
+}
+
+/// [MS-PAC 2.5](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf)
+/// KERB_VALIDATION_INFO.
+///
+/// Relative group identifiers are resolved into full SIDs using the domain SIDs. Times set to "never" are `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacLogonInfo {
+    pub logon_time: Option<OffsetDateTime>,
+    pub logoff_time: Option<OffsetDateTime>,
+    pub kick_off_time: Option<OffsetDateTime>,
+    pub password_last_set: Option<OffsetDateTime>,
+    pub password_can_change: Option<OffsetDateTime>,
+    pub password_must_change: Option<OffsetDateTime>,
+    pub effective_name: String,
+    pub full_name: String,
+    pub logon_script: String,
+    pub profile_path: String,
+    pub home_directory: String,
+    pub home_directory_drive: String,
+    pub logon_count: u16,
+    pub bad_password_count: u16,
+    /// Relative identifier of the user in the logon domain.
+    pub user_id: u32,
+    /// Relative identifier of the user's primary group in the logon domain.
+    pub primary_group_id: u32,
+    pub user_flags: u32,
+    pub logon_server: String,
+    pub logon_domain_name: String,
+    pub logon_domain_id: Sid,
+    pub user_account_control: u32,
+    /// Groups of the logon domain the user is a member of.
+    pub groups: Vec<GroupMembership>,
+    /// SIDs of other domains and well-known groups.
+    pub extra_sids: Vec<GroupMembership>,
+    pub resource_group_domain_sid: Option<Sid>,
+    /// Domain local groups of the resource domain the user is a member of.
+    pub resource_groups: Vec<GroupMembership>,
+}
+
+impl PacLogonInfo {
+    pub fn user_sid(&self) -> Sid {
+        self.logon_domain_id.with_rid(self.user_id)
+    }
+
+    pub fn primary_group_sid(&self) -> Sid {
+        self.logon_domain_id.with_rid(self.primary_group_id)
+    }
+
+    /// Returns all group memberships of the user: the logon domain groups, the extra SIDs, and the resource groups.
+    pub fn group_memberships(&self) -> impl Iterator<Item = &GroupMembership> {
+        self.groups
+            .iter()
+            .chain(self.extra_sids.iter())
+            .chain(self.resource_groups.iter())
+    }
+}
+
+/// [MS-PAC 2.7](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf)
+/// PAC_CLIENT_INFO.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacClientInfo {
+    /// Kerberos initial ticket-granting ticket (TGT) authentication time.
+    pub client_id: Option<OffsetDateTime>,
+    pub name: String,
+}
+
+/// [MS-PAC 2.10](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf)
+/// UPN_DNS_INFO.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpnDnsInfo {
+    pub upn: String,
+    pub dns_domain_name: String,
+    pub flags: u32,
+    /// The user's SAM account name. Present only in the extended structure.
+    pub sam_name: Option<String>,
+    /// The user's SID. Present only in the extended structure.
+    pub sid: Option<Sid>,
+}
+
+/// [MS-PAC 2.8](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf)
+/// PAC_SIGNATURE_DATA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacSignature {
+    pub signature_type: i32,
+    pub signature: Vec<u8>,
+}
+
+impl PacSignature {
+    fn signature_size(signature_type: i32) -> Result<usize> {
+        match signature_type {
+            HMAC_SHA1_96_AES128 | HMAC_SHA1_96_AES256 => Ok(12),
+            HMAC_MD5 => Ok(16),
+            _ => Err(Error::new(
+                ErrorKind::UnsupportedFunction,
+                format!("Unsupported PAC signature type: {}", signature_type),
+            )),
+        }
+    }
+
+    fn compute(signature_type: i32, key: &ServiceKey, data: &[u8]) -> Result<Vec<u8>> {
+        match (signature_type, &key.encryption_type) {
+            (HMAC_SHA1_96_AES128, CipherSuite::Aes128CtsHmacSha196)
+            | (HMAC_SHA1_96_AES256, CipherSuite::Aes256CtsHmacSha196) => Ok(ChecksumSuite::try_from(
+                signature_type as usize,
+            )?
+            .hasher()
+            .checksum(key.key.as_ref(), PAC_SIGNATURE_KEY_USAGE, data)?),
+            (HMAC_MD5, CipherSuite::Rc4Hmac) => {
+                Ok(hmac_md5_checksum(key.key.as_ref(), PAC_SIGNATURE_KEY_USAGE, data)?.to_vec())
+            }
+            (signature_type, encryption_type) => Err(Error::new(
+                ErrorKind::NoKerbKey,
+                format!(
+                    "The {:?} key cannot verify the PAC signature of type {}",
+                    encryption_type, signature_type
+                ),
+            )),
+        }
+    }
+
+    /// Verifies the signature of the data.
+    pub fn verify(&self, key: &ServiceKey, data: &[u8]) -> Result<()> {
+        if Self::compute(self.signature_type, key, data)? != self.signature {
+            return Err(Error::new(ErrorKind::MessageAltered, "Invalid PAC signature"));
+        }
+
+        Ok(())
+    }
+}
+
+/// [MS-PAC 2.3](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf)
+/// Decoded PACTYPE structure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pac {
+    pub logon_info: Option<PacLogonInfo>,
+    pub client_info: Option<PacClientInfo>,
+    pub upn_dns_info: Option<UpnDnsInfo>,
+    /// The signature created by the KDC using the service key.
+    pub server_signature: PacSignature,
+    /// The signature created by the KDC using the `krbtgt` key.
+    pub kdc_signature: PacSignature,
+}
+
+impl Pac {
+    /// Verifies the PAC signatures and decodes the PAC.
+    ///
+    /// The server signature is verified using the service key that decrypted the ticket, and the KDC signature
+    /// is verified if the `krbtgt` key is specified. The PAC buffers are decoded only after the signatures
+    /// are verified, so the NDR decoder never processes the data that is not signed by the KDC.
+    pub fn decode(data: &[u8], service_key: &ServiceKey, kdc_key: Option<&ServiceKey>) -> Result<Self> {
+        let mut header = data;
+        let buffers_count = header.read_u32::<LittleEndian>().map_err(invalid_pac)?;
+        let version = header.read_u32::<LittleEndian>().map_err(invalid_pac)?;
+        if version != 0 {
+            return Err(invalid_pac(format!("unsupported version: {}", version)));
+        }
+
+        if buffers_count as usize * PAC_INFO_BUFFER_SIZE + PAC_HEADER_SIZE > data.len() {
+            return Err(invalid_pac("the buffers count is too big"));
+        }
+
+        let mut buffers = Vec::new();
+        let mut server_signature = None;
+        let mut kdc_signature = None;
+        // the encoded PAC with zeroed signatures: the data signed by the server signature
+        let mut signed_data = data.to_vec();
+
+        for _ in 0..buffers_count {
+            let buffer_type = header.read_u32::<LittleEndian>().map_err(invalid_pac)?;
+            let buffer_size = header.read_u32::<LittleEndian>().map_err(invalid_pac)?;
+            let offset = header.read_u64::<LittleEndian>().map_err(invalid_pac)?;
+
+            let start = usize::try_from(offset).map_err(invalid_pac)?;
+            let end = start
+                .checked_add(buffer_size as usize)
+                .filter(|end| start >= PAC_HEADER_SIZE && *end <= data.len())
+                .ok_or_else(|| invalid_pac(format!("buffer {} is out of bounds", buffer_type)))?;
+            let buffer = &data[start..end];
+
+            match buffer_type {
+                SERVER_CHECKSUM | PRIVSVR_CHECKSUM | TICKET_CHECKSUM | FULL_CHECKSUM => {
+                    let signature = read_signature(buffer)?;
+
+                    // all signatures are zeroed when the server signature is computed
+                    signed_data[start + 4..start + 4 + signature.signature.len()].fill(0);
+
+                    match buffer_type {
+                        SERVER_CHECKSUM => server_signature = Some(signature),
+                        PRIVSVR_CHECKSUM => kdc_signature = Some(signature),
+                        _ => {}
+                    }
+                }
+                _ => buffers.push((buffer_type, buffer)),
+            }
+        }
+
+        let server_signature = server_signature.ok_or_else(|| invalid_pac("the server signature is missing"))?;
+        let kdc_signature = kdc_signature.ok_or_else(|| invalid_pac("the KDC signature is missing"))?;
+
+        server_signature.verify(service_key, &signed_data)?;
+        // the KDC signature protects the server signature, so it is verified after the latter
+        if let Some(kdc_key) = kdc_key {
+            kdc_signature.verify(kdc_key, &server_signature.signature)?;
+        }
+
+        let mut logon_info = None;
+        let mut client_info = None;
+        let mut upn_dns_info = None;
+
+        for (buffer_type, buffer) in buffers {
+            match buffer_type {
+                LOGON_INFO => logon_info = Some(read_logon_info(buffer)?),
+                CLIENT_INFO => client_info = Some(read_client_info(buffer)?),
+                UPN_DNS_INFO => upn_dns_info = Some(read_upn_dns_info(buffer)?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            logon_info,
+            client_info,
+            upn_dns_info,
+            server_signature,
+            kdc_signature,
+        })
+    }
+}
+
+fn read_signature(mut buffer: &[u8]) -> Result<PacSignature> {
+    let signature_type = buffer.read_i32::<LittleEndian>().map_err(invalid_pac)?;
+
+    let mut signature = vec![0; PacSignature::signature_size(signature_type)?];
+    buffer.read_exact(&mut signature).map_err(invalid_pac)?;
+
+    Ok(PacSignature {
+        signature_type,
+        signature,
+    })
+}
+
+fn filetime_to_date(filetime: u64) -> Result<Option<OffsetDateTime>> {
+    if filetime == 0 || filetime == FILETIME_NEVER {
+        return Ok(None);
+    }
+
+    let filetime = i64::try_from(filetime).map_err(invalid_pac)?;
+
+    OffsetDateTime::UNIX_EPOCH
+        .checked_add(Duration::nanoseconds(filetime - FILETIME_UNIX_EPOCH) * 100)
+        .map(Some)
+        .ok_or_else(|| invalid_pac(format!("invalid time: {}", filetime)))
+}
+
+fn utf16_to_string(data: &[u8]) -> Result<String> {
+    if data.len() % 2 != 0 {
+        return Err(invalid_pac("invalid UTF-16 string length"));
+    }
+
+    let data = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<_>>();
+
+    String::from_utf16(&data).map_err(invalid_pac)
+}
+
+fn read_client_info(mut buffer: &[u8]) -> Result<PacClientInfo> {
+    let client_id = filetime_to_date(buffer.read_u64::<LittleEndian>().map_err(invalid_pac)?)?;
+    let name_length = buffer.read_u16::<LittleEndian>().map_err(invalid_pac)?;
+
+    let name = buffer
+        .get(..usize::from(name_length))
+        .ok_or_else(|| invalid_pac("the client name is out of bounds"))?;
+
+    Ok(PacClientInfo {
+        client_id,
+        name: utf16_to_string(name)?,
+    })
+}
+
+/// Reads the length and the offset of the UPN_DNS_INFO field and returns the field data.
+fn read_upn_dns_info_field<'a>(buffer: &'a [u8], reader: &mut &[u8]) -> Result<&'a [u8]> {
+    let length = reader.read_u16::<LittleEndian>().map_err(invalid_pac)?;
+    let offset = reader.read_u16::<LittleEndian>().map_err(invalid_pac)?;
+
+    buffer
+        .get(usize::from(offset)..usize::from(offset) + usize::from(length))
+        .ok_or_else(|| invalid_pac("the UPN_DNS_INFO field is out of bounds"))
+}
+
+fn read_upn_dns_info(buffer: &[u8]) -> Result<UpnDnsInfo> {
+    let mut reader = buffer;
+
+    let upn = utf16_to_string(read_upn_dns_info_field(buffer, &mut reader)?)?;
+    let dns_domain_name = utf16_to_string(read_upn_dns_info_field(buffer, &mut reader)?)?;
+    let flags = reader.read_u32::<LittleEndian>().map_err(invalid_pac)?;
+
+    let (sam_name, sid) = if flags & UPN_DNS_INFO_EXTENDED != 0 {
+        let sam_name = utf16_to_string(read_upn_dns_info_field(buffer, &mut reader)?)?;
+        let sid = read_sid(&mut read_upn_dns_info_field(buffer, &mut reader)?)?;
+
+        (Some(sam_name), Some(sid))
+    } else {
+        (None, None)
+    };
+
+    Ok(UpnDnsInfo {
+        upn,
+        dns_domain_name,
+        flags,
+        sam_name,
+        sid,
+    })
+}
+
+fn resolve_group_ids(group_ids: Vec<(u32, u32)>, domain_sid: Option<&Sid>) -> Result<Vec<GroupMembership>> {
+    if group_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let domain_sid = domain_sid.ok_or_else(|| invalid_pac("the groups domain SID is missing"))?;
+
+    Ok(group_ids
+        .into_iter()
+        .map(|(rid, attributes)| GroupMembership {
+            sid: domain_sid.with_rid(rid),
+            attributes,
+        })
+        .collect())
+}
+
+fn read_sid(reader: &mut impl Read) -> Result<Sid> {
+    let revision = reader.read_u8().map_err(invalid_pac)?;
+    let sub_authority_count = reader.read_u8().map_err(invalid_pac)?;
+
+    let mut identifier_authority = [0; 6];
+    reader.read_exact(&mut identifier_authority).map_err(invalid_pac)?;
+
+    let sub_authorities = (0..sub_authority_count)
+        .map(|_| reader.read_u32::<LittleEndian>().map_err(invalid_pac))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Sid {
+        revision,
+        identifier_authority,
+        sub_authorities,
+    })
+}
+
+/// RPC_UNICODE_STRING header. The string itself is a deferred conformant varying array.
+struct UnicodeStringHeader {
+    length: u16,
+    pointer: u32,
+}
+
+/// Reader of the NDR-encoded data (DCE 1.1 RPC, chapter 14)
+/// in the little-endian byte order.
+struct NdrReader<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> NdrReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            cursor: Cursor::new(data),
+        }
+    }
+
+    fn align(&mut self, alignment: u64) {
+        let position = self.cursor.position();
+        self.cursor.set_position(position.div_ceil(alignment) * alignment);
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        self.cursor.read_u8().map_err(invalid_pac)
+    }
+
+    fn read_u16(&mut self) -> Result<u16> {
+        self.align(2);
+        self.cursor.read_u16::<LittleEndian>().map_err(invalid_pac)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        self.align(4);
+        self.cursor.read_u32::<LittleEndian>().map_err(invalid_pac)
+    }
+
+    fn read_filetime(&mut self) -> Result<Option<OffsetDateTime>> {
+        let low = self.read_u32()?;
+        let high = self.read_u32()?;
+
+        filetime_to_date((u64::from(high) << 32) | u64::from(low))
+    }
+
+    fn read_unicode_string_header(&mut self) -> Result<UnicodeStringHeader> {
+        let length = self.read_u16()?;
+        let _maximum_length = self.read_u16()?;
+        let pointer = self.read_u32()?;
+
+        Ok(UnicodeStringHeader { length, pointer })
+    }
+
+    fn read_unicode_string(&mut self, header: &UnicodeStringHeader) -> Result<String> {
+        if header.pointer == 0 {
+            return Ok(String::new());
+        }
+
+        let _max_count = self.read_u32()?;
+        let _offset = self.read_u32()?;
+        let actual_count = self.read_u32()?;
+
+        // the count is not trusted: the string must fit into the remaining data
+        let remaining = (self.cursor.get_ref().len() as u64).saturating_sub(self.cursor.position());
+        let size = u64::from(actual_count) * 2;
+        if size > remaining {
+            return Err(invalid_pac("the string is out of bounds"));
+        }
+
+        if u64::from(header.length) > size {
+            return Err(invalid_pac("the string length is bigger than the string buffer"));
+        }
+
+        let mut data = vec![0; usize::try_from(size).map_err(invalid_pac)?];
+        self.cursor.read_exact(&mut data).map_err(invalid_pac)?;
+        data.truncate(usize::from(header.length));
+
+        utf16_to_string(&data)
+    }
+
+    fn read_sid(&mut self) -> Result<Sid> {
+        let _max_count = self.read_u32()?;
+
+        read_sid(&mut self.cursor)
+    }
+
+    fn read_sid_pointer(&mut self, pointer: u32) -> Result<Option<Sid>> {
+        if pointer == 0 {
+            return Ok(None);
+        }
+
+        self.read_sid().map(Some)
+    }
+
+    /// Reads the GROUP_MEMBERSHIP array: relative identifiers and attributes of the groups.
+    fn read_group_ids(&mut self, pointer: u32, count: u32) -> Result<Vec<(u32, u32)>> {
+        if pointer == 0 {
+            return Ok(Vec::new());
+        }
+
+        let max_count = self.read_u32()?;
+        if max_count != count {
+            return Err(invalid_pac("the groups count mismatch"));
+        }
+
+        (0..count).map(|_| Ok((self.read_u32()?, self.read_u32()?))).collect()
+    }
+
+    fn read_extra_sids(&mut self, pointer: u32, count: u32) -> Result<Vec<GroupMembership>> {
+        if pointer == 0 {
+            return Ok(Vec::new());
+        }
+
+        let max_count = self.read_u32()?;
+        if max_count != count {
+            return Err(invalid_pac("the extra SIDs count mismatch"));
+        }
+
+        let sid_pointers = (0..count)
+            .map(|_| Ok((self.read_u32()?, self.read_u32()?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        sid_pointers
+            .into_iter()
+            .filter(|(sid_pointer, _)| *sid_pointer != 0)
+            .map(|(_, attributes)| {
+                Ok(GroupMembership {
+                    sid: self.read_sid()?,
+                    attributes,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Reads the KERB_VALIDATION_INFO encoded using the type serialization version 1 (MS-RPCE 2.2.6).
+fn read_logon_info(buffer: &[u8]) -> Result<PacLogonInfo> {
+    let mut reader = NdrReader::new(buffer);
+
+    // common type header
+    let version = reader.read_u8()?;
+    let endianness = reader.read_u8()?;
+    if version != 1 || endianness != 0x10 {
+        return Err(invalid_pac("unsupported KERB_VALIDATION_INFO serialization"));
+    }
+    let _header_length = reader.read_u16()?;
+    let _filler = reader.read_u32()?;
+    // private type header
+    let _object_buffer_length = reader.read_u32()?;
+    let _filler = reader.read_u32()?;
+
+    let referent = reader.read_u32()?;
+    if referent == 0 {
+        return Err(invalid_pac("KERB_VALIDATION_INFO is null"));
+    }
+
+    let logon_time = reader.read_filetime()?;
+    let logoff_time = reader.read_filetime()?;
+    let kick_off_time = reader.read_filetime()?;
+    let password_last_set = reader.read_filetime()?;
+    let password_can_change = reader.read_filetime()?;
+    let password_must_change = reader.read_filetime()?;
+
+    let effective_name = reader.read_unicode_string_header()?;
+    let full_name = reader.read_unicode_string_header()?;
+    let logon_script = reader.read_unicode_string_header()?;
+    let profile_path = reader.read_unicode_string_header()?;
+    let home_directory = reader.read_unicode_string_header()?;
+    let home_directory_drive = reader.read_unicode_string_header()?;
+
+    let logon_count = reader.read_u16()?;
+    let bad_password_count = reader.read_u16()?;
+    let user_id = reader.read_u32()?;
+    let primary_group_id = reader.read_u32()?;
+    let group_count = reader.read_u32()?;
+    let group_ids = reader.read_u32()?;
+    let user_flags = reader.read_u32()?;
+    let mut _user_session_key = [0; 16];
+    reader.cursor.read_exact(&mut _user_session_key).map_err(invalid_pac)?;
+
+    let logon_server = reader.read_unicode_string_header()?;
+    let logon_domain_name = reader.read_unicode_string_header()?;
+    let logon_domain_id = reader.read_u32()?;
+
+    let _reserved1 = (reader.read_u32()?, reader.read_u32()?);
+    let user_account_control = reader.read_u32()?;
+    let _sub_auth_status = reader.read_u32()?;
+    let _last_successful_i_logon = reader.read_filetime()?;
+    let _last_failed_i_logon = reader.read_filetime()?;
+    let _failed_i_logon_count = reader.read_u32()?;
+    let _reserved3 = reader.read_u32()?;
+    let sid_count = reader.read_u32()?;
+    let extra_sids = reader.read_u32()?;
+    let resource_group_domain_sid = reader.read_u32()?;
+    let resource_group_count = reader.read_u32()?;
+    let resource_group_ids = reader.read_u32()?;
+
+    // deferred pointers data in the order of the pointers
+    let effective_name = reader.read_unicode_string(&effective_name)?;
+    let full_name = reader.read_unicode_string(&full_name)?;
+    let logon_script = reader.read_unicode_string(&logon_script)?;
+    let profile_path = reader.read_unicode_string(&profile_path)?;
+    let home_directory = reader.read_unicode_string(&home_directory)?;
+    let home_directory_drive = reader.read_unicode_string(&home_directory_drive)?;
+
+    let group_ids = reader.read_group_ids(group_ids, group_count)?;
+    let logon_server = reader.read_unicode_string(&logon_server)?;
+    let logon_domain_name = reader.read_unicode_string(&logon_domain_name)?;
+    let logon_domain_id = reader
+        .read_sid_pointer(logon_domain_id)?
+        .ok_or_else(|| invalid_pac("the logon domain SID is missing"))?;
+
+    let extra_sids = reader.read_extra_sids(extra_sids, sid_count)?;
+    let resource_group_domain_sid = reader.read_sid_pointer(resource_group_domain_sid)?;
+    let resource_group_ids = reader.read_group_ids(resource_group_ids, resource_group_count)?;
+
+    let groups = resolve_group_ids(group_ids, Some(&logon_domain_id))?;
+    let resource_groups = resolve_group_ids(resource_group_ids, resource_group_domain_sid.as_ref())?;
+
+    Ok(PacLogonInfo {
+        logon_time,
+        logoff_time,
+        kick_off_time,
+        password_last_set,
+        password_can_change,
+        password_must_change,
+        effective_name,
+        full_name,
+        logon_script,
+        profile_path,
+        home_directory,
+        home_directory_drive,
+        logon_count,
+        bad_password_count,
+        user_id,
+        primary_group_id,
+        user_flags,
+        logon_server,
+        logon_domain_name,
+        logon_domain_id,
+        user_account_control,
+        groups,
+        extra_sids,
+        resource_group_domain_sid,
+        resource_groups,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const LOGON_TIME: u64 = 133_000_000_000_000_000;
+
+    fn sid(authority: u8, sub_authorities: &[u32]) -> Sid {
+        Sid {
+            revision: 1,
+            identifier_authority: [0, 0, 0, 0, 0, authority],
+            sub_authorities: sub_authorities.to_vec(),
+        }
+    }
+
+    fn utf16(value: &str) -> Vec<u8> {
+        value.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    fn sid_bytes(sid: &Sid) -> Vec<u8> {
+        let mut data = vec![sid.revision, sid.sub_authorities.len() as u8];
+        data.extend_from_slice(&sid.identifier_authority);
+        for sub_authority in &sid.sub_authorities {
+            data.extend_from_slice(&sub_authority.to_le_bytes());
+        }
+
+        data
+    }
+
+    #[derive(Default)]
+    struct NdrWriter {
+        data: Vec<u8>,
+    }
+
+    impl NdrWriter {
+        fn align(&mut self, alignment: usize) {
+            while self.data.len() % alignment != 0 {
+                self.data.push(0);
+            }
+        }
+
+        fn u16(&mut self, value: u16) {
+            self.align(2);
+            self.data.extend_from_slice(&value.to_le_bytes());
+        }
+
+        fn u32(&mut self, value: u32) {
+            self.align(4);
+            self.data.extend_from_slice(&value.to_le_bytes());
+        }
+
+        fn filetime(&mut self, value: u64) {
+            self.u32(value as u32);
+            self.u32((value >> 32) as u32);
+        }
+
+        fn string_header(&mut self, value: &str, pointer: u32) {
+            let length = utf16(value).len() as u16;
+            self.u16(length);
+            self.u16(length);
+            self.u32(if value.is_empty() { 0 } else { pointer });
+        }
+
+        fn string(&mut self, value: &str) {
+            if value.is_empty() {
+                return;
+            }
+
+            let count = value.encode_utf16().count() as u32;
+            self.u32(count);
+            self.u32(0);
+            self.u32(count);
+            self.data.extend_from_slice(&utf16(value));
+        }
+
+        fn sid(&mut self, sid: &Sid) {
+            self.u32(sid.sub_authorities.len() as u32);
+            self.data.extend_from_slice(&sid_bytes(sid));
+        }
+    }
+
+    fn logon_info() -> Vec<u8> {
+        let strings = ["user", "Test User", "", "", "", ""];
+
+        let mut ndr = NdrWriter::default();
+        ndr.u32(0x0002_0000);
+        for time in [
+            LOGON_TIME,
+            FILETIME_NEVER,
+            FILETIME_NEVER,
+            LOGON_TIME,
+            0,
+            FILETIME_NEVER,
+        ] {
+            ndr.filetime(time);
+        }
+        for (i, value) in strings.iter().enumerate() {
+            ndr.string_header(value, 0x0002_0004 + i as u32 * 4);
+        }
+        ndr.u16(5); // logon count
+        ndr.u16(0); // bad password count
+        ndr.u32(1105); // user id
+        ndr.u32(513); // primary group id
+        ndr.u32(2); // group count
+        ndr.u32(0x0002_001c); // group ids
+        ndr.u32(0x20 | 0x200); // user flags
+        ndr.data.extend_from_slice(&[0; 16]);
+        ndr.string_header("DC01", 0x0002_0020);
+        ndr.string_header("EXAMPLE", 0x0002_0024);
+        ndr.u32(0x0002_0028); // logon domain id
+        ndr.u32(0);
+        ndr.u32(0);
+        ndr.u32(0x10); // user account control
+        ndr.u32(0);
+        ndr.filetime(0);
+        ndr.filetime(0);
+        ndr.u32(0);
+        ndr.u32(0);
+        ndr.u32(1); // sid count
+        ndr.u32(0x0002_002c); // extra sids
+        ndr.u32(0x0002_0030); // resource group domain sid
+        ndr.u32(1); // resource group count
+        ndr.u32(0x0002_0034); // resource group ids
+
+        for value in strings {
+            ndr.string(value);
+        }
+        ndr.u32(2);
+        for rid in [513, 1108] {
+            ndr.u32(rid);
+            ndr.u32(7);
+        }
+        ndr.string("DC01");
+        ndr.string("EXAMPLE");
+        ndr.sid(&sid(5, &[21, 1, 2, 3]));
+        ndr.u32(1);
+        ndr.u32(0x0002_0038);
+        ndr.u32(7);
+        ndr.sid(&sid(18, &[1]));
+        ndr.sid(&sid(5, &[21, 4, 5, 6]));
+        ndr.u32(1);
+        ndr.u32(1200);
+        ndr.u32(0x2000_0007);
+        ndr.align(8);
+
+        let mut data = vec![0x01, 0x10, 0x08, 0x00, 0xcc, 0xcc, 0xcc, 0xcc];
+        data.extend_from_slice(&(ndr.data.len() as u32).to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&ndr.data);
+
+        data
+    }
+
+    fn client_info(client_info: &PacClientInfo) -> Vec<u8> {
+        let client_id = client_info.client_id.map_or(0, |client_id| {
+            (client_id.unix_timestamp_nanos() / 100) as u64 + FILETIME_UNIX_EPOCH as u64
+        });
+
+        let mut data = client_id.to_le_bytes().to_vec();
+        data.extend_from_slice(&(utf16(&client_info.name).len() as u16).to_le_bytes());
+        data.extend_from_slice(&utf16(&client_info.name));
+
+        data
+    }
+
+    fn upn_dns_info() -> Vec<u8> {
+        let fields = [
+            utf16("user@example.com"),
+            utf16("EXAMPLE.COM"),
+            utf16("user"),
+            sid_bytes(&sid(5, &[21, 1, 2, 3, 1105])),
+        ];
+
+        let mut header = Vec::new();
+        let mut data = Vec::new();
+        let mut offset = 24;
+        for (i, field) in fields.iter().enumerate() {
+            header.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            header.extend_from_slice(&(offset as u16).to_le_bytes());
+            if i == 1 {
+                header.extend_from_slice(&UPN_DNS_INFO_EXTENDED.to_le_bytes());
+            }
+            data.extend_from_slice(field);
+            offset += field.len();
+        }
+        header.resize(24, 0);
+        header.extend_from_slice(&data);
+
+        header
+    }
+
+    /// Encodes the PAC signed by the service and `krbtgt` keys. The keys must be AES256 ones.
+    pub(crate) fn encode_pac(service_key: &ServiceKey, kdc_key: &ServiceKey, client: &PacClientInfo) -> Vec<u8> {
+        let mut signature = HMAC_SHA1_96_AES256.to_le_bytes().to_vec();
+        signature.extend_from_slice(&[0; 12]);
+
+        let buffers = [
+            (LOGON_INFO, logon_info()),
+            (CLIENT_INFO, client_info(client)),
+            (UPN_DNS_INFO, upn_dns_info()),
+            (SERVER_CHECKSUM, signature.clone()),
+            (PRIVSVR_CHECKSUM, signature),
+        ];
+
+        let mut pac = Vec::new();
+        pac.extend_from_slice(&(buffers.len() as u32).to_le_bytes());
+        pac.extend_from_slice(&0_u32.to_le_bytes());
+
+        let mut offset = PAC_HEADER_SIZE + buffers.len() * PAC_INFO_BUFFER_SIZE;
+        let mut offsets = Vec::new();
+        for (buffer_type, buffer) in &buffers {
+            pac.extend_from_slice(&buffer_type.to_le_bytes());
+            pac.extend_from_slice(&(buffer.len() as u32).to_le_bytes());
+            pac.extend_from_slice(&(offset as u64).to_le_bytes());
+            offsets.push(offset);
+            offset += buffer.len().div_ceil(8) * 8;
+        }
+        for (_, buffer) in &buffers {
+            pac.extend_from_slice(buffer);
+            pac.resize(pac.len().div_ceil(8) * 8, 0);
+        }
+
+        let server_signature = PacSignature::compute(HMAC_SHA1_96_AES256, service_key, &pac).unwrap();
+        pac[offsets[3] + 4..offsets[3] + 16].copy_from_slice(&server_signature);
+
+        let kdc_signature = PacSignature::compute(HMAC_SHA1_96_AES256, kdc_key, &server_signature).unwrap();
+        pac[offsets[4] + 4..offsets[4] + 16].copy_from_slice(&kdc_signature);
+
+        pac
+    }
+
+    pub(crate) fn key(password: &str) -> ServiceKey {
+        ServiceKey::from_password(CipherSuite::Aes256CtsHmacSha196, password, "EXAMPLE.COMservice", None).unwrap()
+    }
+
+    fn client() -> PacClientInfo {
+        PacClientInfo {
+            client_id: filetime_to_date(LOGON_TIME).unwrap(),
+            name: "user".to_owned(),
+        }
+    }
+
+    #[test]
+    fn decode_pac() {
+        let data = encode_pac(&key("service"), &key("krbtgt"), &client());
+        let pac = Pac::decode(&data, &key("service"), Some(&key("krbtgt"))).unwrap();
+
+        let logon_info = pac.logon_info.unwrap();
+        assert_eq!(logon_info.logon_time.unwrap().unix_timestamp(), 1_655_526_400);
+        assert_eq!(logon_info.logoff_time, None);
+        assert_eq!(logon_info.password_can_change, None);
+        assert_eq!(logon_info.effective_name, "user");
+        assert_eq!(logon_info.full_name, "Test User");
+        assert_eq!(logon_info.logon_script, "");
+        assert_eq!(logon_info.logon_count, 5);
+        assert_eq!(logon_info.logon_server, "DC01");
+        assert_eq!(logon_info.logon_domain_name, "EXAMPLE");
+        assert_eq!(logon_info.user_account_control, 0x10);
+        assert_eq!(logon_info.user_sid().to_string(), "S-1-5-21-1-2-3-1105");
+        assert_eq!(logon_info.primary_group_sid().to_string(), "S-1-5-21-1-2-3-513");
+        assert_eq!(
+            logon_info
+                .group_memberships()
+                .map(|group| (group.sid.to_string(), group.attributes))
+                .collect::<Vec<_>>(),
+            [
+                ("S-1-5-21-1-2-3-513".to_owned(), 7),
+                ("S-1-5-21-1-2-3-1108".to_owned(), 7),
+                ("S-1-18-1".to_owned(), 7),
+                ("S-1-5-21-4-5-6-1200".to_owned(), 0x2000_0007),
+            ]
+        );
+
+        let client_info = pac.client_info.unwrap();
+        assert_eq!(client_info.name, "user");
+        assert_eq!(client_info.client_id, logon_info.logon_time);
+
+        let upn_dns_info = pac.upn_dns_info.unwrap();
+        assert_eq!(upn_dns_info.upn, "user@example.com");
+        assert_eq!(upn_dns_info.dns_domain_name, "EXAMPLE.COM");
+        assert_eq!(upn_dns_info.sam_name.as_deref(), Some("user"));
+        assert_eq!(upn_dns_info.sid, Some(logon_info.user_sid()));
+    }
+
+    #[test]
+    fn modified_pac_fails_server_signature_verification() {
+        let mut data = encode_pac(&key("service"), &key("krbtgt"), &client());
+        // user id in the KERB_VALIDATION_INFO
+        let position = data
+            .windows(4)
+            .position(|window| window == 1105_u32.to_le_bytes())
+            .unwrap();
+        data[position] = 0xf4;
+
+        let err = Pac::decode(&data, &key("service"), None).unwrap_err();
+        assert_eq!(err.error_type, ErrorKind::MessageAltered);
+    }
+
+    #[test]
+    fn modified_pac_is_not_decoded_before_signature_verification() {
+        let mut data = encode_pac(&key("service"), &key("krbtgt"), &client());
+        // actual count of the "user" effective name in the KERB_VALIDATION_INFO
+        let position = data
+            .windows(12)
+            .position(|window| window == [4, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0])
+            .unwrap();
+        data[position + 8..position + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let err = Pac::decode(&data, &key("service"), None).unwrap_err();
+        assert_eq!(err.error_type, ErrorKind::MessageAltered);
+    }
+
+    #[test]
+    fn pac_signed_by_another_kdc_fails_kdc_signature_verification() {
+        let data = encode_pac(&key("service"), &key("krbtgt"), &client());
+
+        Pac::decode(&data, &key("service"), None).unwrap();
+
+        let err = Pac::decode(&data, &key("service"), Some(&key("another krbtgt"))).unwrap_err();
+        assert_eq!(err.error_type, ErrorKind::MessageAltered);
+    }
+
+    #[test]
+    fn truncated_pac() {
+        let data = encode_pac(&key("service"), &key("krbtgt"), &client());
+
+        let err = Pac::decode(&data[..data.len() - 16], &key("service"), None).unwrap_err();
+        assert_eq!(err.error_type, ErrorKind::InvalidToken);
+    }
+
+    #[test]
+    fn unicode_string_out_of_bounds() {
+        let header = UnicodeStringHeader {
+            length: 8,
+            pointer: 0x0002_0004,
+        };
+
+        for actual_count in [5, u32::MAX] {
+            let mut data = 4_u32.to_le_bytes().to_vec();
+            data.extend_from_slice(&0_u32.to_le_bytes());
+            data.extend_from_slice(&actual_count.to_le_bytes());
+            data.extend_from_slice(&utf16("user"));
+
+            let err = NdrReader::new(&data).read_unicode_string(&header).unwrap_err();
+            assert_eq!(err.error_type, ErrorKind::InvalidToken);
+        }
+    }
+
+    #[test]
+    fn sid_to_string() {
+        assert_eq!(sid(5, &[32, 544]).to_string(), "S-1-5-32-544");
+        assert_eq!(
+            Sid {
+                revision: 1,
+                identifier_authority: [0x01, 0, 0, 0, 0, 0x05],
+                sub_authorities: vec![1],
+            }
+            .to_string(),
+            "S-1-0x010000000005-1"
+        );
+    }
+}
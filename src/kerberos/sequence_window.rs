@@ -0,0 +1,293 @@
+//! Replay and out-of-sequence detection for per-message tokens.
+//!
+//! [RFC 4121 §4.2.6](https://www.rfc-editor.org/rfc/rfc4121#section-4.2.6) leaves the sequence number
+//! checking to the GSS-API layer. The algorithm follows
+//! [RFC 2743 §1.2.3](https://www.rfc-editor.org/rfc/rfc2743#section-1.2.3):
+//! the receiver keeps a window of recently seen sequence numbers and reports
+//! duplicate, old, unsequenced and gap conditions.
+//!
+//! Duplicate and old tokens are rejected. Unsequenced and gap conditions are supplementary information:
+//! the token is still processed, so the communication continues after the reordering.
+
+use tracing::warn;
+
+use crate::{Error, ErrorKind, Result};
+
+/// Amount of sequence numbers remembered behind the next expected one.
+const WINDOW_SIZE: u32 = 64;
+
+/// Result of checking the sequence number of the received token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceStatus {
+    /// The token has the expected sequence number.
+    Complete,
+    /// The token has already been received.
+    Duplicate,
+    /// The token is too old to be checked for duplication.
+    Old,
+    /// A later token has already been received.
+    Unsequenced,
+    /// An earlier expected token has not been received yet.
+    Gap,
+}
+
+impl SequenceStatus {
+    /// Converts the status into the `OutOfSequence` error if the token must be rejected.
+    ///
+    /// Unsequenced and gap tokens are accepted: they are only logged.
+    pub fn into_result(self) -> Result<()> {
+        let description = match self {
+            SequenceStatus::Complete => return Ok(()),
+            SequenceStatus::Unsequenced => {
+                warn!("Unsequenced token: a later token has already been received");
+
+                return Ok(());
+            }
+            SequenceStatus::Gap => {
+                warn!("Gap token: an earlier expected token has not been received");
+
+                return Ok(());
+            }
+            SequenceStatus::Duplicate => "duplicate token: the token has already been received",
+            SequenceStatus::Old => "old token: the token is too old to be checked for duplication",
+        };
+
+        Err(Error::new(ErrorKind::OutOfSequence, description))
+    }
+}
+
+/// Receive-side sequence window of the security context.
+///
+/// Sequence numbers are 32-bit values that wrap around. The window is anchored at the peer's initial sequence number
+/// from the AP exchange: tokens with sequence numbers below it are old. Implementations do not agree on whether
+/// the first token reuses the initial sequence number, so the next expected sequence number is taken from
+/// the first received token. When the initial sequence number is unknown, the first received token becomes the anchor.
+#[derive(Debug, Clone)]
+pub struct SequenceWindow {
+    replay_detect: bool,
+    sequence_detect: bool,
+    base: Option<u32>,
+    /// Next expected sequence number relative to the `base`. Zero until the first token is received.
+    next: u32,
+    /// Bit `n` is set when the token `next - 1 - n` has been received.
+    received: u64,
+}
+
+impl SequenceWindow {
+    pub fn new(replay_detect: bool, sequence_detect: bool, initial_seq_number: Option<u32>) -> Self {
+        Self {
+            replay_detect,
+            sequence_detect,
+            base: initial_seq_number,
+            next: 0,
+            received: 0,
+        }
+    }
+
+    /// The window that does not check sequence numbers.
+    pub fn disabled() -> Self {
+        Self::new(false, false, None)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.replay_detect || self.sequence_detect
+    }
+
+    /// Sets the peer's initial sequence number if it became known after the window creation.
+    ///
+    /// Has no effect once a token has been received.
+    pub fn set_initial_seq_number(&mut self, initial_seq_number: u32) {
+        if self.next == 0 {
+            self.base = Some(initial_seq_number);
+        }
+    }
+
+    /// Records the sequence number of the received token and reports its status.
+    ///
+    /// Must be called only for the tokens that passed the integrity check.
+    pub fn check(&mut self, seq_number: u64) -> SequenceStatus {
+        if !self.is_enabled() {
+            return SequenceStatus::Complete;
+        }
+
+        // Sequence numbers are generated from the 32-bit initial sequence number of the AP exchange.
+        let seq_number = seq_number as u32;
+        let base = *self.base.get_or_insert(seq_number);
+        let relative = seq_number.wrapping_sub(base);
+
+        // The offsets in the upper half of the sequence number space are negative:
+        // such tokens precede the initial sequence number.
+        if relative > i32::MAX as u32 {
+            return SequenceStatus::Old;
+        }
+
+        if self.next == 0 {
+            self.received = 1;
+            self.next = relative + 1;
+
+            return SequenceStatus::Complete;
+        }
+
+        if relative >= self.next {
+            let offset = relative - self.next;
+
+            self.received = if offset + 1 >= WINDOW_SIZE {
+                0
+            } else {
+                self.received << (offset + 1)
+            } | 1;
+            self.next = relative + 1;
+
+            return if offset > 0 && self.sequence_detect {
+                SequenceStatus::Gap
+            } else {
+                SequenceStatus::Complete
+            };
+        }
+
+        let offset = self.next - relative - 1;
+
+        if offset >= WINDOW_SIZE {
+            return if self.sequence_detect {
+                SequenceStatus::Unsequenced
+            } else {
+                SequenceStatus::Old
+            };
+        }
+
+        let bit = 1 << offset;
+
+        if self.received & bit != 0 {
+            return if self.replay_detect {
+                SequenceStatus::Duplicate
+            } else {
+                SequenceStatus::Complete
+            };
+        }
+
+        self.received |= bit;
+
+        if self.sequence_detect {
+            SequenceStatus::Unsequenced
+        } else {
+            SequenceStatus::Complete
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order_tokens() {
+        let mut window = SequenceWindow::new(true, true, None);
+
+        for seq_number in 1000..1100 {
+            assert_eq!(window.check(seq_number), SequenceStatus::Complete);
+        }
+    }
+
+    #[test]
+    fn duplicate_token() {
+        let mut window = SequenceWindow::new(true, false, None);
+
+        assert_eq!(window.check(7), SequenceStatus::Complete);
+        assert_eq!(window.check(8), SequenceStatus::Complete);
+        assert_eq!(window.check(7), SequenceStatus::Duplicate);
+        assert_eq!(window.check(8), SequenceStatus::Duplicate);
+    }
+
+    #[test]
+    fn reordered_tokens_without_sequence_detect() {
+        let mut window = SequenceWindow::new(true, false, None);
+
+        assert_eq!(window.check(1), SequenceStatus::Complete);
+        assert_eq!(window.check(3), SequenceStatus::Complete);
+        assert_eq!(window.check(2), SequenceStatus::Complete);
+        assert_eq!(window.check(2), SequenceStatus::Duplicate);
+        assert_eq!(window.check(100), SequenceStatus::Complete);
+        assert_eq!(window.check(3), SequenceStatus::Old);
+    }
+
+    #[test]
+    fn gap_and_unsequenced_tokens() {
+        let mut window = SequenceWindow::new(true, true, None);
+
+        assert_eq!(window.check(1), SequenceStatus::Complete);
+        assert_eq!(window.check(3), SequenceStatus::Gap);
+        assert_eq!(window.check(2), SequenceStatus::Unsequenced);
+        assert_eq!(window.check(2), SequenceStatus::Duplicate);
+        assert_eq!(window.check(4), SequenceStatus::Complete);
+        assert_eq!(window.check(200), SequenceStatus::Gap);
+        assert_eq!(window.check(4), SequenceStatus::Unsequenced);
+    }
+
+    #[test]
+    fn sequence_number_wraps() {
+        let mut window = SequenceWindow::new(true, true, None);
+
+        assert_eq!(window.check(u64::from(u32::MAX)), SequenceStatus::Complete);
+        assert_eq!(window.check(0), SequenceStatus::Complete);
+        assert_eq!(window.check(u64::from(u32::MAX)), SequenceStatus::Duplicate);
+    }
+
+    #[test]
+    fn token_before_base() {
+        let mut window = SequenceWindow::new(true, true, None);
+
+        assert_eq!(window.check(10), SequenceStatus::Complete);
+        assert_eq!(window.check(9), SequenceStatus::Old);
+        assert_eq!(window.check(11), SequenceStatus::Complete);
+        assert_eq!(window.check(9), SequenceStatus::Old);
+        assert_eq!(window.check(12), SequenceStatus::Complete);
+
+        let mut window = SequenceWindow::new(true, false, None);
+
+        assert_eq!(window.check(10), SequenceStatus::Complete);
+        assert_eq!(window.check(9), SequenceStatus::Old);
+        assert_eq!(window.check(10), SequenceStatus::Duplicate);
+    }
+
+    #[test]
+    fn anchored_at_initial_seq_number() {
+        let mut window = SequenceWindow::new(true, true, Some(u32::MAX - 1));
+
+        // the first token may skip the initial sequence number
+        assert_eq!(window.check(u64::from(u32::MAX)), SequenceStatus::Complete);
+        assert_eq!(window.check(u64::from(u32::MAX - 2)), SequenceStatus::Old);
+        assert_eq!(window.check(u64::from(u32::MAX - 1)), SequenceStatus::Unsequenced);
+        assert_eq!(window.check(0), SequenceStatus::Complete);
+        assert_eq!(window.check(0), SequenceStatus::Duplicate);
+
+        let mut window = SequenceWindow::new(true, true, Some(100));
+        window.set_initial_seq_number(10);
+
+        assert_eq!(window.check(9), SequenceStatus::Old);
+        assert_eq!(window.check(10), SequenceStatus::Complete);
+    }
+
+    #[test]
+    fn disabled_window_accepts_everything() {
+        let mut window = SequenceWindow::disabled();
+
+        assert_eq!(window.check(5), SequenceStatus::Complete);
+        assert_eq!(window.check(5), SequenceStatus::Complete);
+        assert_eq!(window.check(1), SequenceStatus::Complete);
+    }
+
+    #[test]
+    fn status_into_result() {
+        assert!(SequenceStatus::Complete.into_result().is_ok());
+        assert!(SequenceStatus::Unsequenced.into_result().is_ok());
+        assert!(SequenceStatus::Gap.into_result().is_ok());
+        assert_eq!(
+            SequenceStatus::Old.into_result().unwrap_err().error_type,
+            ErrorKind::OutOfSequence
+        );
+        assert_eq!(
+            SequenceStatus::Duplicate.into_result().unwrap_err().error_type,
+            ErrorKind::OutOfSequence
+        );
+    }
+}
@@ -1,15 +1,37 @@
 use std::io::Read;
 
+use oid::ObjectIdentifier;
+use picky_asn1::wrapper::ExplicitContextTag0;
+use picky_asn1::wrapper::ObjectIdentifierAsn1;
 use picky_asn1_der::application_tag::ApplicationTag;
 use picky_asn1_der::Asn1RawDer;
-use picky_krb::constants::key_usages::AP_REP_ENC;
-use picky_krb::data_types::{EncApRepPart, Ticket};
-use picky_krb::gss_api::NegTokenTarg1;
-use picky_krb::messages::{ApRep, TgtRep};
+use picky_asn1_x509::oids;
+use picky_krb::constants::gss_api::{AP_REQ_TOKEN_ID, AUTHENTICATOR_CHECKSUM_TYPE, TGT_REQ_TOKEN_ID};
+use picky_krb::constants::key_usages::{AP_REP_ENC, AP_REQ_AUTHENTICATOR, TICKET_REP};
+use picky_krb::data_types::{Authenticator, AuthorizationData, EncApRepPart, EncryptionKey, Ticket};
+use picky_krb::gss_api::{NegTokenInit, NegTokenTarg1};
+use picky_krb::messages::{ApRep, ApReq, TgtRep, TgtReq};
 
+use crate::kerberos::ccache::{CCache, CCacheCredential};
+use crate::kerberos::cipher::CipherSuite;
+use crate::kerberos::client::generators::{EncKey, GssFlags};
+use crate::kerberos::data_types::{EncKrbCredPart, EncTicketPart, KrbCred, KRB_CRED_ENC_PART_KEY_USAGE, KRB_CRED_TYPE};
+use crate::kerberos::flags::ApOptions;
+use crate::kerberos::server::ServiceKey;
+use crate::kerberos::utils::integer_as_u32;
 use crate::kerberos::{EncryptionParams, DEFAULT_ENCRYPTION_TYPE};
 use crate::{Error, ErrorKind, Result};
 
+/// [NegTokenTarg](https://datatracker.ietf.org/doc/html/rfc2478#section-3.2.1) is wrapped in the `[1]` context tag.
+const NEG_TOKEN_TARG_TAG: u8 = 0xa1;
+/// [Mechanism-Independent Token Format](https://datatracker.ietf.org/doc/html/rfc2743#section-3.1):
+/// the initial context token starts with the `[APPLICATION 0]` tag.
+const GSS_API_INITIAL_CONTEXT_TOKEN_TAG: u8 = 0x60;
+/// [RFC 4120 5.2.6.1](https://www.rfc-editor.org/rfc/rfc4120#section-5.2.6.1): AD-IF-RELEVANT.
+const AD_IF_RELEVANT_TYPE: u32 = 1;
+/// MS-PAC 4: AD-WIN2K-PAC.
+const AD_WIN2K_PAC_TYPE: u32 = 128;
+
 pub fn extract_ap_rep_from_neg_token_targ(token: &NegTokenTarg1) -> Result<ApRep> {
     let resp_token = &token
         .0
@@ -30,11 +52,11 @@ pub fn extract_ap_rep_from_neg_token_targ(token: &NegTokenTarg1) -> Result<ApRep
 }
 
 #[instrument(level = "trace", ret)]
-pub fn extract_sub_session_key_from_ap_rep(
+pub fn decrypt_ap_rep_enc_part(
     ap_rep: &ApRep,
     session_key: &[u8],
     enc_params: &EncryptionParams,
-) -> Result<Vec<u8>> {
+) -> Result<EncApRepPart> {
     let cipher = enc_params
         .encryption_type
         .as_ref()
@@ -50,17 +72,43 @@ pub fn extract_sub_session_key_from_ap_rep(
             )
         })?;
 
-    let ap_rep_enc_part: EncApRepPart = picky_asn1_der::from_bytes(&res)?;
+    Ok(picky_asn1_der::from_bytes(&res)?)
+}
 
+pub fn extract_sub_session_key_from_ap_rep(ap_rep_enc_part: &EncApRepPart) -> Result<Vec<u8>> {
     Ok(ap_rep_enc_part
         .0
         .subkey
         .0
+        .as_ref()
         .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Missing sub-key in ap_req"))?
         .0
         .key_value
         .0
-         .0)
+         .0
+        .clone())
+}
+
+/// Extracts the acceptor's initial sequence number from the AP-REP.
+pub fn extract_seq_number_from_ap_rep(ap_rep_enc_part: &EncApRepPart) -> Result<Option<u32>> {
+    ap_rep_enc_part
+        .0
+        .seq_number
+        .0
+        .as_ref()
+        .map(|seq_number| integer_as_u32(&seq_number.0))
+        .transpose()
+}
+
+/// Extracts the initiator's initial sequence number from the authenticator.
+pub fn extract_seq_number_from_authenticator(authenticator: &Authenticator) -> Result<Option<u32>> {
+    authenticator
+        .0
+        .seq_number
+        .0
+        .as_ref()
+        .map(|seq_number| integer_as_u32(&seq_number.0))
+        .transpose()
 }
 
 #[instrument(level = "trace", ret)]
@@ -83,3 +131,376 @@ pub fn extract_tgt_ticket(data: &[u8]) -> Result<Option<Ticket>> {
         Ok(None)
     }
 }
+
+/// Extracts the user-to-user TGT-REQ from the SPNEGO `NegTokenInit` our client starts the authentication with.
+///
+/// Returns `None` if the token does not carry the TGT-REQ.
+#[instrument(level = "trace", ret)]
+pub fn extract_tgt_req(mut data: &[u8]) -> Option<TgtReq> {
+    let oid: ApplicationTag<ObjectIdentifierAsn1, 0> = picky_asn1_der::from_reader(&mut data).ok()?;
+    if oid.0 .0 != oids::spnego() {
+        return None;
+    }
+
+    let neg_token_init: ExplicitContextTag0<NegTokenInit> = picky_asn1_der::from_reader(&mut data).ok()?;
+    let mech_token = neg_token_init.0.mech_token.0?.0 .0;
+
+    let mut mech_token = mech_token.as_slice();
+    let oid: ApplicationTag<ObjectIdentifierAsn1, 0> = picky_asn1_der::from_reader(&mut mech_token).ok()?;
+    if oid.0 .0 != oids::krb5_user_to_user() {
+        return None;
+    }
+
+    let mut token_id = [0, 0];
+    mech_token.read_exact(&mut token_id).ok()?;
+    if token_id != TGT_REQ_TOKEN_ID {
+        return None;
+    }
+
+    picky_asn1_der::from_reader(&mut mech_token).ok()
+}
+
+/// AP-REQ received from the client.
+#[derive(Debug)]
+pub struct ApReqToken {
+    pub ap_req: ApReq,
+    /// Kerberos mechanism OID from the GSS-API token header.
+    ///
+    /// It is `None` if the client has sent a bare AP-REQ message without the GSS-API framing.
+    pub mech_id: Option<ObjectIdentifier>,
+    /// Indicates that the AP-REQ was wrapped in the SPNEGO `NegTokenTarg` message.
+    pub spnego: bool,
+}
+
+/// Extracts the AP-REQ from the client's token.
+///
+/// The AP-REQ can be wrapped in the SPNEGO `NegTokenTarg` (as our client does),
+/// framed as the [Kerberos GSS-API initial context token](https://datatracker.ietf.org/doc/html/rfc4121#section-4.1),
+/// or sent as a bare AP-REQ message.
+#[instrument(level = "trace", ret)]
+pub fn extract_ap_req(data: &[u8]) -> Result<ApReqToken> {
+    match data.first() {
+        Some(&NEG_TOKEN_TARG_TAG) => {
+            let neg_token_targ: NegTokenTarg1 = picky_asn1_der::from_bytes(data)?;
+
+            let resp_token = neg_token_targ
+                .0
+                .response_token
+                .0
+                .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Missing response token in NegTokenTarg"))?
+                .0
+                 .0;
+            let (mech_id, ap_req) = extract_ap_req_from_gss_token(&resp_token)?;
+
+            Ok(ApReqToken {
+                ap_req,
+                mech_id: Some(mech_id),
+                spnego: true,
+            })
+        }
+        Some(&GSS_API_INITIAL_CONTEXT_TOKEN_TAG) => {
+            let (mech_id, ap_req) = extract_ap_req_from_gss_token(data)?;
+
+            Ok(ApReqToken {
+                ap_req,
+                mech_id: Some(mech_id),
+                spnego: false,
+            })
+        }
+        _ => Ok(ApReqToken {
+            ap_req: picky_asn1_der::from_bytes(data)?,
+            mech_id: None,
+            spnego: false,
+        }),
+    }
+}
+
+fn extract_ap_req_from_gss_token(mut data: &[u8]) -> Result<(ObjectIdentifier, ApReq)> {
+    let oid: ApplicationTag<ObjectIdentifierAsn1, 0> = picky_asn1_der::from_reader(&mut data)?;
+
+    let mut token_id = [0, 0];
+    data.read_exact(&mut token_id)?;
+
+    if token_id != AP_REQ_TOKEN_ID {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!("Invalid GSS-API token id: expected AP-REQ but got {:?}", token_id),
+        ));
+    }
+
+    Ok((oid.0 .0, picky_asn1_der::from_reader(&mut data)?))
+}
+
+pub fn extract_ap_options(ap_req: &ApReq) -> ApOptions {
+    let mut options = [0; 4];
+    let payload = ap_req.0.ap_options.0 .0.payload_view();
+    let len = payload.len().min(options.len());
+    options[..len].copy_from_slice(&payload[..len]);
+
+    ApOptions::from_bits_truncate(u32::from_be_bytes(options))
+}
+
+/// Finds the service key the ticket is encrypted with.
+pub fn find_service_key<'a>(ticket: &Ticket, service_keys: &'a [ServiceKey]) -> Result<&'a ServiceKey> {
+    let enc_part = &ticket.0.enc_part.0;
+
+    let encryption_type = CipherSuite::try_from(enc_part.etype.0 .0.as_slice())?;
+    let kvno = enc_part
+        .kvno
+        .0
+        .as_ref()
+        .map(|kvno| integer_as_u32(&kvno.0))
+        .transpose()?;
+
+    service_keys
+        .iter()
+        .find(|key| {
+            key.encryption_type == encryption_type && (key.kvno.is_none() || kvno.is_none() || key.kvno == kvno)
+        })
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NoKerbKey,
+                format!(
+                    "Service key is not available: encryption type {:?}, kvno {:?}",
+                    encryption_type, kvno
+                ),
+            )
+        })
+}
+
+/// Decrypts the ticket encrypted part using the matching service key.
+#[instrument(level = "trace", ret, skip(service_keys))]
+pub fn extract_enc_ticket_part(ticket: &Ticket, service_keys: &[ServiceKey]) -> Result<EncTicketPart> {
+    let enc_part = &ticket.0.enc_part.0;
+    let service_key = find_service_key(ticket, service_keys)?;
+
+    let enc_ticket_part = service_key
+        .encryption_type
+        .cipher()
+        .decrypt(service_key.key.as_ref(), TICKET_REP, &enc_part.cipher.0 .0)
+        .map_err(|err| {
+            Error::new(
+                ErrorKind::DecryptFailure,
+                format!("Cannot decrypt ticket.enc_part: {:?}", err),
+            )
+        })?;
+
+    Ok(picky_asn1_der::from_bytes(&enc_ticket_part)?)
+}
+
+/// Extracts the encoded PAC from the ticket authorization data.
+///
+/// [MS-PAC 4](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf):
+/// the PAC is placed in the AD-WIN2K-PAC element wrapped in the AD-IF-RELEVANT element.
+pub(crate) fn extract_pac(enc_ticket_part: &EncTicketPart) -> Result<Option<Vec<u8>>> {
+    fn find_pac(authorization_data: &AuthorizationData) -> Result<Option<Vec<u8>>> {
+        for element in &authorization_data.0 {
+            match integer_as_u32(&element.ad_type.0)? {
+                AD_IF_RELEVANT_TYPE => {
+                    let inner: AuthorizationData = picky_asn1_der::from_bytes(&element.ad_data.0 .0)?;
+
+                    if let Some(pac) = find_pac(&inner)? {
+                        return Ok(Some(pac));
+                    }
+                }
+                AD_WIN2K_PAC_TYPE => return Ok(Some(element.ad_data.0 .0.clone())),
+                _ => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    match enc_ticket_part.0.authorization_data.0.as_ref() {
+        Some(authorization_data) => find_pac(&authorization_data.0),
+        None => Ok(None),
+    }
+}
+
+/// Decrypts the AP-REQ authenticator using the session key from the ticket.
+#[instrument(level = "trace", ret, skip(session_key))]
+pub fn extract_authenticator(ap_req: &ApReq, session_key: &[u8]) -> Result<Authenticator> {
+    let enc_part = &ap_req.0.authenticator.0;
+
+    let authenticator = CipherSuite::try_from(enc_part.etype.0 .0.as_slice())?
+        .cipher()
+        .decrypt(session_key, AP_REQ_AUTHENTICATOR, &enc_part.cipher.0 .0)
+        .map_err(|err| {
+            Error::new(
+                ErrorKind::DecryptFailure,
+                format!("Cannot decrypt ap_req.authenticator: {:?}", err),
+            )
+        })?;
+
+    Ok(picky_asn1_der::from_bytes(&authenticator)?)
+}
+
+pub fn extract_encryption_key(key: &EncryptionKey) -> Result<EncKey> {
+    Ok(EncKey {
+        key_type: CipherSuite::try_from(key.key_type.0 .0.as_slice())?,
+        key_value: key.key_value.0 .0.clone(),
+    })
+}
+
+/// [Authenticator Checksum](https://datatracker.ietf.org/doc/html/rfc4121#section-4.1.1)
+#[derive(Debug)]
+pub(crate) struct AuthenticatorChecksum {
+    /// MD5 hash of the channel bindings. It is filled with zeros if the client did not provide the channel bindings.
+    pub channel_bindings_hash: [u8; 16],
+    pub flags: GssFlags,
+    /// DER-encoded `KRB-CRED` message with the delegated credentials. Present only if `GSS_C_DELEG_FLAG` is set.
+    pub delegation: Option<Vec<u8>>,
+}
+
+/// Extracts the GSS-API checksum from the authenticator.
+///
+/// Returns `None` if the authenticator does not contain the checksum of the `0x8003` type.
+pub(crate) fn extract_authenticator_checksum(authenticator: &Authenticator) -> Result<Option<AuthenticatorChecksum>> {
+    let checksum = match authenticator.0.cksum.0.as_ref() {
+        Some(checksum) => &checksum.0,
+        None => return Ok(None),
+    };
+
+    if checksum.cksumtype.0 .0 != AUTHENTICATOR_CHECKSUM_TYPE {
+        return Ok(None);
+    }
+
+    let checksum_value = &checksum.checksum.0 .0;
+
+    if checksum_value.len() < 24 {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!(
+                "Invalid authenticator checksum length: expected >= 24 but got {}",
+                checksum_value.len()
+            ),
+        ));
+    }
+
+    // 0..3 - Lgth: number of octets in Bnd field. Always 16
+    let bnd_len = u32::from_le_bytes(checksum_value[0..4].try_into().unwrap());
+    if bnd_len != 16 {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!(
+                "Invalid authenticator checksum Bnd length: expected 16 but got {}",
+                bnd_len
+            ),
+        ));
+    }
+
+    let flags = GssFlags::from_bits_retain(u32::from_le_bytes(checksum_value[20..24].try_into().unwrap()));
+
+    // 24..25 - DlgOpt: the delegation option identifier, 26..27 - Dlgth: the length of the Deleg field,
+    // 28..(28 + Dlgth) - Deleg: the KRB_CRED message.
+    let delegation = if flags.contains(GssFlags::GSS_C_DELEG_FLAG) {
+        if checksum_value.len() < 28 {
+            return Err(Error::new(
+                ErrorKind::InvalidToken,
+                format!(
+                    "Invalid authenticator checksum length: expected >= 28 when GSS_C_DELEG_FLAG is set but got {}",
+                    checksum_value.len()
+                ),
+            ));
+        }
+
+        let dlg_opt = u16::from_le_bytes(checksum_value[24..26].try_into().unwrap());
+        if dlg_opt != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidToken,
+                format!("Invalid authenticator checksum DlgOpt: expected 1 but got {}", dlg_opt),
+            ));
+        }
+
+        let dlg_len = usize::from(u16::from_le_bytes(checksum_value[26..28].try_into().unwrap()));
+
+        Some(
+            checksum_value
+                .get(28..28 + dlg_len)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidToken,
+                        "Authenticator checksum Deleg field is too short",
+                    )
+                })?
+                .to_vec(),
+        )
+    } else {
+        None
+    };
+
+    Ok(Some(AuthenticatorChecksum {
+        channel_bindings_hash: checksum_value[4..20].try_into().unwrap(),
+        flags,
+        delegation,
+    }))
+}
+
+/// Extracts the credentials delegated by the client from the `KRB-CRED` message.
+///
+/// [RFC 4121 4.1.1.1](https://datatracker.ietf.org/doc/html/rfc4121#section-4.1.1.1): the `KRB-CRED` message is
+/// encrypted using the session key of the service ticket. Some implementations use the initiator sub-key instead,
+/// so it is also tried if provided.
+#[instrument(level = "trace", ret, skip_all)]
+pub(crate) fn extract_delegated_credentials(
+    krb_cred: &[u8],
+    session_key: &EncKey,
+    initiator_sub_key: Option<&EncKey>,
+) -> Result<CCache> {
+    let krb_cred: KrbCred = picky_asn1_der::from_bytes(krb_cred)?;
+    let krb_cred = krb_cred.0;
+
+    let msg_type = integer_as_u32(&krb_cred.msg_type.0)?;
+    if msg_type != u32::from(KRB_CRED_TYPE) {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!("Invalid KRB-CRED message type: {}", msg_type),
+        ));
+    }
+
+    let enc_part = &krb_cred.enc_part.0;
+    let etype = CipherSuite::try_from(usize::try_from(integer_as_u32(&enc_part.etype.0)?)?)?;
+    let cipher = etype.cipher();
+
+    let encoded_enc_part = std::iter::once(session_key)
+        .chain(initiator_sub_key)
+        .filter(|key| key.key_type == etype)
+        .find_map(|key| {
+            cipher
+                .decrypt(&key.key_value, KRB_CRED_ENC_PART_KEY_USAGE, &enc_part.cipher.0 .0)
+                .ok()
+        })
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::DecryptFailure,
+                "Unable to decrypt the KRB-CRED encrypted part",
+            )
+        })?;
+    let enc_krb_cred_part: EncKrbCredPart = picky_asn1_der::from_bytes(&encoded_enc_part)?;
+
+    let tickets = &krb_cred.tickets.0 .0;
+    let ticket_info = &enc_krb_cred_part.0.ticket_info.0 .0;
+    if tickets.is_empty() || tickets.len() != ticket_info.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!(
+                "KRB-CRED contains {} tickets and {} ticket info entries",
+                tickets.len(),
+                ticket_info.len()
+            ),
+        ));
+    }
+
+    let credentials = tickets
+        .iter()
+        .zip(ticket_info)
+        .map(|(ticket, info)| CCacheCredential::from_krb_cred_info(ticket, info))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut ccache = CCache::new(credentials[0].client.clone());
+    for credential in credentials {
+        ccache.store(credential);
+    }
+
+    Ok(ccache)
+}
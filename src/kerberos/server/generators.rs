@@ -0,0 +1,98 @@
+use oid::ObjectIdentifier;
+use picky_asn1::wrapper::{
+    ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag2, ExplicitContextTag3, IntegerAsn1,
+    ObjectIdentifierAsn1, OctetStringAsn1, Optional,
+};
+use picky_asn1_der::application_tag::ApplicationTag;
+use picky_asn1_der::Asn1RawDer;
+use picky_asn1_x509::oids;
+use picky_krb::constants::gss_api::{ACCEPT_INCOMPLETE, AP_REP_TOKEN_ID};
+use picky_krb::constants::key_usages::AP_REP_ENC;
+use picky_krb::constants::types::AP_REP_MSG_TYPE;
+use picky_krb::data_types::{Authenticator, EncApRepPart, EncApRepPartInner, EncryptedData, EncryptionKey};
+use picky_krb::gss_api::{KrbMessage, NegTokenTarg, NegTokenTarg1};
+use picky_krb::messages::{ApRep, ApRepInner};
+
+use crate::kerberos::client::generators::EncKey;
+use crate::kerberos::KERBEROS_VERSION;
+use crate::Result;
+
+/// Generates the AP-REP message.
+///
+/// [Receipt of KRB_AP_REQ Message](https://www.rfc-editor.org/rfc/rfc4120#section-3.2.3):
+/// "If mutual authentication is required, the server MUST ... generate a reply (KRB_AP_REP) that contains
+/// the timestamp and microsecond field from the client's authenticator... encrypted in the session key".
+#[instrument(level = "trace", ret, skip(session_key))]
+pub fn generate_ap_rep(
+    session_key: &EncKey,
+    authenticator: &Authenticator,
+    sub_key: Option<&EncKey>,
+    seq_number: u32,
+) -> Result<ApRep> {
+    let enc_ap_rep_part = EncApRepPart::from(EncApRepPartInner {
+        ctime: ExplicitContextTag0::from(authenticator.0.ctime.0.clone()),
+        cusec: ExplicitContextTag1::from(authenticator.0.cusec.0.clone()),
+        subkey: Optional::from(sub_key.map(|EncKey { key_type, key_value }| {
+            ExplicitContextTag2::from(EncryptionKey {
+                key_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![key_type.into()])),
+                key_value: ExplicitContextTag1::from(OctetStringAsn1::from(key_value.clone())),
+            })
+        })),
+        seq_number: Optional::from(Some(ExplicitContextTag3::from(IntegerAsn1::from_bytes_be_unsigned(
+            seq_number.to_be_bytes().to_vec(),
+        )))),
+    });
+
+    let encrypted_enc_ap_rep_part = session_key.key_type.cipher().encrypt(
+        &session_key.key_value,
+        AP_REP_ENC,
+        &picky_asn1_der::to_vec(&enc_ap_rep_part)?,
+    )?;
+
+    Ok(ApRep::from(ApRepInner {
+        pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+        msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![AP_REP_MSG_TYPE])),
+        enc_part: ExplicitContextTag2::from(EncryptedData {
+            etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![(&session_key.key_type).into()])),
+            kvno: Optional::from(None),
+            cipher: ExplicitContextTag2::from(OctetStringAsn1::from(encrypted_enc_ap_rep_part)),
+        }),
+    }))
+}
+
+/// Generates the reply to the user-to-user TGT-REQ.
+///
+/// The acceptor decrypts the tickets using its service keys and has no TGT to send back, so the reply selects
+/// the Kerberos mechanism without the TGT-REP: the client uses the regular service ticket then.
+pub fn generate_neg_token_targ_without_tgt() -> NegTokenTarg1 {
+    NegTokenTarg1::from(NegTokenTarg {
+        neg_result: Optional::from(Some(ExplicitContextTag0::from(Asn1RawDer(ACCEPT_INCOMPLETE.to_vec())))),
+        supported_mech: Optional::from(Some(ExplicitContextTag1::from(
+            ObjectIdentifierAsn1::from(oids::krb5()),
+        ))),
+        response_token: Optional::from(None),
+        mech_list_mic: Optional::from(None),
+    })
+}
+
+/// Wraps the AP-REP into the [Kerberos GSS-API token](https://datatracker.ietf.org/doc/html/rfc4121#section-4.1).
+pub fn generate_gss_ap_rep(ap_rep: ApRep, mech_id: ObjectIdentifier) -> ApplicationTag<KrbMessage<ApRep>, 0> {
+    ApplicationTag(KrbMessage {
+        krb5_oid: ObjectIdentifierAsn1::from(mech_id),
+        krb5_token_id: AP_REP_TOKEN_ID,
+        krb_msg: ap_rep,
+    })
+}
+
+pub fn generate_neg_ap_rep(ap_rep: ApRep, mech_id: ObjectIdentifier, mech_list_mic: Vec<u8>) -> Result<NegTokenTarg1> {
+    let krb_blob = generate_gss_ap_rep(ap_rep, mech_id.clone());
+
+    Ok(NegTokenTarg1::from(NegTokenTarg {
+        neg_result: Optional::from(Some(ExplicitContextTag0::from(Asn1RawDer(ACCEPT_INCOMPLETE.to_vec())))),
+        supported_mech: Optional::from(Some(ExplicitContextTag1::from(ObjectIdentifierAsn1::from(mech_id)))),
+        response_token: Optional::from(Some(ExplicitContextTag2::from(OctetStringAsn1::from(
+            picky_asn1_der::to_vec(&krb_blob)?,
+        )))),
+        mech_list_mic: Optional::from(Some(ExplicitContextTag3::from(OctetStringAsn1::from(mech_list_mic)))),
+    }))
+}
@@ -1 +1,116 @@
 pub mod extractors;
+pub mod generators;
+pub mod replay_cache;
+pub mod validate;
+
+use time::Duration;
+
+use crate::kerberos::ccache::CCache;
+use crate::kerberos::cipher::CipherSuite;
+use crate::kerberos::client::generators::GssFlags;
+use crate::kerberos::data_types::EncTicketPart;
+use crate::kerberos::keytab::Keytab;
+use crate::kerberos::pac::Pac;
+use crate::kerberos::server::replay_cache::ReplayCache;
+use crate::{Result, Secret};
+
+/// [Kerberos V5 system administration](https://web.mit.edu/kerberos/krb5-1.12/doc/admin/conf_files/krb5_conf.html#libdefaults)
+/// "clockskew: Sets the maximum allowable amount of clockskew in seconds that the library will tolerate
+/// before assuming that a Kerberos message is invalid. The default value is 300 seconds, or five minutes."
+pub const DEFAULT_MAX_TIME_SKEW: Duration = Duration::minutes(5);
+
+/// Long-term key of the service principal.
+///
+/// The KDC encrypts service tickets using this key, so the acceptor needs it to decrypt the ticket from the AP-REQ.
+#[derive(Debug, Clone)]
+pub struct ServiceKey {
+    /// Key version number. If specified, it must match the `kvno` of the ticket encrypted part.
+    pub kvno: Option<u32>,
+    pub encryption_type: CipherSuite,
+    pub key: Secret<Vec<u8>>,
+}
+
+impl ServiceKey {
+    pub fn new(encryption_type: CipherSuite, key: Vec<u8>, kvno: Option<u32>) -> Self {
+        Self {
+            kvno,
+            encryption_type,
+            key: key.into(),
+        }
+    }
+
+    /// Derives the service key from the account password and salt.
+    ///
+    /// The default salt is the realm concatenated with the components of the service principal name
+    /// (e.g. `EXAMPLE.COMHTTPwww.example.com`), but Active Directory may use a different one.
+    pub fn from_password(encryption_type: CipherSuite, password: &str, salt: &str, kvno: Option<u32>) -> Result<Self> {
+        let key = encryption_type
+            .cipher()
+            .generate_key_from_password(password.as_bytes(), salt.as_bytes())?;
+
+        Ok(Self::new(encryption_type, key, kvno))
+    }
+}
+
+/// Settings of the Kerberos acceptor (server side of the authentication).
+#[derive(Debug, Clone)]
+pub struct ServerProperties {
+    /// Long-term keys of the service principal. They are used to decrypt incoming service tickets.
+    pub service_keys: Vec<ServiceKey>,
+    /// Service principal name without the realm (e.g. `HTTP/www.example.com`).
+    ///
+    /// If specified, tickets issued for other services are rejected.
+    pub service_name: Option<String>,
+    /// Maximum allowed difference between the client and server clocks.
+    pub max_time_skew: Duration,
+    /// Key of the `krbtgt` account. If specified, the KDC signature of the ticket PAC is verified as well.
+    ///
+    /// The server signature of the PAC is always verified using the service key.
+    pub kdc_key: Option<ServiceKey>,
+    /// Recently accepted authenticators. Replayed AP-REQs are rejected.
+    ///
+    /// Clones of the properties share the cache, so it covers all contexts created with them.
+    pub replay_cache: ReplayCache,
+}
+
+impl ServerProperties {
+    pub fn new(service_keys: Vec<ServiceKey>) -> Self {
+        Self {
+            service_keys,
+            service_name: None,
+            max_time_skew: DEFAULT_MAX_TIME_SKEW,
+            kdc_key: None,
+            replay_cache: ReplayCache::default(),
+        }
+    }
+
+    /// Takes the service keys of the principal (e.g. `HTTP/www.example.com@EXAMPLE.COM`) from the keytab.
+    pub fn from_keytab(keytab: &Keytab, service_principal: &str) -> Self {
+        let service_name = service_principal
+            .rsplit_once('@')
+            .map(|(service_name, _realm)| service_name)
+            .unwrap_or(service_principal);
+
+        Self {
+            service_name: Some(service_name.to_owned()),
+            ..Self::new(keytab.service_keys(service_principal))
+        }
+    }
+}
+
+/// Acceptor-side data obtained from the client's AP-REQ.
+#[derive(Debug, Clone)]
+pub(crate) struct ServerContext {
+    /// Decrypted part of the client's service ticket.
+    pub enc_ticket_part: EncTicketPart,
+    /// Flags from the authenticator checksum.
+    pub flags: GssFlags,
+    /// Credentials delegated by the client.
+    pub delegated_credentials: Option<CCache>,
+    /// The client's channel bindings were verified against the acceptor's ones.
+    pub channel_bindings_verified: bool,
+    /// The SPNEGO `mechListMIC` of the client was verified.
+    pub mic_verified: bool,
+    /// PAC from the ticket authorization data.
+    pub pac: Option<Pac>,
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use time::{Duration, OffsetDateTime};
+
+use crate::{Error, ErrorKind, Result};
+
+/// Default maximum number of the authenticators remembered by the [ReplayCache].
+pub const DEFAULT_REPLAY_CACHE_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ReplayCacheEntry {
+    client: String,
+    server: String,
+    ctime: OffsetDateTime,
+    /// Big-endian bytes of the microsecond part of the authenticator time.
+    cusec: Vec<u8>,
+}
+
+/// Cache of the recently accepted authenticators.
+///
+/// [RFC 4120 3.2.3](https://www.rfc-editor.org/rfc/rfc4120#section-3.2.3):
+/// "the server MUST utilize a replay cache to remember any authenticator presented within the allowable clock skew".
+/// The authenticator is identified by the client and server principal names, `ctime`, and `cusec`.
+///
+/// Entries older than the allowed clock skew are dropped since such authenticators are rejected anyway.
+/// When the cache is full, the oldest entry is evicted. Clones share the same storage.
+#[derive(Debug, Clone)]
+pub struct ReplayCache {
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<ReplayCacheEntry>>>,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Remembers the authenticator or fails if it has already been seen.
+    pub(crate) fn insert(
+        &self,
+        client: String,
+        server: String,
+        ctime: OffsetDateTime,
+        cusec: Vec<u8>,
+        max_time_skew: Duration,
+    ) -> Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| Error::new(ErrorKind::InternalError, "Replay cache is poisoned"))?;
+
+        let now = OffsetDateTime::now_utc();
+        entries.retain(|entry| now - entry.ctime <= max_time_skew);
+
+        let entry = ReplayCacheEntry {
+            client,
+            server,
+            ctime,
+            cusec,
+        };
+        if entries.contains(&entry) {
+            return Err(Error::new(ErrorKind::InvalidToken, "Replayed authenticator"));
+        }
+
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+
+        Ok(())
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_REPLAY_CACHE_CAPACITY)
+    }
+}
@@ -0,0 +1,164 @@
+use picky_krb::data_types::{Authenticator, KerberosTime, PrincipalName, Ticket};
+use time::OffsetDateTime;
+
+use crate::channel_bindings::ChannelBindings;
+use crate::crypto::compute_md5_channel_bindings_hash;
+use crate::kerberos::data_types::EncTicketPart;
+use crate::kerberos::pac::Pac;
+use crate::kerberos::server::extractors::{extract_pac, AuthenticatorChecksum};
+use crate::kerberos::server::{ServerProperties, ServiceKey};
+use crate::{Error, ErrorKind, Result};
+
+pub(crate) fn kerberos_time_to_date(time: &KerberosTime) -> Result<OffsetDateTime> {
+    OffsetDateTime::try_from(time.0.clone())
+        .map_err(|err| Error::new(ErrorKind::InvalidToken, format!("Invalid Kerberos time: {:?}", err)))
+}
+
+pub(crate) fn principal_name_to_string(name: &PrincipalName) -> String {
+    name.name_string
+        .0
+         .0
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Checks that the ticket was issued for our service and is valid at the current moment.
+pub fn validate_ticket(ticket: &Ticket, enc_ticket_part: &EncTicketPart, properties: &ServerProperties) -> Result<()> {
+    if let Some(service_name) = properties.service_name.as_ref() {
+        let sname = principal_name_to_string(&ticket.0.sname.0);
+
+        if !sname.eq_ignore_ascii_case(service_name) {
+            return Err(Error::new(
+                ErrorKind::WrongPrincipalName,
+                format!("The ticket isn't for us: expected {} but got {}", service_name, sname),
+            ));
+        }
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let enc_ticket_part = &enc_ticket_part.0;
+
+    let start_time = kerberos_time_to_date(
+        enc_ticket_part
+            .start_time
+            .0
+            .as_ref()
+            .map(|start_time| &start_time.0)
+            .unwrap_or(&enc_ticket_part.auth_time.0),
+    )?;
+    if start_time - properties.max_time_skew > now {
+        return Err(Error::new(ErrorKind::InvalidToken, "The ticket is not yet valid"));
+    }
+
+    let end_time = kerberos_time_to_date(&enc_ticket_part.end_time.0)?;
+    if end_time + properties.max_time_skew < now {
+        return Err(Error::new(ErrorKind::ContextExpired, "The ticket has expired"));
+    }
+
+    Ok(())
+}
+
+/// Checks that the authenticator belongs to the ticket owner, was created recently, and is not replayed.
+pub fn validate_authenticator(
+    authenticator: &Authenticator,
+    ticket: &Ticket,
+    enc_ticket_part: &EncTicketPart,
+    properties: &ServerProperties,
+) -> Result<()> {
+    let authenticator = &authenticator.0;
+    let enc_ticket_part = &enc_ticket_part.0;
+
+    if authenticator.crealm.0 != enc_ticket_part.crealm.0
+        || authenticator.cname.0.name_string != enc_ticket_part.cname.0.name_string
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            "The ticket and the authenticator don't match",
+        ));
+    }
+
+    let ctime = kerberos_time_to_date(&authenticator.ctime.0)?;
+    if (OffsetDateTime::now_utc() - ctime).abs() > properties.max_time_skew {
+        return Err(Error::new(ErrorKind::TimeSkew, "Clock skew too great"));
+    }
+
+    properties.replay_cache.insert(
+        format!(
+            "{}@{}",
+            principal_name_to_string(&authenticator.cname.0),
+            *authenticator.crealm.0
+        ),
+        format!("{}@{}", principal_name_to_string(&ticket.0.sname.0), *ticket.0.realm.0),
+        ctime,
+        authenticator.cusec.0.as_unsigned_bytes_be().to_vec(),
+        properties.max_time_skew,
+    )
+}
+
+/// Checks that the channel bindings hash from the authenticator checksum matches the acceptor's channel bindings.
+///
+/// The validation is skipped when the acceptor does not have channel bindings.
+pub(crate) fn validate_channel_bindings(
+    checksum: Option<&AuthenticatorChecksum>,
+    channel_bindings: Option<&ChannelBindings>,
+) -> Result<()> {
+    let channel_bindings = match channel_bindings {
+        Some(channel_bindings) => channel_bindings,
+        None => return Ok(()),
+    };
+
+    match checksum {
+        Some(checksum) if checksum.channel_bindings_hash == compute_md5_channel_bindings_hash(channel_bindings) => {
+            Ok(())
+        }
+        _ => Err(Error::new(ErrorKind::BadBindings, "Channel bindings mismatch")),
+    }
+}
+
+/// Verifies the signatures of the PAC from the ticket authorization data and decodes it.
+///
+/// The server signature is verified using the service key that decrypted the ticket. The KDC signature
+/// is verified only if the `krbtgt` key is configured.
+///
+/// [MS-PAC 2.7](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-PAC/%5bMS-PAC%5d.pdf):
+/// the PAC_CLIENT_INFO must match the client name and the authentication time of the ticket,
+/// so the PAC can not be moved to the ticket of another client.
+pub(crate) fn validate_pac(
+    enc_ticket_part: &EncTicketPart,
+    service_key: &ServiceKey,
+    properties: &ServerProperties,
+) -> Result<Option<Pac>> {
+    let pac = match extract_pac(enc_ticket_part)? {
+        Some(pac) => Pac::decode(&pac, service_key, properties.kdc_key.as_ref())?,
+        None => return Ok(None),
+    };
+
+    let client_info = pac
+        .client_info
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "The PAC client info is missing"))?;
+
+    let cname = principal_name_to_string(&enc_ticket_part.0.cname.0);
+    if !client_info.name.eq_ignore_ascii_case(&cname) {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!(
+                "The PAC client name doesn't match the ticket: expected {} but got {}",
+                cname, client_info.name
+            ),
+        ));
+    }
+
+    // the Kerberos time has the precision of seconds
+    let auth_time = kerberos_time_to_date(&enc_ticket_part.0.auth_time.0)?;
+    if client_info.client_id.map(OffsetDateTime::unix_timestamp) != Some(auth_time.unix_timestamp()) {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            "The PAC client info doesn't match the ticket authentication time",
+        ));
+    }
+
+    Ok(Some(pac))
+}
@@ -1,13 +1,21 @@
-use std::io::Write;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
 
-use picky_krb::constants::key_usages::INITIATOR_SIGN;
+use picky_asn1::wrapper::IntegerAsn1;
+use picky_krb::constants::key_usages::{ACCEPTOR_SIGN, INITIATOR_SIGN};
 use picky_krb::crypto::aes::{checksum_sha_aes, AesSize};
 use picky_krb::gss_api::MicToken;
 use serde::Serialize;
 
+use crate::kerberos::cipher::CipherSuite;
 use crate::kerberos::client::generators::get_mech_list;
 use crate::kerberos::encryption_params::EncryptionParams;
-use crate::{Error, ErrorKind, Result};
+use crate::kerberos::gss_rc4;
+use crate::utils::{extract_data_to_sign, get_encryption_key};
+use crate::{Error, ErrorKind, Result, SecurityBuffer, SecurityBufferType};
+
+const MIC_TOKEN_FLAG_SENT_BY_ACCEPTOR: u8 = 0x01;
 
 pub fn serialize_message<T: ?Sized + Serialize>(v: &T) -> Result<Vec<u8>> {
     let mut data = Vec::new();
@@ -23,9 +31,28 @@ pub fn serialize_message<T: ?Sized + Serialize>(v: &T) -> Result<Vec<u8>> {
 }
 
 pub fn validate_mic_token(raw_token: &[u8], key_usage: i32, params: &EncryptionParams) -> Result<()> {
+    if params.encryption_type == Some(CipherSuite::Rc4Hmac) {
+        gss_rc4::verify_mic_token(
+            get_encryption_key(params)?,
+            key_usage == ACCEPTOR_SIGN,
+            raw_token,
+            &picky_asn1_der::to_vec(&get_mech_list())?,
+        )?;
+
+        return Ok(());
+    }
+
     let token = MicToken::decode(raw_token)?;
 
-    let mut payload = picky_asn1_der::to_vec(&get_mech_list())?;
+    validate_mic_token_checksum(&token, key_usage, picky_asn1_der::to_vec(&get_mech_list())?, params)
+}
+
+fn validate_mic_token_checksum(
+    token: &MicToken,
+    key_usage: i32,
+    mut payload: Vec<u8>,
+    params: &EncryptionParams,
+) -> Result<()> {
     payload.extend_from_slice(&token.header());
 
     // the sub-session key is always preferred over the session key
@@ -46,16 +73,108 @@ pub fn validate_mic_token(raw_token: &[u8], key_usage: i32, params: &EncryptionP
     Ok(())
 }
 
-pub fn generate_initiator_raw(mut payload: Vec<u8>, seq_number: u64, session_key: &[u8]) -> Result<Vec<u8>> {
-    let mut mic_token = MicToken::with_initiator_flags().with_seq_number(seq_number);
+pub fn generate_initiator_raw(payload: Vec<u8>, seq_number: u64, params: &EncryptionParams) -> Result<Vec<u8>> {
+    generate_mic_token_raw(
+        MicToken::with_initiator_flags(),
+        INITIATOR_SIGN,
+        payload,
+        seq_number,
+        params,
+    )
+}
+
+pub fn generate_acceptor_raw(payload: Vec<u8>, seq_number: u64, params: &EncryptionParams) -> Result<Vec<u8>> {
+    generate_mic_token_raw(
+        MicToken::with_acceptor_flags(),
+        ACCEPTOR_SIGN,
+        payload,
+        seq_number,
+        params,
+    )
+}
+
+/// Computes the [MIC token](https://www.rfc-editor.org/rfc/rfc4121#section-4.2.6.1) over the `Data` buffers
+/// of the message and writes it into the `Token` buffer.
+pub fn make_mic_token(
+    message: &mut [SecurityBuffer],
+    mic_token: MicToken,
+    key_usage: i32,
+    seq_number: u64,
+    params: &EncryptionParams,
+) -> Result<()> {
+    let mic_token_raw =
+        generate_mic_token_raw(mic_token, key_usage, extract_data_to_sign(message), seq_number, params)?;
+
+    SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Token)?.write_data(&mic_token_raw)
+}
+
+/// Verifies the MIC token from the `Token` buffer against the `Data` buffers of the message.
+///
+/// `sent_by_acceptor` is the expected direction of the token: the peer of the initiator is the acceptor.
+/// Returns the sequence number of the verified token.
+pub fn verify_mic_token(
+    message: &[SecurityBuffer],
+    sent_by_acceptor: bool,
+    key_usage: i32,
+    params: &EncryptionParams,
+) -> Result<u64> {
+    let raw_token = SecurityBuffer::buf_data(message, SecurityBufferType::Token)?;
+
+    if params.encryption_type == Some(CipherSuite::Rc4Hmac) {
+        let seq_number = gss_rc4::verify_mic_token(
+            get_encryption_key(params)?,
+            sent_by_acceptor,
+            raw_token,
+            &extract_data_to_sign(message),
+        )?;
+
+        return Ok(u64::from(seq_number));
+    }
+
+    let token = MicToken::decode(raw_token)?;
+
+    // [Token Header](https://www.rfc-editor.org/rfc/rfc4121#section-4.2.2):
+    // SentByAcceptor: when set, this flag indicates the sender is the context acceptor.
+    if (token.flags & MIC_TOKEN_FLAG_SENT_BY_ACCEPTOR != 0) != sent_by_acceptor {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            "invalid direction of the mic token",
+        ));
+    }
+
+    validate_mic_token_checksum(&token, key_usage, extract_data_to_sign(message), params)?;
+
+    Ok(token.seq_num)
+}
+
+fn generate_mic_token_raw(
+    mic_token: MicToken,
+    key_usage: i32,
+    mut payload: Vec<u8>,
+    seq_number: u64,
+    params: &EncryptionParams,
+) -> Result<Vec<u8>> {
+    let key = get_encryption_key(params)?;
+
+    if params.encryption_type == Some(CipherSuite::Rc4Hmac) {
+        // RC4-HMAC sequence numbers are 32-bit
+        return gss_rc4::make_mic_token(
+            key,
+            seq_number as u32,
+            mic_token.flags & MIC_TOKEN_FLAG_SENT_BY_ACCEPTOR != 0,
+            &payload,
+        );
+    }
+
+    let mut mic_token = mic_token.with_seq_number(seq_number);
 
     payload.extend_from_slice(&mic_token.header());
 
     mic_token.set_checksum(checksum_sha_aes(
-        session_key,
-        INITIATOR_SIGN,
+        key,
+        key_usage,
         &payload,
-        &AesSize::Aes256,
+        &params.aes_size().unwrap_or(AesSize::Aes256),
     )?);
 
     let mut mic_token_raw = Vec::new();
@@ -64,6 +183,23 @@ pub fn generate_initiator_raw(mut payload: Vec<u8>, seq_number: u64, session_key
     Ok(mic_token_raw)
 }
 
+/// Converts the ASN.1 integer (e.g. key version number or sequence number) into `u32`.
+pub fn integer_as_u32(value: &IntegerAsn1) -> Result<u32> {
+    let bytes = value.as_unsigned_bytes_be();
+
+    if bytes.len() > 4 {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!(
+                "integer value is too big: expected at most 4 bytes but got {}",
+                bytes.len()
+            ),
+        ));
+    }
+
+    Ok(bytes.iter().fold(0, |value, byte| (value << 8) | u32::from(*byte)))
+}
+
 pub fn unwrap_hostname(hostname: Option<&str>) -> Result<String> {
     if let Some(hostname) = hostname {
         Ok(hostname.into())
@@ -71,3 +207,40 @@ pub fn unwrap_hostname(hostname: Option<&str>) -> Result<String> {
         Err(Error::new(ErrorKind::InvalidParameter, "The hostname is not provided"))
     }
 }
+
+/// Writes the file that contains secrets (keys, tickets) so it is readable only by the owner (mode 0600 on Unix).
+///
+/// The data is written to a temporary file first and then renamed, so readers never see a partially written file.
+pub(crate) fn write_private_file(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidParameter, "The path does not contain a file name"))?;
+    let mut tmp_file_name = file_name.to_owned();
+    tmp_file_name.push(format!(".tmp{}", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_file_name);
+
+    let result = write_new_private_file(&tmp_path, write).and_then(|_| Ok(fs::rename(&tmp_path, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+fn write_new_private_file(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+
+    let mut writer = BufWriter::new(options.open(path)?);
+    write(&mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    Ok(())
+}
@@ -85,6 +85,17 @@ impl Krb5Conf {
         None
     }
 
+    /// Returns all values of the repeated key in the order they are listed in the file.
+    pub fn get_values(&self, path: Vec<&str>) -> Vec<&str> {
+        let path = path.join("|");
+
+        self.values
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(&path))
+            .map(|(_, val)| val.as_str())
+            .collect()
+    }
+
     pub fn get_values_in_section(&self, path: &[&str]) -> Option<Vec<(&str, &str)>> {
         let mut values = Vec::new();
 
@@ -67,6 +67,7 @@ pub mod pku2u;
 
 mod auth_identity;
 mod ber;
+mod cert_chain;
 pub mod cert_utils;
 mod crypto;
 mod dns;
@@ -111,8 +112,11 @@ pub use self::kerberos::config::KerberosConfig;
 pub use self::kerberos::{Kerberos, KerberosState, KERBEROS_VERSION};
 pub use self::negotiate::{Negotiate, NegotiateConfig, NegotiatedProtocol};
 pub use self::ntlm::Ntlm;
+pub use self::pk_init::{EcdhCurve, KeyAgreement, PkInitAlgorithms, PkInitDigest};
 pub use self::pku2u::{Pku2u, Pku2uConfig, Pku2uState};
 pub use self::secret::Secret;
+#[cfg(feature = "scard")]
+pub use self::smartcard::SmartCardContextProvider;
 use crate::builders::{
     EmptyAcceptSecurityContext, EmptyAcquireCredentialsHandle, EmptyInitializeSecurityContext,
     InitializeSecurityContext,
@@ -718,6 +722,54 @@ where
     /// * [DecryptMessage function](https://docs.microsoft.com/en-us/windows/win32/api/sspi/nf-sspi-decryptmessage)
     fn decrypt_message(&mut self, message: &mut [SecurityBuffer], sequence_number: u32) -> Result<DecryptionFlags>;
 
+    /// Generates a cryptographic checksum of the message, and also includes sequencing information to prevent message loss or insertion.
+    /// The message itself is not encrypted.
+    ///
+    /// # Parameters
+    ///
+    /// * `flags`: package-specific flags that indicate the quality of protection. Kerberos and NTLM do not use it, so it must be zero
+    /// * `message`: on input, the structure references one or more `SecurityBuffer` structures.
+    ///   All `SecurityBufferType::Data` buffers are signed. The signature is written into the `SecurityBufferType::Token` buffer
+    /// * `sequence_number`: the sequence number that the transport application assigned to the message. If the transport application does not maintain sequence numbers, this parameter must be zero
+    ///
+    /// # Returns
+    ///
+    /// * `()` on success
+    /// * `Error` on error
+    ///
+    /// # MSDN
+    ///
+    /// * [MakeSignature function](https://learn.microsoft.com/en-us/windows/win32/api/sspi/nf-sspi-makesignature)
+    fn make_signature(&mut self, _flags: u32, _message: &mut [SecurityBuffer], _sequence_number: u32) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::UnsupportedFunction,
+            "make_signature is not supported",
+        ))
+    }
+
+    /// Verifies that a message signed by using the `make_signature` function was received in the correct sequence and has not been modified.
+    ///
+    /// # Parameters
+    ///
+    /// * `message`: the structure references one or more `SecurityBuffer` structures: the `SecurityBufferType::Data` buffers
+    ///   with the message and the `SecurityBufferType::Token` buffer with the signature
+    /// * `sequence_number`: the sequence number expected by the transport application, if any. If the transport application does not maintain sequence numbers, this parameter must be zero
+    ///
+    /// # Returns
+    ///
+    /// * package-specific flags that indicate the quality of protection upon success
+    /// * `Error` with `ErrorKind::MessageAltered` if the signature does not match the message
+    ///
+    /// # MSDN
+    ///
+    /// * [VerifySignature function](https://learn.microsoft.com/en-us/windows/win32/api/sspi/nf-sspi-verifysignature)
+    fn verify_signature(&mut self, _message: &[SecurityBuffer], _sequence_number: u32) -> Result<u32> {
+        Err(Error::new(
+            ErrorKind::UnsupportedFunction,
+            "verify_signature is not supported",
+        ))
+    }
+
     /// Retrieves information about the bounds of sizes of authentication information of the current security principal.
     ///
     /// # Returns
@@ -877,6 +929,23 @@ where
         ))
     }
 
+    /// Retrieves the identity of the client authenticated by the acceptor. This function is implemented
+    /// for the acceptor side of the NTLM, Kerberos, and Negotiate security packages.
+    ///
+    /// Unlike `query_context_names`, the returned identity is taken from the authentication exchange itself:
+    /// the AUTHENTICATE message for NTLM and the decrypted service ticket for Kerberos.
+    ///
+    /// # Returns
+    ///
+    /// * `ContextClientIdentity` on success
+    /// * `Error` if the authentication is not complete or the context is not an acceptor
+    fn query_context_client_identity(&mut self) -> Result<ContextClientIdentity> {
+        Err(Error::new(
+            ErrorKind::UnsupportedFunction,
+            "query_context_client_identity is not supported",
+        ))
+    }
+
     /// Changes the password for a Windows domain account.
     ///
     /// # Returns
@@ -1080,7 +1149,7 @@ bitflags! {
         const DELEGATE = 0x1;
         /// The mutual authentication policy of the service will be satisfied.
         const MUTUAL_AUTH = 0x2;
-        /// Detect replayed messages that have been encoded by using the `encrypt_message` or `make_signature` (TBI) functions.
+        /// Detect replayed messages that have been encoded by using the `encrypt_message` or `make_signature` functions.
         const REPLAY_DETECT = 0x4;
         /// Detect messages received out of sequence.
         const SEQUENCE_DETECT = 0x8;
@@ -1103,7 +1172,7 @@ bitflags! {
         const EXTENDED_ERROR = 0x4000;
         /// Support a stream-oriented connection.
         const STREAM = 0x8000;
-        /// Sign messages and verify signatures by using the `encrypt_message` and `make_signature` (TBI) functions.
+        /// Sign messages and verify signatures by using the `encrypt_message` and `make_signature` functions.
         const INTEGRITY = 0x0001_0000;
         const IDENTIFY = 0x0002_0000;
         const NULL_SESSION = 0x0004_0000;
@@ -1178,7 +1247,7 @@ bitflags! {
         const DELEGATE = 0x1;
         /// The mutual authentication policy of the service will be satisfied.
         const MUTUAL_AUTH = 0x2;
-        /// Detect replayed messages that have been encoded by using the `encrypt_message` or `make_signature` (TBI) functions.
+        /// Detect replayed messages that have been encoded by using the `encrypt_message` or `make_signature` functions.
         const REPLAY_DETECT = 0x4;
         /// Detect messages received out of sequence.
         const SEQUENCE_DETECT = 0x8;
@@ -1201,7 +1270,7 @@ bitflags! {
         const EXTENDED_ERROR = 0x4000;
         /// Support a stream-oriented connection.
         const STREAM = 0x8000;
-        /// Sign messages and verify signatures by using the `encrypt_message` and `make_signature` (TBI) functions.
+        /// Sign messages and verify signatures by using the `encrypt_message` and `make_signature` functions.
         const INTEGRITY = 0x0001_0000;
         const IDENTIFY = 0x0002_0000;
         const NULL_SESSION = 0x0004_0000;
@@ -1467,7 +1536,7 @@ bitflags! {
     /// * [SecPkgInfoW structure (`fCapabilities` parameter)](https://docs.microsoft.com/en-us/windows/win32/api/sspi/ns-sspi-secpkginfow)
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct PackageCapabilities: u32 {
-        /// The security package supports the `make_signature` (TBI) and `verify_signature` (TBI) functions.
+        /// The security package supports the `make_signature` and `verify_signature` functions.
         const INTEGRITY = 0x1;
         /// The security package supports the `encrypt_message` and `decrypt_message` functions.
         const PRIVACY = 0x2;
@@ -1694,6 +1763,24 @@ pub struct ContextNames {
     pub username: Username,
 }
 
+/// Identity of the client authenticated by the acceptor.
+/// `query_context_client_identity` function returns this structure.
+#[derive(Debug, Clone)]
+pub struct ContextClientIdentity {
+    /// Client principal name: the NTLM user name or the Kerberos `cname`.
+    pub principal: String,
+    /// Client domain: the NTLM domain name or the Kerberos `crealm`.
+    pub realm: String,
+    /// Security package that authenticated the client.
+    pub package: SecurityPackageType,
+    /// Logon time: the completion of the NTLM authentication or the Kerberos ticket `authtime`.
+    pub logon_time: time::OffsetDateTime,
+    /// The integrity of the authentication exchange was verified: the NTLM MIC or the SPNEGO `mechListMIC`.
+    pub mic_verified: bool,
+    /// The client's channel bindings were verified against the acceptor's channel bindings.
+    pub channel_bindings_verified: bool,
+}
+
 /// The kind of an SSPI related error. Enables to specify an error based on its type.
 ///
 /// [SSPI Status Codes](https://learn.microsoft.com/en-us/windows/win32/secauthn/sspi-status-codes).
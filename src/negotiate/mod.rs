@@ -0,0 +1,1226 @@
+mod spnego;
+
+use std::fmt::Debug;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+
+use oid::ObjectIdentifier;
+use url::Url;
+
+use self::spnego::{
+    add_mech_list_mic, decode_initial_token, decode_response_token, encode_response_token, InitialToken, NegState,
+    ResponseToken, SpnegoAcceptor, SpnegoMech,
+};
+use crate::generator::{GeneratorChangePassword, GeneratorInitSecurityContext, YieldPointLocal};
+use crate::kdc::detect_kdc_url;
+use crate::kerberos::client::generators::get_client_principal_realm;
+use crate::kerberos::kdc_certificate::KdcTrustStore;
+use crate::ntlm::NtlmConfig;
+#[allow(unused)]
+use crate::utils::is_azure_ad_domain;
+#[cfg(feature = "scard")]
+use crate::SmartCardContextProvider;
+use crate::{
+    builders, kerberos, ntlm, pku2u, AcceptSecurityContextResult, AcquireCredentialsHandleResult, AuthIdentity,
+    CertTrustStatus, ContextClientIdentity, ContextNames, ContextSizes, CredentialUse, Credentials, CredentialsBuffers,
+    DecryptionFlags, Error, ErrorKind, InitializeSecurityContextResult, Kerberos, KerberosConfig, Ntlm,
+    OwnedSecurityBuffer, PackageCapabilities, PackageInfo, Pku2u, Result, SecurityBuffer, SecurityBufferType,
+    SecurityPackageType, SecurityStatus, ServerResponseFlags, Sspi, SspiEx, SspiImpl, PACKAGE_ID_NONE,
+};
+
+pub const PKG_NAME: &str = "Negotiate";
+
+pub static PACKAGE_INFO: LazyLock<PackageInfo> = LazyLock::new(|| PackageInfo {
+    capabilities: PackageCapabilities::empty(),
+    rpc_id: PACKAGE_ID_NONE,
+    max_token_len: 0xbb80, // 48 000 bytes: default maximum token len in Windows
+    name: SecurityPackageType::Negotiate,
+    comment: String::from("Microsoft Package Negotiator"),
+});
+
+pub trait ProtocolConfig: Debug + Send + Sync {
+    fn new_client(&self) -> Result<NegotiatedProtocol>;
+
+    /// Creates the acceptor of the configured security package.
+    fn new_server(&self) -> Result<NegotiatedProtocol> {
+        Err(Error::new(
+            ErrorKind::UnsupportedFunction,
+            "the security package cannot be used as the acceptor",
+        ))
+    }
+
+    fn box_clone(&self) -> Box<dyn ProtocolConfig>;
+}
+
+impl Clone for Box<dyn ProtocolConfig> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct NegotiateConfig {
+    pub protocol_config: Box<dyn ProtocolConfig>,
+    pub package_list: Option<String>,
+    /// Computer name, or "workstation name", of the client machine performing the authentication attempt
+    ///
+    /// This is also referred to as the "Source Workstation", i.e.: the name of the computer attempting to logon.
+    pub client_computer_name: String,
+    /// Trust anchors of the KDC certificate passed to the Kerberos client (see [KerberosConfig::kdc_trust_store])
+    pub kdc_trust_store: Option<KdcTrustStore>,
+    /// Smart card resource manager context passed to the Kerberos client (see [KerberosConfig::scard_context])
+    #[cfg(feature = "scard")]
+    pub scard_context: Option<SmartCardContextProvider>,
+}
+
+impl NegotiateConfig {
+    /// package_list format, "kerberos,ntlm,pku2u"
+    pub fn new(
+        protocol_config: Box<dyn ProtocolConfig + Send>,
+        package_list: Option<String>,
+        client_computer_name: String,
+    ) -> Self {
+        Self {
+            protocol_config,
+            package_list,
+            client_computer_name,
+            kdc_trust_store: None,
+            #[cfg(feature = "scard")]
+            scard_context: None,
+        }
+    }
+
+    pub fn from_protocol_config(protocol_config: Box<dyn ProtocolConfig + Send>, client_computer_name: String) -> Self {
+        Self {
+            protocol_config,
+            package_list: None,
+            client_computer_name,
+            kdc_trust_store: None,
+            #[cfg(feature = "scard")]
+            scard_context: None,
+        }
+    }
+}
+
+impl Clone for NegotiateConfig {
+    fn clone(&self) -> Self {
+        Self {
+            protocol_config: self.protocol_config.box_clone(),
+            package_list: None,
+            client_computer_name: self.client_computer_name.clone(),
+            kdc_trust_store: self.kdc_trust_store.clone(),
+            #[cfg(feature = "scard")]
+            scard_context: self.scard_context.clone(),
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum NegotiatedProtocol {
+    Pku2u(Pku2u),
+    Kerberos(Kerberos),
+    Ntlm(Ntlm),
+}
+
+impl NegotiatedProtocol {
+    pub fn protocol_name(&self) -> &str {
+        match self {
+            NegotiatedProtocol::Pku2u(_) => pku2u::PKG_NAME,
+            NegotiatedProtocol::Kerberos(_) => kerberos::PKG_NAME,
+            NegotiatedProtocol::Ntlm(_) => ntlm::PKG_NAME,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Negotiate {
+    protocol: NegotiatedProtocol,
+    protocol_config: Box<dyn ProtocolConfig>,
+    package_list: Option<String>,
+    auth_identity: Option<CredentialsBuffers>,
+    client_computer_name: String,
+    spnego: Option<SpnegoAcceptor>,
+    kdc_trust_store: Option<KdcTrustStore>,
+    #[cfg(feature = "scard")]
+    scard_context: Option<SmartCardContextProvider>,
+}
+
+struct PackageListConfig {
+    ntlm: bool,
+    kerberos: bool,
+    pku2u: bool,
+}
+
+impl Negotiate {
+    pub fn new(config: NegotiateConfig) -> Result<Self> {
+        let mut negotiate = Negotiate {
+            protocol: config.protocol_config.new_client()?,
+            protocol_config: config.protocol_config,
+            package_list: config.package_list,
+            auth_identity: None,
+            client_computer_name: config.client_computer_name,
+            spnego: None,
+            kdc_trust_store: config.kdc_trust_store,
+            #[cfg(feature = "scard")]
+            scard_context: config.scard_context,
+        };
+
+        if let Some(filtered_protocol) = negotiate.filter_protocol()? {
+            negotiate.protocol = filtered_protocol;
+        }
+
+        Ok(negotiate)
+    }
+
+    // negotiates the authorization protocol based on the username and the domain
+    // Decision rules:
+    // 1) if `self.protocol` is not NTLM then we've already negotiated a suitable protocol. Nothing to do.
+    // 2) if the provided domain is Azure AD domain then it'll use Pku2u
+    // 3) if the provided username is FQDN and we can resolve KDC then it'll use Kerberos
+    // 4) if SSPI_KDC_URL_ENV is set then it'll also use Kerberos
+    // 5) in any other cases, it'll use NTLM
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip(self))]
+    fn negotiate_protocol(&mut self, username: &str, domain: &str) -> Result<()> {
+        if let NegotiatedProtocol::Ntlm(_) = &self.protocol {
+            #[cfg(target_os = "windows")]
+            if is_azure_ad_domain(domain) {
+                use super::pku2u::Pku2uConfig;
+
+                info!("Negotiate: try Pku2u");
+
+                self.protocol = NegotiatedProtocol::Pku2u(Pku2u::new_client_from_config(
+                    Pku2uConfig::default_client_config(self.client_computer_name.clone())?,
+                )?);
+            }
+
+            if let Some(host) = detect_kdc_url(&get_client_principal_realm(username, domain)) {
+                info!("Negotiate: try Kerberos");
+
+                self.protocol =
+                    NegotiatedProtocol::Kerberos(Kerberos::new_client_from_config(self.kerberos_config(Some(host)))?);
+            }
+        }
+
+        if let Some(filtered_protocol) = self.filter_protocol()? {
+            self.protocol = filtered_protocol;
+        }
+
+        Ok(())
+    }
+
+    fn parse_package_list_config(package_list: &Option<String>) -> PackageListConfig {
+        let mut ntlm: bool = true;
+        let mut kerberos: bool = true;
+        let mut pku2u: bool = true;
+
+        if let Some(package_list) = &package_list {
+            for package in package_list.split(',') {
+                let (package_name, enabled) = if let Some(package_name) = package.strip_prefix('!') {
+                    (package_name.to_lowercase(), false)
+                } else {
+                    let package_name = package.to_lowercase();
+                    (package_name, true)
+                };
+
+                match package_name.as_str() {
+                    "ntlm" => ntlm = enabled,
+                    "kerberos" => kerberos = enabled,
+                    "pku2u" => pku2u = enabled,
+                    _ => eprintln!("unexpected package name: {}", &package_name),
+                }
+            }
+        }
+
+        PackageListConfig { ntlm, kerberos, pku2u }
+    }
+
+    fn kerberos_config(&self, kdc_url: Option<Url>) -> KerberosConfig {
+        KerberosConfig {
+            kdc_url,
+            client_computer_name: Some(self.client_computer_name.clone()),
+            server_properties: None,
+            ticket_cache: None,
+            allow_rc4_hmac: false,
+            fast: None,
+            pkinit: Default::default(),
+            kdc_trust_store: self.kdc_trust_store.clone(),
+            allow_unvalidated_kdc_certificate: false,
+            #[cfg(feature = "scard")]
+            scard_context: self.scard_context.clone(),
+        }
+    }
+
+    fn filter_protocol(&self) -> Result<Option<NegotiatedProtocol>> {
+        let mut filtered_protocol = None;
+        let PackageListConfig {
+            ntlm: is_ntlm,
+            kerberos: is_kerberos,
+            pku2u: is_pku2u,
+        } = Self::parse_package_list_config(&self.package_list);
+
+        match &self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => {
+                if !is_pku2u {
+                    let ntlm_config = NtlmConfig::new(pku2u.config().client_hostname.clone());
+                    filtered_protocol = Some(NegotiatedProtocol::Ntlm(Ntlm::with_config(ntlm_config)));
+                }
+            }
+            NegotiatedProtocol::Kerberos(kerberos) => {
+                if !is_kerberos {
+                    let ntlm_config = kerberos
+                        .config()
+                        .client_computer_name
+                        .clone()
+                        .map(NtlmConfig::new)
+                        .unwrap_or_default();
+                    filtered_protocol = Some(NegotiatedProtocol::Ntlm(Ntlm::with_config(ntlm_config)));
+                }
+            }
+            NegotiatedProtocol::Ntlm(_) => {
+                if !is_ntlm {
+                    let kerberos_client = Kerberos::new_client_from_config(self.kerberos_config(None))?;
+                    filtered_protocol = Some(NegotiatedProtocol::Kerberos(kerberos_client));
+                }
+            }
+        }
+
+        Ok(filtered_protocol)
+    }
+
+    fn is_protocol_ntlm(&self) -> bool {
+        matches!(&self.protocol, NegotiatedProtocol::Ntlm(_))
+    }
+
+    fn can_downgrade_ntlm(&self) -> bool {
+        let package_list = Self::parse_package_list_config(&self.package_list);
+        package_list.ntlm
+    }
+
+    fn is_target_name_ip_address(address: &str) -> bool {
+        let stripped_address = address.split('/').last().unwrap_or(address);
+        stripped_address.parse::<IpAddr>().is_ok()
+    }
+
+    fn check_target_name_for_ntlm_downgrade(&mut self, target_name: &str) {
+        let should_downgrade = Self::is_target_name_ip_address(target_name);
+        let can_downgrade = self.can_downgrade_ntlm();
+
+        if can_downgrade && should_downgrade && !self.is_protocol_ntlm() {
+            let ntlm_config = NtlmConfig::new(self.client_computer_name.clone());
+            self.protocol = NegotiatedProtocol::Ntlm(Ntlm::with_config(ntlm_config));
+        }
+    }
+}
+
+impl SspiEx for Negotiate {
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn custom_set_auth_identity(&mut self, identity: Self::AuthenticationData) -> Result<()> {
+        self.auth_identity = Some(identity.clone().try_into().unwrap());
+
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => {
+                pku2u.custom_set_auth_identity(identity.auth_identity().ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::IncompleteCredentials,
+                        "Provided credentials are not password-based",
+                    )
+                })?)
+            }
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.custom_set_auth_identity(identity),
+            NegotiatedProtocol::Ntlm(ntlm) => {
+                ntlm.custom_set_auth_identity(identity.auth_identity().ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::IncompleteCredentials,
+                        "Provided credentials are not password-based",
+                    )
+                })?)
+            }
+        }
+    }
+}
+
+impl Sspi for Negotiate {
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip(self))]
+    fn complete_auth_token(&mut self, token: &mut [OwnedSecurityBuffer]) -> Result<SecurityStatus> {
+        let status = match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.complete_auth_token(token),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.complete_auth_token(token),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.complete_auth_token(token),
+        }?;
+
+        // the initiator's mechListMIC can be verified only when the security context keys are established
+        if let Some(mic) = self.spnego.as_mut().and_then(|spnego| spnego.pending_mic.take()) {
+            self.verify_mech_list_mic(mic)?;
+
+            // the acceptor's mechListMIC is added to the final `NegTokenResp` returned by the `accept_security_context`
+            let acceptor_mic = self.make_mech_list_mic()?;
+            match OwnedSecurityBuffer::find_buffer_mut(token, SecurityBufferType::Token) {
+                Ok(output_token) => {
+                    output_token.buffer = add_mech_list_mic(&output_token.buffer, acceptor_mic)?;
+
+                    if let Some(spnego) = self.spnego.as_mut() {
+                        spnego.mic_sent = true;
+                    }
+                }
+                Err(_) => warn!("The output token is not provided: the acceptor's mechListMIC is not sent"),
+            }
+        }
+
+        Ok(status)
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn encrypt_message(
+        &mut self,
+        flags: crate::EncryptionFlags,
+        message: &mut [SecurityBuffer],
+        sequence_number: u32,
+    ) -> Result<SecurityStatus> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.encrypt_message(flags, message, sequence_number),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.encrypt_message(flags, message, sequence_number),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.encrypt_message(flags, message, sequence_number),
+        }
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn decrypt_message<'data>(
+        &mut self,
+        message: &mut [SecurityBuffer<'data>],
+        sequence_number: u32,
+    ) -> Result<DecryptionFlags> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.decrypt_message(message, sequence_number),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.decrypt_message(message, sequence_number),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.decrypt_message(message, sequence_number),
+        }
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn make_signature(&mut self, flags: u32, message: &mut [SecurityBuffer], sequence_number: u32) -> Result<()> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.make_signature(flags, message, sequence_number),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.make_signature(flags, message, sequence_number),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.make_signature(flags, message, sequence_number),
+        }
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn verify_signature(&mut self, message: &[SecurityBuffer], sequence_number: u32) -> Result<u32> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.verify_signature(message, sequence_number),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.verify_signature(message, sequence_number),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.verify_signature(message, sequence_number),
+        }
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn query_context_sizes(&mut self) -> Result<ContextSizes> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.query_context_sizes(),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.query_context_sizes(),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.query_context_sizes(),
+        }
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn query_context_names(&mut self) -> Result<ContextNames> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.query_context_names(),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.query_context_names(),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.query_context_names(),
+        }
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn query_context_client_identity(&mut self) -> Result<ContextClientIdentity> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.query_context_client_identity(),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.query_context_client_identity(),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.query_context_client_identity(),
+        }
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn query_context_package_info(&mut self) -> Result<PackageInfo> {
+        crate::query_security_package_info(SecurityPackageType::Negotiate)
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn query_context_negotiation_package(&mut self) -> Result<PackageInfo> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.query_context_package_info(),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.query_context_package_info(),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.query_context_package_info(),
+        }
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn query_context_cert_trust_status(&mut self) -> Result<CertTrustStatus> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => pku2u.query_context_cert_trust_status(),
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.query_context_cert_trust_status(),
+            NegotiatedProtocol::Ntlm(ntlm) => ntlm.query_context_cert_trust_status(),
+        }
+    }
+
+    fn change_password<'a>(
+        &'a mut self,
+        change_password: builders::ChangePassword<'a>,
+    ) -> Result<GeneratorChangePassword> {
+        Ok(GeneratorChangePassword::new(move |mut yield_point| async move {
+            self.change_password(&mut yield_point, change_password).await
+        }))
+    }
+}
+
+impl SspiImpl for Negotiate {
+    type CredentialsHandle = Option<CredentialsBuffers>;
+    type AuthenticationData = Credentials;
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn acquire_credentials_handle_impl(
+        &mut self,
+        builder: builders::FilledAcquireCredentialsHandle<'_, Self::CredentialsHandle, Self::AuthenticationData>,
+    ) -> Result<AcquireCredentialsHandleResult<Self::CredentialsHandle>> {
+        if builder.credential_use == CredentialUse::Outbound && builder.auth_data.is_none() {
+            return Err(Error::new(
+                ErrorKind::NoCredentials,
+                "The client must specify the auth data",
+            ));
+        }
+
+        if let Some(Credentials::AuthIdentity(identity)) = builder.auth_data {
+            let account_name = identity.username.account_name();
+            let domain_name = identity.username.domain_name().unwrap_or("");
+            self.negotiate_protocol(account_name, domain_name)?;
+        }
+
+        self.auth_identity = builder
+            .auth_data
+            .cloned()
+            .map(|auth_data| auth_data.try_into())
+            .transpose()?;
+
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => {
+                let auth_identity = if let Some(Credentials::AuthIdentity(identity)) = builder.auth_data {
+                    identity
+                } else {
+                    return Err(Error::new(
+                        ErrorKind::NoCredentials,
+                        "Auth identity is not provided for the Pku2u",
+                    ));
+                };
+                let new_builder = builder.full_transform(Some(auth_identity));
+                new_builder.execute(pku2u)?;
+            }
+            NegotiatedProtocol::Kerberos(kerberos) => {
+                kerberos.acquire_credentials_handle_impl(builder)?;
+            }
+            NegotiatedProtocol::Ntlm(ntlm) => {
+                let auth_identity = if builder.credential_use == CredentialUse::Outbound {
+                    if let Some(Credentials::AuthIdentity(identity)) = builder.auth_data {
+                        Some(identity)
+                    } else {
+                        return Err(Error::new(
+                            ErrorKind::NoCredentials,
+                            "Auth identity is not provided for the Ntlm",
+                        ));
+                    }
+                } else {
+                    None
+                };
+                let new_builder = builder.full_transform(auth_identity);
+                new_builder.execute(ntlm)?;
+            }
+        };
+
+        Ok(AcquireCredentialsHandleResult {
+            credentials_handle: self.auth_identity.clone(),
+            expiry: None,
+        })
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    fn accept_security_context_impl(
+        &mut self,
+        builder: builders::FilledAcceptSecurityContext<'_, Self::CredentialsHandle>,
+    ) -> Result<AcceptSecurityContextResult> {
+        let input = builder
+            .input
+            .as_deref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Input buffers must be specified"))?;
+        let input_token = OwnedSecurityBuffer::find_buffer(input, SecurityBufferType::Token)?;
+
+        if self.spnego.is_some() {
+            let response_token = decode_response_token(&input_token.buffer)?;
+
+            return self.accept_response_token(builder, response_token);
+        }
+
+        match decode_initial_token(&input_token.buffer)? {
+            Some(initial_token) => self.accept_initial_token(builder, initial_token),
+            // the token is not wrapped in SPNEGO: it is passed directly to the security package
+            None => self.accept_mech_token(builder),
+        }
+    }
+
+    fn initialize_security_context_impl<'a>(
+        &'a mut self,
+        builder: &'a mut builders::FilledInitializeSecurityContext<Self::CredentialsHandle>,
+    ) -> Result<GeneratorInitSecurityContext> {
+        Ok(GeneratorInitSecurityContext::new(move |mut yield_point| async move {
+            self.initialize_security_context_impl(&mut yield_point, builder).await
+        }))
+    }
+}
+
+impl Negotiate {
+    fn accept_mech_token(
+        &mut self,
+        builder: builders::FilledAcceptSecurityContext<'_, <Self as SspiImpl>::CredentialsHandle>,
+    ) -> Result<AcceptSecurityContextResult> {
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => {
+                let mut creds_handle = if let Some(creds_handle) = &builder.credentials_handle {
+                    creds_handle.as_ref().and_then(|c| c.clone().auth_identity())
+                } else {
+                    None
+                };
+                let new_builder = builder.full_transform(Some(&mut creds_handle));
+                new_builder.execute(pku2u)
+            }
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.accept_security_context_impl(builder),
+            NegotiatedProtocol::Ntlm(ntlm) => {
+                let mut creds_handle = if let Some(creds_handle) = &builder.credentials_handle {
+                    creds_handle.as_ref().and_then(|c| c.clone().auth_identity())
+                } else {
+                    None
+                };
+                let new_builder = builder.full_transform(Some(&mut creds_handle));
+                new_builder.execute(ntlm)
+            }
+        }
+    }
+
+    /// Selects the first mechanism from the initiator's preference list that is enabled on the acceptor.
+    fn select_server_protocol(
+        &self,
+        mech_types: &[ObjectIdentifier],
+    ) -> Result<Option<(ObjectIdentifier, NegotiatedProtocol)>> {
+        let packages = Self::parse_package_list_config(&self.package_list);
+        let configured = self.protocol_config.new_server()?;
+
+        let selected = mech_types.iter().find_map(|mech_id| {
+            let mech = SpnegoMech::from_oid(mech_id)?;
+            let is_enabled = match mech {
+                SpnegoMech::Kerberos => {
+                    packages.kerberos
+                        && matches!(
+                            &configured,
+                            NegotiatedProtocol::Kerberos(kerberos) if kerberos.config().server_properties.is_some()
+                        )
+                }
+                SpnegoMech::Pku2u => packages.pku2u && matches!(&configured, NegotiatedProtocol::Pku2u(_)),
+                SpnegoMech::Ntlm => packages.ntlm && matches!(&configured, NegotiatedProtocol::Ntlm(_)),
+            };
+
+            is_enabled.then(|| mech_id.clone())
+        });
+
+        // only the configured security package is offered to the initiator
+        Ok(selected.map(|mech_id| (mech_id, configured)))
+    }
+
+    fn accept_initial_token(
+        &mut self,
+        builder: builders::FilledAcceptSecurityContext<'_, <Self as SspiImpl>::CredentialsHandle>,
+        initial_token: InitialToken,
+    ) -> Result<AcceptSecurityContextResult> {
+        let InitialToken {
+            mech_types,
+            mech_types_raw,
+            mech_token,
+            mech_list_mic,
+        } = initial_token;
+
+        let (mech_id, protocol) = match self.select_server_protocol(&mech_types)? {
+            Some(selected) => selected,
+            None => {
+                let output_token = OwnedSecurityBuffer::find_buffer_mut(builder.output, SecurityBufferType::Token)?;
+                output_token
+                    .buffer
+                    .write_all(&encode_response_token(NegState::Reject, None, None, None)?)?;
+
+                return Err(Error::new(
+                    ErrorKind::SecurityPackageNotFound,
+                    format!("none of the initiator's mechanisms is enabled: {:?}", mech_types),
+                ));
+            }
+        };
+        info!(
+            protocol = protocol.protocol_name(),
+            "Negotiate: SPNEGO mechanism is selected"
+        );
+
+        // https://www.rfc-editor.org/rfc/rfc4178#section-5
+        // If the selected mechanism is not the initiator's preferred one, the optimistic token is discarded
+        // and the MIC exchange is required to protect the negotiation.
+        let mic_required = mech_types.first() != Some(&mech_id);
+
+        self.protocol = protocol;
+        self.spnego = Some(SpnegoAcceptor {
+            mech_types: mech_types_raw,
+            mic_required,
+            mech_complete: false,
+            mic_sent: false,
+            pending_mic: None,
+            flags: ServerResponseFlags::empty(),
+        });
+
+        match mech_token {
+            Some(mech_token) if !mic_required => {
+                self.accept_spnego_token(builder, Some(mech_token), mech_list_mic, Some(mech_id))
+            }
+            _ => {
+                let neg_state = if mic_required {
+                    NegState::RequestMic
+                } else {
+                    NegState::AcceptIncomplete
+                };
+
+                let output_token = OwnedSecurityBuffer::find_buffer_mut(builder.output, SecurityBufferType::Token)?;
+                output_token
+                    .buffer
+                    .write_all(&encode_response_token(neg_state, Some(mech_id), None, None)?)?;
+
+                Ok(AcceptSecurityContextResult {
+                    status: SecurityStatus::ContinueNeeded,
+                    flags: ServerResponseFlags::empty(),
+                    expiry: None,
+                })
+            }
+        }
+    }
+
+    fn accept_response_token(
+        &mut self,
+        builder: builders::FilledAcceptSecurityContext<'_, <Self as SspiImpl>::CredentialsHandle>,
+        response_token: ResponseToken,
+    ) -> Result<AcceptSecurityContextResult> {
+        let ResponseToken {
+            response_token,
+            mech_list_mic,
+        } = response_token;
+
+        self.accept_spnego_token(builder, response_token, mech_list_mic, None)
+    }
+
+    /// Passes the mechanism token to the selected security package and wraps its output in the `NegTokenResp`.
+    ///
+    /// The `mech_token` can be omitted only in the last initiator's message containing just the `mechListMIC`.
+    fn accept_spnego_token(
+        &mut self,
+        mut builder: builders::FilledAcceptSecurityContext<'_, <Self as SspiImpl>::CredentialsHandle>,
+        mech_token: Option<Vec<u8>>,
+        mech_list_mic: Option<Vec<u8>>,
+        supported_mech: Option<ObjectIdentifier>,
+    ) -> Result<AcceptSecurityContextResult> {
+        let spnego = self
+            .spnego
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::OutOfSequence, "SPNEGO negotiation is not started"))?;
+        let (mic_required, mic_sent) = (spnego.mic_required, spnego.mic_sent);
+
+        let output = std::mem::take(&mut builder.output);
+
+        let (status, flags, response_token) = match mech_token {
+            Some(_) if spnego.mech_complete => {
+                return Err(Error::new(
+                    ErrorKind::OutOfSequence,
+                    "the security context is already accepted by the selected mechanism",
+                ));
+            }
+            Some(mech_token) => {
+                let mut input = builder.input.as_deref().unwrap_or_default().to_vec();
+                OwnedSecurityBuffer::find_buffer_mut(&mut input, SecurityBufferType::Token)?.buffer = mech_token;
+                let mut mech_output = vec![OwnedSecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
+
+                let result = self.accept_mech_token(builder.buffers_transform(&mut input, &mut mech_output))?;
+
+                let response_token = OwnedSecurityBuffer::find_buffer_mut(&mut mech_output, SecurityBufferType::Token)?;
+                let response_token = Some(std::mem::take(&mut response_token.buffer)).filter(|token| !token.is_empty());
+
+                (result.status, result.flags, response_token)
+            }
+            None if spnego.mech_complete && mech_list_mic.is_some() => (SecurityStatus::Ok, spnego.flags, None),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidToken,
+                    "NegTokenResp contains neither responseToken nor mechListMIC",
+                ));
+            }
+        };
+
+        let mech_complete = matches!(status, SecurityStatus::Ok | SecurityStatus::CompleteNeeded);
+
+        let (neg_state, acceptor_mic, status) = match status {
+            SecurityStatus::Ok => {
+                let initiator_mic_verified = if let Some(mic) = mech_list_mic {
+                    self.verify_mech_list_mic(mic)?;
+                    true
+                } else {
+                    false
+                };
+
+                if initiator_mic_verified || !mic_required {
+                    // the acceptor's mechListMIC is sent back only in response to the initiator's one
+                    let acceptor_mic = if initiator_mic_verified && !mic_sent {
+                        Some(self.make_mech_list_mic()?)
+                    } else {
+                        None
+                    };
+
+                    (NegState::AcceptCompleted, acceptor_mic, SecurityStatus::Ok)
+                } else if mic_sent {
+                    return Err(Error::new(ErrorKind::InvalidToken, "mechListMIC is required"));
+                } else {
+                    (
+                        NegState::AcceptIncomplete,
+                        Some(self.make_mech_list_mic()?),
+                        SecurityStatus::ContinueNeeded,
+                    )
+                }
+            }
+            SecurityStatus::CompleteNeeded => {
+                if mic_required && mech_list_mic.is_none() {
+                    return Err(Error::new(ErrorKind::InvalidToken, "mechListMIC is required"));
+                }
+
+                // the security context keys are not established yet: the initiator's mechListMIC is verified
+                // in `complete_auth_token` which also adds the acceptor's mechListMIC to the output token
+                if let Some(spnego) = self.spnego.as_mut() {
+                    spnego.pending_mic = mech_list_mic;
+                }
+
+                (NegState::AcceptCompleted, None, SecurityStatus::CompleteNeeded)
+            }
+            status => (NegState::AcceptIncomplete, None, status),
+        };
+
+        if let Some(spnego) = self.spnego.as_mut() {
+            spnego.flags = flags;
+            spnego.mech_complete |= mech_complete;
+            spnego.mic_sent |= acceptor_mic.is_some();
+        }
+
+        let output_token = OwnedSecurityBuffer::find_buffer_mut(output, SecurityBufferType::Token)?;
+        output_token.buffer.write_all(&encode_response_token(
+            neg_state,
+            supported_mech,
+            response_token,
+            acceptor_mic,
+        )?)?;
+
+        Ok(AcceptSecurityContextResult {
+            status,
+            flags,
+            expiry: None,
+        })
+    }
+
+    /// Calculates the acceptor's [mechListMIC](https://www.rfc-editor.org/rfc/rfc4178#section-5).
+    fn make_mech_list_mic(&mut self) -> Result<Vec<u8>> {
+        let mut mech_types = self
+            .spnego
+            .as_ref()
+            .map(|spnego| spnego.mech_types.clone())
+            .unwrap_or_default();
+        let mut token = vec![0; self.query_context_sizes()?.max_signature as usize];
+
+        let mut message = [
+            SecurityBuffer::Data(mech_types.as_mut_slice()),
+            SecurityBuffer::Token(token.as_mut_slice()),
+        ];
+        self.make_signature(0, &mut message, 0)?;
+
+        Ok(message[1].data().to_vec())
+    }
+
+    /// Verifies the initiator's [mechListMIC](https://www.rfc-editor.org/rfc/rfc4178#section-5).
+    fn verify_mech_list_mic(&mut self, mut mic: Vec<u8>) -> Result<()> {
+        let mut mech_types = self
+            .spnego
+            .as_ref()
+            .map(|spnego| spnego.mech_types.clone())
+            .unwrap_or_default();
+
+        let message = [
+            SecurityBuffer::Data(mech_types.as_mut_slice()),
+            SecurityBuffer::Token(mic.as_mut_slice()),
+        ];
+        self.verify_signature(&message, 0)?;
+
+        Ok(())
+    }
+}
+
+impl<'a> Negotiate {
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    pub(crate) async fn change_password(
+        &'a mut self,
+        yield_point: &mut YieldPointLocal,
+        change_password: builders::ChangePassword<'a>,
+    ) -> Result<()> {
+        self.negotiate_protocol(&change_password.account_name, &change_password.domain_name)?;
+
+        match &mut self.protocol {
+            NegotiatedProtocol::Kerberos(kerberos) => kerberos.change_password(yield_point, change_password).await,
+            _ => Err(crate::Error::new(
+                ErrorKind::UnsupportedFunction,
+                "cannot change password for this protocol",
+            )),
+        }
+    }
+
+    #[instrument(ret, fields(protocol = self.protocol.protocol_name()), skip_all)]
+    pub(crate) async fn initialize_security_context_impl(
+        &'a mut self,
+        yield_point: &mut YieldPointLocal,
+        builder: &'a mut builders::FilledInitializeSecurityContext<'_, <Self as SspiImpl>::CredentialsHandle>,
+    ) -> Result<InitializeSecurityContextResult> {
+        if let Some(target_name) = &builder.target_name {
+            self.check_target_name_for_ntlm_downgrade(target_name);
+        }
+
+        if let Some(Some(CredentialsBuffers::AuthIdentity(identity))) = builder.credentials_handle {
+            let auth_identity =
+                AuthIdentity::try_from(&*identity).map_err(|e| Error::new(ErrorKind::InvalidParameter, e))?;
+            let account_name = auth_identity.username.account_name();
+            let domain_name = auth_identity.username.domain_name().unwrap_or("");
+            self.negotiate_protocol(account_name, domain_name)?;
+            self.auth_identity = Some(CredentialsBuffers::AuthIdentity(auth_identity.into()));
+        }
+
+        if let Some(Some(CredentialsBuffers::SmartCard(identity))) = builder.credentials_handle {
+            if let NegotiatedProtocol::Ntlm(_) = &self.protocol {
+                let username = crate::utils::bytes_to_utf16_string(&identity.username);
+                let host = detect_kdc_url(&get_client_principal_realm(&username, ""))
+                    .ok_or_else(|| Error::new(ErrorKind::NoAuthenticatingAuthority, "can not detect KDC url"))?;
+                info!("Negotiate: try Kerberos");
+
+                self.protocol =
+                    NegotiatedProtocol::Kerberos(Kerberos::new_client_from_config(self.kerberos_config(Some(host)))?);
+            }
+        }
+
+        if let NegotiatedProtocol::Kerberos(kerberos) = &mut self.protocol {
+            match kerberos.initialize_security_context_impl(yield_point, builder).await {
+                Result::Err(Error {
+                    error_type: ErrorKind::NoCredentials,
+                    ..
+                }) => {
+                    warn!("Negotiate: Fall back to the NTLM");
+
+                    let ntlm_config = kerberos
+                        .config()
+                        .client_computer_name
+                        .clone()
+                        .map(NtlmConfig::new)
+                        .unwrap_or_default();
+                    self.protocol = NegotiatedProtocol::Ntlm(Ntlm::with_auth_identity(
+                        self.auth_identity.clone().and_then(|c| c.auth_identity()),
+                        ntlm_config,
+                    ));
+                }
+                result => return result,
+            };
+        }
+
+        match &mut self.protocol {
+            NegotiatedProtocol::Pku2u(pku2u) => {
+                let mut credentials_handle = self.auth_identity.as_mut().and_then(|c| c.clone().auth_identity());
+                let mut transformed_builder = builder.full_transform(Some(&mut credentials_handle));
+
+                pku2u.initialize_security_context_impl(&mut transformed_builder)
+            }
+            NegotiatedProtocol::Kerberos(kerberos) => {
+                kerberos.initialize_security_context_impl(yield_point, builder).await
+            }
+            NegotiatedProtocol::Ntlm(ntlm) => {
+                let mut credentials_handle = self.auth_identity.as_mut().and_then(|c| c.clone().auth_identity());
+                let mut transformed_builder = builder.full_transform(Some(&mut credentials_handle));
+
+                ntlm.initialize_security_context_impl(&mut transformed_builder)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use picky_asn1::wrapper::{
+        ExplicitContextTag0, ExplicitContextTag2, ExplicitContextTag3, ObjectIdentifierAsn1, OctetStringAsn1, Optional,
+    };
+    use picky_asn1_x509::oids;
+    use picky_krb::constants::gss_api::{ACCEPT_COMPLETE, ACCEPT_INCOMPLETE};
+    use picky_krb::gss_api::{ApplicationTag0, GssApiNegInit, MechTypeList, NegTokenInit, NegTokenTarg, NegTokenTarg1};
+
+    use super::{Negotiate, NegotiateConfig};
+    use crate::kerberos::kdc_certificate::KdcTrustStore;
+    use crate::ntlm::NtlmConfig;
+    use crate::{
+        AuthIdentity, ClientRequestFlags, Credentials, DataRepresentation, ErrorKind, KerberosConfig, Ntlm,
+        OwnedSecurityBuffer, Result, SecurityBuffer, SecurityBufferType, SecurityStatus, ServerRequestFlags, Sspi,
+        SspiEx, Username,
+    };
+
+    fn credentials() -> AuthIdentity {
+        AuthIdentity {
+            username: Username::new("Username", Some("Domain")).unwrap(),
+            password: String::from("Password").into(),
+        }
+    }
+
+    fn mech_types(mech_types: Vec<oid::ObjectIdentifier>) -> MechTypeList {
+        MechTypeList::from(
+            mech_types
+                .into_iter()
+                .map(ObjectIdentifierAsn1::from)
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn neg_token_init(mech_types: &MechTypeList, mech_token: Option<Vec<u8>>) -> Vec<u8> {
+        picky_asn1_der::to_vec(&ApplicationTag0(GssApiNegInit {
+            oid: ObjectIdentifierAsn1::from(oids::spnego()),
+            neg_token_init: ExplicitContextTag0::from(NegTokenInit {
+                mech_types: Optional::from(Some(ExplicitContextTag0::from(mech_types.clone()))),
+                req_flags: Optional::from(None),
+                mech_token: Optional::from(
+                    mech_token.map(|token| ExplicitContextTag2::from(OctetStringAsn1::from(token))),
+                ),
+                mech_list_mic: Optional::from(None),
+            }),
+        }))
+        .unwrap()
+    }
+
+    fn encode_neg_token_resp(response_token: Option<Vec<u8>>, mech_list_mic: Option<Vec<u8>>) -> Vec<u8> {
+        picky_asn1_der::to_vec(&NegTokenTarg1::from(NegTokenTarg {
+            neg_result: Optional::from(None),
+            supported_mech: Optional::from(None),
+            response_token: Optional::from(
+                response_token.map(|token| ExplicitContextTag2::from(OctetStringAsn1::from(token))),
+            ),
+            mech_list_mic: Optional::from(
+                mech_list_mic.map(|mic| ExplicitContextTag3::from(OctetStringAsn1::from(mic))),
+            ),
+        }))
+        .unwrap()
+    }
+
+    fn ntlm_server() -> Negotiate {
+        Negotiate::new(NegotiateConfig::from_protocol_config(
+            Box::new(NtlmConfig::default()),
+            "server".into(),
+        ))
+        .unwrap()
+    }
+
+    fn accept(server: &mut Negotiate, token: Vec<u8>) -> Result<(SecurityStatus, NegTokenTarg)> {
+        let mut input = [OwnedSecurityBuffer::new(token, SecurityBufferType::Token)];
+        let mut output = [OwnedSecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
+        let mut credentials_handle = None;
+
+        let result = server
+            .accept_security_context()
+            .with_credentials_handle(&mut credentials_handle)
+            .with_context_requirements(ServerRequestFlags::empty())
+            .with_target_data_representation(DataRepresentation::Native)
+            .with_input(&mut input)
+            .with_output(&mut output)
+            .execute(server)?;
+
+        let neg_token_resp: NegTokenTarg1 = picky_asn1_der::from_bytes(&output[0].buffer).unwrap();
+
+        Ok((result.status, neg_token_resp.0))
+    }
+
+    fn initialize(client: &mut Ntlm, token: Option<Vec<u8>>) -> (SecurityStatus, Vec<u8>) {
+        let mut input = token
+            .map(|token| vec![OwnedSecurityBuffer::new(token, SecurityBufferType::Token)])
+            .unwrap_or_default();
+        let mut output = vec![OwnedSecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
+        let mut credentials_handle = Some(credentials().into());
+
+        let mut builder = client
+            .initialize_security_context()
+            .with_credentials_handle(&mut credentials_handle)
+            .with_context_requirements(ClientRequestFlags::INTEGRITY)
+            .with_target_data_representation(DataRepresentation::Native)
+            .with_input(&mut input)
+            .with_output(&mut output);
+        let result = client.initialize_security_context_impl(&mut builder).unwrap();
+
+        (result.status, output.remove(0).buffer)
+    }
+
+    fn response_token(neg_token_resp: &NegTokenTarg) -> Vec<u8> {
+        neg_token_resp.response_token.0.as_ref().unwrap().0 .0.clone()
+    }
+
+    fn ntlm_spnego_authentication(mech_list: MechTypeList, corrupt_mic: bool) -> Result<SecurityStatus> {
+        let mut server = ntlm_server();
+        let mut client = Ntlm::new();
+
+        let (_, negotiate) = initialize(&mut client, None);
+        let (status, neg_token_resp) = accept(&mut server, neg_token_init(&mech_list, Some(negotiate))).unwrap();
+
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+        assert_eq!(neg_token_resp.neg_result.0.as_ref().unwrap().0 .0, ACCEPT_INCOMPLETE);
+        assert_eq!(neg_token_resp.supported_mech.0.as_ref().unwrap().0 .0, oids::ntlm_ssp());
+
+        let (status, authenticate) = initialize(&mut client, Some(response_token(&neg_token_resp)));
+        if status == SecurityStatus::CompleteNeeded {
+            client.complete_auth_token(&mut []).unwrap();
+        }
+
+        let mut mech_list = picky_asn1_der::to_vec(&mech_list).unwrap();
+        let mut mic = [0; 16];
+        let mut message = [
+            SecurityBuffer::Data(mech_list.as_mut_slice()),
+            SecurityBuffer::Token(mic.as_mut_slice()),
+        ];
+        client.make_signature(0, &mut message, 0).unwrap();
+        let mut mic = message[1].data().to_vec();
+        if corrupt_mic {
+            mic[15] ^= 0xff;
+        }
+
+        let (status, neg_token_resp) =
+            accept(&mut server, encode_neg_token_resp(Some(authenticate), Some(mic))).unwrap();
+
+        assert_eq!(status, SecurityStatus::CompleteNeeded);
+        assert_eq!(neg_token_resp.neg_result.0.as_ref().unwrap().0 .0, ACCEPT_COMPLETE);
+        assert!(neg_token_resp.mech_list_mic.0.is_none());
+
+        server.custom_set_auth_identity(Credentials::AuthIdentity(credentials()))?;
+        let mut output = [OwnedSecurityBuffer::new(
+            picky_asn1_der::to_vec(&NegTokenTarg1::from(neg_token_resp)).unwrap(),
+            SecurityBufferType::Token,
+        )];
+        let status = server.complete_auth_token(&mut output)?;
+
+        // the acceptor's mechListMIC is added to the final token
+        let neg_token_resp: NegTokenTarg1 = picky_asn1_der::from_bytes(&output[0].buffer).unwrap();
+        assert_eq!(neg_token_resp.0.neg_result.0.unwrap().0 .0, ACCEPT_COMPLETE);
+        let mut acceptor_mic = neg_token_resp.0.mech_list_mic.0.unwrap().0 .0;
+        let message = [
+            SecurityBuffer::Data(mech_list.as_mut_slice()),
+            SecurityBuffer::Token(acceptor_mic.as_mut_slice()),
+        ];
+        client.verify_signature(&message, 0)?;
+
+        Ok(status)
+    }
+
+    #[test]
+    fn spnego_ntlm_authentication() {
+        let status = ntlm_spnego_authentication(mech_types(vec![oids::ntlm_ssp()]), false).unwrap();
+
+        assert_eq!(status, SecurityStatus::Ok);
+    }
+
+    #[test]
+    fn spnego_rejects_altered_mech_list_mic() {
+        let err = ntlm_spnego_authentication(mech_types(vec![oids::ntlm_ssp()]), true).unwrap_err();
+
+        assert_eq!(err.error_type, ErrorKind::MessageAltered);
+    }
+
+    #[test]
+    fn spnego_requests_mic_when_preferred_mech_is_not_supported() {
+        let mut server = ntlm_server();
+
+        let (status, neg_token_resp) = accept(
+            &mut server,
+            neg_token_init(
+                &mech_types(vec![oids::ms_krb5(), oids::krb5(), oids::ntlm_ssp()]),
+                Some(b"optimistic kerberos token".to_vec()),
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+        // request-mic
+        assert_eq!(neg_token_resp.neg_result.0.unwrap().0 .0, [0x0a, 0x01, 0x03]);
+        assert_eq!(neg_token_resp.supported_mech.0.unwrap().0 .0, oids::ntlm_ssp());
+        assert!(neg_token_resp.response_token.0.is_none());
+
+        // the optimistic token is discarded: the next token must contain the NTLM negotiate message
+        let mut client = Ntlm::new();
+        let (_, negotiate) = initialize(&mut client, None);
+        let (status, neg_token_resp) = accept(&mut server, encode_neg_token_resp(Some(negotiate), None)).unwrap();
+
+        assert_eq!(status, SecurityStatus::ContinueNeeded);
+        assert!(neg_token_resp.response_token.0.is_some());
+    }
+
+    #[test]
+    fn spnego_requires_mic_when_preferred_mech_is_not_selected() {
+        let mut server = ntlm_server();
+        let mut client = Ntlm::new();
+        let mech_list = mech_types(vec![oids::krb5(), oids::ntlm_ssp()]);
+
+        accept(&mut server, neg_token_init(&mech_list, None)).unwrap();
+
+        let (_, negotiate) = initialize(&mut client, None);
+        let (_, neg_token_resp) = accept(&mut server, encode_neg_token_resp(Some(negotiate), None)).unwrap();
+
+        let (_, authenticate) = initialize(&mut client, Some(response_token(&neg_token_resp)));
+        let err = accept(&mut server, encode_neg_token_resp(Some(authenticate), None)).unwrap_err();
+
+        assert_eq!(err.error_type, ErrorKind::InvalidToken);
+    }
+
+    #[test]
+    fn spnego_offers_only_configured_package() {
+        let mut server = Negotiate::new(NegotiateConfig::from_protocol_config(
+            Box::new(KerberosConfig::new("tcp://kdc.example.com:88", "server".into())),
+            "server".into(),
+        ))
+        .unwrap();
+
+        let err = accept(&mut server, neg_token_init(&mech_types(vec![oids::ntlm_ssp()]), None)).unwrap_err();
+
+        assert_eq!(err.error_type, ErrorKind::SecurityPackageNotFound);
+    }
+
+    #[test]
+    fn kerberos_client_uses_kdc_trust_store() {
+        assert!(ntlm_server().kerberos_config(None).kdc_trust_store.is_none());
+
+        let mut config = NegotiateConfig::from_protocol_config(Box::new(NtlmConfig::default()), "client".into());
+        config.kdc_trust_store = Some(KdcTrustStore::default());
+        let negotiate = Negotiate::new(config.clone()).unwrap();
+
+        assert!(negotiate.kerberos_config(None).kdc_trust_store.is_some());
+    }
+
+    #[test]
+    fn spnego_rejects_unsupported_mechs() {
+        let mut server = ntlm_server();
+
+        let err = accept(
+            &mut server,
+            neg_token_init(&mech_types(vec![oids::krb5(), oids::negoex()]), None),
+        )
+        .unwrap_err();
+
+        assert_eq!(err.error_type, ErrorKind::SecurityPackageNotFound);
+    }
+}
@@ -0,0 +1,232 @@
+use oid::ObjectIdentifier;
+use picky_asn1::wrapper::{
+    Asn1SequenceOf, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag2, ExplicitContextTag3,
+    ObjectIdentifierAsn1, OctetStringAsn1, Optional,
+};
+use picky_asn1_der::application_tag::ApplicationTag;
+use picky_asn1_der::Asn1RawDer;
+use picky_asn1_x509::oids;
+use picky_krb::constants::gss_api::{ACCEPT_COMPLETE, ACCEPT_INCOMPLETE};
+use picky_krb::gss_api::{MechTypeList, NegTokenInit, NegTokenTarg, NegTokenTarg1};
+
+use crate::{Error, ErrorKind, Result, ServerResponseFlags};
+
+/// [Mechanism-Independent Token Format](https://datatracker.ietf.org/doc/html/rfc2743#section-3.1):
+/// the initial context token starts with the `[APPLICATION 0]` tag.
+const GSS_API_INITIAL_CONTEXT_TOKEN_TAG: u8 = 0x60;
+
+/// [negState](https://www.rfc-editor.org/rfc/rfc4178#section-4.2.2): no mechanism is acceptable.
+const REJECT: [u8; 3] = [0x0a, 0x01, 0x02];
+/// [negState](https://www.rfc-editor.org/rfc/rfc4178#section-4.2.2): at least one more message is needed
+/// from the initiator and the MIC token is required.
+const REQUEST_MIC: [u8; 3] = [0x0a, 0x01, 0x03];
+
+/// `responseToken [2] OCTET STRING` field tag of the `NegTokenResp`.
+const RESPONSE_TOKEN_TAG: u8 = 0xa2;
+/// `mechListMIC [3] OCTET STRING` field tag of the `NegTokenResp`.
+const MECH_LIST_MIC_TAG: u8 = 0xa3;
+
+/// Security packages that can be selected by the SPNEGO acceptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpnegoMech {
+    Kerberos,
+    Ntlm,
+    Pku2u,
+}
+
+impl SpnegoMech {
+    pub fn from_oid(mech_id: &ObjectIdentifier) -> Option<Self> {
+        if *mech_id == oids::krb5() || *mech_id == oids::ms_krb5() {
+            Some(SpnegoMech::Kerberos)
+        } else if *mech_id == oids::ntlm_ssp() {
+            Some(SpnegoMech::Ntlm)
+        } else if *mech_id == oids::negoex() {
+            Some(SpnegoMech::Pku2u)
+        } else {
+            None
+        }
+    }
+}
+
+/// State of the [SPNEGO](https://www.rfc-editor.org/rfc/rfc4178) negotiation on the acceptor side.
+#[derive(Debug, Clone)]
+pub struct SpnegoAcceptor {
+    /// DER-encoded `mechTypes` from the initiator's `NegTokenInit`: the `mechListMIC` is calculated over it.
+    pub mech_types: Vec<u8>,
+    /// The MIC exchange is mandatory when the selected mechanism is not the initiator's preferred one.
+    pub mic_required: bool,
+    /// Set when the selected mechanism has accepted the security context.
+    pub mech_complete: bool,
+    /// Set when the acceptor has sent its `mechListMIC`.
+    pub mic_sent: bool,
+    /// Initiator's `mechListMIC` which can be verified only after the security context is completed.
+    pub pending_mic: Option<Vec<u8>>,
+    /// Context attributes returned by the selected mechanism.
+    pub flags: ServerResponseFlags,
+}
+
+/// Initiator's [NegTokenInit](https://www.rfc-editor.org/rfc/rfc4178#section-4.2.1).
+#[derive(Debug)]
+pub struct InitialToken {
+    /// Mechanisms in the initiator's preference order.
+    pub mech_types: Vec<ObjectIdentifier>,
+    /// DER-encoded `mechTypes`.
+    pub mech_types_raw: Vec<u8>,
+    /// Optimistic token of the initiator's preferred mechanism.
+    pub mech_token: Option<Vec<u8>>,
+    pub mech_list_mic: Option<Vec<u8>>,
+}
+
+/// Subsequent initiator's [NegTokenResp](https://www.rfc-editor.org/rfc/rfc4178#section-4.2.2).
+#[derive(Debug)]
+pub struct ResponseToken {
+    pub response_token: Option<Vec<u8>>,
+    pub mech_list_mic: Option<Vec<u8>>,
+}
+
+/// Decodes the initiator's `NegTokenInit`.
+///
+/// Returns `None` if the token is not the SPNEGO initial context token: it is a raw token
+/// of the security package then.
+pub fn decode_initial_token(mut data: &[u8]) -> Result<Option<InitialToken>> {
+    if data.first() != Some(&GSS_API_INITIAL_CONTEXT_TOKEN_TAG) {
+        return Ok(None);
+    }
+
+    let oid: ApplicationTag<ObjectIdentifierAsn1, 0> = picky_asn1_der::from_reader(&mut data)?;
+    if oid.0 .0 != oids::spnego() {
+        return Ok(None);
+    }
+
+    let neg_token_init: ExplicitContextTag0<NegTokenInit> = picky_asn1_der::from_reader(&mut data)?;
+    let NegTokenInit {
+        mech_types,
+        mech_token,
+        mech_list_mic,
+        ..
+    } = neg_token_init.0;
+
+    let mech_types: MechTypeList = mech_types
+        .0
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "mechTypes is missing in NegTokenInit"))?
+        .0;
+
+    Ok(Some(InitialToken {
+        mech_types_raw: picky_asn1_der::to_vec(&mech_types)?,
+        mech_types: mech_types.0.into_iter().map(|mech_type| mech_type.0).collect(),
+        mech_token: mech_token.0.map(|token| token.0 .0),
+        mech_list_mic: mech_list_mic.0.map(|mic| mic.0 .0),
+    }))
+}
+
+/// Decodes the initiator's `NegTokenResp`.
+///
+/// All `NegTokenResp` fields are optional and the initiators usually omit the `negState`.
+/// The fields are decoded one by one because the derived decoder does not handle the missing `negState`.
+pub fn decode_response_token(data: &[u8]) -> Result<ResponseToken> {
+    let fields: ExplicitContextTag1<Asn1SequenceOf<Asn1RawDer>> = picky_asn1_der::from_bytes(data)?;
+
+    let mut response_token = ResponseToken {
+        response_token: None,
+        mech_list_mic: None,
+    };
+
+    for field in fields.0 .0 {
+        match field.0.first() {
+            Some(&RESPONSE_TOKEN_TAG) => {
+                let token: ExplicitContextTag2<OctetStringAsn1> = picky_asn1_der::from_bytes(&field.0)?;
+                response_token.response_token = Some(token.0 .0);
+            }
+            Some(&MECH_LIST_MIC_TAG) => {
+                let mic: ExplicitContextTag3<OctetStringAsn1> = picky_asn1_der::from_bytes(&field.0)?;
+                response_token.mech_list_mic = Some(mic.0 .0);
+            }
+            // negState and supportedMech are meaningless in the initiator's tokens
+            _ => {}
+        }
+    }
+
+    Ok(response_token)
+}
+
+/// `negState` of the acceptor's `NegTokenResp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegState {
+    AcceptCompleted,
+    AcceptIncomplete,
+    Reject,
+    RequestMic,
+}
+
+impl NegState {
+    fn to_der(self) -> Vec<u8> {
+        match self {
+            NegState::AcceptCompleted => ACCEPT_COMPLETE.to_vec(),
+            NegState::AcceptIncomplete => ACCEPT_INCOMPLETE.to_vec(),
+            NegState::Reject => REJECT.to_vec(),
+            NegState::RequestMic => REQUEST_MIC.to_vec(),
+        }
+    }
+}
+
+/// Encodes the acceptor's `NegTokenResp`.
+pub fn encode_response_token(
+    neg_state: NegState,
+    supported_mech: Option<ObjectIdentifier>,
+    response_token: Option<Vec<u8>>,
+    mech_list_mic: Option<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    Ok(picky_asn1_der::to_vec(&NegTokenTarg1::from(NegTokenTarg {
+        neg_result: Optional::from(Some(ExplicitContextTag0::from(Asn1RawDer(neg_state.to_der())))),
+        supported_mech: Optional::from(
+            supported_mech.map(|mech_id| ExplicitContextTag1::from(ObjectIdentifierAsn1::from(mech_id))),
+        ),
+        response_token: Optional::from(
+            response_token.map(|token| ExplicitContextTag2::from(OctetStringAsn1::from(token))),
+        ),
+        mech_list_mic: Optional::from(mech_list_mic.map(|mic| ExplicitContextTag3::from(OctetStringAsn1::from(mic)))),
+    }))?)
+}
+
+/// Adds the `mechListMIC` to the encoded acceptor's `NegTokenResp`.
+pub fn add_mech_list_mic(response_token: &[u8], mech_list_mic: Vec<u8>) -> Result<Vec<u8>> {
+    let mut neg_token_resp: NegTokenTarg1 = picky_asn1_der::from_bytes(response_token)?;
+    neg_token_resp.0.mech_list_mic =
+        Optional::from(Some(ExplicitContextTag3::from(OctetStringAsn1::from(mech_list_mic))));
+
+    Ok(picky_asn1_der::to_vec(&neg_token_resp)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use picky_asn1::wrapper::{ExplicitContextTag2, ExplicitContextTag3, OctetStringAsn1, Optional};
+    use picky_krb::gss_api::{NegTokenTarg, NegTokenTarg1};
+
+    use super::{decode_response_token, encode_response_token, NegState};
+
+    #[test]
+    fn decode_response_token_without_neg_state() {
+        let token = picky_asn1_der::to_vec(&NegTokenTarg1::from(NegTokenTarg {
+            neg_result: Optional::from(None),
+            supported_mech: Optional::from(None),
+            response_token: Optional::from(Some(ExplicitContextTag2::from(OctetStringAsn1::from(vec![1, 2, 3])))),
+            mech_list_mic: Optional::from(Some(ExplicitContextTag3::from(OctetStringAsn1::from(vec![4, 5])))),
+        }))
+        .unwrap();
+
+        let response_token = decode_response_token(&token).unwrap();
+
+        assert_eq!(response_token.response_token.unwrap(), [1, 2, 3]);
+        assert_eq!(response_token.mech_list_mic.unwrap(), [4, 5]);
+    }
+
+    #[test]
+    fn decode_response_token_with_neg_state() {
+        let token = encode_response_token(NegState::AcceptIncomplete, None, None, Some(vec![4, 5])).unwrap();
+
+        let response_token = decode_response_token(&token).unwrap();
+
+        assert!(response_token.response_token.is_none());
+        assert_eq!(response_token.mech_list_mic.unwrap(), [4, 5]);
+    }
+}
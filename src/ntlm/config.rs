@@ -1,18 +1,152 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::crypto::HASH_SIZE;
 use crate::negotiate::ProtocolConfig;
+use crate::ntlm::CHALLENGE_SIZE;
 use crate::{NegotiatedProtocol, Ntlm, Result};
 
+/// Result of the account lookup performed by the [NtHashProvider].
+#[derive(Clone, PartialEq, Eq)]
+pub enum NtHashLookup {
+    /// NT hash of the account password: MD4 of the UTF-16LE encoded password.
+    NtHash([u8; HASH_SIZE]),
+    NoSuchUser,
+    AccountDisabled,
+}
+
+impl Debug for NtHashLookup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NtHashLookup::NtHash(_) => write!(f, "NtHash(***)"),
+            NtHashLookup::NoSuchUser => write!(f, "NoSuchUser"),
+            NtHashLookup::AccountDisabled => write!(f, "AccountDisabled"),
+        }
+    }
+}
+
+/// Provides NT hashes of the accounts to the NTLM acceptor.
+///
+/// Allows the server to authenticate users of its own user store instead of the single identity
+/// passed to `acquire_credentials_handle`.
+pub trait NtHashProvider: Debug + Send + Sync {
+    /// Looks up the account by the user name and domain name from the AUTHENTICATE message.
+    fn nt_hash(&self, username: &str, domain: &str) -> Result<NtHashLookup>;
+}
+
+/// NTLM logon request passed through to the domain controller by the [NtlmValidator].
+///
+/// Contains everything needed for the network logon (`NetrLogonSamLogonWithFlags`) of the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtlmValidationRequest {
+    pub username: String,
+    pub domain: String,
+    /// Workstation name of the client from the AUTHENTICATE message.
+    pub workstation: String,
+    pub server_challenge: [u8; CHALLENGE_SIZE],
+    pub lm_challenge_response: Vec<u8>,
+    pub nt_challenge_response: Vec<u8>,
+}
+
+/// Result of the successful pass-through logon.
+#[derive(Clone, PartialEq, Eq)]
+pub struct NtlmValidationInfo {
+    /// User session key (`SessionBaseKey`) of the logon session.
+    pub user_session_key: [u8; HASH_SIZE],
+    /// Authorization information of the user returned by the domain controller,
+    /// for example, the encoded `NETLOGON_VALIDATION_SAM_INFO4` structure.
+    pub authorization_data: Vec<u8>,
+}
+
+impl Debug for NtlmValidationInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NtlmValidationInfo")
+            .field("user_session_key", &"***")
+            .field("authorization_data", &self.authorization_data)
+            .finish()
+    }
+}
+
+/// Validates NTLM logons on behalf of the NTLM acceptor.
+///
+/// Allows the server to authenticate domain users without knowing their secrets:
+/// the logon request is passed through to the domain controller, for example, using the Netlogon protocol.
+pub trait NtlmValidator: Debug + Send + Sync {
+    /// Validates the client's responses. Returns an error if the logon is denied.
+    fn validate(&self, request: &NtlmValidationRequest) -> Result<NtlmValidationInfo>;
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct NtlmConfig {
     /// Computer name, or "workstation name", of the client machine performing the authentication attempt
     ///
     /// This is also referred to as the "Source Workstation".
     pub client_computer_name: Option<String>,
+    /// NT hash provider of the NTLM acceptor
+    ///
+    /// If specified, the server verifies the client's response using the NT hash of the account
+    /// from the AUTHENTICATE message instead of the credentials handle identity.
+    pub nt_hash_provider: Option<Arc<dyn NtHashProvider>>,
+    /// Pass-through logon validator of the NTLM acceptor
+    ///
+    /// If specified, the server does not verify the client's response itself and uses the session key
+    /// returned by the validator. Takes precedence over the [NtHashProvider].
+    pub validator: Option<Arc<dyn NtlmValidator>>,
+    /// Connectionless (datagram) NTLM
+    ///
+    /// The initiator skips the NEGOTIATE message and requests the connectionless mode, and the acceptor
+    /// starts the connectionless authentication when the first input token is empty. In this mode,
+    /// the sealing key is re-derived for every message from its sequence number.
+    /// Disabled by default: connectionless CHALLENGE and AUTHENTICATE messages are rejected.
+    pub datagram: bool,
+    /// NTLMv1 and NTLM2 session responses
+    ///
+    /// The initiator sends the NTLMv1 response (the NTLM2 session response if the extended session security
+    /// is negotiated) instead of the NTLMv2 one, and the acceptor accepts such responses.
+    /// NTLMv1 is cryptographically weak and should be enabled only for legacy peers.
+    /// Disabled by default: NTLMv1 responses are rejected.
+    pub ntlm_v1: bool,
+    /// NetBIOS computer name of the server (MsvAvNbComputerName)
+    ///
+    /// The server names are sent in the target info of the CHALLENGE message.
+    /// If a name is not specified, the empty value is sent, because clients require these fields to be present.
+    pub nb_computer_name: Option<String>,
+    /// NetBIOS domain name of the server (MsvAvNbDomainName)
+    pub nb_domain_name: Option<String>,
+    /// FQDN of the server (MsvAvDnsComputerName)
+    pub dns_computer_name: Option<String>,
+    /// FQDN of the server's domain (MsvAvDnsDomainName)
+    pub dns_domain_name: Option<String>,
+    /// FQDN of the server's forest (MsvAvDnsTreeName)
+    ///
+    /// Not sent if not specified.
+    pub dns_tree_name: Option<String>,
+    /// Do not send the MsvAvTimestamp in the CHALLENGE message
+    ///
+    /// Without the timestamp, clients use their own time and may send the LMv2 response instead of the MIC.
+    pub omit_timestamp: bool,
+    /// Expected service principal names of the acceptor
+    ///
+    /// If not empty, the server requires the MsvAvTargetName of the AUTHENTICATE message to match
+    /// one of these names (case-insensitive), so the NTLM authentication cannot be relayed to another service.
+    pub target_names: Vec<String>,
 }
 
 impl NtlmConfig {
     pub fn new(client_machine_name: String) -> Self {
         Self {
             client_computer_name: Some(client_machine_name),
+            nt_hash_provider: None,
+            validator: None,
+            datagram: false,
+            ntlm_v1: false,
+            nb_computer_name: None,
+            nb_domain_name: None,
+            dns_computer_name: None,
+            dns_domain_name: None,
+            dns_tree_name: None,
+            omit_timestamp: false,
+            target_names: Vec::new(),
         }
     }
 }
@@ -22,6 +156,10 @@ impl ProtocolConfig for NtlmConfig {
         Ok(NegotiatedProtocol::Ntlm(Ntlm::with_config(Clone::clone(self))))
     }
 
+    fn new_server(&self) -> Result<NegotiatedProtocol> {
+        Ok(NegotiatedProtocol::Ntlm(Ntlm::with_config(Clone::clone(self))))
+    }
+
     fn box_clone(&self) -> Box<dyn ProtocolConfig> {
         Box::new(Clone::clone(self))
     }
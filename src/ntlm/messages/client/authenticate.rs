@@ -4,12 +4,9 @@ use byteorder::{LittleEndian, WriteBytesExt};
 use rand::rngs::OsRng;
 use rand::Rng;
 
-use crate::crypto::Rc4;
+use crate::crypto::{compute_md4, Rc4};
 use crate::ntlm::messages::computations::*;
-use crate::ntlm::messages::{
-    MessageFields, MessageTypes, CLIENT_SEAL_MAGIC, CLIENT_SIGN_MAGIC, NTLM_SIGNATURE, NTLM_VERSION_SIZE,
-    SERVER_SEAL_MAGIC, SERVER_SIGN_MAGIC,
-};
+use crate::ntlm::messages::{init_session_security, MessageFields, MessageTypes, NTLM_SIGNATURE, NTLM_VERSION_SIZE};
 use crate::ntlm::{
     AuthIdentityBuffers, AuthenticateMessage, Mic, NegotiateFlags, Ntlm, NtlmState, ENCRYPTED_RANDOM_SESSION_KEY_SIZE,
     MESSAGE_INTEGRITY_CHECK_SIZE, SESSION_KEY_SIZE,
@@ -87,37 +84,67 @@ pub fn write_authenticate(
 ) -> crate::Result<SecurityStatus> {
     check_state(context.state)?;
 
+    // there is no NEGOTIATE message in the connectionless mode
     let negotiate_message = context
         .negotiate_message
         .as_ref()
-        .expect("negotiate message must be set on negotiate phase");
+        .map(|negotiate_message| negotiate_message.message.as_slice())
+        .unwrap_or_default();
     let challenge_message = context
         .challenge_message
         .as_ref()
         .expect("challenge message must be set on challenge phase");
 
     // calculate needed fields
-    // NTLMv2
-    let target_info = get_authenticate_target_info(
-        challenge_message.target_info.as_ref(),
-        context.channel_bindings.as_ref(),
-        context.send_single_host_data,
-    )?;
-
     let client_challenge = generate_challenge()?;
-    let ntlm_v2_hash = compute_ntlm_v2_hash(credentials)?;
-    let lm_challenge_response = compute_lm_v2_response(
-        client_challenge.as_ref(),
-        challenge_message.server_challenge.as_ref(),
-        ntlm_v2_hash.as_ref(),
-    )?;
-    let (nt_challenge_response, key_exchange_key) = compute_ntlm_v2_response(
-        client_challenge.as_ref(),
-        challenge_message.server_challenge.as_ref(),
-        target_info.as_ref(),
-        ntlm_v2_hash.as_ref(),
-        challenge_message.timestamp,
-    )?;
+    let (lm_challenge_response, nt_challenge_response, key_exchange_key) = if context.anonymous {
+        // the anonymous LmChallengeResponse is Z(1), the NtChallengeResponse is empty, and the session base key is Z(16)
+        (vec![0x00], Vec::new(), [0x00; SESSION_KEY_SIZE])
+    } else if context.config().ntlm_v1 {
+        let nt_hash = compute_nt_hash(credentials)?;
+        let (lm_challenge_response, nt_challenge_response) = compute_ntlm_v1_response(
+            &nt_hash,
+            &challenge_message.server_challenge,
+            &client_challenge,
+            context.flags,
+        )?;
+        let key_exchange_key = compute_ntlm_v1_key_exchange_key(
+            &compute_md4(&nt_hash),
+            &challenge_message.server_challenge,
+            &lm_challenge_response,
+            context.flags,
+        )?;
+
+        (
+            lm_challenge_response.to_vec(),
+            nt_challenge_response.to_vec(),
+            key_exchange_key,
+        )
+    } else {
+        // NTLMv2
+        let target_info = get_authenticate_target_info(
+            challenge_message.target_info.as_ref(),
+            context.channel_bindings.as_ref(),
+            context.target_name.as_deref(),
+            context.send_single_host_data,
+        )?;
+
+        let ntlm_v2_hash = compute_ntlm_v2_hash(credentials)?;
+        let lm_challenge_response = compute_lm_v2_response(
+            client_challenge.as_ref(),
+            challenge_message.server_challenge.as_ref(),
+            ntlm_v2_hash.as_ref(),
+        )?;
+        let (nt_challenge_response, key_exchange_key) = compute_ntlm_v2_response(
+            client_challenge.as_ref(),
+            challenge_message.server_challenge.as_ref(),
+            target_info.as_ref(),
+            ntlm_v2_hash.as_ref(),
+            challenge_message.timestamp,
+        )?;
+
+        (lm_challenge_response.to_vec(), nt_challenge_response, key_exchange_key)
+    };
     context.flags = get_flags(context, credentials);
 
     let session_key = if context.flags.contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_KEY_EXCH) {
@@ -148,7 +175,7 @@ pub fn write_authenticate(
 
     let mut buffer = io::Cursor::new(buffer);
     let mic = write_mic(
-        negotiate_message.message.as_ref(),
+        negotiate_message,
         challenge_message.message.as_ref(),
         message.as_ref(),
         session_key.as_ref(),
@@ -159,17 +186,14 @@ pub fn write_authenticate(
     transport.write_all(buffer.into_inner().as_slice())?;
     transport.flush()?;
 
-    context.send_signing_key = generate_signing_key(session_key.as_ref(), CLIENT_SIGN_MAGIC);
-    context.recv_signing_key = generate_signing_key(session_key.as_ref(), SERVER_SIGN_MAGIC);
-    context.send_sealing_key = Some(Rc4::new(&generate_signing_key(session_key.as_ref(), CLIENT_SEAL_MAGIC)));
-    context.recv_sealing_key = Some(Rc4::new(&generate_signing_key(session_key.as_ref(), SERVER_SEAL_MAGIC)));
+    init_session_security(context, &session_key, true);
     context.session_key = Some(session_key);
 
     context.authenticate_message = Some(AuthenticateMessage::new(
         message,
         Some(mic),
-        target_info,
-        client_challenge,
+        lm_challenge_response,
+        nt_challenge_response,
         Some(encrypted_session_key),
     ));
     context.state = NtlmState::Final;
@@ -189,19 +213,32 @@ fn check_state(state: NtlmState) -> crate::Result<()> {
 }
 
 fn get_flags(context: &Ntlm, identity: &AuthIdentityBuffers) -> NegotiateFlags {
-    // set KEY_EXCH flag if it was in the challenge message
-    let mut flags = context.flags & NegotiateFlags::NTLM_SSP_NEGOTIATE_KEY_EXCH;
+    // set KEY_EXCH and DATAGRAM flags if they were in the challenge message
+    let mut flags =
+        context.flags & (NegotiateFlags::NTLM_SSP_NEGOTIATE_KEY_EXCH | NegotiateFlags::NTLM_SSP_NEGOTIATE_DATAGRAM);
+
+    // NTLMv1 uses the extended session security only if the server supports it
+    if !context.config().ntlm_v1
+        || context
+            .flags
+            .contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY)
+    {
+        flags |= NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY;
+    }
 
     if !identity.domain.is_empty() {
         flags |= NegotiateFlags::NTLM_SSP_NEGOTIATE_DOMAIN_SUPPLIED;
     }
 
+    if context.anonymous {
+        flags |= NegotiateFlags::NTLM_SSP_NEGOTIATE_ANONYMOUS;
+    }
+
     // will not set workstation because it is not used anywhere
 
     flags |= NegotiateFlags::NTLM_SSP_NEGOTIATE56
         | NegotiateFlags::NTLM_SSP_NEGOTIATE128
         | NegotiateFlags::NTLM_SSP_NEGOTIATE_ALWAYS_SIGN
-        | NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY
         | NegotiateFlags::NTLM_SSP_NEGOTIATE_NTLM
         | NegotiateFlags::NTLM_SSP_NEGOTIATE_REQUEST_TARGET
         | NegotiateFlags::NTLM_SSP_NEGOTIATE_UNICODE
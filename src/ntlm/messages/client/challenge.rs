@@ -3,7 +3,9 @@ use std::io;
 use byteorder::{LittleEndian, ReadBytesExt};
 
 use crate::ntlm::messages::computations::*;
-use crate::ntlm::messages::{read_ntlm_header, try_read_version, MessageFields, MessageTypes};
+use crate::ntlm::messages::{
+    check_connectionless_flags, read_ntlm_header, try_read_version, MessageFields, MessageTypes,
+};
 use crate::ntlm::{ChallengeMessage, NegotiateFlags, Ntlm, NtlmState, CHALLENGE_SIZE};
 use crate::SecurityStatus;
 
@@ -23,6 +25,13 @@ pub fn read_challenge(context: &mut Ntlm, mut stream: impl io::Read) -> crate::R
 
     read_ntlm_header(&mut buffer, MessageTypes::Challenge)?;
     let (mut message_fields, flags, server_challenge) = read_header(&mut buffer)?;
+    check_connectionless_flags(context.config(), flags)?;
+    if context.config().datagram && !flags.contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_DATAGRAM) {
+        return Err(crate::Error::new(
+            crate::ErrorKind::InvalidToken,
+            "connectionless NTLM is requested but the server does not support it",
+        ));
+    }
     context.flags = flags;
     let _version = try_read_version(context.flags, &mut buffer)?;
     read_payload(&mut message_fields, &mut buffer)?;
@@ -86,7 +86,10 @@ fn get_flags(context: &Ntlm) -> NegotiateFlags {
         | NegotiateFlags::NTLM_SSP_NEGOTIATE_VERSION;
 
     if context.sealing {
-        flags |= NegotiateFlags::NTLM_SSP_NEGOTIATE_LM_KEY;
+        // the LM session key is not supported for NTLMv1 without the extended session security
+        if !context.config().ntlm_v1 {
+            flags |= NegotiateFlags::NTLM_SSP_NEGOTIATE_LM_KEY;
+        }
         flags |= NegotiateFlags::NTLM_SSP_NEGOTIATE_SEAL;
         flags |= NegotiateFlags::NTLM_SSP_NEGOTIATE_KEY_EXCH;
     }
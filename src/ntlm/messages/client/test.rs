@@ -135,6 +135,41 @@ fn read_challenge_does_not_fail_with_correct_header() {
     read_challenge(&mut context, buff.as_ref()).unwrap();
 }
 
+#[test]
+fn read_challenge_fails_on_connectionless_challenge_when_disabled() {
+    let mut context = Ntlm::new();
+    context.set_version(LOCAL_NEGOTIATE_VERSION);
+    context.state = NtlmState::Challenge;
+    context.negotiate_message = Some(NegotiateMessage::new(LOCAL_NEGOTIATE_MESSAGE.to_vec()));
+    context.flags = NegotiateFlags::from_bits(LOCAL_NEGOTIATE_FLAGS).unwrap();
+
+    let mut buff = LOCAL_CHALLENGE_MESSAGE.to_vec();
+    // negotiate flags start after the signature, the message type, and the target name fields
+    buff[20] |= NegotiateFlags::NTLM_SSP_NEGOTIATE_DATAGRAM.bits() as u8;
+
+    assert_eq!(
+        read_challenge(&mut context, buff.as_slice()).unwrap_err().error_type,
+        crate::ErrorKind::UnsupportedFunction
+    );
+}
+
+#[test]
+fn read_challenge_fails_on_connection_oriented_challenge_in_connectionless_mode() {
+    let mut context = Ntlm::with_config(NtlmConfig {
+        datagram: true,
+        ..Default::default()
+    });
+    context.set_version(LOCAL_NEGOTIATE_VERSION);
+    context.state = NtlmState::Challenge;
+    context.flags = NegotiateFlags::from_bits(LOCAL_NEGOTIATE_FLAGS).unwrap();
+
+    let buff = *LOCAL_CHALLENGE_MESSAGE;
+    assert_eq!(
+        read_challenge(&mut context, buff.as_ref()).unwrap_err().error_type,
+        crate::ErrorKind::InvalidToken
+    );
+}
+
 #[test]
 fn read_challenge_fails_with_incorrect_signature() {
     let mut context = Ntlm::new();
@@ -5,15 +5,20 @@ use std::io::{self, Read, Write};
 use std::sync::LazyLock;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use des::cipher::generic_array::GenericArray;
+use des::cipher::{BlockEncrypt, KeyInit};
+use des::Des;
 use rand::rngs::OsRng;
 use rand::Rng;
 use time::OffsetDateTime;
 
 use crate::channel_bindings::ChannelBindings;
+use crate::credssp::NStatusCode;
 use crate::crypto::{compute_hmac_md5, compute_md4, compute_md5, compute_md5_channel_bindings_hash, HASH_SIZE};
 use crate::ntlm::messages::av_pair::*;
 use crate::ntlm::{
-    AuthIdentityBuffers, CHALLENGE_SIZE, LM_CHALLENGE_RESPONSE_BUFFER_SIZE, MESSAGE_INTEGRITY_CHECK_SIZE,
+    AuthIdentityBuffers, NegotiateFlags, NtlmConfig, CHALLENGE_SIZE, LM_CHALLENGE_RESPONSE_BUFFER_SIZE,
+    MESSAGE_INTEGRITY_CHECK_SIZE,
 };
 use crate::utils;
 
@@ -21,6 +26,7 @@ pub const SSPI_CREDENTIALS_HASH_LENGTH_OFFSET: usize = 512;
 pub const SINGLE_HOST_DATA_SIZE: usize = 48;
 
 const NT_V2_RESPONSE_BASE_SIZE: usize = 28;
+pub const NTLM_V1_RESPONSE_SIZE: usize = 24;
 
 // The Single_Host_Data structure allows a client to send machine-specific information
 // within an authentication exchange to services on the same machine. The client can
@@ -64,23 +70,34 @@ fn convert_to_file_time(end_date: OffsetDateTime) -> crate::Result<u64> {
     }
 }
 
-pub fn get_challenge_target_info(timestamp: u64) -> crate::Result<Vec<u8>> {
+pub fn get_challenge_target_info(config: &NtlmConfig, timestamp: u64) -> crate::Result<Vec<u8>> {
+    let name = |name: &Option<String>| name.as_ref().map(utils::string_to_utf16).unwrap_or_default();
+
     // Windows requires _DomainName, _ComputerName fields, but does not care what they are contain
-    let av_pairs = vec![
-        AvPair::NbDomainName(Vec::new()),
-        AvPair::NbComputerName(Vec::new()),
-        AvPair::DnsDomainName(Vec::new()),
-        AvPair::DnsComputerName(Vec::new()),
-        AvPair::Timestamp(timestamp),
-        AvPair::EOL,
+    let mut av_pairs = vec![
+        AvPair::NbDomainName(name(&config.nb_domain_name)),
+        AvPair::NbComputerName(name(&config.nb_computer_name)),
+        AvPair::DnsDomainName(name(&config.dns_domain_name)),
+        AvPair::DnsComputerName(name(&config.dns_computer_name)),
     ];
 
+    if config.dns_tree_name.is_some() {
+        av_pairs.push(AvPair::DnsTreeName(name(&config.dns_tree_name)));
+    }
+
+    if !config.omit_timestamp {
+        av_pairs.push(AvPair::Timestamp(timestamp));
+    }
+
+    av_pairs.push(AvPair::EOL);
+
     Ok(AvPair::list_to_buffer(&av_pairs)?)
 }
 
 pub fn get_authenticate_target_info(
     target_info: &[u8],
     channel_bindings: Option<&ChannelBindings>,
+    target_name: Option<&str>,
     send_single_host_data: bool,
 ) -> crate::Result<Vec<u8>> {
     let mut av_pairs = AvPair::buffer_to_av_pairs(target_info)?;
@@ -96,9 +113,7 @@ pub fn get_authenticate_target_info(
         av_pairs.push(single_host_av_pair);
     }
 
-    // will not check suppress_extended_protection and
-    // will not add channel bindings and service principal name
-    // because it is not used anywhere
+    // will not check suppress_extended_protection because it is not used anywhere
 
     if let Some(channel_bindings) = channel_bindings {
         av_pairs.push(AvPair::ChannelBindings(compute_md5_channel_bindings_hash(
@@ -106,6 +121,10 @@ pub fn get_authenticate_target_info(
         )));
     }
 
+    if let Some(target_name) = target_name {
+        av_pairs.push(AvPair::TargetName(utils::string_to_utf16(target_name)));
+    }
+
     let mut authenticate_target_info = AvPair::list_to_buffer(&av_pairs)?;
 
     // NTLMv2
@@ -171,19 +190,14 @@ pub fn convert_password_hash(identity_password: &[u8]) -> crate::Result<[u8; HAS
     }
 }
 
-pub fn compute_ntlm_v2_hash(identity: &AuthIdentityBuffers) -> crate::Result<[u8; HASH_SIZE]> {
+/// Computes the NT hash (NTOWFv1) of the identity password.
+pub fn compute_nt_hash(identity: &AuthIdentityBuffers) -> crate::Result<[u8; HASH_SIZE]> {
     if !identity.is_empty() {
-        let hmac_key = if identity.password.as_ref().len() > SSPI_CREDENTIALS_HASH_LENGTH_OFFSET {
-            convert_password_hash(identity.password.as_ref())?
+        if identity.password.as_ref().len() > SSPI_CREDENTIALS_HASH_LENGTH_OFFSET {
+            convert_password_hash(identity.password.as_ref())
         } else {
-            compute_md4(identity.password.as_ref())
-        };
-
-        let user_utf16 = utils::bytes_to_utf16_string(identity.user.as_ref());
-        let mut user_uppercase_with_domain = utils::string_to_utf16(user_utf16.to_uppercase().as_str());
-        user_uppercase_with_domain.extend(&identity.domain);
-
-        Ok(compute_hmac_md5(&hmac_key, &user_uppercase_with_domain)?)
+            Ok(compute_md4(identity.password.as_ref()))
+        }
     } else {
         Err(crate::Error::new(
             crate::ErrorKind::InvalidToken,
@@ -193,6 +207,141 @@ pub fn compute_ntlm_v2_hash(identity: &AuthIdentityBuffers) -> crate::Result<[u8
     // hash by the callback is not implemented because the callback never sets
 }
 
+pub fn compute_ntlm_v2_hash(identity: &AuthIdentityBuffers) -> crate::Result<[u8; HASH_SIZE]> {
+    let nt_hash = compute_nt_hash(identity)?;
+
+    compute_ntlm_v2_hash_from_nt_hash(&nt_hash, &identity.user, &identity.domain)
+}
+
+/// Computes the NTLMv2 hash from the NT hash of the password. `user` and `domain` are UTF-16 encoded.
+pub fn compute_ntlm_v2_hash_from_nt_hash(nt_hash: &[u8], user: &[u8], domain: &[u8]) -> crate::Result<[u8; HASH_SIZE]> {
+    let user_utf16 = utils::bytes_to_utf16_string(user);
+    let mut user_uppercase_with_domain = utils::string_to_utf16(user_utf16.to_uppercase().as_str());
+    user_uppercase_with_domain.extend(domain);
+
+    Ok(compute_hmac_md5(nt_hash, &user_uppercase_with_domain)?)
+}
+
+/// Computes the NTLMv1 `LmChallengeResponse` and `NtChallengeResponse`.
+///
+/// If the extended session security is negotiated, the NTLM2 session response is computed using the client challenge.
+/// Otherwise, the `LmChallengeResponse` is the copy of the `NtChallengeResponse`.
+pub fn compute_ntlm_v1_response(
+    nt_hash: &[u8; HASH_SIZE],
+    server_challenge: &[u8; CHALLENGE_SIZE],
+    client_challenge: &[u8; CHALLENGE_SIZE],
+    flags: NegotiateFlags,
+) -> crate::Result<([u8; NTLM_V1_RESPONSE_SIZE], [u8; NTLM_V1_RESPONSE_SIZE])> {
+    if flags.contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY) {
+        let mut lm_challenge_response = [0x00; NTLM_V1_RESPONSE_SIZE];
+        lm_challenge_response[..CHALLENGE_SIZE].copy_from_slice(client_challenge);
+
+        let challenge = compute_md5(&[server_challenge.as_ref(), client_challenge.as_ref()].concat());
+        let nt_challenge_response = compute_desl(nt_hash, &challenge[..CHALLENGE_SIZE])?;
+
+        Ok((lm_challenge_response, nt_challenge_response))
+    } else {
+        let nt_challenge_response = compute_desl(nt_hash, server_challenge)?;
+
+        Ok((nt_challenge_response, nt_challenge_response))
+    }
+}
+
+/// Verifies the client's NTLMv1 response and returns the session base key.
+pub fn verify_ntlm_v1_response(
+    nt_hash: &[u8; HASH_SIZE],
+    server_challenge: &[u8; CHALLENGE_SIZE],
+    lm_challenge_response: &[u8],
+    nt_challenge_response: &[u8],
+    flags: NegotiateFlags,
+) -> crate::Result<[u8; HASH_SIZE]> {
+    if lm_challenge_response.len() != NTLM_V1_RESPONSE_SIZE {
+        return Err(crate::Error::new(
+            crate::ErrorKind::InvalidToken,
+            format!(
+                "invalid NTLMv1 LmChallengeResponse length: {}",
+                lm_challenge_response.len()
+            ),
+        ));
+    }
+
+    let mut client_challenge = [0x00; CHALLENGE_SIZE];
+    client_challenge.copy_from_slice(&lm_challenge_response[..CHALLENGE_SIZE]);
+
+    let (_, expected_nt_challenge_response) =
+        compute_ntlm_v1_response(nt_hash, server_challenge, &client_challenge, flags)?;
+
+    if expected_nt_challenge_response.as_ref() != nt_challenge_response {
+        return Err(crate::Error::new_with_nstatus(
+            crate::ErrorKind::LogonDenied,
+            "NTLMv1 response verification failed",
+            NStatusCode::LOGON_FAILURE,
+        ));
+    }
+
+    Ok(compute_md4(nt_hash))
+}
+
+/// Computes the NTLMv1 key exchange key (KXKEY) from the session base key.
+pub fn compute_ntlm_v1_key_exchange_key(
+    session_base_key: &[u8; HASH_SIZE],
+    server_challenge: &[u8; CHALLENGE_SIZE],
+    lm_challenge_response: &[u8],
+    flags: NegotiateFlags,
+) -> crate::Result<[u8; HASH_SIZE]> {
+    if flags.contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY) {
+        // the client challenge of the NTLM2 session response
+        let client_challenge = lm_challenge_response.get(..CHALLENGE_SIZE).ok_or_else(|| {
+            crate::Error::new(
+                crate::ErrorKind::InvalidToken,
+                format!(
+                    "invalid NTLMv1 LmChallengeResponse length: {}",
+                    lm_challenge_response.len()
+                ),
+            )
+        })?;
+
+        let mut data = server_challenge.to_vec();
+        data.extend_from_slice(client_challenge);
+
+        Ok(compute_hmac_md5(session_base_key, &data)?)
+    } else {
+        Ok(*session_base_key)
+    }
+}
+
+// DESL(K, D): the 16-byte key is split into three 7-byte DES keys, the last one is padded with zeros.
+fn compute_desl(key: &[u8; HASH_SIZE], data: &[u8]) -> crate::Result<[u8; NTLM_V1_RESPONSE_SIZE]> {
+    let mut padded_key = [0x00; 21];
+    padded_key[..HASH_SIZE].copy_from_slice(key);
+
+    let mut result = [0x00; NTLM_V1_RESPONSE_SIZE];
+    for (des_key, block) in padded_key.chunks(7).zip(result.chunks_mut(8)) {
+        let cipher = Des::new_from_slice(&expand_des_key(des_key))
+            .map_err(|err| crate::Error::new(crate::ErrorKind::InternalError, format!("invalid DES key: {}", err)))?;
+
+        let block = GenericArray::from_mut_slice(block);
+        block.copy_from_slice(data);
+        cipher.encrypt_block(block);
+    }
+
+    Ok(result)
+}
+
+// Converts the 56-bit key into the 64-bit DES key. Parity bits are ignored by the DES implementation.
+fn expand_des_key(key: &[u8]) -> [u8; 8] {
+    [
+        key[0],
+        (key[0] << 7) | (key[1] >> 1),
+        (key[1] << 6) | (key[2] >> 2),
+        (key[2] << 5) | (key[3] >> 3),
+        (key[3] << 4) | (key[4] >> 4),
+        (key[4] << 3) | (key[5] >> 5),
+        (key[5] << 2) | (key[6] >> 6),
+        key[6] << 1,
+    ]
+}
+
 pub fn compute_lm_v2_response(
     client_challenge: &[u8],
     server_challenge: &[u8],
@@ -237,6 +386,35 @@ pub fn compute_ntlm_v2_response(
     Ok((nt_challenge_response, key_exchange_key))
 }
 
+/// Verifies the NTProofStr of the client's NTLMv2 response and returns the key exchange key.
+pub fn verify_ntlm_v2_response(
+    nt_challenge_response: &[u8],
+    server_challenge: &[u8],
+    ntlm_v2_hash: &[u8],
+) -> crate::Result<[u8; HASH_SIZE]> {
+    if nt_challenge_response.len() < NT_V2_RESPONSE_BASE_SIZE + HASH_SIZE {
+        return Err(crate::Error::new(
+            crate::ErrorKind::InvalidToken,
+            format!("NTLMv2 response is too short: {} bytes", nt_challenge_response.len()),
+        ));
+    }
+
+    let (nt_proof, ntlm_v2_temp) = nt_challenge_response.split_at(HASH_SIZE);
+
+    let mut nt_proof_input = server_challenge.to_vec();
+    nt_proof_input.extend_from_slice(ntlm_v2_temp);
+
+    if compute_hmac_md5(ntlm_v2_hash, &nt_proof_input)?.as_ref() != nt_proof {
+        return Err(crate::Error::new_with_nstatus(
+            crate::ErrorKind::LogonDenied,
+            "NTLMv2 response verification failed",
+            NStatusCode::LOGON_FAILURE,
+        ));
+    }
+
+    Ok(compute_hmac_md5(ntlm_v2_hash, nt_proof)?)
+}
+
 pub fn read_ntlm_v2_response(mut challenge_response: &[u8]) -> io::Result<(Vec<u8>, [u8; CHALLENGE_SIZE])> {
     let mut response = [0x00; HASH_SIZE];
     challenge_response.read_exact(response.as_mut())?;
@@ -3,7 +3,8 @@ use time::{Date, Month, OffsetDateTime};
 use crate::ntlm::messages::av_pair::*;
 use crate::ntlm::messages::computations::*;
 use crate::ntlm::messages::test::*;
-use crate::{AuthIdentity, Username};
+use crate::ntlm::{AuthIdentityBuffers, NegotiateFlags, NtlmConfig};
+use crate::{utils, AuthIdentity, Username};
 
 #[test]
 fn get_system_time_as_file_time_test_one_second_diff() {
@@ -33,7 +34,7 @@ fn get_system_time_as_file_time_test_returns_value_in_correct_case() {
 
 #[test]
 fn get_challenge_target_info_correct_writes_needed_values_with_timestamp() {
-    let challenge_target_info_buffer = get_challenge_target_info(TIMESTAMP).unwrap();
+    let challenge_target_info_buffer = get_challenge_target_info(&NtlmConfig::default(), TIMESTAMP).unwrap();
     let mut av_pairs = AvPair::buffer_to_av_pairs(&challenge_target_info_buffer).unwrap();
 
     // check that does not have duplicates
@@ -60,7 +61,7 @@ fn get_challenge_target_info_correct_writes_needed_values_with_timestamp() {
 
 #[test]
 fn get_challenge_target_info_correct_writes_needed_values_with_empty_timestamp() {
-    let challenge_target_info_buffer = get_challenge_target_info(TIMESTAMP).unwrap();
+    let challenge_target_info_buffer = get_challenge_target_info(&NtlmConfig::default(), TIMESTAMP).unwrap();
     let mut av_pairs = AvPair::buffer_to_av_pairs(&challenge_target_info_buffer).unwrap();
 
     // check that does not have duplicates
@@ -85,13 +86,69 @@ fn get_challenge_target_info_correct_writes_needed_values_with_empty_timestamp()
     }
 }
 
+#[test]
+fn get_challenge_target_info_writes_server_names() {
+    let config = NtlmConfig {
+        nb_computer_name: Some("SERVER".to_owned()),
+        nb_domain_name: Some("EXAMPLE".to_owned()),
+        dns_computer_name: Some("server.example.com".to_owned()),
+        dns_domain_name: Some("example.com".to_owned()),
+        dns_tree_name: Some("forest.example.com".to_owned()),
+        ..Default::default()
+    };
+    let challenge_target_info_buffer = get_challenge_target_info(&config, TIMESTAMP).unwrap();
+    let av_pairs = AvPair::buffer_to_av_pairs(&challenge_target_info_buffer).unwrap();
+
+    let mut names = 0;
+    for av_pair in av_pairs.iter() {
+        let (value, expected) = match av_pair {
+            AvPair::NbComputerName(value) => (value, "SERVER"),
+            AvPair::NbDomainName(value) => (value, "EXAMPLE"),
+            AvPair::DnsComputerName(value) => (value, "server.example.com"),
+            AvPair::DnsDomainName(value) => (value, "example.com"),
+            AvPair::DnsTreeName(value) => (value, "forest.example.com"),
+            _ => continue,
+        };
+        assert_eq!(utils::bytes_to_utf16_string(value), expected);
+        names += 1;
+    }
+    assert_eq!(names, 5);
+}
+
+#[test]
+fn get_challenge_target_info_omits_timestamp() {
+    let config = NtlmConfig {
+        omit_timestamp: true,
+        ..Default::default()
+    };
+    let challenge_target_info_buffer = get_challenge_target_info(&config, TIMESTAMP).unwrap();
+    let av_pairs = AvPair::buffer_to_av_pairs(&challenge_target_info_buffer).unwrap();
+
+    assert!(!av_pairs.iter().any(|av_pair| av_pair.as_u16() == AV_PAIR_TIMESTAMP));
+    assert!(!av_pairs.iter().any(|av_pair| av_pair.as_u16() == AV_PAIR_DNS_TREE_NAME));
+}
+
+#[test]
+fn get_authenticate_target_info_writes_target_name() {
+    let target_info = get_challenge_target_info(&NtlmConfig::default(), TIMESTAMP).unwrap();
+
+    let authenticate_target_info =
+        get_authenticate_target_info(target_info.as_ref(), None, Some("HTTP/server.example.com"), false).unwrap();
+    let av_pairs = AvPair::buffer_to_av_pairs(&authenticate_target_info).unwrap();
+
+    assert!(av_pairs.iter().any(|av_pair| matches!(
+        av_pair,
+        AvPair::TargetName(value) if utils::bytes_to_utf16_string(value) == "HTTP/server.example.com"
+    )));
+}
+
 #[test]
 fn get_authenticate_target_info_correct_returns_with_use_mic() {
     let send_single_host_data = false;
-    let target_info = get_challenge_target_info(TIMESTAMP).unwrap();
+    let target_info = get_challenge_target_info(&NtlmConfig::default(), TIMESTAMP).unwrap();
 
     let mut authenticate_target_info =
-        get_authenticate_target_info(target_info.as_ref(), None, send_single_host_data).unwrap();
+        get_authenticate_target_info(target_info.as_ref(), None, None, send_single_host_data).unwrap();
 
     assert_eq!(
         authenticate_target_info[authenticate_target_info.len() - AUTHENTICATE_TARGET_INFO_PADDING_SIZE..],
@@ -129,10 +186,10 @@ fn get_authenticate_target_info_correct_returns_with_use_mic() {
 #[test]
 fn get_authenticate_target_info_correct_returns_with_send_single_host_data() {
     let send_single_host_data = true;
-    let target_info = get_challenge_target_info(TIMESTAMP).unwrap();
+    let target_info = get_challenge_target_info(&NtlmConfig::default(), TIMESTAMP).unwrap();
 
     let mut authenticate_target_info =
-        get_authenticate_target_info(target_info.as_ref(), None, send_single_host_data).unwrap();
+        get_authenticate_target_info(target_info.as_ref(), None, None, send_single_host_data).unwrap();
 
     assert_eq!(
         authenticate_target_info[authenticate_target_info.len() - AUTHENTICATE_TARGET_INFO_PADDING_SIZE..],
@@ -171,10 +228,10 @@ fn get_authenticate_target_info_correct_returns_with_send_single_host_data() {
 #[test]
 fn get_authenticate_target_info_returns_without_principal_name() {
     let send_single_host_data = false;
-    let target_info = get_challenge_target_info(TIMESTAMP).unwrap();
+    let target_info = get_challenge_target_info(&NtlmConfig::default(), TIMESTAMP).unwrap();
 
     let mut authenticate_target_info =
-        get_authenticate_target_info(target_info.as_ref(), None, send_single_host_data).unwrap();
+        get_authenticate_target_info(target_info.as_ref(), None, None, send_single_host_data).unwrap();
 
     assert_eq!(
         authenticate_target_info[authenticate_target_info.len() - AUTHENTICATE_TARGET_INFO_PADDING_SIZE..],
@@ -502,3 +559,122 @@ fn av_pair_from_buffer_fails_on_invalid_av_type() {
     let buffer = [0xa1, 0x0, 0x1, 0x0, 0x0];
     assert!(AvPair::from_buffer(buffer.as_ref()).is_err());
 }
+
+// [MS-NLMP 4.2.1 Common Values](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-nlmp/be0d3a1e-9f4b-4c45-b7b1-2d4e1e2bc36c)
+const SPEC_NT_HASH: [u8; 16] = [
+    0xa4, 0xf4, 0x9c, 0x40, 0x65, 0x10, 0xbd, 0xca, 0xb6, 0x82, 0x4e, 0xe7, 0xc3, 0x0f, 0xd8, 0x52,
+];
+const SPEC_SERVER_CHALLENGE: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+const SPEC_CLIENT_CHALLENGE: [u8; 8] = [0xaa; 8];
+const SPEC_SESSION_BASE_KEY: [u8; 16] = [
+    0xd8, 0x72, 0x62, 0xb0, 0xcd, 0xe4, 0xb1, 0xcb, 0x74, 0x99, 0xbe, 0xcc, 0xcd, 0xf1, 0x07, 0x84,
+];
+
+#[test]
+fn compute_nt_hash_of_password() {
+    let identity = AuthIdentityBuffers::from(AuthIdentity {
+        username: Username::new("User", Some("Domain")).unwrap(),
+        password: String::from("Password").into(),
+    });
+
+    assert_eq!(SPEC_NT_HASH, compute_nt_hash(&identity).unwrap());
+}
+
+#[test]
+fn compute_ntlm_v1_response_correct_computes_response() {
+    let expected_nt_challenge_response = [
+        0x67, 0xc4, 0x30, 0x11, 0xf3, 0x02, 0x98, 0xa2, 0xad, 0x35, 0xec, 0xe6, 0x4f, 0x16, 0x33, 0x1c, 0x44, 0xbd,
+        0xbe, 0xd9, 0x27, 0x84, 0x1f, 0x94,
+    ];
+
+    let (lm_challenge_response, nt_challenge_response) = compute_ntlm_v1_response(
+        &SPEC_NT_HASH,
+        &SPEC_SERVER_CHALLENGE,
+        &SPEC_CLIENT_CHALLENGE,
+        NegotiateFlags::empty(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_nt_challenge_response, nt_challenge_response);
+    assert_eq!(nt_challenge_response, lm_challenge_response);
+    assert_eq!(
+        SPEC_SESSION_BASE_KEY,
+        verify_ntlm_v1_response(
+            &SPEC_NT_HASH,
+            &SPEC_SERVER_CHALLENGE,
+            &lm_challenge_response,
+            &nt_challenge_response,
+            NegotiateFlags::empty(),
+        )
+        .unwrap()
+    );
+}
+
+#[test]
+fn compute_ntlm_v1_response_correct_computes_ntlm2_session_response() {
+    let flags = NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY;
+    let expected_nt_challenge_response = [
+        0x75, 0x37, 0xf8, 0x03, 0xae, 0x36, 0x71, 0x28, 0xca, 0x45, 0x82, 0x04, 0xbd, 0xe7, 0xca, 0xf8, 0x1e, 0x97,
+        0xed, 0x26, 0x83, 0x26, 0x72, 0x32,
+    ];
+    let expected_key_exchange_key = [
+        0xeb, 0x93, 0x42, 0x9a, 0x8b, 0xd9, 0x52, 0xf8, 0xb8, 0x9c, 0x55, 0xb8, 0x7f, 0x47, 0x5e, 0xdc,
+    ];
+
+    let (lm_challenge_response, nt_challenge_response) =
+        compute_ntlm_v1_response(&SPEC_NT_HASH, &SPEC_SERVER_CHALLENGE, &SPEC_CLIENT_CHALLENGE, flags).unwrap();
+
+    assert_eq!(expected_nt_challenge_response, nt_challenge_response);
+    assert_eq!(SPEC_CLIENT_CHALLENGE, lm_challenge_response[..8]);
+    assert_eq!([0x00; 16], lm_challenge_response[8..]);
+
+    let session_base_key = verify_ntlm_v1_response(
+        &SPEC_NT_HASH,
+        &SPEC_SERVER_CHALLENGE,
+        &lm_challenge_response,
+        &nt_challenge_response,
+        flags,
+    )
+    .unwrap();
+    assert_eq!(SPEC_SESSION_BASE_KEY, session_base_key);
+    assert_eq!(
+        expected_key_exchange_key,
+        compute_ntlm_v1_key_exchange_key(&session_base_key, &SPEC_SERVER_CHALLENGE, &lm_challenge_response, flags)
+            .unwrap()
+    );
+}
+
+#[test]
+fn compute_ntlm_v1_key_exchange_key_fails_on_short_lm_challenge_response() {
+    let err = compute_ntlm_v1_key_exchange_key(
+        &SPEC_SESSION_BASE_KEY,
+        &SPEC_SERVER_CHALLENGE,
+        &[0x01, 0x02, 0x03],
+        NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY,
+    )
+    .unwrap_err();
+
+    assert_eq!(crate::ErrorKind::InvalidToken, err.error_type);
+}
+
+#[test]
+fn verify_ntlm_v1_response_fails_on_wrong_nt_hash() {
+    let (lm_challenge_response, nt_challenge_response) = compute_ntlm_v1_response(
+        &SPEC_NT_HASH,
+        &SPEC_SERVER_CHALLENGE,
+        &SPEC_CLIENT_CHALLENGE,
+        NegotiateFlags::empty(),
+    )
+    .unwrap();
+
+    let err = verify_ntlm_v1_response(
+        &[0x42; 16],
+        &SPEC_SERVER_CHALLENGE,
+        &lm_challenge_response,
+        &nt_challenge_response,
+        NegotiateFlags::empty(),
+    )
+    .unwrap_err();
+
+    assert_eq!(crate::ErrorKind::LogonDenied, err.error_type);
+}
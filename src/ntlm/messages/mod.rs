@@ -9,7 +9,9 @@ mod computations;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io;
 
-use crate::ntlm::{NegotiateFlags, NTLM_VERSION_SIZE};
+use crate::crypto::Rc4;
+use crate::ntlm::messages::computations::generate_signing_key;
+use crate::ntlm::{NegotiateFlags, Ntlm, NtlmConfig, NTLM_VERSION_SIZE, SESSION_KEY_SIZE};
 
 const NTLM_SIGNATURE: &[u8; NTLM_SIGNATURE_SIZE] = b"NTLMSSP\0";
 const NTLM_SIGNATURE_SIZE: usize = 8;
@@ -118,3 +120,70 @@ pub fn read_ntlm_header(mut stream: impl io::Read, expected_message_type: Messag
 
     Ok(())
 }
+
+/// Checks that the connectionless mode is allowed by the NTLM policy.
+fn check_connectionless_flags(config: &NtlmConfig, flags: NegotiateFlags) -> crate::Result<()> {
+    if !flags.contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_DATAGRAM) {
+        return Ok(());
+    }
+
+    if !config.datagram {
+        return Err(crate::Error::new(
+            crate::ErrorKind::UnsupportedFunction,
+            "connectionless NTLM is disabled by the NTLM policy",
+        ));
+    }
+
+    if !flags.contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_KEY_EXCH)
+        || !flags.contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY)
+    {
+        return Err(crate::Error::new(
+            crate::ErrorKind::UnsupportedFunction,
+            "connectionless NTLM requires the key exchange and the extended session security",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Derives the signing and sealing keys of the session from the exported session key.
+fn init_session_security(context: &mut Ntlm, session_key: &[u8; SESSION_KEY_SIZE], initiator: bool) {
+    if context
+        .flags
+        .contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY)
+    {
+        let (send_sign_magic, recv_sign_magic, send_seal_magic, recv_seal_magic) = if initiator {
+            (
+                CLIENT_SIGN_MAGIC,
+                SERVER_SIGN_MAGIC,
+                CLIENT_SEAL_MAGIC,
+                SERVER_SEAL_MAGIC,
+            )
+        } else {
+            (
+                SERVER_SIGN_MAGIC,
+                CLIENT_SIGN_MAGIC,
+                SERVER_SEAL_MAGIC,
+                CLIENT_SEAL_MAGIC,
+            )
+        };
+
+        let send_sealing_key = generate_signing_key(session_key, send_seal_magic);
+        let recv_sealing_key = generate_signing_key(session_key, recv_seal_magic);
+
+        context.send_signing_key = generate_signing_key(session_key, send_sign_magic);
+        context.recv_signing_key = generate_signing_key(session_key, recv_sign_magic);
+        context.send_sealing_key = Some(Rc4::new(&send_sealing_key));
+        context.recv_sealing_key = Some(Rc4::new(&recv_sealing_key));
+
+        if context.flags.contains(NegotiateFlags::NTLM_SSP_NEGOTIATE_DATAGRAM) {
+            context.connectionless_sealing_keys = Some((send_sealing_key, recv_sealing_key));
+        }
+    } else {
+        // Without the extended session security, the exported session key is used as the sealing key,
+        // and the single RC4 handle is shared by both directions.
+        context.extended_session_security = false;
+        context.send_sealing_key = Some(Rc4::new(session_key));
+        context.recv_sealing_key = None;
+    }
+}
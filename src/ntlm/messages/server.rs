@@ -8,4 +8,4 @@ mod test;
 pub use self::authenticate::read_authenticate;
 pub use self::challenge::write_challenge;
 pub use self::complete_authenticate::complete_authenticate;
-pub use self::negotiate::read_negotiate;
+pub use self::negotiate::{read_negotiate, skip_negotiate};
@@ -5,12 +5,14 @@ use byteorder::{LittleEndian, ReadBytesExt};
 use crate::crypto::compute_md5_channel_bindings_hash;
 use crate::ntlm::messages::av_pair::{AvPair, MsvAvFlags, AV_PAIR_CHANNEL_BINDINGS};
 use crate::ntlm::messages::computations::*;
-use crate::ntlm::messages::{read_ntlm_header, try_read_version, MessageFields, MessageTypes};
+use crate::ntlm::messages::{
+    check_connectionless_flags, read_ntlm_header, try_read_version, MessageFields, MessageTypes,
+};
 use crate::ntlm::{
-    AuthIdentityBuffers, AuthenticateMessage, ChannelBindings, Mic, NegotiateFlags, Ntlm, NtlmState,
+    AuthIdentityBuffers, AuthenticateMessage, ChannelBindings, Mic, NegotiateFlags, Ntlm, NtlmConfig, NtlmState,
     ENCRYPTED_RANDOM_SESSION_KEY_SIZE, MESSAGE_INTEGRITY_CHECK_SIZE,
 };
-use crate::SecurityStatus;
+use crate::{utils, SecurityStatus};
 
 const HEADER_SIZE: usize = 64;
 
@@ -32,19 +34,28 @@ pub fn read_authenticate(context: &mut Ntlm, mut stream: impl io::Read) -> crate
 
     read_ntlm_header(&mut buffer, MessageTypes::Authenticate)?;
     let (mut message_fields, flags) = read_header(&mut buffer)?;
+    check_connectionless_flags(context.config(), flags)?;
     context.flags = flags;
     let _version = try_read_version(context.flags, &mut buffer)?;
     let mic = read_payload(flags, &mut message_fields, &mut buffer)?;
     let message = buffer.into_inner();
 
-    let (authenticate_message, updated_identity) = process_message_fields(
+    // the anonymous client sends the empty user name and NtChallengeResponse, and the LmChallengeResponse is Z(1) or empty
+    context.anonymous = message_fields.user_name.buffer.is_empty()
+        && message_fields.nt_challenge_response.buffer.is_empty()
+        && matches!(message_fields.lm_challenge_response.buffer.as_slice(), [] | [0x00]);
+
+    let (authenticate_message, updated_identity, channel_bindings_verified) = process_message_fields(
+        &context.config,
         &context.identity,
         message_fields,
         mic,
         message,
         &context.channel_bindings,
+        context.anonymous,
     )?;
     context.identity = Some(updated_identity);
+    context.channel_bindings_verified = channel_bindings_verified;
     context.authenticate_message = Some(authenticate_message);
 
     context.state = NtlmState::Completion;
@@ -140,49 +151,85 @@ where
 }
 
 fn process_message_fields(
+    config: &NtlmConfig,
     identity: &Option<AuthIdentityBuffers>,
     message_fields: AuthenticateMessageFields,
     mic: Option<Mic>,
     authenticate_message: Vec<u8>,
     channel_bindings: &Option<ChannelBindings>,
-) -> crate::Result<(AuthenticateMessage, AuthIdentityBuffers)> {
-    if message_fields.nt_challenge_response.buffer.is_empty() {
+    anonymous: bool,
+) -> crate::Result<(AuthenticateMessage, AuthIdentityBuffers, bool)> {
+    if !anonymous && message_fields.nt_challenge_response.buffer.is_empty() {
         return Err(crate::Error::new(
             crate::ErrorKind::InvalidToken,
             "NtChallengeResponse cannot be empty",
         ));
     }
 
-    let (target_info, client_challenge) = read_ntlm_v2_response(message_fields.nt_challenge_response.buffer.as_ref())?;
+    let (mic, target_name, channel_bindings_verified) = if anonymous {
+        (None, None, false)
+    } else if message_fields.nt_challenge_response.buffer.len() == NTLM_V1_RESPONSE_SIZE {
+        if !config.ntlm_v1 {
+            return Err(crate::Error::new(
+                crate::ErrorKind::LogonDenied,
+                "NTLMv1 responses are disabled by the NTLM policy",
+            ));
+        }
 
-    let av_pairs = AvPair::buffer_to_av_pairs(target_info.as_ref())?;
+        // the NTLMv1 response has no target info, so the MIC cannot be used
+        (None, None, false)
+    } else {
+        let (target_info, _) = read_ntlm_v2_response(message_fields.nt_challenge_response.buffer.as_ref())?;
 
-    let mic = if mic.is_some() {
-        let challenge_response_av_flags = get_av_flags_from_response(&av_pairs)?;
-        if challenge_response_av_flags.contains(MsvAvFlags::MESSAGE_INTEGRITY_CHECK) {
-            mic
+        let av_pairs = AvPair::buffer_to_av_pairs(target_info.as_ref())?;
+
+        let mic = if mic.is_some() {
+            let challenge_response_av_flags = get_av_flags_from_response(&av_pairs)?;
+            if challenge_response_av_flags.contains(MsvAvFlags::MESSAGE_INTEGRITY_CHECK) {
+                mic
+            } else {
+                None
+            }
         } else {
             None
+        };
+
+        // without the MIC, the NEGOTIATE and CHALLENGE messages of the relayed authentication can be altered
+        if mic.is_none() && !config.target_names.is_empty() {
+            return Err(crate::Error::new(
+                crate::ErrorKind::BadBindings,
+                "the MIC is required when the target names are configured",
+            ));
         }
-    } else {
-        None
-    };
 
-    if let Some(AvPair::ChannelBindings(hash)) = av_pairs
-        .iter()
-        .find(|av_pair| av_pair.as_u16() == AV_PAIR_CHANNEL_BINDINGS)
-    {
-        if let Some(channel_bindings) = channel_bindings.as_ref() {
-            if compute_md5_channel_bindings_hash(channel_bindings) != *hash {
-                return Err(crate::Error::new(
-                    crate::ErrorKind::BadBindings,
-                    "Channel bindings hash mismatch",
-                ));
+        let mut channel_bindings_verified = false;
+        if let Some(AvPair::ChannelBindings(hash)) = av_pairs
+            .iter()
+            .find(|av_pair| av_pair.as_u16() == AV_PAIR_CHANNEL_BINDINGS)
+        {
+            if let Some(channel_bindings) = channel_bindings.as_ref() {
+                if compute_md5_channel_bindings_hash(channel_bindings) != *hash {
+                    return Err(crate::Error::new(
+                        crate::ErrorKind::BadBindings,
+                        "Channel bindings hash mismatch",
+                    ));
+                }
+
+                channel_bindings_verified = true;
             }
         }
-    }
 
-    // will not set workstation because it is not used anywhere
+        let target_name = av_pairs.iter().find_map(|av_pair| match av_pair {
+            AvPair::TargetName(target_name) => Some(utils::bytes_to_utf16_string(target_name)),
+            _ => None,
+        });
+
+        (mic, target_name, channel_bindings_verified)
+    };
+
+    if !anonymous {
+        check_target_name(&config.target_names, target_name.as_deref())?;
+    }
 
     let encrypted_random_session_key: Option<[u8; ENCRYPTED_RANDOM_SESSION_KEY_SIZE]> = match message_fields.encrypted_random_session_key.buffer.len() {
       0 => None,
@@ -199,10 +246,10 @@ fn process_message_fields(
       }
     };
 
-    let mut identity = if let Some(identity) = identity {
-        identity.clone()
-    } else {
-        AuthIdentityBuffers::default()
+    // the server credentials must not be used for the anonymous client
+    let mut identity = match identity {
+        Some(identity) if !anonymous => identity.clone(),
+        _ => AuthIdentityBuffers::default(),
     };
 
     if !message_fields.user_name.buffer.is_empty() {
@@ -212,14 +259,39 @@ fn process_message_fields(
         identity.domain = message_fields.domain_name.buffer;
     }
 
-    Ok((
-        AuthenticateMessage::new(
-            authenticate_message,
-            mic,
-            target_info,
-            client_challenge,
-            encrypted_random_session_key,
-        ),
-        identity,
-    ))
+    let mut authenticate_message = AuthenticateMessage::new(
+        authenticate_message,
+        mic,
+        message_fields.lm_challenge_response.buffer,
+        message_fields.nt_challenge_response.buffer,
+        encrypted_random_session_key,
+    );
+    // passed through to the domain controller by the NtlmValidator
+    authenticate_message.workstation = message_fields.workstation.buffer;
+
+    Ok((authenticate_message, identity, channel_bindings_verified))
+}
+
+fn check_target_name(expected_target_names: &[String], target_name: Option<&str>) -> crate::Result<()> {
+    if expected_target_names.is_empty() {
+        return Ok(());
+    }
+
+    match target_name {
+        Some(target_name)
+            if expected_target_names
+                .iter()
+                .any(|expected_target_name| expected_target_name.eq_ignore_ascii_case(target_name)) =>
+        {
+            Ok(())
+        }
+        Some(target_name) => Err(crate::Error::new(
+            crate::ErrorKind::BadBindings,
+            format!("unexpected MsvAvTargetName: {}", target_name),
+        )),
+        None => Err(crate::Error::new(
+            crate::ErrorKind::BadBindings,
+            "the AUTHENTICATE message does not contain the MsvAvTargetName",
+        )),
+    }
 }
@@ -41,7 +41,7 @@ pub fn write_challenge(context: &mut Ntlm, mut transport: impl io::Write) -> cra
 
     let server_challenge = generate_challenge()?;
     let timestamp = now_file_time_timestamp()?;
-    let target_info = get_challenge_target_info(timestamp)?;
+    let target_info = get_challenge_target_info(context.config(), timestamp)?;
 
     context.flags = get_flags(context.flags);
     let message_fields = ChallengeMessageFields::new(target_info.as_ref(), CHALLENGE_MESSAGE_OFFSET as u32);
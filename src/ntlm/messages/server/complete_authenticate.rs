@@ -1,16 +1,24 @@
+use time::OffsetDateTime;
+
+use crate::credssp::NStatusCode;
 use crate::crypto::{Rc4, HASH_SIZE};
 use crate::ntlm::messages::computations::*;
-use crate::ntlm::messages::{CLIENT_SEAL_MAGIC, CLIENT_SIGN_MAGIC, SERVER_SEAL_MAGIC, SERVER_SIGN_MAGIC};
-use crate::ntlm::{Mic, NegotiateFlags, Ntlm, NtlmState, MESSAGE_INTEGRITY_CHECK_SIZE, SESSION_KEY_SIZE};
-use crate::SecurityStatus;
+use crate::ntlm::messages::init_session_security;
+use crate::ntlm::{
+    AuthIdentityBuffers, Mic, NegotiateFlags, NtHashLookup, NtHashProvider, Ntlm, NtlmState, NtlmValidationRequest,
+    ANONYMOUS_DOMAIN_NAME, ANONYMOUS_USER_NAME, MESSAGE_INTEGRITY_CHECK_SIZE, SESSION_KEY_SIZE,
+};
+use crate::{utils, ContextClientIdentity, SecurityPackageType, SecurityStatus};
 
 pub fn complete_authenticate(context: &mut Ntlm) -> crate::Result<SecurityStatus> {
     check_state(context.state)?;
 
+    // there is no NEGOTIATE message in the connectionless mode
     let negotiate_message = context
         .negotiate_message
         .as_ref()
-        .expect("negotiate message must be set on negotiate phase");
+        .map(|negotiate_message| negotiate_message.message.as_slice())
+        .unwrap_or_default();
     let challenge_message = context
         .challenge_message
         .as_ref()
@@ -20,44 +28,137 @@ pub fn complete_authenticate(context: &mut Ntlm) -> crate::Result<SecurityStatus
         .as_ref()
         .expect("authenticate message must be set on authenticate phase");
 
-    let ntlm_v2_hash = compute_ntlm_v2_hash(
-        context
-            .identity
-            .as_ref()
-            .expect("Identity must be present on complete_authenticate phase"),
-    )?;
-    let (_, key_exchange_key) = compute_ntlm_v2_response(
-        authenticate_message.client_challenge.as_ref(),
-        challenge_message.server_challenge.as_ref(),
-        authenticate_message.target_info.as_ref(),
-        ntlm_v2_hash.as_ref(),
-        challenge_message.timestamp,
-    )?;
+    let identity = context
+        .identity
+        .as_ref()
+        .expect("Identity must be present on complete_authenticate phase");
+    // the NTLMv1 response is allowed by the policy, otherwise it is rejected on the read_authenticate phase
+    let ntlm_v1 = authenticate_message.nt_challenge_response.len() == NTLM_V1_RESPONSE_SIZE;
+    let mut validation_info = None;
+    let key_exchange_key = if context.anonymous {
+        // the session base key of the anonymous authentication is Z(16)
+        [0x00; HASH_SIZE]
+    } else if let Some(validator) = context.config.validator.as_ref() {
+        let info = validator.validate(&NtlmValidationRequest {
+            username: utils::bytes_to_utf16_string(identity.user.as_ref()),
+            domain: utils::bytes_to_utf16_string(identity.domain.as_ref()),
+            workstation: utils::bytes_to_utf16_string(&authenticate_message.workstation),
+            server_challenge: challenge_message.server_challenge,
+            lm_challenge_response: authenticate_message.lm_challenge_response.clone(),
+            nt_challenge_response: authenticate_message.nt_challenge_response.clone(),
+        })?;
+        let user_session_key = info.user_session_key;
+        validation_info = Some(info);
+
+        if ntlm_v1 {
+            compute_ntlm_v1_key_exchange_key(
+                &user_session_key,
+                &challenge_message.server_challenge,
+                &authenticate_message.lm_challenge_response,
+                context.flags,
+            )?
+        } else {
+            user_session_key
+        }
+    } else if ntlm_v1 {
+        let nt_hash = if let Some(nt_hash_provider) = context.config.nt_hash_provider.as_ref() {
+            lookup_nt_hash(nt_hash_provider.as_ref(), identity)?
+        } else {
+            compute_nt_hash(identity)?
+        };
+
+        let session_base_key = verify_ntlm_v1_response(
+            &nt_hash,
+            &challenge_message.server_challenge,
+            &authenticate_message.lm_challenge_response,
+            &authenticate_message.nt_challenge_response,
+            context.flags,
+        )?;
+
+        compute_ntlm_v1_key_exchange_key(
+            &session_base_key,
+            &challenge_message.server_challenge,
+            &authenticate_message.lm_challenge_response,
+            context.flags,
+        )?
+    } else {
+        let ntlm_v2_hash = if let Some(nt_hash_provider) = context.config.nt_hash_provider.as_ref() {
+            let nt_hash = lookup_nt_hash(nt_hash_provider.as_ref(), identity)?;
+
+            compute_ntlm_v2_hash_from_nt_hash(&nt_hash, &identity.user, &identity.domain)?
+        } else {
+            compute_ntlm_v2_hash(identity)?
+        };
+
+        // the NTProofStr covers the whole NTLMv2 response including the target info,
+        // so the MsvAvFlags and MsvAvTargetName read from it are trusted only if it is valid
+        verify_ntlm_v2_response(
+            authenticate_message.nt_challenge_response.as_ref(),
+            challenge_message.server_challenge.as_ref(),
+            ntlm_v2_hash.as_ref(),
+        )?
+    };
     let session_key = authenticate_message
         .encrypted_random_session_key
         .map_or(Ok(key_exchange_key), |encrypted_random_session_key| {
             get_session_key(key_exchange_key, &encrypted_random_session_key, context.flags)
         })?;
 
-    context.send_signing_key = generate_signing_key(session_key.as_ref(), SERVER_SIGN_MAGIC);
-    context.recv_signing_key = generate_signing_key(session_key.as_ref(), CLIENT_SIGN_MAGIC);
-    context.send_sealing_key = Some(Rc4::new(&generate_signing_key(session_key.as_ref(), SERVER_SEAL_MAGIC)));
-    context.recv_sealing_key = Some(Rc4::new(&generate_signing_key(session_key.as_ref(), CLIENT_SEAL_MAGIC)));
-
     check_mic_correctness(
-        negotiate_message.message.as_ref(),
+        negotiate_message,
         challenge_message.message.as_ref(),
         authenticate_message.message.as_ref(),
         &authenticate_message.mic,
         session_key.as_ref(),
     )?;
 
+    let (principal, realm) = if context.anonymous {
+        (ANONYMOUS_USER_NAME.to_owned(), ANONYMOUS_DOMAIN_NAME.to_owned())
+    } else {
+        (
+            utils::bytes_to_utf16_string(identity.user.as_ref()),
+            utils::bytes_to_utf16_string(identity.domain.as_ref()),
+        )
+    };
+    let client_identity = ContextClientIdentity {
+        principal,
+        realm,
+        package: SecurityPackageType::Ntlm,
+        logon_time: OffsetDateTime::now_utc(),
+        // the MIC is kept only if it is required by the client, and it is verified above
+        mic_verified: authenticate_message.mic.is_some(),
+        channel_bindings_verified: context.channel_bindings_verified,
+    };
+
+    init_session_security(context, &session_key, false);
+
     context.session_key = Some(session_key);
+    context.validation_info = validation_info;
+    context.client_identity = Some(client_identity);
     context.state = NtlmState::Final;
 
     Ok(SecurityStatus::Ok)
 }
 
+fn lookup_nt_hash(nt_hash_provider: &dyn NtHashProvider, identity: &AuthIdentityBuffers) -> crate::Result<[u8; 16]> {
+    let username = utils::bytes_to_utf16_string(identity.user.as_ref());
+    let domain = utils::bytes_to_utf16_string(identity.domain.as_ref());
+
+    match nt_hash_provider.nt_hash(&username, &domain)? {
+        NtHashLookup::NtHash(nt_hash) => Ok(nt_hash),
+        NtHashLookup::NoSuchUser => Err(crate::Error::new_with_nstatus(
+            crate::ErrorKind::LogonDenied,
+            format!("user {}\\{} does not exist", domain, username),
+            NStatusCode::NO_SUCH_USER,
+        )),
+        NtHashLookup::AccountDisabled => Err(crate::Error::new_with_nstatus(
+            crate::ErrorKind::LogonDenied,
+            format!("account {}\\{} is disabled", domain, username),
+            NStatusCode::ACCOUNT_DISABLED,
+        )),
+    }
+}
+
 fn check_state(state: NtlmState) -> crate::Result<()> {
     if state != NtlmState::Completion {
         Err(crate::Error::new(
@@ -2,7 +2,9 @@ use std::io;
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
-use crate::ntlm::messages::{read_ntlm_header, try_read_version, MessageFields, MessageTypes};
+use crate::ntlm::messages::{
+    check_connectionless_flags, read_ntlm_header, try_read_version, MessageFields, MessageTypes,
+};
 use crate::ntlm::{NegotiateFlags, NegotiateMessage, Ntlm, NtlmState};
 use crate::SecurityStatus;
 
@@ -17,6 +19,7 @@ pub fn read_negotiate(context: &mut Ntlm, mut stream: impl io::Read) -> crate::R
 
     read_ntlm_header(&mut buffer, MessageTypes::Negotiate)?;
     context.flags = read_header(&mut buffer)?;
+    check_connectionless_flags(context.config(), context.flags)?;
     let _version = try_read_version(context.flags, &mut buffer)?;
 
     let message = buffer.into_inner();
@@ -27,6 +30,29 @@ pub fn read_negotiate(context: &mut Ntlm, mut stream: impl io::Read) -> crate::R
     Ok(crate::SecurityStatus::ContinueNeeded)
 }
 
+/// Starts the connectionless authentication, which has no NEGOTIATE message, so the server offers
+/// all the capabilities required by the connectionless mode in the CHALLENGE message.
+pub fn skip_negotiate(context: &mut Ntlm) -> crate::Result<SecurityStatus> {
+    check_state(context.state)?;
+
+    context.flags = NegotiateFlags::NTLM_SSP_NEGOTIATE_DATAGRAM
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE_KEY_EXCH
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE_EXTENDED_SESSION_SECURITY
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE128
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE56
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE_SIGN
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE_SEAL
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE_ALWAYS_SIGN
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE_NTLM
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE_REQUEST_TARGET
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE_UNICODE
+        | NegotiateFlags::NTLM_SSP_NEGOTIATE_VERSION;
+
+    context.state = NtlmState::Challenge;
+
+    Ok(crate::SecurityStatus::ContinueNeeded)
+}
+
 fn check_state(state: NtlmState) -> crate::Result<()> {
     if state != NtlmState::Negotiate {
         Err(crate::Error::new(
@@ -507,6 +507,63 @@ fn read_authenticate_domain_logon_correct_reads_domain_name() {
     assert_eq!(expected.as_ref(), context.identity.as_ref().unwrap().domain.as_slice());
 }
 
+#[test]
+fn read_authenticate_domain_logon_correct_reads_workstation() {
+    let buffer = DOMAIN_AUTHENTICATE_MESSAGE;
+    let mut context = Ntlm::new();
+    context.negotiate_message = Some(NegotiateMessage::new(Vec::new()));
+    context.challenge_message = Some(ChallengeMessage::new(
+        vec![0x04, 0x05, 0x06],
+        Vec::new(),
+        [0x00; CHALLENGE_SIZE],
+        0,
+    ));
+    context.state = NtlmState::Authenticate;
+
+    authenticate::read_authenticate(&mut context, buffer.as_ref()).unwrap();
+
+    assert_eq!(
+        "WINDOWS7",
+        crate::utils::bytes_to_utf16_string(&context.authenticate_message.unwrap().workstation)
+    );
+}
+
+#[test]
+fn read_authenticate_requires_mic_when_target_names_are_configured() {
+    let authenticate = |message: &[u8]| {
+        let mut context = Ntlm::with_config(NtlmConfig {
+            target_names: vec!["TERMSRV/0.0.0.0".to_owned()],
+            ..Default::default()
+        });
+        context.negotiate_message = Some(NegotiateMessage::new(Vec::new()));
+        context.challenge_message = Some(ChallengeMessage::new(
+            vec![0x04, 0x05, 0x06],
+            Vec::new(),
+            [0x00; CHALLENGE_SIZE],
+            0,
+        ));
+        context.state = NtlmState::Authenticate;
+
+        authenticate::read_authenticate(&mut context, message)
+    };
+
+    authenticate(LOCAL_AUTHENTICATE_MESSAGE.as_ref()).unwrap();
+
+    // the relay clears the MESSAGE_INTEGRITY_CHECK bit of the MsvAvFlags to skip the MIC verification
+    let av_flags = [0x06, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00, 0x00];
+    let av_flags_start = LOCAL_AUTHENTICATE_MESSAGE
+        .windows(av_flags.len())
+        .position(|window| window == av_flags)
+        .unwrap();
+    let mut message = LOCAL_AUTHENTICATE_MESSAGE.to_vec();
+    message[av_flags_start + 4] = 0x00;
+
+    assert_eq!(
+        crate::ErrorKind::BadBindings,
+        authenticate(&message).unwrap_err().error_type
+    );
+}
+
 #[test]
 fn read_authenticate_fails_on_incorrect_state() {
     let buffer = DOMAIN_AUTHENTICATE_MESSAGE;
@@ -596,14 +653,46 @@ fn complete_authenticate_does_not_fail_on_correct_mic() {
             ],
             64,
         )),
-        DOMAIN_TARGET_INFO.to_vec(),
-        DOMAIN_CLIENT_CHALLENGE,
+        Vec::new(),
+        test_nt_challenge_response(&DOMAIN_CLIENT_CHALLENGE, &DOMAIN_TARGET_INFO),
         Some(DOMAIN_ENCRYPTED_SESSION_KEY),
     ));
 
     complete_authenticate(&mut context).unwrap();
 }
 
+#[test]
+fn complete_authenticate_fails_on_altered_nt_challenge_response() {
+    let mut context = Ntlm::new();
+
+    context.identity = Some(TEST_CREDENTIALS.clone());
+    context.flags = NegotiateFlags::NTLM_SSP_NEGOTIATE_KEY_EXCH;
+    context.state = NtlmState::Completion;
+    context.negotiate_message = Some(NegotiateMessage::new(vec![0x01, 0x02, 0x03]));
+    context.challenge_message = Some(ChallengeMessage::new(
+        vec![0x04, 0x05, 0x06],
+        Vec::new(),
+        [0x00; CHALLENGE_SIZE],
+        0,
+    ));
+    // the target info at the end of the NTLMv2 response is altered, and the MIC is dropped
+    let mut nt_challenge_response = test_nt_challenge_response(&DOMAIN_CLIENT_CHALLENGE, &DOMAIN_TARGET_INFO);
+    let last = nt_challenge_response.len() - 1;
+    nt_challenge_response[last] ^= 0x01;
+    context.authenticate_message = Some(AuthenticateMessage::new(
+        DOMAIN_AUTHENTICATE_MESSAGE.to_vec(),
+        None,
+        Vec::new(),
+        nt_challenge_response,
+        Some(DOMAIN_ENCRYPTED_SESSION_KEY),
+    ));
+
+    assert_eq!(
+        crate::ErrorKind::LogonDenied,
+        complete_authenticate(&mut context).unwrap_err().error_type
+    );
+}
+
 #[test]
 fn complete_authenticate_fails_on_incorrect_challenge_message() {
     let mut context = Ntlm::new();
@@ -626,8 +715,8 @@ fn complete_authenticate_fails_on_incorrect_challenge_message() {
             ],
             64,
         )),
-        DOMAIN_TARGET_INFO.to_vec(),
-        DOMAIN_CLIENT_CHALLENGE,
+        Vec::new(),
+        test_nt_challenge_response(&DOMAIN_CLIENT_CHALLENGE, &DOMAIN_TARGET_INFO),
         Some(DOMAIN_ENCRYPTED_SESSION_KEY),
     ));
 
@@ -1,6 +1,7 @@
 use std::sync::LazyLock;
 
-use crate::ntlm::{AuthIdentityBuffers, NTLM_VERSION_SIZE};
+use crate::ntlm::messages::computations::{compute_ntlm_v2_hash, compute_ntlm_v2_response};
+use crate::ntlm::{AuthIdentityBuffers, CHALLENGE_SIZE, NTLM_VERSION_SIZE};
 use crate::*;
 
 pub const SIGNATURE_SIZE: usize = 8;
@@ -126,3 +127,12 @@ pub static TEST_CREDENTIALS: LazyLock<AuthIdentityBuffers> = LazyLock::new(|| {
     }
     .into()
 });
+
+/// Computes the NTLMv2 response of the [TEST_CREDENTIALS] to the zero server challenge with the zero timestamp.
+pub fn test_nt_challenge_response(client_challenge: &[u8], target_info: &[u8]) -> Vec<u8> {
+    let ntlm_v2_hash = compute_ntlm_v2_hash(&TEST_CREDENTIALS).unwrap();
+
+    compute_ntlm_v2_response(client_challenge, &[0x00; CHALLENGE_SIZE], target_info, &ntlm_v2_hash, 0)
+        .unwrap()
+        .0
+}
@@ -4,25 +4,27 @@ mod messages;
 mod test;
 
 use std::fmt::Debug;
-use std::io;
 use std::sync::LazyLock;
+use std::{io, mem};
 
 use bitflags::bitflags;
 use byteorder::{LittleEndian, WriteBytesExt};
 use messages::{client, server};
 
-pub use self::config::NtlmConfig;
+pub use self::config::{
+    NtHashLookup, NtHashProvider, NtlmConfig, NtlmValidationInfo, NtlmValidationRequest, NtlmValidator,
+};
 use super::channel_bindings::ChannelBindings;
-use crate::crypto::{compute_hmac_md5, Rc4, HASH_SIZE};
+use crate::crypto::{compute_hmac_md5, compute_md5, Rc4, HASH_SIZE};
 use crate::generator::GeneratorInitSecurityContext;
 use crate::utils::{extract_encrypted_data, save_decrypted_data};
 use crate::{
     AcceptSecurityContextResult, AcquireCredentialsHandleResult, AuthIdentity, AuthIdentityBuffers, CertTrustStatus,
-    ClientRequestFlags, ClientResponseFlags, ContextNames, ContextSizes, CredentialUse, DecryptionFlags,
-    EncryptionFlags, Error, ErrorKind, FilledAcceptSecurityContext, FilledAcquireCredentialsHandle,
+    ClientRequestFlags, ClientResponseFlags, ContextClientIdentity, ContextNames, ContextSizes, CredentialUse,
+    DecryptionFlags, EncryptionFlags, Error, ErrorKind, FilledAcceptSecurityContext, FilledAcquireCredentialsHandle,
     FilledInitializeSecurityContext, InitializeSecurityContextResult, OwnedSecurityBuffer, PackageCapabilities,
-    PackageInfo, SecurityBuffer, SecurityBufferType, SecurityPackageType, SecurityStatus, ServerResponseFlags, Sspi,
-    SspiEx, SspiImpl, PACKAGE_ID_NONE,
+    PackageInfo, SecurityBuffer, SecurityBufferType, SecurityPackageType, SecurityStatus, ServerRequestFlags,
+    ServerResponseFlags, Sspi, SspiEx, SspiImpl, Username, PACKAGE_ID_NONE,
 };
 
 pub const PKG_NAME: &str = "NTLM";
@@ -40,8 +42,14 @@ const LM_CHALLENGE_RESPONSE_BUFFER_SIZE: usize = HASH_SIZE + CHALLENGE_SIZE;
 const SIGNATURE_VERSION_SIZE: usize = 4;
 const SIGNATURE_SEQ_NUM_SIZE: usize = 4;
 const SIGNATURE_CHECKSUM_SIZE: usize = 8;
+const SIGNATURE_RANDOM_PAD_SIZE: usize = 4;
+const SIGNATURE_CRC32_SIZE: usize = 4;
 const MESSAGES_VERSION: u32 = 1;
 
+// the well-known name of the anonymous logon: NT AUTHORITY\ANONYMOUS LOGON
+const ANONYMOUS_USER_NAME: &str = "ANONYMOUS LOGON";
+const ANONYMOUS_DOMAIN_NAME: &str = "NT AUTHORITY";
+
 pub static PACKAGE_INFO: LazyLock<PackageInfo> = LazyLock::new(|| PackageInfo {
     capabilities: PackageCapabilities::empty(),
     rpc_id: PACKAGE_ID_NONE,
@@ -75,6 +83,8 @@ pub struct Ntlm {
     authenticate_message: Option<AuthenticateMessage>,
 
     channel_bindings: Option<ChannelBindings>,
+    // service principal name of the target sent by the client in the MsvAvTargetName
+    target_name: Option<String>,
 
     state: NtlmState,
     flags: NegotiateFlags,
@@ -89,8 +99,19 @@ pub struct Ntlm {
     recv_signing_key: [u8; HASH_SIZE],
     send_sealing_key: Option<Rc4>,
     recv_sealing_key: Option<Rc4>,
+    // without the extended session security, `send_sealing_key` is used in both directions
+    extended_session_security: bool,
+    // send and receive sealing keys of the connectionless mode
+    connectionless_sealing_keys: Option<([u8; HASH_SIZE], [u8; HASH_SIZE])>,
+    // anonymous (null session) authentication
+    anonymous: bool,
+    // the client's channel bindings hash was verified by the acceptor
+    channel_bindings_verified: bool,
+    // identity of the client authenticated by the acceptor
+    client_identity: Option<ContextClientIdentity>,
 
     session_key: Option<[u8; SESSION_KEY_SIZE]>,
+    validation_info: Option<NtlmValidationInfo>,
 }
 
 #[derive(Debug, Clone)]
@@ -116,9 +137,11 @@ struct ChallengeMessage {
 struct AuthenticateMessage {
     message: Vec<u8>,
     mic: Option<Mic>,
-    target_info: Vec<u8>,
-    client_challenge: [u8; CHALLENGE_SIZE],
+    lm_challenge_response: Vec<u8>,
+    nt_challenge_response: Vec<u8>,
     encrypted_random_session_key: Option<[u8; ENCRYPTED_RANDOM_SESSION_KEY_SIZE]>,
+    /// Workstation name of the client. Only the acceptor reads it.
+    workstation: Vec<u8>,
 }
 
 impl Ntlm {
@@ -131,6 +154,7 @@ impl Ntlm {
             authenticate_message: None,
 
             channel_bindings: None,
+            target_name: None,
 
             state: NtlmState::Initial,
             flags: NegotiateFlags::empty(),
@@ -145,7 +169,13 @@ impl Ntlm {
             recv_signing_key: [0x00; HASH_SIZE],
             send_sealing_key: None,
             recv_sealing_key: None,
+            extended_session_security: true,
+            connectionless_sealing_keys: None,
+            anonymous: false,
+            channel_bindings_verified: false,
+            client_identity: None,
             session_key: None,
+            validation_info: None,
         }
     }
 
@@ -158,6 +188,7 @@ impl Ntlm {
             authenticate_message: None,
 
             channel_bindings: None,
+            target_name: None,
 
             state: NtlmState::Initial,
             flags: NegotiateFlags::empty(),
@@ -172,7 +203,13 @@ impl Ntlm {
             recv_signing_key: [0x00; HASH_SIZE],
             send_sealing_key: None,
             recv_sealing_key: None,
+            extended_session_security: true,
+            connectionless_sealing_keys: None,
+            anonymous: false,
+            channel_bindings_verified: false,
+            client_identity: None,
             session_key: None,
+            validation_info: None,
         }
     }
 
@@ -185,6 +222,7 @@ impl Ntlm {
             authenticate_message: None,
 
             channel_bindings: None,
+            target_name: None,
 
             state: NtlmState::Initial,
             flags: NegotiateFlags::empty(),
@@ -199,7 +237,13 @@ impl Ntlm {
             recv_signing_key: [0x00; HASH_SIZE],
             send_sealing_key: None,
             recv_sealing_key: None,
+            extended_session_security: true,
+            connectionless_sealing_keys: None,
+            anonymous: false,
+            channel_bindings_verified: false,
+            client_identity: None,
             session_key: None,
+            validation_info: None,
         }
     }
 
@@ -214,6 +258,55 @@ impl Ntlm {
     pub fn session_key(&self) -> Option<[u8; SESSION_KEY_SIZE]> {
         self.session_key
     }
+
+    fn recv_sealing_key_mut(&mut self) -> &mut Option<Rc4> {
+        if self.extended_session_security {
+            &mut self.recv_sealing_key
+        } else {
+            &mut self.send_sealing_key
+        }
+    }
+
+    // In the connectionless mode, the RC4 handles are re-initialized for every message
+    // with the MD5(SealingKey, SeqNum) key.
+    fn reset_connectionless_sealing_keys(&mut self, sequence_number: u32) {
+        if let Some((send_sealing_key, recv_sealing_key)) = self.connectionless_sealing_keys {
+            let sequence_number = sequence_number.to_le_bytes();
+
+            self.send_sealing_key = Some(Rc4::new(&compute_md5(
+                &[send_sealing_key.as_ref(), sequence_number.as_ref()].concat(),
+            )));
+            self.recv_sealing_key = Some(Rc4::new(&compute_md5(
+                &[recv_sealing_key.as_ref(), sequence_number.as_ref()].concat(),
+            )));
+        }
+    }
+
+    fn compute_recv_signature(
+        &mut self,
+        message: &[SecurityBuffer],
+        sequence_number: u32,
+    ) -> crate::Result<[u8; SIGNATURE_SIZE]> {
+        let extended_session_security = self.extended_session_security;
+        let checksum = compute_checksum(
+            &self.recv_signing_key,
+            sequence_number,
+            &extract_data_to_sign(message),
+            extended_session_security,
+        )?;
+
+        Ok(seal_checksum(
+            self.recv_sealing_key_mut().as_mut().unwrap(),
+            &checksum,
+            sequence_number,
+            extended_session_security,
+        ))
+    }
+
+    /// Returns the result of the pass-through logon performed by the [NtlmValidator] of the acceptor.
+    pub fn validation_info(&self) -> Option<&NtlmValidationInfo> {
+        self.validation_info.as_ref()
+    }
 }
 
 impl Default for Ntlm {
@@ -260,7 +353,12 @@ impl SspiImpl for Ntlm {
                 let output_token = OwnedSecurityBuffer::find_buffer_mut(builder.output, SecurityBufferType::Token)?;
 
                 self.state = NtlmState::Negotiate;
-                server::read_negotiate(self, input_token.buffer.as_slice())?;
+                if self.config.datagram && input_token.buffer.is_empty() {
+                    // the connectionless client does not send the NEGOTIATE message
+                    server::skip_negotiate(self)?;
+                } else {
+                    server::read_negotiate(self, input_token.buffer.as_slice())?;
+                }
 
                 server::write_challenge(self, &mut output_token.buffer)?
             }
@@ -273,7 +371,20 @@ impl SspiImpl for Ntlm {
                     self.channel_bindings = Some(ChannelBindings::from_bytes(&sec_buffer.buffer)?);
                 }
 
-                server::read_authenticate(self, input_token.buffer.as_slice())?
+                let status = server::read_authenticate(self, input_token.buffer.as_slice())?;
+
+                if self.anonymous
+                    && !builder
+                        .context_requirements
+                        .contains(ServerRequestFlags::ALLOW_NULL_SESSION)
+                {
+                    return Err(crate::Error::new(
+                        crate::ErrorKind::LogonDenied,
+                        "anonymous authentication is not allowed",
+                    ));
+                }
+
+                status
             }
             _ => {
                 return Err(crate::Error::new(
@@ -285,7 +396,11 @@ impl SspiImpl for Ntlm {
 
         Ok(AcceptSecurityContextResult {
             status,
-            flags: ServerResponseFlags::empty(),
+            flags: if self.anonymous {
+                ServerResponseFlags::NULL_SESSION
+            } else {
+                ServerResponseFlags::empty()
+            },
             expiry: None,
         })
     }
@@ -320,7 +435,16 @@ impl Ntlm {
                     self.signing = true; // sealing implies signing
                 }
 
-                client::write_negotiate(self, &mut output_token.buffer)?
+                self.anonymous = builder.context_requirements.contains(ClientRequestFlags::NULL_SESSION);
+
+                if self.config.datagram {
+                    // the connectionless authentication starts with the CHALLENGE message from the server
+                    self.state = NtlmState::Challenge;
+
+                    SecurityStatus::ContinueNeeded
+                } else {
+                    client::write_negotiate(self, &mut output_token.buffer)?
+                }
             }
             NtlmState::Challenge => {
                 let input = builder.input.as_ref().ok_or_else(|| {
@@ -339,18 +463,26 @@ impl Ntlm {
                     self.channel_bindings = Some(ChannelBindings::from_bytes(&sec_buffer.buffer)?);
                 }
 
+                if let Some(target_name) = builder.target_name {
+                    self.target_name = Some(target_name.to_owned());
+                }
+
                 client::read_challenge(self, input_token.buffer.as_slice())?;
 
-                client::write_authenticate(
-                    self,
+                // the anonymous client does not use credentials
+                let anonymous_identity = AuthIdentityBuffers::default();
+                let credentials = if self.anonymous {
+                    &anonymous_identity
+                } else {
                     builder
                         .credentials_handle
                         .as_ref()
                         .expect("CredentialsHandle must be passed to the method")
                         .as_ref()
-                        .expect("CredentialsHandle must be Some for the client's method"),
-                    &mut output_token.buffer,
-                )?
+                        .expect("CredentialsHandle must be Some for the client's method")
+                };
+
+                client::write_authenticate(self, credentials, &mut output_token.buffer)?
             }
             _ => {
                 return Err(crate::Error::new(
@@ -364,7 +496,11 @@ impl Ntlm {
 
         Ok(InitializeSecurityContextResult {
             status,
-            flags: ClientResponseFlags::empty(),
+            flags: if self.anonymous {
+                ClientResponseFlags::NULL_SESSION
+            } else {
+                ClientResponseFlags::empty()
+            },
             expiry: None,
         })
     }
@@ -376,10 +512,10 @@ impl Sspi for Ntlm {
         server::complete_authenticate(self)
     }
 
-    #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self, _flags))]
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self, flags))]
     fn encrypt_message(
         &mut self,
-        _flags: EncryptionFlags,
+        flags: EncryptionFlags,
         message: &mut [SecurityBuffer],
         sequence_number: u32,
     ) -> crate::Result<SecurityStatus> {
@@ -388,27 +524,41 @@ impl Sspi for Ntlm {
         }
 
         SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Token)?; // check if exists
-        let data = SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Data)?;
-
-        let digest = compute_digest(&self.send_signing_key, sequence_number, data.data())?;
-
-        let encrypted_data = self.send_sealing_key.as_mut().unwrap().process(data.data());
-        if encrypted_data.len() < data.buf_len() {
-            return Err(Error::new(ErrorKind::BufferTooSmall, "The Data buffer is too small"));
+        SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Data)?; // check if exists
+
+        self.reset_connectionless_sealing_keys(sequence_number);
+
+        let checksum = compute_checksum(
+            &self.send_signing_key,
+            sequence_number,
+            &extract_data_to_sign(message),
+            self.extended_session_security,
+        )?;
+
+        // `ReadOnlyWithChecksum` buffers are only signed, and nothing is sealed in the integrity-only mode
+        if !flags.contains(EncryptionFlags::WRAP_NO_ENCRYPT) {
+            let sealing_key = self.send_sealing_key.as_mut().unwrap();
+
+            for data in message
+                .iter_mut()
+                .filter(|buffer| buffer.security_buffer_type() == SecurityBufferType::Data)
+            {
+                let encrypted_data = sealing_key.process(data.data());
+                data.write_data(&encrypted_data)?;
+            }
         }
-        data.write_data(&encrypted_data)?;
 
-        let checksum = self
-            .send_sealing_key
-            .as_mut()
-            .unwrap()
-            .process(&digest[0..SIGNATURE_CHECKSUM_SIZE]);
+        let signature = seal_checksum(
+            self.send_sealing_key.as_mut().unwrap(),
+            &checksum,
+            sequence_number,
+            self.extended_session_security,
+        );
 
         let signature_buffer = SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Token)?;
         if signature_buffer.buf_len() < SIGNATURE_SIZE {
             return Err(Error::new(ErrorKind::BufferTooSmall, "The Token buffer is too small"));
         }
-        let signature = compute_signature(&checksum, sequence_number);
         signature_buffer.write_data(signature.as_slice())?;
 
         Ok(SecurityStatus::Ok)
@@ -420,29 +570,93 @@ impl Sspi for Ntlm {
         message: &mut [SecurityBuffer],
         sequence_number: u32,
     ) -> crate::Result<DecryptionFlags> {
-        if self.recv_sealing_key.is_none() {
+        if self.recv_sealing_key_mut().is_none() {
             self.complete_auth_token(&mut [])?;
         }
 
-        let encrypted = extract_encrypted_data(message)?;
+        self.reset_connectionless_sealing_keys(sequence_number);
+
+        let (signature, encrypted_data) = extract_sealed_data(message)?;
+
+        // The NTLM signature does not indicate whether the data was sealed, so the message wrapped
+        // in the integrity-only mode is detected by the signature verification of the unsealed data.
+        let sign_only_sealing_key = self.recv_sealing_key_mut().clone();
 
-        if encrypted.len() < 16 {
-            return Err(Error::new(ErrorKind::MessageAltered, "Invalid encrypted message size!"));
+        // the `Data` buffers are sealed in order using the same RC4 handle
+        let sealing_key = self.recv_sealing_key_mut().as_mut().unwrap();
+        let decrypted_data = encrypted_data
+            .iter()
+            .map(|encrypted| sealing_key.process(encrypted))
+            .collect::<Vec<_>>();
+        save_unsealed_data(&decrypted_data, message)?;
+
+        if signature == self.compute_recv_signature(message, sequence_number)? {
+            return Ok(DecryptionFlags::empty());
         }
 
-        let (signature, encrypted_message) = encrypted.split_at(16);
+        let sealed_sealing_key = mem::replace(self.recv_sealing_key_mut(), sign_only_sealing_key);
+        save_unsealed_data(&encrypted_data, message)?;
 
-        let decrypted = self.recv_sealing_key.as_mut().unwrap().process(encrypted_message);
+        if signature == self.compute_recv_signature(message, sequence_number)? {
+            return Ok(DecryptionFlags::SIGN_ONLY);
+        }
 
-        save_decrypted_data(&decrypted, message)?;
+        *self.recv_sealing_key_mut() = sealed_sealing_key;
 
-        let digest = compute_digest(&self.recv_signing_key, sequence_number, &decrypted)?;
-        let checksum = self
-            .recv_sealing_key
-            .as_mut()
-            .unwrap()
-            .process(&digest[0..SIGNATURE_CHECKSUM_SIZE]);
-        let expected_signature = compute_signature(&checksum, sequence_number);
+        Err(Error::new(
+            ErrorKind::MessageAltered,
+            "Signature verification failed, something nasty is going on!",
+        ))
+    }
+
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self, _flags))]
+    fn make_signature(
+        &mut self,
+        _flags: u32,
+        message: &mut [SecurityBuffer],
+        sequence_number: u32,
+    ) -> crate::Result<()> {
+        if self.send_sealing_key.is_none() {
+            self.complete_auth_token(&mut [])?;
+        }
+
+        self.reset_connectionless_sealing_keys(sequence_number);
+
+        let checksum = compute_checksum(
+            &self.send_signing_key,
+            sequence_number,
+            &extract_data_to_sign(message),
+            self.extended_session_security,
+        )?;
+
+        // [MAC(Handle, SigningKey, SeqNum, Message)](https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-nlmp/a92716d5-d164-4960-9e15-300f4eef44a8):
+        // the checksum is encrypted using the same RC4 handle as the sealing, so the message signing changes its state
+        let signature = seal_checksum(
+            self.send_sealing_key.as_mut().unwrap(),
+            &checksum,
+            sequence_number,
+            self.extended_session_security,
+        );
+
+        let signature_buffer = SecurityBuffer::find_buffer_mut(message, SecurityBufferType::Token)?;
+        if signature_buffer.buf_len() < SIGNATURE_SIZE {
+            return Err(Error::new(ErrorKind::BufferTooSmall, "The Token buffer is too small"));
+        }
+        signature_buffer.write_data(signature.as_slice())?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
+    fn verify_signature(&mut self, message: &[SecurityBuffer], sequence_number: u32) -> crate::Result<u32> {
+        if self.recv_sealing_key_mut().is_none() {
+            self.complete_auth_token(&mut [])?;
+        }
+
+        self.reset_connectionless_sealing_keys(sequence_number);
+
+        let signature = SecurityBuffer::buf_data(message, SecurityBufferType::Token)?;
+        let expected_signature = self.compute_recv_signature(message, sequence_number)?;
 
         if signature != expected_signature.as_ref() {
             return Err(Error::new(
@@ -451,7 +665,7 @@ impl Sspi for Ntlm {
             ));
         }
 
-        Ok(DecryptionFlags::empty())
+        Ok(0)
     }
 
     #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
@@ -466,6 +680,13 @@ impl Sspi for Ntlm {
 
     #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
     fn query_context_names(&mut self) -> crate::Result<ContextNames> {
+        if self.anonymous {
+            return Ok(ContextNames {
+                username: Username::new_down_level_logon_name(ANONYMOUS_USER_NAME, ANONYMOUS_DOMAIN_NAME)
+                    .map_err(|e| Error::new(ErrorKind::InternalError, e))?,
+            });
+        }
+
         if let Some(identity_buffers) = &self.identity {
             let identity =
                 AuthIdentity::try_from(identity_buffers).map_err(|e| Error::new(ErrorKind::InvalidParameter, e))?;
@@ -486,6 +707,16 @@ impl Sspi for Ntlm {
         crate::query_security_package_info(SecurityPackageType::Ntlm)
     }
 
+    #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
+    fn query_context_client_identity(&mut self) -> crate::Result<ContextClientIdentity> {
+        self.client_identity.clone().ok_or_else(|| {
+            crate::Error::new(
+                crate::ErrorKind::NoCredentials,
+                "Requested the client identity, but the acceptor has not completed the authentication",
+            )
+        })
+    }
+
     #[instrument(level = "debug", ret, fields(state = ?self.state), skip(self))]
     fn query_context_cert_trust_status(&mut self) -> crate::Result<CertTrustStatus> {
         Err(crate::Error::new(
@@ -535,16 +766,17 @@ impl AuthenticateMessage {
     fn new(
         message: Vec<u8>,
         mic: Option<Mic>,
-        target_info: Vec<u8>,
-        client_challenge: [u8; CHALLENGE_SIZE],
+        lm_challenge_response: Vec<u8>,
+        nt_challenge_response: Vec<u8>,
         encrypted_random_session_key: Option<[u8; ENCRYPTED_RANDOM_SESSION_KEY_SIZE]>,
     ) -> Self {
         Self {
             message,
             mic,
-            target_info,
-            client_challenge,
+            lm_challenge_response,
+            nt_challenge_response,
             encrypted_random_session_key,
+            workstation: Vec::new(),
         }
     }
 }
@@ -664,6 +896,72 @@ bitflags! {
     }
 }
 
+/// Extracts the data covered by the NTLM signature.
+///
+/// The signature covers all `Data` and `ReadOnlyWithChecksum` buffers concatenated together,
+/// so the headers that are not sealed (e.g., DCE/RPC and SMB headers) are protected as well.
+fn extract_data_to_sign(buffers: &[SecurityBuffer]) -> Vec<u8> {
+    buffers
+        .iter()
+        .filter(|buffer| {
+            matches!(
+                buffer.security_buffer_type(),
+                SecurityBufferType::Data | SecurityBufferType::ReadOnlyWithChecksum
+            )
+        })
+        .flat_map(|buffer| buffer.data().iter().copied())
+        .collect()
+}
+
+/// Extracts the signature and the sealed data of every `Data` buffer in order.
+///
+/// If the message is passed in the `Stream` buffer or the signature is not in the separate `Token` buffer,
+/// the sealed data is the single payload after the signature.
+fn extract_sealed_data(buffers: &[SecurityBuffer]) -> crate::Result<([u8; SIGNATURE_SIZE], Vec<Vec<u8>>)> {
+    let token = SecurityBuffer::buf_data(buffers, SecurityBufferType::Token).unwrap_or_default();
+    let mut signature = [0x00; SIGNATURE_SIZE];
+
+    if SecurityBuffer::find_buffer(buffers, SecurityBufferType::Stream).is_err() && token.len() >= SIGNATURE_SIZE {
+        signature.copy_from_slice(&token[..SIGNATURE_SIZE]);
+        SecurityBuffer::find_buffer(buffers, SecurityBufferType::Data)?; // check if exists
+
+        let encrypted_data = buffers
+            .iter()
+            .filter(|buffer| buffer.security_buffer_type() == SecurityBufferType::Data)
+            .map(|buffer| buffer.data().to_vec())
+            .collect();
+
+        return Ok((signature, encrypted_data));
+    }
+
+    let encrypted = extract_encrypted_data(buffers)?;
+    if encrypted.len() < SIGNATURE_SIZE {
+        return Err(Error::new(ErrorKind::MessageAltered, "Invalid encrypted message size!"));
+    }
+
+    let (encrypted_signature, encrypted_message) = encrypted.split_at(SIGNATURE_SIZE);
+    signature.copy_from_slice(encrypted_signature);
+
+    Ok((signature, vec![encrypted_message.to_vec()]))
+}
+
+/// Writes the unsealed data extracted by [extract_sealed_data] back to the message buffers.
+fn save_unsealed_data(data: &[Vec<u8>], buffers: &mut [SecurityBuffer]) -> crate::Result<()> {
+    if let [data] = data {
+        return save_decrypted_data(data, buffers);
+    }
+
+    for (buffer, data) in buffers
+        .iter_mut()
+        .filter(|buffer| buffer.security_buffer_type() == SecurityBufferType::Data)
+        .zip(data)
+    {
+        buffer.write_data(data)?;
+    }
+
+    Ok(())
+}
+
 fn compute_digest(key: &[u8], seq_num: u32, data: &[u8]) -> io::Result<[u8; 16]> {
     let mut digest_data = Vec::with_capacity(SIGNATURE_SEQ_NUM_SIZE + data.len());
     digest_data.write_u32::<LittleEndian>(seq_num)?;
@@ -672,6 +970,54 @@ fn compute_digest(key: &[u8], seq_num: u32, data: &[u8]) -> io::Result<[u8; 16]>
     compute_hmac_md5(key, &digest_data)
 }
 
+// Computes the message checksum that is encrypted with the sealing key to make the signature.
+fn compute_checksum(
+    signing_key: &[u8],
+    seq_num: u32,
+    data: &[u8],
+    extended_session_security: bool,
+) -> io::Result<Vec<u8>> {
+    if extended_session_security {
+        Ok(compute_digest(signing_key, seq_num, data)?[0..SIGNATURE_CHECKSUM_SIZE].to_vec())
+    } else {
+        // RandomPad, CRC32(Message) and SeqNum placeholder
+        let mut checksum = vec![0x00; SIGNATURE_SIZE - SIGNATURE_VERSION_SIZE];
+        checksum[SIGNATURE_RANDOM_PAD_SIZE..SIGNATURE_RANDOM_PAD_SIZE + SIGNATURE_CRC32_SIZE]
+            .copy_from_slice(&crc32fast::hash(data).to_le_bytes());
+
+        Ok(checksum)
+    }
+}
+
+fn seal_checksum(
+    sealing_key: &mut Rc4,
+    checksum: &[u8],
+    seq_num: u32,
+    extended_session_security: bool,
+) -> [u8; SIGNATURE_SIZE] {
+    let sealed_checksum = sealing_key.process(checksum);
+
+    if extended_session_security {
+        compute_signature(&sealed_checksum, seq_num)
+    } else {
+        // MAC without the extended session security ([MS-NLMP] 3.4.4.1):
+        // the random pad is zeroed after the encryption, and the encrypted zero sequence number is XORed with SeqNum
+        let mut sealed_seq_num = [0x00; SIGNATURE_SEQ_NUM_SIZE];
+        sealed_seq_num.copy_from_slice(&sealed_checksum[SIGNATURE_RANDOM_PAD_SIZE + SIGNATURE_CRC32_SIZE..]);
+        let seq_num = u32::from_le_bytes(sealed_seq_num) ^ seq_num;
+
+        let mut signature = [0x00; SIGNATURE_SIZE];
+        signature[..SIGNATURE_VERSION_SIZE].clone_from_slice(&MESSAGES_VERSION.to_le_bytes());
+        signature[SIGNATURE_VERSION_SIZE + SIGNATURE_RANDOM_PAD_SIZE..SIGNATURE_SIZE - SIGNATURE_SEQ_NUM_SIZE]
+            .clone_from_slice(
+                &sealed_checksum[SIGNATURE_RANDOM_PAD_SIZE..SIGNATURE_RANDOM_PAD_SIZE + SIGNATURE_CRC32_SIZE],
+            );
+        signature[SIGNATURE_SIZE - SIGNATURE_SEQ_NUM_SIZE..].clone_from_slice(&seq_num.to_le_bytes());
+
+        signature
+    }
+}
+
 fn compute_signature(checksum: &[u8], seq_num: u32) -> [u8; SIGNATURE_SIZE] {
     let mut signature = [0x00; SIGNATURE_SIZE];
     signature[..SIGNATURE_VERSION_SIZE].clone_from_slice(&MESSAGES_VERSION.to_le_bytes());
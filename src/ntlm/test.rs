@@ -1,5 +1,5 @@
 use crate::crypto::{Rc4, HASH_SIZE};
-use crate::ntlm::messages::test::TEST_CREDENTIALS;
+use crate::ntlm::messages::test::{test_nt_challenge_response, TEST_CREDENTIALS};
 use crate::ntlm::{
     AuthenticateMessage, ChallengeMessage, Mic, NegotiateFlags, NegotiateMessage, Ntlm, NtlmState, CHALLENGE_SIZE,
     SIGNATURE_SIZE,
@@ -94,6 +94,70 @@ fn encrypt_message_writes_seq_num_to_signature() {
     assert_eq!(expected, signature.data()[12..SIGNATURE_SIZE]);
 }
 
+#[test]
+fn make_signature_does_not_encrypt_data() {
+    let mut context = Ntlm::new();
+    context.send_signing_key = SIGNING_KEY;
+    context.send_sealing_key = Some(Rc4::new(&SEALING_KEY));
+
+    let mut token = [0; 100];
+    let mut data = TEST_DATA.to_vec();
+    let mut buffers = vec![
+        SecurityBuffer::Token(token.as_mut_slice()),
+        SecurityBuffer::Data(data.as_mut_slice()),
+    ];
+
+    context.make_signature(0, &mut buffers, TEST_SEQ_NUM).unwrap();
+    let output = SecurityBuffer::find_buffer(&buffers, SecurityBufferType::Data).unwrap();
+    let signature = SecurityBuffer::find_buffer(&buffers, SecurityBufferType::Token).unwrap();
+
+    assert_eq!(TEST_DATA, output.data());
+    assert_eq!(SIGNATURE_SIZE, signature.data().len());
+    assert_eq!(TEST_SEQ_NUM.to_le_bytes(), signature.data()[12..SIGNATURE_SIZE]);
+}
+
+#[test]
+fn verify_signature_of_signed_message() {
+    let mut client = Ntlm::new();
+    client.send_signing_key = SIGNING_KEY;
+    client.send_sealing_key = Some(Rc4::new(&SEALING_KEY));
+
+    let mut server = Ntlm::new();
+    server.recv_signing_key = SIGNING_KEY;
+    server.recv_sealing_key = Some(Rc4::new(&SEALING_KEY));
+
+    for seq_num in 0..2 {
+        let mut token = [0; 100];
+        let mut data = TEST_DATA.to_vec();
+        let mut buffers = vec![
+            SecurityBuffer::Token(token.as_mut_slice()),
+            SecurityBuffer::Data(data.as_mut_slice()),
+        ];
+
+        client.make_signature(0, &mut buffers, seq_num).unwrap();
+        server.verify_signature(&buffers, seq_num).unwrap();
+    }
+}
+
+#[test]
+fn verify_signature_fails_on_altered_message() {
+    let mut context = Ntlm::new();
+    context.recv_signing_key = SIGNING_KEY;
+    context.recv_sealing_key = Some(Rc4::new(&SEALING_KEY));
+
+    let mut data = b"Hello, World!!?".to_vec();
+    let mut signature_test_data = SIGNATURE_FOR_TEST_DATA.to_vec();
+    let buffers = vec![
+        SecurityBuffer::Data(&mut data),
+        SecurityBuffer::Token(&mut signature_test_data),
+    ];
+
+    assert_eq!(
+        context.verify_signature(&buffers, TEST_SEQ_NUM).unwrap_err().error_type,
+        ErrorKind::MessageAltered
+    );
+}
+
 #[test]
 fn decrypt_message_decrypts_data() {
     let mut context = Ntlm::new();
@@ -225,6 +289,223 @@ fn decrypt_message_fails_on_incorrect_sealing_key() {
     assert!(context.decrypt_message(&mut buffers, TEST_SEQ_NUM).is_err());
 }
 
+fn client_server_contexts() -> (Ntlm, Ntlm) {
+    let mut client = Ntlm::new();
+    client.send_signing_key = SIGNING_KEY;
+    client.send_sealing_key = Some(Rc4::new(&SEALING_KEY));
+
+    let mut server = Ntlm::new();
+    server.recv_signing_key = SIGNING_KEY;
+    server.recv_sealing_key = Some(Rc4::new(&SEALING_KEY));
+
+    (client, server)
+}
+
+#[test]
+fn encrypt_message_with_wrap_no_encrypt_does_not_encrypt_data() {
+    let mut context = Ntlm::new();
+    context.send_signing_key = SIGNING_KEY;
+    context.send_sealing_key = Some(Rc4::new(&SEALING_KEY));
+
+    let mut token = [0; 100];
+    let mut data = TEST_DATA.to_vec();
+    let mut buffers = vec![
+        SecurityBuffer::Token(token.as_mut_slice()),
+        SecurityBuffer::Data(data.as_mut_slice()),
+    ];
+
+    context
+        .encrypt_message(EncryptionFlags::WRAP_NO_ENCRYPT, &mut buffers, TEST_SEQ_NUM)
+        .unwrap();
+    let output = SecurityBuffer::find_buffer(&buffers, SecurityBufferType::Data).unwrap();
+    let signature = SecurityBuffer::find_buffer(&buffers, SecurityBufferType::Token).unwrap();
+
+    assert_eq!(TEST_DATA, output.data());
+    assert_eq!(TEST_SEQ_NUM.to_le_bytes(), signature.data()[12..SIGNATURE_SIZE]);
+}
+
+#[test]
+fn decrypt_message_reports_sign_only() {
+    let (mut client, mut server) = client_server_contexts();
+
+    for (seq_num, encryption_flags) in [
+        EncryptionFlags::WRAP_NO_ENCRYPT,
+        EncryptionFlags::empty(),
+        EncryptionFlags::WRAP_NO_ENCRYPT,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let seq_num = seq_num as u32;
+        let mut token = [0; 100];
+        let mut data = TEST_DATA.to_vec();
+        let mut buffers = vec![
+            SecurityBuffer::Token(token.as_mut_slice()),
+            SecurityBuffer::Data(data.as_mut_slice()),
+        ];
+
+        client.encrypt_message(encryption_flags, &mut buffers, seq_num).unwrap();
+        let decryption_flags = server.decrypt_message(&mut buffers, seq_num).unwrap();
+        let output = SecurityBuffer::find_buffer(&buffers, SecurityBufferType::Data).unwrap();
+
+        assert_eq!(TEST_DATA, output.data());
+        if encryption_flags.is_empty() {
+            assert_eq!(DecryptionFlags::empty(), decryption_flags);
+        } else {
+            assert_eq!(DecryptionFlags::SIGN_ONLY, decryption_flags);
+        }
+    }
+}
+
+#[test]
+fn encrypt_message_signs_read_only_with_checksum_buffers() {
+    let (mut client, mut server) = client_server_contexts();
+
+    let mut header = b"header".to_vec();
+    let mut token = [0; 100];
+    let mut data = TEST_DATA.to_vec();
+    let mut buffers = vec![
+        SecurityBuffer::ReadOnlyWithChecksum(header.as_mut_slice()),
+        SecurityBuffer::Token(token.as_mut_slice()),
+        SecurityBuffer::Data(data.as_mut_slice()),
+    ];
+
+    client
+        .encrypt_message(EncryptionFlags::empty(), &mut buffers, TEST_SEQ_NUM)
+        .unwrap();
+    assert_eq!(
+        b"header",
+        SecurityBuffer::buf_data(&buffers, SecurityBufferType::ReadOnlyWithChecksum).unwrap()
+    );
+
+    let mut altered_server = Ntlm::new();
+    altered_server.recv_signing_key = SIGNING_KEY;
+    altered_server.recv_sealing_key = Some(Rc4::new(&SEALING_KEY));
+    let mut altered_header = b"HEADER".to_vec();
+    let mut altered_token = SecurityBuffer::buf_data(&buffers, SecurityBufferType::Token)
+        .unwrap()
+        .to_vec();
+    let mut altered_data = SecurityBuffer::buf_data(&buffers, SecurityBufferType::Data)
+        .unwrap()
+        .to_vec();
+    let mut altered_buffers = vec![
+        SecurityBuffer::ReadOnlyWithChecksum(altered_header.as_mut_slice()),
+        SecurityBuffer::Token(altered_token.as_mut_slice()),
+        SecurityBuffer::Data(altered_data.as_mut_slice()),
+    ];
+    assert_eq!(
+        altered_server
+            .decrypt_message(&mut altered_buffers, TEST_SEQ_NUM)
+            .unwrap_err()
+            .error_type,
+        ErrorKind::MessageAltered
+    );
+
+    assert_eq!(
+        DecryptionFlags::empty(),
+        server.decrypt_message(&mut buffers, TEST_SEQ_NUM).unwrap()
+    );
+    assert_eq!(
+        TEST_DATA,
+        SecurityBuffer::buf_data(&buffers, SecurityBufferType::Data).unwrap()
+    );
+}
+
+#[test]
+fn decrypt_message_with_multiple_data_buffers() {
+    let (mut client, mut server) = client_server_contexts();
+
+    for (seq_num, encryption_flags) in [EncryptionFlags::empty(), EncryptionFlags::WRAP_NO_ENCRYPT]
+        .into_iter()
+        .enumerate()
+    {
+        let seq_num = seq_num as u32;
+        let mut token = [0; 100];
+        let mut header = b"header".to_vec();
+        let mut first = TEST_DATA[..10].to_vec();
+        let mut second = TEST_DATA[10..].to_vec();
+        let mut buffers = vec![
+            SecurityBuffer::Token(token.as_mut_slice()),
+            SecurityBuffer::Data(first.as_mut_slice()),
+            SecurityBuffer::ReadOnlyWithChecksum(header.as_mut_slice()),
+            SecurityBuffer::Data(second.as_mut_slice()),
+        ];
+
+        client.encrypt_message(encryption_flags, &mut buffers, seq_num).unwrap();
+        let decryption_flags = server.decrypt_message(&mut buffers, seq_num).unwrap();
+
+        let data = buffers
+            .iter()
+            .filter(|buffer| buffer.security_buffer_type() == SecurityBufferType::Data)
+            .flat_map(|buffer| buffer.data().iter().copied())
+            .collect::<Vec<_>>();
+        assert_eq!(TEST_DATA, data.as_slice());
+        if encryption_flags.is_empty() {
+            assert_eq!(DecryptionFlags::empty(), decryption_flags);
+        } else {
+            assert_eq!(DecryptionFlags::SIGN_ONLY, decryption_flags);
+        }
+    }
+}
+
+#[test]
+fn encrypt_and_sign_messages_without_extended_session_security() {
+    let context = || {
+        let mut context = Ntlm::new();
+        context.extended_session_security = false;
+        context.send_sealing_key = Some(Rc4::new(&SEALING_KEY));
+
+        context
+    };
+    let (mut client, mut server) = (context(), context());
+
+    for seq_num in 0..3 {
+        let mut token = [0; 100];
+        let mut data = TEST_DATA.to_vec();
+        let mut buffers = vec![
+            SecurityBuffer::Token(token.as_mut_slice()),
+            SecurityBuffer::Data(data.as_mut_slice()),
+        ];
+
+        client
+            .encrypt_message(EncryptionFlags::empty(), &mut buffers, seq_num)
+            .unwrap();
+        let signature = SecurityBuffer::buf_data(&buffers, SecurityBufferType::Token).unwrap();
+        // version and the zero random pad
+        assert_eq!([0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], signature[..8]);
+        assert_ne!(
+            TEST_DATA,
+            SecurityBuffer::buf_data(&buffers, SecurityBufferType::Data).unwrap()
+        );
+
+        server.decrypt_message(&mut buffers, seq_num).unwrap();
+        assert_eq!(
+            TEST_DATA,
+            SecurityBuffer::buf_data(&buffers, SecurityBufferType::Data).unwrap()
+        );
+    }
+
+    let mut token = [0; 100];
+    let mut data = TEST_DATA.to_vec();
+    let mut buffers = vec![
+        SecurityBuffer::Token(token.as_mut_slice()),
+        SecurityBuffer::Data(data.as_mut_slice()),
+    ];
+    client.make_signature(0, &mut buffers, 3).unwrap();
+    server.verify_signature(&buffers, 3).unwrap();
+
+    client.make_signature(0, &mut buffers, 4).unwrap();
+    let mut altered_token = SecurityBuffer::buf_data(&buffers, SecurityBufferType::Token)
+        .unwrap()
+        .to_vec();
+    let mut altered_data = b"Hello, World!!?".to_vec();
+    let altered_buffers = vec![
+        SecurityBuffer::Token(altered_token.as_mut_slice()),
+        SecurityBuffer::Data(altered_data.as_mut_slice()),
+    ];
+    assert!(server.verify_signature(&altered_buffers, 4).is_err());
+}
+
 #[test]
 fn initialize_security_context_wrong_state_negotiate() {
     let mut context = Ntlm::new();
@@ -649,6 +930,27 @@ fn complete_auth_token_changes_state() {
         [0x00; CHALLENGE_SIZE],
         0,
     ));
+    let target_info = vec![
+        0x02, 0x00, 0x16, 0x00, 0x41, 0x00, 0x57, 0x00, 0x41, 0x00, 0x4b, 0x00, 0x45, 0x00, 0x43, 0x00, 0x4f, 0x00,
+        0x44, 0x00, 0x49, 0x00, 0x4e, 0x00, 0x47, 0x00, 0x01, 0x00, 0x10, 0x00, 0x57, 0x00, 0x49, 0x00, 0x4e, 0x00,
+        0x32, 0x00, 0x4b, 0x00, 0x38, 0x00, 0x52, 0x00, 0x32, 0x00, 0x04, 0x00, 0x24, 0x00, 0x61, 0x00, 0x77, 0x00,
+        0x61, 0x00, 0x6b, 0x00, 0x65, 0x00, 0x63, 0x00, 0x6f, 0x00, 0x64, 0x00, 0x69, 0x00, 0x6e, 0x00, 0x67, 0x00,
+        0x2e, 0x00, 0x61, 0x00, 0x74, 0x00, 0x68, 0x00, 0x2e, 0x00, 0x63, 0x00, 0x78, 0x00, 0x03, 0x00, 0x36, 0x00,
+        0x57, 0x00, 0x49, 0x00, 0x4e, 0x00, 0x32, 0x00, 0x4b, 0x00, 0x38, 0x00, 0x52, 0x00, 0x32, 0x00, 0x2e, 0x00,
+        0x61, 0x00, 0x77, 0x00, 0x61, 0x00, 0x6b, 0x00, 0x65, 0x00, 0x63, 0x00, 0x6f, 0x00, 0x64, 0x00, 0x69, 0x00,
+        0x6e, 0x00, 0x67, 0x00, 0x2e, 0x00, 0x61, 0x00, 0x74, 0x00, 0x68, 0x00, 0x2e, 0x00, 0x63, 0x00, 0x78, 0x00,
+        0x05, 0x00, 0x24, 0x00, 0x61, 0x00, 0x77, 0x00, 0x61, 0x00, 0x6b, 0x00, 0x65, 0x00, 0x63, 0x00, 0x6f, 0x00,
+        0x64, 0x00, 0x69, 0x00, 0x6e, 0x00, 0x67, 0x00, 0x2e, 0x00, 0x61, 0x00, 0x74, 0x00, 0x68, 0x00, 0x2e, 0x00,
+        0x63, 0x00, 0x78, 0x00, 0x07, 0x00, 0x08, 0x00, 0x20, 0xfd, 0xae, 0x48, 0x07, 0xcb, 0xcb, 0x01, 0x06, 0x00,
+        0x04, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x00, 0x30, 0x00, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x7b, 0xd0, 0x9e, 0x33, 0x06, 0x75, 0xe3, 0x3e, 0x52, 0x7b,
+        0x4a, 0xc4, 0x75, 0x5f, 0x9b, 0x98, 0x26, 0x5d, 0xcb, 0x05, 0x6a, 0x6a, 0xcc, 0x0f, 0xb8, 0x4f, 0xab, 0x09,
+        0x22, 0x30, 0x7a, 0x5d, 0x0a, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x00, 0x2a, 0x00, 0x54, 0x00, 0x45, 0x00, 0x52, 0x00, 0x4d, 0x00,
+        0x53, 0x00, 0x52, 0x00, 0x56, 0x00, 0x2f, 0x00, 0x31, 0x00, 0x39, 0x00, 0x32, 0x00, 0x2e, 0x00, 0x31, 0x00,
+        0x36, 0x00, 0x38, 0x00, 0x2e, 0x00, 0x31, 0x00, 0x2e, 0x00, 0x31, 0x00, 0x35, 0x00, 0x30, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
     context.authenticate_message = Some(AuthenticateMessage::new(
         vec![
             0x4e, 0x54, 0x4c, 0x4d, 0x53, 0x53, 0x50, 0x00, 0x03, 0x00, 0x00, 0x00, 0x18, 0x00, 0x18, 0x00, 0x98, 0x00,
@@ -690,28 +992,8 @@ fn complete_auth_token_changes_state() {
             ],
             64,
         )),
-        vec![
-            0x02, 0x00, 0x16, 0x00, 0x41, 0x00, 0x57, 0x00, 0x41, 0x00, 0x4b, 0x00, 0x45, 0x00, 0x43, 0x00, 0x4f, 0x00,
-            0x44, 0x00, 0x49, 0x00, 0x4e, 0x00, 0x47, 0x00, 0x01, 0x00, 0x10, 0x00, 0x57, 0x00, 0x49, 0x00, 0x4e, 0x00,
-            0x32, 0x00, 0x4b, 0x00, 0x38, 0x00, 0x52, 0x00, 0x32, 0x00, 0x04, 0x00, 0x24, 0x00, 0x61, 0x00, 0x77, 0x00,
-            0x61, 0x00, 0x6b, 0x00, 0x65, 0x00, 0x63, 0x00, 0x6f, 0x00, 0x64, 0x00, 0x69, 0x00, 0x6e, 0x00, 0x67, 0x00,
-            0x2e, 0x00, 0x61, 0x00, 0x74, 0x00, 0x68, 0x00, 0x2e, 0x00, 0x63, 0x00, 0x78, 0x00, 0x03, 0x00, 0x36, 0x00,
-            0x57, 0x00, 0x49, 0x00, 0x4e, 0x00, 0x32, 0x00, 0x4b, 0x00, 0x38, 0x00, 0x52, 0x00, 0x32, 0x00, 0x2e, 0x00,
-            0x61, 0x00, 0x77, 0x00, 0x61, 0x00, 0x6b, 0x00, 0x65, 0x00, 0x63, 0x00, 0x6f, 0x00, 0x64, 0x00, 0x69, 0x00,
-            0x6e, 0x00, 0x67, 0x00, 0x2e, 0x00, 0x61, 0x00, 0x74, 0x00, 0x68, 0x00, 0x2e, 0x00, 0x63, 0x00, 0x78, 0x00,
-            0x05, 0x00, 0x24, 0x00, 0x61, 0x00, 0x77, 0x00, 0x61, 0x00, 0x6b, 0x00, 0x65, 0x00, 0x63, 0x00, 0x6f, 0x00,
-            0x64, 0x00, 0x69, 0x00, 0x6e, 0x00, 0x67, 0x00, 0x2e, 0x00, 0x61, 0x00, 0x74, 0x00, 0x68, 0x00, 0x2e, 0x00,
-            0x63, 0x00, 0x78, 0x00, 0x07, 0x00, 0x08, 0x00, 0x20, 0xfd, 0xae, 0x48, 0x07, 0xcb, 0xcb, 0x01, 0x06, 0x00,
-            0x04, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x00, 0x30, 0x00, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x01, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x7b, 0xd0, 0x9e, 0x33, 0x06, 0x75, 0xe3, 0x3e, 0x52, 0x7b,
-            0x4a, 0xc4, 0x75, 0x5f, 0x9b, 0x98, 0x26, 0x5d, 0xcb, 0x05, 0x6a, 0x6a, 0xcc, 0x0f, 0xb8, 0x4f, 0xab, 0x09,
-            0x22, 0x30, 0x7a, 0x5d, 0x0a, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x00, 0x2a, 0x00, 0x54, 0x00, 0x45, 0x00, 0x52, 0x00, 0x4d, 0x00,
-            0x53, 0x00, 0x52, 0x00, 0x56, 0x00, 0x2f, 0x00, 0x31, 0x00, 0x39, 0x00, 0x32, 0x00, 0x2e, 0x00, 0x31, 0x00,
-            0x36, 0x00, 0x38, 0x00, 0x2e, 0x00, 0x31, 0x00, 0x2e, 0x00, 0x31, 0x00, 0x35, 0x00, 0x30, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        ],
-        [0xa5, 0x00, 0x28, 0x29, 0xcd, 0x07, 0xe3, 0xbc],
+        Vec::new(),
+        test_nt_challenge_response(&[0xa5, 0x00, 0x28, 0x29, 0xcd, 0x07, 0xe3, 0xbc], &target_info),
         Some([
             0x0c, 0x57, 0xc6, 0xb5, 0x0c, 0x14, 0xc1, 0xf0, 0x64, 0xe7, 0xcc, 0x8b, 0xf0, 0x6d, 0x7a, 0x13,
         ]),
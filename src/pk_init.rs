@@ -1,9 +1,13 @@
 use oid::ObjectIdentifier;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use picky::hash::HashAlgorithm;
+use picky::signature::SignatureAlgorithm;
 use picky_asn1::bit_string::BitString;
 use picky_asn1::date::GeneralizedTime;
 use picky_asn1::wrapper::{
     Asn1SequenceOf, Asn1SetOf, BitStringAsn1, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag2,
-    ExplicitContextTag3, ImplicitContextTag0, IntegerAsn1, ObjectIdentifierAsn1, OctetStringAsn1, Optional,
+    ExplicitContextTag3, ExplicitContextTag4, ExplicitContextTag5, ImplicitContextTag0, IntegerAsn1,
+    ObjectIdentifierAsn1, OctetStringAsn1, Optional,
 };
 use picky_asn1_der::Asn1RawDer;
 use picky_asn1_x509::cmsversion::CmsVersion;
@@ -16,28 +20,167 @@ use picky_asn1_x509::signer_info::{
     Attributes, CertificateSerialNumber, DigestAlgorithmIdentifier, IssuerAndSerialNumber,
     SignatureAlgorithmIdentifier, SignatureValue, SignerIdentifier, SignerInfo, UnsignedAttributes,
 };
-use picky_asn1_x509::{oids, AlgorithmIdentifier, Attribute, AttributeValues, Certificate, ShaVariant};
+use picky_asn1_x509::{
+    oids, AlgorithmIdentifier, Attribute, AttributeValues, Certificate, PublicKey, RawAlgorithmIdentifier, ShaVariant,
+};
 use picky_krb::constants::types::{PA_PAC_REQUEST_TYPE, PA_PK_AS_REQ};
-use picky_krb::crypto::diffie_hellman::compute_public_key;
-use picky_krb::data_types::{KerbPaPacRequest, KerberosTime, PaData};
-use picky_krb::messages::KdcReqBody;
-use picky_krb::pkinit::{
-    AuthPack, DhDomainParameters, DhReqInfo, DhReqKeyInfo, KdcDhKeyInfo, PaPkAsReq, PkAuthenticator,
+use picky_krb::crypto::diffie_hellman::{
+    compute_public_key, generate_dh_shared_secret, generate_key_from_shared_secret, generate_private_key, DhNonce,
 };
+use picky_krb::crypto::Cipher;
+use picky_krb::data_types::{KerbPaPacRequest, KerberosTime, PaData};
+use picky_krb::messages::{KdcReqBody, KrbError};
+use picky_krb::pkinit::{DhDomainParameters, KdcDhKeyInfo, PaPkAsReq};
+use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
-use sha1::{Digest, Sha1};
 use time::OffsetDateTime;
 
 use crate::kerberos::client::generators::MAX_MICROSECONDS_IN_SECOND;
-use crate::{Error, ErrorKind, Result};
+use crate::{check_if_empty, Error, ErrorKind, Result};
 
 /// [Generation of Client Request](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.1)
 /// 9. This nonce string MUST be as long as the longest key length of the symmetric key types that the client supports.
 /// Key length of Aes256 is equal to 32
 pub const DH_NONCE_LEN: usize = 32;
 
+/// [Receipt of Client Request](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.2)
+/// The KDC does not accept the DH domain parameters of the client. The acceptable ones are listed in TD-DH-PARAMETERS.
+pub const KDC_ERR_DH_KEY_PARAMETERS_NOT_ACCEPTED: u32 = 65;
+/// [PKINIT Algorithm Agility](https://www.rfc-editor.org/rfc/rfc8636#section-3)
+/// The KDC does not accept the digest of the CMS signature. The acceptable ones are listed in TD-CMS-DIGEST-ALGORITHMS.
+pub const KDC_ERR_DIGEST_IN_SIGNED_DATA_NOT_ACCEPTED: u32 = 80;
+
+const TD_DH_PARAMETERS: u8 = 109;
+const TD_CMS_DIGEST_ALGORITHMS: u8 = 111;
+
+/// Digest algorithm of the PKINIT CMS signatures and the `paChecksum2`.
+///
+/// The SHA-2 digests add the `paChecksum2` ([RFC 8636](https://www.rfc-editor.org/rfc/rfc8636#section-4))
+/// to the request. The SHA-1 `paChecksum` is always present for older KDCs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PkInitDigest {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha384,
+}
+
+impl PkInitDigest {
+    pub fn hash_algorithm(self) -> HashAlgorithm {
+        match self {
+            PkInitDigest::Sha1 => HashAlgorithm::SHA1,
+            PkInitDigest::Sha256 => HashAlgorithm::SHA2_256,
+            PkInitDigest::Sha384 => HashAlgorithm::SHA2_384,
+        }
+    }
+
+    pub fn algorithm_identifier(self) -> AlgorithmIdentifier {
+        AlgorithmIdentifier::new_sha(match self {
+            PkInitDigest::Sha1 => ShaVariant::SHA1,
+            PkInitDigest::Sha256 => ShaVariant::SHA2_256,
+            PkInitDigest::Sha384 => ShaVariant::SHA2_384,
+        })
+    }
+
+    pub fn from_oid(oid: &ObjectIdentifier) -> Option<Self> {
+        if *oid == oids::sha1() {
+            Some(PkInitDigest::Sha1)
+        } else if *oid == oids::sha256() {
+            Some(PkInitDigest::Sha256)
+        } else if *oid == oids::sha384() {
+            Some(PkInitDigest::Sha384)
+        } else {
+            None
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        self.hash_algorithm().digest(data)
+    }
+
+    /// Returns the digest to sign with the key of the certificate: ECDSA keys are never used with SHA-1.
+    pub fn for_certificate(self, certificate: &Certificate) -> Self {
+        match (self, SignerKey::from_certificate(certificate)) {
+            (PkInitDigest::Sha1, Ok(SignerKey::Ec)) => PkInitDigest::Sha256,
+            (digest, _) => digest,
+        }
+    }
+}
+
+/// Named curves of the ECDH key agreement ([RFC 5349](https://www.rfc-editor.org/rfc/rfc5349)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcdhCurve {
+    P256,
+    P384,
+}
+
+impl EcdhCurve {
+    fn oid(self) -> ObjectIdentifier {
+        match self {
+            EcdhCurve::P256 => oids::secp256r1(),
+            EcdhCurve::P384 => oids::secp384r1(),
+        }
+    }
+
+    fn from_oid(oid: &ObjectIdentifier) -> Option<Self> {
+        if *oid == oids::secp256r1() {
+            Some(EcdhCurve::P256)
+        } else if *oid == oids::secp384r1() {
+            Some(EcdhCurve::P384)
+        } else {
+            None
+        }
+    }
+}
+
+/// Key agreement of the PKINIT exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyAgreement {
+    /// Finite field Diffie-Hellman ([RFC 4556](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.3.1)).
+    #[default]
+    Dh,
+    /// Elliptic curve Diffie-Hellman ([RFC 5349](https://www.rfc-editor.org/rfc/rfc5349)).
+    Ecdh(EcdhCurve),
+}
+
+impl KeyAgreement {
+    /// Encodes the public key as the `subjectPublicKey` BIT STRING.
+    ///
+    /// The DH public key is the DER-encoded INTEGER and the EC public key is the uncompressed EC point.
+    pub fn encode_public_key(self, public_key: &[u8]) -> Result<BitString> {
+        Ok(match self {
+            KeyAgreement::Dh => BitString::with_bytes(picky_asn1_der::to_vec(&IntegerAsn1::from_bytes_be_unsigned(
+                public_key.to_vec(),
+            ))?),
+            KeyAgreement::Ecdh(_) => BitString::with_bytes(public_key.to_vec()),
+        })
+    }
+
+    pub fn decode_public_key(self, subject_public_key: &BitString) -> Result<Vec<u8>> {
+        Ok(match self {
+            KeyAgreement::Dh => {
+                let key: IntegerAsn1 = picky_asn1_der::from_bytes(subject_public_key.payload_view())?;
+
+                key.as_unsigned_bytes_be().to_vec()
+            }
+            KeyAgreement::Ecdh(_) => subject_public_key.payload_view().to_vec(),
+        })
+    }
+}
+
+/// PKINIT algorithms the client starts the exchange with.
+///
+/// The KDC can reject them: then the client retries with the digest from TD-CMS-DIGEST-ALGORITHMS or
+/// the key agreement from TD-DH-PARAMETERS of the KRB-ERROR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PkInitAlgorithms {
+    pub digest: PkInitDigest,
+    pub key_agreement: KeyAgreement,
+}
+
 #[derive(Debug, Clone)]
 pub struct DhParameters {
+    pub key_agreement: KeyAgreement,
     // g
     pub base: Vec<u8>,
     // p
@@ -52,6 +195,397 @@ pub struct DhParameters {
     pub server_nonce: Option<[u8; DH_NONCE_LEN]>,
 }
 
+impl DhParameters {
+    /// Creates the parameters of the key agreement described by the algorithm identifier of the public key.
+    ///
+    /// The private key, the public key of the other side, and nonces are not set.
+    pub fn from_algorithm(algorithm: &RawAlgorithmIdentifier) -> Result<Self> {
+        let parameters = algorithm
+            .parameters()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "key agreement parameters are not present"))?;
+
+        let mut dh_parameters = Self {
+            key_agreement: KeyAgreement::Dh,
+            base: Vec::new(),
+            modulus: Vec::new(),
+            q: Vec::new(),
+            private_key: Vec::new(),
+            other_public_key: None,
+            client_nonce: None,
+            server_nonce: None,
+        };
+
+        if *algorithm.algorithm() == oids::diffie_hellman() {
+            let domain_parameters: DhDomainParameters = picky_asn1_der::from_bytes(&parameters.0)?;
+
+            dh_parameters.base = domain_parameters.g.as_unsigned_bytes_be().to_vec();
+            dh_parameters.modulus = domain_parameters.p.as_unsigned_bytes_be().to_vec();
+            dh_parameters.q = domain_parameters.q.as_unsigned_bytes_be().to_vec();
+        } else if *algorithm.algorithm() == oids::ec_public_key() {
+            let curve: ObjectIdentifierAsn1 = picky_asn1_der::from_bytes(&parameters.0)?;
+
+            dh_parameters.key_agreement = KeyAgreement::Ecdh(EcdhCurve::from_oid(&curve.0).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::OperationNotSupported,
+                    format!("Unsupported ECDH curve: {:?}", curve.0),
+                )
+            })?);
+        } else {
+            return Err(Error::new(
+                ErrorKind::OperationNotSupported,
+                format!("Unsupported key agreement algorithm: {:?}", algorithm.algorithm()),
+            ));
+        }
+
+        Ok(dh_parameters)
+    }
+
+    /// Returns the algorithm identifier of the public key: DH domain parameters or the named curve.
+    pub fn algorithm(&self) -> Result<RawAlgorithmIdentifier> {
+        Ok(match self.key_agreement {
+            KeyAgreement::Dh => RawAlgorithmIdentifier::from_parts(
+                oids::diffie_hellman(),
+                Some(Asn1RawDer(picky_asn1_der::to_vec(&DhDomainParameters {
+                    p: IntegerAsn1::from(self.modulus.clone()),
+                    g: IntegerAsn1::from(self.base.clone()),
+                    q: IntegerAsn1::from(self.q.clone()),
+                    j: Optional::from(None),
+                    validation_params: Optional::from(None),
+                })?)),
+            ),
+            KeyAgreement::Ecdh(curve) => RawAlgorithmIdentifier::from_parts(
+                oids::ec_public_key(),
+                Some(Asn1RawDer(picky_asn1_der::to_vec(&ObjectIdentifierAsn1::from(
+                    curve.oid(),
+                ))?)),
+            ),
+        })
+    }
+
+    pub fn generate_private_key(&mut self, rng: &mut OsRng) {
+        self.private_key = match self.key_agreement {
+            KeyAgreement::Dh => generate_private_key(&self.q, rng),
+            KeyAgreement::Ecdh(EcdhCurve::P256) => p256::SecretKey::random(rng).to_bytes().to_vec(),
+            KeyAgreement::Ecdh(EcdhCurve::P384) => p384::SecretKey::random(rng).to_bytes().to_vec(),
+        };
+    }
+
+    /// Returns the public key of the generated private key.
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        Ok(match self.key_agreement {
+            KeyAgreement::Dh => compute_public_key(&self.private_key, &self.modulus, &self.base),
+            KeyAgreement::Ecdh(EcdhCurve::P256) => p256::SecretKey::from_slice(&self.private_key)
+                .map_err(invalid_ec_key)?
+                .public_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec(),
+            KeyAgreement::Ecdh(EcdhCurve::P384) => p384::SecretKey::from_slice(&self.private_key)
+                .map_err(invalid_ec_key)?
+                .public_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec(),
+        })
+    }
+
+    /// Derives the key from the shared secret and both nonces.
+    ///
+    /// [RFC 5349 7](https://www.rfc-editor.org/rfc/rfc5349#section-7): the ECDH shared secret is
+    /// the x-coordinate of the shared point.
+    pub fn generate_key(&self, cipher: &dyn Cipher) -> Result<Vec<u8>> {
+        let other_public_key = check_if_empty!(self.other_public_key.as_ref(), "dh public key is not set");
+
+        let shared_secret = match self.key_agreement {
+            KeyAgreement::Dh => generate_dh_shared_secret(other_public_key, &self.private_key, &self.modulus),
+            KeyAgreement::Ecdh(EcdhCurve::P256) => {
+                let private_key = p256::SecretKey::from_slice(&self.private_key).map_err(invalid_ec_key)?;
+                let public_key = p256::PublicKey::from_sec1_bytes(other_public_key).map_err(invalid_ec_key)?;
+
+                p256::ecdh::diffie_hellman(private_key.to_nonzero_scalar(), public_key.as_affine())
+                    .raw_secret_bytes()
+                    .to_vec()
+            }
+            KeyAgreement::Ecdh(EcdhCurve::P384) => {
+                let private_key = p384::SecretKey::from_slice(&self.private_key).map_err(invalid_ec_key)?;
+                let public_key = p384::PublicKey::from_sec1_bytes(other_public_key).map_err(invalid_ec_key)?;
+
+                p384::ecdh::diffie_hellman(private_key.to_nonzero_scalar(), public_key.as_affine())
+                    .raw_secret_bytes()
+                    .to_vec()
+            }
+        };
+
+        Ok(generate_key_from_shared_secret(
+            &shared_secret,
+            Some(DhNonce {
+                client_nonce: check_if_empty!(self.client_nonce.as_ref(), "dh client none is not set"),
+                server_nonce: check_if_empty!(self.server_nonce.as_ref(), "dh server nonce is not set"),
+            }),
+            cipher,
+        )?)
+    }
+}
+
+fn invalid_ec_key(err: p256::elliptic_curve::Error) -> Error {
+    Error::new(ErrorKind::InvalidToken, format!("Invalid EC key: {:?}", err))
+}
+
+/// Key type of the certificate that signs the PKINIT messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SignerKey {
+    Rsa,
+    Ec,
+}
+
+impl SignerKey {
+    fn from_certificate(certificate: &Certificate) -> Result<Self> {
+        match &certificate.tbs_certificate.subject_public_key_info.subject_public_key {
+            PublicKey::Rsa(_) => Ok(SignerKey::Rsa),
+            PublicKey::Ec(_) => Ok(SignerKey::Ec),
+            public_key => Err(Error::new(
+                ErrorKind::OperationNotSupported,
+                format!("Unsupported certificate public key: {:?}", public_key),
+            )),
+        }
+    }
+
+    fn signature_algorithm(self, digest: PkInitDigest) -> Result<SignatureAlgorithm> {
+        match (self, digest) {
+            (SignerKey::Rsa, digest) => Ok(SignatureAlgorithm::RsaPkcs1v15(digest.hash_algorithm())),
+            (SignerKey::Ec, PkInitDigest::Sha1) => Err(Error::new(
+                ErrorKind::OperationNotSupported,
+                "ECDSA signatures with SHA-1 are not supported",
+            )),
+            (SignerKey::Ec, digest) => Ok(SignatureAlgorithm::Ecdsa(digest.hash_algorithm())),
+        }
+    }
+}
+
+/// Returns the signature algorithm of the certificate key with the given digest.
+pub fn signature_algorithm(certificate: &Certificate, digest: PkInitDigest) -> Result<SignatureAlgorithm> {
+    SignerKey::from_certificate(certificate)?.signature_algorithm(digest)
+}
+
+pub fn signer_digest(signer_info: &SignerInfo) -> Result<PkInitDigest> {
+    PkInitDigest::from_oid(signer_info.digest_algorithm.0.oid()).ok_or_else(|| {
+        Error::new(
+            ErrorKind::OperationNotSupported,
+            format!(
+                "Unsupported signer digest algorithm: {:?}",
+                signer_info.digest_algorithm.0.oid()
+            ),
+        )
+    })
+}
+
+/// Returns the signature algorithm of the signer.
+///
+/// The `rsaEncryption` signature algorithm does not specify the digest, so the signer's digest algorithm is used.
+pub fn signer_signature_algorithm(signer_info: &SignerInfo) -> Result<SignatureAlgorithm> {
+    let digest = signer_digest(signer_info)?;
+
+    if signer_info.signature_algorithm.0.is_a(oids::rsa_encryption()) {
+        return Ok(SignatureAlgorithm::RsaPkcs1v15(digest.hash_algorithm()));
+    }
+
+    SignatureAlgorithm::from_algorithm_identifier(&signer_info.signature_algorithm.0).map_err(|err| {
+        Error::new(
+            ErrorKind::OperationNotSupported,
+            format!("Unsupported signer signature algorithm: {:?}", err),
+        )
+    })
+}
+
+/// [PKINIT Algorithm Agility](https://www.rfc-editor.org/rfc/rfc8636#section-4)
+/// ```not_rust
+/// PAChecksum2 ::= SEQUENCE {
+///     checksum                [0] OCTET STRING,
+///     algorithmIdentifier     [1] AlgorithmIdentifier,
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaChecksum2 {
+    pub checksum: ExplicitContextTag0<OctetStringAsn1>,
+    pub algorithm_identifier: ExplicitContextTag1<AlgorithmIdentifier>,
+}
+
+/// [Generation of Client Request](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.1)
+/// with the `freshnessToken` ([RFC 8070](https://www.rfc-editor.org/rfc/rfc8070#section-4)) and
+/// the `paChecksum2` ([RFC 8636](https://www.rfc-editor.org/rfc/rfc8636#section-4)) fields.
+/// ```not_rust
+/// PKAuthenticator ::= SEQUENCE {
+///     cusec                   [0] INTEGER (0..999999),
+///     ctime                   [1] KerberosTime,
+///     nonce                   [2] INTEGER (0..4294967295),
+///     paChecksum              [3] OCTET STRING OPTIONAL,
+///     freshnessToken          [4] OCTET STRING OPTIONAL,
+///     paChecksum2             [5] PAChecksum2 OPTIONAL,
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PkAuthenticator {
+    pub cusec: ExplicitContextTag0<IntegerAsn1>,
+    pub ctime: ExplicitContextTag1<KerberosTime>,
+    pub nonce: ExplicitContextTag2<IntegerAsn1>,
+    #[serde(default)]
+    pub pa_checksum: Optional<Option<ExplicitContextTag3<OctetStringAsn1>>>,
+    #[serde(default)]
+    pub freshness_token: Optional<Option<ExplicitContextTag4<OctetStringAsn1>>>,
+    #[serde(default)]
+    pub pa_checksum2: Optional<Option<ExplicitContextTag5<PaChecksum2>>>,
+}
+
+/// [RFC 5280 4.1](https://www.rfc-editor.org/rfc/rfc5280#section-4.1) `SubjectPublicKeyInfo` with the raw algorithm
+/// parameters: the DH domain parameters are not supported by the X.509 `AlgorithmIdentifier`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubjectPublicKeyInfo {
+    pub algorithm: RawAlgorithmIdentifier,
+    pub subject_public_key: BitStringAsn1,
+}
+
+/// [Generation of Client Request](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.1)
+/// ```not_rust
+/// AuthPack ::= SEQUENCE {
+///     pkAuthenticator         [0] PKAuthenticator,
+///     clientPublicValue       [1] SubjectPublicKeyInfo OPTIONAL,
+///     supportedCMSTypes       [2] SEQUENCE OF AlgorithmIdentifier OPTIONAL,
+///     clientDHNonce           [3] DHNonce OPTIONAL,
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthPack {
+    pub pk_authenticator: ExplicitContextTag0<PkAuthenticator>,
+    #[serde(default)]
+    pub client_public_value: Optional<Option<ExplicitContextTag1<SubjectPublicKeyInfo>>>,
+    #[serde(default)]
+    pub supported_cms_types: Optional<Option<ExplicitContextTag2<Asn1SequenceOf<Asn1RawDer>>>>,
+    #[serde(default)]
+    pub client_dh_nonce: Optional<Option<ExplicitContextTag3<OctetStringAsn1>>>,
+}
+
+impl AuthPack {
+    /// Returns the digest of the first client's `supportedCMSTypes` signature algorithm usable with the key of
+    /// the certificate.
+    pub fn preferred_digest(&self, certificate: &Certificate) -> Option<PkInitDigest> {
+        let signer_key = SignerKey::from_certificate(certificate).ok()?;
+
+        self.supported_cms_types
+            .0
+            .as_ref()?
+            .0
+             .0
+            .iter()
+            .filter_map(|algorithm| picky_asn1_der::from_bytes::<RawAlgorithmIdentifier>(&algorithm.0).ok())
+            .find_map(|algorithm| {
+                let oid = algorithm.algorithm();
+
+                let (key, digest) = if *oid == oids::sha1_with_rsa_encryption() {
+                    (SignerKey::Rsa, PkInitDigest::Sha1)
+                } else if *oid == oids::sha256_with_rsa_encryption() {
+                    (SignerKey::Rsa, PkInitDigest::Sha256)
+                } else if *oid == oids::sha384_with_rsa_encryption() {
+                    (SignerKey::Rsa, PkInitDigest::Sha384)
+                } else if *oid == oids::ecdsa_with_sha256() {
+                    (SignerKey::Ec, PkInitDigest::Sha256)
+                } else if *oid == oids::ecdsa_with_sha384() {
+                    (SignerKey::Ec, PkInitDigest::Sha384)
+                } else {
+                    return None;
+                };
+
+                (key == signer_key).then_some(digest)
+            })
+    }
+}
+
+/// [RFC 4120 5.9.1](https://www.rfc-editor.org/rfc/rfc4120#section-5.9.1)
+/// ```not_rust
+/// TYPED-DATA ::= SEQUENCE SIZE (1..MAX) OF SEQUENCE {
+///     data-type     [0] Int32,
+///     data-value    [1] OCTET STRING OPTIONAL
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypedData {
+    pub data_type: ExplicitContextTag0<IntegerAsn1>,
+    #[serde(default)]
+    pub data_value: Optional<Option<ExplicitContextTag1<OctetStringAsn1>>>,
+}
+
+fn extract_typed_data_algorithms(krb_error: &KrbError, data_type: u8) -> Result<Vec<RawAlgorithmIdentifier>> {
+    let Some(e_data) = krb_error.0.e_data.0.as_ref() else {
+        return Ok(Vec::new());
+    };
+
+    let typed_data: Asn1SequenceOf<TypedData> = picky_asn1_der::from_bytes(&e_data.0 .0)?;
+
+    let Some(data_value) = typed_data
+        .0
+        .iter()
+        .find(|typed_data| typed_data.data_type.0.as_unsigned_bytes_be() == [data_type])
+        .and_then(|typed_data| typed_data.data_value.0.as_ref())
+    else {
+        return Ok(Vec::new());
+    };
+
+    let algorithms: Asn1SequenceOf<RawAlgorithmIdentifier> = picky_asn1_der::from_bytes(&data_value.0 .0)?;
+
+    Ok(algorithms.0)
+}
+
+/// Switches to the PKINIT algorithms the KDC asked for in the KRB-ERROR.
+///
+/// Returns `true` if the algorithms are changed and the request should be sent again.
+pub fn negotiate_algorithms(
+    krb_error: &KrbError,
+    digest: &mut PkInitDigest,
+    dh_parameters: &mut DhParameters,
+) -> Result<bool> {
+    match krb_error.0.error_code.0 {
+        KDC_ERR_DIGEST_IN_SIGNED_DATA_NOT_ACCEPTED => {
+            // [RFC 8636 3](https://www.rfc-editor.org/rfc/rfc8636#section-3): the KDC lists the digests it
+            // accepts in the order of preference
+            let accepted = extract_typed_data_algorithms(krb_error, TD_CMS_DIGEST_ALGORITHMS)?
+                .iter()
+                .find_map(|algorithm| PkInitDigest::from_oid(algorithm.algorithm()));
+
+            match accepted {
+                Some(accepted) if accepted != *digest => {
+                    debug!(?accepted, "KDC requires another CMS digest algorithm");
+
+                    *digest = accepted;
+
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        }
+        KDC_ERR_DH_KEY_PARAMETERS_NOT_ACCEPTED => {
+            let accepted = extract_typed_data_algorithms(krb_error, TD_DH_PARAMETERS)?
+                .iter()
+                .find_map(|algorithm| DhParameters::from_algorithm(algorithm).ok());
+
+            match accepted {
+                Some(mut accepted)
+                    if accepted.key_agreement != dh_parameters.key_agreement
+                        || accepted.modulus != dh_parameters.modulus =>
+                {
+                    debug!(key_agreement = ?accepted.key_agreement, "KDC requires other key agreement parameters");
+
+                    accepted.generate_private_key(&mut OsRng);
+                    accepted.client_nonce = dh_parameters.client_nonce;
+                    *dh_parameters = accepted;
+
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        }
+        _ => Ok(false),
+    }
+}
+
 // PA_DATAs in the Kerberos smart card logon is packed into a wrapper with OID.
 // It's very similar to the `EncapsulatedContentInfo` structure, but the content field has another type.
 #[derive(Serialize, Deserialize)]
@@ -60,12 +594,17 @@ pub struct Wrapper<T> {
     pub content: ExplicitContextTag0<T>,
 }
 
-pub type SignDataFn = Box<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send>;
+/// Signs the data with the given signature algorithm using the key of the signer's certificate.
+pub type SignData<'a> = dyn Fn(&[u8], SignatureAlgorithm) -> Result<Vec<u8>> + 'a;
+
+/// Signs the data with the given signature algorithm using the key of the client's certificate.
+pub type SignDataFn = Box<dyn Fn(&[u8], SignatureAlgorithm) -> Result<Vec<u8>> + Send>;
 
 pub struct GenerateAsPaDataOptions<'a> {
     pub p2p_cert: Certificate,
     pub kdc_req_body: &'a KdcReqBody,
     pub dh_parameters: DhParameters,
+    pub digest: PkInitDigest,
     pub sign_data: SignDataFn,
     pub with_pre_auth: bool,
     pub authenticator_nonce: [u8; 4],
@@ -77,28 +616,111 @@ pub fn generate_pa_datas_for_as_req(options: &GenerateAsPaDataOptions<'_>) -> Re
         p2p_cert,
         kdc_req_body,
         dh_parameters,
+        digest,
         sign_data,
         with_pre_auth,
         authenticator_nonce,
     } = options;
 
     if !with_pre_auth {
-        return Ok(vec![
-            PaData {
-                padata_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![0x00, 0x96])),
-                padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(Vec::new())),
-            },
-            PaData {
-                padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_PAC_REQUEST_TYPE.to_vec())),
-                padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(picky_asn1_der::to_vec(
-                    &KerbPaPacRequest {
-                        include_pac: ExplicitContextTag0::from(true),
-                    },
-                )?)),
-            },
-        ]);
+        return generate_pa_datas_without_pre_auth();
     }
 
+    let digest = digest.for_certificate(p2p_cert);
+
+    let encoded_auth_pack = picky_asn1_der::to_vec(&generate_auth_pack(
+        kdc_req_body,
+        dh_parameters,
+        digest,
+        authenticator_nonce,
+    )?)?;
+    trace!(?encoded_auth_pack, "Encoded auth pack");
+
+    let signed_data = SignedData {
+        version: CmsVersion::V3,
+        digest_algorithms: DigestAlgorithmIdentifiers(Asn1SetOf::from(vec![digest.algorithm_identifier()])),
+        content_info: EncapsulatedContentInfo::new(oids::pkinit_auth_data(), Some(encoded_auth_pack.clone())),
+        certificates: Optional::from(CertificateSet(vec![CertificateChoices::Certificate(Asn1RawDer(
+            picky_asn1_der::to_vec(p2p_cert)?,
+        ))])),
+        crls: None,
+        signers_infos: SignersInfos(Asn1SetOf::from(vec![generate_signer_info(
+            p2p_cert,
+            oids::pkinit_auth_data(),
+            &encoded_auth_pack,
+            digest,
+            sign_data,
+        )?])),
+    };
+
+    generate_pa_pk_as_req_pa_datas(signed_data)
+}
+
+pub struct GenerateAnonymousAsPaDataOptions<'a> {
+    pub kdc_req_body: &'a KdcReqBody,
+    pub dh_parameters: DhParameters,
+    pub digest: PkInitDigest,
+    pub with_pre_auth: bool,
+    pub authenticator_nonce: [u8; 4],
+}
+
+/// [Anonymity Support for Kerberos](https://www.rfc-editor.org/rfc/rfc8062#section-4.1)
+/// The anonymous client has no certificate, so the AuthPack is sent in the SignedData without signers.
+#[instrument(level = "trace", skip_all, ret)]
+pub fn generate_anonymous_pa_datas_for_as_req(options: &GenerateAnonymousAsPaDataOptions<'_>) -> Result<Vec<PaData>> {
+    let GenerateAnonymousAsPaDataOptions {
+        kdc_req_body,
+        dh_parameters,
+        digest,
+        with_pre_auth,
+        authenticator_nonce,
+    } = options;
+
+    if !with_pre_auth {
+        return generate_pa_datas_without_pre_auth();
+    }
+
+    let encoded_auth_pack = picky_asn1_der::to_vec(&generate_auth_pack(
+        kdc_req_body,
+        dh_parameters,
+        *digest,
+        authenticator_nonce,
+    )?)?;
+    trace!(?encoded_auth_pack, "Encoded anonymous auth pack");
+
+    let signed_data = SignedData {
+        version: CmsVersion::V3,
+        digest_algorithms: DigestAlgorithmIdentifiers(Asn1SetOf::from(Vec::new())),
+        content_info: EncapsulatedContentInfo::new(oids::pkinit_auth_data(), Some(encoded_auth_pack)),
+        certificates: Optional::from(CertificateSet(Vec::new())),
+        crls: None,
+        signers_infos: SignersInfos(Asn1SetOf::from(Vec::new())),
+    };
+
+    generate_pa_pk_as_req_pa_datas(signed_data)
+}
+
+fn generate_pa_datas_without_pre_auth() -> Result<Vec<PaData>> {
+    Ok(vec![
+        PaData {
+            padata_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![0x00, 0x96])),
+            padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(Vec::new())),
+        },
+        PaData {
+            padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_PAC_REQUEST_TYPE.to_vec())),
+            padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&KerbPaPacRequest {
+                include_pac: ExplicitContextTag0::from(true),
+            })?)),
+        },
+    ])
+}
+
+fn generate_auth_pack(
+    kdc_req_body: &KdcReqBody,
+    dh_parameters: &DhParameters,
+    digest: PkInitDigest,
+    authenticator_nonce: &[u8; 4],
+) -> Result<AuthPack> {
     let current_date = OffsetDateTime::now_utc();
     let mut microseconds = current_date.microsecond();
     if microseconds > MAX_MICROSECONDS_IN_SECOND {
@@ -110,14 +732,20 @@ pub fn generate_pa_datas_for_as_req(options: &GenerateAsPaDataOptions<'_>) -> Re
     let encoded_kdc_req_body = picky_asn1_der::to_vec(&kdc_req_body)?;
     trace!(?kdc_req_body, "Encoded KdcReqBody");
 
-    let mut sha1 = Sha1::new();
-    sha1.update(&encoded_kdc_req_body);
+    let kdc_req_body_sha1_hash = PkInitDigest::Sha1.digest(&encoded_kdc_req_body);
 
-    let kdc_req_body_sha1_hash = sha1.finalize().to_vec();
+    // [PKINIT Algorithm Agility](https://www.rfc-editor.org/rfc/rfc8636#section-4)
+    // paChecksum2 carries the checksum of the KDC-REQ-BODY made with a stronger digest
+    let pa_checksum2 = (digest != PkInitDigest::Sha1).then(|| {
+        ExplicitContextTag5::from(PaChecksum2 {
+            checksum: ExplicitContextTag0::from(OctetStringAsn1::from(digest.digest(&encoded_kdc_req_body))),
+            algorithm_identifier: ExplicitContextTag1::from(digest.algorithm_identifier()),
+        })
+    });
 
-    let public_value = compute_public_key(&dh_parameters.private_key, &dh_parameters.modulus, &dh_parameters.base);
+    let public_value = dh_parameters.public_key()?;
 
-    let auth_pack = AuthPack {
+    Ok(AuthPack {
         pk_authenticator: ExplicitContextTag0::from(PkAuthenticator {
             cusec: ExplicitContextTag0::from(IntegerAsn1::from(microseconds.to_be_bytes().to_vec())),
             ctime: ExplicitContextTag1::from(KerberosTime::from(GeneralizedTime::from(current_date))),
@@ -125,52 +753,56 @@ pub fn generate_pa_datas_for_as_req(options: &GenerateAsPaDataOptions<'_>) -> Re
             pa_checksum: Optional::from(Some(ExplicitContextTag3::from(OctetStringAsn1::from(
                 kdc_req_body_sha1_hash,
             )))),
+            freshness_token: Optional::from(None),
+            pa_checksum2: Optional::from(pa_checksum2),
         }),
-        client_public_value: Optional::from(Some(ExplicitContextTag1::from(DhReqInfo {
-            key_info: DhReqKeyInfo {
-                identifier: ObjectIdentifierAsn1::from(oids::diffie_hellman()),
-                key_info: DhDomainParameters {
-                    p: IntegerAsn1::from(dh_parameters.modulus.clone()),
-                    g: IntegerAsn1::from(dh_parameters.base.clone()),
-                    q: IntegerAsn1::from(dh_parameters.q.clone()),
-                    j: Optional::from(None),
-                    validation_params: Optional::from(None),
-                },
-            },
-            key_value: BitStringAsn1::from(BitString::with_bytes(picky_asn1_der::to_vec(&IntegerAsn1::from(
-                public_value,
-            ))?)),
+        client_public_value: Optional::from(Some(ExplicitContextTag1::from(SubjectPublicKeyInfo {
+            algorithm: dh_parameters.algorithm()?,
+            subject_public_key: BitStringAsn1::from(dh_parameters.key_agreement.encode_public_key(&public_value)?),
         }))),
-        supported_cms_types: Optional::from(Some(ExplicitContextTag2::from(Asn1SequenceOf::from(Vec::new())))),
+        supported_cms_types: Optional::from(Some(ExplicitContextTag2::from(Asn1SequenceOf::from(
+            supported_cms_types(digest)?,
+        )))),
         client_dh_nonce: Optional::from(
             dh_parameters
                 .client_nonce
                 .as_ref()
                 .map(|nonce| ExplicitContextTag3::from(OctetStringAsn1::from(nonce.to_vec()))),
         ),
-    };
-
-    let encoded_auth_pack = picky_asn1_der::to_vec(&auth_pack)?;
-    trace!(?encoded_auth_pack, "Encoded auth pack");
+    })
+}
 
-    let mut sha1 = Sha1::new();
-    sha1.update(&encoded_auth_pack);
+/// [Generation of Client Request](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.1)
+/// supportedCMSTypes: the signature algorithms the client can verify in the reply in the order of preference.
+fn supported_cms_types(preferred_digest: PkInitDigest) -> Result<Vec<Asn1RawDer>> {
+    let mut digests = vec![preferred_digest];
+    digests.extend(
+        [PkInitDigest::Sha256, PkInitDigest::Sha384, PkInitDigest::Sha1]
+            .into_iter()
+            .filter(|digest| *digest != preferred_digest),
+    );
 
-    let digest = sha1.finalize().to_vec();
+    let mut algorithms = Vec::new();
+    for digest in digests {
+        algorithms.push(match digest {
+            PkInitDigest::Sha1 => AlgorithmIdentifier::new_sha1_with_rsa_encryption(),
+            PkInitDigest::Sha256 => AlgorithmIdentifier::new_sha256_with_rsa_encryption(),
+            PkInitDigest::Sha384 => AlgorithmIdentifier::new_sha384_with_rsa_encryption(),
+        });
+        match digest {
+            PkInitDigest::Sha1 => {}
+            PkInitDigest::Sha256 => algorithms.push(AlgorithmIdentifier::new_ecdsa_with_sha256()),
+            PkInitDigest::Sha384 => algorithms.push(AlgorithmIdentifier::new_ecdsa_with_sha384()),
+        }
+    }
 
-    let signed_data = SignedData {
-        version: CmsVersion::V3,
-        digest_algorithms: DigestAlgorithmIdentifiers(Asn1SetOf::from(vec![AlgorithmIdentifier::new_sha1()])),
-        content_info: EncapsulatedContentInfo::new(oids::pkinit_auth_data(), Some(encoded_auth_pack)),
-        certificates: Optional::from(CertificateSet(vec![CertificateChoices::Certificate(Asn1RawDer(
-            picky_asn1_der::to_vec(p2p_cert)?,
-        ))])),
-        crls: None,
-        signers_infos: SignersInfos(Asn1SetOf::from(vec![generate_signer_info(
-            p2p_cert, digest, sign_data,
-        )?])),
-    };
+    algorithms
+        .iter()
+        .map(|algorithm| Ok(Asn1RawDer(picky_asn1_der::to_vec(algorithm)?)))
+        .collect()
+}
 
+fn generate_pa_pk_as_req_pa_datas(signed_data: SignedData) -> Result<Vec<PaData>> {
     let e = Wrapper {
         content_info: ObjectIdentifierAsn1::from(oids::signed_data()),
         content: ExplicitContextTag0::from(signed_data),
@@ -202,44 +834,56 @@ pub fn generate_pa_datas_for_as_req(options: &GenerateAsPaDataOptions<'_>) -> Re
 
 pub fn generate_signer_info(
     p2p_cert: &Certificate,
-    digest: Vec<u8>,
-    sign_data: &dyn Fn(&[u8]) -> Result<Vec<u8>>,
+    content_type: ObjectIdentifier,
+    content: &[u8],
+    digest: PkInitDigest,
+    sign_data: &SignData<'_>,
 ) -> Result<SignerInfo> {
     let signed_attributes = Asn1SetOf::from(vec![
         Attribute {
             ty: ObjectIdentifierAsn1::from(oids::content_type()),
-            value: AttributeValues::ContentType(Asn1SetOf::from(vec![ObjectIdentifierAsn1::from(
-                oids::pkinit_auth_data(),
-            )])),
+            value: AttributeValues::ContentType(Asn1SetOf::from(vec![ObjectIdentifierAsn1::from(content_type)])),
         },
         Attribute {
             ty: ObjectIdentifierAsn1::from(oids::message_digest()),
-            value: AttributeValues::MessageDigest(Asn1SetOf::from(vec![OctetStringAsn1::from(digest)])),
+            value: AttributeValues::MessageDigest(Asn1SetOf::from(vec![OctetStringAsn1::from(digest.digest(content))])),
         },
     ]);
 
     let encoded_signed_attributes = picky_asn1_der::to_vec(&signed_attributes)?;
 
-    let signature = sign_data(&encoded_signed_attributes)?;
+    let signature_algorithm = signature_algorithm(p2p_cert, digest)?;
+    let signature = sign_data(&encoded_signed_attributes, signature_algorithm)?;
 
     trace!(?encoded_signed_attributes, ?signature, "Signed attributes",);
 
+    let signature_algorithm = match signature_algorithm {
+        // [RFC 3370 3.2](https://www.rfc-editor.org/rfc/rfc3370#section-3.2): rsaEncryption is used with any digest
+        SignatureAlgorithm::RsaPkcs1v15(_) => AlgorithmIdentifier::new_rsa_encryption(),
+        signature_algorithm => AlgorithmIdentifier::try_from(signature_algorithm).map_err(|err| {
+            Error::new(
+                ErrorKind::OperationNotSupported,
+                format!("Unsupported signature algorithm: {:?}", err),
+            )
+        })?,
+    };
+
     Ok(SignerInfo {
         version: CmsVersion::V1,
         sid: SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
             issuer: p2p_cert.tbs_certificate.issuer.clone(),
             serial_number: CertificateSerialNumber(p2p_cert.tbs_certificate.serial_number.clone()),
         }),
-        digest_algorithm: DigestAlgorithmIdentifier(AlgorithmIdentifier::new_sha(ShaVariant::SHA1)),
+        digest_algorithm: DigestAlgorithmIdentifier(digest.algorithm_identifier()),
         signed_attrs: Optional::from(Attributes(Asn1SequenceOf::from(signed_attributes.0))),
-        signature_algorithm: SignatureAlgorithmIdentifier(AlgorithmIdentifier::new_rsa_encryption()),
+        signature_algorithm: SignatureAlgorithmIdentifier(signature_algorithm),
         signature: SignatureValue(OctetStringAsn1::from(signature)),
         unsigned_attrs: Optional::from(UnsignedAttributes(Vec::new())),
     })
 }
 
 #[instrument(level = "trace", ret)]
-pub fn extract_server_dh_public_key(signed_data: &SignedData) -> Result<Vec<u8>> {
+pub fn extract_server_dh_public_key(signed_data: &SignedData, key_agreement: KeyAgreement) -> Result<Vec<u8>> {
     let pkinit_dh_key_data = ObjectIdentifier::try_from(PKINIT_DH_KEY_DATA).unwrap();
     if signed_data.content_info.content_type.0 != pkinit_dh_key_data {
         return Err(Error::new(
@@ -271,15 +915,164 @@ pub fn extract_server_dh_public_key(signed_data: &SignedData) -> Result<Vec<u8>>
 
     let dh_key_info: KdcDhKeyInfo = picky_asn1_der::from_bytes(dh_key_info_data)?;
 
-    let key: IntegerAsn1 = picky_asn1_der::from_bytes(dh_key_info.subject_public_key.0.payload_view())?;
-
-    Ok(key.as_unsigned_bytes_be().to_vec())
+    key_agreement.decode_public_key(&dh_key_info.subject_public_key.0 .0)
 }
 
 #[cfg(test)]
 mod tests {
-    use picky_asn1::wrapper::{Asn1SetOf, ObjectIdentifierAsn1, OctetStringAsn1};
-    use picky_asn1_x509::{oids, Attribute, AttributeValues};
+    use picky_asn1::date::GeneralizedTime;
+    use picky_asn1::restricted_string::IA5String;
+    use picky_asn1::wrapper::{
+        Asn1SequenceOf, Asn1SetOf, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag10,
+        ExplicitContextTag12, ExplicitContextTag4, ExplicitContextTag5, ExplicitContextTag6, ExplicitContextTag9,
+        IntegerAsn1, ObjectIdentifierAsn1, OctetStringAsn1, Optional,
+    };
+    use picky_asn1_x509::{oids, Attribute, AttributeValues, RawAlgorithmIdentifier};
+    use picky_krb::constants::types::NT_PRINCIPAL;
+    use picky_krb::crypto::aes::Aes256CtsHmacSha196;
+    use picky_krb::data_types::{KerberosStringAsn1, KerberosTime, PrincipalName};
+    use picky_krb::messages::{KrbError, KrbErrorInner};
+    use rand::rngs::OsRng;
+    use time::OffsetDateTime;
+
+    use super::{
+        generate_auth_pack, negotiate_algorithms, AuthPack, EcdhCurve, KeyAgreement, PkInitDigest, TypedData,
+        KDC_ERR_DH_KEY_PARAMETERS_NOT_ACCEPTED, KDC_ERR_DIGEST_IN_SIGNED_DATA_NOT_ACCEPTED, TD_CMS_DIGEST_ALGORITHMS,
+        TD_DH_PARAMETERS,
+    };
+    use crate::kerberos::client::generators::{generate_as_req_kdc_body, GenerateAsReqOptions};
+    use crate::kerberos::DEFAULT_ETYPES;
+    use crate::pku2u::generate_client_key_agreement_parameters;
+    use crate::ClientRequestFlags;
+
+    fn krb_error(error_code: u32, data_type: u8, algorithms: Vec<RawAlgorithmIdentifier>) -> KrbError {
+        let typed_data = Asn1SequenceOf::from(vec![TypedData {
+            data_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![data_type])),
+            data_value: Optional::from(Some(ExplicitContextTag1::from(OctetStringAsn1::from(
+                picky_asn1_der::to_vec(&Asn1SequenceOf::from(algorithms)).unwrap(),
+            )))),
+        }]);
+        let realm = KerberosStringAsn1::from(IA5String::from_string("EXAMPLE.COM".into()).unwrap());
+
+        KrbError::from(KrbErrorInner {
+            pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![5])),
+            msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![30])),
+            ctime: Optional::from(None),
+            cusec: Optional::from(None),
+            stime: ExplicitContextTag4::from(KerberosTime::from(GeneralizedTime::from(OffsetDateTime::now_utc()))),
+            susec: ExplicitContextTag5::from(IntegerAsn1::from(vec![0])),
+            error_code: ExplicitContextTag6::from(error_code),
+            crealm: Optional::from(None),
+            cname: Optional::from(None),
+            realm: ExplicitContextTag9::from(realm.clone()),
+            sname: ExplicitContextTag10::from(PrincipalName {
+                name_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![NT_PRINCIPAL])),
+                name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![realm])),
+            }),
+            e_text: Optional::from(None),
+            e_data: Optional::from(Some(ExplicitContextTag12::from(OctetStringAsn1::from(
+                picky_asn1_der::to_vec(&typed_data).unwrap(),
+            )))),
+        })
+    }
+
+    #[test]
+    fn auth_pack_checksums() {
+        let kdc_req_body = generate_as_req_kdc_body(&GenerateAsReqOptions {
+            realm: "EXAMPLE.COM",
+            username: "user",
+            cname_type: NT_PRINCIPAL,
+            snames: &["krbtgt", "EXAMPLE.COM"],
+            nonce: &[1, 2, 3, 4],
+            hostname: "client",
+            context_requirements: ClientRequestFlags::empty(),
+            etypes: &DEFAULT_ETYPES,
+        })
+        .unwrap();
+        let dh_parameters =
+            generate_client_key_agreement_parameters(KeyAgreement::Ecdh(EcdhCurve::P256), &mut OsRng).unwrap();
+
+        let auth_pack = generate_auth_pack(&kdc_req_body, &dh_parameters, PkInitDigest::Sha256, &[1, 2, 3, 4]).unwrap();
+        let auth_pack: AuthPack = picky_asn1_der::from_bytes(&picky_asn1_der::to_vec(&auth_pack).unwrap()).unwrap();
+
+        let encoded_kdc_req_body = picky_asn1_der::to_vec(&kdc_req_body).unwrap();
+        let pk_authenticator = &auth_pack.pk_authenticator.0;
+        assert_eq!(
+            pk_authenticator.pa_checksum.0.as_ref().unwrap().0 .0,
+            PkInitDigest::Sha1.digest(&encoded_kdc_req_body)
+        );
+        let pa_checksum2 = &pk_authenticator.pa_checksum2.0.as_ref().unwrap().0;
+        assert_eq!(
+            PkInitDigest::from_oid(pa_checksum2.algorithm_identifier.0.oid()),
+            Some(PkInitDigest::Sha256)
+        );
+        assert_eq!(
+            pa_checksum2.checksum.0 .0,
+            PkInitDigest::Sha256.digest(&encoded_kdc_req_body)
+        );
+
+        let client_public_value = &auth_pack.client_public_value.0.as_ref().unwrap().0;
+        assert_eq!(
+            KeyAgreement::Ecdh(EcdhCurve::P256)
+                .decode_public_key(&client_public_value.subject_public_key.0)
+                .unwrap(),
+            dh_parameters.public_key().unwrap()
+        );
+    }
+
+    #[test]
+    fn ecdh_key_agreement() {
+        for curve in [EcdhCurve::P256, EcdhCurve::P384] {
+            let mut client = generate_client_key_agreement_parameters(KeyAgreement::Ecdh(curve), &mut OsRng).unwrap();
+            let mut server = generate_client_key_agreement_parameters(KeyAgreement::Ecdh(curve), &mut OsRng).unwrap();
+
+            client.other_public_key = Some(server.public_key().unwrap());
+            server.other_public_key = Some(client.public_key().unwrap());
+            client.server_nonce = Some([2; 32]);
+            server.client_nonce = client.client_nonce;
+            server.server_nonce = client.server_nonce;
+
+            assert_eq!(
+                client.generate_key(&Aes256CtsHmacSha196::new()).unwrap(),
+                server.generate_key(&Aes256CtsHmacSha196::new()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn negotiate_digest_algorithm() {
+        let krb_error = krb_error(
+            KDC_ERR_DIGEST_IN_SIGNED_DATA_NOT_ACCEPTED,
+            TD_CMS_DIGEST_ALGORITHMS,
+            vec![RawAlgorithmIdentifier::from_parts(oids::sha256(), None)],
+        );
+        let mut digest = PkInitDigest::Sha1;
+        let mut dh_parameters = generate_client_key_agreement_parameters(KeyAgreement::Dh, &mut OsRng).unwrap();
+
+        assert!(negotiate_algorithms(&krb_error, &mut digest, &mut dh_parameters).unwrap());
+        assert_eq!(digest, PkInitDigest::Sha256);
+        // the KDC asks for the digest the request already uses
+        assert!(!negotiate_algorithms(&krb_error, &mut digest, &mut dh_parameters).unwrap());
+    }
+
+    #[test]
+    fn negotiate_key_agreement() {
+        let kdc_parameters =
+            generate_client_key_agreement_parameters(KeyAgreement::Ecdh(EcdhCurve::P384), &mut OsRng).unwrap();
+        let krb_error = krb_error(
+            KDC_ERR_DH_KEY_PARAMETERS_NOT_ACCEPTED,
+            TD_DH_PARAMETERS,
+            vec![kdc_parameters.algorithm().unwrap()],
+        );
+        let mut digest = PkInitDigest::Sha1;
+        let mut dh_parameters = generate_client_key_agreement_parameters(KeyAgreement::Dh, &mut OsRng).unwrap();
+        let client_nonce = dh_parameters.client_nonce;
+
+        assert!(negotiate_algorithms(&krb_error, &mut digest, &mut dh_parameters).unwrap());
+        assert_eq!(dh_parameters.key_agreement, KeyAgreement::Ecdh(EcdhCurve::P384));
+        assert_eq!(dh_parameters.client_nonce, client_nonce);
+        assert!(dh_parameters.public_key().is_ok());
+    }
 
     #[test]
     fn signing() {
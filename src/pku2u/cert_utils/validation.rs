@@ -1,14 +1,15 @@
 use num_bigint_dig::BigUint;
-use picky::key::PublicKey as RsaPublicKey;
+use picky::key::PublicKey;
 use picky_asn1_x509::signed_data::{CertificateChoices, SignedData};
-use picky_asn1_x509::{Certificate, PublicKey};
+use picky_asn1_x509::{oids, Certificate, ExtensionView, PublicKey as SubjectPublicKey};
 
+use crate::cert_chain::{build_certificate_chain, find_signer_certificate, signed_data_certificates, ChainErrorKinds};
 use crate::{Error, ErrorKind, Result};
 
 /// validates server's p2p certificate.
 /// If certificate is valid then return its public key.
 #[instrument(level = "trace", ret)]
-pub fn validate_server_p2p_certificate(signed_data: &SignedData) -> Result<RsaPublicKey> {
+pub fn validate_server_p2p_certificate(signed_data: &SignedData) -> Result<PublicKey> {
     let certificates = &signed_data.certificates.0 .0;
 
     if let Some(certificate) = certificates.iter().next() {
@@ -24,30 +25,99 @@ pub fn validate_server_p2p_certificate(signed_data: &SignedData) -> Result<RsaPu
             }
         };
 
-        let public_key = match cert.tbs_certificate.subject_public_key_info.subject_public_key {
-            PublicKey::Rsa(rsa) => rsa,
-            public_key => {
-                error!(
-                    ?public_key,
-                    "Server sent unsupported public key type. Only RSA keys supported",
-                );
+        return extract_public_key(&cert);
+    }
+
+    Err(Error::new(
+        ErrorKind::Pku2uCertFailure,
+        "Received invalid server certificates",
+    ))
+}
+
+/// Extracts the certificate public key. Only RSA and EC keys are supported.
+fn extract_public_key(cert: &Certificate) -> Result<PublicKey> {
+    let subject_public_key_info = &cert.tbs_certificate.subject_public_key_info;
+
+    match &subject_public_key_info.subject_public_key {
+        SubjectPublicKey::Rsa(rsa) => Ok(PublicKey::from_rsa_components(
+            &BigUint::from_bytes_be(&rsa.0.modulus.0),
+            &BigUint::from_bytes_be(&rsa.0.public_exponent.0),
+        )),
+        SubjectPublicKey::Ec(_) => Ok(PublicKey::from(subject_public_key_info.clone())),
+        public_key => {
+            error!(
+                ?public_key,
+                "Unsupported public key type. Only RSA and EC keys supported"
+            );
+
+            Err(Error::new(
+                ErrorKind::Pku2uCertFailure,
+                "Received certificate has unsupported public key type. Only RSA and EC are supported",
+            ))
+        }
+    }
+}
 
+/// Validates the client's p2p certificate chain.
+///
+/// The client's certificate is the one that signed the `signed_data`. PKU2U peers are joined to the same
+/// Azure AD tenant, so the chain must lead to one of the trusted P2P certification authorities configured
+/// on the acceptor. Intermediate certificates must be present in the signed data. The signature of every
+/// certificate in the chain is verified, including the one issued by the trusted certification authority.
+/// If the chain is valid then returns the client's certificate and its public key.
+#[instrument(level = "trace", ret, skip(trusted_cas))]
+pub fn validate_client_p2p_certificate(
+    signed_data: &SignedData,
+    trusted_cas: &[Certificate],
+) -> Result<(Certificate, PublicKey)> {
+    let certificates = signed_data_certificates(signed_data, ErrorKind::Pku2uCertFailure)?;
+    let client_certificate = find_signer_certificate(signed_data, &certificates, ErrorKind::Pku2uCertFailure)?;
+
+    validate_client_certificate_usage(client_certificate)?;
+
+    build_certificate_chain(
+        client_certificate,
+        &certificates.iter().collect::<Vec<_>>(),
+        trusted_cas,
+        ChainErrorKinds {
+            untrusted: ErrorKind::Pku2uCertFailure,
+            untrusted_root: ErrorKind::Pku2uCertFailure,
+            expired: ErrorKind::Pku2uCertFailure,
+        },
+        |_, _| Ok(()),
+    )?;
+
+    let public_key = extract_public_key(client_certificate)?;
+
+    Ok((client_certificate.clone(), public_key))
+}
+
+/// Checks that the certificate can be used for the client authentication:
+/// the key usage (if present) must allow digital signatures and the extended key usage must contain ClientAuth.
+fn validate_client_certificate_usage(certificate: &Certificate) -> Result<()> {
+    let mut client_auth = false;
+
+    for extension in certificate.extensions() {
+        match extension.extn_value() {
+            ExtensionView::KeyUsage(key_usage) if !key_usage.digital_signature() => {
                 return Err(Error::new(
                     ErrorKind::Pku2uCertFailure,
-                    "Received certificate has unsupported public key type. Only RSA is supported",
+                    "Client certificate key usage does not allow digital signatures",
                 ));
             }
+            ExtensionView::ExtendedKeyUsage(ext_key_usage) => {
+                client_auth |= ext_key_usage.contains(oids::kp_client_auth());
+            }
+            _ => {}
         }
-        .0;
+    }
 
-        return Ok(RsaPublicKey::from_rsa_components(
-            &BigUint::from_bytes_be(&public_key.modulus.0),
-            &BigUint::from_bytes_be(&public_key.public_exponent.0),
+    if !client_auth {
+        return Err(Error::new(
+            ErrorKind::Pku2uCertFailure,
+            "Client certificate does not have the ClientAuth extended key usage",
         ));
     }
 
-    Err(Error::new(
-        ErrorKind::Pku2uCertFailure,
-        "Received invalid server certificates",
-    ))
+    Ok(())
 }
@@ -2,6 +2,7 @@ use picky::key::PrivateKey;
 use picky_asn1_x509::Certificate;
 
 use crate::negotiate::{NegotiatedProtocol, ProtocolConfig};
+use crate::pk_init::PkInitAlgorithms;
 use crate::secret::SecretPrivateKey;
 use crate::{Pku2u, Result};
 
@@ -10,6 +11,15 @@ pub struct Pku2uConfig {
     pub p2p_certificate: Certificate,
     pub private_key: SecretPrivateKey,
     pub client_hostname: String,
+    /// PKINIT algorithms of the client's AS-REQ
+    ///
+    /// The acceptor replies with the key agreement of the client and the digest the client prefers.
+    pub pkinit: PkInitAlgorithms,
+    /// Trusted P2P certification authorities
+    ///
+    /// The acceptor accepts only the client certificates issued by one of them: the MS-Organization-P2P-Access
+    /// certification authority of the Azure AD tenant. The client certificates are rejected if the list is empty.
+    pub p2p_ca_certificates: Vec<Certificate>,
 }
 
 impl Pku2uConfig {
@@ -18,6 +28,8 @@ impl Pku2uConfig {
             p2p_certificate,
             private_key: private_key.into(),
             client_hostname,
+            pkinit: PkInitAlgorithms::default(),
+            p2p_ca_certificates: Vec::new(),
         }
     }
 
@@ -31,6 +43,8 @@ impl Pku2uConfig {
             p2p_certificate,
             private_key: private_key.into(),
             client_hostname,
+            pkinit: PkInitAlgorithms::default(),
+            p2p_ca_certificates: Vec::new(),
         })
     }
 }
@@ -42,6 +56,12 @@ impl ProtocolConfig for Pku2uConfig {
         ))?))
     }
 
+    fn new_server(&self) -> Result<NegotiatedProtocol> {
+        Ok(NegotiatedProtocol::Pku2u(Pku2u::new_server_from_config(Clone::clone(
+            self,
+        ))?))
+    }
+
     fn box_clone(&self) -> Box<dyn ProtocolConfig> {
         Box::new(Clone::clone(self))
     }
@@ -1,17 +1,35 @@
 use std::convert::TryInto;
 
+use picky_asn1::wrapper::{ExplicitContextTag0, ObjectIdentifierAsn1};
 use picky_asn1_der::application_tag::ApplicationTag;
 use picky_asn1_der::Asn1RawDer;
+use picky_asn1_x509::content_info::ContentValue;
+use picky_asn1_x509::oids;
+use picky_asn1_x509::signed_data::SignedData;
 use picky_krb::constants::key_usages::AS_REP_ENC;
-use picky_krb::constants::types::PA_PK_AS_REP;
-use picky_krb::messages::{AsRep, EncAsRepPart};
-use picky_krb::pkinit::{DhRepInfo, PaPkAsRep};
+use picky_krb::constants::types::{PA_PK_AS_REP, PA_PK_AS_REQ};
+use picky_krb::data_types::Authenticator;
+use picky_krb::gss_api::{NegTokenInit, NegTokenTarg1};
+use picky_krb::messages::{AsRep, AsReq, EncAsRepPart, EncKdcRepPart};
+use picky_krb::pkinit::{DhRepInfo, KrbFinished, PaPkAsRep, PaPkAsReq};
 use serde::Deserialize;
 
+use super::generators::GSS_EXTS_FINISHED;
+use crate::kerberos::server::extractors::extract_authenticator_checksum;
 use crate::kerberos::{EncryptionParams, DEFAULT_ENCRYPTION_TYPE};
-use crate::pk_init::DH_NONCE_LEN;
+use crate::pk_init::{AuthPack, DhParameters, Wrapper, DH_NONCE_LEN};
 use crate::{Error, ErrorKind, Result};
 
+/// [Mechanism-Independent Token Format](https://datatracker.ietf.org/doc/html/rfc2743#section-3.1):
+/// the initial context token starts with the `[APPLICATION 0]` tag.
+const GSS_API_INITIAL_CONTEXT_TOKEN_TAG: u8 = 0x60;
+/// [NegTokenTarg](https://datatracker.ietf.org/doc/html/rfc2478#section-3.2.1) is wrapped in the `[1]` context tag.
+const NEG_TOKEN_TARG_TAG: u8 = 0xa1;
+
+/// [Authenticator Checksum](https://datatracker.ietf.org/doc/html/rfc4121#section-4.1.1)
+/// Extensions follow the 24-byte checksum value without the delegation info.
+const AUTHENTICATOR_CHECKSUM_LEN: usize = 24;
+
 pub fn extract_krb_rep<'a, T: Deserialize<'a>>(mut data: &'a [u8]) -> Result<(T, &'a [u8])> {
     let _oid: ApplicationTag<Asn1RawDer, 0> = picky_asn1_der::from_reader(&mut data)?;
 
@@ -65,7 +83,7 @@ pub fn extract_server_nonce(dh_rep_info: &DhRepInfo) -> Result<[u8; DH_NONCE_LEN
 }
 
 #[instrument(level = "trace", ret)]
-pub fn extract_session_key_from_as_rep(as_rep: &AsRep, key: &[u8], enc_params: &EncryptionParams) -> Result<Vec<u8>> {
+pub fn extract_enc_as_rep_part(as_rep: &AsRep, key: &[u8], enc_params: &EncryptionParams) -> Result<EncKdcRepPart> {
     let cipher = enc_params
         .encryption_type
         .as_ref()
@@ -77,5 +95,209 @@ pub fn extract_session_key_from_as_rep(as_rep: &AsRep, key: &[u8], enc_params: &
 
     let enc_as_rep_part: EncAsRepPart = picky_asn1_der::from_bytes(&enc_data)?;
 
-    Ok(enc_as_rep_part.0.key.0.key_value.0.to_vec())
+    Ok(enc_as_rep_part.0)
+}
+
+#[instrument(level = "trace", ret)]
+pub fn extract_session_key_from_as_rep(as_rep: &AsRep, key: &[u8], enc_params: &EncryptionParams) -> Result<Vec<u8>> {
+    let enc_as_rep_part = extract_enc_as_rep_part(as_rep, key, enc_params)?;
+
+    Ok(enc_as_rep_part.key.0.key_value.0.to_vec())
+}
+
+/// Extracts NEGOEX messages from the initiator's token.
+///
+/// Our client wraps NEGOEX messages in SPNEGO tokens (`NegTokenInit` for the first message and
+/// `NegTokenTarg` for subsequent ones), but the SPNEGO layer can also be handled by the Negotiate package.
+/// Returns the NEGOEX messages and `true` if they were wrapped in the SPNEGO token.
+#[instrument(level = "trace", ret)]
+pub fn extract_negoex_messages(mut data: &[u8]) -> Result<(Vec<u8>, bool)> {
+    match data.first() {
+        Some(&GSS_API_INITIAL_CONTEXT_TOKEN_TAG) => {
+            let _oid: ApplicationTag<ObjectIdentifierAsn1, 0> = picky_asn1_der::from_reader(&mut data)?;
+            let neg_token_init: ExplicitContextTag0<NegTokenInit> = picky_asn1_der::from_reader(&mut data)?;
+
+            let mech_token = neg_token_init
+                .0
+                .mech_token
+                .0
+                .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Missing mech_token in NegTokenInit"))?
+                .0
+                 .0;
+
+            Ok((mech_token, true))
+        }
+        Some(&NEG_TOKEN_TARG_TAG) => {
+            let neg_token_targ: NegTokenTarg1 = picky_asn1_der::from_bytes(data)?;
+
+            let response_token = neg_token_targ
+                .0
+                .response_token
+                .0
+                .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Missing response_token in NegTokenTarg"))?
+                .0
+                 .0;
+
+            Ok((response_token, true))
+        }
+        _ => Ok((data.to_vec(), false)),
+    }
+}
+
+#[instrument(level = "trace", ret)]
+pub fn extract_pa_pk_as_req(as_req: &AsReq) -> Result<PaPkAsReq> {
+    Ok(picky_asn1_der::from_bytes(
+        &as_req
+            .0
+            .padata
+            .0
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "pa-datas is not present in as-req"))?
+            .0
+            .iter()
+            .find(|pa_data| pa_data.padata_type.0 .0 == PA_PK_AS_REQ)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidToken,
+                    "PA_PK_AS_REQ is not present in pa-datas of the as-req",
+                )
+            })?
+            .padata_data
+            .0
+             .0,
+    )?)
+}
+
+/// Extracts the signed data from the `signedAuthPack` field.
+#[instrument(level = "trace", ret)]
+pub fn extract_signed_auth_pack(pa_pk_as_req: &PaPkAsReq) -> Result<SignedData> {
+    let wrapped_signed_data: Wrapper<SignedData> = picky_asn1_der::from_bytes(&pa_pk_as_req.signed_auth_pack.0 .0)?;
+
+    if wrapped_signed_data.content_info.0 != oids::signed_data() {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!(
+                "Invalid signed auth pack content type: {:?}. Expected: {:?}",
+                wrapped_signed_data.content_info.0,
+                oids::signed_data()
+            ),
+        ));
+    }
+
+    Ok(wrapped_signed_data.content.0)
+}
+
+/// Extracts the encoded `AuthPack` from the signed data.
+#[instrument(level = "trace", ret)]
+pub fn extract_auth_pack_data(signed_data: &SignedData) -> Result<&[u8]> {
+    if signed_data.content_info.content_type.0 != oids::pkinit_auth_data() {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!(
+                "Invalid content info identifier: {:?}. Expected: {:?}",
+                signed_data.content_info.content_type.0,
+                oids::pkinit_auth_data()
+            ),
+        ));
+    }
+
+    match &signed_data
+        .content_info
+        .content
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "content info is not present"))?
+        .0
+    {
+        ContentValue::OctetString(data) | ContentValue::Data(data) => Ok(&data.0),
+        content_value => {
+            error!(?content_value, "The client has sent AuthPack in unsupported format");
+
+            Err(Error::new(ErrorKind::InvalidToken, "unexpected content info"))
+        }
+    }
+}
+
+/// Extracts the client's key agreement parameters, public key, and nonce from the `AuthPack`.
+///
+/// Both the DH and ECDH ([RFC 5349](https://www.rfc-editor.org/rfc/rfc5349)) client public values are supported.
+/// The acceptor's private key is not generated here: the returned `private_key` is empty.
+#[instrument(level = "trace", ret)]
+pub fn extract_client_dh_parameters(auth_pack: &AuthPack) -> Result<DhParameters> {
+    let client_public_value = &auth_pack
+        .client_public_value
+        .0
+        .as_ref()
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::OperationNotSupported,
+                "clientPublicValue is not present: only the DH key delivery method is supported",
+            )
+        })?
+        .0;
+
+    let mut dh_parameters = DhParameters::from_algorithm(&client_public_value.algorithm)?;
+    let public_key = dh_parameters
+        .key_agreement
+        .decode_public_key(&client_public_value.subject_public_key.0)?;
+
+    let client_nonce = auth_pack
+        .client_dh_nonce
+        .0
+        .as_ref()
+        .map(|nonce| nonce.0 .0.clone())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "DH client nonce is not present"))?;
+
+    if client_nonce.len() != DH_NONCE_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            format!(
+                "invalid client dh nonce length: {}. Expected: {}",
+                client_nonce.len(),
+                DH_NONCE_LEN
+            ),
+        ));
+    }
+
+    dh_parameters.other_public_key = Some(public_key);
+    dh_parameters.client_nonce = Some(client_nonce.try_into().unwrap());
+
+    Ok(dh_parameters)
+}
+
+/// Extracts the `KRB-FINISHED` message from the authenticator checksum extensions.
+///
+/// [The GSS-API Binding for PKU2U](https://datatracker.ietf.org/doc/html/draft-zhu-pku2u-04#section-6):
+/// the initiator MUST include the `GSS_EXTS_FINISHED` extension into the authenticator checksum.
+#[instrument(level = "trace", ret)]
+pub fn extract_krb_finished(authenticator: &Authenticator) -> Result<KrbFinished> {
+    if extract_authenticator_checksum(authenticator)?.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            "Authenticator checksum is not present",
+        ));
+    }
+
+    // the checksum presence and length are checked by the `extract_authenticator_checksum` function
+    let checksum_value = &authenticator.0.cksum.0.as_ref().unwrap().0.checksum.0 .0;
+    let mut extensions = &checksum_value[AUTHENTICATOR_CHECKSUM_LEN..];
+
+    while extensions.len() >= 8 {
+        let extension_type = u32::from_be_bytes(extensions[0..4].try_into().unwrap());
+        let extension_len = u32::from_be_bytes(extensions[4..8].try_into().unwrap()) as usize;
+
+        let extension_value = extensions
+            .get(8..8 + extension_len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Authenticator checksum extension is too short"))?;
+
+        if extension_type == GSS_EXTS_FINISHED {
+            return Ok(picky_asn1_der::from_bytes(extension_value)?);
+        }
+
+        extensions = &extensions[8 + extension_len..];
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidToken,
+        "KRB-FINISHED is not present in the authenticator checksum",
+    ))
 }
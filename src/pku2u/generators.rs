@@ -1,41 +1,55 @@
 use std::fmt::Debug;
 use std::str::FromStr;
 
+use picky_asn1::bit_string::BitString;
 use picky_asn1::date::GeneralizedTime;
 use picky_asn1::restricted_string::IA5String;
 use picky_asn1::wrapper::{
-    Asn1SequenceOf, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag2, ExplicitContextTag3,
-    ExplicitContextTag4, ExplicitContextTag5, ExplicitContextTag6, ExplicitContextTag7, ExplicitContextTag8,
-    ImplicitContextTag0, IntegerAsn1, ObjectIdentifierAsn1, OctetStringAsn1, Optional,
+    Asn1SequenceOf, Asn1SetOf, BitStringAsn1, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag10,
+    ExplicitContextTag2, ExplicitContextTag3, ExplicitContextTag4, ExplicitContextTag5, ExplicitContextTag6,
+    ExplicitContextTag7, ExplicitContextTag8, ExplicitContextTag9, ImplicitContextTag0, IntegerAsn1,
+    ObjectIdentifierAsn1, OctetStringAsn1, Optional,
 };
 use picky_asn1_der::application_tag::ApplicationTag;
 use picky_asn1_der::Asn1RawDer;
+use picky_asn1_x509::cmsversion::CmsVersion;
+use picky_asn1_x509::content_info::EncapsulatedContentInfo;
+use picky_asn1_x509::signed_data::{
+    CertificateChoices, CertificateSet, DigestAlgorithmIdentifiers, SignedData, SignersInfos,
+};
 use picky_asn1_x509::{oids, AttributeTypeAndValueParameters, Certificate};
 use picky_krb::constants::gss_api::{ACCEPT_INCOMPLETE, AUTHENTICATOR_CHECKSUM_TYPE};
-use picky_krb::constants::key_usages::KEY_USAGE_FINISHED;
-use picky_krb::constants::types::NT_SRV_INST;
+use picky_krb::constants::key_usages::{AS_REP_ENC, KEY_USAGE_FINISHED, TICKET_REP};
+use picky_krb::constants::types::{AS_REP_MSG_TYPE, NT_SRV_INST, PA_PK_AS_REP};
 use picky_krb::crypto::diffie_hellman::generate_private_key;
 use picky_krb::crypto::ChecksumSuite;
 use picky_krb::data_types::{
-    Authenticator, AuthenticatorInner, AuthorizationData, AuthorizationDataInner, Checksum, EncryptionKey,
-    KerbAdRestrictionEntry, KerberosStringAsn1, KerberosTime, LsapTokenInfoIntegrity, PrincipalName, Realm,
+    Authenticator, AuthenticatorInner, AuthorizationData, AuthorizationDataInner, Checksum, EncryptedData,
+    EncryptionKey, KerbAdRestrictionEntry, KerberosFlags, KerberosStringAsn1, KerberosTime, LastReq, LastReqInner,
+    LsapTokenInfoIntegrity, PaData, PrincipalName, Realm, Ticket, TicketInner,
 };
 use picky_krb::gss_api::{
     ApplicationTag0, GssApiNegInit, KrbMessage, MechType, MechTypeList, NegTokenInit, NegTokenTarg,
 };
+use picky_krb::messages::{AsRep, EncAsRepPart, EncKdcRepPart, KdcRep, KdcReqBody};
 use picky_krb::negoex::RANDOM_ARRAY_SIZE;
-use picky_krb::pkinit::{KrbFinished, Pku2uNegoBody, Pku2uNegoReq, Pku2uNegoReqMetadata};
+use picky_krb::pkinit::{
+    DhRepInfo, KdcDhKeyInfo, KrbFinished, PaPkAsRep, Pku2uNegoBody, Pku2uNegoRep, Pku2uNegoReq, Pku2uNegoReqMetadata,
+};
 use rand::rngs::OsRng;
 use rand::Rng;
-use time::OffsetDateTime;
+use time::{Duration, OffsetDateTime};
 
 use super::Pku2uConfig;
 use crate::crypto::compute_md5_channel_bindings_hash;
 use crate::kerberos::client::generators::{
     AuthenticatorChecksumExtension, ChecksumOptions, EncKey, GenerateAuthenticatorOptions, MAX_MICROSECONDS_IN_SECOND,
 };
-use crate::pk_init::DhParameters;
-use crate::{Error, ErrorKind, Result, KERBEROS_VERSION};
+use crate::kerberos::data_types::{EncTicketPart, EncTicketPartInner, TransitedEncoding};
+use crate::kerberos::flags::TicketFlags;
+use crate::kerberos::server::ServiceKey;
+use crate::pk_init::{generate_signer_info, DhParameters, KeyAgreement, PkInitDigest};
+use crate::{check_if_empty, Error, ErrorKind, Result, KERBEROS_VERSION};
 
 /// [The PKU2U Realm Name](https://datatracker.ietf.org/doc/html/draft-zhu-pku2u-09#section-3)
 /// The PKU2U realm name is defined as a reserved Kerberos realm name, and it has the value of "WELLKNOWN:PKU2U".
@@ -44,7 +58,14 @@ pub const WELLKNOWN_REALM: &str = "WELLKNOWN:PKU2U";
 /// [The GSS-API Binding for PKU2U](https://datatracker.ietf.org/doc/html/draft-zhu-pku2u-04#section-6)
 /// The type for the checksum extension.
 /// GSS_EXTS_FINISHED 2
-const GSS_EXTS_FINISHED: u32 = 2;
+pub(crate) const GSS_EXTS_FINISHED: u32 = 2;
+
+/// [RFC 4120 5.3](https://www.rfc-editor.org/rfc/rfc4120#section-5.3)
+/// The transited field of the ticket issued by the acceptor to itself is empty: DOMAIN-X500-COMPRESS encoding.
+const DOMAIN_X500_COMPRESS: u8 = 1;
+
+/// Lifetime of the ticket issued by the acceptor. It matches the default maximum ticket lifetime in Windows.
+const TICKET_LIFETIME: Duration = Duration::hours(10);
 
 /// [2.2.5 LSAP_TOKEN_INFO_INTEGRITY](https://winprotocoldoc.blob.core.windows.net/productionwindowsarchives/MS-KILE/%5bMS-KILE%5d.pdf)
 /// indicating the token information type
@@ -147,6 +168,7 @@ pub fn get_default_parameters() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
 
 pub fn generate_server_dh_parameters(rng: &mut OsRng) -> Result<DhParameters> {
     Ok(DhParameters {
+        key_agreement: KeyAgreement::Dh,
         base: Vec::new(),
         modulus: Vec::new(),
         q: Vec::new(),
@@ -163,6 +185,7 @@ pub fn generate_client_dh_parameters(rng: &mut OsRng) -> Result<DhParameters> {
     let private_key = generate_private_key(&q, rng);
 
     Ok(DhParameters {
+        key_agreement: KeyAgreement::Dh,
         base: g,
         modulus: p,
         q,
@@ -173,6 +196,27 @@ pub fn generate_client_dh_parameters(rng: &mut OsRng) -> Result<DhParameters> {
     })
 }
 
+/// Generates the client parameters of the given key agreement with a fresh private key and client nonce.
+pub fn generate_client_key_agreement_parameters(key_agreement: KeyAgreement, rng: &mut OsRng) -> Result<DhParameters> {
+    if key_agreement == KeyAgreement::Dh {
+        return generate_client_dh_parameters(rng);
+    }
+
+    let mut dh_parameters = DhParameters {
+        key_agreement,
+        base: Vec::new(),
+        modulus: Vec::new(),
+        q: Vec::new(),
+        private_key: Vec::new(),
+        other_public_key: None,
+        client_nonce: Some(rng.gen::<[u8; RANDOM_ARRAY_SIZE]>()),
+        server_nonce: None,
+    };
+    dh_parameters.generate_private_key(rng);
+
+    Ok(dh_parameters)
+}
+
 pub fn generate_neg<T: Debug + PartialEq + Clone>(
     krb_msg: T,
     krb5_token_id: [u8; 2],
@@ -207,7 +251,8 @@ pub fn generate_authenticator_extension(key: &[u8], payload: &[u8]) -> Result<Au
 #[instrument(level = "trace", ret)]
 pub fn generate_authenticator(options: GenerateAuthenticatorOptions) -> Result<Authenticator> {
     let GenerateAuthenticatorOptions {
-        kdc_rep,
+        crealm,
+        cname,
         seq_num,
         sub_key,
         checksum,
@@ -287,8 +332,8 @@ pub fn generate_authenticator(options: GenerateAuthenticatorOptions) -> Result<A
 
     Ok(Authenticator::from(AuthenticatorInner {
         authenticator_bno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
-        crealm: ExplicitContextTag1::from(kdc_rep.crealm.0.clone()),
-        cname: ExplicitContextTag2::from(kdc_rep.cname.0.clone()),
+        crealm: ExplicitContextTag1::from(crealm.clone()),
+        cname: ExplicitContextTag2::from(cname.clone()),
         cksum,
         cusec: ExplicitContextTag4::from(IntegerAsn1::from(microseconds.to_be_bytes().to_vec())),
         ctime: ExplicitContextTag5::from(KerberosTime::from(GeneralizedTime::from(current_date))),
@@ -350,3 +395,200 @@ pub fn generate_as_req_username_from_certificate(certificate: &Certificate) -> R
 
     Ok(username)
 }
+
+/// [PKU2U Realm Name](https://datatracker.ietf.org/doc/html/draft-zhu-pku2u-09#section-3)
+/// The acceptor sends the issuer of its p2p certificate back in the NEGOEX metadata.
+pub fn generate_pku2u_nego_rep(config: &Pku2uConfig) -> Result<Pku2uNegoRep> {
+    Ok(Pku2uNegoRep {
+        metadata: ExplicitContextTag0::from(Asn1SequenceOf::from(vec![Pku2uNegoReqMetadata {
+            inner: ImplicitContextTag0::from(OctetStringAsn1::from(picky_asn1_der::to_vec(
+                &config.p2p_certificate.tbs_certificate.issuer,
+            )?)),
+        }])),
+    })
+}
+
+/// [Generation of KDC Reply](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.3)
+/// The acceptor acts as the KDC: it signs its DH or ECDH public value using the p2p certificate private key.
+#[instrument(level = "trace", ret, skip(config))]
+pub fn generate_pa_pk_as_rep(
+    config: &Pku2uConfig,
+    dh_parameters: &DhParameters,
+    authenticator_nonce: IntegerAsn1,
+    digest: PkInitDigest,
+) -> Result<PaData> {
+    let kdc_dh_key_info = KdcDhKeyInfo {
+        subject_public_key: ExplicitContextTag0::from(BitStringAsn1::from(
+            dh_parameters
+                .key_agreement
+                .encode_public_key(&dh_parameters.public_key()?)?,
+        )),
+        nonce: ExplicitContextTag1::from(authenticator_nonce),
+        dh_key_expiration: Optional::from(None),
+    };
+    let encoded_kdc_dh_key_info = picky_asn1_der::to_vec(&kdc_dh_key_info)?;
+
+    let digest = digest.for_certificate(&config.p2p_certificate);
+
+    let private_key = config.private_key.clone();
+    let signed_data = SignedData {
+        version: CmsVersion::V3,
+        digest_algorithms: DigestAlgorithmIdentifiers(Asn1SetOf::from(vec![digest.algorithm_identifier()])),
+        content_info: EncapsulatedContentInfo::new(oids::kpinit_dh_key_data(), Some(encoded_kdc_dh_key_info.clone())),
+        certificates: Optional::from(CertificateSet(vec![CertificateChoices::Certificate(Asn1RawDer(
+            picky_asn1_der::to_vec(&config.p2p_certificate)?,
+        ))])),
+        crls: None,
+        signers_infos: SignersInfos(Asn1SetOf::from(vec![generate_signer_info(
+            &config.p2p_certificate,
+            oids::kpinit_dh_key_data(),
+            &encoded_kdc_dh_key_info,
+            digest,
+            &move |data_to_sign, signature_algorithm| {
+                signature_algorithm
+                    .sign(data_to_sign, private_key.as_ref())
+                    .map_err(|err| {
+                        Error::new(
+                            ErrorKind::InternalError,
+                            format!("Cannot calculate signer info signature: {:?}", err),
+                        )
+                    })
+            },
+        )?])),
+    };
+
+    let pa_pk_as_rep = PaPkAsRep::DhInfo(ExplicitContextTag0::from(DhRepInfo {
+        dh_signed_data: ImplicitContextTag0::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&signed_data)?)),
+        server_dh_nonce: Optional::from(Some(ExplicitContextTag1::from(OctetStringAsn1::from(
+            check_if_empty!(dh_parameters.server_nonce.as_ref(), "dh server nonce is not set").to_vec(),
+        )))),
+    }));
+
+    Ok(PaData {
+        padata_type: ExplicitContextTag1::from(IntegerAsn1::from(PA_PK_AS_REP.to_vec())),
+        padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(picky_asn1_der::to_vec(&pa_pk_as_rep)?)),
+    })
+}
+
+#[derive(Debug)]
+pub struct GenerateAsRepOptions<'a> {
+    pub kdc_req_body: &'a KdcReqBody,
+    pub pa_datas: Vec<PaData>,
+    pub session_key: &'a EncKey,
+    /// Key derived from the DH shared secret. It encrypts the AS-REP encrypted part.
+    pub reply_key: &'a [u8],
+    /// Key the ticket is encrypted with. Only the acceptor knows it.
+    pub service_key: &'a ServiceKey,
+}
+
+/// [The PKU2U Realm Name](https://datatracker.ietf.org/doc/html/draft-zhu-pku2u-09#section-3)
+/// The acceptor issues the ticket to itself: the ticket is for the service principal name requested
+/// by the client in the `WELLKNOWN:PKU2U` realm.
+#[instrument(level = "trace", ret, skip(options))]
+pub fn generate_as_rep(options: GenerateAsRepOptions<'_>) -> Result<AsRep> {
+    let GenerateAsRepOptions {
+        kdc_req_body,
+        pa_datas,
+        session_key,
+        reply_key,
+        service_key,
+    } = options;
+
+    let cname = kdc_req_body
+        .cname
+        .0
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "cname is not present in the AS-REQ"))?
+        .0
+        .clone();
+    let sname = kdc_req_body
+        .sname
+        .0
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "sname is not present in the AS-REQ"))?
+        .0
+        .clone();
+    let realm = kdc_req_body.realm.0.clone();
+
+    let auth_time = OffsetDateTime::now_utc();
+    let end_time = auth_time + TICKET_LIFETIME;
+    let flags = KerberosFlags::from(BitString::with_bytes(
+        (TicketFlags::INITIAL | TicketFlags::PRE_AUTHENT)
+            .bits()
+            .to_be_bytes()
+            .to_vec(),
+    ));
+    let key = EncryptionKey {
+        key_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![(&session_key.key_type).into()])),
+        key_value: ExplicitContextTag1::from(OctetStringAsn1::from(session_key.key_value.clone())),
+    };
+
+    let enc_ticket_part = EncTicketPart::from(EncTicketPartInner {
+        flags: ExplicitContextTag0::from(flags.clone()),
+        key: ExplicitContextTag1::from(key.clone()),
+        crealm: ExplicitContextTag2::from(realm.clone()),
+        cname: ExplicitContextTag3::from(cname.clone()),
+        transited: ExplicitContextTag4::from(TransitedEncoding {
+            tr_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![DOMAIN_X500_COMPRESS])),
+            contents: ExplicitContextTag1::from(OctetStringAsn1::from(Vec::new())),
+        }),
+        auth_time: ExplicitContextTag5::from(KerberosTime::from(GeneralizedTime::from(auth_time))),
+        start_time: Optional::from(None),
+        end_time: ExplicitContextTag7::from(KerberosTime::from(GeneralizedTime::from(end_time))),
+        renew_till: Optional::from(None),
+        caddr: Optional::from(None),
+        authorization_data: Optional::from(None),
+    });
+    let service_cipher = service_key.encryption_type.cipher();
+    let ticket = Ticket::from(TicketInner {
+        tkt_vno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+        realm: ExplicitContextTag1::from(realm.clone()),
+        sname: ExplicitContextTag2::from(sname.clone()),
+        enc_part: ExplicitContextTag3::from(EncryptedData {
+            etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![(&service_key.encryption_type).into()])),
+            kvno: Optional::from(None),
+            cipher: ExplicitContextTag2::from(OctetStringAsn1::from(service_cipher.encrypt(
+                service_key.key.as_ref(),
+                TICKET_REP,
+                &picky_asn1_der::to_vec(&enc_ticket_part)?,
+            )?)),
+        }),
+    });
+
+    let enc_as_rep_part = EncAsRepPart::from(EncKdcRepPart {
+        key: ExplicitContextTag0::from(key),
+        last_req: ExplicitContextTag1::from(LastReq::from(vec![LastReqInner {
+            lr_type: ExplicitContextTag0::from(IntegerAsn1::from(vec![0])),
+            lr_value: ExplicitContextTag1::from(KerberosTime::from(GeneralizedTime::from(auth_time))),
+        }])),
+        nonce: ExplicitContextTag2::from(kdc_req_body.nonce.0.clone()),
+        key_expiration: Optional::from(None),
+        flags: ExplicitContextTag4::from(flags),
+        auth_time: ExplicitContextTag5::from(KerberosTime::from(GeneralizedTime::from(auth_time))),
+        start_time: Optional::from(None),
+        end_time: ExplicitContextTag7::from(KerberosTime::from(GeneralizedTime::from(end_time))),
+        renew_till: Optional::from(None),
+        srealm: ExplicitContextTag9::from(realm.clone()),
+        sname: ExplicitContextTag10::from(sname),
+        caadr: Optional::from(None),
+        encrypted_pa_data: Optional::from(None),
+    });
+
+    Ok(AsRep::from(KdcRep {
+        pvno: ExplicitContextTag0::from(IntegerAsn1::from(vec![KERBEROS_VERSION])),
+        msg_type: ExplicitContextTag1::from(IntegerAsn1::from(vec![AS_REP_MSG_TYPE])),
+        padata: Optional::from(Some(ExplicitContextTag2::from(Asn1SequenceOf::from(pa_datas)))),
+        crealm: ExplicitContextTag3::from(realm),
+        cname: ExplicitContextTag4::from(cname),
+        ticket: ExplicitContextTag5::from(ticket),
+        enc_part: ExplicitContextTag6::from(EncryptedData {
+            etype: ExplicitContextTag0::from(IntegerAsn1::from(vec![(&session_key.key_type).into()])),
+            kvno: Optional::from(None),
+            cipher: ExplicitContextTag2::from(OctetStringAsn1::from(session_key.key_type.cipher().encrypt(
+                reply_key,
+                AS_REP_ENC,
+                &picky_asn1_der::to_vec(&enc_as_rep_part)?,
+            )?)),
+        }),
+    }))
+}
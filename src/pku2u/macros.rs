@@ -4,7 +4,7 @@ macro_rules! check_conversation_id {
             return Err(Error::new(
                 ErrorKind::InvalidToken,
                 format!(
-                    "Server sent invalid conversation id. Got {:?} but expected {:?}",
+                    "Peer sent invalid conversation id. Got {:?} but expected {:?}",
                     $actual, $expected
                 ),
             ));
@@ -22,7 +22,7 @@ macro_rules! check_auth_scheme {
             return Err(Error::new(
                 ErrorKind::InvalidToken,
                 format!(
-                    "Server sent invalid conversation id. Got {:?} but expected {:?}",
+                    "Peer sent invalid auth scheme id. Got {:?} but expected {:?}",
                     $actual,
                     $expected.unwrap()
                 ),
@@ -37,7 +37,7 @@ macro_rules! check_sequence_number {
             return Err(Error::new(
                 ErrorKind::OutOfSequence,
                 format!(
-                    "Server sent invalid sequence number. Got {:?} but expected {:?}",
+                    "Peer sent invalid sequence number. Got {:?} but expected {:?}",
                     $actual, $expected
                 ),
             ));
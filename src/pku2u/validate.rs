@@ -1,12 +1,19 @@
-use picky::hash::HashAlgorithm;
-use picky::key::PublicKey as RsaPublicKey;
-use picky::signature::SignatureAlgorithm;
+use picky::key::PublicKey;
 use picky_asn1::wrapper::Asn1SetOf;
 use picky_asn1_x509::signed_data::SignedData;
+use picky_asn1_x509::{oids, AttributeValues};
+use picky_krb::constants::key_usages::KEY_USAGE_FINISHED;
+use picky_krb::crypto::ChecksumSuite;
+use picky_krb::messages::KdcReqBody;
+use picky_krb::pkinit::KrbFinished;
+use time::OffsetDateTime;
 
+use crate::kerberos::server::DEFAULT_MAX_TIME_SKEW;
+use crate::pk_init::{signer_digest, signer_signature_algorithm, PkAuthenticator, PkInitDigest};
 use crate::{Error, ErrorKind, Result};
 
-pub fn validate_signed_data(signed_data: &SignedData, rsa_public_key: &RsaPublicKey) -> Result<()> {
+/// Verifies the signature of the signed data. The signature algorithm is taken from the signer info.
+pub fn validate_signed_data(signed_data: &SignedData, public_key: &PublicKey) -> Result<()> {
     let signer_info = signed_data
         .signers_infos
         .0
@@ -17,7 +24,117 @@ pub fn validate_signed_data(signed_data: &SignedData, rsa_public_key: &RsaPublic
     let signed_attributes = Asn1SetOf::from(signer_info.signed_attrs.0 .0 .0.clone());
     let encoded_signed_attributes = picky_asn1_der::to_vec(&signed_attributes)?;
 
-    SignatureAlgorithm::RsaPkcs1v15(HashAlgorithm::SHA1)
-        .verify(rsa_public_key, &encoded_signed_attributes, &signer_info.signature.0 .0)
+    signer_signature_algorithm(signer_info)?
+        .verify(public_key, &encoded_signed_attributes, &signer_info.signature.0 .0)
         .map_err(|_| Error::new(ErrorKind::InvalidToken, "Invalid signed data signature"))
 }
+
+/// Checks that the signed `message-digest` attribute matches the signed content.
+///
+/// The signature covers only the signed attributes, so the content is bound to the signature by its digest.
+pub fn validate_signed_content_digest(signed_data: &SignedData, content: &[u8]) -> Result<()> {
+    let signer_info = signed_data
+        .signers_infos
+        .0
+         .0
+        .first()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Missing signers_infos in signed data"))?;
+
+    let message_digest = signer_info
+        .signed_attrs
+        .0
+         .0
+         .0
+        .iter()
+        .find(|attribute| attribute.ty.0 == oids::message_digest())
+        .and_then(|attribute| match &attribute.value {
+            AttributeValues::MessageDigest(digest) => digest.0.first(),
+            _ => None,
+        })
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Missing message digest in signed attributes"))?;
+
+    if message_digest.0 != signer_digest(signer_info)?.digest(content) {
+        return Err(Error::new(ErrorKind::MessageAltered, "Invalid signed content digest"));
+    }
+
+    Ok(())
+}
+
+/// [Receipt of Client Request](https://www.rfc-editor.org/rfc/rfc4556.html#section-3.2.2)
+/// The KDC checks the `paChecksum` over the KDC-REQ-BODY and the client time.
+///
+/// [PKINIT Algorithm Agility](https://www.rfc-editor.org/rfc/rfc8636#section-4): the `paChecksum2` is checked
+/// as well if it is present. At least one of the checksums must be present.
+pub fn validate_pk_authenticator(pk_authenticator: &PkAuthenticator, kdc_req_body: &KdcReqBody) -> Result<()> {
+    let encoded_kdc_req_body = picky_asn1_der::to_vec(kdc_req_body)?;
+
+    let pa_checksum = pk_authenticator.pa_checksum.0.as_ref();
+    let pa_checksum2 = pk_authenticator.pa_checksum2.0.as_ref();
+
+    if pa_checksum.is_none() && pa_checksum2.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidToken,
+            "paChecksum is not present in PKAuthenticator",
+        ));
+    }
+
+    if let Some(pa_checksum) = pa_checksum {
+        if pa_checksum.0 .0 != PkInitDigest::Sha1.digest(&encoded_kdc_req_body) {
+            return Err(Error::new(
+                ErrorKind::MessageAltered,
+                "paChecksum does not match the KDC-REQ-BODY",
+            ));
+        }
+    }
+
+    if let Some(pa_checksum2) = pa_checksum2 {
+        let algorithm = pa_checksum2.0.algorithm_identifier.0.oid();
+        let digest = PkInitDigest::from_oid(algorithm).ok_or_else(|| {
+            Error::new(
+                ErrorKind::OperationNotSupported,
+                format!("Unsupported paChecksum2 algorithm: {:?}", algorithm),
+            )
+        })?;
+
+        if pa_checksum2.0.checksum.0 .0 != digest.digest(&encoded_kdc_req_body) {
+            return Err(Error::new(
+                ErrorKind::MessageAltered,
+                "paChecksum2 does not match the KDC-REQ-BODY",
+            ));
+        }
+    }
+
+    let ctime = OffsetDateTime::try_from(pk_authenticator.ctime.0 .0.clone())
+        .map_err(|err| Error::new(ErrorKind::InvalidToken, format!("Invalid Kerberos time: {:?}", err)))?;
+    if (OffsetDateTime::now_utc() - ctime).abs() > DEFAULT_MAX_TIME_SKEW {
+        return Err(Error::new(ErrorKind::TimeSkew, "Clock skew too great"));
+    }
+
+    Ok(())
+}
+
+/// [The GSS-API Binding for PKU2U](https://datatracker.ietf.org/doc/html/draft-zhu-pku2u-04#section-6)
+/// The `gss-mic` is calculated over the preceding GSS-API messages: the AS-REQ and the AS-REP.
+pub fn validate_krb_finished(krb_finished: &KrbFinished, key: &[u8], gss_api_messages: &[u8]) -> Result<()> {
+    let gss_mic = &krb_finished.gss_mic.0;
+
+    let checksum_type = gss_mic
+        .cksumtype
+        .0
+         .0
+        .last()
+        .copied()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidToken, "Missing KRB-FINISHED checksum type"))?;
+
+    let checksum = ChecksumSuite::try_from(usize::from(checksum_type))?.hasher().checksum(
+        key,
+        KEY_USAGE_FINISHED,
+        gss_api_messages,
+    )?;
+
+    if gss_mic.checksum.0 .0 != checksum {
+        return Err(Error::new(ErrorKind::MessageAltered, "Invalid KRB-FINISHED checksum"));
+    }
+
+    Ok(())
+}
@@ -22,6 +22,8 @@ pub enum SecurityBuffer<'data> {
     Stream(&'data mut [u8]),
     Extra(&'data mut [u8]),
     Padding(&'data mut [u8]),
+    /// Read-only buffer that is included in the message signature but is not encrypted.
+    ReadOnlyWithChecksum(&'data mut [u8]),
     Missing(usize),
     Empty,
 }
@@ -38,6 +40,7 @@ impl<'data> SecurityBuffer<'data> {
             SecurityBufferType::Missing => Ok(SecurityBuffer::Missing(0)),
             SecurityBufferType::Extra => Ok(SecurityBuffer::Extra(&mut [])),
             SecurityBufferType::Padding => Ok(SecurityBuffer::Padding(&mut [])),
+            SecurityBufferType::ReadOnlyWithChecksum => Ok(SecurityBuffer::ReadOnlyWithChecksum(&mut [])),
             SecurityBufferType::StreamTrailer => Ok(SecurityBuffer::StreamTrailer(&mut [])),
             SecurityBufferType::StreamHeader => Ok(SecurityBuffer::StreamHeader(&mut [])),
             SecurityBufferType::Stream => Ok(SecurityBuffer::Stream(&mut [])),
@@ -57,6 +60,7 @@ impl<'data> SecurityBuffer<'data> {
             SecurityBuffer::Stream(_) => SecurityBuffer::Stream(data),
             SecurityBuffer::Extra(_) => SecurityBuffer::Extra(data),
             SecurityBuffer::Padding(_) => SecurityBuffer::Padding(data),
+            SecurityBuffer::ReadOnlyWithChecksum(_) => SecurityBuffer::ReadOnlyWithChecksum(data),
             SecurityBuffer::Missing(_) => {
                 return Err(Error::new(
                     ErrorKind::InternalError,
@@ -79,6 +83,7 @@ impl<'data> SecurityBuffer<'data> {
             SecurityBuffer::Stream(data) => *data = buf,
             SecurityBuffer::Extra(data) => *data = buf,
             SecurityBuffer::Padding(data) => *data = buf,
+            SecurityBuffer::ReadOnlyWithChecksum(data) => *data = buf,
             SecurityBuffer::Missing(_) => {
                 return Err(Error::new(
                     ErrorKind::InternalError,
@@ -100,6 +105,7 @@ impl<'data> SecurityBuffer<'data> {
             SecurityBuffer::Stream(_) => SecurityBufferType::Stream,
             SecurityBuffer::Extra(_) => SecurityBufferType::Extra,
             SecurityBuffer::Padding(_) => SecurityBufferType::Padding,
+            SecurityBuffer::ReadOnlyWithChecksum(_) => SecurityBufferType::ReadOnlyWithChecksum,
             SecurityBuffer::Missing(_) => SecurityBufferType::Missing,
             SecurityBuffer::Empty => SecurityBufferType::Empty,
         }
@@ -158,6 +164,7 @@ impl<'data> SecurityBuffer<'data> {
             SecurityBuffer::Stream(data) => data,
             SecurityBuffer::Extra(data) => data,
             SecurityBuffer::Padding(data) => data,
+            SecurityBuffer::ReadOnlyWithChecksum(data) => data,
             SecurityBuffer::Missing(_) => &[],
             SecurityBuffer::Empty => &[],
         }
@@ -173,6 +180,7 @@ impl<'data> SecurityBuffer<'data> {
             SecurityBuffer::Stream(data) => data.len(),
             SecurityBuffer::Extra(data) => data.len(),
             SecurityBuffer::Padding(data) => data.len(),
+            SecurityBuffer::ReadOnlyWithChecksum(data) => data.len(),
             SecurityBuffer::Missing(needed_bytes_amount) => *needed_bytes_amount,
             SecurityBuffer::Empty => 0,
         }
@@ -198,6 +206,7 @@ impl<'data> SecurityBuffer<'data> {
             SecurityBuffer::Stream(data) => take(data),
             SecurityBuffer::Extra(data) => take(data),
             SecurityBuffer::Padding(data) => take(data),
+            SecurityBuffer::ReadOnlyWithChecksum(data) => take(data),
             SecurityBuffer::Missing(_) => &mut [],
             SecurityBuffer::Empty => &mut [],
         }
@@ -236,6 +245,7 @@ impl fmt::Debug for SecurityBuffer<'_> {
             SecurityBuffer::Stream(data) => write_buffer(data, "Stream", f)?,
             SecurityBuffer::Extra(data) => write_buffer(data, "Extra", f)?,
             SecurityBuffer::Padding(data) => write_buffer(data, "Padding", f)?,
+            SecurityBuffer::ReadOnlyWithChecksum(data) => write_buffer(data, "ReadOnlyWithChecksum", f)?,
             SecurityBuffer::Missing(needed_bytes_amount) => write!(f, "Missing({})", *needed_bytes_amount)?,
             SecurityBuffer::Empty => f.write_str("Empty")?,
         };
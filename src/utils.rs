@@ -1,8 +1,8 @@
 use byteorder::{LittleEndian, ReadBytesExt};
-use picky_krb::crypto::CipherSuite;
 use rand::rngs::OsRng;
 use rand::Rng;
 
+use crate::kerberos::cipher::CipherSuite;
 use crate::kerberos::EncryptionParams;
 use crate::{Error, ErrorKind, Result, SecurityBuffer, SecurityBufferType};
 
@@ -309,6 +309,17 @@ pub fn extract_encrypted_data(buffers: &[SecurityBuffer]) -> Result<Vec<u8>> {
     Ok(encrypted)
 }
 
+/// Extracts data to sign from the message buffers.
+///
+/// The signature covers all `Data` buffers concatenated together.
+pub fn extract_data_to_sign(buffers: &[SecurityBuffer]) -> Vec<u8> {
+    buffers
+        .iter()
+        .filter(|buffer| buffer.security_buffer_type() == SecurityBufferType::Data)
+        .flat_map(|buffer| buffer.data().iter().copied())
+        .collect()
+}
+
 pub fn parse_target_name(target_name: &str) -> Result<(&str, &str)> {
     let divider = target_name.find('/').ok_or_else(|| {
         Error::new(
@@ -205,3 +205,21 @@ pub fn check_messages_encryption(client: &mut impl Sspi, server: &mut impl Sspi)
 
     Ok(())
 }
+
+pub fn check_messages_signing(client: &mut impl Sspi, server: &mut impl Sspi) -> sspi::Result<()> {
+    let server_sizes = server.query_context_sizes()?;
+    let sequence_number = 1;
+
+    let mut token = vec![0; server_sizes.max_signature as usize];
+    let mut data = MESSAGE_TO_CLIENT.to_vec();
+    let mut messages = [
+        SecurityBuffer::Data(data.as_mut_slice()),
+        SecurityBuffer::Token(token.as_mut_slice()),
+    ];
+    server.make_signature(0, &mut messages, sequence_number)?;
+    assert_eq!(MESSAGE_TO_CLIENT, messages[0].data());
+
+    client.verify_signature(&messages, sequence_number)?;
+
+    Ok(())
+}
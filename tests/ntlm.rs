@@ -1,11 +1,115 @@
 pub mod common;
 
 use common::{
-    check_messages_encryption, create_client_credentials_handle, create_server_credentials_handle,
-    process_authentication_without_complete, set_identity_and_try_complete_authentication, try_complete_authentication,
-    CredentialsProxyImpl, CREDENTIALS,
+    check_messages_encryption, check_messages_signing, create_client_credentials_handle,
+    create_server_credentials_handle, process_authentication_without_complete,
+    set_identity_and_try_complete_authentication, try_complete_authentication, CredentialsProxyImpl, CREDENTIALS,
 };
-use sspi::Ntlm;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sspi::credssp::NStatusCode;
+use sspi::ntlm::{NtHashLookup, NtHashProvider, NtlmConfig, NtlmValidationInfo, NtlmValidationRequest, NtlmValidator};
+use sspi::{
+    ClientRequestFlags, ClientResponseFlags, DataRepresentation, Error, ErrorKind, Ntlm, OwnedSecurityBuffer,
+    SecurityBufferType, SecurityPackageType, SecurityStatus, ServerRequestFlags, ServerResponseFlags, Sspi, SspiImpl,
+};
+
+// MD4 of the UTF-16LE encoded "Password"
+const PASSWORD_NT_HASH: [u8; 16] = [
+    0xa4, 0xf4, 0x9c, 0x40, 0x65, 0x10, 0xbd, 0xca, 0xb6, 0x82, 0x4e, 0xe7, 0xc3, 0x0f, 0xd8, 0x52,
+];
+
+#[derive(Debug)]
+struct NtHashProviderImpl {
+    lookup: NtHashLookup,
+}
+
+impl NtHashProvider for NtHashProviderImpl {
+    fn nt_hash(&self, username: &str, domain: &str) -> sspi::Result<NtHashLookup> {
+        assert_eq!(username, "Username");
+        assert_eq!(domain, "Domain");
+
+        Ok(self.lookup.clone())
+    }
+}
+
+fn hmac_md5(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut mac = Hmac::<Md5>::new_from_slice(key).unwrap();
+    mac.update(data);
+
+    mac.finalize().into_bytes().into()
+}
+
+/// Verifies the NTLMv2 response the way the domain controller does.
+#[derive(Debug)]
+struct NtlmValidatorImpl {
+    nt_hash: [u8; 16],
+}
+
+impl NtlmValidator for NtlmValidatorImpl {
+    fn validate(&self, request: &NtlmValidationRequest) -> sspi::Result<NtlmValidationInfo> {
+        let user_with_domain = (request.username.to_uppercase() + &request.domain)
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<u8>>();
+        let ntlm_v2_hash = hmac_md5(&self.nt_hash, &user_with_domain);
+
+        let (nt_proof, ntlm_v2_temp) = request.nt_challenge_response.split_at(16);
+        let calculated_nt_proof = hmac_md5(&ntlm_v2_hash, &[&request.server_challenge, ntlm_v2_temp].concat());
+
+        if calculated_nt_proof != nt_proof {
+            return Err(Error::new_with_nstatus(
+                ErrorKind::LogonDenied,
+                "wrong password",
+                NStatusCode::LOGON_FAILURE,
+            ));
+        }
+
+        Ok(NtlmValidationInfo {
+            user_session_key: hmac_md5(&ntlm_v2_hash, nt_proof),
+            authorization_data: vec![0x01, 0x02, 0x03],
+        })
+    }
+}
+
+fn authenticate_with_config(config: NtlmConfig) -> sspi::Result<(Ntlm, Ntlm)> {
+    authenticate_with_configs(NtlmConfig::default(), config)
+}
+
+fn authenticate_with_configs(client_config: NtlmConfig, server_config: NtlmConfig) -> sspi::Result<(Ntlm, Ntlm)> {
+    let mut client = Ntlm::with_config(client_config);
+    let client_credentials_handle = create_client_credentials_handle(&mut client, Some(&*CREDENTIALS)).unwrap();
+
+    let mut server = Ntlm::with_config(server_config);
+    let server_credentials_handle = create_server_credentials_handle(&mut server).unwrap();
+
+    let (client_status, server_status) = process_authentication_without_complete(
+        &mut client,
+        client_credentials_handle,
+        &mut server,
+        server_credentials_handle,
+    )?;
+    try_complete_authentication(&mut client, client_status)?;
+    try_complete_authentication(&mut server, server_status)?;
+
+    Ok((client, server))
+}
+
+fn authenticate_with_nt_hash_provider(lookup: NtHashLookup) -> sspi::Result<(Ntlm, Ntlm)> {
+    authenticate_with_config(NtlmConfig {
+        nt_hash_provider: Some(Arc::new(NtHashProviderImpl { lookup })),
+        ..Default::default()
+    })
+}
+
+fn authenticate_with_validator(nt_hash: [u8; 16]) -> sspi::Result<(Ntlm, Ntlm)> {
+    authenticate_with_config(NtlmConfig {
+        validator: Some(Arc::new(NtlmValidatorImpl { nt_hash })),
+        ..Default::default()
+    })
+}
 
 #[test]
 fn successful_ntlm_authentication_with_client_auth_data() {
@@ -28,4 +132,296 @@ fn successful_ntlm_authentication_with_client_auth_data() {
     set_identity_and_try_complete_authentication(&mut server, server_status, &mut credentials_proxy).unwrap();
 
     check_messages_encryption(&mut client, &mut server).unwrap();
+    check_messages_signing(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn successful_ntlm_authentication_with_nt_hash_provider() {
+    let (mut client, mut server) = authenticate_with_nt_hash_provider(NtHashLookup::NtHash(PASSWORD_NT_HASH)).unwrap();
+
+    check_messages_encryption(&mut client, &mut server).unwrap();
+    check_messages_signing(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn ntlm_authentication_with_nt_hash_provider_fails_on_wrong_nt_hash() {
+    let err = authenticate_with_nt_hash_provider(NtHashLookup::NtHash([0x42; 16])).unwrap_err();
+
+    assert_eq!(err.error_type, ErrorKind::LogonDenied);
+    assert_eq!(err.nstatus, Some(NStatusCode::LOGON_FAILURE));
+}
+
+#[test]
+fn ntlm_authentication_with_nt_hash_provider_fails_on_unknown_user() {
+    let err = authenticate_with_nt_hash_provider(NtHashLookup::NoSuchUser).unwrap_err();
+
+    assert_eq!(err.error_type, ErrorKind::LogonDenied);
+    assert_eq!(err.nstatus, Some(NStatusCode::NO_SUCH_USER));
+}
+
+#[test]
+fn ntlm_authentication_with_nt_hash_provider_fails_on_disabled_account() {
+    let err = authenticate_with_nt_hash_provider(NtHashLookup::AccountDisabled).unwrap_err();
+
+    assert_eq!(err.error_type, ErrorKind::LogonDenied);
+    assert_eq!(err.nstatus, Some(NStatusCode::ACCOUNT_DISABLED));
+}
+
+#[test]
+fn ntlm_acceptor_returns_client_identity() {
+    let (mut client, mut server) = authenticate_with_nt_hash_provider(NtHashLookup::NtHash(PASSWORD_NT_HASH)).unwrap();
+
+    let client_identity = server.query_context_client_identity().unwrap();
+    assert_eq!(client_identity.principal, "Username");
+    assert_eq!(client_identity.realm, "Domain");
+    assert!(matches!(client_identity.package, SecurityPackageType::Ntlm));
+    assert!(client_identity.mic_verified);
+    assert!(!client_identity.channel_bindings_verified);
+
+    assert!(client.query_context_client_identity().is_err());
+}
+
+#[test]
+fn successful_ntlm_pass_through_authentication() {
+    let (mut client, mut server) = authenticate_with_validator(PASSWORD_NT_HASH).unwrap();
+
+    assert_eq!(
+        server.validation_info().unwrap().authorization_data,
+        vec![0x01, 0x02, 0x03]
+    );
+
+    check_messages_encryption(&mut client, &mut server).unwrap();
+    check_messages_signing(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn ntlm_pass_through_authentication_fails_on_denied_logon() {
+    let err = authenticate_with_validator([0x42; 16]).unwrap_err();
+
+    assert_eq!(err.error_type, ErrorKind::LogonDenied);
+    assert_eq!(err.nstatus, Some(NStatusCode::LOGON_FAILURE));
+}
+
+fn config_with_nt_hash_provider(datagram: bool, ntlm_v1: bool) -> NtlmConfig {
+    NtlmConfig {
+        datagram,
+        ntlm_v1,
+        nt_hash_provider: Some(Arc::new(NtHashProviderImpl {
+            lookup: NtHashLookup::NtHash(PASSWORD_NT_HASH),
+        })),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn successful_connectionless_ntlm_authentication() {
+    let (mut client, mut server) = authenticate_with_configs(
+        config_with_nt_hash_provider(true, false),
+        config_with_nt_hash_provider(true, false),
+    )
+    .unwrap();
+
+    check_messages_encryption(&mut client, &mut server).unwrap();
+    check_messages_signing(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn connectionless_ntlm_authentication_fails_when_disabled_on_server() {
+    assert!(authenticate_with_configs(
+        config_with_nt_hash_provider(true, false),
+        config_with_nt_hash_provider(false, false)
+    )
+    .is_err());
+}
+
+#[test]
+fn successful_ntlm_v1_authentication() {
+    let (mut client, mut server) = authenticate_with_configs(
+        config_with_nt_hash_provider(false, true),
+        config_with_nt_hash_provider(false, true),
+    )
+    .unwrap();
+
+    check_messages_encryption(&mut client, &mut server).unwrap();
+    check_messages_signing(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn successful_connectionless_ntlm_v1_authentication() {
+    let (mut client, mut server) = authenticate_with_configs(
+        config_with_nt_hash_provider(true, true),
+        config_with_nt_hash_provider(true, true),
+    )
+    .unwrap();
+
+    check_messages_encryption(&mut client, &mut server).unwrap();
+    check_messages_signing(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn ntlm_v1_authentication_fails_when_disabled_on_server() {
+    let err = authenticate_with_configs(
+        config_with_nt_hash_provider(false, true),
+        config_with_nt_hash_provider(false, false),
+    )
+    .unwrap_err();
+
+    assert_eq!(err.error_type, ErrorKind::LogonDenied);
+}
+
+fn authenticate_with_requirements(
+    client_requirements: ClientRequestFlags,
+    target_name: Option<&str>,
+    server_config: NtlmConfig,
+    server_requirements: ServerRequestFlags,
+) -> sspi::Result<(Ntlm, Ntlm, ClientResponseFlags, ServerResponseFlags)> {
+    let mut client = Ntlm::new();
+    let mut client_credentials_handle = if client_requirements.contains(ClientRequestFlags::NULL_SESSION) {
+        None
+    } else {
+        create_client_credentials_handle(&mut client, Some(&*CREDENTIALS))?
+    };
+
+    let mut server = Ntlm::with_config(server_config);
+    let mut server_credentials_handle = create_server_credentials_handle(&mut server)?;
+
+    let mut server_output = Vec::new();
+    loop {
+        let mut client_output = vec![OwnedSecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
+        let mut builder = client
+            .initialize_security_context()
+            .with_credentials_handle(&mut client_credentials_handle)
+            .with_context_requirements(client_requirements)
+            .with_target_data_representation(DataRepresentation::Native)
+            .with_input(&mut server_output)
+            .with_output(&mut client_output);
+        if let Some(target_name) = target_name {
+            builder = builder.with_target_name(target_name);
+        }
+        let client_result = client
+            .initialize_security_context_impl(&mut builder)?
+            .resolve_to_result()?;
+
+        server_output = vec![OwnedSecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
+        let server_result = server
+            .accept_security_context()
+            .with_credentials_handle(&mut server_credentials_handle)
+            .with_context_requirements(server_requirements)
+            .with_target_data_representation(DataRepresentation::Native)
+            .with_input(&mut client_output)
+            .with_output(&mut server_output)
+            .execute(&mut server)?;
+
+        if server_result.status == SecurityStatus::CompleteNeeded {
+            try_complete_authentication(&mut server, server_result.status)?;
+
+            return Ok((client, server, client_result.flags, server_result.flags));
+        }
+    }
+}
+
+fn authenticate_anonymously(
+    server_requirements: ServerRequestFlags,
+) -> sspi::Result<(Ntlm, Ntlm, ClientResponseFlags, ServerResponseFlags)> {
+    authenticate_with_requirements(
+        ClientRequestFlags::NULL_SESSION | ClientRequestFlags::CONFIDENTIALITY,
+        None,
+        config_with_nt_hash_provider(false, false),
+        server_requirements,
+    )
+}
+
+#[test]
+fn successful_anonymous_ntlm_authentication() {
+    let (mut client, mut server, client_flags, server_flags) =
+        authenticate_anonymously(ServerRequestFlags::ALLOW_NULL_SESSION).unwrap();
+
+    assert!(client_flags.contains(ClientResponseFlags::NULL_SESSION));
+    assert!(server_flags.contains(ServerResponseFlags::NULL_SESSION));
+    assert_eq!(
+        server.query_context_names().unwrap().username.account_name(),
+        "ANONYMOUS LOGON"
+    );
+    let client_identity = server.query_context_client_identity().unwrap();
+    assert_eq!(client_identity.principal, "ANONYMOUS LOGON");
+    assert_eq!(client_identity.realm, "NT AUTHORITY");
+
+    check_messages_encryption(&mut client, &mut server).unwrap();
+    check_messages_signing(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn anonymous_ntlm_authentication_fails_when_null_session_is_not_allowed() {
+    let err = authenticate_anonymously(ServerRequestFlags::empty()).unwrap_err();
+
+    assert_eq!(err.error_type, ErrorKind::LogonDenied);
+}
+
+fn authenticate_with_target_name(target_name: Option<&str>) -> sspi::Result<(Ntlm, Ntlm)> {
+    let server_config = NtlmConfig {
+        target_names: vec!["HTTP/server.example.com".to_owned(), "HOST/server".to_owned()],
+        ..config_with_nt_hash_provider(false, false)
+    };
+
+    authenticate_with_requirements(
+        ClientRequestFlags::CONFIDENTIALITY,
+        target_name,
+        server_config,
+        ServerRequestFlags::empty(),
+    )
+    .map(|(client, server, _, _)| (client, server))
+}
+
+#[test]
+fn successful_ntlm_authentication_with_expected_target_name() {
+    let (mut client, mut server) = authenticate_with_target_name(Some("http/SERVER.example.com")).unwrap();
+
+    check_messages_encryption(&mut client, &mut server).unwrap();
+    check_messages_signing(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn ntlm_authentication_fails_on_unexpected_target_name() {
+    let err = authenticate_with_target_name(Some("HTTP/attacker.example.com")).unwrap_err();
+
+    assert_eq!(err.error_type, ErrorKind::BadBindings);
+}
+
+#[test]
+fn ntlm_authentication_fails_without_target_name() {
+    let err = authenticate_with_target_name(None).unwrap_err();
+
+    assert_eq!(err.error_type, ErrorKind::BadBindings);
+}
+
+#[test]
+fn successful_ntlm_authentication_without_challenge_timestamp() {
+    let mut credentials_proxy = CredentialsProxyImpl::new(&CREDENTIALS);
+
+    let mut client = Ntlm::new();
+    let client_credentials_handle = create_client_credentials_handle(&mut client, Some(&*CREDENTIALS)).unwrap();
+
+    let mut server = Ntlm::with_config(NtlmConfig {
+        nb_computer_name: Some("SERVER".to_owned()),
+        nb_domain_name: Some("EXAMPLE".to_owned()),
+        dns_computer_name: Some("server.example.com".to_owned()),
+        dns_domain_name: Some("example.com".to_owned()),
+        dns_tree_name: Some("example.com".to_owned()),
+        omit_timestamp: true,
+        ..Default::default()
+    });
+    let server_credentials_handle = create_server_credentials_handle(&mut server).unwrap();
+
+    let (client_status, server_status) = process_authentication_without_complete(
+        &mut client,
+        client_credentials_handle,
+        &mut server,
+        server_credentials_handle,
+    )
+    .unwrap();
+    try_complete_authentication(&mut client, client_status).unwrap();
+    set_identity_and_try_complete_authentication(&mut server, server_status, &mut credentials_proxy).unwrap();
+
+    check_messages_encryption(&mut client, &mut server).unwrap();
+    check_messages_signing(&mut client, &mut server).unwrap();
 }
@@ -17,6 +17,7 @@ pub fn credssp_client() {
             protocol_config: Box::<NtlmConfig>::default(),
             package_list: None,
             client_computer_name: "win2017".into(),
+            kdc_trust_store: None,
         }),
         String::new(),
     )